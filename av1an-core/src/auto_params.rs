@@ -0,0 +1,439 @@
+//! `--auto-params`, which picks the default parameters of the encoder for the
+//! source instead of using the same defaults for every source.
+//!
+//! The source is inspected once before encoding: the resolution of the encode
+//! and the frame rate select the preset, tiles and keyframe interval, its transfer
+//! characteristics whether it is HDR, and a few frames spread over the first
+//! minutes estimate how grainy it is, which turns off the temporal filtering
+//! that would smooth out the grain.
+
+use std::io::Read;
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context};
+use av1_grain::TransferFunction;
+
+use crate::{into_vec, tiles_for_resolution, Encoder, Input};
+
+/// Frames sampled to estimate the grain
+const GRAIN_FRAMES: usize = 12;
+/// Seconds between the sampled frames
+const GRAIN_INTERVAL: f64 = 10.0;
+/// Largest part of the frames that is sampled, from the center
+const GRAIN_CROP: (usize, usize) = (640, 360);
+/// Standard deviation of the noise (in 8-bit values) above which a source is
+/// considered grainy
+const GRAINY_SIGMA: f64 = 2.5;
+
+/// Properties of the source that the parameters depend on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourceInfo {
+  pub width: u32,
+  pub height: u32,
+  pub frame_rate: f64,
+  /// Estimated standard deviation of the noise, in 8-bit values
+  pub grain: f64,
+  pub hdr: bool,
+}
+
+impl SourceInfo {
+  /// Inspects the source, decoding a few of its frames, for an encode at the
+  /// given resolution
+  pub fn probe(input: &Input, (width, height): (u32, u32)) -> anyhow::Result<Self> {
+    let frame_rate = input.frame_rate()?;
+    let hdr = input.transfer_function_params_adjusted(&[])? == TransferFunction::SMPTE2084;
+    let grain = estimate_grain(input, frame_rate)?;
+    let info = Self {
+      width,
+      height,
+      frame_rate,
+      grain,
+      hdr,
+    };
+    info!(
+      "--auto-params: {width}x{height} at {frame_rate:.3} fps, {}, grain {grain:.2}{}",
+      if hdr { "HDR" } else { "SDR" },
+      if info.is_grainy() { " (grainy)" } else { "" }
+    );
+
+    Ok(info)
+  }
+
+  pub fn is_grainy(&self) -> bool {
+    self.grain > GRAINY_SIGMA
+  }
+
+  /// Returns how many steps faster (positive) or slower (negative) than the
+  /// usual preset the source should be encoded with
+  fn speed_offset(&self) -> i32 {
+    let pixels = f64::from(self.width) * f64::from(self.height) * self.frame_rate.min(120.0) / 30.0;
+    if pixels > 3840.0 * 2160.0 {
+      2
+    } else if pixels > 1920.0 * 1080.0 {
+      1
+    } else if pixels <= 1280.0 * 720.0 {
+      -1
+    } else {
+      0
+    }
+  }
+
+  /// Returns the keyframe interval of about 10 seconds
+  fn keyint(&self) -> u32 {
+    (self.frame_rate * 10.0).round().max(1.0) as u32
+  }
+
+  fn tiles(&self) -> (u32, u32) {
+    tiles_for_resolution((self.width, self.height))
+  }
+}
+
+/// Returns the default parameters of the encoder tuned for the source
+pub fn auto_arguments(encoder: Encoder, info: &SourceInfo) -> Vec<String> {
+  let (cols, rows) = info.tiles();
+  let speed = info.speed_offset();
+  let mut params = match encoder {
+    Encoder::aom => {
+      let mut params: Vec<String> = into_vec![
+        "--threads=8",
+        format!("--cpu-used={}", (6 + speed).clamp(3, 8)),
+        "--end-usage=q",
+        "--cq-level=30",
+        format!("--kf-max-dist={}", info.keyint()),
+        format!("--tile-columns={}", cols.ilog2()),
+        format!("--tile-rows={}", rows.ilog2()),
+      ];
+      if info.is_grainy() {
+        params.push("--arnr-strength=1".to_owned());
+      }
+      // the bit depth follows the pixel format of the encode
+      if info.hdr {
+        params.extend(into_vec![
+          "--color-primaries=bt2020",
+          "--transfer-characteristics=smpte2084",
+          "--matrix-coefficients=bt2020ncl",
+        ]);
+      }
+      params
+    }
+    Encoder::rav1e => {
+      let mut params: Vec<String> = into_vec![
+        "--speed",
+        (6 + speed).clamp(3, 10).to_string(),
+        "--quantizer",
+        "100",
+        "--no-scene-detection",
+        "--keyint",
+        info.keyint().to_string(),
+        "--tiles",
+        (cols * rows).to_string(),
+      ];
+      if info.hdr {
+        params.extend(into_vec![
+          "--primaries",
+          "BT2020",
+          "--transfer",
+          "SMPTE2084",
+          "--matrix",
+          "BT2020NCL",
+        ]);
+      }
+      params
+    }
+    Encoder::vpx => {
+      let mut params: Vec<String> = into_vec![
+        "--codec=vp9",
+        "-b",
+        "10",
+        "--profile=2",
+        "--threads=4",
+        format!("--cpu-used={}", (2 + speed).clamp(1, 5)),
+        "--end-usage=q",
+        "--cq-level=30",
+        "--row-mt=1",
+        format!("--kf-max-dist={}", info.keyint()),
+        format!("--tile-columns={}", cols.ilog2()),
+        format!("--tile-rows={}", rows.ilog2()),
+      ];
+      if info.is_grainy() {
+        params.extend(into_vec!["--auto-alt-ref=1", "--arnr-strength=1"]);
+      } else {
+        params.push("--auto-alt-ref=6".to_owned());
+      }
+      if info.hdr {
+        params.push("--color-space=bt2020".to_owned());
+      }
+      params
+    }
+    Encoder::svt_av1 => {
+      let mut params: Vec<String> = into_vec![
+        "--preset",
+        (4 + speed).clamp(2, 8).to_string(),
+        "--keyint",
+        info.keyint().to_string(),
+        "--rc",
+        "0",
+        "--crf",
+        "25",
+        "--tile-columns",
+        cols.ilog2().to_string(),
+        "--tile-rows",
+        rows.ilog2().to_string(),
+      ];
+      if info.is_grainy() {
+        params.extend(into_vec!["--enable-tf", "0"]);
+      }
+      if info.hdr {
+        params.extend(into_vec![
+          "--color-primaries",
+          "9",
+          "--transfer-characteristics",
+          "16",
+          "--matrix-coefficients",
+          "9",
+        ]);
+      }
+      params
+    }
+    Encoder::x264 => {
+      let mut params: Vec<String> = into_vec![
+        "--preset",
+        x26x_preset(speed),
+        "--crf",
+        "25",
+        "--keyint",
+        info.keyint().to_string(),
+      ];
+      if info.is_grainy() {
+        params.extend(into_vec!["--tune", "grain"]);
+      }
+      if info.hdr {
+        params.extend(into_vec![
+          "--colorprim",
+          "bt2020",
+          "--transfer",
+          "smpte2084",
+          "--colormatrix",
+          "bt2020nc",
+        ]);
+      }
+      params
+    }
+    Encoder::x265 => {
+      let mut params: Vec<String> = into_vec![
+        "-p",
+        x26x_preset(speed),
+        "--crf",
+        "25",
+        "-D",
+        "10",
+        "--level-idc",
+        "5.0",
+        "--keyint",
+        info.keyint().to_string(),
+      ];
+      if info.is_grainy() {
+        params.extend(into_vec!["--tune", "grain"]);
+      }
+      if info.hdr {
+        params.extend(into_vec![
+          "--colorprim",
+          "bt2020",
+          "--transfer",
+          "smpte2084",
+          "--colormatrix",
+          "bt2020nc",
+          "--hdr10",
+        ]);
+      }
+      params
+    }
+  };
+
+  // tiles are only worth it for large resolutions
+  if cols == 1 && rows == 1 {
+    remove_tiles(&mut params);
+  }
+  params
+}
+
+const fn x26x_preset(speed: i32) -> &'static str {
+  match speed {
+    i32::MIN..=-1 => "slower",
+    0 => "slow",
+    1 => "medium",
+    _ => "fast",
+  }
+}
+
+/// Removes the tile parameters, which are set to a single tile
+fn remove_tiles(params: &mut Vec<String>) {
+  let mut i = 0;
+  while i < params.len() {
+    if params[i].starts_with("--tile-") && params[i].contains('=') {
+      params.remove(i);
+    } else if matches!(
+      params[i].as_str(),
+      "--tile-columns" | "--tile-rows" | "--tiles"
+    ) {
+      params.drain(i..(i + 2).min(params.len()));
+    } else {
+      i += 1;
+    }
+  }
+}
+
+/// Decodes a few frames of the source, and returns the median of their noise
+fn estimate_grain(input: &Input, frame_rate: f64) -> anyhow::Result<f64> {
+  // smaller sources are sampled whole, as padding them would add flat borders
+  let (source_width, source_height) = input.resolution()?;
+  let width = GRAIN_CROP.0.min(source_width as usize);
+  let height = GRAIN_CROP.1.min(source_height as usize);
+  let interval = (frame_rate * GRAIN_INTERVAL).round().max(1.0);
+  let filter = format!("select=not(mod(n\\,{interval})),crop={width}:{height},format=gray");
+
+  let mut vspipe = None;
+  let mut ffmpeg = Command::new("ffmpeg");
+  ffmpeg.args(["-hide_banner", "-loglevel", "error"]);
+  match input {
+    Input::Video { path } => {
+      ffmpeg.arg("-i").arg(path);
+    }
+    Input::VapourSynth { path, vspipe_args } => {
+      let mut command = Command::new("vspipe");
+      for arg in vspipe_args {
+        command.args(["-a", arg]);
+      }
+      let mut child = command
+        .args(["-c", "y4m"])
+        .arg(path)
+        .arg("-")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .context("Failed to run vspipe to estimate the grain")?;
+      ffmpeg.stdin(child.stdout.take().unwrap()).args(["-i", "-"]);
+      vspipe = Some(child);
+    }
+  }
+  let mut ffmpeg = ffmpeg
+    .args(["-vf", &filter, "-vsync", "0", "-frames:v"])
+    .arg(GRAIN_FRAMES.to_string())
+    .args(["-f", "rawvideo", "-"])
+    .stdout(Stdio::piped())
+    .stderr(Stdio::null())
+    .spawn()
+    .context("Failed to run ffmpeg to estimate the grain")?;
+
+  let mut frames = Vec::new();
+  ffmpeg.stdout.take().unwrap().read_to_end(&mut frames)?;
+  ffmpeg.wait()?;
+  if let Some(mut vspipe) = vspipe {
+    // vspipe is stopped by the closed pipe once ffmpeg has read enough frames
+    vspipe.wait().ok();
+  }
+
+  let mut sigmas: Vec<f64> = frames
+    .chunks_exact(width * height)
+    .map(|frame| noise_sigma(frame, width, height))
+    .collect();
+  if sigmas.is_empty() {
+    bail!("Failed to decode frames of the source to estimate its grain");
+  }
+  sigmas.sort_unstable_by(f64::total_cmp);
+  Ok(sigmas[sigmas.len() / 2])
+}
+
+/// Estimates the standard deviation of the noise of an 8-bit plane, with the
+/// method of Immerkær (1996), skipping the edges found by a Sobel filter
+fn noise_sigma(plane: &[u8], width: usize, height: usize) -> f64 {
+  const EDGE_THRESHOLD: i32 = 50;
+
+  let px = |x: usize, y: usize| i32::from(plane[y * width + x]);
+  let mut sum = 0u64;
+  let mut count = 0u64;
+  for y in 1..height.saturating_sub(1) {
+    for x in 1..width.saturating_sub(1) {
+      let gx = px(x + 1, y - 1) + 2 * px(x + 1, y) + px(x + 1, y + 1)
+        - px(x - 1, y - 1)
+        - 2 * px(x - 1, y)
+        - px(x - 1, y + 1);
+      let gy = px(x - 1, y + 1) + 2 * px(x, y + 1) + px(x + 1, y + 1)
+        - px(x - 1, y - 1)
+        - 2 * px(x, y - 1)
+        - px(x + 1, y - 1);
+      if gx.abs() + gy.abs() > EDGE_THRESHOLD {
+        continue;
+      }
+
+      let laplacian = px(x - 1, y - 1) + px(x + 1, y - 1) + px(x - 1, y + 1) + px(x + 1, y + 1)
+        - 2 * (px(x, y - 1) + px(x - 1, y) + px(x + 1, y) + px(x, y + 1))
+        + 4 * px(x, y);
+      sum += u64::from(laplacian.unsigned_abs());
+      count += 1;
+    }
+  }
+
+  if count == 0 {
+    return 0.0;
+  }
+  (std::f64::consts::PI / 2.0).sqrt() / 6.0 * sum as f64 / count as f64
+}
+
+#[cfg(test)]
+mod tests {
+  use rand::{Rng, SeedableRng};
+
+  use super::*;
+
+  #[test]
+  fn auto_params_for_source() {
+    let (width, height) = GRAIN_CROP;
+    let flat = vec![128u8; width * height];
+    assert!(noise_sigma(&flat, width, height) < 0.01);
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let noisy: Vec<u8> = (0..width * height)
+      .map(|_| 128u8.saturating_add_signed(rng.gen_range(-8..=8)))
+      .collect();
+    // uniform noise in -8..=8 has a standard deviation of about 4.9
+    let sigma = noise_sigma(&noisy, width, height);
+    assert!((4.0..6.0).contains(&sigma), "{sigma}");
+
+    let uhd_hdr = SourceInfo {
+      width: 3840,
+      height: 2160,
+      frame_rate: 24000.0 / 1001.0,
+      grain: sigma,
+      hdr: true,
+    };
+    let params = auto_arguments(Encoder::svt_av1, &uhd_hdr);
+    assert_eq!(params[..4], ["--preset", "5", "--keyint", "240"]);
+    assert!(params.windows(2).any(|p| p == ["--tile-columns", "2"]));
+    assert!(params.windows(2).any(|p| p == ["--enable-tf", "0"]));
+    assert!(params
+      .windows(2)
+      .any(|p| p == ["--transfer-characteristics", "16"]));
+
+    let sd = SourceInfo {
+      width: 720,
+      height: 480,
+      frame_rate: 30000.0 / 1001.0,
+      grain: 0.5,
+      hdr: false,
+    };
+    assert_eq!(
+      auto_arguments(Encoder::aom, &sd),
+      [
+        "--threads=8",
+        "--cpu-used=5",
+        "--end-usage=q",
+        "--cq-level=30",
+        "--kf-max-dist=300"
+      ]
+    );
+    assert_eq!(
+      auto_arguments(Encoder::x264, &sd),
+      ["--preset", "slower", "--crf", "25", "--keyint", "300"]
+    );
+  }
+}
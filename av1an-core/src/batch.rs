@@ -0,0 +1,236 @@
+//! Batches of inputs, encoded one after another.
+//!
+//! When several inputs (or a folder of inputs) are given, the status of every
+//! input is kept in a manifest in the current directory, next to the default
+//! temporary folders. With `--resume`, the inputs that were already encoded are
+//! skipped, and the others are resumed from their temporary folders. The
+//! manifest is removed once every input is encoded.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::context::Av1anContext;
+use crate::settings::EncodeArgs;
+use crate::util::retry_io;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileStatus {
+  Pending,
+  /// Started but not finished, e.g. because av1an was interrupted
+  Encoding,
+  Done,
+  Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchFile {
+  pub input: PathBuf,
+  pub output: PathBuf,
+  pub status: FileStatus,
+  /// Error of the last attempt, if it failed
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Manifest {
+  pub files: Vec<BatchFile>,
+}
+
+impl Manifest {
+  /// Lists the inputs in order, with the status of the previous manifest for
+  /// the inputs it contains with the same output
+  fn new(args: &[EncodeArgs], previous: Option<&Self>) -> Self {
+    let files = args
+      .iter()
+      .map(|arg| {
+        let input = arg.input.as_path().to_path_buf();
+        let output = PathBuf::from(&arg.output_file);
+        previous
+          .and_then(|previous| {
+            previous
+              .files
+              .iter()
+              .find(|file| file.input == input && file.output == output)
+          })
+          .cloned()
+          .unwrap_or(BatchFile {
+            input,
+            output,
+            status: FileStatus::Pending,
+            error: None,
+          })
+      })
+      .collect();
+
+    Self { files }
+  }
+
+  fn load(path: &Path) -> anyhow::Result<Self> {
+    let contents =
+      fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_json::from_str(&contents).with_context(|| format!("Failed to parse {}", path.display()))
+  }
+
+  fn save(&self, path: &Path) -> anyhow::Result<()> {
+    // serializing the manifest should never fail, so unwrap is OK
+    let contents = serde_json::to_string_pretty(self).unwrap();
+    retry_io(|| fs::write(path, &contents))
+      .with_context(|| format!("Failed to write {}", path.display()))
+  }
+}
+
+/// Returns the path of the manifest of a batch of inputs, which only depends on
+/// the inputs
+pub fn manifest_path(args: &[EncodeArgs]) -> PathBuf {
+  let mut s = DefaultHasher::new();
+  for arg in args {
+    arg.input.as_path().hash(&mut s);
+  }
+  PathBuf::from(format!(
+    ".av1an-batch-{}.json",
+    &format!("{:x}", s.finish())[..7]
+  ))
+}
+
+/// Encodes the inputs one after another, keeping track of their status in the
+/// manifest. Unless `continue_on_error` is set, the batch stops at the first
+/// input that fails.
+pub fn run(args: Vec<EncodeArgs>, continue_on_error: bool) -> anyhow::Result<()> {
+  let mut outputs = HashSet::new();
+  let mut temps = HashSet::new();
+  for arg in &args {
+    ensure!(
+      outputs.insert(&arg.output_file),
+      "Several inputs would be encoded to the same output {}, -o cannot be used with multiple inputs",
+      arg.output_file
+    );
+    ensure!(
+      temps.insert(&arg.temp),
+      "Several inputs would use the same temporary folder {}, --temp cannot be used with multiple inputs",
+      arg.temp
+    );
+  }
+
+  let path = manifest_path(&args);
+  let previous = if args.iter().any(|arg| arg.resume) && path.exists() {
+    Some(Manifest::load(&path)?)
+  } else {
+    None
+  };
+  let mut manifest = Manifest::new(&args, previous.as_ref());
+  manifest.save(&path)?;
+  info!(
+    "batch of {} inputs, manifest: {}",
+    args.len(),
+    path.display()
+  );
+
+  let total = args.len();
+  let mut failed = 0;
+  for (i, arg) in args.into_iter().enumerate() {
+    let file = &mut manifest.files[i];
+    if file.status == FileStatus::Done && file.output.exists() {
+      info!(
+        "[{}/{}] {} was already encoded, skipping",
+        i + 1,
+        total,
+        file.input.display()
+      );
+      continue;
+    }
+
+    info!("[{}/{}] encoding {}", i + 1, total, file.input.display());
+    file.status = FileStatus::Encoding;
+    file.error = None;
+    manifest.save(&path)?;
+
+    let result = Av1anContext::new(arg).and_then(|mut context| context.encode_file());
+    let file = &mut manifest.files[i];
+    match result {
+      Ok(()) => file.status = FileStatus::Done,
+      Err(e) => {
+        file.status = FileStatus::Failed;
+        file.error = Some(format!("{e:#}"));
+        failed += 1;
+        if !continue_on_error {
+          manifest.save(&path)?;
+          return Err(e.context(format!(
+            "Failed to encode {}, the batch can be resumed with --resume",
+            manifest.files[i].input.display()
+          )));
+        }
+        error!("failed to encode {}: {:#}", file.input.display(), e);
+      }
+    }
+    manifest.save(&path)?;
+  }
+
+  if failed > 0 {
+    bail!(
+      "{failed} of {total} inputs failed to encode, see {} for the errors",
+      path.display()
+    );
+  }
+
+  if let Err(e) = fs::remove_file(&path) {
+    warn!("Failed to remove {}: {}", path.display(), e);
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn batch_manifest_keeps_status() {
+    let previous = Manifest {
+      files: vec![
+        BatchFile {
+          input: PathBuf::from("a.mkv"),
+          output: PathBuf::from("a_aom.mkv"),
+          status: FileStatus::Done,
+          error: None,
+        },
+        BatchFile {
+          input: PathBuf::from("b.mkv"),
+          output: PathBuf::from("b_aom.mkv"),
+          status: FileStatus::Failed,
+          error: Some("x".to_owned()),
+        },
+      ],
+    };
+    let contents = serde_json::to_string(&previous).unwrap();
+    assert!(contents.contains(r#""status":"done""#));
+    let previous: Manifest = serde_json::from_str(&contents).unwrap();
+
+    let args = |input: &str, output: &str| {
+      let mut args = crate::scenes::get_test_args().args;
+      args.input = crate::Input::Video {
+        path: PathBuf::from(input),
+      };
+      args.output_file = output.to_owned();
+      args
+    };
+
+    let manifest = Manifest::new(
+      &[args("a.mkv", "a_aom.mkv"), args("c.mkv", "c_aom.mkv")],
+      Some(&previous),
+    );
+    let statuses: Vec<_> = manifest.files.iter().map(|file| file.status).collect();
+    assert_eq!(statuses, [FileStatus::Done, FileStatus::Pending]);
+
+    // an input encoded to a different output is encoded again
+    let manifest = Manifest::new(&[args("a.mkv", "a_rav1e.mkv")], Some(&previous));
+    assert_eq!(manifest.files[0].status, FileStatus::Pending);
+  }
+}
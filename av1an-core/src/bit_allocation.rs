@@ -0,0 +1,302 @@
+//! Bit allocation, which assigns a q to each scene so that the whole encode
+//! hits a bitrate with the highest mean VMAF.
+//!
+//! Every chunk is first probed like target quality does: it is encoded at a few
+//! q across its q range, and each probe is scored with VMAF. The bits per frame
+//! and the VMAF of the q between the probes are interpolated. The q of the
+//! scenes are then chosen by a Lagrangian search, which maximizes the VMAF of
+//! the encode, weighted by the frames of the scenes, under the size of the
+//! bitrate: each scene takes the q with the best trade of VMAF for bits at a
+//! common price of a bit, and the price is bisected until the predicted size
+//! fits. The bits thus go to the scenes where they gain the most VMAF. The q of
+//! each chunk is stored in its parameters, so the probes are only done once per
+//! encode.
+
+use std::path::Path;
+use std::{fs, mem};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::chunk::Chunk;
+use crate::encoder::RateParam;
+use crate::proxy;
+use crate::target_quality::TargetQuality;
+use crate::vmaf::read_probe_vmaf;
+
+/// Average bitrate of `--allocate-bitrate`, and the settings of its probes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BitAllocation {
+  pub kbps: f64,
+  /// VMAF settings, q range and number of probes of each chunk, whose target is
+  /// unused
+  pub probes: TargetQuality,
+}
+
+/// Encode of a scene at a q, measured by the probes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Probe {
+  pub q: usize,
+  pub bits_per_frame: f64,
+  pub vmaf: f64,
+}
+
+/// Scene measured by the probes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scene {
+  /// Probes of the scene, in increasing order of q
+  pub probes: Vec<Probe>,
+  /// Frames of the final encode of the scene
+  pub frames: usize,
+}
+
+impl Scene {
+  /// Returns the bits and the VMAF weighted by the frames of the scene, of
+  /// each q from the first probe to the last, interpolated linearly between
+  /// the probes, the bits on a logarithmic scale
+  fn curve(&self) -> Vec<(usize, f64, f64)> {
+    let frames = self.frames as f64;
+    let point = |q: usize, log_bits: f64, vmaf: f64| (q, frames * log_bits.exp2(), frames * vmaf);
+
+    self
+      .probes
+      .windows(2)
+      .flat_map(|pair| {
+        let [a, b] = pair else { unreachable!() };
+        let (log_a, log_b) = (
+          a.bits_per_frame.max(1.0).log2(),
+          b.bits_per_frame.max(1.0).log2(),
+        );
+        (a.q..b.q).map(move |q| {
+          let t = (q - a.q) as f64 / (b.q - a.q) as f64;
+          point(
+            q,
+            t.mul_add(log_b - log_a, log_a),
+            t.mul_add(b.vmaf - a.vmaf, a.vmaf),
+          )
+        })
+      })
+      .chain(
+        self
+          .probes
+          .last()
+          .map(|p| point(p.q, p.bits_per_frame.max(1.0).log2(), p.vmaf)),
+      )
+      .collect()
+  }
+}
+
+/// Assigns a q to each scene, which maximizes the VMAF of all the scenes,
+/// weighted by their frames, so that their predicted number of bits fits in
+/// `budget` bits.
+///
+/// Only the q on the convex hull of the VMAF and bits of each scene can be
+/// chosen, so the budget is only met approximately, and not at all if the
+/// scenes do not fit even at the q of their last probes.
+pub fn allocate(scenes: &[Scene], budget: f64) -> Vec<usize> {
+  let curves: Vec<_> = scenes.iter().map(Scene::curve).collect();
+
+  // the q and bits each scene chooses at a price of a bit, in VMAF-frames; of
+  // equal trades, the last and highest q is chosen
+  let choose = |price: f64| -> Vec<(usize, f64)> {
+    curves
+      .iter()
+      .map(|curve| {
+        curve
+          .iter()
+          .max_by(|a, b| (price.mul_add(-a.1, a.2)).total_cmp(&price.mul_add(-b.1, b.2)))
+          .map_or((0, 0.0), |&(q, bits, _)| (q, bits))
+      })
+      .collect()
+  };
+  let bits = |choice: &[(usize, f64)]| choice.iter().map(|&(_, bits)| bits).sum::<f64>();
+
+  // the size only decreases as the price increases, so the lowest price that
+  // fits is found by bisection of its logarithm
+  let (mut low, mut high) = (-64.0_f64, 16.0_f64);
+  for _ in 0..60 {
+    let mid = (low + high) / 2.0;
+    if bits(&choose(mid.exp2())) > budget {
+      low = mid;
+    } else {
+      high = mid;
+    }
+  }
+
+  choose(high.exp2()).into_iter().map(|(q, _)| q).collect()
+}
+
+/// Probes the chunks, and sets the q in their parameters so that the encode
+/// has the bitrate of `allocation`
+pub fn apply(
+  chunks: &mut [Chunk],
+  allocation: &BitAllocation,
+  workers: usize,
+) -> anyhow::Result<()> {
+  info!(
+    "allocating {} kbps to {} chunks, with up to {} VMAF probes each",
+    allocation.kbps,
+    chunks.len(),
+    allocation.probes.probes.max(2)
+  );
+  let scenes = proxy::measure_chunks(chunks, workers, |chunk| {
+    probe_scene(chunk, &allocation.probes)
+  })?;
+
+  let seconds: f64 = chunks
+    .iter()
+    .map(|chunk| chunk.frames() as f64 / chunk.frame_rate)
+    .sum();
+  let budget = allocation.kbps * 1000.0 * seconds;
+
+  let q = allocate(&scenes, budget);
+  for ((chunk, q), scene) in chunks.iter_mut().zip(q).zip(scenes) {
+    debug!(
+      "chunk {}: q {q}, probes {}",
+      chunk.name(),
+      scene
+        .probes
+        .iter()
+        .map(|p| format!(
+          "q {} {:.0} bits per frame VMAF {:.2}",
+          p.q, p.bits_per_frame, p.vmaf
+        ))
+        .collect::<Vec<_>>()
+        .join(", ")
+    );
+    chunk.video_params = chunk
+      .encoder
+      .man_command(mem::take(&mut chunk.video_params), q);
+  }
+
+  Ok(())
+}
+
+/// Encodes probes of a chunk spread evenly across its q range, and measures
+/// their bits per frame and VMAF
+fn probe_scene(chunk: &Chunk, probes: &TargetQuality) -> anyhow::Result<Scene> {
+  // the q range of --min-q and --max-q is for the encoder of the encode, not of
+  // a zone
+  let (min_q, max_q) = if chunk.encoder == probes.encoder {
+    (probes.min_q as usize, probes.max_q as usize)
+  } else {
+    chunk.encoder.get_default_cq_range()
+  };
+  let count = probes.probes.max(2) as usize;
+  let mut qs: Vec<usize> = (0..count)
+    .map(|i| min_q + (max_q - min_q) * i / (count - 1))
+    .collect();
+  qs.dedup();
+
+  Ok(Scene {
+    probes: qs
+      .into_iter()
+      .map(|q| probe(chunk, probes, q))
+      .collect::<anyhow::Result<_>>()?,
+    frames: chunk.frames(),
+  })
+}
+
+/// Encodes every frame of a chunk at `q`, and measures the bits per frame and
+/// the VMAF of the encode
+fn probe(chunk: &Chunk, probes: &TargetQuality, q: usize) -> anyhow::Result<Probe> {
+  let (pipe, encoder_cmd) = chunk.encoder.probe_cmd(
+    chunk.temp.clone(),
+    chunk.index,
+    RateParam::Q,
+    q,
+    chunk.pix_format.unwrap_or(probes.pix_format),
+    1,
+    1,
+    chunk.video_params.clone(),
+    probes.probe_slow,
+    None,
+    chunk.denoise_filter().as_deref(),
+  );
+  let output = Path::new(&chunk.temp)
+    .join("split")
+    .join(format!("v_{q}_{}.ivf", chunk.index));
+
+  // the probes encode the source frames of the chunk, without --fps or the
+  // filters, while the size is predicted for the frames of the final encode
+  let probe = proxy::bits_per_frame(
+    chunk,
+    &pipe,
+    &encoder_cmd.iter().map(AsRef::as_ref).collect::<Vec<&str>>(),
+    &output,
+  )
+  .and_then(|bits_per_frame| {
+    let log = probes.score_vmaf(chunk, q as u32, 1)?;
+    let vmaf = read_probe_vmaf(&log, probes.probing_statistic, probes.frame_weighting, 1)
+      .with_context(|| format!("Failed to read the VMAF of {}", log.display()));
+    fs::remove_file(&log).ok();
+    Ok(Probe {
+      q,
+      bits_per_frame,
+      vmaf: vmaf?,
+    })
+  });
+  fs::remove_file(&output).ok();
+  probe
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn scene(probes: [(usize, f64, f64); 3]) -> Scene {
+    Scene {
+      probes: probes
+        .into_iter()
+        .map(|(q, bits_per_frame, vmaf)| Probe {
+          q,
+          bits_per_frame,
+          vmaf,
+        })
+        .collect(),
+      frames: 100,
+    }
+  }
+
+  #[test]
+  fn bit_allocation_maximizes_vmaf() {
+    // the VMAF of the first scene gains a lot from bits, the second one is
+    // already transparent at q 50
+    let scenes = [
+      scene([
+        (10, 40_000.0, 95.0),
+        (30, 10_000.0, 85.0),
+        (50, 2_500.0, 60.0),
+      ]),
+      scene([
+        (10, 40_000.0, 98.0),
+        (30, 10_000.0, 97.0),
+        (50, 2_500.0, 94.0),
+      ]),
+    ];
+    let predict = |q: &[usize]| -> (f64, f64) {
+      scenes
+        .iter()
+        .zip(q)
+        .fold((0.0, 0.0), |(bits, vmaf), (scene, &q)| {
+          let &(_, b, v) = scene.curve().iter().find(|point| point.0 == q).unwrap();
+          (bits + b, vmaf + v)
+        })
+    };
+
+    // the bits of q 30 are moved from the second scene to the first one, which
+    // raises the mean VMAF within the same size
+    let (budget, vmaf_at_30) = predict(&[30, 30]);
+    let q = allocate(&scenes, budget);
+    assert!(q[0] < 30 && q[1] > 30, "{q:?}");
+    let (bits, vmaf) = predict(&q);
+    assert!(bits <= budget, "{bits} {budget}");
+    assert!(vmaf > vmaf_at_30, "{vmaf} {vmaf_at_30}");
+
+    // a budget that cannot be met uses the last probes, and a large one the
+    // first probes
+    assert_eq!(allocate(&scenes, 1.0), [50, 50]);
+    assert_eq!(allocate(&scenes, 1e12), [10, 10]);
+    assert!(allocate(&[], 1000.0).is_empty());
+  }
+}
@@ -1,25 +1,75 @@
 use std::fmt::{Debug, Display};
-use std::fs::File;
-use std::io::Write;
 use std::path::Path;
-use std::process::ExitStatus;
+use std::process::{Command, ExitStatus};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::Sender;
-use std::thread::available_parallelism;
+use std::time::Duration;
+use std::{fs, thread};
 
 use cfg_if::cfg_if;
-use smallvec::SmallVec;
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use itertools::Itertools;
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
 use thiserror::Error;
-use tracing::{debug, error, warn};
+use tracing::{debug, error, info, warn};
 
+use crate::concat::ProgressiveConcat;
 use crate::context::Av1anContext;
-use crate::progress_bar::{dec_bar, update_progress_bar_estimates};
-use crate::util::printable_base10_digits;
-use crate::{finish_progress_bar, get_done, Chunk, DoneChunk, Instant};
+use crate::logging::{self, Event};
+use crate::progress_bar::{
+  dec_bar, eta_chunk_started, inc_bar, inc_mp_bar, update_progress_bar_estimates,
+};
+use crate::target_quality::TargetQuality;
+use crate::util::{printable_base10_digits, retry_io};
+use crate::{
+  checkpoint, crash, disk_space, finish_progress_bar, get_done, numa, proxy, target_quality, Chunk,
+  ChunkMethod, DoneChunk, DoneJsonWriter, Instant,
+};
+
+/// Encodes of a chunk at a higher q for `--max-chunk-bitrate`, at most
+const MAX_CAP_ENCODES: usize = 3;
+
+/// Delay before a chunk whose source failed to decode is tried again,
+/// multiplied by the number of tries so far
+const DECODE_RETRY_DELAY: Duration = Duration::from_secs(2);
 
 #[derive(Debug)]
 pub struct Broker<'a> {
   pub chunk_queue: Vec<Chunk>,
   pub project: &'a Av1anContext,
+  pub done_writer: &'a DoneJsonWriter,
+  pub verify_queue: VerifyQueue,
+  pub progressive_concat: Option<&'a ProgressiveConcat>,
+  /// Workers stopped after running out of memory, the ones with the highest ids
+  pub stopped_workers: AtomicUsize,
+}
+
+/// Encoded chunks waiting for `--verify-chunks`, with the risk of missing the target
+#[derive(Debug, Default)]
+pub struct VerifyQueue {
+  pending: Mutex<Vec<(f64, Chunk)>>,
+  /// Chunks being encoded, which may still be added to the queue
+  encoding: AtomicUsize,
+}
+
+impl VerifyQueue {
+  /// Takes the riskiest chunk, only if it is risky unless `all` is set
+  fn pop(&self, all: bool) -> Option<Chunk> {
+    let mut pending = self.pending.lock();
+    let (idx, &(risk, _)) = pending
+      .iter()
+      .enumerate()
+      .max_by(|(_, (a, _)), (_, (b, _))| a.total_cmp(b))?;
+    let chunk = (all || risk > 1.0).then(|| pending.swap_remove(idx).1);
+    drop(pending);
+    chunk
+  }
+
+  /// Whether no chunks are left to verify, nor will be
+  fn is_finished(&self) -> bool {
+    self.encoding.load(Ordering::SeqCst) == 0 && self.pending.lock().is_empty()
+  }
 }
 
 #[derive(Clone)]
@@ -71,6 +121,30 @@ impl StringOrBytes {
   }
 }
 
+/// Substitutes the placeholders of the chunk command with the details of a finished chunk
+fn chunk_command_args(
+  command: &[String],
+  chunk_output: String,
+  chunk_index: usize,
+  chunk_frames: usize,
+) -> Vec<String> {
+  let mut has_output = false;
+  let mut args: Vec<String> = command
+    .iter()
+    .map(|arg| {
+      has_output |= arg.contains("{chunk}");
+      arg
+        .replace("{chunk}", &chunk_output)
+        .replace("{index}", &chunk_index.to_string())
+        .replace("{frames}", &chunk_frames.to_string())
+    })
+    .collect();
+  if !has_output {
+    args.push(chunk_output);
+  }
+  args
+}
+
 #[derive(Error, Debug)]
 pub struct EncoderCrash {
   pub exit_status: ExitStatus,
@@ -78,6 +152,162 @@ pub struct EncoderCrash {
   pub stderr: StringOrBytes,
   pub source_pipe_stderr: StringOrBytes,
   pub ffmpeg_pipe_stderr: Option<StringOrBytes>,
+  /// Commands of the processes piped into each other, from the source to the
+  /// encoder, empty if the error is not from the pipeline of a chunk
+  pub pipeline: Vec<Vec<String>>,
+}
+
+/// Kind of a failure of the pipeline of a chunk, which decides how it is
+/// handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+  /// The encoder rejected its parameters, which fails the same on every
+  /// chunk, so the encode is stopped without trying again
+  InvalidArgument,
+  /// A process of the pipeline ran out of memory, or was killed by the OOM
+  /// killer, so fewer workers are run
+  OutOfMemory,
+  /// The encoder crashed
+  Segfault,
+  /// The source could not be decoded, or the pipe into the encoder broke,
+  /// which is often transient, so the chunk is tried again after a delay
+  Decode,
+  Other,
+}
+
+impl Display for FailureKind {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(match self {
+      Self::InvalidArgument => "invalid argument",
+      Self::OutOfMemory => "out of memory",
+      Self::Segfault => "segmentation fault",
+      Self::Decode => "decoding failed",
+      Self::Other => "unknown",
+    })
+  }
+}
+
+/// Messages of processes that ran out of memory
+const OOM_PATTERNS: &[&str] = &[
+  "out of memory",
+  "cannot allocate memory",
+  "bad_alloc",
+  "memory allocation failed",
+  "failed to allocate",
+];
+
+/// Messages of encoders rejecting their parameters
+///
+/// The "Invalid argument" of `EINVAL` is not one of them, as it is also the
+/// message of failing system calls.
+const INVALID_ARGUMENT_PATTERNS: &[&str] = &[
+  "unknown option",
+  "unrecognized option",
+  "unknown argument",
+  "unexpected argument",
+  "invalid option",
+  "[error]: invalid argument",
+  "invalid value",
+  "invalid parameter",
+  "error parsing",
+];
+
+/// Messages of ffmpeg and vspipe failing to decode the source
+const DECODE_PATTERNS: &[&str] = &[
+  "error while decoding",
+  "invalid data found when processing input",
+  "corrupt",
+  "concealing",
+  "missing reference picture",
+  "failed to retrieve frame",
+  "broken pipe",
+];
+
+/// Number of processes killed by the OOM killer when the encode started
+static OOM_KILLS: OnceCell<Option<u64>> = OnceCell::new();
+
+/// Returns the number of processes killed by the OOM killer so far, in the
+/// cgroup of av1an (which includes the kills of a memory limit of the cgroup),
+/// or else in the whole system
+fn oom_kills() -> Option<u64> {
+  if !cfg!(target_os = "linux") {
+    return None;
+  }
+  let counter = |events: String| {
+    events
+      .lines()
+      .find_map(|line| line.strip_prefix("oom_kill ")?.trim().parse().ok())
+  };
+  fs::read_to_string("/proc/self/cgroup")
+    .ok()
+    .and_then(|cgroups| {
+      let own = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+      let cgroup = Path::new("/sys/fs/cgroup").join(own.trim_start_matches('/'));
+      fs::read_to_string(cgroup.join("memory.events")).ok()
+    })
+    .and_then(counter)
+    .or_else(|| fs::read_to_string("/proc/vmstat").ok().and_then(counter))
+}
+
+/// Records the number of processes killed by the OOM killer before encoding,
+/// to tell the processes it killed from those killed otherwise
+pub fn record_oom_kills() {
+  OOM_KILLS.get_or_init(oom_kills);
+}
+
+/// Whether the OOM killer killed a process since the encode started
+fn oom_killed() -> bool {
+  matches!(
+    (OOM_KILLS.get().copied().flatten(), oom_kills()),
+    (Some(before), Some(now)) if now > before
+  )
+}
+
+impl EncoderCrash {
+  /// Classifies the failure from the exit status and the output of the
+  /// processes of the pipeline
+  pub fn kind(&self) -> FailureKind {
+    let lowercase =
+      |output: &StringOrBytes| String::from_utf8_lossy(output.as_bytes()).to_lowercase();
+    let stderr = lowercase(&self.stderr);
+    let pipe_stderr = lowercase(&self.source_pipe_stderr)
+      + &self
+        .ffmpeg_pipe_stderr
+        .as_ref()
+        .map(lowercase)
+        .unwrap_or_default();
+    let contains = |output: &str, patterns: &[&str]| patterns.iter().any(|p| output.contains(p));
+
+    cfg_if! {
+      if #[cfg(unix)] {
+        use std::os::unix::process::ExitStatusExt;
+        match self.exit_status.signal() {
+          // SIGKILL is also sent by users and timeouts
+          Some(libc::SIGKILL) if oom_killed() => return FailureKind::OutOfMemory,
+          Some(libc::SIGSEGV | libc::SIGBUS | libc::SIGILL) => return FailureKind::Segfault,
+          _ => {}
+        }
+      } else if #[cfg(windows)] {
+        match self.exit_status.code().map(|code| code as u32) {
+          // STATUS_NO_MEMORY, STATUS_COMMITMENT_LIMIT
+          Some(0xC000_0017 | 0xC000_012D) => return FailureKind::OutOfMemory,
+          // STATUS_ACCESS_VIOLATION, STATUS_STACK_OVERFLOW
+          Some(0xC000_0005 | 0xC000_00FD) => return FailureKind::Segfault,
+          _ => {}
+        }
+      }
+    }
+
+    if contains(&stderr, OOM_PATTERNS) || contains(&pipe_stderr, OOM_PATTERNS) {
+      FailureKind::OutOfMemory
+    } else if contains(&stderr, INVALID_ARGUMENT_PATTERNS) {
+      FailureKind::InvalidArgument
+    } else if contains(&pipe_stderr, DECODE_PATTERNS) || stderr.contains("broken pipe") {
+      FailureKind::Decode
+    } else {
+      FailureKind::Other
+    }
+  }
 }
 
 impl Display for EncoderCrash {
@@ -96,10 +326,55 @@ impl Display for EncoderCrash {
   }
 }
 
+/// Removes the logs of the passes and parts of a finished chunk
+fn remove_chunk_logs(chunk: &Chunk) {
+  let prefix = format!("chunk_{}_", chunk.name());
+  let Ok(entries) = fs::read_dir(Path::new(&chunk.temp).join("logs")) else {
+    return;
+  };
+  for entry in entries.filter_map(Result::ok) {
+    if entry.file_name().to_string_lossy().starts_with(&prefix) {
+      if let Err(e) = fs::remove_file(entry.path()) {
+        debug!(
+          "[chunk {}] failed to remove {:?}: {}",
+          chunk.index,
+          entry.path(),
+          e
+        );
+      }
+    }
+  }
+}
+
 impl Broker<'_> {
+  /// Runs the user-specified command on a finished chunk
+  fn run_chunk_command(&self, chunk: &Chunk) {
+    let args = chunk_command_args(
+      &self.project.args.chunk_command,
+      chunk.output(),
+      chunk.index,
+      chunk.frames(),
+    );
+
+    let [program, args @ ..] = args.as_slice() else {
+      unreachable!()
+    };
+    match Command::new(program).args(args).output() {
+      Ok(out) if out.status.success() => {}
+      Ok(out) => warn!(
+        "[chunk {}] chunk command failed with {}:\n{:#?}",
+        chunk.index,
+        out.status,
+        StringOrBytes::from(out.stderr)
+      ),
+      Err(e) => warn!("[chunk {}] failed to run chunk command: {}", chunk.index, e),
+    }
+  }
+
   /// Main encoding loop. set_thread_affinity may be ignored if the value is invalid.
   #[tracing::instrument(skip(self))]
   pub fn encoding_loop(self, tx: Sender<()>, set_thread_affinity: Option<usize>) {
+    record_oom_kills();
     if !self.chunk_queue.is_empty() {
       let (sender, receiver) = crossbeam_channel::bounded(self.chunk_queue.len());
 
@@ -108,41 +383,107 @@ impl Broker<'_> {
       }
       drop(sender);
 
-      crossbeam_utils::thread::scope(|s| {
-        let consumers: Vec<_> = (0..self.project.args.workers)
-          .map(|idx| (receiver.clone(), &self, idx))
-          .map(|(rx, queue, worker_id)| {
-            let tx = tx.clone();
-            s.spawn(move |_| {
-              cfg_if! {
-                if #[cfg(any(target_os = "linux", target_os = "windows"))] {
-                  if let Some(threads) = set_thread_affinity {
-                    if threads == 0 {
-                      warn!("Ignoring set_thread_affinity: Requested 0 threads");
-                    } else {
-                      match available_parallelism() {
-                        Ok(parallelism) => {
-                          let available_threads = parallelism.get();
-                          let mut cpu_set = SmallVec::<[usize; 16]>::new();
-                          let start_thread = (threads * worker_id) % available_threads;
-                          cpu_set.extend((start_thread..start_thread + threads).map(|t| t % available_threads));
-                          if let Err(e) = affinity::set_thread_affinity(&cpu_set) {
-                            warn!(
-                              "Failed to set thread affinity for worker {}: {}",
-                              worker_id, e
-                            );
-                          }
-                        },
-                        Err(e) => {
-                          warn!("Failed to get thread count: {}. Thread affinity will not be set", e);
-                        }
-                      }
+      self.encode_received(&receiver, tx, set_thread_affinity);
+    }
+  }
+
+  /// Encodes the chunks of `receiver` until it is closed, which lets the
+  /// chunks be queued while the workers are already encoding
+  pub(crate) fn encode_received(
+    self,
+    receiver: &Receiver<Chunk>,
+    tx: Sender<()>,
+    set_thread_affinity: Option<usize>,
+  ) {
+    target_quality::reset_idle_slots();
+
+    crossbeam_utils::thread::scope(|s| {
+      let probe_workers = self
+        .project
+        .args
+        .target_quality
+        .as_ref()
+        .map_or(0, |tq| tq.probe_workers);
+
+      // Probe workers search the Q of upcoming chunks ahead of the encoders,
+      // which then receive the chunks with their Q already chosen
+      let receiver = if probe_workers > 0 {
+        let (probed_sender, probed_receiver) =
+          crossbeam_channel::bounded(self.project.args.workers);
+
+        for _ in 0..probe_workers {
+          let (rx, probed_tx, tx) = (receiver.clone(), probed_sender.clone(), tx.clone());
+          let queue = &self;
+          s.spawn(move |_| loop {
+            queue.wait_for_schedule();
+            let Ok(mut chunk) = rx.recv() else {
+              return;
+            };
+            if let Err(e) = queue.probe_chunk(&mut chunk) {
+              error!("[chunk {}] {}", chunk.index, e);
+
+              tx.send(()).unwrap();
+              return;
+            }
+            if probed_tx.send(chunk).is_err() {
+              return;
+            }
+          });
+        }
+
+        probed_receiver
+      } else {
+        receiver.clone()
+      };
+
+      let consumers: Vec<_> = (0..self.project.args.workers)
+        .map(|idx| (receiver.clone(), &self, idx))
+        .map(|(rx, queue, worker_id)| {
+          let tx = tx.clone();
+          s.spawn(move |_| {
+            cfg_if! {
+              if #[cfg(any(target_os = "linux", target_os = "windows"))] {
+                if let Some(threads) = set_thread_affinity {
+                  if threads == 0 {
+                    warn!("Ignoring set_thread_affinity: Requested 0 threads");
+                  } else {
+                    // Child processes spawned from this thread inherit its affinity,
+                    // so the whole pipeline of this worker stays on the same NUMA node
+                    let cpu_set = numa::topology().cpu_set_for_worker(worker_id, threads);
+                    if let Err(e) = affinity::set_thread_affinity(&cpu_set) {
+                      warn!(
+                        "Failed to set thread affinity for worker {}: {}",
+                        worker_id, e
+                      );
                     }
                   }
                 }
               }
+            }
+
+            let verify_tq = queue
+              .project
+              .args
+              .target_quality
+              .as_ref()
+              .filter(|tq| tq.verify_chunks.is_some());
+            if let Some(tq) = verify_tq {
+              if let Err((index, e)) = queue.encode_and_verify(&rx, tq, worker_id) {
+                error!("[chunk {}] {}", index, e);
 
-              while let Ok(mut chunk) = rx.recv() {
+                tx.send(()).unwrap();
+                return Err(());
+              }
+            } else {
+              loop {
+                queue.wait_for_schedule();
+                queue.wait_for_disk_space();
+                if queue.is_stopped(worker_id) {
+                  break;
+                }
+                let Ok(mut chunk) = rx.recv() else {
+                  break;
+                };
                 if let Err(e) = queue.encode_chunk(&mut chunk, worker_id) {
                   error!("[chunk {}] {}", chunk.index, e);
 
@@ -150,97 +491,584 @@ impl Broker<'_> {
                   return Err(());
                 }
               }
-              Ok(())
-            })
+            }
+            target_quality::release_idle_slot();
+            Ok(())
           })
-          .collect();
-        for consumer in consumers {
-          consumer.join().unwrap().ok();
+        })
+        .collect();
+      for consumer in consumers {
+        consumer.join().unwrap().ok();
+      }
+    })
+    .unwrap();
+
+    finish_progress_bar();
+  }
+
+  /// Encodes chunks like the plain worker loop, and verifies the encoded chunks
+  /// in between, riskiest first. Risky chunks are verified as soon as they are
+  /// encoded, so that the ones that miss the target are found and encoded
+  /// again while other workers are still encoding, and the rest once no chunks
+  /// are left to encode.
+  fn encode_and_verify(
+    &self,
+    rx: &Receiver<Chunk>,
+    tq: &TargetQuality,
+    worker_id: usize,
+  ) -> Result<(), (usize, Box<EncoderCrash>)> {
+    loop {
+      self.wait_for_schedule();
+      self.wait_for_disk_space();
+      if self.is_stopped(worker_id) {
+        return Ok(());
+      }
+      if let Some(mut chunk) = self.verify_queue.pop(rx.is_empty()) {
+        if let Some(q) = tq
+          .verify_encoded_chunk(&chunk)
+          .map_err(|e| (chunk.index, e))?
+        {
+          dec_bar(chunk.frames() as u64);
+          chunk.tq_cq = Some(q);
+          self
+            .encode_chunk(&mut chunk, worker_id)
+            .map_err(|e| (chunk.index, e))?;
         }
-      })
-      .unwrap();
+        continue;
+      }
 
-      finish_progress_bar();
+      match rx.recv_timeout(Duration::from_millis(100)) {
+        Ok(mut chunk) => {
+          self.verify_queue.encoding.fetch_add(1, Ordering::SeqCst);
+          let result = self.encode_chunk(&mut chunk, worker_id);
+          if result.is_ok() {
+            if let Some(risk) = tq.verify_risk(&chunk) {
+              self.verify_queue.pending.lock().push((risk, chunk.clone()));
+            }
+          }
+          self.verify_queue.encoding.fetch_sub(1, Ordering::SeqCst);
+          result.map_err(|e| (chunk.index, e))?;
+        }
+        Err(RecvTimeoutError::Timeout) => {}
+        Err(RecvTimeoutError::Disconnected) => {
+          if self.verify_queue.is_finished() {
+            return Ok(());
+          }
+          // other workers are still encoding chunks that may need to be verified
+          thread::sleep(Duration::from_millis(100));
+        }
+      }
     }
   }
 
-  #[tracing::instrument(skip(self))]
-  fn encode_chunk(&self, chunk: &mut Chunk, worker_id: usize) -> Result<(), Box<EncoderCrash>> {
-    let st_time = Instant::now();
+  /// Stops the worker with the highest id once it has finished its chunk,
+  /// after a process ran out of memory, keeping at least one worker
+  fn stop_worker(&self) {
+    let workers = self.project.args.workers;
+    if let Ok(stopped) =
+      self
+        .stopped_workers
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |stopped| {
+          (stopped + 1 < workers).then_some(stopped + 1)
+        })
+    {
+      warn!(
+        "out of memory, reducing the workers from {} to {}",
+        workers - stopped,
+        workers - stopped - 1
+      );
+    }
+  }
+
+  /// Whether the worker was stopped after running out of memory
+  fn is_stopped(&self, worker_id: usize) -> bool {
+    worker_id > 0
+      && worker_id >= self.project.args.workers - self.stopped_workers.load(Ordering::SeqCst)
+  }
 
-    if let Some(ref tq) = self.project.args.target_quality {
-      tq.per_shot_target_quality_routine(chunk).unwrap();
+  /// Pauses the calling worker outside of the windows of `--schedule`
+  fn wait_for_schedule(&self) {
+    if let Some(schedule) = &self.project.args.schedule {
+      schedule.wait();
     }
+  }
 
-    // space padding at the beginning to align with "finished chunk"
-    debug!(
-      " started chunk {:05}: {} frames",
-      chunk.index,
-      chunk.frames()
-    );
+  /// Blocks the worker while the disk of the temporary directory or of the
+  /// output is running out of space
+  fn wait_for_disk_space(&self) {
+    let args = &self.project.args;
+    if let Some(min_free) = args.min_free_space {
+      disk_space::wait(
+        &[Path::new(&args.temp), Path::new(&args.output_file)],
+        min_free,
+      );
+    }
+  }
 
-    // we display the index, so we need to subtract 1 to get the max index
-    let padding = printable_base10_digits(self.chunk_queue.len() - 1) as usize;
+  /// Runs the target quality search for the chunk, if it has not been done yet
+  fn probe_chunk(&self, chunk: &mut Chunk) -> Result<(), Box<EncoderCrash>> {
+    crate::split::wait_for_segment(chunk);
+    match self.project.args.target_quality {
+      Some(ref tq) if chunk.tq_cq.is_none() => tq.per_shot_target_quality_routine(chunk),
+      _ => Ok(()),
+    }
+  }
 
-    let passes = chunk.passes;
-    for current_pass in 1..=passes {
-      for r#try in 1..=self.project.args.max_tries {
+  #[tracing::instrument(skip(self))]
+  /// Encodes every pass of a chunk (or part of a chunk), trying each pass up
+  /// to `max_tries` times, and returns the time of every pass
+  fn encode_passes(
+    &self,
+    chunk: &Chunk,
+    worker_id: usize,
+    padding: usize,
+    max_tries: usize,
+  ) -> Result<Vec<f64>, Box<EncoderCrash>> {
+    let mut pass_times = Vec::with_capacity(chunk.passes as usize);
+    for current_pass in 1..=chunk.passes {
+      let pass_time = Instant::now();
+      for r#try in 1..=max_tries {
         let res = self
           .project
           .create_pipes(chunk, current_pass, worker_id, padding);
         if let Err((e, frames)) = res {
           dec_bar(frames);
+          let kind = e.kind();
+          let will_retry = r#try < max_tries && kind != FailureKind::InvalidArgument;
+          logging::event(&Event::WorkerCrash {
+            chunk: chunk.index,
+            worker: worker_id,
+            attempt: r#try,
+            will_retry,
+            exit_status: e.exit_status.to_string(),
+            failure: kind.to_string(),
+            error: e.to_string(),
+          });
 
-          if r#try == self.project.args.max_tries {
+          if kind == FailureKind::InvalidArgument {
             error!(
-              "[chunk {}] encoder failed {} times, shutting down worker",
-              chunk.index, self.project.args.max_tries
+              "[chunk {}] the encoder rejected its parameters, which fails on every chunk, stopping without trying again",
+              chunk.index
             );
+          }
+          if !will_retry {
             return Err(e);
           }
+          match kind {
+            FailureKind::OutOfMemory => self.stop_worker(),
+            FailureKind::Decode => thread::sleep(DECODE_RETRY_DELAY * r#try as u32),
+            _ => {}
+          }
           // avoids double-print of the error message as both a WARN and ERROR,
           // since `Broker::encoding_loop` will print the error message as well
-          warn!("Encoder failed (on chunk {}):\n{}", chunk.index, e);
+          warn!(
+            "Encoder failed (on chunk {}, {}):\n{}",
+            chunk.index, kind, e
+          );
         } else {
           break;
         }
       }
+      pass_times.push(pass_time.elapsed().as_secs_f64());
+    }
+
+    Ok(pass_times)
+  }
+
+  /// Encodes a chunk as parts of at most `max_frames` frames, skipping the
+  /// parts that were already encoded, and concatenates them into the output of
+  /// the chunk
+  fn encode_parts(
+    &self,
+    chunk: &Chunk,
+    max_frames: usize,
+    worker_id: usize,
+    padding: usize,
+  ) -> Result<Vec<f64>, Box<EncoderCrash>> {
+    let max_tries = self.project.args.max_tries;
+    let dir = checkpoint::parts_dir(chunk);
+    let parts = checkpoint::split(chunk.frames(), max_frames);
+    let mut done = checkpoint::load_done(&dir);
+    let mut pass_times = vec![0.0; chunk.passes as usize];
+
+    for &part in &parts {
+      let part_chunk = Chunk {
+        part: Some(part),
+        ..chunk.clone()
+      };
+      if let Some(times) = done
+        .get(&part.index)
+        .filter(|_| Path::new(&part_chunk.output()).exists())
+      {
+        for (total, time) in pass_times.iter_mut().zip(times) {
+          *total += time;
+        }
+        let frames = (part.end - part.start) as u64;
+        inc_bar(frames);
+        inc_mp_bar(frames);
+        continue;
+      }
+
+      if let Err(e) = fs::create_dir_all(&dir) {
+        return Err(parts_failed(
+          chunk,
+          &format!("failed to create {}: {e}", dir.display()),
+        ));
+      }
+      let times = self
+        .encode_passes(&part_chunk, worker_id, padding, max_tries)
+        .inspect_err(|_| {
+          error!(
+            "[chunk {}] encoder failed {} times on part {} (frames {}..{}), shutting down worker",
+            chunk.index, max_tries, part.index, part.start, part.end
+          );
+        })?;
+      for (total, time) in pass_times.iter_mut().zip(&times) {
+        *total += time;
+      }
+
+      done.insert(part.index, times);
+      if let Err(e) = checkpoint::save_done(&dir, &done) {
+        warn!("[chunk {}] {:#}", chunk.index, e);
+      }
+    }
+
+    checkpoint::concat_parts(chunk, &parts)
+      .map_err(|e| parts_failed(chunk, &format!("failed to concatenate the parts: {e:#}")))?;
+
+    Ok(pass_times)
+  }
+
+  /// Encodes a chunk that keeps failing again, decoding it with each fallback
+  /// chunk method in turn, in case its source can not be decoded with the
+  /// chunk method of the encode. Returns the error of the last method if it
+  /// fails with all of them.
+  fn encode_fallback(
+    &self,
+    chunk: &mut Chunk,
+    worker_id: usize,
+    padding: usize,
+    mut error: Box<EncoderCrash>,
+  ) -> Result<(Vec<f64>, ChunkMethod), Box<EncoderCrash>> {
+    let max_tries = self.project.args.max_tries;
+    let mut current = self.project.args.chunk_method;
+    for method in self.project.fallback_methods() {
+      let fallback = match self.project.fallback_chunk(chunk, method) {
+        Ok(fallback) => fallback,
+        Err(e) => {
+          warn!(
+            "[chunk {}] unable to decode it with {}: {:#}",
+            chunk.index,
+            <&str>::from(method),
+            e
+          );
+          continue;
+        }
+      };
+      warn!(
+        "[chunk {}] encoder failed with {}, decoding the chunk with {} instead:\n{}",
+        chunk.index,
+        <&str>::from(current),
+        <&str>::from(method),
+        error
+      );
+
+      match self.encode_passes(&fallback, worker_id, padding, max_tries) {
+        Ok(pass_times) => {
+          *chunk = fallback;
+          return Ok((pass_times, method));
+        }
+        Err(e) => {
+          error = e;
+          current = method;
+        }
+      }
+    }
+
+    Err(error)
+  }
+
+  /// Encodes the chunk again at a higher q while its bitrate exceeds `kbps`,
+  /// adding the time of the encodes to `pass_times`
+  fn cap_bitrate(
+    &self,
+    chunk: &mut Chunk,
+    kbps: f64,
+    worker_id: usize,
+    padding: usize,
+    pass_times: &mut [f64],
+  ) -> Result<(), Box<EncoderCrash>> {
+    for _ in 0..MAX_CAP_ENCODES {
+      let Ok(metadata) = retry_io(|| Path::new(&chunk.output()).metadata()) else {
+        return Ok(());
+      };
+      let seconds = chunk.frames() as f64 / chunk.frame_rate;
+      let bitrate = metadata.len() as f64 * 8.0 / 1000.0 / seconds;
+      if bitrate <= kbps {
+        return Ok(());
+      }
+
+      let Some(q) = chunk
+        .tq_cq
+        .map(|q| q as usize)
+        .or_else(|| chunk.encoder.get_q(&chunk.video_params))
+      else {
+        warn!(
+          "[chunk {}] bitrate of {bitrate:.0} kbps exceeds --max-chunk-bitrate, but its q is not set \
+           in the parameters",
+          chunk.index
+        );
+        return Ok(());
+      };
+      let max_q = proxy::max_q(chunk.encoder);
+      let steps = (proxy::q_per_halving(chunk.encoder) * (bitrate / kbps).log2()).ceil();
+      let capped_q = (q + (steps as usize).max(1)).min(max_q);
+      if capped_q == q {
+        warn!(
+          "[chunk {}] bitrate of {bitrate:.0} kbps exceeds --max-chunk-bitrate at the highest q",
+          chunk.index
+        );
+        return Ok(());
+      }
+
+      info!(
+        "[chunk {}] bitrate of {bitrate:.0} kbps exceeds --max-chunk-bitrate, encoding again at q \
+         {capped_q} instead of {q}",
+        chunk.index
+      );
+      dec_bar(chunk.frames() as u64);
+      chunk.tq_cq = Some(capped_q as u32);
+      let times = self.encode_passes(chunk, worker_id, padding, self.project.args.max_tries)?;
+      for (total, time) in pass_times.iter_mut().zip(times) {
+        *total += time;
+      }
+    }
+
+    Ok(())
+  }
+
+  fn encode_chunk(&self, chunk: &mut Chunk, worker_id: usize) -> Result<(), Box<EncoderCrash>> {
+    let st_time = Instant::now();
+
+    // a failed search is reported like a crash of the encoder, by the caller
+    self.probe_chunk(chunk)?;
+
+    // space padding at the beginning to align with "finished chunk"
+    debug!(
+      " started chunk {:05}: {} frames",
+      chunk.index,
+      chunk.frames()
+    );
+    logging::event(&Event::ChunkStarted {
+      chunk: chunk.index,
+      frames: chunk.frames(),
+      worker: worker_id,
+    });
+    eta_chunk_started(chunk.name());
+
+    // we display the index, so we need to subtract 1 to get the max index, and
+    // the queue is empty when the chunks are received while they are created
+    let padding = printable_base10_digits(self.chunk_queue.len().saturating_sub(1)) as usize;
+
+    let max_tries = self.project.args.max_tries;
+    let checkpoint = self
+      .project
+      .args
+      .checkpoint_frames
+      .filter(|&frames| chunk.frames() > frames);
+    let result = match checkpoint {
+      // parts of a previous run are resumed
+      Some(frames) if checkpoint::parts_dir(chunk).exists() => {
+        self.encode_parts(chunk, frames, worker_id, padding)
+      }
+      // the whole chunk is only tried once, as it is encoded again in parts if it fails
+      Some(frames) => self
+        .encode_passes(chunk, worker_id, padding, 1)
+        .or_else(|e| {
+          if e.kind() == FailureKind::InvalidArgument {
+            return Err(e);
+          }
+          warn!(
+            "Encoder failed (on chunk {}), encoding it again in parts of at most {} frames:\n{}",
+            chunk.index, frames, e
+          );
+          self.encode_parts(chunk, frames, worker_id, padding)
+        }),
+      None => self.encode_passes(chunk, worker_id, padding, max_tries),
+    };
+    let mut fallback = None;
+    let mut pass_times = match result {
+      Ok(pass_times) => pass_times,
+      Err(e) => {
+        // other chunk methods would fail the same
+        let result = if e.kind() == FailureKind::InvalidArgument {
+          Err(e)
+        } else {
+          self.encode_fallback(chunk, worker_id, padding, e)
+        };
+        let (pass_times, method) = result.inspect_err(|e| {
+          error!(
+            "[chunk {}] encoder failed {} times, shutting down worker",
+            chunk.index, max_tries
+          );
+          match crash::write_report(chunk, e) {
+            Ok(report) => error!(
+              "[chunk {}] the crash can be reproduced with {}",
+              chunk.index,
+              report.display()
+            ),
+            Err(e) => warn!(
+              "[chunk {}] failed to write the crash report: {}",
+              chunk.index, e
+            ),
+          }
+        })?;
+        fallback = Some(method);
+        pass_times
+      }
+    };
+    if let Some(kbps) = self.project.args.max_chunk_bitrate.filter(|_| !chunk.skip) {
+      self.cap_bitrate(chunk, kbps, worker_id, padding, &mut pass_times)?;
     }
 
     let enc_time = st_time.elapsed();
     let fps = chunk.frames() as f64 / enc_time.as_secs_f64();
 
-    let progress_file = Path::new(&self.project.args.temp).join("done.json");
+    // run before the chunk is marked as done, so that it is handed off again on resume
+    // if av1an is interrupted in between
+    if !self.project.args.chunk_command.is_empty() {
+      self.run_chunk_command(chunk);
+    }
+
+    // a chunk whose output cannot be read is not done, so that it is encoded again
+    let size_bytes = retry_io(|| Path::new(&chunk.output()).metadata())
+      .map_err(|e| output_unreadable(chunk, &e))?
+      .len();
     get_done().done.insert(
       chunk.name(),
       DoneChunk {
         frames: chunk.frames(),
-        size_bytes: Path::new(&chunk.output())
-          .metadata()
-          .expect("Unable to get size of finished chunk")
-          .len(),
+        size_bytes,
+        pass_times: pass_times.clone(),
+        tq_cq: chunk.tq_cq,
+        mean_probe_score: target_quality::mean_probe_score(chunk),
+        fallback,
       },
     );
 
-    let mut progress_file = File::create(progress_file).unwrap();
-    progress_file
-      .write_all(serde_json::to_string(get_done()).unwrap().as_bytes())
-      .unwrap();
+    self.done_writer.request_save();
+    if !self.project.args.keep_chunk_logs {
+      remove_chunk_logs(chunk);
+    }
+    if let Some(progressive_concat) = self.progressive_concat {
+      // the output is concatenated completely at the end anyway
+      if let Err(e) = progressive_concat.request_update() {
+        warn!("Progressive concatenation failed: {:#}", e);
+      }
+    }
 
     update_progress_bar_estimates(
       chunk.frame_rate,
-      self.project.frames,
+      self.project.output_frames(),
       self.project.args.verbosity,
     );
 
     debug!(
-      "finished chunk {:05}: {} frames, {:.2} fps, took {:.2?}",
+      "finished chunk {:05}: {} frames, {:.2} fps, took {:.2?} ({})",
       chunk.index,
       chunk.frames(),
       fps,
-      enc_time
+      enc_time,
+      pass_times
+        .iter()
+        .enumerate()
+        .map(|(pass, secs)| format!("pass {}: {secs:.2}s", pass + 1))
+        .join(", ")
     );
+    logging::event(&Event::ChunkFinished {
+      chunk: chunk.index,
+      frames: chunk.frames(),
+      worker: worker_id,
+      seconds: enc_time.as_secs_f64(),
+      fps,
+      size_bytes,
+    });
 
     Ok(())
   }
 }
+
+/// Returns the error of a chunk whose parts could not be put together
+fn parts_failed(chunk: &Chunk, message: &str) -> Box<EncoderCrash> {
+  Box::new(EncoderCrash {
+    exit_status: ExitStatus::default(),
+    stdout: format!("CHECKPOINT FAILED: chunk {}: {message}", chunk.index).into(),
+    stderr: String::new().into(),
+    source_pipe_stderr: String::new().into(),
+    ffmpeg_pipe_stderr: None,
+    pipeline: Vec::new(),
+  })
+}
+
+/// Returns the error of a chunk whose output cannot be read once it is encoded
+fn output_unreadable(chunk: &Chunk, error: &std::io::Error) -> Box<EncoderCrash> {
+  Box::new(EncoderCrash {
+    exit_status: ExitStatus::default(),
+    stdout: format!(
+      "OUTPUT UNREADABLE: chunk {}: unable to get the size of {}: {error}",
+      chunk.index,
+      chunk.output()
+    )
+    .into(),
+    stderr: String::new().into(),
+    source_pipe_stderr: String::new().into(),
+    ffmpeg_pipe_stderr: None,
+    pipeline: Vec::new(),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn classifies_failures() {
+    let crash = |stderr: &str, source_pipe_stderr: &str| EncoderCrash {
+      exit_status: ExitStatus::default(),
+      stdout: String::new().into(),
+      stderr: stderr.to_owned().into(),
+      source_pipe_stderr: source_pipe_stderr.to_owned().into(),
+      ffmpeg_pipe_stderr: None,
+      pipeline: Vec::new(),
+    };
+
+    assert_eq!(
+      crash("x264 [error]: invalid argument: crf = abc", "").kind(),
+      FailureKind::InvalidArgument
+    );
+    assert_eq!(
+      crash("error: unexpected argument '--foo' found", "").kind(),
+      FailureKind::InvalidArgument
+    );
+    assert_eq!(
+      crash("Svt[error]: failed to allocate the picture buffers", "").kind(),
+      FailureKind::OutOfMemory
+    );
+    assert_eq!(
+      crash("", "[h264 @ 0x0] error while decoding MB 10 20").kind(),
+      FailureKind::Decode
+    );
+    assert_eq!(crash("Broken pipe", "").kind(), FailureKind::Decode);
+    assert_eq!(crash("", "").kind(), FailureKind::Other);
+
+    // failing system calls and other errors of the source are not specific enough
+    assert_eq!(
+      crash("Failed to write the output: Invalid argument", "").kind(),
+      FailureKind::Other
+    );
+    assert_eq!(
+      crash("", "Error: the script has no output node").kind(),
+      FailureKind::Other
+    );
+  }
+}
@@ -0,0 +1,235 @@
+//! Isolation of the processes spawned by av1an in a dedicated cgroup (v2).
+//!
+//! This is only supported on Linux. All encoder, ffmpeg and vspipe processes
+//! (including target quality probes and VMAF calculations) join the cgroup
+//! before they start executing, so the limits also apply to any processes
+//! they spawn themselves.
+
+use std::path::PathBuf;
+
+use once_cell::sync::OnceCell;
+
+use crate::ProcessPriority;
+
+static CGROUP: OnceCell<Cgroup> = OnceCell::new();
+
+#[derive(Debug)]
+pub struct Cgroup {
+  path: PathBuf,
+  /// Path of `cgroup.procs`, prepared in advance as it cannot be allocated between fork and exec
+  #[cfg(target_os = "linux")]
+  procs: std::ffi::CString,
+  /// Leaf cgroup av1an moved itself to, and the controllers it then enabled in the parent
+  #[cfg(target_os = "linux")]
+  leaf: Option<(PathBuf, Vec<&'static str>)>,
+}
+
+#[cfg(target_os = "linux")]
+impl Cgroup {
+  const ROOT: &'static str = "/sys/fs/cgroup";
+
+  /// Creates a cgroup for the processes spawned by av1an, nested in the
+  /// cgroup av1an was started in.
+  ///
+  /// `max_memory` sets `memory.max`, and `priority` sets `cpu.weight.nice`.
+  fn create(max_memory: Option<u64>, priority: Option<ProcessPriority>) -> anyhow::Result<Self> {
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    use anyhow::Context;
+
+    let own_cgroup = fs::read_to_string("/proc/self/cgroup")?;
+    let own_cgroup = own_cgroup
+      .lines()
+      .find_map(|line| line.strip_prefix("0::"))
+      .context("cgroup v2 (the unified hierarchy) is not mounted")?;
+    let parent = Path::new(Self::ROOT).join(own_cgroup.trim_start_matches('/'));
+
+    let mut controllers = Vec::new();
+    if max_memory.is_some() {
+      controllers.push("memory");
+    }
+    if priority.is_some() {
+      controllers.push("cpu");
+    }
+
+    let enabled = fs::read_to_string(parent.join("cgroup.subtree_control")).unwrap_or_default();
+    let missing: Vec<_> = controllers
+      .into_iter()
+      .filter(|controller| !enabled.split_whitespace().any(|c| c == *controller))
+      .collect();
+    let moved_to = if missing.is_empty() {
+      None
+    } else {
+      // Controllers can only be enabled for the children of a cgroup without processes of
+      // its own, so av1an first moves itself to a leaf cgroup
+      let leaf = parent.join("av1an-main");
+      crate::create_dir!(leaf)?;
+      fs::write(leaf.join("cgroup.procs"), std::process::id().to_string())
+        .and_then(|()| {
+          fs::write(
+            parent.join("cgroup.subtree_control"),
+            missing
+              .iter()
+              .map(|controller| format!("+{controller}"))
+              .collect::<Vec<_>>()
+              .join(" "),
+          )
+        })
+        .with_context(|| {
+          format!(
+            "Failed to enable the {} controller(s) in {}. The cgroup may need to be delegated to \
+             av1an, e.g. by running it with `systemd-run --user --scope -p Delegate=yes av1an ...`",
+            missing.join(", "),
+            parent.display()
+          )
+        })?;
+      Some((leaf, missing))
+    };
+
+    let path = parent.join(format!("av1an-{}", std::process::id()));
+    crate::create_dir!(path)?;
+
+    if let Some(max_memory) = max_memory {
+      fs::write(path.join("memory.max"), max_memory.to_string())
+        .context("Failed to set memory.max")?;
+    }
+    if let Some(priority) = priority {
+      fs::write(
+        path.join("cpu.weight.nice"),
+        priority.nice_value().to_string(),
+      )
+      .context("Failed to set cpu.weight.nice")?;
+    }
+
+    let procs = std::ffi::CString::new(path.join("cgroup.procs").as_os_str().as_bytes())?;
+
+    Ok(Self {
+      path,
+      procs,
+      leaf: moved_to,
+    })
+  }
+
+  /// Removes the cgroup, and the leaf cgroup av1an moved itself to, moving
+  /// av1an back to its parent
+  fn remove(&self) -> std::io::Result<()> {
+    use std::fs;
+
+    fs::remove_dir(&self.path)?;
+    if let Some((leaf, enabled)) = &self.leaf {
+      let parent = leaf.parent().unwrap();
+      // the parent can only hold processes again without the controllers for its children
+      fs::write(
+        parent.join("cgroup.subtree_control"),
+        enabled
+          .iter()
+          .map(|controller| format!("-{controller}"))
+          .collect::<Vec<_>>()
+          .join(" "),
+      )?;
+      fs::write(parent.join("cgroup.procs"), std::process::id().to_string())?;
+      fs::remove_dir(leaf)?;
+    }
+
+    Ok(())
+  }
+
+  /// Moves the calling process into the cgroup.
+  ///
+  /// Only async-signal-safe functions are called, so this is safe to use
+  /// between fork and exec.
+  fn join(&self) -> std::io::Result<()> {
+    // SAFETY: `procs` is a valid nul-terminated string, and the file descriptor is
+    // only used within this block
+    unsafe {
+      let fd = libc::open(self.procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+      if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+      }
+      // writing 0 moves the writing process
+      let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+      libc::close(fd);
+      if written != 1 {
+        return Err(std::io::Error::last_os_error());
+      }
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Cgroup {
+  fn create(_max_memory: Option<u64>, _priority: Option<ProcessPriority>) -> anyhow::Result<Self> {
+    anyhow::bail!("cgroups are only supported on Linux")
+  }
+
+  fn remove(&self) -> std::io::Result<()> {
+    std::fs::remove_dir(&self.path)
+  }
+}
+
+/// Creates the cgroup that spawned processes are placed in, unless it was
+/// already created by a previous encode
+pub fn init(max_memory: Option<u64>, priority: Option<ProcessPriority>) -> anyhow::Result<()> {
+  if CGROUP.get().is_some() {
+    return Ok(());
+  }
+
+  let cgroup = Cgroup::create(max_memory, priority)?;
+  debug!("created cgroup {}", cgroup.path.display());
+  CGROUP.set(cgroup).ok();
+  Ok(())
+}
+
+/// Removes the cgroup, once no spawned processes are running anymore
+pub fn remove() {
+  if let Some(cgroup) = CGROUP.get() {
+    if let Err(e) = cgroup.remove() {
+      warn!("Failed to remove cgroup {}: {}", cgroup.path.display(), e);
+    }
+  }
+}
+
+/// Removes the cgroup when dropped, so that it is also removed when an encode
+/// fails
+#[derive(Debug)]
+pub struct RemoveGuard;
+
+impl Drop for RemoveGuard {
+  fn drop(&mut self) {
+    remove();
+  }
+}
+
+/// Makes the command join the cgroup (if any) before it starts executing
+pub fn apply(command: &mut std::process::Command) {
+  #[cfg(target_os = "linux")]
+  if let Some(cgroup) = CGROUP.get() {
+    use std::os::unix::process::CommandExt;
+
+    // SAFETY: `Cgroup::join` is async-signal-safe
+    unsafe {
+      command.pre_exec(|| cgroup.join());
+    }
+  }
+
+  #[cfg(not(target_os = "linux"))]
+  let _ = command;
+}
+
+/// Makes the command join the cgroup (if any) before it starts executing
+pub fn apply_async(command: &mut tokio::process::Command) {
+  #[cfg(target_os = "linux")]
+  if let Some(cgroup) = CGROUP.get() {
+    // SAFETY: `Cgroup::join` is async-signal-safe
+    unsafe {
+      command.pre_exec(|| cgroup.join());
+    }
+  }
+
+  #[cfg(not(target_os = "linux"))]
+  let _ = command;
+}
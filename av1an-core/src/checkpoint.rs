@@ -0,0 +1,141 @@
+//! Checkpoints of long chunks.
+//!
+//! When the encoder crashes on a chunk longer than `--checkpoint-frames`, the
+//! chunk is encoded again as parts of at most that many frames, which are
+//! trimmed from the source of the chunk in the ffmpeg pipe. The parts that are
+//! encoded are recorded in the folder of the parts with the time of their
+//! passes, so that another crash, or resuming an interrupted encode, only
+//! encodes the remaining parts while the stats of the chunk still count all of
+//! them. Once all parts are encoded, they are concatenated into the output of
+//! the chunk.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context};
+
+use crate::chunk::{Chunk, ChunkPart};
+use crate::concat;
+use crate::util::retry_io;
+
+/// Returns the folder of the parts of a chunk
+pub fn parts_dir(chunk: &Chunk) -> PathBuf {
+  Path::new(&chunk.temp).join("parts").join(chunk.name())
+}
+
+fn done_path(dir: &Path) -> PathBuf {
+  dir.join("done.json")
+}
+
+/// Splits the frames of a chunk into parts of at most `max_frames` frames, of
+/// about the same length
+pub fn split(frames: usize, max_frames: usize) -> Vec<ChunkPart> {
+  let count = frames.div_ceil(max_frames.max(1)).max(1);
+  (0..count)
+    .map(|index| ChunkPart {
+      index,
+      start: frames * index / count,
+      end: frames * (index + 1) / count,
+    })
+    .collect()
+}
+
+/// Returns the time of each pass of the parts of a chunk that were encoded, by
+/// this run or by a previous one, by index of the part
+pub fn load_done(dir: &Path) -> BTreeMap<usize, Vec<f64>> {
+  fs::read_to_string(done_path(dir))
+    .ok()
+    .and_then(|contents| serde_json::from_str(&contents).ok())
+    .unwrap_or_default()
+}
+
+/// Records the parts of a chunk that are encoded, with the time of their passes
+pub fn save_done(dir: &Path, done: &BTreeMap<usize, Vec<f64>>) -> anyhow::Result<()> {
+  let path = done_path(dir);
+  // serializing a map of integers to numbers should never fail, so unwrap is OK
+  let contents = serde_json::to_string(done).unwrap();
+  retry_io(|| fs::write(&path, &contents))
+    .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Concatenates the encoded parts into the output of the chunk, and removes
+/// the parts
+pub fn concat_parts(chunk: &Chunk, parts: &[ChunkPart]) -> anyhow::Result<()> {
+  let dir = parts_dir(chunk);
+  let output = PathBuf::from(chunk.output());
+  let files: Vec<PathBuf> = parts
+    .iter()
+    .map(|&part| {
+      PathBuf::from(
+        Chunk {
+          part: Some(part),
+          ..chunk.clone()
+        }
+        .output(),
+      )
+    })
+    .collect();
+
+  if chunk.output_ext == "ivf" {
+    concat::ivf_files(&files, &output)?;
+  } else {
+    let mut command = Command::new("mkvmerge");
+    command.args(["-q", "-o"]).arg(&output);
+    for (index, file) in files.iter().enumerate() {
+      if index > 0 {
+        command.arg("+");
+      }
+      command.arg(file);
+    }
+    let result = command
+      .output()
+      .context("Failed to run mkvmerge to concatenate the parts")?;
+    // mkvmerge exits with 1 on warnings
+    if !matches!(result.status.code(), Some(0 | 1)) {
+      bail!(
+        "mkvmerge failed to concatenate the parts: {}",
+        String::from_utf8_lossy(&result.stdout)
+      );
+    }
+  }
+
+  if let Err(e) = fs::remove_dir_all(&dir) {
+    warn!("Failed to remove {}: {}", dir.display(), e);
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn checkpoint_parts() {
+    let parts = split(5000, 1000);
+    assert_eq!(parts.len(), 5);
+    assert_eq!((parts[0].start, parts[0].end), (0, 1000));
+    assert_eq!(parts[4].end, 5000);
+
+    let parts = split(2500, 1000);
+    assert_eq!(parts.len(), 3);
+    assert!(parts.windows(2).all(|w| w[0].end == w[1].start));
+    assert!(parts.iter().all(|part| part.end - part.start <= 1000));
+    assert_eq!(parts[2].end, 2500);
+
+    assert_eq!(split(10, 1000).len(), 1);
+  }
+
+  #[test]
+  fn checkpoint_done_record() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    assert!(load_done(dir).is_empty());
+
+    let done = BTreeMap::from([(0, vec![12.5, 40.0]), (2, vec![11.0, 38.5])]);
+    save_done(dir, &done).unwrap();
+    assert_eq!(load_done(dir), done);
+  }
+}
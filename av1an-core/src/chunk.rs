@@ -1,12 +1,14 @@
 use std::ffi::OsString;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use av1_grain::{generate_photon_noise_params, write_grain_table, NoiseGenArgs};
+use ffmpeg::format::Pixel;
 use serde::{Deserialize, Serialize};
 use tracing::debug;
 
 use crate::encoder::Encoder;
 use crate::settings::insert_noise_table_params;
+use crate::target_quality::ChunkTarget;
 use crate::Input;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -19,6 +21,9 @@ pub struct Chunk {
   pub start_frame: usize,
   // End frame is exclusive, i.e. the range of frames is `start_frame..end_frame`
   pub end_frame: usize,
+  /// Number of frames after converting to the frame rate of `--fps`
+  #[serde(default)]
+  pub output_frames: Option<usize>,
   pub frame_rate: f64,
   pub passes: u8,
   pub video_params: Vec<String>,
@@ -28,7 +33,73 @@ pub struct Chunk {
   /// Optional target quality CQ level
   #[serde(rename = "per_shot_target_quality_cq")]
   pub tq_cq: Option<u32>,
+  #[serde(default)]
+  pub target_quality: ChunkTarget,
   pub ignore_frame_mismatch: bool,
+  /// Pixel format of the zone of the chunk, if it is not the one of the encode
+  #[serde(default, skip_serializing_if = "Option::is_none", with = "pixel_name")]
+  pub pix_format: Option<Pixel>,
+  /// Part of the chunk that is encoded on its own, see `checkpoint`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub part: Option<ChunkPart>,
+  /// Whether the chunk is in a skip zone, and keeps the frames of the source
+  #[serde(default)]
+  pub skip: bool,
+  /// Strength of the `hqdn3d` filter denoising the frames of the zone of the
+  /// chunk
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub denoise: Option<f64>,
+}
+
+/// Serializes pixel formats by their ffmpeg name
+pub(crate) mod pixel_name {
+  use ffmpeg::format::pixel::Descriptor;
+  use ffmpeg::format::Pixel;
+  use serde::de::Error;
+  use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+  // serde passes the field by reference
+  #[allow(clippy::ref_option, clippy::trivially_copy_pass_by_ref)]
+  pub fn serialize<S: Serializer>(
+    format: &Option<Pixel>,
+    serializer: S,
+  ) -> Result<S::Ok, S::Error> {
+    format
+      .and_then(Pixel::descriptor)
+      .map(Descriptor::name)
+      .serialize(serializer)
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+  ) -> Result<Option<Pixel>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+      .map(|name| {
+        name
+          .parse()
+          .map_err(|_| D::Error::custom(format!("unknown pixel format {name}")))
+      })
+      .transpose()
+  }
+}
+
+/// Frames of a part of a chunk, relative to the first frame of the chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkPart {
+  pub index: usize,
+  pub start: usize,
+  /// Exclusive, like the end frame of chunks
+  pub end: usize,
+}
+
+impl ChunkPart {
+  /// Returns the ffmpeg filter that keeps the frames of the part
+  pub fn filter(&self) -> String {
+    format!(
+      "trim=start_frame={}:end_frame={},setpts=PTS-STARTPTS",
+      self.start, self.end
+    )
+  }
 }
 
 impl Chunk {
@@ -38,16 +109,56 @@ impl Chunk {
   }
 
   pub fn output(&self) -> String {
-    Path::new(&self.temp)
-      .join("encode")
-      .join(format!("{}.{}", self.name(), self.output_ext))
-      .to_str()
-      .unwrap()
-      .to_owned()
+    let temp = Path::new(&self.temp);
+    let path = self.part.map_or_else(
+      || {
+        temp
+          .join("encode")
+          .join(format!("{}.{}", self.name(), self.output_ext))
+      },
+      |part| {
+        temp
+          .join("parts")
+          .join(self.name())
+          .join(format!("{:03}.{}", part.index, self.output_ext))
+      },
+    );
+    path.to_str().unwrap().to_owned()
+  }
+
+  /// Returns the ffmpeg filter denoising the frames of the chunk, if its zone
+  /// denoises them
+  pub fn denoise_filter(&self) -> Option<String> {
+    self.denoise.map(|strength| format!("hqdn3d={strength}"))
+  }
+
+  /// Returns the first pass file of multi-pass encodes
+  pub fn fpf_file(&self) -> PathBuf {
+    let split = Path::new(&self.temp).join("split");
+    self.part.map_or_else(
+      || split.join(format!("{}_fpf", self.name())),
+      |part| split.join(format!("{}_{:03}_fpf", self.name(), part.index)),
+    )
   }
 
-  pub const fn frames(&self) -> usize {
-    self.end_frame - self.start_frame
+  /// Returns the file the output of the encoder and of the source pipe of a
+  /// pass is written to
+  pub fn log_file(&self, pass: u8) -> PathBuf {
+    let name = self.part.map_or_else(
+      || format!("chunk_{}_pass{pass}.log", self.name()),
+      |part| format!("chunk_{}_part{:03}_pass{pass}.log", self.name(), part.index),
+    );
+    Path::new(&self.temp).join("logs").join(name)
+  }
+
+  /// Returns the number of frames that are encoded
+  pub fn frames(&self) -> usize {
+    if let Some(part) = self.part {
+      return part.end - part.start;
+    }
+    self
+      .output_frames
+      .unwrap_or(self.end_frame - self.start_frame)
   }
 
   pub(crate) fn apply_photon_noise_args(
@@ -108,13 +219,19 @@ mod tests {
       output_ext: "ivf".to_owned(),
       start_frame: 0,
       end_frame: 5,
+      output_frames: None,
       frame_rate: 30.0,
       tq_cq: None,
+      target_quality: ChunkTarget::Inherit,
       passes: 1,
       video_params: vec![],
       encoder: Encoder::x264,
       noise_size: (None, None),
       ignore_frame_mismatch: false,
+      pix_format: None,
+      part: None,
+      skip: false,
+      denoise: None,
     };
     assert_eq!("00001", ch.name());
   }
@@ -130,13 +247,19 @@ mod tests {
       output_ext: "ivf".to_owned(),
       start_frame: 0,
       end_frame: 5,
+      output_frames: None,
       frame_rate: 30.0,
       tq_cq: None,
+      target_quality: ChunkTarget::Inherit,
       passes: 1,
       video_params: vec![],
       encoder: Encoder::x264,
       noise_size: (None, None),
       ignore_frame_mismatch: false,
+      pix_format: None,
+      part: None,
+      skip: false,
+      denoise: None,
     };
     assert_eq!("10000", ch.name());
   }
@@ -153,14 +276,37 @@ mod tests {
       output_ext: "ivf".to_owned(),
       start_frame: 0,
       end_frame: 5,
+      output_frames: None,
       frame_rate: 30.0,
       tq_cq: None,
+      target_quality: ChunkTarget::Inherit,
       passes: 1,
       video_params: vec![],
       encoder: Encoder::x264,
       noise_size: (None, None),
       ignore_frame_mismatch: false,
+      pix_format: None,
+      part: None,
+      skip: false,
+      denoise: None,
     };
     assert_eq!("d/encode/00001.ivf", ch.output());
+    assert_eq!(Path::new("d/logs/chunk_00001_pass2.log"), ch.log_file(2));
+
+    let part = Chunk {
+      part: Some(ChunkPart {
+        index: 2,
+        start: 2,
+        end: 4,
+      }),
+      ..ch
+    };
+    assert_eq!("d/parts/00001/002.ivf", part.output());
+    assert_eq!(Path::new("d/split/00001_002_fpf"), part.fpf_file());
+    assert_eq!(
+      Path::new("d/logs/chunk_00001_part002_pass1.log"),
+      part.log_file(1)
+    );
+    assert_eq!(2, part.frames());
   }
 }
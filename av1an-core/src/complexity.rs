@@ -0,0 +1,160 @@
+//! Quick consistency mode, which evens out the quality of the scenes without
+//! the probes of target quality.
+//!
+//! Every chunk is encoded once with the ultrafast preset of x264 at a fixed
+//! CRF. The bits per frame of these encodes are a cheap estimate of the
+//! complexity of the scenes, and the q of the chunks is lowered for complex
+//! scenes and raised for simple ones, relative to the median scene. The
+//! adjusted q is stored in the parameters of the chunks, so the proxy encodes
+//! are only done once per encode.
+
+use std::ffi::OsStr;
+use std::path::Path;
+use std::{fs, mem};
+
+use ffmpeg::format::Pixel;
+
+use crate::chunk::Chunk;
+use crate::ffmpeg::compose_ffmpeg_pipe;
+use crate::{create_dir, proxy, Encoder};
+
+/// CRF of the proxy encodes, which only needs to be in the usual range
+const PROXY_CRF: &str = "23";
+
+/// Estimates the complexity of each chunk as the bits per frame of a proxy
+/// encode with x264, in the order of `chunks`
+pub fn proxy_bitrates(
+  chunks: &[Chunk],
+  temp: &Path,
+  ffmpeg_filter_args: &[String],
+  workers: usize,
+) -> anyhow::Result<Vec<f64>> {
+  let proxy_dir = temp.join("proxy");
+  create_dir!(proxy_dir)?;
+
+  // x264 is not necessarily built with support for high bit depths
+  let ffmpeg_pipe = compose_ffmpeg_pipe(ffmpeg_filter_args, Pixel::YUV420P);
+  let bitrates = proxy::measure_chunks(chunks, workers, |chunk| {
+    proxy_bitrate(chunk, &proxy_dir, &ffmpeg_pipe)
+  })?;
+
+  if let Err(e) = fs::remove_dir_all(&proxy_dir) {
+    warn!("Failed to remove {}: {}", proxy_dir.display(), e);
+  }
+
+  Ok(bitrates)
+}
+
+/// Encodes a chunk with ultrafast x264, and returns the bits per frame
+fn proxy_bitrate(chunk: &Chunk, proxy_dir: &Path, ffmpeg_pipe: &[String]) -> anyhow::Result<f64> {
+  let output = proxy_dir.join(format!("{}.264", chunk.name()));
+  let x264: [&OsStr; 14] = [
+    "x264".as_ref(),
+    "--preset".as_ref(),
+    "ultrafast".as_ref(),
+    "--crf".as_ref(),
+    PROXY_CRF.as_ref(),
+    "--threads".as_ref(),
+    "1".as_ref(),
+    "--log-level".as_ref(),
+    "error".as_ref(),
+    "--demuxer".as_ref(),
+    "y4m".as_ref(),
+    "-o".as_ref(),
+    output.as_os_str(),
+    "-".as_ref(),
+  ];
+
+  let bitrate = proxy::bits_per_frame(chunk, ffmpeg_pipe, &x264, &output);
+  fs::remove_file(&output).ok();
+  bitrate
+}
+
+/// Returns the complexity of each chunk relative to the median chunk, as the
+/// binary logarithm of the ratio of their bits per frame
+pub fn relative_complexity(bitrates: &[f64]) -> Vec<f64> {
+  let log_bitrates: Vec<f64> = bitrates.iter().map(|b| b.max(1.0).log2()).collect();
+  let mut sorted = log_bitrates.clone();
+  sorted.sort_unstable_by(f64::total_cmp);
+  let Some(&median) = sorted.get(sorted.len() / 2) else {
+    return Vec::new();
+  };
+
+  log_bitrates.iter().map(|b| b - median).collect()
+}
+
+/// Returns the offset of q for a chunk of the given relative complexity.
+///
+/// Each doubling of the bitrate lowers q by a 20th of the usual q range of the
+/// encoder, up to an 8th of the range.
+pub fn q_offset(relative_complexity: f64, encoder: Encoder) -> i64 {
+  let (min_q, max_q) = encoder.get_default_cq_range();
+  let range = (max_q - min_q) as f64;
+  let max_offset = range / 8.0;
+
+  (-relative_complexity * range / 20.0)
+    .clamp(-max_offset, max_offset)
+    .round() as i64
+}
+
+/// Runs the proxy encodes, and adjusts the q in the parameters of the chunks
+pub fn apply(
+  chunks: &mut [Chunk],
+  temp: &Path,
+  ffmpeg_filter_args: &[String],
+  workers: usize,
+) -> anyhow::Result<()> {
+  info!(
+    "estimating the complexity of {} chunks with x264",
+    chunks.len()
+  );
+  let bitrates = proxy_bitrates(chunks, temp, ffmpeg_filter_args, workers)?;
+
+  for (chunk, relative) in chunks.iter_mut().zip(relative_complexity(&bitrates)) {
+    let Some(q) = chunk.encoder.get_q(&chunk.video_params) else {
+      warn!(
+        "chunk {} does not set the q of {} in its parameters, its q is not adjusted",
+        chunk.name(),
+        chunk.encoder
+      );
+      continue;
+    };
+
+    let offset = q_offset(relative, chunk.encoder);
+    let adjusted = (q as i64 + offset).clamp(0, proxy::max_q(chunk.encoder) as i64) as usize;
+    debug!(
+      "chunk {}: relative complexity {:+.2}, q {} -> {}",
+      chunk.name(),
+      relative,
+      q,
+      adjusted
+    );
+    chunk.video_params = chunk
+      .encoder
+      .man_command(mem::take(&mut chunk.video_params), adjusted);
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn complexity_q_offsets() {
+    let relative = relative_complexity(&[1000.0, 2000.0, 4000.0, 500.0, 2000.0]);
+    assert_eq!(relative, [-1.0, 0.0, 1.0, -2.0, 0.0]);
+
+    // aomenc has a q range of 40, so 2 per doubling, at most 5
+    let offsets: Vec<_> = relative
+      .iter()
+      .map(|&r| q_offset(r, Encoder::aom))
+      .collect();
+    assert_eq!(offsets, [2, 0, -2, 4, 0]);
+    assert_eq!(q_offset(-8.0, Encoder::aom), 5);
+    assert_eq!(q_offset(8.0, Encoder::x264), -3);
+
+    assert!(relative_complexity(&[]).is_empty());
+  }
+}
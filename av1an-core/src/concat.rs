@@ -1,14 +1,18 @@
 use std::fmt::{Display, Write as FmtWrite};
 use std::fs::{self, DirEntry, File};
-use std::io::Write;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::mem;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, ensure, Context};
 use av_format::buffer::AccReader;
 use av_format::demuxer::{Context as DemuxerContext, Event};
 use av_format::muxer::{Context as MuxerContext, Writer};
+use av_format::rational::Rational64;
 use av_ivf::demuxer::IvfDemuxer;
 use av_ivf::muxer::IvfMuxer;
 use path_abs::{PathAbs, PathInfo};
@@ -16,7 +20,8 @@ use serde::{Deserialize, Serialize};
 use tracing::{debug, error, warn};
 
 use crate::encoder::Encoder;
-use crate::util::read_in_dir;
+use crate::get_done;
+use crate::util::{read_in_dir, retry_io};
 
 #[derive(
   PartialEq, Eq, Copy, Clone, Serialize, Deserialize, Debug, strum::EnumString, strum::IntoStaticStr,
@@ -50,40 +55,92 @@ pub fn sort_files_by_filename(files: &mut [PathBuf]) {
   });
 }
 
+/// Concatenates the IVF chunks of `input` into `out`.
 #[tracing::instrument]
 pub fn ivf(input: &Path, out: &Path) -> anyhow::Result<()> {
   let mut files: Vec<PathBuf> = read_in_dir(input)?.collect();
 
   sort_files_by_filename(&mut files);
 
-  assert!(!files.is_empty());
+  ensure!(
+    !files.is_empty(),
+    "{} has no chunks to concatenate",
+    input.display()
+  );
+
+  ivf_files(&files, out)
+}
+
+/// Concatenates the IVF `files` into `out`, in order.
+///
+/// The timestamps of each chunk are shifted to follow those of the previous
+/// chunk, VP9 frames that are not shown are grouped with the next shown frame
+/// into a superframe, so that each packet is one frame, and the chunks are
+/// checked to use the same codec and frame rate, and to start with a keyframe.
+#[tracing::instrument]
+pub fn ivf_files(files: &[PathBuf], out: &Path) -> anyhow::Result<()> {
+  ensure!(!files.is_empty(), "there are no chunks to concatenate");
+
+  let headers = files
+    .iter()
+    .map(|file| IvfHeader::read(file))
+    .collect::<anyhow::Result<Vec<_>>>()?;
+  let first = headers[0];
+  for (file, header) in files.iter().zip(&headers).skip(1) {
+    ensure!(
+      header.fourcc == first.fourcc,
+      "{} is {}, but {} is {}, which cannot be concatenated",
+      file.display(),
+      String::from_utf8_lossy(&header.fourcc),
+      files[0].display(),
+      String::from_utf8_lossy(&first.fourcc)
+    );
+    ensure!(
+      u64::from(header.rate) * u64::from(first.scale)
+        == u64::from(first.rate) * u64::from(header.scale),
+      "{} has a frame rate of {}/{}, but {} has {}/{}",
+      file.display(),
+      header.rate,
+      header.scale,
+      files[0].display(),
+      first.rate,
+      first.scale
+    );
+    if (header.width, header.height) != (first.width, first.height) {
+      warn!(
+        "{} is {}x{}, but {} is {}x{}, the IVF header of the output has the size of the first \
+         chunk",
+        file.display(),
+        header.width,
+        header.height,
+        files[0].display(),
+        first.width,
+        first.height
+      );
+    }
+  }
 
   let output = File::create(out)?;
 
   let mut muxer = MuxerContext::new(IvfMuxer::new(), Writer::new(output));
 
   let global_info = {
-    let acc = AccReader::new(std::fs::File::open(&files[0]).unwrap());
+    let acc = AccReader::new(
+      retry_io(|| File::open(&files[0]))
+        .with_context(|| format!("Failed to open {}", files[0].display()))?,
+    );
     let mut demuxer = DemuxerContext::new(IvfDemuxer::new(), acc);
 
-    demuxer.read_headers().unwrap();
-
-    // attempt to set the duration correctly
-    let duration = demuxer.info.duration.unwrap_or(0)
-      + files
-        .iter()
-        .skip(1)
-        .filter_map(|file| {
-          let acc = AccReader::new(std::fs::File::open(file).unwrap());
-          let mut demuxer = DemuxerContext::new(IvfDemuxer::new(), acc);
-
-          demuxer.read_headers().unwrap();
-          demuxer.info.duration
-        })
-        .sum::<u64>();
+    demuxer.read_headers()?;
 
     let mut info = demuxer.info;
-    info.duration = Some(duration);
+    // the muxer writes the frame rate of the timebase, which the demuxer does not set
+    if first.rate > 0 && first.scale > 0 {
+      info.timebase = Some(Rational64::new(
+        i64::from(first.scale),
+        i64::from(first.rate),
+      ));
+    }
     info
   };
 
@@ -92,10 +149,12 @@ pub fn ivf(input: &Path, out: &Path) -> anyhow::Result<()> {
   muxer.configure()?;
   muxer.write_header()?;
 
-  let mut pos_offset: usize = 0;
-  for file in &files {
-    let mut last_pos: usize = 0;
-    let input = std::fs::File::open(file)?;
+  let mut first_config: Option<StreamConfig> = None;
+  let mut next_pts = 0;
+  let mut packets: u32 = 0;
+  for file in files {
+    let input = retry_io(|| File::open(file))
+      .with_context(|| format!("Failed to open {}", file.display()))?;
 
     let acc = AccReader::new(input);
 
@@ -104,19 +163,49 @@ pub fn ivf(input: &Path, out: &Path) -> anyhow::Result<()> {
 
     trace!("global info: {:#?}", demuxer.info);
 
+    let mut timestamps = Timestamps::new(next_pts);
+    // VP9 frames that are not shown yet, which are put in the next superframe
+    let mut hidden: Vec<Vec<u8>> = Vec::new();
+    let mut first_packet = true;
+
     loop {
       match demuxer.read_event() {
         Ok(event) => match event {
           Event::MoreDataNeeded(sz) => panic!("needed more data: {sz} bytes"),
           Event::NewStream(s) => panic!("new stream: {s:?}"),
           Event::NewPacket(mut packet) => {
-            if let Some(p) = packet.pos.as_mut() {
-              last_pos = *p;
-              *p += pos_offset;
+            if first_packet {
+              first_packet = false;
+              let config = StreamConfig::of(first.fourcc, &packet.data)
+                .with_context(|| format!("{} does not start with a keyframe", file.display()))?;
+              match &first_config {
+                Some(first_config) if *first_config != config => warn!(
+                  "{} has a different sequence header than the first chunk, decoders that do not \
+                   support changes of the sequence header within a stream may fail to decode \
+                   the output",
+                  file.display()
+                ),
+                Some(_) => {}
+                None => first_config = Some(config),
+              }
+            }
+
+            if &first.fourcc == b"VP90" {
+              let frames = vp9_frames(&packet.data);
+              let shown = frames.iter().any(|frame| vp9_frame_is_shown(frame));
+              hidden.extend(frames.into_iter().map(<[u8]>::to_vec));
+              if !shown {
+                continue;
+              }
+              packet.data = vp9_superframe(&mem::take(&mut hidden))?;
             }
 
+            let pts = timestamps.rewrite(packet.pos.unwrap_or_default() as u64);
+            packet.pos = Some(pts as usize);
+
             trace!("received packet with pos: {:?}", packet.pos);
             muxer.write_packet(Arc::new(packet))?;
+            packets += 1;
           }
           Event::Continue => continue,
           Event::Eof => {
@@ -131,14 +220,333 @@ pub fn ivf(input: &Path, out: &Path) -> anyhow::Result<()> {
         }
       }
     }
-    pos_offset += last_pos + 1;
+
+    if !hidden.is_empty() {
+      warn!(
+        "{} ends with {} VP9 frames that are never shown, which are dropped",
+        file.display(),
+        hidden.len()
+      );
+    }
+    next_pts = timestamps.end();
   }
 
   muxer.write_trailer()?;
+  drop(muxer);
+
+  // the number of frames is only known once they are written, as VP9 frames
+  // may have been joined into superframes
+  let mut output = File::options().write(true).open(out)?;
+  output.seek(SeekFrom::Start(24))?;
+  output.write_all(&packets.to_le_bytes())?;
 
   Ok(())
 }
 
+/// Header of an IVF file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IvfHeader {
+  fourcc: [u8; 4],
+  width: u16,
+  height: u16,
+  /// Frame rate is `rate / scale`, which is also the timebase of the timestamps
+  rate: u32,
+  scale: u32,
+}
+
+impl IvfHeader {
+  fn read(path: &Path) -> anyhow::Result<Self> {
+    let mut header = [0; 32];
+    retry_io(|| File::open(path))
+      .and_then(|mut file| file.read_exact(&mut header))
+      .with_context(|| format!("Failed to read the IVF header of {}", path.display()))?;
+    Self::parse(&header).with_context(|| format!("{} is not an IVF file", path.display()))
+  }
+
+  fn parse(header: &[u8; 32]) -> Option<Self> {
+    let u16_at = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+    (&header[..4] == b"DKIF").then(|| Self {
+      fourcc: header[8..12].try_into().unwrap(),
+      width: u16_at(12),
+      height: u16_at(14),
+      rate: u32_at(16),
+      scale: u32_at(20),
+    })
+  }
+}
+
+/// Rewrites the timestamps of the packets of a chunk, so that they start where
+/// the previous chunk ended and always increase
+#[derive(Debug)]
+struct Timestamps {
+  start: u64,
+  /// Timestamp of the first packet of the chunk
+  first: Option<u64>,
+  /// Last timestamp of the chunk, and the rewritten one
+  last: Option<(u64, u64)>,
+  /// Shortest duration of a frame of the chunk
+  step: Option<u64>,
+}
+
+impl Timestamps {
+  const fn new(start: u64) -> Self {
+    Self {
+      start,
+      first: None,
+      last: None,
+      step: None,
+    }
+  }
+
+  fn rewrite(&mut self, pts: u64) -> u64 {
+    let first = *self.first.get_or_insert(pts);
+    let mut rewritten = self.start + pts.saturating_sub(first);
+    if let Some((last, last_rewritten)) = self.last {
+      if pts > last {
+        self.step = Some(self.step.map_or(pts - last, |step| step.min(pts - last)));
+      }
+      rewritten = rewritten.max(last_rewritten + 1);
+    }
+    self.last = Some((pts, rewritten));
+    rewritten
+  }
+
+  /// Timestamp of the first packet of the next chunk
+  fn end(&self) -> u64 {
+    self.last.map_or(self.start, |(_, last_rewritten)| {
+      last_rewritten + self.step.unwrap_or(1)
+    })
+  }
+}
+
+/// What decoders are configured with at the start of a chunk, which should be
+/// the same for all chunks
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum StreamConfig {
+  /// Payload of the sequence header OBU
+  Av1(Vec<u8>),
+  Vp9(Vp9ColorConfig),
+  Vp8,
+}
+
+impl StreamConfig {
+  /// Returns the configuration of the keyframe in the first packet of a chunk,
+  /// or `None` if it does not start with a keyframe
+  fn of(fourcc: [u8; 4], packet: &[u8]) -> Option<Self> {
+    match &fourcc {
+      b"AV01" => av1_sequence_header(packet).map(|header| Self::Av1(header.to_vec())),
+      b"VP90" => vp9_color_config(vp9_frames(packet)[0]).map(Self::Vp9),
+      // the frame type is the lowest bit of the frame tag, 0 for keyframes
+      b"VP80" => packet
+        .first()
+        .is_some_and(|tag| tag & 1 == 0)
+        .then_some(Self::Vp8),
+      _ => Some(Self::Vp8),
+    }
+  }
+}
+
+/// Returns the payload of the first sequence header OBU of a temporal unit
+fn av1_sequence_header(mut data: &[u8]) -> Option<&[u8]> {
+  const OBU_SEQUENCE_HEADER: u8 = 1;
+
+  while let Some((&header, rest)) = data.split_first() {
+    let obu_type = (header >> 3) & 0xf;
+    let has_extension = header & 0b100 != 0;
+    let has_size = header & 0b10 != 0;
+    let rest = if has_extension { rest.get(1..)? } else { rest };
+    let (size, rest) = if has_size {
+      leb128(rest)?
+    } else {
+      (rest.len(), rest)
+    };
+    let payload = rest.get(..size)?;
+    if obu_type == OBU_SEQUENCE_HEADER {
+      return Some(payload);
+    }
+    data = &rest[size..];
+  }
+  None
+}
+
+/// Reads an unsigned LEB128 value, returning it and the rest of `data`
+fn leb128(data: &[u8]) -> Option<(usize, &[u8])> {
+  let mut value = 0;
+  for (i, &byte) in data.iter().enumerate().take(8) {
+    value |= usize::from(byte & 0x7f) << (i * 7);
+    if byte & 0x80 == 0 {
+      return Some((value, &data[i + 1..]));
+    }
+  }
+  None
+}
+
+/// Splits a VP9 superframe into its frames, or returns the packet if it is a
+/// single frame
+fn vp9_frames(data: &[u8]) -> Vec<&[u8]> {
+  let Some(&marker) = data.last() else {
+    return vec![data];
+  };
+  if marker & 0xe0 != 0xc0 {
+    return vec![data];
+  }
+  let frames = usize::from(marker & 0x7) + 1;
+  let mag = usize::from((marker >> 3) & 0x3) + 1;
+  let index_size = 2 + mag * frames;
+  if data.len() < index_size || data[data.len() - index_size] != marker {
+    return vec![data];
+  }
+
+  let index = &data[data.len() - index_size + 1..data.len() - 1];
+  let mut split = Vec::with_capacity(frames);
+  let mut rest = &data[..data.len() - index_size];
+  for size in index.chunks_exact(mag) {
+    let size = size
+      .iter()
+      .rev()
+      .fold(0, |size, &byte| (size << 8) | usize::from(byte));
+    if size > rest.len() {
+      return vec![data];
+    }
+    let (frame, tail) = rest.split_at(size);
+    split.push(frame);
+    rest = tail;
+  }
+  split
+}
+
+/// Joins VP9 frames into a superframe, which is a single frame as is
+fn vp9_superframe(frames: &[Vec<u8>]) -> anyhow::Result<Vec<u8>> {
+  if let [frame] = frames {
+    return Ok(frame.clone());
+  }
+  ensure!(
+    (1..=8).contains(&frames.len()),
+    "{} VP9 frames cannot be put in a superframe, which holds at most 8",
+    frames.len()
+  );
+
+  let largest = frames.iter().map(Vec::len).max().unwrap_or_default();
+  let mag = (1..=4)
+    .find(|&mag| largest < 1 << (8 * mag))
+    .context("VP9 frame too large for a superframe")?;
+  let marker = 0xc0 | ((mag - 1) << 3) as u8 | (frames.len() - 1) as u8;
+
+  let mut superframe = frames.concat();
+  superframe.push(marker);
+  for frame in frames {
+    superframe.extend_from_slice(&frame.len().to_le_bytes()[..mag]);
+  }
+  superframe.push(marker);
+  Ok(superframe)
+}
+
+/// Reads the bits of the uncompressed header of a VP9 frame
+struct BitReader<'a> {
+  data: &'a [u8],
+  pos: usize,
+}
+
+impl BitReader<'_> {
+  fn read(&mut self, bits: usize) -> Option<u32> {
+    let mut value = 0;
+    for _ in 0..bits {
+      let byte = self.data.get(self.pos / 8)?;
+      value = (value << 1) | u32::from((byte >> (7 - self.pos % 8)) & 1);
+      self.pos += 1;
+    }
+    Some(value)
+  }
+}
+
+/// Color configuration of a VP9 keyframe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Vp9ColorConfig {
+  profile: u32,
+  bit_depth: u32,
+  color_space: u32,
+  color_range: u32,
+  subsampling: (u32, u32),
+}
+
+/// Reads the profile and frame type of a VP9 frame, returning the reader
+/// positioned after them, or `None` if it shows an existing frame, which is
+/// shown but not a keyframe
+fn vp9_frame_header(frame: &[u8]) -> Option<(u32, u32, BitReader<'_>)> {
+  let mut reader = BitReader {
+    data: frame,
+    pos: 0,
+  };
+  if reader.read(2)? != 2 {
+    return None;
+  }
+  let low = reader.read(1)?;
+  let profile = (reader.read(1)? << 1) | low;
+  if profile == 3 {
+    reader.read(1)?;
+  }
+  if reader.read(1)? == 1 {
+    return None;
+  }
+  let frame_type = reader.read(1)?;
+  Some((profile, frame_type, reader))
+}
+
+/// Whether a VP9 frame is shown, instead of only being used as a reference.
+/// Frames that cannot be parsed are assumed to be shown.
+fn vp9_frame_is_shown(frame: &[u8]) -> bool {
+  vp9_frame_header(frame)
+    .and_then(|(.., mut reader)| reader.read(1))
+    .map_or(true, |show_frame| show_frame == 1)
+}
+
+/// Returns the color configuration of a VP9 keyframe, or `None` if the frame is
+/// not a keyframe
+fn vp9_color_config(frame: &[u8]) -> Option<Vp9ColorConfig> {
+  const KEY_FRAME: u32 = 0;
+  const SYNC_CODE: u32 = 0x49_83_42;
+  const CS_RGB: u32 = 7;
+
+  let (profile, frame_type, mut reader) = vp9_frame_header(frame)?;
+  if frame_type != KEY_FRAME {
+    return None;
+  }
+  // show_frame and error_resilient_mode
+  reader.read(2)?;
+  if reader.read(24)? != SYNC_CODE {
+    return None;
+  }
+  let bit_depth = if profile >= 2 {
+    if reader.read(1)? == 1 {
+      12
+    } else {
+      10
+    }
+  } else {
+    8
+  };
+  let color_space = reader.read(3)?;
+  let (color_range, subsampling) = if color_space == CS_RGB {
+    (1, (0, 0))
+  } else {
+    let color_range = reader.read(1)?;
+    let subsampling = if profile % 2 == 1 {
+      (reader.read(1)?, reader.read(1)?)
+    } else {
+      (1, 1)
+    };
+    (color_range, subsampling)
+  };
+  Some(Vp9ColorConfig {
+    profile,
+    bit_depth,
+    color_space,
+    color_range,
+    subsampling,
+  })
+}
+
 #[tracing::instrument]
 fn read_encoded_chunks(encode_dir: &Path) -> anyhow::Result<Vec<DirEntry>> {
   Ok(
@@ -148,12 +556,16 @@ fn read_encoded_chunks(encode_dir: &Path) -> anyhow::Result<Vec<DirEntry>> {
   )
 }
 
+/// Concatenates the files `00000.<extension>`, `00001.<extension>`, ... in
+/// `encode_dir` with the audio of the temporary folder, if there is any
 #[tracing::instrument]
 pub fn mkvmerge(
   temp_dir: &Path,
+  encode_dir: &Path,
   output: &Path,
-  encoder: Encoder,
+  extension: &str,
   num_chunks: usize,
+  timestamps: Option<&Path>,
 ) -> anyhow::Result<()> {
   // mkvmerge does not accept UNC paths on Windows
   #[cfg(windows)]
@@ -186,27 +598,26 @@ pub fn mkvmerge(
     None
   };
 
-  let mut encode_dir = PathBuf::from(temp_dir);
-  encode_dir.push("encode");
-
   let output = PathAbs::new(output)?;
 
   assert!(num_chunks != 0);
 
   let options_path = PathBuf::from(&temp_dir).join("options.json");
+  let timestamps = timestamps.map(PathAbs::new).transpose()?.map(fix_path);
   let options_json_contents = mkvmerge_options_json(
     num_chunks,
-    encoder,
+    extension,
     &fix_path(output.to_str().unwrap()),
     audio_file.as_deref(),
+    timestamps.as_deref(),
   );
 
-  let mut options_json = File::create(options_path)?;
+  let mut options_json = File::create(&options_path)?;
   options_json.write_all(options_json_contents.as_bytes())?;
 
   let mut cmd = Command::new("mkvmerge");
-  cmd.current_dir(&encode_dir);
-  cmd.arg("@../options.json");
+  cmd.current_dir(encode_dir);
+  cmd.arg(format!("@{}", fix_path(PathAbs::new(options_path)?)));
 
   let out = cmd
     .output()
@@ -226,35 +637,106 @@ pub fn mkvmerge(
 }
 
 /// Create mkvmerge options.json
+///
+/// With the folder of the timestamp files of the chunks, each chunk is
+/// appended with its own timestamps rather than concatenated as a whole.
 #[tracing::instrument]
 pub fn mkvmerge_options_json(
   num: usize,
-  encoder: Encoder,
+  extension: &str,
   output: &str,
   audio: Option<&str>,
+  timestamps: Option<&str>,
 ) -> String {
   let mut file_string = String::with_capacity(64 + 12 * num);
   write!(file_string, "[\"-o\", {output:?}").unwrap();
   if let Some(audio) = audio {
     write!(file_string, ", {audio:?}").unwrap();
   }
+  if let Some(timestamps) = timestamps {
+    for i in 0..num {
+      let append = if i == 0 { "" } else { "+" };
+      let timestamp_file = format!("0:{timestamps}/{i:05}.txt");
+      write!(
+        file_string,
+        ", \"--timestamps\", {timestamp_file:?}, \"{append}{i:05}.{extension}\""
+      )
+      .unwrap();
+    }
+    file_string.push(']');
+    return file_string;
+  }
   file_string.push_str(", \"[\"");
   for i in 0..num {
-    write!(file_string, ", \"{i:05}.{}\"", encoder.output_extension()).unwrap();
+    write!(file_string, ", \"{i:05}.{extension}\"").unwrap();
   }
   file_string.push_str(",\"]\"]");
 
   file_string
 }
 
-/// Concatenates using ffmpeg (does not work with x265)
+/// Whether the output is an MP4 (or QuickTime) file
+pub fn is_mp4(output: &Path) -> bool {
+  output.extension().is_some_and(|ext| {
+    ["mp4", "m4v", "mov"]
+      .iter()
+      .any(|mp4| ext.eq_ignore_ascii_case(mp4))
+  })
+}
+
+/// Arguments of ffmpeg to write MP4 files that can be played while they are
+/// downloaded, with the codec configuration box that players expect
+fn mp4_args(encoder: Encoder) -> Vec<&'static str> {
+  let mut args = vec!["-movflags", "+faststart"];
+  match encoder {
+    // hvc1 stores the parameter sets in the hvcC box only, which Apple players require
+    Encoder::x265 => args.extend(["-tag:v", "hvc1"]),
+    // av01 with the av1C box from the sequence header of the chunks
+    Encoder::aom | Encoder::rav1e | Encoder::svt_av1 => args.extend(["-tag:v", "av01"]),
+    Encoder::vpx | Encoder::x264 => {}
+  }
+  args
+}
+
+/// Copies the video and audio of a Matroska file into an MP4 output
+pub fn remux_mp4(input: &Path, output: &Path, encoder: Encoder) -> anyhow::Result<()> {
+  let mut cmd = Command::new("ffmpeg");
+  cmd
+    .args(["-y", "-hide_banner", "-loglevel", "error", "-i"])
+    .arg(input)
+    .args(["-map", "0:v", "-map", "0:a?", "-c", "copy"])
+    .args(mp4_args(encoder))
+    .arg(output);
+
+  debug!("FFmpeg MP4 remux command: {:?}", cmd);
+
+  let out = cmd
+    .output()
+    .with_context(|| "Failed to execute FFmpeg command for remuxing into MP4")?;
+  if !out.status.success() {
+    error!(
+      "FFmpeg remuxing into MP4 failed with output: {:#?}\ncommand: {:?}",
+      out, cmd
+    );
+    return Err(anyhow!("FFmpeg remuxing into MP4 failed"));
+  }
+
+  Ok(())
+}
+
+/// Concatenates the files in `encode_dir` using ffmpeg, with the audio of the
+/// temporary folder if there is any (does not work with x265)
 #[tracing::instrument]
-pub fn ffmpeg(temp: &Path, output: &Path) -> anyhow::Result<()> {
-  fn write_concat_file(temp_folder: &Path) -> anyhow::Result<()> {
+pub fn ffmpeg(
+  temp: &Path,
+  encode_dir: &Path,
+  output: &Path,
+  encoder: Encoder,
+) -> anyhow::Result<()> {
+  fn write_concat_file(temp_folder: &Path, encode_folder: &Path) -> anyhow::Result<()> {
     let concat_file = temp_folder.join("concat");
-    let encode_folder = temp_folder.join("encode");
 
-    let mut files = read_encoded_chunks(&encode_folder)?;
+    let mut files = read_encoded_chunks(encode_folder)?;
 
     files.sort_by_key(DirEntry::path);
 
@@ -283,7 +765,7 @@ pub fn ffmpeg(temp: &Path, output: &Path) -> anyhow::Result<()> {
   let concat = temp.join("concat");
   let concat_file = concat.to_str().unwrap();
 
-  write_concat_file(temp)?;
+  write_concat_file(temp, PathAbs::new(encode_dir)?.as_path())?;
 
   let audio_file = {
     let file = temp.join("audio.mkv");
@@ -294,6 +776,21 @@ pub fn ffmpeg(temp: &Path, output: &Path) -> anyhow::Result<()> {
     }
   };
 
+  let mp4 = is_mp4(output);
+  let muxer_args = if mp4 {
+    mp4_args(encoder)
+  } else if output
+    .extension()
+    .is_some_and(|ext| ext.eq_ignore_ascii_case("webm"))
+  {
+    // moves the cues to the front, so that players can seek without reading the end first
+    vec!["-cues_to_front", "1"]
+  } else {
+    Vec::new()
+  };
+  // MP4 only supports the mov_text subtitles, and no attachments
+  let audio_map = if mp4 { "1:a" } else { "1" };
+
   let mut cmd = Command::new("ffmpeg");
 
   cmd.stdout(Stdio::piped());
@@ -315,7 +812,8 @@ pub fn ffmpeg(temp: &Path, output: &Path) -> anyhow::Result<()> {
         "-i",
       ])
       .arg(file)
-      .args(["-map", "0", "-map", "1", "-c", "copy"])
+      .args(["-map", "0", "-map", audio_map, "-c", "copy"])
+      .args(&muxer_args)
       .arg(output);
   } else {
     cmd
@@ -332,6 +830,7 @@ pub fn ffmpeg(temp: &Path, output: &Path) -> anyhow::Result<()> {
         concat_file,
       ])
       .args(["-map", "0", "-c", "copy"])
+      .args(&muxer_args)
       .arg(output);
   }
 
@@ -351,3 +850,401 @@ pub fn ffmpeg(temp: &Path, output: &Path) -> anyhow::Result<()> {
 
   Ok(())
 }
+
+/// Concatenation planned at the end of an encode, which is saved in the
+/// temporary folder, so that `av1an concat` can retry it if it fails.
+///
+/// The paths within the temporary folder are relative to it, so that the
+/// folder can be moved before retrying, while the output is absolute, so that
+/// the retry does not depend on the working directory.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConcatPlan {
+  pub method: ConcatMethod,
+  pub output: PathBuf,
+  /// Folder of the chunks to concatenate
+  pub encode_dir: PathBuf,
+  pub extension: String,
+  pub num_files: usize,
+  pub encoder: Encoder,
+  /// Timestamps of the chunks of a variable frame rate source, for mkvmerge
+  pub timestamps: Option<PathBuf>,
+  /// Frame rate to inject the Dolby Vision RPU at, if `--dolby-vision` is used
+  pub dolby_vision: Option<f64>,
+}
+
+impl ConcatPlan {
+  pub fn path(temp: &Path) -> PathBuf {
+    temp.join("concat.json")
+  }
+
+  /// Writes the plan into the temporary folder
+  pub fn save(&self, temp: &Path) -> anyhow::Result<()> {
+    let relative = |path: &Path| path.strip_prefix(temp).unwrap_or(path).to_path_buf();
+    let plan = Self {
+      output: std::path::absolute(&self.output)
+        .with_context(|| format!("Failed to resolve {}", self.output.display()))?,
+      encode_dir: relative(&self.encode_dir),
+      timestamps: self.timestamps.as_deref().map(relative),
+      ..self.clone()
+    };
+    fs::write(Self::path(temp), serde_json::to_string_pretty(&plan)?)
+      .with_context(|| format!("Failed to write {}", Self::path(temp).display()))
+  }
+
+  /// Reads the plan saved by an encode in the temporary folder
+  pub fn load(temp: &Path) -> anyhow::Result<Self> {
+    let path = Self::path(temp);
+    let plan: Self = serde_json::from_str(
+      &fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?,
+    )
+    .with_context(|| format!("Failed to parse {}", path.display()))?;
+    Ok(Self {
+      encode_dir: temp.join(&plan.encode_dir),
+      timestamps: plan
+        .timestamps
+        .as_ref()
+        .map(|timestamps| temp.join(timestamps)),
+      ..plan
+    })
+  }
+
+  /// Concatenates the chunks and the audio in the temporary folder into the
+  /// output
+  pub fn run(&self, temp: &Path) -> anyhow::Result<()> {
+    match self.method {
+      ConcatMethod::Ivf => ivf(&self.encode_dir, &self.output)?,
+      ConcatMethod::MKVMerge => {
+        // mkvmerge only writes Matroska, which is then remuxed into MP4 outputs
+        let mkv_output = if is_mp4(&self.output) {
+          temp.join("concat.mkv")
+        } else {
+          self.output.clone()
+        };
+        mkvmerge(
+          temp,
+          &self.encode_dir,
+          &mkv_output,
+          &self.extension,
+          self.num_files,
+          self.timestamps.as_deref(),
+        )?;
+        if mkv_output != self.output {
+          remux_mp4(&mkv_output, &self.output, self.encoder)?;
+        }
+      }
+      ConcatMethod::FFmpeg => ffmpeg(temp, &self.encode_dir, &self.output, self.encoder)?,
+    }
+
+    if let Some(frame_rate) = self.dolby_vision {
+      crate::dovi::inject_rpu(temp, &temp.join("RPU.bin"), &self.output, frame_rate)
+        .context("Failed to inject the Dolby Vision RPU into the output")?;
+    }
+
+    Ok(())
+  }
+}
+
+/// Concatenates the finished chunks at the start of the video into the output
+/// while the encode is still running, so that the start of a long encode can be
+/// previewed.
+///
+/// Each update links the newly finished chunks into `progressive/` of the
+/// temporary folder, concatenates them along with the audio (once it is
+/// encoded) next to the output, and then replaces the output. The final
+/// concatenation overwrites the output as usual.
+#[derive(Debug)]
+pub struct ProgressiveConcat {
+  tx: parking_lot::Mutex<Option<crossbeam_channel::Sender<()>>>,
+  handle: parking_lot::Mutex<Option<JoinHandle<()>>>,
+}
+
+impl ProgressiveConcat {
+  /// Minimum time between updates, as each concatenates all finished chunks again
+  const INTERVAL: Duration = Duration::from_secs(30);
+
+  pub fn spawn(temp: PathBuf, output: PathBuf, method: ConcatMethod, encoder: Encoder) -> Self {
+    let (tx, rx) = crossbeam_channel::unbounded::<()>();
+
+    let handle = thread::spawn(move || {
+      let mut concatenated = 0;
+      let mut last_update: Option<Instant> = None;
+      while rx.recv().is_ok() {
+        if let Some(last_update) = last_update {
+          let deadline = last_update + Self::INTERVAL;
+          loop {
+            match rx.recv_deadline(deadline) {
+              Ok(()) => {}
+              Err(crossbeam_channel::RecvTimeoutError::Timeout) => break,
+              // the encode has finished, and will be concatenated completely
+              Err(crossbeam_channel::RecvTimeoutError::Disconnected) => return,
+            }
+          }
+        }
+        last_update = Some(Instant::now());
+
+        // chunks are named after their index, so this counts the finished chunks from the start
+        let done = get_done();
+        let finished = (0..done.done.len())
+          .take_while(|index| done.done.contains_key(&format!("{index:05}")))
+          .count();
+        if finished <= concatenated {
+          continue;
+        }
+
+        match concat_preview(&temp, &output, method, encoder, concatenated..finished) {
+          Ok(()) => {
+            debug!("concatenated the first {} chunks into the output", finished);
+            concatenated = finished;
+          }
+          Err(e) => warn!("Progressive concatenation failed: {:#}", e),
+        }
+      }
+    });
+
+    Self {
+      tx: parking_lot::Mutex::new(Some(tx)),
+      handle: parking_lot::Mutex::new(Some(handle)),
+    }
+  }
+
+  /// Requests the output to be updated in the background, after a chunk has finished
+  pub fn request_update(&self) -> anyhow::Result<()> {
+    if let Some(tx) = &*self.tx.lock() {
+      tx.send(())
+        .map_err(|_| anyhow!("the progressive concatenation thread has stopped"))?;
+    }
+    Ok(())
+  }
+
+  /// Waits for a running update to finish and stops the thread, so that it does not
+  /// overwrite the output after the final concatenation
+  pub fn finish(&self) {
+    drop(self.tx.lock().take());
+    let handle = self.handle.lock().take();
+    if let Some(handle) = handle {
+      handle.join().unwrap();
+    }
+  }
+}
+
+/// Links the chunks in `new_chunks` into the preview folder, and concatenates
+/// all chunks up to them into the output
+fn concat_preview(
+  temp: &Path,
+  output: &Path,
+  method: ConcatMethod,
+  encoder: Encoder,
+  new_chunks: std::ops::Range<usize>,
+) -> anyhow::Result<()> {
+  // hard links take no additional space, but are not supported by every file system
+  fn link(original: &Path, link: &Path) -> anyhow::Result<()> {
+    if !link.exists() {
+      fs::hard_link(original, link)
+        .or_else(|_| fs::copy(original, link).map(|_| ()))
+        .with_context(|| format!("Failed to link {}", original.display()))?;
+    }
+    Ok(())
+  }
+
+  let preview_dir = temp.join("progressive");
+  let encode_dir = preview_dir.join("encode");
+  fs::create_dir_all(&encode_dir)?;
+
+  let extension = encoder.output_extension();
+  let num_chunks = new_chunks.end;
+  for index in new_chunks {
+    let name = format!("{index:05}.{extension}");
+    link(&temp.join("encode").join(&name), &encode_dir.join(&name))?;
+  }
+
+  let audio_file = temp.join("audio.mkv");
+  if audio_file.exists() {
+    link(&audio_file, &preview_dir.join("audio.mkv"))?;
+  }
+
+  // the output is only replaced once the concatenation has succeeded, and
+  // ffmpeg needs the extension of the output to pick the container
+  let mut partial_name = output.file_stem().unwrap_or_default().to_owned();
+  partial_name.push(".partial.");
+  partial_name.push(output.extension().unwrap_or_default());
+  let partial = output.with_file_name(partial_name);
+
+  match method {
+    ConcatMethod::Ivf => ivf(&encode_dir, &partial)?,
+    ConcatMethod::MKVMerge => mkvmerge(
+      &preview_dir,
+      &encode_dir,
+      &partial,
+      extension,
+      num_chunks,
+      None,
+    )?,
+    ConcatMethod::FFmpeg => ffmpeg(&preview_dir, &encode_dir, &partial, encoder)?,
+  }
+
+  retry_io(|| fs::rename(&partial, output))
+    .with_context(|| format!("Failed to replace {}", output.display()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const VP9_KEYFRAME: [u8; 6] = [0x82, 0x49, 0x83, 0x42, 0x40, 0xaa];
+  const VP9_HIDDEN: [u8; 3] = [0x84, 0x01, 0x02];
+  const VP9_SHOWN: [u8; 2] = [0x86, 0x03];
+
+  fn write_ivf(path: &Path, fourcc: [u8; 4], packets: &[(u64, &[u8])]) {
+    let mut file = Vec::new();
+    file.extend_from_slice(b"DKIF\0\0\x20\0");
+    file.extend_from_slice(&fourcc);
+    file.extend_from_slice(&640u16.to_le_bytes());
+    file.extend_from_slice(&360u16.to_le_bytes());
+    file.extend_from_slice(&24u32.to_le_bytes());
+    file.extend_from_slice(&1u32.to_le_bytes());
+    file.extend_from_slice(&(packets.len() as u32).to_le_bytes());
+    file.extend_from_slice(&[0; 4]);
+    for (pts, data) in packets {
+      file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+      file.extend_from_slice(&pts.to_le_bytes());
+      file.extend_from_slice(data);
+    }
+    fs::write(path, file).unwrap();
+  }
+
+  /// Returns the timestamps and data of the packets of an IVF file
+  fn read_packets(path: &Path) -> Vec<(u64, Vec<u8>)> {
+    let file = fs::read(path).unwrap();
+    let mut packets = Vec::new();
+    let mut rest = &file[32..];
+    while !rest.is_empty() {
+      let size = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+      let pts = u64::from_le_bytes(rest[4..12].try_into().unwrap());
+      packets.push((pts, rest[12..12 + size].to_vec()));
+      rest = &rest[12 + size..];
+    }
+    packets
+  }
+
+  #[test]
+  fn timestamps_follow_previous_chunk() {
+    let mut timestamps = Timestamps::new(0);
+    assert_eq!([0, 2, 4].map(|pts| timestamps.rewrite(pts)), [0, 2, 4]);
+    assert_eq!(timestamps.end(), 6);
+
+    // chunks starting after 0, and timestamps going back
+    let mut timestamps = Timestamps::new(6);
+    assert_eq!(
+      [100, 101, 101, 103].map(|pts| timestamps.rewrite(pts)),
+      [6, 7, 8, 9]
+    );
+    assert_eq!(timestamps.end(), 10);
+    assert_eq!(Timestamps::new(10).end(), 10);
+  }
+
+  #[test]
+  fn vp9_superframes() {
+    let frames = vec![VP9_HIDDEN.to_vec(), VP9_SHOWN.to_vec()];
+    let superframe = vp9_superframe(&frames).unwrap();
+    assert_eq!(superframe, [0x84, 0x01, 0x02, 0x86, 0x03, 0xc1, 3, 2, 0xc1]);
+    assert_eq!(vp9_frames(&superframe), [&VP9_HIDDEN[..], &VP9_SHOWN[..]]);
+    assert_eq!(vp9_frames(&VP9_SHOWN), [&VP9_SHOWN[..]]);
+    assert_eq!(vp9_superframe(&frames[1..]).unwrap(), VP9_SHOWN);
+
+    assert!(vp9_frame_is_shown(&VP9_KEYFRAME));
+    assert!(!vp9_frame_is_shown(&VP9_HIDDEN));
+    assert!(vp9_frame_is_shown(&VP9_SHOWN));
+    // show_existing_frame
+    assert!(vp9_frame_is_shown(&[0x88]));
+  }
+
+  #[test]
+  fn keyframe_config() {
+    assert_eq!(
+      vp9_color_config(&VP9_KEYFRAME),
+      Some(Vp9ColorConfig {
+        profile: 0,
+        bit_depth: 8,
+        color_space: 2,
+        color_range: 0,
+        subsampling: (1, 1),
+      })
+    );
+    assert_eq!(vp9_color_config(&VP9_SHOWN), None);
+
+    // temporal delimiter, then a sequence header with an extension and a size
+    let packet = [
+      0x12, 0x00, 0x0e, 0x00, 0x03, 0x0a, 0x0b, 0x0c, 0x32, 0x01, 0xff,
+    ];
+    assert_eq!(av1_sequence_header(&packet), Some(&[0x0a, 0x0b, 0x0c][..]));
+    assert_eq!(av1_sequence_header(&[0x12, 0x00, 0x32, 0x01, 0xff]), None);
+    assert_eq!(leb128(&[0x80, 0x01, 0x05]), Some((128, &[0x05][..])));
+  }
+
+  #[test]
+  fn concat_plan_relative_to_temp() {
+    let dir = tempfile::tempdir().unwrap();
+    let temp = dir.path().join("temp");
+    fs::create_dir_all(&temp).unwrap();
+    let plan = ConcatPlan {
+      method: ConcatMethod::MKVMerge,
+      output: PathBuf::from("output.mkv"),
+      encode_dir: temp.join("encode"),
+      extension: "ivf".to_owned(),
+      num_files: 12,
+      encoder: Encoder::aom,
+      timestamps: Some(temp.join("chunk_timestamps.txt")),
+      dolby_vision: None,
+    };
+    plan.save(&temp).unwrap();
+    assert!(fs::read_to_string(ConcatPlan::path(&temp))
+      .unwrap()
+      .contains("\"encode_dir\": \"encode\""));
+
+    // the folder can be moved before retrying
+    let moved = dir.path().join("moved");
+    fs::rename(&temp, &moved).unwrap();
+    let loaded = ConcatPlan::load(&moved).unwrap();
+    assert_eq!(loaded.encode_dir, moved.join("encode"));
+    assert_eq!(loaded.timestamps, Some(moved.join("chunk_timestamps.txt")));
+    assert_eq!(
+      loaded.output,
+      std::env::current_dir().unwrap().join("output.mkv")
+    );
+  }
+
+  #[test]
+  fn concatenate_ivf() {
+    let dir = tempfile::tempdir().unwrap();
+    let encode = dir.path().join("encode");
+    fs::create_dir_all(&encode).unwrap();
+    write_ivf(
+      &encode.join("00000.ivf"),
+      *b"VP90",
+      &[(0, &VP9_KEYFRAME), (1, &VP9_HIDDEN), (1, &VP9_SHOWN)],
+    );
+    write_ivf(
+      &encode.join("00001.ivf"),
+      *b"VP90",
+      &[(48, &VP9_KEYFRAME), (49, &VP9_SHOWN)],
+    );
+    let output = dir.path().join("output.ivf");
+    ivf(&encode, &output).unwrap();
+
+    let header = IvfHeader::read(&output).unwrap();
+    assert_eq!((header.rate, header.scale), (24, 1));
+    assert_eq!(fs::read(&output).unwrap()[24..28], 4u32.to_le_bytes());
+    let packets = read_packets(&output);
+    assert_eq!(
+      packets.iter().map(|(pts, _)| *pts).collect::<Vec<_>>(),
+      [0, 1, 2, 3]
+    );
+    assert_eq!(
+      packets[1].1,
+      vp9_superframe(&[VP9_HIDDEN.to_vec(), VP9_SHOWN.to_vec()]).unwrap()
+    );
+
+    write_ivf(&encode.join("00002.ivf"), *b"AV01", &[(0, &[0x12, 0x00])]);
+    assert!(ivf(&encode, &output).is_err());
+  }
+}
@@ -1,52 +1,103 @@
 use std::borrow::Cow;
+use std::cell::Cell;
 use std::cmp::Reverse;
 use std::collections::BTreeSet;
 use std::convert::TryInto;
 use std::ffi::OsString;
-use std::fs::File;
-use std::io::{IsTerminal, Write};
+use std::io::IsTerminal;
 use std::path::{Path, PathBuf};
 use std::process::{exit, Command, Stdio};
 use std::sync::atomic::{self, AtomicBool, AtomicUsize};
 use std::sync::{mpsc, Arc};
 use std::thread::available_parallelism;
+use std::time::{Duration, Instant};
 use std::{cmp, fs, iter, thread};
 
 use ansi_term::{Color, Style};
-use anyhow::{bail, Context};
+use anyhow::{bail, ensure, Context};
 use av1_grain::TransferFunction;
 use crossbeam_utils;
+use indicatif::{HumanBytes, HumanDuration};
 use itertools::Itertools;
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
 use rand::prelude::SliceRandom;
 use rand::thread_rng;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::ChildStderr;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{ChildStderr, ChildStdout};
 use tracing::{debug, error, info, warn};
 
-use crate::broker::{Broker, EncoderCrash};
+use crate::broker::{Broker, EncoderCrash, VerifyQueue};
 use crate::chunk::Chunk;
-use crate::concat::{self, ConcatMethod};
-use crate::ffmpeg::{compose_ffmpeg_pipe, num_frames};
+use crate::concat::{self, ConcatMethod, ConcatPlan, ProgressiveConcat};
+use crate::encoder::RateParam;
+use crate::ffmpeg::{
+  changes_frame_count, chapter_frames, compose_ffmpeg_pipe, get_keyframes, num_frames,
+  with_video_filter,
+};
+use crate::interlace::{self, Deinterlace, FieldOrder};
+use crate::logging::{self, Event};
+use crate::process_group::ProcessGroup;
 use crate::progress_bar::{
-  finish_progress_bar, inc_bar, inc_mp_bar, init_multi_progress_bar, init_progress_bar,
+  finish_progress_bar, inc_bar, inc_mp_bar, init_eta, init_multi_progress_bar, init_progress_bar,
   reset_bar_at, reset_mp_bar_at, set_audio_size, update_mp_chunk, update_mp_msg,
   update_progress_bar_estimates,
 };
-use crate::scene_detect::av_scenechange_detect;
+use crate::scene_detect::{av_scenechange_detect, external_detect, ScBackend};
 use crate::scenes::{Scene, ZoneOptions};
 use crate::settings::{EncodeArgs, InputPixelFormat};
-use crate::split::{extra_splits, segment, write_scenes_to_file};
-use crate::vapoursynth::create_vs_file;
+use crate::split::{
+  adaptive_extra_splits, extra_splits, keyframe_scenes, merge_short_scenes, segment,
+  segment_within, stop_segmenting, write_scenes_to_file,
+};
+use crate::status::{self, State};
+use crate::target_quality::ChunkTarget;
+use crate::util::{dir_size, read_in_dir, retry_io};
+use crate::vapoursynth::{self, create_vs_file};
 use crate::{
-  create_dir, determine_workers, get_done, init_done, into_vec, read_chunk_queue, save_chunk_queue,
-  vmaf, ChunkMethod, ChunkOrdering, DashMap, DoneJson, Input, SplitMethod, Verbosity,
+  bit_allocation, cgroup, complexity, create_dir, determine_workers, disk_space, dovi, get_done,
+  init_done, into_vec, legacy, notify, pipe_layout, read_chunk_queue, remote, report,
+  save_chunk_queue, sidecar, super_chunk, verify, vfr, vmaf, y4m, BestSourceCacheMode, ChunkMethod,
+  ChunkOrdering, DashMap, DoneJson, DoneJsonWriter, Input, PipeMode, SplitMethod, Verbosity,
 };
 
+/// Size of the reads of the frames piped into the encoder, which are counted
+/// on the way
+const SPLICE_BUFFER_SIZE: usize = 1 << 20;
+
+/// Time without progress in the output of the encoder after which the
+/// progress of its chunk is estimated from the size of its output
+const STALLED_PROGRESS: Duration = Duration::from_secs(60);
+
+/// Interval of the checks of the progress of the encoder
+const PROGRESS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether the progress of a chunk was estimated from the size of its output,
+/// which is only warned about once per run
+static ESTIMATING_PROGRESS: AtomicBool = AtomicBool::new(false);
+
 #[derive(Debug)]
 pub struct Av1anContext {
   pub frames: usize,
   pub vs_script: Option<PathBuf>,
   pub args: EncodeArgs,
+  /// Frame rate of the input, probed on first use when converting with `--fps`
+  pub(crate) source_frame_rate: OnceCell<f64>,
+  /// Whether to pipe the source through ffmpeg even if it does not need to be
+  /// filtered or converted, which the pipe layout benchmark found to be faster
+  pub(crate) redundant_ffmpeg_pipe: bool,
+  /// Field order the source is deinterlaced with QTGMC in the VapourSynth
+  /// script, if it is
+  pub(crate) qtgmc: Option<FieldOrder>,
+  /// Filters and pixel format conversion done in the VapourSynth script
+  /// instead of in an ffmpeg process for each chunk, per `--pipe-mode`
+  pub(crate) script_filters: Option<String>,
+  /// Script loading the source with ffms2, created when a chunk first falls
+  /// back to it
+  pub(crate) fallback_script: OnceCell<PathBuf>,
+  /// Number of frames of the output when the filters of `--ffmpeg` change
+  /// the number of frames, counted by passing the chunks through the filters
+  pub(crate) filtered_frames: OnceCell<usize>,
 }
 
 impl Av1anContext {
@@ -57,28 +108,81 @@ impl Av1anContext {
       frames: 0,
       vs_script: None,
       args,
+      source_frame_rate: OnceCell::new(),
+      redundant_ffmpeg_pipe: false,
+      qtgmc: None,
+      script_filters: None,
+      fallback_script: OnceCell::new(),
+      filtered_frames: OnceCell::new(),
     };
     this.initialize()?;
     Ok(this)
   }
 
+  /// Parses the zones file against the input, without creating the temporary
+  /// folder, and returns the zones
+  pub fn check_zones(mut args: EncodeArgs) -> anyhow::Result<Vec<Scene>> {
+    args.validate()?;
+    ffmpeg::init()?;
+    ffmpeg::util::log::set_level(ffmpeg::util::log::level::Level::Fatal);
+
+    let this = Self {
+      frames: args.input.frames()?,
+      vs_script: None,
+      args,
+      source_frame_rate: OnceCell::new(),
+      redundant_ffmpeg_pipe: false,
+      qtgmc: None,
+      script_filters: None,
+      fallback_script: OnceCell::new(),
+      filtered_frames: OnceCell::new(),
+    };
+    this.parse_zones()
+  }
+
   /// Initialize logging routines and create temporary directories
   #[tracing::instrument]
   fn initialize(&mut self) -> anyhow::Result<()> {
     ffmpeg::init()?;
     ffmpeg::util::log::set_level(ffmpeg::util::log::level::Level::Fatal);
 
+    // target quality probes stay valid for a new encode of the same input (e.g. with a different target)
+    let probes_path = Path::new(&self.args.temp).join("probes.json");
+    let mut probes = None;
     if !self.args.resume && Path::new(&self.args.temp).is_dir() {
+      probes = fs::read(&probes_path).ok();
       fs::remove_dir_all(&self.args.temp)
         .with_context(|| format!("Failed to remove temporary directory {:?}", &self.args.temp))?;
     }
+    let index_dir = self.args.index_dir();
+    if !self.args.resume && self.args.temp_on.is_some() && index_dir.is_dir() {
+      fs::remove_dir_all(&index_dir)
+        .with_context(|| format!("Failed to remove index directory {}", index_dir.display()))?;
+    }
 
     create_dir!(Path::new(&self.args.temp))?;
     create_dir!(Path::new(&self.args.temp).join("split"))?;
     create_dir!(Path::new(&self.args.temp).join("encode"))?;
+    create_dir!(Path::new(&self.args.temp).join("logs"))?;
+    create_dir!(self.args.index_dir())?;
+
+    if let Some(probes) = probes {
+      fs::write(&probes_path, probes)?;
+    }
 
     debug!("temporary directory: {}", &self.args.temp);
 
+    // temp folders of legacy versions have no usable chunks.json, it is created again from the scenes
+    let imported = self.args.resume
+      && legacy::import_temp(Path::new(&self.args.temp))
+        .context("Failed to import the temporary directory of a legacy version of av1an")?;
+    if imported {
+      info!(
+        "imported temporary directory {:?} written by a legacy version of av1an",
+        &self.args.temp
+      );
+    }
+
     let done_path = Path::new(&self.args.temp).join("done.json");
     let done_json_exists = done_path.exists();
     let chunks_json_exists = Path::new(&self.args.temp).join("chunks.json").exists();
@@ -87,6 +191,7 @@ impl Av1anContext {
       match (done_json_exists, chunks_json_exists) {
         // both files exist, so there is no problem
         (true, true) => {}
+        (true, false) if imported => {}
         (false, true) => {
           info!(
             "resume was set but done.json does not exist in temporary directory {:?}",
@@ -111,9 +216,16 @@ impl Av1anContext {
       }
     }
 
+    if self.args.remux && !self.args.resume {
+      bail!(
+        "--remux requires the chunks of a previous encode in temporary directory {:?}",
+        &self.args.temp
+      );
+    }
+
     if self.args.resume && done_json_exists {
-      let done =
-        fs::read_to_string(done_path).with_context(|| "Failed to read contents of done.json")?;
+      let done = retry_io(|| fs::read_to_string(&done_path))
+        .with_context(|| "Failed to read contents of done.json")?;
       let done: DoneJson =
         serde_json::from_str(&done).with_context(|| "Failed to parse done.json")?;
       self.frames = done.frames.load(atomic::Ordering::Relaxed);
@@ -132,15 +244,63 @@ impl Av1anContext {
         audio_done: AtomicBool::new(false),
       });
 
-      let mut done_file = File::create(&done_path).unwrap();
-      done_file.write_all(serde_json::to_string(get_done())?.as_bytes())?;
+      let contents = serde_json::to_string(get_done())?;
+      retry_io(|| fs::write(&done_path, &contents))
+        .with_context(|| format!("Failed to write {}", done_path.display()))?;
     };
 
+    if self.args.cgroup {
+      cgroup::init(self.args.max_memory, self.args.priority).context("Failed to set up cgroup")?;
+    }
+
+    status::init(Path::new(&self.args.temp));
+    if let Err(e) = status::write_latest(&self.args.status_dir, &self.args) {
+      warn!(
+        "Failed to write status pointers to {}: {}",
+        self.args.status_dir.display(),
+        e
+      );
+    }
+
     Ok(())
   }
 
   #[tracing::instrument]
   pub fn encode_file(&mut self) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let result = self.encode();
+    notify::send(
+      &self.args,
+      start.elapsed(),
+      result.as_ref().err().map(|e| format!("{e:#}")),
+    );
+    result
+  }
+
+  fn encode(&mut self) -> anyhow::Result<()> {
+    if self.args.input.is_remote() {
+      let url = self.args.input.as_path().to_string_lossy().into_owned();
+      let cached = remote::cache(&url, Path::new(&self.args.temp))
+        .with_context(|| format!("Failed to download the input {url}"))?;
+      self.args.input_pix_format = InputPixelFormat::FFmpeg {
+        format: crate::ffmpeg::get_pixel_format(&cached)
+          .context("Failed to get the pixel format of the downloaded input")?,
+      };
+      self.args.input = Input::Video { path: cached };
+    }
+
+    if let Some(video) = self.args.audio_only.clone() {
+      return self.replace_audio(&video);
+    }
+    if self.args.input.is_stdin() {
+      return self.encode_stdin();
+    }
+
+    let start = Instant::now();
+    let qtgmc = self.setup_deinterlace();
+    self.qtgmc = qtgmc;
+    self.script_filters = self.script_filters()?;
+
     let initial_frames = get_done()
       .done
       .iter()
@@ -158,14 +318,22 @@ impl Av1anContext {
         {
           self.vs_script = Some(match &self.args.input {
             Input::VapourSynth { path, .. } => path.clone(),
-            Input::Video{ path } => create_vs_file(&self.args.temp, path, self.args.chunk_method)?,
+            Input::Video{ path } => create_vs_file(&self.args.temp, &self.args.index_dir(), path, self.args.chunk_method, qtgmc, self.args.bestsource_cachemode, &self.args.gpu_devices, self.script_filters.as_deref())?,
           });
 
           let vs_script = self.vs_script.clone().unwrap();
           let vspipe_args = self.args.input.as_vspipe_args_vec()?;
+          if self.args.input.is_video() && self.args.chunk_method == ChunkMethod::BESTSOURCE {
+            // the index is needed before anything else, and takes long enough to show its progress
+            if self.args.bestsource_cachemode != BestSourceCacheMode::Never {
+              vapoursynth::index_bestsource(&vs_script, self.args.verbosity)?;
+            }
+            None
+          } else {
           Some({
             thread::spawn(move || {
               let mut command = Command::new("vspipe");
+              cgroup::apply(&mut command);
               command.arg("-i")
                 .arg(vs_script)
                 .args(["-i", "-"])
@@ -179,10 +347,18 @@ impl Av1anContext {
                 .unwrap()
             })
           })
+          }
         } else {
           None
         };
 
+    if self.args.chunk_method == ChunkMethod::DGDECNV
+      && self.args.input.is_video()
+      && !self.args.resume
+    {
+      self.check_dgdecnv_frames()?;
+    }
+
     let res = self.args.input.resolution()?;
     let fps = self.args.input.frame_rate()?;
     let format = self.args.input.pixel_format()?;
@@ -202,19 +378,42 @@ impl Av1anContext {
       }
     );
 
+    // before splitting, which writes the segments of the source with the
+    // segment and hybrid chunk methods
+    if !self.args.ignore_disk_space {
+      self.check_disk_space(initial_frames)?;
+    }
+
     let splits = self.split_routine()?;
 
     if self.args.sc_only {
       debug!("scene detection only");
 
-      if let Err(e) = fs::remove_dir_all(&self.args.temp) {
-        warn!("Failed to delete temp directory: {}", e);
-      }
+      self.remove_temp();
 
       exit(0);
     }
 
-    let (chunk_queue, total_chunks) = self.load_or_gen_chunk_queue(&splits)?;
+    if self.args.input.is_video() && self.args.fps.is_none() && !self.args.resume {
+      self.detect_vfr()?;
+    }
+
+    let (mut chunk_queue, total_chunks) = self.load_or_gen_chunk_queue(&splits)?;
+    // the output can only grow while the chunks are encoded from the start of the video
+    if self.args.progressive_concat {
+      chunk_queue.sort_unstable_by_key(|chunk| chunk.index);
+    }
+
+    if self.args.remux && !chunk_queue.is_empty() {
+      bail!(
+        "--remux requires all chunks to be encoded, but {} of {} chunks are not, resume the encode with --resume first",
+        chunk_queue.len(),
+        total_chunks
+      );
+    }
+
+    status::set_totals(self.output_frames(), total_chunks);
+    status::set_state(State::Encoding);
 
     if self.args.resume {
       let chunks_done = get_done().done.len();
@@ -230,23 +429,58 @@ impl Av1anContext {
       vspipe_cache.join().unwrap();
     }
 
-    crossbeam_utils::thread::scope(|s| -> anyhow::Result<()> {
+    self.redundant_ffmpeg_pipe =
+      pipe_layout::redundant_ffmpeg_pipe(self.args.pipe_mode, self.needs_ffmpeg_pipe(), || {
+        chunk_queue.first().map_or(Ok(false), |chunk| {
+          pipe_layout::ffmpeg_is_faster(chunk, self.args.output_pix_format.format)
+        })
+      });
+
+    if let Some(chunks) = self.args.benchmark {
+      return self.benchmark(chunks);
+    }
+
+    if let Some(indices) = self.args.preview_chunks.clone() {
+      return self.preview_chunks(&indices);
+    }
+
+    let done_writer = DoneJsonWriter::spawn(Path::new(&self.args.temp).join("done.json"));
+
+    let progressive_concat = self.args.progressive_concat.then(|| {
+      ProgressiveConcat::spawn(
+        PathBuf::from(&self.args.temp),
+        PathBuf::from(&self.args.output_file),
+        self.args.concat,
+        self.args.encoder,
+      )
+    });
+
+    let result = crossbeam_utils::thread::scope(|s| -> anyhow::Result<()> {
+      // the audio of a previous session is encoded again when remuxing, as its settings may have changed,
+      // and is not muxed in at all without audio
+      if self.args.remux || self.args.no_audio {
+        let audio_file = Path::new(&self.args.temp).join("audio.mkv");
+        if audio_file.exists() {
+          fs::remove_file(&audio_file)
+            .with_context(|| format!("Failed to remove {}", audio_file.display()))?;
+        }
+      }
+
       // vapoursynth audio is currently unsupported
       let audio_thread = if self.args.input.is_video()
-        && (!self.args.resume || !get_done().audio_done.load(atomic::Ordering::SeqCst))
+        && !self.args.no_audio
+        && (!self.args.resume
+          || self.args.remux
+          || !get_done().audio_done.load(atomic::Ordering::SeqCst))
       {
         let input = self.args.input.as_video_path();
         let temp = self.args.temp.as_str();
         let audio_params = self.args.audio_params.as_slice();
+        let done_writer = &done_writer;
         Some(s.spawn(move |_| {
           let audio_output = crate::ffmpeg::encode_audio(input, temp, audio_params);
           get_done().audio_done.store(true, atomic::Ordering::SeqCst);
-
-          let progress_file = Path::new(temp).join("done.json");
-          let mut progress_file = File::create(progress_file).unwrap();
-          progress_file
-            .write_all(serde_json::to_string(get_done()).unwrap().as_bytes())
-            .unwrap();
+          done_writer.request_save();
 
           if let Some(ref audio_output) = audio_output {
             let audio_size = audio_output.metadata().unwrap().len();
@@ -260,7 +494,12 @@ impl Av1anContext {
       };
 
       if self.args.workers == 0 {
-        self.args.workers = determine_workers(self.args.encoder) as usize;
+        self.args.workers = determine_workers(
+          self.args.encoder,
+          self.args.chunk_method,
+          &self.args.gpu_devices,
+          self.args.output_resolution()?,
+        ) as usize;
       }
       self.args.workers = cmp::min(self.args.workers, chunk_queue.len());
 
@@ -291,12 +530,21 @@ impl Av1anContext {
         );
       }
 
+      logging::event(&Event::EncodeStarted {
+        input: self.args.input.as_path().to_path_buf(),
+        output: PathBuf::from(&self.args.output_file),
+        frames: self.output_frames(),
+        done_frames: initial_frames,
+        chunks: total_chunks,
+        workers: self.args.workers,
+      });
+
       if self.args.verbosity == Verbosity::Normal {
-        init_progress_bar(self.frames as u64, initial_frames as u64);
+        init_progress_bar(self.output_frames() as u64, initial_frames as u64);
         reset_bar_at(initial_frames as u64);
       } else if self.args.verbosity == Verbosity::Verbose {
         init_multi_progress_bar(
-          self.frames as u64,
+          self.output_frames() as u64,
           self.args.workers,
           total_chunks,
           initial_frames as u64,
@@ -304,58 +552,159 @@ impl Av1anContext {
         reset_mp_bar_at(initial_frames as u64);
       }
 
+      init_eta(
+        self.args.workers,
+        chunk_queue
+          .iter()
+          .map(|chunk| (chunk.name(), chunk.frames())),
+      );
+
       if !get_done().done.is_empty() {
-        let frame_rate = self.args.input.frame_rate()?;
-        update_progress_bar_estimates(frame_rate, self.frames, self.args.verbosity);
+        let frame_rate = match self.args.fps {
+          Some(fps) => fps.as_f64(),
+          None => self.args.input.frame_rate()?,
+        };
+        update_progress_bar_estimates(frame_rate, self.output_frames(), self.args.verbosity);
       }
 
-      let broker = Broker {
-        chunk_queue,
-        project: self,
+      // super-chunks are encoded one after another, so that each can be concatenated once it is finished
+      let (groups, all_chunks) = if let Some(size) = self.args.super_chunks {
+        let mut groups = vec![Vec::new(); super_chunk::count(total_chunks, size)];
+        for chunk in chunk_queue {
+          groups[super_chunk::index_of(&chunk, size)].push(chunk);
+        }
+        (groups, read_chunk_queue(self.args.temp.as_ref())?)
+      } else {
+        (vec![chunk_queue], Vec::new())
       };
 
-      let (tx, rx) = mpsc::channel();
-      let handle = s.spawn(|_| {
-        broker.encoding_loop(tx, self.args.set_thread_affinity);
-      });
+      for (index, chunk_queue) in groups.into_iter().enumerate() {
+        let broker = Broker {
+          chunk_queue,
+          project: self,
+          done_writer: &done_writer,
+          verify_queue: VerifyQueue::default(),
+          progressive_concat: progressive_concat.as_ref(),
+          stopped_workers: AtomicUsize::new(0),
+        };
 
-      // Queue::encoding_loop only sends a message if there was an error (meaning a chunk crashed)
-      // more than MAX_TRIES. So, we have to explicitly exit the program if that happens.
-      if rx.recv().is_ok() {
-        exit(1);
+        let (tx, rx) = mpsc::channel();
+        let handle = s.spawn(|_| {
+          broker.encoding_loop(tx, self.args.set_thread_affinity);
+        });
+
+        // Queue::encoding_loop only sends a message if there was an error (meaning a chunk crashed)
+        // more than MAX_TRIES. So, we have to explicitly exit the program if that happens.
+        if rx.recv().is_ok() {
+          done_writer.flush();
+          notify::send(
+            &self.args,
+            start.elapsed(),
+            Some("the encoder failed on a chunk, see the log for details".to_owned()),
+          );
+          exit(1);
+        }
+
+        handle.join().unwrap();
+
+        if let Some(size) = self.args.super_chunks {
+          super_chunk::finish(
+            self.args.temp.as_ref(),
+            index,
+            size,
+            &all_chunks,
+            self.args.concat,
+          )
+          .with_context(|| format!("Failed to concatenate super-chunk {index}"))?;
+        }
       }
 
-      handle.join().unwrap();
+      if let Some(progressive_concat) = &progressive_concat {
+        progressive_concat.finish();
+      }
 
       finish_progress_bar();
 
+      self.report_pass_times();
+      self.report_fallbacks();
+      self.report_energy();
+
       // TODO add explicit parameter to concatenation functions to control whether audio is also muxed in
       let _audio_output_exists =
         audio_thread.map_or(false, |audio_thread| audio_thread.join().unwrap());
 
-      debug!("encoding finished, concatenating with {}", self.args.concat);
+      done_writer.finish();
 
-      match self.args.concat {
-        ConcatMethod::Ivf => {
-          concat::ivf(
-            &Path::new(&self.args.temp).join("encode"),
-            self.args.output_file.as_ref(),
-          )?;
-        }
-        ConcatMethod::MKVMerge => {
-          concat::mkvmerge(
-            self.args.temp.as_ref(),
-            self.args.output_file.as_ref(),
-            self.args.encoder,
-            total_chunks,
-          )?;
-        }
-        ConcatMethod::FFmpeg => {
-          concat::ffmpeg(self.args.temp.as_ref(), self.args.output_file.as_ref())?;
-        }
+      match read_chunk_queue(self.args.temp.as_ref())
+        .and_then(|chunks| report::chunks_report(self.args.temp.as_ref(), &chunks))
+      {
+        Ok(path) => debug!("wrote the report of the chunks to {}", path.display()),
+        Err(e) => warn!("Failed to write the report of the chunks: {:#}", e),
+      }
+
+      let (encode_dir, extension, num_files) = match self.args.super_chunks {
+        Some(size) => (
+          super_chunk::dir(self.args.temp.as_ref()),
+          super_chunk::extension(self.args.concat),
+          super_chunk::count(total_chunks, size),
+        ),
+        None => (
+          Path::new(&self.args.temp).join("encode"),
+          self.args.encoder.output_extension(),
+          total_chunks,
+        ),
+      };
+
+      let temp = Path::new(&self.args.temp);
+      let plan = ConcatPlan {
+        method: self.args.concat,
+        output: PathBuf::from(&self.args.output_file),
+        encode_dir: encode_dir.clone(),
+        extension: extension.to_owned(),
+        num_files,
+        encoder: self.args.encoder,
+        timestamps: if self.args.concat == ConcatMethod::MKVMerge
+          && vfr::timestamps_path(temp).exists()
+          && self.args.super_chunks.is_none()
+        {
+          Some(vfr::write_chunk_timestamps(temp, &splits)?)
+        } else {
+          None
+        },
+        dolby_vision: if self.args.dolby_vision {
+          Some(self.args.input.frame_rate()?)
+        } else {
+          None
+        },
+      };
+      if !self.concatenate(&plan)? {
+        return Ok(());
       }
 
-      if self.args.vmaf || self.args.target_quality.is_some() {
+      if let Some(mode) = self.args.verify_output {
+        let mut chunks: Vec<PathBuf> = read_in_dir(&encode_dir)?.collect();
+        concat::sort_files_by_filename(&mut chunks);
+        let frame_rate = match self.args.fps {
+          Some(fps) => fps.as_f64(),
+          None => self.args.input.frame_rate()?,
+        };
+        // the temporary folder is kept, as the encode stops here
+        verify::verify(
+          self.args.output_file.as_ref(),
+          self.output_frames(),
+          frame_rate,
+          mode,
+          &chunks,
+        )
+        .with_context(|| {
+          format!(
+            "Verification of the output failed, the temporary folder is kept: {}",
+            self.args.temp
+          )
+        })?;
+      }
+
+      if self.args.vmaf || self.args.quality_report || self.args.target_quality.is_some() {
         let vmaf_res = if let Some(ref tq) = self.args.target_quality {
           if tq.vmaf_res == "inputres" {
             let inputres = self.args.input.resolution()?;
@@ -367,13 +716,18 @@ impl Av1anContext {
           self.args.vmaf_res.clone()
         };
 
-        let vmaf_model = self.args.vmaf_path.as_deref().or_else(|| {
-          self
-            .args
-            .target_quality
-            .as_ref()
-            .and_then(|tq| tq.model.as_deref())
-        });
+        let vmaf_model = vmaf::select_model(
+          self.args.vmaf_path.as_deref().or_else(|| {
+            self
+              .args
+              .target_quality
+              .as_ref()
+              .and_then(|tq| tq.model.as_deref())
+          }),
+          self.args.vmaf_model_auto.as_ref(),
+          self.args.vmaf_neg,
+          &vmaf_res,
+        );
         let vmaf_scaler = "bicubic";
         let vmaf_filter = self.args.vmaf_filter.as_deref().or_else(|| {
           self
@@ -386,10 +740,24 @@ impl Av1anContext {
         if self.args.vmaf {
           let vmaf_threads = available_parallelism().map_or(1, std::num::NonZero::get);
 
+          // chunks are created from the scenes in order, so the chunk index is the scene index
+          let scenes: Vec<_> = splits
+            .iter()
+            .enumerate()
+            .map(|(index, scene)| vmaf::SceneAnnotation {
+              start_frame: scene.start_frame,
+              cq: get_done()
+                .done
+                .get(&format!("{index:05}"))
+                .and_then(|chunk| chunk.tq_cq),
+            })
+            .collect();
+
           if let Err(e) = vmaf::plot(
             self.args.output_file.as_ref(),
             &self.args.input,
-            vmaf_model,
+            &scenes,
+            vmaf_model.as_ref(),
             &vmaf_res,
             vmaf_scaler,
             1,
@@ -399,39 +767,722 @@ impl Av1anContext {
             error!("VMAF calculation failed with error: {}", e);
           }
         }
+
+        if self.args.quality_report {
+          let vmaf_threads = self
+            .args
+            .vmaf_threads
+            .unwrap_or_else(|| available_parallelism().map_or(1, std::num::NonZero::get));
+
+          if let Err(e) = report::quality_report(
+            self.args.output_file.as_ref(),
+            &self.args.input,
+            &splits,
+            vmaf_model.as_ref(),
+            &vmaf_res,
+            vmaf_scaler,
+            vmaf_filter,
+            vmaf_threads,
+          ) {
+            error!("Quality report failed with error: {}", e);
+          }
+        }
+      }
+
+      self.finish_output(start);
+
+      Ok(())
+    })
+    .unwrap();
+
+    // the segmenting is only still running if the encode failed
+    stop_segmenting();
+    result
+  }
+
+  /// Encodes the audio of the input, and muxes it with the video of an existing
+  /// encode into the output
+  fn replace_audio(&self, video: &Path) -> anyhow::Result<()> {
+    let temp = Path::new(&self.args.temp);
+    // the temporary folder is only reused with --resume, which is not allowed here
+    let Some(audio) = crate::ffmpeg::encode_audio(
+      self.args.input.as_video_path(),
+      temp,
+      &self.args.audio_params,
+    ) else {
+      bail!("The input has no audio, or its audio could not be encoded");
+    };
+
+    crate::ffmpeg::mux_video_with_audio(video, &audio, self.args.output_file.as_ref())?;
+
+    if !self.args.keep {
+      self.remove_temp();
+    }
+
+    Ok(())
+  }
+
+  /// Deletes the temporary folder, along with the index caches on the storage
+  /// of `--temp-on`
+  pub(crate) fn remove_temp(&self) {
+    let removed = if self.args.keep_chunk_logs {
+      // everything but the logs of the chunks
+      fs::read_dir(&self.args.temp).and_then(|entries| {
+        entries
+          .filter_map(Result::ok)
+          .filter(|entry| entry.file_name() != "logs")
+          .try_for_each(|entry| {
+            if entry.path().is_dir() {
+              fs::remove_dir_all(entry.path())
+            } else {
+              fs::remove_file(entry.path())
+            }
+          })
+      })
+    } else {
+      fs::remove_dir_all(&self.args.temp)
+    };
+    if let Err(e) = removed {
+      warn!("Failed to delete temp directory: {}", e);
+    }
+    if self.args.temp_on.is_some() {
+      if let Err(e) = fs::remove_dir_all(self.args.index_dir()) {
+        warn!("Failed to delete index directory: {}", e);
+      }
+    }
+  }
+
+  #[tracing::instrument]
+  fn read_queue_files(source_path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut queue_files = fs::read_dir(source_path)
+      .with_context(|| format!("Failed to read queue files from source path {source_path:?}"))?
+      .map(|res| res.map(|e| e.path()))
+      .collect::<Result<Vec<_>, _>>()?;
+
+    queue_files.retain(|file| {
+      file.is_file() && matches!(file.extension().map(|ext| ext == "mkv"), Some(true))
+    });
+    concat::sort_files_by_filename(&mut queue_files);
+
+    Ok(queue_files)
+  }
+
+  /// Returns the number of frames of the output, which differs from the input with `--fps` or
+  /// filters that change the number of frames
+  pub(crate) fn output_frames(&self) -> usize {
+    if let Some(&frames) = self.filtered_frames.get() {
+      return frames;
+    }
+    // the frames of a stream are only known up to the part that was read
+    if self.args.input.is_stdin() {
+      return get_done().frames.load(atomic::Ordering::Relaxed);
+    }
+    self.args.fps.map_or(self.frames, |fps| {
+      fps.convert_frame(
+        self.frames,
+        *self
+          .source_frame_rate
+          .get_or_init(|| self.args.input.frame_rate().unwrap_or(fps.as_f64())),
+      )
+    })
+  }
+
+  /// Concatenates the encoded chunks into the output following `plan`, unless
+  /// `--no-concat` is passed, in which case the encoded chunks are the result
+  /// of the encode and the temporary folder is kept. Returns whether the
+  /// chunks were concatenated.
+  pub(crate) fn concatenate(&self, plan: &ConcatPlan) -> anyhow::Result<bool> {
+    if self.args.no_concat {
+      info!(
+        "encoding finished, skipping concatenation, the chunks are kept in {}",
+        plan.encode_dir.display()
+      );
+      status::set_state(State::Finished);
+      return Ok(false);
+    }
+
+    debug!("encoding finished, concatenating with {}", self.args.concat);
+    status::set_state(State::Concatenating);
+
+    let temp = Path::new(&self.args.temp);
+    if let Err(e) = plan.save(temp) {
+      warn!("{:#}", e);
+    }
+    // the temporary folder is kept, as the encode stops here
+    plan.run(temp).with_context(|| {
+      format!(
+        "Concatenation failed, the encoded chunks are kept. Run `av1an concat --temp {}` to \
+         concatenate them again",
+        self.args.temp
+      )
+    })?;
+    Ok(true)
+  }
+
+  /// Finishes an encode once its output is written: writes its settings next
+  /// to it, and removes the temporary folder unless `--keep` is passed
+  pub(crate) fn finish_output(&self, start: Instant) {
+    if !Path::new(&self.args.output_file).exists() {
+      warn!(
+        "Concatenation failed for unknown reasons! Temp folder will not be deleted: {}",
+        &self.args.temp
+      );
+      return;
+    }
+
+    logging::event(&Event::EncodeFinished {
+      output: PathBuf::from(&self.args.output_file),
+      seconds: start.elapsed().as_secs_f64(),
+    });
+    if let Err(e) = sidecar::write(&self.args) {
+      warn!("Failed to write the settings of the encode: {:#}", e);
+    }
+
+    if self.args.keep {
+      status::set_state(State::Finished);
+    } else {
+      self.remove_temp();
+    }
+  }
+
+  /// Creates a temporary folder of its own under the temporary folder for the
+  /// chunks encoded by `--benchmark` or `--preview-chunks`, so that the encoded
+  /// chunks and done.json of the encode, which may be resumed, are left as is
+  fn sample_temp(&self, name: &str, chunks: &mut [Chunk]) -> anyhow::Result<PathBuf> {
+    let temp = Path::new(&self.args.temp).join(name);
+    if temp.exists() {
+      fs::remove_dir_all(&temp).with_context(|| format!("Failed to remove {}", temp.display()))?;
+    }
+    for dir in ["split", "encode", "logs"] {
+      fs::create_dir_all(temp.join(dir))
+        .with_context(|| format!("Failed to create {}", temp.join(dir).display()))?;
+    }
+    for chunk in chunks {
+      chunk.temp = temp.to_string_lossy().into_owned();
+    }
+    Ok(temp)
+  }
+
+  /// Removes the temporary folder of `--benchmark` or `--preview-chunks`, and
+  /// the temporary folder of the encode unless it is resumed
+  fn remove_sample_temp(&self, temp: &Path) {
+    if self.args.keep {
+      return;
+    }
+    if self.args.resume {
+      if let Err(e) = fs::remove_dir_all(temp) {
+        warn!("Failed to delete {}: {}", temp.display(), e);
+      }
+    } else {
+      self.remove_temp();
+    }
+  }
+
+  /// Encodes a sample of chunks spread evenly across the video and reports the
+  /// projected encode time and output size for the whole video.
+  fn benchmark(&mut self, chunks: usize) -> anyhow::Result<()> {
+    // sampled from all chunks, including the ones already encoded when resuming
+    let mut all_chunks = read_chunk_queue(self.args.temp.as_ref())?;
+    all_chunks.sort_unstable_by_key(|chunk| chunk.index);
+    let total_chunks = all_chunks.len();
+    let chunks = cmp::min(chunks, total_chunks);
+    ensure!(
+      chunks > 0,
+      "Benchmark failed: the video has no chunks to encode"
+    );
+    let mut sample: Vec<Chunk> = (0..chunks)
+      .map(|i| all_chunks[i * total_chunks / chunks].clone())
+      .collect();
+    let sample_frames: usize = sample.iter().map(Chunk::frames).sum();
+    ensure!(
+      sample_frames > 0,
+      "Benchmark failed: the sampled chunks have no frames"
+    );
+    let temp = self.sample_temp("benchmark", &mut sample)?;
+    let done_writer = DoneJsonWriter::spawn(temp.join("done.json"));
+
+    if self.args.workers == 0 {
+      self.args.workers = determine_workers(
+        self.args.encoder,
+        self.args.chunk_method,
+        &self.args.gpu_devices,
+        self.args.output_resolution()?,
+      ) as usize;
+    }
+    self.args.workers = cmp::min(self.args.workers, sample.len());
+
+    eprintln!(
+      "Benchmarking {} of {} chunks ({} frames) with {} workers",
+      sample.len(),
+      total_chunks,
+      sample_frames,
+      self.args.workers
+    );
+
+    if self.args.verbosity == Verbosity::Normal {
+      init_progress_bar(sample_frames as u64, 0);
+    } else if self.args.verbosity == Verbosity::Verbose {
+      init_multi_progress_bar(sample_frames as u64, self.args.workers, sample.len(), 0);
+    }
+
+    let outputs: Vec<String> = sample.iter().map(Chunk::output).collect();
+    let broker = Broker {
+      chunk_queue: sample,
+      project: self,
+      done_writer: &done_writer,
+      verify_queue: VerifyQueue::default(),
+      progressive_concat: None,
+      stopped_workers: AtomicUsize::new(0),
+    };
+
+    let start = Instant::now();
+    let (tx, rx) = mpsc::channel();
+    broker.encoding_loop(tx, self.args.set_thread_affinity);
+    let elapsed = start.elapsed();
+    done_writer.finish();
+
+    if rx.try_recv().is_ok() {
+      bail!("Benchmark failed: a chunk could not be encoded");
+    }
+    let elapsed = elapsed.as_secs_f64().max(f64::EPSILON);
+
+    let sample_bytes: u64 = outputs
+      .iter()
+      .filter_map(|output| fs::metadata(output).ok())
+      .map(|metadata| metadata.len())
+      .sum();
+    let fps = sample_frames as f64 / elapsed;
+    let projected_time = Duration::from_secs_f64(self.output_frames() as f64 / fps);
+    let projected_size =
+      (sample_bytes as f64 / sample_frames as f64 * self.output_frames() as f64) as u64;
+
+    info!(
+      "benchmark: {:.2} fps, projected time {:.2?}, projected size {} bytes",
+      fps, projected_time, projected_size
+    );
+    eprintln!(
+      "Benchmark: {:.2} fps\nProjected encode time: {}\nProjected video size: {}",
+      fps,
+      HumanDuration(projected_time),
+      HumanBytes(projected_size)
+    );
+
+    self.remove_sample_temp(&temp);
+
+    Ok(())
+  }
+
+  /// Encodes only the chunks with the given indices, and copies each next to the output file
+  fn preview_chunks(&mut self, indices: &[usize]) -> anyhow::Result<()> {
+    // done chunks are encoded again, as the settings may have changed since
+    let chunks = read_chunk_queue(self.args.temp.as_ref())?;
+    let mut preview: Vec<Chunk> = indices
+      .iter()
+      .map(|&index| {
+        chunks
+          .iter()
+          .find(|chunk| chunk.index == index)
+          .cloned()
+          .with_context(|| {
+            format!(
+              "--preview-chunks: chunk {index} does not exist, there are {} chunks",
+              chunks.len()
+            )
+          })
+      })
+      .collect::<anyhow::Result<_>>()?;
+    let preview_frames: usize = preview.iter().map(Chunk::frames).sum();
+    let temp = self.sample_temp("preview", &mut preview)?;
+    let done_writer = DoneJsonWriter::spawn(temp.join("done.json"));
+
+    if self.args.workers == 0 {
+      self.args.workers = determine_workers(
+        self.args.encoder,
+        self.args.chunk_method,
+        &self.args.gpu_devices,
+        self.args.output_resolution()?,
+      ) as usize;
+    }
+    self.args.workers = cmp::min(self.args.workers, preview.len());
+
+    eprintln!(
+      "Previewing {} of {} chunks ({} frames) with {} workers",
+      preview.len(),
+      chunks.len(),
+      preview_frames,
+      self.args.workers
+    );
+
+    if self.args.verbosity == Verbosity::Normal {
+      init_progress_bar(preview_frames as u64, 0);
+    } else if self.args.verbosity == Verbosity::Verbose {
+      init_multi_progress_bar(preview_frames as u64, self.args.workers, preview.len(), 0);
+    }
+
+    let output = Path::new(&self.args.output_file);
+    let copies: Vec<(PathBuf, PathBuf)> = preview
+      .iter()
+      .map(|chunk| {
+        let mut name = output.file_stem().unwrap_or_default().to_owned();
+        name.push(format!(".chunk{}.{}", chunk.name(), chunk.output_ext));
+        (PathBuf::from(chunk.output()), output.with_file_name(name))
+      })
+      .collect();
+
+    let broker = Broker {
+      chunk_queue: preview,
+      project: self,
+      done_writer: &done_writer,
+      verify_queue: VerifyQueue::default(),
+      progressive_concat: None,
+      stopped_workers: AtomicUsize::new(0),
+    };
+
+    let (tx, rx) = mpsc::channel();
+    broker.encoding_loop(tx, self.args.set_thread_affinity);
+    done_writer.finish();
+    finish_progress_bar();
+
+    if rx.try_recv().is_ok() {
+      bail!("Preview failed: a chunk could not be encoded");
+    }
+
+    for (chunk_output, preview_output) in &copies {
+      fs::copy(chunk_output, preview_output).with_context(|| {
+        format!(
+          "Failed to copy {} to {}",
+          chunk_output.display(),
+          preview_output.display()
+        )
+      })?;
+      eprintln!("Wrote {}", preview_output.display());
+    }
+
+    self.remove_sample_temp(&temp);
+
+    Ok(())
+  }
+
+  /// Prints the total time spent in each pass across all chunks, for multi-pass encodes
+  pub(crate) fn report_pass_times(&self) {
+    let mut totals: Vec<f64> = Vec::new();
+    for chunk in &get_done().done {
+      for (pass, secs) in chunk.pass_times.iter().enumerate() {
+        if pass == totals.len() {
+          totals.push(0.0);
+        }
+        totals[pass] += secs;
+      }
+    }
+
+    if totals.len() < 2 {
+      return;
+    }
+
+    let total: f64 = totals.iter().sum();
+    let breakdown = totals
+      .iter()
+      .enumerate()
+      .map(|(pass, secs)| {
+        format!(
+          "pass {}: {:.2?} ({:.1}%)",
+          pass + 1,
+          Duration::from_secs_f64(*secs),
+          secs / total * 100.0
+        )
+      })
+      .join(", ");
+
+    info!("encoder time per pass: {}", breakdown);
+    if self.args.verbosity != Verbosity::Quiet {
+      eprintln!("Encoder time per pass: {breakdown}");
+    }
+  }
+
+  /// Logs the chunks that were decoded with a fallback chunk method
+  pub(crate) fn report_fallbacks(&self) {
+    let mut fallbacks: Vec<_> = get_done()
+      .done
+      .iter()
+      .filter_map(|chunk| chunk.fallback.map(|method| (chunk.key().clone(), method)))
+      .collect();
+    if fallbacks.is_empty() {
+      return;
+    }
+    fallbacks.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+    warn!(
+      "{} chunks could not be decoded with {} and used a fallback: {}",
+      fallbacks.len(),
+      <&str>::from(self.args.chunk_method),
+      fallbacks
+        .iter()
+        .map(|(name, method)| format!("{name} ({})", <&str>::from(*method)))
+        .join(", ")
+    );
+  }
+
+  /// Reports the energy (and cost) of the encode estimated from `--power-draw`
+  pub(crate) fn report_energy(&self) {
+    let Some(watts) = self.args.power_draw else {
+      return;
+    };
+
+    let chunk_secs: f64 = get_done()
+      .done
+      .iter()
+      .map(|chunk| chunk.pass_times.iter().sum::<f64>())
+      .sum();
+    let hours = chunk_secs / self.args.workers as f64 / 3600.0;
+    let kwh = watts * hours / 1000.0;
+
+    let mut estimate = format!(
+      "{kwh:.3} kWh ({:.2?} at {watts} W)",
+      Duration::from_secs_f64(hours * 3600.0)
+    );
+    if let Some(price) = self.args.energy_price {
+      estimate = format!("{estimate}, costing {:.2} at {price}/kWh", kwh * price);
+    }
+
+    info!("estimated energy: {}", estimate);
+    if self.args.verbosity != Verbosity::Quiet {
+      eprintln!("Estimated energy: {estimate}");
+    }
+  }
+
+  /// Returns the GPU the DGDecNV decoder of a worker runs on, with the workers
+  /// spread round-robin across `--gpu-devices`
+  fn gpu_device(&self, worker_id: usize) -> Option<usize> {
+    if self.args.chunk_method != ChunkMethod::DGDECNV || self.args.gpu_devices.is_empty() {
+      return None;
+    }
+    Some(self.args.gpu_devices[worker_id % self.args.gpu_devices.len()])
+  }
+
+  /// Applies the requested CPU and IO priority (and cgroup) to a process spawned for a chunk
+  fn set_priority(&self, command: &mut tokio::process::Command) {
+    cgroup::apply_async(command);
+
+    let priority = self.args.priority;
+    let io_priority = self.args.io_priority;
+
+    #[cfg(unix)]
+    if priority.is_some() || io_priority.is_some() {
+      // SAFETY: only async-signal-safe syscalls are made between fork and exec.
+      // Failures (e.g. raising the priority without privileges) are ignored, as
+      // the process can still run with its inherited priority.
+      unsafe {
+        command.pre_exec(move || {
+          if let Some(priority) = priority {
+            libc::setpriority(libc::PRIO_PROCESS as _, 0, priority.nice_value());
+          }
+          #[cfg(target_os = "linux")]
+          if let Some(io_priority) = io_priority {
+            const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+            libc::syscall(
+              libc::SYS_ioprio_set,
+              IOPRIO_WHO_PROCESS,
+              0,
+              io_priority.ioprio_value(),
+            );
+          }
+          Ok(())
+        });
+      }
+    }
+
+    #[cfg(windows)]
+    if let Some(priority) = priority {
+      command.creation_flags(priority.priority_class());
+    }
+
+    #[cfg(not(unix))]
+    let _ = (command, priority, io_priority);
+  }
+
+  /// Fails if the disks of the temporary directory and of the output do not
+  /// have enough space free for what is left of the encode. Only the size of
+  /// video files is known, so VapourSynth scripts are not checked.
+  fn check_disk_space(&self, done_frames: usize) -> anyhow::Result<()> {
+    let Input::Video { path } = &self.args.input else {
+      return Ok(());
+    };
+    let source_size = fs::metadata(path)
+      .with_context(|| format!("Failed to get the size of {}", path.display()))?
+      .len();
+    // the frames are only known before splitting when resuming, in which case
+    // they are those of the previous run
+    let remaining = 1.0 - done_frames as f64 / self.frames.max(1) as f64;
+    let estimate = disk_space::Estimate::new(
+      source_size,
+      remaining,
+      self.args.chunk_method,
+      self.args.max_temp_size,
+      dir_size(&Path::new(&self.args.temp).join("split")),
+      !self.args.no_concat,
+    );
+    disk_space::check(
+      Path::new(&self.args.temp),
+      Path::new(&self.args.output_file),
+      estimate,
+    )
+  }
+
+  /// Checks that DGDecNV decodes as many frames as are counted in the source,
+  /// as the chunks would not be trimmed at the right frames otherwise
+  fn check_dgdecnv_frames(&self) -> anyhow::Result<()> {
+    let script = self.vs_script.as_ref().unwrap();
+    let decoded = vapoursynth::num_frames(script, self.args.input.as_vspipe_args_map()?)?;
+    let frames = self.args.input.frames()?;
+    ensure!(
+      decoded == frames,
+      "DGDecNV decodes {decoded} frames from the input, but it has {frames} frames, use another chunk method"
+    );
+    Ok(())
+  }
+
+  /// Detects whether the source is interlaced, and adds the bwdif filter if it
+  /// should be deinterlaced with it. Returns the field order to deinterlace
+  /// with QTGMC, if it should be deinterlaced with it instead.
+  fn setup_deinterlace(&mut self) -> Option<FieldOrder> {
+    let order = if self.args.input.is_video() {
+      match interlace::detect(self.args.input.as_video_path()) {
+        Ok(order) => Some(order),
+        Err(e) => {
+          warn!("failed to detect interlacing: {:#}", e);
+          None
+        }
+      }
+    } else {
+      None
+    };
+    let interlaced = order.filter(|&order| order != FieldOrder::Progressive);
+    if let Some(order) = interlaced {
+      info!("the source is interlaced ({})", order);
+    }
+
+    match self.args.deinterlace {
+      None => {
+        if interlaced.is_some() {
+          warn!("the source is interlaced, which the encoders do not handle well, consider --deinterlace");
+        }
+        None
+      }
+      Some(Deinterlace::Auto) if interlaced.is_none() => None,
+      Some(Deinterlace::Auto | Deinterlace::Bwdif) => {
+        self.args.ffmpeg_filter_args = with_video_filter(
+          &self.args.ffmpeg_filter_args,
+          &interlace::bwdif_filter(order),
+        );
+        None
       }
+      Some(Deinterlace::Qtgmc) => Some(order.unwrap_or(FieldOrder::TopFieldFirst)),
+    }
+  }
+
+  /// Saves the timestamps of a variable frame rate source, so that they can be
+  /// applied to the chunks when concatenating
+  fn detect_vfr(&self) -> anyhow::Result<()> {
+    let timestamps = match vfr::read_timestamps(self.args.input.as_video_path()) {
+      Ok(timestamps) => timestamps,
+      Err(e) => {
+        debug!("failed to read the timestamps of the source: {:#}", e);
+        return Ok(());
+      }
+    };
+    if !vfr::is_vfr(&timestamps) {
+      return Ok(());
+    }
+    if timestamps.len() != self.frames {
+      warn!(
+        "the source has a variable frame rate, but {} timestamps for {} frames, the output will have a constant frame rate",
+        timestamps.len(),
+        self.frames
+      );
+      return Ok(());
+    }
+
+    if self.args.concat == ConcatMethod::MKVMerge && self.args.super_chunks.is_none() {
+      info!("the source has a variable frame rate, its timestamps are applied when concatenating");
+      vfr::save_timestamps(Path::new(&self.args.temp), &timestamps)?;
+    } else {
+      warn!(
+        "the source has a variable frame rate, which is only kept with --concat mkvmerge (without --super-chunks), the output will have a constant frame rate and may drift from the audio"
+      );
+    }
+
+    Ok(())
+  }
+
+  /// Whether the source has to be piped through ffmpeg to filter the frames or
+  /// to convert their pixel format
+  fn needs_ffmpeg_pipe(&self) -> bool {
+    if self.script_filters.is_some() {
+      return false;
+    }
+    if !self.args.ffmpeg_filter_args.is_empty() || self.args.fps.is_some() {
+      return true;
+    }
 
-      if !Path::new(&self.args.output_file).exists() {
-        warn!(
-          "Concatenation failed for unknown reasons! Temp folder will not be deleted: {}",
-          &self.args.temp
-        );
-      } else if !self.args.keep {
-        if let Err(e) = fs::remove_dir_all(&self.args.temp) {
-          warn!("Failed to delete temp directory: {}", e);
-        }
+    match &self.args.input_pix_format {
+      InputPixelFormat::FFmpeg { format } => self.args.output_pix_format.format != *format,
+      InputPixelFormat::VapourSynth { bit_depth } => {
+        self.args.output_pix_format.bit_depth != *bit_depth
       }
+    }
+  }
 
-      Ok(())
-    })
-    .unwrap()?;
-
-    Ok(())
+  /// Returns the filters of `--ffmpeg` left to the ffmpeg pipe, which are
+  /// none if they are done in the VapourSynth script
+  fn pipe_filter_args(&self) -> &[String] {
+    if self.script_filters.is_some() {
+      &[]
+    } else {
+      &self.args.ffmpeg_filter_args
+    }
   }
 
-  #[tracing::instrument]
-  fn read_queue_files(source_path: &Path) -> anyhow::Result<Vec<PathBuf>> {
-    let mut queue_files = fs::read_dir(source_path)
-      .with_context(|| format!("Failed to read queue files from source path {source_path:?}"))?
-      .map(|res| res.map(|e| e.path()))
-      .collect::<Result<Vec<_>, _>>()?;
+  /// Returns the filters and the pixel format conversion to do in the
+  /// VapourSynth script of the source, following `--pipe-mode`, or `None` if
+  /// the frames go through ffmpeg
+  fn script_filters(&self) -> anyhow::Result<Option<String>> {
+    if self.args.pipe_mode == PipeMode::FFmpeg || !self.needs_ffmpeg_pipe() {
+      return Ok(None);
+    }
 
-    queue_files.retain(|file| {
-      file.is_file() && matches!(file.extension().map(|ext| ext == "mkv"), Some(true))
-    });
-    concat::sort_files_by_filename(&mut queue_files);
+    let filters = match self.args.input_pix_format {
+      InputPixelFormat::FFmpeg { format }
+        if self.args.input.is_video()
+          && self.args.fps.is_none()
+          && matches!(
+            self.args.chunk_method,
+            ChunkMethod::LSMASH
+              | ChunkMethod::FFMS2
+              | ChunkMethod::DGDECNV
+              | ChunkMethod::BESTSOURCE
+          ) =>
+      {
+        vapoursynth::filter_script(
+          &self.args.ffmpeg_filter_args,
+          self.args.input.resolution()?,
+          format,
+          self.args.output_pix_format.format,
+        )
+      }
+      _ => None,
+    };
 
-    Ok(queue_files)
+    if filters.is_none() && self.args.pipe_mode == PipeMode::VsOnly {
+      bail!(
+        "--pipe-mode vs-only needs a video input split with a VapourSynth chunk method, without \
+         --fps, and with only the scale, crop, hflip and vflip filters in --ffmpeg, which must not \
+         split the chroma samples of the source"
+      );
+    }
+    if filters.is_some() {
+      debug!("filtering and converting the pixel format in the VapourSynth script");
+    }
+    Ok(filters)
   }
 
   /// Returns the number of frames encoded if crashed, to reset the progress bar.
@@ -444,109 +1495,176 @@ impl Av1anContext {
   ) -> Result<(), (Box<EncoderCrash>, u64)> {
     update_mp_chunk(worker_id, chunk.index, padding);
 
-    let fpf_file = Path::new(&chunk.temp)
-      .join("split")
-      .join(format!("{}_fpf", chunk.name()));
+    let fpf_file = chunk.fpf_file();
 
     let video_params = chunk.video_params.clone();
 
-    let mut enc_cmd = if chunk.passes == 1 {
-      chunk
-        .encoder
-        .compose_1_1_pass(video_params, chunk.output(), chunk.frames())
-    } else if current_pass == 1 {
-      chunk
-        .encoder
-        .compose_1_2_pass(video_params, fpf_file.to_str().unwrap(), chunk.frames())
-    } else {
-      chunk.encoder.compose_2_2_pass(
+    let mut enc_cmd = match self.args.intermediate {
+      Some(intermediate) => {
+        // the pixel formats of the zones were checked against x264 when parsing them
+        let format = chunk
+          .pix_format
+          .map_or(self.args.output_pix_format, |format| {
+            intermediate.pixel_format(format).unwrap()
+          });
+        intermediate.compose(chunk.output(), chunk.frames(), format)
+      }
+      None if chunk.passes == 1 => {
+        chunk
+          .encoder
+          .compose_1_1_pass(video_params, chunk.output(), chunk.frames())
+      }
+      None => chunk.encoder.compose_pass(
         video_params,
+        current_pass,
+        chunk.passes,
         fpf_file.to_str().unwrap(),
         chunk.output(),
         chunk.frames(),
-      )
+      ),
     };
 
     if let Some(per_shot_target_quality_cq) = chunk.tq_cq {
-      enc_cmd = chunk
-        .encoder
-        .man_command(enc_cmd, per_shot_target_quality_cq as usize);
+      let encoder = chunk.encoder;
+      enc_cmd = match self.args.target_quality.as_ref().map(|tq| tq.rate) {
+        Some(RateParam::Bitrate) => encoder.man_rate(
+          encoder.bitrate_params(enc_cmd),
+          RateParam::Bitrate,
+          per_shot_target_quality_cq as usize,
+        ),
+        Some(RateParam::Q) | None => {
+          encoder.man_command(enc_cmd, per_shot_target_quality_cq as usize)
+        }
+      };
     }
 
+    let source_cmd = if let [source, args @ ..] = &*chunk.source_cmd {
+      let mut command = vec![source.clone()];
+      for arg in chunk.input.as_vspipe_args_vec().unwrap() {
+        command.extend(["-a".into(), arg.into()]);
+      }
+      if let Some(device) = self.gpu_device(worker_id) {
+        command.extend(["-a".into(), format!("gpu={device}").into()]);
+      }
+      command.extend(args.iter().cloned());
+      command
+    } else {
+      unreachable!()
+    };
+
+    // converts the pixel format
+    let pix_format = chunk
+      .pix_format
+      .unwrap_or(self.args.output_pix_format.format);
+    let ffmpeg_cmd = (self.needs_ffmpeg_pipe()
+      || self.redundant_ffmpeg_pipe
+      || chunk.part.is_some()
+      || chunk.pix_format.is_some()
+      || chunk.denoise.is_some())
+    .then(|| {
+      // the frames are denoised before the parts are cut and the frame rate is
+      // changed, so that the filter sees the frames around them
+      let with_denoise = |args: &[String]| {
+        chunk
+          .denoise_filter()
+          .map_or_else(|| args.to_vec(), |filter| with_video_filter(args, &filter))
+      };
+      self.args.fps.map_or_else(
+        || {
+          // --fps is not allowed with checkpoints, so the frames of parts are frames of the source
+          let mut args = with_denoise(self.pipe_filter_args());
+          if let Some(part) = chunk.part {
+            args = with_video_filter(&args, &part.filter());
+          }
+          compose_ffmpeg_pipe(args, pix_format)
+        },
+        |fps| {
+          let mut args = with_video_filter(
+            &with_denoise(&self.args.ffmpeg_filter_args),
+            &fps.filter(self.args.fps_interpolate),
+          );
+          args.extend(["-frames:v".to_owned(), chunk.frames().to_string()]);
+          compose_ffmpeg_pipe(args, pix_format)
+        },
+      )
+    });
+
+    // the commands of the processes piped into each other, from the source to the encoder
+    let pipeline: Vec<Vec<String>> = iter::once(
+      source_cmd
+        .iter()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect(),
+    )
+    .chain(ffmpeg_cmd.clone())
+    .chain(iter::once(enc_cmd.clone()))
+    .collect();
+
     let rt = tokio::runtime::Builder::new_current_thread()
       .enable_io()
       .build()
       .unwrap();
 
-    let (source_pipe_stderr, ffmpeg_pipe_stderr, enc_output, enc_stderr, frame) =
-      rt.block_on(async {
-        let mut source_pipe = if let [source, args @ ..] = &*chunk.source_cmd {
+    // kills what is left of the pipeline once it is done, or if this panics
+    let group = ProcessGroup::new();
+
+    let (source_pipe, source_pipe_stderr, ffmpeg_pipe_stderr, enc_output, enc_stderr, frame) = rt
+      .block_on(async {
+        let mut source_pipe = if let [source, args @ ..] = &*source_cmd {
           let mut command = tokio::process::Command::new(source);
-          for arg in chunk.input.as_vspipe_args_vec().unwrap() {
-            command.args(["-a", &arg]);
-          }
-          command
+          self.set_priority(&mut command);
+          group.add(&mut command);
+          // a process reading the terminal outside of its foreground process group is stopped
+          let child = command
             .args(args)
+            .stdin(Stdio::null())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .spawn()
-            .unwrap()
+            .unwrap();
+          group.register(&child);
+          child
         } else {
           unreachable!()
         };
 
-        let source_pipe_stdout: Stdio = source_pipe.stdout.take().unwrap().try_into().unwrap();
+        let source_pipe_stdout = source_pipe.stdout.take().unwrap();
 
         let source_pipe_stderr = source_pipe.stderr.take().unwrap();
 
-        // converts the pixel format
-        let create_ffmpeg_pipe = |pipe_from: Stdio, source_pipe_stderr: ChildStderr| {
-          let ffmpeg_pipe = compose_ffmpeg_pipe(
-            self.args.ffmpeg_filter_args.as_slice(),
-            self.args.output_pix_format.format,
-          );
-
-          let mut ffmpeg_pipe = if let [ffmpeg, args @ ..] = &*ffmpeg_pipe {
-            tokio::process::Command::new(ffmpeg)
-              .args(args)
-              .stdin(pipe_from)
-              .stdout(Stdio::piped())
-              .stderr(Stdio::piped())
-              .spawn()
-              .unwrap()
-          } else {
-            unreachable!()
+        let create_ffmpeg_pipe =
+          |pipe_from: ChildStdout, source_pipe_stderr: ChildStderr, ffmpeg_cmd: &[String]| {
+            let mut ffmpeg_pipe = if let [ffmpeg, args @ ..] = ffmpeg_cmd {
+              let mut command = tokio::process::Command::new(ffmpeg);
+              self.set_priority(&mut command);
+              group.add(&mut command);
+              let child = command
+                .args(args)
+                .stdin(TryInto::<Stdio>::try_into(pipe_from).unwrap())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap();
+              group.register(&child);
+              child
+            } else {
+              unreachable!()
+            };
+
+            let ffmpeg_pipe_stdout = ffmpeg_pipe.stdout.take().unwrap();
+            let ffmpeg_pipe_stderr = ffmpeg_pipe.stderr.take().unwrap();
+            (
+              ffmpeg_pipe_stdout,
+              source_pipe_stderr,
+              Some(ffmpeg_pipe_stderr),
+            )
           };
 
-          let ffmpeg_pipe_stdout: Stdio = ffmpeg_pipe.stdout.take().unwrap().try_into().unwrap();
-          let ffmpeg_pipe_stderr = ffmpeg_pipe.stderr.take().unwrap();
-          (
-            ffmpeg_pipe_stdout,
-            source_pipe_stderr,
-            Some(ffmpeg_pipe_stderr),
-          )
-        };
-
-        let (y4m_pipe, source_pipe_stderr, mut ffmpeg_pipe_stderr) =
-          if self.args.ffmpeg_filter_args.is_empty() {
-            match &self.args.input_pix_format {
-              InputPixelFormat::FFmpeg { format } => {
-                if self.args.output_pix_format.format == *format {
-                  (source_pipe_stdout, source_pipe_stderr, None)
-                } else {
-                  create_ffmpeg_pipe(source_pipe_stdout, source_pipe_stderr)
-                }
-              }
-              InputPixelFormat::VapourSynth { bit_depth } => {
-                if self.args.output_pix_format.bit_depth == *bit_depth {
-                  (source_pipe_stdout, source_pipe_stderr, None)
-                } else {
-                  create_ffmpeg_pipe(source_pipe_stdout, source_pipe_stderr)
-                }
-              }
-            }
+        let (mut y4m_pipe, source_pipe_stderr, mut ffmpeg_pipe_stderr) =
+          if let Some(ffmpeg_cmd) = &ffmpeg_cmd {
+            create_ffmpeg_pipe(source_pipe_stdout, source_pipe_stderr, ffmpeg_cmd)
           } else {
-            create_ffmpeg_pipe(source_pipe_stdout, source_pipe_stderr)
+            (source_pipe_stdout, source_pipe_stderr, None)
           };
 
         let mut source_reader = BufReader::new(source_pipe_stderr).lines();
@@ -584,60 +1702,131 @@ impl Av1anContext {
         }
 
         let mut enc_pipe = if let [encoder, args @ ..] = &*enc_cmd {
-          tokio::process::Command::new(encoder)
+          let mut command = tokio::process::Command::new(encoder);
+          self.set_priority(&mut command);
+          group.add(&mut command);
+          let child = command
             .args(args)
-            .stdin(y4m_pipe)
+            .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .spawn()
-            .unwrap()
+            .unwrap();
+          group.register(&child);
+          child
         } else {
           unreachable!()
         };
 
-        let mut frame = 0;
-
-        let mut reader = BufReader::new(enc_pipe.stderr.take().unwrap());
-
-        let mut buf = Vec::with_capacity(128);
-        let mut enc_stderr = String::with_capacity(128);
-
-        while let Ok(read) = reader.read_until(b'\r', &mut buf).await {
-          if read == 0 {
-            break;
+        // the progress is the highest of the frames piped into the encoder and of the
+        // frames it reports, as parsing its output breaks with new versions of encoders
+        let frame = Cell::new(0);
+        let report_progress = |new: u64| {
+          if current_pass != chunk.passes || new <= frame.get() {
+            return;
+          }
+          // the progress of parts is reported as the progress of their chunk
+          let offset = chunk.part.map_or(0, |part| part.start) as u64;
+          logging::chunk_progress(chunk.index, worker_id, offset + new);
+          if self.args.verbosity == Verbosity::Normal {
+            inc_bar(new - frame.get());
+          } else if self.args.verbosity == Verbosity::Verbose {
+            inc_mp_bar(new - frame.get());
           }
+          frame.set(new);
+        };
 
-          if let Ok(line) = simdutf8::basic::from_utf8_mut(&mut buf) {
-            if self.args.verbosity == Verbosity::Verbose && !line.contains('\n') {
-              update_mp_msg(worker_id, line.trim().to_string());
+        let mut enc_stdin = enc_pipe.stdin.take().unwrap();
+        let splice = async {
+          let mut counter = y4m::FrameCounter::default();
+          let mut buf = vec![0; SPLICE_BUFFER_SIZE];
+          loop {
+            let read = match y4m_pipe.read(&mut buf).await {
+              Ok(0) | Err(_) => break,
+              Ok(read) => read,
+            };
+            // the encoder exited early, which is reported from its exit status
+            if enc_stdin.write_all(&buf[..read]).await.is_err() {
+              break;
             }
-            // This needs to be done before parse_encoded_frames, as it potentially
-            // mutates the string
-            enc_stderr.push_str(line);
-            enc_stderr.push('\n');
-
-            if current_pass == chunk.passes {
-              if let Some(new) = chunk.encoder.parse_encoded_frames(line) {
-                if new > frame {
-                  if self.args.verbosity == Verbosity::Normal {
-                    inc_bar(new - frame);
-                  } else if self.args.verbosity == Verbosity::Verbose {
-                    inc_mp_bar(new - frame);
+            report_progress(counter.feed(&buf[..read]));
+          }
+          // closes the input of the encoder
+          drop(enc_stdin);
+        };
+
+        let mut reader = BufReader::new(enc_pipe.stderr.take().unwrap());
+        let read_stderr = async {
+          let mut buf = Vec::with_capacity(128);
+          let mut enc_stderr = String::with_capacity(128);
+
+          // the progress is estimated from the size of the output if the encoder stops
+          // reporting it, in case a new version changed the format of its output
+          let mut last_parsed = Instant::now();
+          let mut estimating = false;
+          let mut checks = tokio::time::interval(PROGRESS_CHECK_INTERVAL);
+
+          loop {
+            // the bytes of a line are kept in buf if the check interrupts its read
+            let read = tokio::select! {
+              read = reader.read_until(b'\r', &mut buf) => read,
+              _ = checks.tick() => {
+                if current_pass != chunk.passes {
+                  continue;
+                }
+                if !estimating && last_parsed.elapsed() > STALLED_PROGRESS {
+                  estimating = true;
+                  warn_stalled_progress(chunk, worker_id);
+                }
+                if estimating {
+                  if let Some(estimate) = estimate_encoded_frames(&chunk.output(), chunk.frames()) {
+                    report_progress(estimate);
                   }
-                  frame = new;
                 }
+                continue;
+              }
+            };
+            let Ok(read) = read else {
+              break;
+            };
+            if read == 0 {
+              break;
+            }
+
+            if let Ok(line) = simdutf8::basic::from_utf8_mut(&mut buf) {
+              if self.args.verbosity == Verbosity::Verbose && !line.contains('\n') {
+                update_mp_msg(worker_id, line.trim().to_string());
+              }
+              // This needs to be done before parse_encoded_frames, as it potentially
+              // mutates the string
+              enc_stderr.push_str(line);
+              enc_stderr.push('\n');
+
+              let parsed = self.args.intermediate.map_or_else(
+                || chunk.encoder.parse_encoded_frames(line),
+                |intermediate| intermediate.parse_encoded_frames(line),
+              );
+              if let Some(new) = parsed {
+                last_parsed = Instant::now();
+                report_progress(new);
               }
             }
+
+            buf.clear();
           }
 
-          buf.clear();
-        }
+          enc_stderr
+        };
+
+        let ((), enc_stderr) = tokio::join!(splice, read_stderr);
+        let frame = frame.get();
 
         let enc_output = enc_pipe.wait_with_output().await.unwrap();
 
         let source_pipe_stderr = pipe_stderr.lock().clone();
         let ffmpeg_pipe_stderr = ffmpeg_stderr.map(|x| x.lock().clone());
         (
+          source_pipe,
           source_pipe_stderr,
           ffmpeg_pipe_stderr,
           enc_output,
@@ -645,6 +1834,27 @@ impl Av1anContext {
           frame,
         )
       });
+    // the source leads the process group, so it is only left to be reaped once the
+    // group is killed
+    drop(group);
+    drop(source_pipe);
+
+    let ffmpeg_log = ffmpeg_pipe_stderr
+      .as_ref()
+      .map_or_else(String::new, |stderr| {
+        format!("\nffmpeg pipe stderr:\n{stderr}\n")
+      });
+    let log = format!(
+      "pipeline: {}\nexit status: {}\n\nencoder stderr:\n{enc_stderr}\nsource pipe stderr:\n{source_pipe_stderr}\n{ffmpeg_log}",
+      pipeline.iter().map(|command| command.join(" ")).join(" | "),
+      enc_output.status,
+    );
+    if let Err(e) = fs::write(chunk.log_file(current_pass), log) {
+      warn!(
+        "[chunk {}] failed to write the log of pass {}: {}",
+        chunk.index, current_pass, e
+      );
+    }
 
     if !enc_output.status.success() {
       return Err((
@@ -654,6 +1864,7 @@ impl Av1anContext {
           ffmpeg_pipe_stderr: ffmpeg_pipe_stderr.map(Into::into),
           stderr: enc_stderr.into(),
           stdout: enc_output.stdout.into(),
+          pipeline,
         }),
         frame,
       ));
@@ -685,6 +1896,7 @@ impl Av1anContext {
             ffmpeg_pipe_stderr: ffmpeg_pipe_stderr.map(Into::into),
             stderr: enc_stderr.into(),
             stdout: err_str.into(),
+            pipeline,
           }),
           frame,
         ));
@@ -711,6 +1923,79 @@ impl Av1anContext {
       Input::VapourSynth { path, .. } => self.create_video_queue_vs(scenes, path.as_path()),
     };
 
+    if let Some(fps) = self.args.fps {
+      // the output frames of each chunk are derived from the whole video, so that they add up
+      let source_rate = self.args.input.frame_rate()?;
+      for chunk in &mut chunks {
+        let scene = &scenes[chunk.index];
+        let frames = fps.convert_frame(scene.end_frame, source_rate)
+          - fps.convert_frame(scene.start_frame, source_rate);
+        chunk.output_frames = Some(frames.max(1));
+        chunk.frame_rate = fps.as_f64();
+      }
+    }
+
+    if self.args.fps.is_none() && changes_frame_count(&self.args.ffmpeg_filter_args) {
+      self.count_filtered_frames(&mut chunks)?;
+    }
+
+    // the chunks of skip zones keep their near-lossless settings
+    let (skipped, mut chunks): (Vec<Chunk>, Vec<Chunk>) =
+      chunks.into_iter().partition(|chunk| chunk.skip);
+
+    if self.args.quick_consistency {
+      complexity::apply(
+        &mut chunks,
+        Path::new(&self.args.temp),
+        self.pipe_filter_args(),
+        // the proxy encodes are single-threaded
+        available_parallelism().map_or(1, std::num::NonZero::get),
+      )?;
+    }
+
+    if let Some(allocation) = &self.args.allocate_bitrate {
+      bit_allocation::apply(
+        &mut chunks,
+        allocation,
+        // the probe encodes are single-threaded, so all cores are used unless
+        // --workers is given
+        match self.args.workers {
+          0 => available_parallelism().map_or(1, std::num::NonZero::get),
+          workers => workers,
+        },
+      )?;
+    }
+
+    chunks.extend(skipped);
+    chunks.sort_unstable_by_key(|chunk| chunk.index);
+
+    if self.args.dolby_vision {
+      let temp = Path::new(&self.args.temp);
+      let rpu = dovi::extract_rpu(self.args.input.as_video_path(), temp)?;
+      // chunks are created from the scenes in order, so the chunk index is the scene index
+      for chunk in &mut chunks {
+        let scene = &scenes[chunk.index];
+        let chunk_rpu = dovi::chunk_rpu(
+          temp,
+          &rpu,
+          &chunk.name(),
+          scene.start_frame..scene.end_frame,
+          self.frames,
+        )?;
+        chunk.video_params.push("--dolby-vision-rpu".to_owned());
+        chunk.video_params.push(chunk_rpu.display().to_string());
+        if !chunk
+          .video_params
+          .iter()
+          .any(|param| param == "--dolby-vision-profile")
+        {
+          chunk
+            .video_params
+            .extend(["--dolby-vision-profile".to_owned(), "8.1".to_owned()]);
+        }
+      }
+    }
+
     match self.args.chunk_order {
       ChunkOrdering::LongestFirst => {
         chunks.sort_unstable_by_key(|chunk| Reverse(chunk.frames()));
@@ -729,22 +2014,54 @@ impl Av1anContext {
     Ok(chunks)
   }
 
-  fn calc_split_locations(&self) -> anyhow::Result<(Vec<Scene>, usize)> {
+  /// Returns the scenes, the number of frames, and the luma difference of each
+  /// frame to the previous one, which is empty without scene detection
+  fn calc_split_locations(
+    &self,
+    min_scene_len: usize,
+  ) -> anyhow::Result<(Vec<Scene>, usize, Vec<f64>)> {
     let zones = self.parse_zones()?;
 
     Ok(match self.args.split_method {
-      SplitMethod::AvScenechange => av_scenechange_detect(
-        &self.args.input,
-        self.args.encoder,
+      SplitMethod::AvScenechange if self.args.sc_backend == ScBackend::AvScenechange => {
+        av_scenechange_detect(
+          self.scene_detection_input()?,
+          self.args.encoder,
+          self.frames,
+          min_scene_len,
+          self.args.verbosity,
+          self.args.scaler.as_str(),
+          self.args.sc_pix_format,
+          self.args.sc_method,
+          self.args.sc_downscale_height,
+          &zones,
+        )?
+      }
+      SplitMethod::AvScenechange => external_detect(
+        self.args.sc_backend,
+        self.scene_detection_input()?,
+        // the script of the chunk method reads the input, not the proxy
+        self
+          .vs_script
+          .as_deref()
+          .filter(|_| self.args.sc_proxy.is_none()),
         self.frames,
-        self.args.min_scene_len,
+        min_scene_len,
         self.args.verbosity,
         self.args.scaler.as_str(),
-        self.args.sc_pix_format,
-        self.args.sc_method,
         self.args.sc_downscale_height,
         &zones,
       )?,
+      SplitMethod::SourceKeyframes => {
+        let keyframes = get_keyframes(self.args.input.as_video_path())
+          .context("Failed to read the keyframes of the source")?;
+        let scenes = keyframe_scenes(&keyframes, self.frames, min_scene_len, &zones);
+        info!(
+          "split into {} scene(s) at the keyframes of the source",
+          scenes.len()
+        );
+        (scenes, self.frames, Vec::new())
+      }
       SplitMethod::None => {
         let mut scenes = Vec::with_capacity(2 * zones.len() + 1);
         let mut frames_processed = 0;
@@ -756,6 +2073,7 @@ impl Av1anContext {
               start_frame: frames_processed,
               end_frame: zone.start_frame,
               zone_overrides: None,
+              cut: None,
             });
           }
 
@@ -768,20 +2086,48 @@ impl Av1anContext {
             start_frame: frames_processed,
             end_frame: self.frames,
             zone_overrides: None,
+            cut: None,
           });
         }
 
-        (scenes, self.args.input.frames()?)
+        (scenes, self.args.input.frames()?, Vec::new())
       }
     })
   }
 
+  /// Returns the input to detect the scenes of, which is the proxy of
+  /// `--sc-proxy` if it has as many frames as the input
+  fn scene_detection_input(&self) -> anyhow::Result<&Input> {
+    let Some(proxy) = &self.args.sc_proxy else {
+      return Ok(&self.args.input);
+    };
+
+    let proxy_frames = proxy.frames()?;
+    ensure!(
+      proxy_frames == self.frames,
+      "The scene detection proxy {} has {proxy_frames} frames, but the input has {}, so its scenes would not match the frames of the input",
+      proxy.as_path().display(),
+      self.frames
+    );
+    info!(
+      "detecting the scenes of the proxy {}",
+      proxy.as_path().display()
+    );
+    Ok(proxy)
+  }
+
   fn parse_zones(&self) -> anyhow::Result<Vec<Scene>> {
     let mut zones = Vec::new();
     if let Some(ref zones_file) = self.args.zones {
       let input = fs::read_to_string(zones_file)?;
-      for zone_line in input.lines().map(str::trim).filter(|line| !line.is_empty()) {
-        zones.push(Scene::parse_from_zone(zone_line, self)?);
+      for (i, zone_line) in input.lines().enumerate() {
+        let zone_line = zone_line.trim();
+        if zone_line.is_empty() {
+          continue;
+        }
+        zones.push(Scene::parse_from_zone(zone_line, self).with_context(|| {
+          format!("Invalid zone on line {} of {}", i + 1, zones_file.display())
+        })?);
       }
       zones.sort_unstable_by_key(|zone| zone.start_frame);
       let mut segments = BTreeSet::new();
@@ -803,15 +2149,27 @@ impl Av1anContext {
       |path| Cow::Borrowed(path.as_path()),
     );
 
+    // the scene lengths are given in frames of the output
+    let mut frames_per_output_frame = 1.0;
     let used_existing_cuts;
-    let (mut scenes, frames) =
+    let (mut scenes, frames, differences) =
       if (self.args.scenes.is_some() && scene_file.exists()) || self.args.resume {
         used_existing_cuts = true;
-        crate::split::read_scenes_from_file(scene_file.as_ref())?
+        let (scenes, frames) = crate::split::read_scenes_from_file(scene_file.as_ref())?;
+        (scenes, frames, Vec::new())
       } else {
         used_existing_cuts = false;
         self.frames = self.args.input.frames()?;
-        self.calc_split_locations()?
+        if self.args.fps.is_none() && changes_frame_count(&self.args.ffmpeg_filter_args) {
+          let filtered = self.count_filtered_input_frames()?;
+          frames_per_output_frame = self.frames as f64 / filtered as f64;
+          info!(
+            "the filters of --ffmpeg leave {filtered} of {} frames, the scene lengths are scaled \
+             to frames of the source",
+            self.frames
+          );
+        }
+        self.calc_split_locations(source_len(self.args.min_scene_len, frames_per_output_frame))?
       };
     self.frames = frames;
     get_done()
@@ -819,7 +2177,18 @@ impl Av1anContext {
       .store(self.frames, atomic::Ordering::SeqCst);
 
     // Add forced keyframes
-    for kf in &self.args.force_keyframes {
+    let mut force_keyframes = self.args.force_keyframes.clone();
+    if self.args.force_keyframes_from_chapters {
+      let chapters = chapter_frames(self.args.input.as_video_path())?;
+      info!(
+        "forcing keyframes at the start of {} chapter(s) of the input",
+        chapters.len()
+      );
+      force_keyframes.extend(chapters);
+      force_keyframes.sort_unstable();
+      force_keyframes.dedup();
+    }
+    for kf in &force_keyframes {
       if let Some((scene_pos, s)) = scenes
         .iter_mut()
         .find_position(|s| (s.start_frame..s.end_frame).contains(kf))
@@ -832,6 +2201,7 @@ impl Av1anContext {
         let mut new = s.clone();
         s.end_frame = *kf;
         new.start_frame = *kf;
+        new.cut = None;
         scenes.insert(scene_pos + 1, new);
       } else {
         warn!(
@@ -841,10 +2211,42 @@ impl Av1anContext {
       }
     }
 
+    if let Some(interval) = self
+      .args
+      .min_keyframe_interval
+      .filter(|_| !used_existing_cuts)
+    {
+      let mut keep = force_keyframes;
+      keep.extend(
+        self
+          .parse_zones()?
+          .iter()
+          .flat_map(|zone| [zone.start_frame, zone.end_frame]),
+      );
+      let found = scenes.len();
+      scenes = merge_short_scenes(scenes, source_len(interval, frames_per_output_frame), &keep);
+      info!(
+        "scenecut: merged {} scene(s) closer than {} frames to the previous keyframe",
+        found - scenes.len(),
+        interval
+      );
+    }
+
     let scenes_before = scenes.len();
     if !used_existing_cuts {
       if let Some(split_len @ 1..) = self.args.extra_splits_len {
-        scenes = extra_splits(&scenes, self.frames, split_len);
+        let source_split_len = source_len(split_len, frames_per_output_frame);
+        scenes = if self.args.extra_splits_adaptive && !differences.is_empty() {
+          adaptive_extra_splits(
+            &scenes,
+            self.frames,
+            source_split_len,
+            source_len(self.args.min_scene_len, frames_per_output_frame),
+            &differences,
+          )
+        } else {
+          extra_splits(&scenes, self.frames, source_split_len)
+        };
         let scenes_after = scenes.len();
         info!(
           "scenecut: found {} scene(s) [with extra_splits ({} frames): {} scene(s)]",
@@ -860,21 +2262,34 @@ impl Av1anContext {
     Ok(scenes)
   }
 
-  fn create_select_chunk(
+  /// Returns the settings of a zone, or the settings passed to av1an itself
+  /// for chunks outside of any zone
+  fn zone_options(&self, overrides: Option<ZoneOptions>) -> ZoneOptions {
+    overrides.unwrap_or_else(|| ZoneOptions {
+      encoder: self.args.encoder,
+      passes: self.args.passes,
+      video_params: self.args.video_params.clone(),
+      photon_noise: self.args.photon_noise,
+      photon_noise_size: self.args.photon_noise_size,
+      chroma_noise: self.args.chroma_noise,
+      target_quality: ChunkTarget::Inherit,
+      extra_splits_len: self.args.extra_splits_len,
+      min_scene_len: self.args.min_scene_len,
+      pix_format: None,
+      skip: false,
+      denoise: None,
+    })
+  }
+
+  /// Returns the command decoding the frames of a chunk from the source with
+  /// ffmpeg
+  fn select_source_cmd(
     &self,
-    index: usize,
     src_path: &Path,
     start_frame: usize,
     end_frame: usize,
-    frame_rate: f64,
-    overrides: Option<ZoneOptions>,
-  ) -> anyhow::Result<Chunk> {
-    assert!(
-      start_frame < end_frame,
-      "Can't make a chunk with <= 0 frames!"
-    );
-
-    let ffmpeg_gen_cmd: Vec<OsString> = into_vec![
+  ) -> Vec<OsString> {
+    into_vec![
       "ffmpeg",
       "-y",
       "-hide_banner",
@@ -897,9 +2312,27 @@ impl Av1anContext {
       "-f",
       "yuv4mpegpipe",
       "-",
-    ];
+    ]
+  }
+
+  pub(crate) fn create_select_chunk(
+    &self,
+    index: usize,
+    src_path: &Path,
+    start_frame: usize,
+    end_frame: usize,
+    frame_rate: f64,
+    overrides: Option<ZoneOptions>,
+  ) -> anyhow::Result<Chunk> {
+    assert!(
+      start_frame < end_frame,
+      "Can't make a chunk with <= 0 frames!"
+    );
+
+    let ffmpeg_gen_cmd = self.select_source_cmd(src_path, start_frame, end_frame);
 
-    let output_ext = self.args.encoder.output_extension();
+    let zone = self.zone_options(overrides);
+    let output_ext = zone.encoder.output_extension();
 
     let mut chunk = Chunk {
       temp: self.args.temp.clone(),
@@ -911,22 +2344,29 @@ impl Av1anContext {
       output_ext: output_ext.to_owned(),
       start_frame,
       end_frame,
+      output_frames: None,
       frame_rate,
-      video_params: overrides.as_ref().map_or_else(
-        || self.args.video_params.clone(),
-        |ovr| ovr.video_params.clone(),
-      ),
-      passes: self.args.passes,
-      encoder: self.args.encoder,
-      noise_size: self.args.photon_noise_size,
+      video_params: zone.video_params,
+      passes: zone.passes,
+      encoder: zone.encoder,
+      noise_size: zone.photon_noise_size,
       tq_cq: None,
+      target_quality: zone.target_quality,
       ignore_frame_mismatch: self.args.ignore_frame_mismatch,
+      pix_format: zone.pix_format,
+      part: None,
+      skip: zone.skip,
+      denoise: zone.denoise,
     };
-    chunk.apply_photon_noise_args(
-      overrides.map_or(self.args.photon_noise, |ovr| ovr.photon_noise),
-      self.args.chroma_noise,
-    )?;
-    if let Some(ref tq) = self.args.target_quality {
+    chunk.apply_photon_noise_args(zone.photon_noise, zone.chroma_noise)?;
+    // with --max-temp-size the segment is not split yet, the chunk is probed
+    // once it is encoded
+    if let Some(tq) = self
+      .args
+      .target_quality
+      .as_ref()
+      .filter(|_| self.args.max_temp_size.is_none())
+    {
       tq.per_shot_target_quality_routine(&mut chunk)?;
     }
     Ok(chunk)
@@ -939,22 +2379,10 @@ impl Av1anContext {
     scene: &Scene,
     frame_rate: f64,
   ) -> anyhow::Result<Chunk> {
-    // the frame end boundary is actually a frame that should be included in the next chunk
-    let frame_end = scene.end_frame - 1;
-
-    let vspipe_cmd_gen: Vec<OsString> = into_vec![
-      "vspipe",
-      vs_script,
-      "-c",
-      "y4m",
-      "-",
-      "-s",
-      scene.start_frame.to_string(),
-      "-e",
-      frame_end.to_string(),
-    ];
+    let vspipe_cmd_gen = vspipe_source_cmd(vs_script, scene.start_frame, scene.end_frame);
 
-    let output_ext = self.args.encoder.output_extension();
+    let zone = self.zone_options(scene.zone_overrides.clone());
+    let output_ext = zone.encoder.output_extension();
 
     let mut chunk = Chunk {
       temp: self.args.temp.clone(),
@@ -967,27 +2395,114 @@ impl Av1anContext {
       output_ext: output_ext.to_owned(),
       start_frame: scene.start_frame,
       end_frame: scene.end_frame,
+      output_frames: None,
       frame_rate,
-      video_params: scene.zone_overrides.as_ref().map_or_else(
-        || self.args.video_params.clone(),
-        |ovr| ovr.video_params.clone(),
-      ),
-      passes: self.args.passes,
-      encoder: self.args.encoder,
-      noise_size: self.args.photon_noise_size,
+      video_params: zone.video_params,
+      passes: zone.passes,
+      encoder: zone.encoder,
+      noise_size: zone.photon_noise_size,
       tq_cq: None,
+      target_quality: zone.target_quality,
       ignore_frame_mismatch: self.args.ignore_frame_mismatch,
+      pix_format: zone.pix_format,
+      part: None,
+      skip: zone.skip,
+      denoise: zone.denoise,
     };
-    chunk.apply_photon_noise_args(
-      scene
-        .zone_overrides
-        .as_ref()
-        .map_or(self.args.photon_noise, |ovr| ovr.photon_noise),
-      self.args.chroma_noise,
-    )?;
+    chunk.apply_photon_noise_args(zone.photon_noise, zone.chroma_noise)?;
     Ok(chunk)
   }
 
+  /// Returns the chunk methods to decode a chunk with, in order, if it keeps
+  /// failing with the chunk method of the encode
+  pub(crate) fn fallback_methods(&self) -> Vec<ChunkMethod> {
+    if !self.args.input.is_video() {
+      return Vec::new();
+    }
+    let mut methods = match self.args.chunk_method {
+      ChunkMethod::LSMASH | ChunkMethod::DGDECNV | ChunkMethod::BESTSOURCE => {
+        vec![ChunkMethod::FFMS2, ChunkMethod::Hybrid]
+      }
+      ChunkMethod::FFMS2 => vec![ChunkMethod::Hybrid],
+      _ => Vec::new(),
+    };
+    // ffmpeg would not deinterlace the chunk like the VapourSynth script
+    if self.qtgmc.is_some() {
+      methods.retain(|&method| method != ChunkMethod::Hybrid);
+    }
+    methods
+  }
+
+  /// Regenerates the source command of a chunk to decode it with another
+  /// chunk method, keeping the rest of the chunk
+  pub(crate) fn fallback_chunk(&self, chunk: &Chunk, method: ChunkMethod) -> anyhow::Result<Chunk> {
+    let source = self.args.input.as_video_path();
+    let (input, source_cmd) = match method {
+      ChunkMethod::FFMS2 => {
+        let script = self
+          .fallback_script
+          .get_or_try_init(|| self.create_fallback_script(source))?;
+        (
+          Input::VapourSynth {
+            path: script.clone(),
+            vspipe_args: Vec::new(),
+          },
+          vspipe_source_cmd(script, chunk.start_frame, chunk.end_frame),
+        )
+      }
+      ChunkMethod::Hybrid | ChunkMethod::Select => (
+        Input::Video {
+          path: source.to_path_buf(),
+        },
+        self.select_source_cmd(source, chunk.start_frame, chunk.end_frame),
+      ),
+      _ => bail!(
+        "{} can not be used as a fallback chunk method",
+        <&str>::from(method)
+      ),
+    };
+
+    Ok(Chunk {
+      input,
+      source_cmd,
+      ..chunk.clone()
+    })
+  }
+
+  /// Creates the script loading the source with ffms2 in its own folder, and
+  /// indexes the source once, rather than in every worker falling back to it
+  fn create_fallback_script(&self, source: &Path) -> anyhow::Result<PathBuf> {
+    let temp = Path::new(&self.args.temp).join("fallback");
+    fs::create_dir_all(temp.join("split"))
+      .with_context(|| format!("Failed to create {}", temp.display()))?;
+    let script = create_vs_file(
+      &temp.to_string_lossy(),
+      &temp.join("split"),
+      source,
+      ChunkMethod::FFMS2,
+      self.qtgmc,
+      self.args.bestsource_cachemode,
+      &[],
+      // the frames of the fallback are piped like those of the chunk method
+      self.script_filters.as_deref(),
+    )?;
+
+    let status = Command::new("vspipe")
+      .arg("-i")
+      .arg(&script)
+      .arg("-")
+      .stdout(Stdio::null())
+      .stderr(Stdio::null())
+      .status()
+      .context("Failed to run vspipe to index the source with ffms2")?;
+    ensure!(
+      status.success(),
+      "Failed to index the source with ffms2 ({status})"
+    );
+
+    Ok(script)
+  }
+
   fn create_video_queue_vs(&self, scenes: &[Scene], vs_script: &Path) -> Vec<Chunk> {
     let frame_rate = self.args.input.frame_rate().unwrap();
     let chunk_queue: Vec<Chunk> = scenes
@@ -1081,12 +2596,20 @@ impl Av1anContext {
       .copied()
       .collect();
 
-    debug!("Segmenting video");
-    segment(input, &self.args.temp, &to_split[1..]);
-    debug!("Segment done");
-
     let source_path = Path::new(&self.args.temp).join("split");
-    let queue_files = Self::read_queue_files(&source_path)?;
+    let queue_files = if let Some(max_size) = self.args.max_temp_size {
+      debug!("Segmenting video in the background");
+      segment_within(input, Path::new(&self.args.temp), &to_split[1..], max_size)?;
+      // the segments do not exist yet, they are waited for by the workers
+      (0..to_split.len())
+        .map(|i| source_path.join(format!("{i:05}.mkv")))
+        .collect()
+    } else {
+      debug!("Segmenting video");
+      segment(input, &self.args.temp, &to_split[1..]);
+      debug!("Segment done");
+      Self::read_queue_files(&source_path)?
+    };
 
     let kf_list = to_split
       .iter()
@@ -1156,7 +2679,8 @@ impl Av1anContext {
       "-",
     ];
 
-    let output_ext = self.args.encoder.output_extension();
+    let zone = self.zone_options(overrides);
+    let output_ext = zone.encoder.output_extension();
 
     let num_frames = num_frames(Path::new(file))?;
 
@@ -1170,29 +2694,125 @@ impl Av1anContext {
       index,
       start_frame: 0,
       end_frame: num_frames,
+      output_frames: None,
       frame_rate,
-      video_params: overrides.as_ref().map_or_else(
-        || self.args.video_params.clone(),
-        |ovr| ovr.video_params.clone(),
-      ),
-      passes: self.args.passes,
-      encoder: self.args.encoder,
-      noise_size: self.args.photon_noise_size,
+      video_params: zone.video_params,
+      passes: zone.passes,
+      encoder: zone.encoder,
+      noise_size: zone.photon_noise_size,
       tq_cq: None,
+      target_quality: zone.target_quality,
       ignore_frame_mismatch: self.args.ignore_frame_mismatch,
+      pix_format: zone.pix_format,
+      part: None,
+      skip: zone.skip,
+      denoise: zone.denoise,
     };
-    chunk.apply_photon_noise_args(
-      overrides.map_or(self.args.photon_noise, |ovr| ovr.photon_noise),
-      self.args.chroma_noise,
-    )?;
+    chunk.apply_photon_noise_args(zone.photon_noise, zone.chroma_noise)?;
     Ok(chunk)
   }
 
+  /// Counts the frames that each chunk has after the filters of `--ffmpeg`, by
+  /// passing it through the filters the same way as when it is encoded, so
+  /// that the frame counts of the chunks and the progress match the output
+  fn count_filtered_frames(&self, chunks: &mut [Chunk]) -> anyhow::Result<()> {
+    info!(
+      "counting the frames of {} chunks after the filters of --ffmpeg",
+      chunks.len()
+    );
+    let next = AtomicUsize::new(0);
+    let counts = Mutex::new(vec![0; chunks.len()]);
+    let workers = available_parallelism().map_or(1, std::num::NonZero::get);
+    thread::scope(|s| {
+      let workers: Vec<_> = (0..workers)
+        .map(|_| {
+          s.spawn(|| -> anyhow::Result<()> {
+            loop {
+              let i = next.fetch_add(1, atomic::Ordering::Relaxed);
+              let Some(chunk) = chunks.get(i) else {
+                return Ok(());
+              };
+              let frames = count_chunk_frames(chunk, &self.args.ffmpeg_filter_args)?;
+              counts.lock()[i] = frames;
+            }
+          })
+        })
+        .collect();
+
+      workers
+        .into_iter()
+        .try_for_each(|worker| worker.join().unwrap())
+    })?;
+
+    for (chunk, frames) in chunks.iter_mut().zip(counts.into_inner()) {
+      if frames == 0 {
+        bail!(
+          "chunk {} has no frames left after the filters of --ffmpeg",
+          chunk.name()
+        );
+      }
+      chunk.output_frames = Some(frames);
+    }
+    self
+      .filtered_frames
+      .set(chunks.iter().map(Chunk::frames).sum())
+      .ok();
+    debug!(
+      "{} frames after the filters of --ffmpeg, from {} frames",
+      self.output_frames(),
+      self.frames
+    );
+
+    Ok(())
+  }
+
+  /// Counts the frames of the whole input after the filters of `--ffmpeg`
+  fn count_filtered_input_frames(&self) -> anyhow::Result<usize> {
+    info!("counting the frames of the input after the filters of --ffmpeg");
+    let source_command = match &self.args.input {
+      Input::Video { path } => {
+        let mut cmd = Command::new("ffmpeg");
+        cmd
+          .args(["-hide_banner", "-loglevel", "error", "-i"])
+          .arg(path)
+          .args(["-map", "0:v:0", "-strict", "-1", "-f", "yuv4mpegpipe", "-"]);
+        cmd
+      }
+      Input::VapourSynth { path, vspipe_args } => {
+        let mut cmd = Command::new("vspipe");
+        for arg in vspipe_args {
+          cmd.args(["-a", arg]);
+        }
+        cmd.args(["-c", "y4m"]).arg(path).arg("-");
+        cmd
+      }
+    };
+    let frames = count_piped_frames(source_command, &self.args.ffmpeg_filter_args)
+      .context("Failed to count the frames of the input after the filters of --ffmpeg")?;
+    ensure!(
+      frames > 0,
+      "no frames are left after the filters of --ffmpeg"
+    );
+    Ok(frames)
+  }
+
   /// Returns unfinished chunks and number of total chunks
   fn load_or_gen_chunk_queue(&self, splits: &[Scene]) -> anyhow::Result<(Vec<Chunk>, usize)> {
     if self.args.resume {
-      let mut chunks = read_chunk_queue(self.args.temp.as_ref())?;
+      let mut chunks = if Path::new(&self.args.temp).join("chunks.json").exists() {
+        read_chunk_queue(self.args.temp.as_ref())?
+      } else {
+        let chunks = self.create_encoding_queue(splits)?;
+        save_chunk_queue(&self.args.temp, &chunks)?;
+        chunks
+      };
       let num_chunks = chunks.len();
+      if chunks.iter().any(|chunk| chunk.output_frames.is_some()) && self.args.fps.is_none() {
+        self
+          .filtered_frames
+          .set(chunks.iter().map(Chunk::frames).sum())
+          .ok();
+      }
 
       let done = get_done();
 
@@ -1208,3 +2828,122 @@ impl Av1anContext {
     }
   }
 }
+
+/// Passes the frames of a chunk through the ffmpeg filters, and returns the
+/// number of frames that come out
+fn count_chunk_frames(chunk: &Chunk, ffmpeg_filter_args: &[String]) -> anyhow::Result<usize> {
+  let [source, source_args @ ..] = &*chunk.source_cmd else {
+    unreachable!()
+  };
+  let mut source_command = Command::new(source);
+  if let Input::VapourSynth { vspipe_args, .. } = &chunk.input {
+    for arg in vspipe_args {
+      source_command.args(["-a", arg]);
+    }
+  }
+  source_command.args(source_args);
+  count_piped_frames(source_command, ffmpeg_filter_args)
+    .with_context(|| format!("Failed to count the frames of chunk {}", chunk.name()))
+}
+
+/// Passes the frames that `source_command` writes to stdout through the ffmpeg
+/// filters, and returns the number of frames that come out
+fn count_piped_frames(
+  mut source_command: Command,
+  ffmpeg_filter_args: &[String],
+) -> anyhow::Result<usize> {
+  let mut source_pipe = source_command
+    .stdin(Stdio::null())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::null())
+    .spawn()
+    .context("Failed to spawn the source")?;
+
+  let output = Command::new("ffmpeg")
+    .args(["-hide_banner", "-nostats", "-loglevel", "error", "-i", "-"])
+    .args(ffmpeg_filter_args)
+    .args(["-progress", "pipe:1", "-f", "null", "-"])
+    .stdin(source_pipe.stdout.take().unwrap())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .output()
+    .context("Failed to run ffmpeg to count the frames after the filters")?;
+  source_pipe.wait()?;
+  if !output.status.success() {
+    bail!(
+      "ffmpeg failed to filter the frames:\n{}",
+      String::from_utf8_lossy(&output.stderr)
+    );
+  }
+
+  Ok(
+    String::from_utf8_lossy(&output.stdout)
+      .lines()
+      .filter_map(|line| line.strip_prefix("frame="))
+      .filter_map(|frames| frames.trim().parse().ok())
+      .next_back()
+      .unwrap_or(0),
+  )
+}
+
+/// Converts a number of frames of the output into frames of the source, where
+/// a length of at least one frame stays at least one frame
+fn source_len(frames: usize, frames_per_output_frame: f64) -> usize {
+  ((frames as f64 * frames_per_output_frame).round() as usize).max(frames.min(1))
+}
+
+/// Warns that the output of the encoder has no progress, once per run
+fn warn_stalled_progress(chunk: &Chunk, worker_id: usize) {
+  if ESTIMATING_PROGRESS.swap(true, atomic::Ordering::Relaxed) {
+    debug!(
+      "worker {worker_id}: no progress from {} on chunk {} for {}s, estimating it from the size of the output",
+      chunk.encoder,
+      chunk.name(),
+      STALLED_PROGRESS.as_secs()
+    );
+  } else {
+    warn!(
+      "worker {worker_id}: no progress could be read from {} on chunk {} for {}s, it is estimated from the size of the output; the format of the output of this version of {} may not be supported",
+      chunk.encoder,
+      chunk.name(),
+      STALLED_PROGRESS.as_secs(),
+      chunk.encoder
+    );
+  }
+}
+
+/// Estimates the frames encoded so far from the size of the output of a
+/// chunk, at the average size of the frames of the finished chunks. The
+/// estimate stays below the frames of the chunk, which are only reported as
+/// done once they are.
+fn estimate_encoded_frames(output: &str, frames: usize) -> Option<u64> {
+  let (done_frames, done_bytes) = get_done()
+    .done
+    .iter()
+    .fold((0, 0), |(frames, bytes), chunk| {
+      (frames + chunk.frames, bytes + chunk.size_bytes)
+    });
+  if done_frames == 0 || done_bytes == 0 {
+    return None;
+  }
+
+  let size = fs::metadata(output).ok()?.len();
+  let estimate = size as f64 * done_frames as f64 / done_bytes as f64;
+  Some((estimate as u64).min(frames.saturating_sub(1) as u64))
+}
+
+/// Returns the command decoding the frames of a chunk from a VapourSynth script
+fn vspipe_source_cmd(vs_script: &Path, start_frame: usize, end_frame: usize) -> Vec<OsString> {
+  // the frame end boundary is actually a frame that should be included in the next chunk
+  into_vec![
+    "vspipe",
+    vs_script,
+    "-c",
+    "y4m",
+    "-",
+    "-s",
+    start_frame.to_string(),
+    "-e",
+    (end_frame - 1).to_string(),
+  ]
+}
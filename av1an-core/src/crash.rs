@@ -0,0 +1,221 @@
+//! Reports of the chunks whose encoder crashed after all tries.
+//!
+//! The report is written to the temporary folder next to the chunks: a
+//! `crash_{chunk}.sh` script, or `.bat` on Windows, that runs the pipeline of
+//! the chunk again from the same folder and environment, and a
+//! `crash_{chunk}.json` record of the crash, to attach to bug reports.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::broker::EncoderCrash;
+use crate::chunk::Chunk;
+use crate::encoder::Encoder;
+
+/// Environment variables that change which programs and plugins the pipeline
+/// runs
+const ENVIRONMENT: &[&str] = &[
+  "PATH",
+  "LD_LIBRARY_PATH",
+  "DYLD_LIBRARY_PATH",
+  "PYTHONPATH",
+  "PYTHONHOME",
+];
+
+#[derive(Serialize, Debug)]
+struct CrashRecord<'a> {
+  av1an_version: &'static str,
+  chunk: usize,
+  start_frame: usize,
+  end_frame: usize,
+  frames: usize,
+  encoder: Encoder,
+  exit_status: String,
+  exit_code: Option<i32>,
+  pipeline: &'a [Vec<String>],
+  working_dir: PathBuf,
+  environment: BTreeMap<&'static str, String>,
+  stdout: String,
+  stderr: String,
+  source_pipe_stderr: String,
+  ffmpeg_pipe_stderr: Option<String>,
+}
+
+/// Writes the reproduction script and the record of the crash of a chunk, and
+/// returns the path of the script, or of the record if there is no pipeline to
+/// run again
+pub fn write_report(chunk: &Chunk, crash: &EncoderCrash) -> anyhow::Result<PathBuf> {
+  let temp = Path::new(&chunk.temp);
+  let working_dir = std::env::current_dir()?;
+  let environment: BTreeMap<_, _> = ENVIRONMENT
+    .iter()
+    .filter_map(|&name| Some((name, std::env::var(name).ok()?)))
+    .collect();
+
+  let record = CrashRecord {
+    av1an_version: env!("CARGO_PKG_VERSION"),
+    chunk: chunk.index,
+    start_frame: chunk.start_frame,
+    end_frame: chunk.end_frame,
+    frames: chunk.frames(),
+    encoder: chunk.encoder,
+    exit_status: crash.exit_status.to_string(),
+    exit_code: crash.exit_status.code(),
+    pipeline: &crash.pipeline,
+    working_dir: working_dir.clone(),
+    environment: environment.clone(),
+    stdout: String::from_utf8_lossy(crash.stdout.as_bytes()).into_owned(),
+    stderr: String::from_utf8_lossy(crash.stderr.as_bytes()).into_owned(),
+    source_pipe_stderr: String::from_utf8_lossy(crash.source_pipe_stderr.as_bytes()).into_owned(),
+    ffmpeg_pipe_stderr: crash
+      .ffmpeg_pipe_stderr
+      .as_ref()
+      .map(|stderr| String::from_utf8_lossy(stderr.as_bytes()).into_owned()),
+  };
+  let record_path = temp.join(format!("crash_{}.json", chunk.name()));
+  fs::write(&record_path, serde_json::to_string_pretty(&record)?)?;
+
+  if crash.pipeline.is_empty() {
+    return Ok(record_path);
+  }
+
+  let header = format!(
+    "Reproduces the crash of chunk {} (frames {}..{}) of av1an {}: {}",
+    chunk.name(),
+    chunk.start_frame,
+    chunk.end_frame,
+    env!("CARGO_PKG_VERSION"),
+    crash.exit_status
+  );
+  let (extension, script) = if cfg!(windows) {
+    (
+      "bat",
+      bat_script(&header, &working_dir, &environment, &crash.pipeline),
+    )
+  } else {
+    (
+      "sh",
+      sh_script(&header, &working_dir, &environment, &crash.pipeline),
+    )
+  };
+  let script_path = temp.join(format!("crash_{}.{extension}", chunk.name()));
+  fs::write(&script_path, script)?;
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(&script_path, fs::Permissions::from_mode(0o755))?;
+  }
+
+  Ok(script_path)
+}
+
+fn sh_script(
+  header: &str,
+  working_dir: &Path,
+  environment: &BTreeMap<&str, String>,
+  pipeline: &[Vec<String>],
+) -> String {
+  let mut script = format!(
+    "#!/bin/sh\n# {header}\ncd {}\n",
+    sh_quote(&working_dir.to_string_lossy())
+  );
+  for (name, value) in environment {
+    writeln!(script, "export {name}={}", sh_quote(value)).unwrap();
+  }
+  script.push_str(&join_pipeline(pipeline, sh_quote));
+  script.push('\n');
+  script
+}
+
+fn bat_script(
+  header: &str,
+  working_dir: &Path,
+  environment: &BTreeMap<&str, String>,
+  pipeline: &[Vec<String>],
+) -> String {
+  let mut script = format!(
+    "@echo off\r\nrem {header}\r\ncd /d {}\r\n",
+    bat_quote(&working_dir.to_string_lossy())
+  );
+  for (name, value) in environment {
+    write!(script, "set \"{name}={}\"\r\n", value.replace('%', "%%")).unwrap();
+  }
+  script.push_str(&join_pipeline(pipeline, bat_quote));
+  script.push_str("\r\n");
+  script
+}
+
+fn join_pipeline(pipeline: &[Vec<String>], quote: fn(&str) -> String) -> String {
+  pipeline
+    .iter()
+    .map(|command| {
+      command
+        .iter()
+        .map(|arg| quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+    })
+    .collect::<Vec<_>>()
+    .join(" | ")
+}
+
+/// Quotes an argument for a POSIX shell
+fn sh_quote(arg: &str) -> String {
+  if !arg.is_empty()
+    && arg
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || "-_=.,:/+@%".contains(c))
+  {
+    arg.to_owned()
+  } else {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+  }
+}
+
+/// Quotes an argument for cmd.exe
+fn bat_quote(arg: &str) -> String {
+  let arg = arg.replace('%', "%%");
+  if !arg.is_empty()
+    && arg
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || "-_=.,:/\\+@%".contains(c))
+  {
+    arg
+  } else {
+    format!("\"{}\"", arg.replace('"', "\"\""))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn quotes_pipeline() {
+    let pipeline = vec![
+      vec!["vspipe".to_owned(), "-c".to_owned(), "y4m".to_owned()],
+      vec![
+        "aomenc".to_owned(),
+        "--cq-level=30".to_owned(),
+        "-o".to_owned(),
+        "it's here/00001.ivf".to_owned(),
+        "-".to_owned(),
+      ],
+    ];
+    assert_eq!(
+      join_pipeline(&pipeline, sh_quote),
+      r"vspipe -c y4m | aomenc --cq-level=30 -o 'it'\''s here/00001.ivf' -"
+    );
+    assert_eq!(
+      join_pipeline(&pipeline, bat_quote),
+      r#"vspipe -c y4m | aomenc --cq-level=30 -o "it's here/00001.ivf" -"#
+    );
+    assert_eq!(sh_quote(""), "''");
+    assert_eq!(bat_quote("100%"), "100%%");
+    assert_eq!(bat_quote("a \"b\""), "\"a \"\"b\"\"\"");
+  }
+}
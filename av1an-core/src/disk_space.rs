@@ -0,0 +1,184 @@
+//! Free disk space for the temporary files and the output.
+//!
+//! Before encoding, the space the encode needs is estimated from the size of
+//! the source and compared with the free space of the disks of the temporary
+//! folder and of the output. While encoding, workers pause before starting a
+//! chunk while either disk has less than `--min-free-space` free, so the encode
+//! does not fail half way through, e.g. while concatenating.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::bail;
+use sysinfo::Disks;
+
+use crate::status::{self, State};
+use crate::util::format_size;
+use crate::ChunkMethod;
+
+/// How often paused workers check whether space was freed
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Whether the workers are paused, so that it is only logged once
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Space needed by an encode, in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Estimate {
+  pub temp: u64,
+  pub output: u64,
+}
+
+impl Estimate {
+  /// Estimates the space needed to encode the `remaining` fraction of a source
+  /// of `source_size` bytes, assuming the encode is no larger than the source.
+  ///
+  /// The temporary folder holds the encoded chunks, and for the segment and
+  /// hybrid chunk methods the segments of the source, of which there are at
+  /// most `max_temp_size` bytes, less the `present_segments` bytes already
+  /// written when resuming, as those already take up disk space. The output is
+  /// written entirely when concatenating, while the chunks are still in the
+  /// temporary folder.
+  pub fn new(
+    source_size: u64,
+    remaining: f64,
+    chunk_method: ChunkMethod,
+    max_temp_size: Option<u64>,
+    present_segments: u64,
+    concat: bool,
+  ) -> Self {
+    let remaining_size = (source_size as f64 * remaining.clamp(0.0, 1.0)) as u64;
+    let segments = match chunk_method {
+      ChunkMethod::Segment | ChunkMethod::Hybrid => max_temp_size
+        .map_or(remaining_size, |max| remaining_size.min(max))
+        .saturating_sub(present_segments),
+      _ => 0,
+    };
+    Self {
+      temp: remaining_size + segments,
+      output: if concat { source_size } else { 0 },
+    }
+  }
+}
+
+/// Returns the mount point and free space of the disk containing `path`, which
+/// does not need to exist yet
+fn disk_of(path: &Path) -> Option<(PathBuf, u64)> {
+  let path = std::path::absolute(path).ok()?;
+  let path = path.ancestors().find_map(|dir| dir.canonicalize().ok())?;
+  Disks::new_with_refreshed_list()
+    .iter()
+    .filter(|disk| path.starts_with(disk.mount_point()))
+    .max_by_key(|disk| disk.mount_point().as_os_str().len())
+    .map(|disk| (disk.mount_point().to_path_buf(), disk.available_space()))
+}
+
+/// Returns the free space of the disk containing `path`, if it can be found
+pub fn available_space(path: &Path) -> Option<u64> {
+  disk_of(path).map(|(_, available)| available)
+}
+
+/// Fails if the disks of the temporary folder and of the output do not have
+/// enough free space for the estimate. Disks whose free space cannot be found
+/// are not checked.
+pub fn check(temp: &Path, output: &Path, estimate: Estimate) -> anyhow::Result<()> {
+  let temp_disk = disk_of(temp);
+  let output_disk = disk_of(output);
+
+  let mut needed = Vec::new();
+  match (temp_disk, output_disk) {
+    (Some((temp_mount, available)), Some((output_mount, _))) if temp_mount == output_mount => {
+      needed.push((temp_mount, estimate.temp + estimate.output, available));
+    }
+    (temp_disk, output_disk) => {
+      needed.extend(temp_disk.map(|(mount, available)| (mount, estimate.temp, available)));
+      needed.extend(output_disk.map(|(mount, available)| (mount, estimate.output, available)));
+    }
+  }
+
+  for (mount, needed, available) in needed {
+    if needed > available {
+      bail!(
+        "Not enough disk space: the encode needs about {} on {}, but only {} is free. Free up some \
+         space, use --temp or --temp-on to put the temporary files on another disk, or use \
+         --ignore-disk-space to start anyway",
+        format_size(needed),
+        mount.display(),
+        format_size(available)
+      );
+    }
+  }
+
+  Ok(())
+}
+
+/// Blocks the calling worker while the disk of any of `paths` has less than
+/// `min_free` bytes free
+pub fn wait(paths: &[&Path], min_free: u64) {
+  loop {
+    let low = paths.iter().find_map(|path| {
+      let (mount, available) = disk_of(path)?;
+      (available < min_free).then_some((mount, available))
+    });
+
+    let Some((mount, available)) = low else {
+      if PAUSED.swap(false, Ordering::Relaxed) {
+        warn!("Enough disk space was freed, resuming encoding");
+        status::set_state(State::Encoding);
+      }
+      return;
+    };
+
+    if !PAUSED.swap(true, Ordering::Relaxed) {
+      warn!(
+        "Only {} is free on {}, which is below --min-free-space {}. The workers are paused until \
+         space is freed",
+        format_size(available),
+        mount.display(),
+        format_size(min_free)
+      );
+      status::set_state(State::Paused);
+    }
+    thread::sleep(POLL_INTERVAL);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn estimate_space() {
+    let gib = 1 << 30;
+    assert_eq!(
+      Estimate::new(10 * gib, 1.0, ChunkMethod::LSMASH, None, 0, true),
+      Estimate {
+        temp: 10 * gib,
+        output: 10 * gib
+      }
+    );
+    assert_eq!(
+      Estimate::new(10 * gib, 0.5, ChunkMethod::Hybrid, Some(2 * gib), 0, true),
+      Estimate {
+        temp: 7 * gib,
+        output: 10 * gib
+      }
+    );
+    assert_eq!(
+      Estimate::new(10 * gib, 1.0, ChunkMethod::Segment, None, 0, false),
+      Estimate {
+        temp: 20 * gib,
+        output: 0
+      }
+    );
+    assert_eq!(
+      Estimate::new(10 * gib, 1.0, ChunkMethod::Segment, None, 4 * gib, true),
+      Estimate {
+        temp: 16 * gib,
+        output: 10 * gib
+      }
+    );
+  }
+}
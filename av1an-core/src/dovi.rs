@@ -0,0 +1,213 @@
+//! Dolby Vision (profile 8.1) encodes with x265, using dovi_tool.
+//!
+//! The RPU of the source is extracted once, and split into the frame ranges of
+//! the chunks, which x265 embeds with `--dolby-vision-rpu`. After
+//! concatenation, the complete RPU is injected into the video again, so that
+//! the metadata matches the source frame for frame regardless of the chunk
+//! boundaries, and the output is remuxed with the Dolby Vision configuration.
+
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context};
+use serde_json::json;
+
+use crate::util::retry_io;
+
+/// Extracts the RPU of the source into the temporary folder, unless it was
+/// already extracted by a previous run
+pub fn extract_rpu(input: &Path, temp: &Path) -> anyhow::Result<PathBuf> {
+  let rpu = temp.join("RPU.bin");
+  if rpu.exists() {
+    return Ok(rpu);
+  }
+
+  debug!("extracting the Dolby Vision RPU of {}", input.display());
+  let partial = temp.join("RPU.partial.bin");
+  let mut ffmpeg = Command::new("ffmpeg")
+    .args(["-y", "-hide_banner", "-loglevel", "error", "-i"])
+    .arg(input)
+    .args([
+      "-map",
+      "0:v:0",
+      "-c:v",
+      "copy",
+      "-bsf:v",
+      "hevc_mp4toannexb",
+      "-f",
+      "hevc",
+      "-",
+    ])
+    .stdin(Stdio::null())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .context("Failed to run ffmpeg to extract the HEVC stream")?;
+  let output = Command::new("dovi_tool")
+    .args(["extract-rpu", "-", "-o"])
+    .arg(&partial)
+    .stdin(ffmpeg.stdout.take().unwrap())
+    .output()
+    .context("Failed to run dovi_tool, is it installed in the system path?")?;
+  let ffmpeg = ffmpeg.wait_with_output()?;
+
+  if !ffmpeg.status.success() {
+    let _ = fs::remove_file(&partial);
+    bail!(
+      "ffmpeg failed to extract the HEVC stream ({}):\n{}",
+      ffmpeg.status,
+      String::from_utf8_lossy(&ffmpeg.stderr)
+    );
+  }
+  if !output.status.success() || !partial.exists() {
+    bail!(
+      "dovi_tool failed to extract the RPU, the input may not contain Dolby Vision:\n{}",
+      String::from_utf8_lossy(&output.stderr)
+    );
+  }
+
+  retry_io(|| fs::rename(&partial, &rpu))?;
+  Ok(rpu)
+}
+
+/// Writes the part of the RPU for the frames of a chunk, and returns its path
+pub fn chunk_rpu(
+  temp: &Path,
+  rpu: &Path,
+  name: &str,
+  frames: Range<usize>,
+  total_frames: usize,
+) -> anyhow::Result<PathBuf> {
+  let chunk_rpu = temp.join("split").join(format!("{name}.rpu.bin"));
+  if chunk_rpu.exists() {
+    return Ok(chunk_rpu);
+  }
+
+  let edit_path = temp.join("split").join(format!("{name}.rpu.json"));
+  retry_io(|| fs::write(&edit_path, rpu_edit(&frames, total_frames).to_string()))?;
+
+  let output = Command::new("dovi_tool")
+    .args(["editor", "-i"])
+    .arg(rpu)
+    .arg("-j")
+    .arg(&edit_path)
+    .arg("-o")
+    .arg(&chunk_rpu)
+    .output()
+    .context("Failed to run dovi_tool")?;
+  if !output.status.success() {
+    bail!(
+      "dovi_tool failed to split the RPU for frames {}..{}:\n{}",
+      frames.start,
+      frames.end,
+      String::from_utf8_lossy(&output.stderr)
+    );
+  }
+
+  Ok(chunk_rpu)
+}
+
+/// Returns the edit of dovi_tool that removes the frames outside of `frames`,
+/// as inclusive ranges
+fn rpu_edit(frames: &Range<usize>, total_frames: usize) -> serde_json::Value {
+  let mut remove = Vec::new();
+  if frames.start > 0 {
+    remove.push(format!("0-{}", frames.start - 1));
+  }
+  if frames.end < total_frames {
+    remove.push(format!("{}-{}", frames.end, total_frames - 1));
+  }
+
+  json!({ "remove": remove })
+}
+
+/// Injects the complete RPU into the video of the concatenated output, and
+/// remuxes it along with the other tracks of the output
+pub fn inject_rpu(temp: &Path, rpu: &Path, output: &Path, frame_rate: f64) -> anyhow::Result<()> {
+  let hevc = temp.join("video.hevc");
+  let injected = temp.join("video.dv.hevc");
+
+  let status = Command::new("ffmpeg")
+    .args(["-y", "-hide_banner", "-loglevel", "error", "-i"])
+    .arg(output)
+    .args([
+      "-map",
+      "0:v:0",
+      "-c:v",
+      "copy",
+      "-bsf:v",
+      "hevc_mp4toannexb",
+      "-f",
+      "hevc",
+    ])
+    .arg(&hevc)
+    .status()
+    .context("Failed to run ffmpeg to extract the encoded HEVC stream")?;
+  if !status.success() {
+    bail!("ffmpeg failed to extract the encoded HEVC stream");
+  }
+
+  let out = Command::new("dovi_tool")
+    .args(["inject-rpu", "-i"])
+    .arg(&hevc)
+    .arg("--rpu-in")
+    .arg(rpu)
+    .arg("-o")
+    .arg(&injected)
+    .output()
+    .context("Failed to run dovi_tool")?;
+  if !out.status.success() {
+    bail!(
+      "dovi_tool failed to inject the RPU:\n{}",
+      String::from_utf8_lossy(&out.stderr)
+    );
+  }
+
+  // the raw stream has no timestamps, and the other tracks are taken from the previous output
+  let mut remuxed_name = output.file_stem().unwrap_or_default().to_owned();
+  remuxed_name.push(".dv.mkv");
+  let remuxed = output.with_file_name(remuxed_name);
+  let out = Command::new("mkvmerge")
+    .arg("-o")
+    .arg(&remuxed)
+    .args(["--default-duration", &format!("0:{frame_rate}fps")])
+    .arg(&injected)
+    .arg("-D")
+    .arg(output)
+    .output()
+    .context("Failed to run mkvmerge")?;
+  // mkvmerge exits with 1 if there were only warnings
+  if !matches!(out.status.code(), Some(0 | 1)) {
+    bail!(
+      "mkvmerge failed to remux the Dolby Vision video:\n{}",
+      String::from_utf8_lossy(&out.stdout)
+    );
+  }
+
+  retry_io(|| fs::rename(&remuxed, output))?;
+  for file in [&hevc, &injected] {
+    if let Err(e) = fs::remove_file(file) {
+      warn!("Failed to remove {}: {}", file.display(), e);
+    }
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn rpu_edit_ranges() {
+    assert_eq!(rpu_edit(&(0..100), 300), json!({ "remove": ["100-299"] }));
+    assert_eq!(
+      rpu_edit(&(100..200), 300),
+      json!({ "remove": ["0-99", "200-299"] })
+    );
+    assert_eq!(rpu_edit(&(200..300), 300), json!({ "remove": ["0-199"] }));
+    assert_eq!(rpu_edit(&(0..300), 300), json!({ "remove": [] }));
+  }
+}
@@ -3,21 +3,23 @@ use std::cmp;
 use std::fmt::Display;
 use std::iter::Iterator;
 use std::path::PathBuf;
-use std::process::Command;
 
 use arrayvec::ArrayVec;
 use cfg_if::cfg_if;
 use ffmpeg::format::Pixel;
 use itertools::chain;
-use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::encoder_profile::{parse_version, EncoderProfile, Version};
 use crate::ffmpeg::compose_ffmpeg_pipe;
 use crate::{inplace_vec, into_array, into_vec, list_index};
 
 const NULL: &str = if cfg!(windows) { "nul" } else { "/dev/null" };
 
+/// q/crf of the chunks of skip zones, the lowest of every encoder above lossless
+const NEAR_LOSSLESS_Q: usize = 1;
+
 #[allow(non_camel_case_types)]
 #[derive(
   Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug, strum::EnumString, strum::IntoStaticStr,
@@ -32,29 +34,58 @@ pub enum Encoder {
   x265,
 }
 
+/// Rate control parameter that is chosen for each chunk, e.g. by target quality
+#[derive(
+  Clone,
+  Copy,
+  PartialEq,
+  Eq,
+  Serialize,
+  Deserialize,
+  Debug,
+  Default,
+  strum::EnumString,
+  strum::IntoStaticStr,
+)]
+pub enum RateParam {
+  /// q/crf of the constant quality mode of the encoder
+  #[default]
+  #[strum(serialize = "q")]
+  Q,
+  /// Target bitrate in kbps of the VBR mode of the encoder
+  #[strum(serialize = "bitrate")]
+  Bitrate,
+}
+
+impl Display for RateParam {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(<&'static str>::from(self))
+  }
+}
+
+impl RateParam {
+  /// Converts a value of the parameter in `min..=max` to its level, which
+  /// lowers the quality as it rises like q does, or a level back to the value.
+  ///
+  /// A bitrate is mirrored within its range, so that the quality searches of
+  /// both parameters are the same.
+  pub const fn level(self, value: u32, min: u32, max: u32) -> u32 {
+    match self {
+      Self::Q => value,
+      Self::Bitrate => (min + max).saturating_sub(value),
+    }
+  }
+}
+
 #[tracing::instrument]
 pub(crate) fn parse_svt_av1_version(version: &[u8]) -> Option<(u32, u32, u32)> {
-  let v_idx = memchr::memchr(b'v', version)?;
-  let s = version.get(v_idx + 1..)?;
-  let s = simdutf8::basic::from_utf8(s).ok()?;
-  let version = s
-    .split_ascii_whitespace()
-    .next()?
-    .split('.')
-    .filter_map(|s| s.split('-').next())
-    .filter_map(|s| s.parse::<u32>().ok())
-    .collect::<ArrayVec<u32, 3>>();
-
-  if let [major, minor, patch] = version[..] {
-    Some((major, minor, patch))
-  } else {
-    None
-  }
+  parse_version(&String::from_utf8_lossy(version), "SVT-AV1")
+    .map(|Version(major, minor, patch)| (major, minor, patch))
 }
 
 #[cfg(test)]
 mod tests {
-  use crate::encoder::parse_svt_av1_version;
+  use crate::encoder::{parse_svt_av1_version, Encoder, RateParam, NULL};
 
   #[test]
   fn svt_av1_parsing() {
@@ -73,25 +104,105 @@ mod tests {
       assert_eq!(parse_svt_av1_version(s.as_bytes()), ans);
     }
   }
-}
 
-pub static USE_OLD_SVT_AV1: Lazy<bool> = Lazy::new(|| {
-  let version = Command::new("SvtAv1EncApp")
-    .arg("--version")
-    .output()
-    .unwrap();
+  #[test]
+  fn three_passes() {
+    let pass_args = |encoder: Encoder, flag: &str| -> Vec<String> {
+      (1..=3)
+        .map(|pass| {
+          let cmd = encoder.compose_pass(Vec::new(), pass, 3, "fpf", "out.mkv".to_owned(), 10);
+          let i = cmd.iter().position(|arg| arg == flag).unwrap();
+          format!("{} {}", cmd[i + 1], cmd.last().unwrap())
+        })
+        .collect()
+    };
 
-  if let Some((major, minor, _)) = parse_svt_av1_version(&version.stdout) {
-    match major {
-      0 => minor < 9,
-      1.. => false,
-    }
-  } else {
-    // assume an old version of SVT-AV1 if the version failed to parse, as
-    // the format for v0.9.0+ should be the same
-    true
+    // only the last pass writes the output, and x265 names it pass 2
+    assert_eq!(
+      pass_args(Encoder::x265, "--pass"),
+      [
+        format!("1 {NULL}"),
+        format!("3 {NULL}"),
+        "2 out.mkv".to_owned()
+      ]
+    );
+    assert_eq!(
+      pass_args(Encoder::svt_av1, "--pass"),
+      [
+        format!("1 {NULL}"),
+        format!("2 {NULL}"),
+        "3 out.mkv".to_owned()
+      ]
+    );
   }
-});
+
+  #[test]
+  fn near_lossless_params() {
+    let params =
+      |params: &[&str]| -> Vec<String> { params.iter().map(|&param| param.to_owned()).collect() };
+
+    assert_eq!(
+      Encoder::aom.near_lossless_params(params(&[
+        "--cpu-used=4",
+        "--end-usage=vbr",
+        "--target-bitrate=2000"
+      ])),
+      params(&["--cpu-used=4", "--end-usage=q", "--cq-level=1"])
+    );
+    assert_eq!(
+      Encoder::x264
+        .near_lossless_params(params(&["--preset", "slow", "--qp", "20", "--qpmin", "5"])),
+      params(&["--preset", "slow", "--qpmin", "5", "--crf", "1"])
+    );
+    assert_eq!(
+      Encoder::svt_av1.near_lossless_params(params(&["--crf", "30", "--preset", "6"])),
+      params(&["--crf", "1", "--preset", "6"])
+    );
+  }
+
+  #[test]
+  fn bitrate_params() {
+    let params =
+      |params: &[&str]| -> Vec<String> { params.iter().map(|&param| param.to_owned()).collect() };
+    let with_bitrate = |encoder: Encoder, p: &[&str], bitrate: usize| {
+      encoder.man_rate(
+        encoder.bitrate_params(params(p)),
+        RateParam::Bitrate,
+        bitrate,
+      )
+    };
+
+    assert_eq!(
+      with_bitrate(
+        Encoder::aom,
+        &["--cpu-used=4", "--end-usage=q", "--cq-level=30"],
+        2000
+      ),
+      params(&["--cpu-used=4", "--end-usage=vbr", "--target-bitrate=2000"])
+    );
+    assert_eq!(
+      with_bitrate(
+        Encoder::x264,
+        &["--preset", "slow", "--bitrate", "3000", "--crf", "20"],
+        1500
+      ),
+      params(&["--preset", "slow", "--bitrate", "1500"])
+    );
+    assert_eq!(
+      with_bitrate(Encoder::svt_av1, &["--crf", "30", "--preset", "6"], 800),
+      params(&["--preset", "6", "--rc", "1", "--tbr", "800"])
+    );
+    assert_eq!(
+      Encoder::x265.man_rate(params(&["--crf", "20"]), RateParam::Q, 24),
+      params(&["--crf", "24"])
+    );
+
+    // levels of bitrates rise as the quality falls, like q does
+    assert_eq!(RateParam::Bitrate.level(1000, 1000, 5000), 5000);
+    assert_eq!(RateParam::Bitrate.level(5000, 1000, 5000), 1000);
+    assert_eq!(RateParam::Q.level(30, 10, 50), 30);
+  }
+}
 
 impl Display for Encoder {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -156,104 +267,37 @@ impl Encoder {
     }
   }
 
-  /// Composes 1st pass command for 2 pass encoding
-  pub fn compose_1_2_pass(self, params: Vec<String>, fpf: &str, frame_count: usize) -> Vec<String> {
-    match self {
-      Self::aom => chain!(
-        into_array!["aomenc", "--passes=2", "--pass=1"],
-        params,
-        into_array![format!("--fpf={fpf}.log"), "-o", NULL, "-"],
-      )
-      .collect(),
-      Self::rav1e => chain!(
-        into_array![
-          "rav1e",
-          "-",
-          "-y",
-          "--quiet",
-          "--limit",
-          frame_count.to_string()
-        ],
-        params,
-        into_array!["--first-pass", format!("{fpf}.stat"), "--output", NULL]
-      )
-      .collect(),
-      Self::vpx => chain!(
-        into_array!["vpxenc", "--passes=2", "--pass=1"],
-        params,
-        into_array![format!("--fpf={fpf}.log"), "-o", NULL, "-"],
-      )
-      .collect(),
-      Self::svt_av1 => chain!(
-        into_array![
-          "SvtAv1EncApp",
-          "-i",
-          "stdin",
-          "--progress",
-          "2",
-          "--irefresh-type",
-          "2",
-        ],
-        params,
-        into_array!["--pass", "1", "--stats", format!("{fpf}.stat"), "-b", NULL,],
-      )
-      .collect(),
-      Self::x264 => chain!(
-        into_array![
-          "x264",
-          "--stitchable",
-          "--log-level",
-          "error",
-          "--pass",
-          "1",
-          "--demuxer",
-          "y4m",
-          "--frames",
-          frame_count.to_string()
-        ],
-        params,
-        into_array!["--stats", format!("{fpf}.log"), "-", "-o", NULL]
-      )
-      .collect(),
-      Self::x265 => chain!(
-        into_array![
-          "x265",
-          "--repeat-headers",
-          "--log-level",
-          "error",
-          "--pass",
-          "1",
-          "--y4m",
-          "--frames",
-          frame_count.to_string()
-        ],
-        params,
-        into_array![
-          "--stats",
-          format!("{fpf}.log"),
-          "--analysis-reuse-file",
-          format!("{fpf}_analysis.dat"),
-          "--input",
-          "-",
-          "-o",
-          NULL
-        ]
-      )
-      .collect(),
-    }
-  }
-
-  /// Composes 2st pass command for 2 pass encoding
-  pub fn compose_2_2_pass(
+  /// Composes the command of pass `pass` (from 1) out of `passes`, for an
+  /// encode in more than 1 pass. The passes before the last one only write
+  /// the statistics to `fpf`, which the next passes read.
+  pub fn compose_pass(
     self,
     params: Vec<String>,
+    pass: u8,
+    passes: u8,
     fpf: &str,
     output: String,
     frame_count: usize,
   ) -> Vec<String> {
+    let output = if pass == passes {
+      output
+    } else {
+      NULL.to_owned()
+    };
+    // x264 and x265 name the last pass 2, and the passes in between 3
+    let x26x_pass = match pass {
+      1 => "1",
+      _ if pass == passes => "2",
+      _ => "3",
+    };
+
     match self {
       Self::aom => chain!(
-        into_array!["aomenc", "--passes=2", "--pass=2"],
+        into_array![
+          "aomenc",
+          format!("--passes={passes}"),
+          format!("--pass={pass}")
+        ],
         params,
         into_array![format!("--fpf={fpf}.log"), "-o", output, "-"],
       )
@@ -268,11 +312,24 @@ impl Encoder {
           frame_count.to_string()
         ],
         params,
-        into_array!["--second-pass", format!("{fpf}.stat"), "--output", output]
+        into_array![
+          if pass == 1 {
+            "--first-pass"
+          } else {
+            "--second-pass"
+          },
+          format!("{fpf}.stat"),
+          "--output",
+          output
+        ]
       )
       .collect(),
       Self::vpx => chain!(
-        into_array!["vpxenc", "--passes=2", "--pass=2"],
+        into_array![
+          "vpxenc",
+          format!("--passes={passes}"),
+          format!("--pass={pass}")
+        ],
         params,
         into_array![format!("--fpf={fpf}.log"), "-o", output, "-"],
       )
@@ -290,7 +347,7 @@ impl Encoder {
         params,
         into_array![
           "--pass",
-          "2",
+          pass.to_string(),
           "--stats",
           format!("{fpf}.stat"),
           "-b",
@@ -305,7 +362,7 @@ impl Encoder {
           "--log-level",
           "error",
           "--pass",
-          "2",
+          x26x_pass,
           "--demuxer",
           "y4m",
           "--frames",
@@ -322,7 +379,7 @@ impl Encoder {
           "--log-level",
           "error",
           "--pass",
-          "2",
+          x26x_pass,
           "--y4m",
           "--frames",
           frame_count.to_string()
@@ -343,6 +400,14 @@ impl Encoder {
     }
   }
 
+  /// Returns the highest number of passes the encoder supports
+  pub const fn max_passes(self) -> u8 {
+    match self {
+      Self::aom | Self::svt_av1 | Self::x264 | Self::x265 => 3,
+      Self::rav1e | Self::vpx => 2,
+    }
+  }
+
   /// Returns default settings for the encoder
   pub fn get_default_arguments(self, (cols, rows): (u32, u32)) -> Vec<String> {
     /// Integer log base 2
@@ -548,20 +613,119 @@ impl Encoder {
     output
   }
 
+  /// Returns the q/crf in command line arguments, if it is set
+  pub fn get_q(self, params: &[String]) -> Option<usize> {
+    if params.is_empty() {
+      return None;
+    }
+    let index = list_index(params, self.q_match_fn())?;
+    let (value_index, _) = self.replace_q(index, 0);
+    params.get(value_index)?.rsplit('=').next()?.parse().ok()
+  }
+
+  /// Returns function pointer used for matching the target bitrate argument
+  /// in command line
+  fn bitrate_match_fn(self) -> fn(&str) -> bool {
+    match self {
+      Self::aom | Self::vpx => |p| p.starts_with("--target-bitrate="),
+      Self::rav1e | Self::x264 | Self::x265 => |p| p == "--bitrate",
+      Self::svt_av1 => |p| p == "--tbr",
+    }
+  }
+
+  fn replace_bitrate(self, index: usize, bitrate: usize) -> (usize, String) {
+    match self {
+      Self::aom | Self::vpx => (index, format!("--target-bitrate={bitrate}")),
+      Self::rav1e | Self::svt_av1 | Self::x265 | Self::x264 => (index + 1, bitrate.to_string()),
+    }
+  }
+
+  fn insert_bitrate(self, bitrate: usize) -> ArrayVec<String, 2> {
+    let mut output = ArrayVec::new();
+    match self {
+      Self::aom | Self::vpx => {
+        output.push(format!("--target-bitrate={bitrate}"));
+      }
+      Self::rav1e | Self::x264 | Self::x265 => {
+        output.push("--bitrate".into());
+        output.push(bitrate.to_string());
+      }
+      Self::svt_av1 => {
+        output.push("--tbr".into());
+        output.push(bitrate.to_string());
+      }
+    }
+    output
+  }
+
   /// Returns changed q/crf in command line arguments
-  pub fn man_command(self, mut params: Vec<String>, q: usize) -> Vec<String> {
-    let index = list_index(&params, self.q_match_fn());
-    if let Some(index) = index {
-      let (replace_index, replace_q) = self.replace_q(index, q);
-      params[replace_index] = replace_q;
+  pub fn man_command(self, params: Vec<String>, q: usize) -> Vec<String> {
+    self.man_rate(params, RateParam::Q, q)
+  }
+
+  /// Returns command line arguments with the `rate` parameter changed to
+  /// `value`, or added if it is not set
+  pub fn man_rate(self, mut params: Vec<String>, rate: RateParam, value: usize) -> Vec<String> {
+    let match_fn = match rate {
+      RateParam::Q => self.q_match_fn(),
+      RateParam::Bitrate => self.bitrate_match_fn(),
+    };
+    if let Some(index) = list_index(&params, match_fn) {
+      let (replace_index, replace_value) = match rate {
+        RateParam::Q => self.replace_q(index, value),
+        RateParam::Bitrate => self.replace_bitrate(index, value),
+      };
+      params[replace_index] = replace_value;
     } else {
-      let args = self.insert_q(q);
+      let args = match rate {
+        RateParam::Q => self.insert_q(value),
+        RateParam::Bitrate => self.insert_bitrate(value),
+      };
       params.extend_from_slice(&args);
     }
 
     params
   }
 
+  /// Returns the parameters in the VBR mode of the encoder, without the
+  /// options of its constant quality modes, for a bitrate to be set by
+  /// [`Self::man_rate`]
+  pub fn bitrate_params(self, params: Vec<String>) -> Vec<String> {
+    let constant_quality: &[&str] = match self {
+      Self::aom | Self::vpx => &["--end-usage", "--cq-level"],
+      Self::rav1e => &["--quantizer"],
+      Self::svt_av1 => &["--rc", "--crf", "--qp", "-q"],
+      Self::x264 | Self::x265 => &["--crf", "--qp"],
+    };
+
+    let mut params = without_options(params, constant_quality);
+    match self {
+      Self::aom | Self::vpx => params.push("--end-usage=vbr".to_owned()),
+      Self::svt_av1 => params.extend(["--rc".to_owned(), "1".to_owned()]),
+      Self::rav1e | Self::x264 | Self::x265 => {}
+    }
+    params
+  }
+
+  /// Returns the parameters with the lowest q/crf above lossless, in the
+  /// constant quality mode of the encoder, for the chunks of skip zones
+  pub fn near_lossless_params(self, params: Vec<String>) -> Vec<String> {
+    // the options of the other rate control modes
+    let rate_control: &[&str] = match self {
+      Self::aom | Self::vpx => &["--end-usage", "--target-bitrate"],
+      Self::rav1e => &["--bitrate"],
+      Self::svt_av1 => &["--rc", "--tbr"],
+      Self::x264 | Self::x265 => &["--bitrate", "--qp"],
+    };
+
+    let mut kept = without_options(params, rate_control);
+    if matches!(self, Self::aom | Self::vpx) {
+      kept.push("--end-usage=q".to_owned());
+    }
+
+    self.man_command(kept, NEAR_LOSSLESS_Q)
+  }
+
   /// Parses the number of encoded frames
   pub(crate) fn parse_encoded_frames(self, line: &str) -> Option<u64> {
     use crate::parse::*;
@@ -590,7 +754,7 @@ impl Encoder {
     threads: usize,
     q: usize,
   ) -> Vec<Cow<'static, str>> {
-    match &self {
+    let mut command = match &self {
       Self::aom => inplace_vec![
         "aomenc",
         "--passes=1",
@@ -651,7 +815,7 @@ impl Encoder {
         "--row-mt=1",
       ],
       Self::svt_av1 => {
-        if *USE_OLD_SVT_AV1 {
+        if EncoderProfile::get(self).is_old_svt_av1() {
           inplace_vec![
             "SvtAv1EncApp",
             "-i",
@@ -763,7 +927,11 @@ impl Encoder {
         "--crf",
         q.to_string(),
       ],
-    }
+    };
+
+    // the speed features of the probes are not all available in older builds
+    EncoderProfile::get(self).retain_supported(&mut command);
+    command
   }
 
   /// Returns command used for target quality probing (slow, correctness focused version)
@@ -821,22 +989,28 @@ impl Encoder {
     self,
     temp: String,
     chunk_index: usize,
+    rate: RateParam,
     q: usize,
     pix_fmt: Pixel,
     probing_rate: usize,
     vmaf_threads: usize,
     mut video_params: Vec<String>,
     probe_slow: bool,
+    probe_res: Option<(&str, &str)>,
+    denoise: Option<&str>,
   ) -> (Vec<String>, Vec<Cow<'static, str>>) {
-    let pipe = compose_ffmpeg_pipe(
-      [
-        "-vf",
-        format!("select=not(mod(n\\,{probing_rate}))").as_str(),
-        "-vsync",
-        "0",
-      ],
-      pix_fmt,
+    // the frames of chunks in denoised zones are encoded denoised, before they
+    // are sampled as the denoiser is temporal
+    let mut filter = format!(
+      "{}select=not(mod(n\\,{probing_rate}))",
+      denoise.map_or_else(String::new, |denoise| format!("{denoise},"))
     );
+    // scaled the same way as the reference is for VMAF, so that both resolutions match
+    if let Some((res, scaler)) = probe_res {
+      filter = format!("{filter},scale={res}:flags={scaler}:force_original_aspect_ratio=decrease");
+    }
+
+    let pipe = compose_ffmpeg_pipe(["-vf", filter.as_str(), "-vsync", "0"], pix_fmt);
 
     let probe_name = format!("v_{q}_{chunk_index}.ivf");
     let mut probe = PathBuf::from(temp);
@@ -865,6 +1039,19 @@ impl Encoder {
       self.construct_target_quality_command(vmaf_threads, q)
     };
 
+    // the probe commands set a q/crf, so in bitrate mode `q` is the bitrate of the VBR mode
+    let params: Vec<Cow<str>> = match rate {
+      RateParam::Q => params,
+      RateParam::Bitrate => {
+        let params = self.bitrate_params(params.into_iter().map(Cow::into_owned).collect());
+        self
+          .man_rate(params, RateParam::Bitrate, q)
+          .into_iter()
+          .map(Cow::Owned)
+          .collect()
+      }
+    };
+
     let output: Vec<Cow<str>> = match self {
       Self::svt_av1 => chain!(params, into_array!["-b", probe_path]).collect(),
       Self::aom | Self::rav1e | Self::vpx | Self::x264 | Self::x265 => {
@@ -889,6 +1076,23 @@ impl Encoder {
   }
 }
 
+/// Removes the options in `keys` from `params`, with their values
+fn without_options(params: Vec<String>, keys: &[&str]) -> Vec<String> {
+  let mut kept = Vec::with_capacity(params.len() + 2);
+  let mut params = params.into_iter();
+  while let Some(param) = params.next() {
+    let key = param.split_once('=').map_or(param.as_str(), |(key, _)| key);
+    if keys.contains(&key) {
+      if !param.contains('=') {
+        params.next();
+      }
+      continue;
+    }
+    kept.push(param);
+  }
+  kept
+}
+
 #[derive(Error, Debug)]
 pub enum UnsupportedPixelFormatError {
   #[error("{0} does not support {1:?}")]
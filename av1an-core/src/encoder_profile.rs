@@ -0,0 +1,474 @@
+//! Behavior differences between versions of the encoders.
+//!
+//! Distributions often package encoders that are several releases behind the
+//! latest one, while bleeding-edge builds may rename or remove options. The
+//! profile of an encoder is detected once, from its version and help output,
+//! and is used to adjust the arguments that av1an passes on its own (the
+//! default arguments and the target quality probe commands) to the installed
+//! build. Arguments passed by the user are never changed.
+//!
+//! The psychovisually tuned forks of the encoders (aomenc-psy and SVT-AV1-PSY)
+//! are detected from their version string. Their additional parameters are
+//! accepted by the parameter validation even if their help does not list them,
+//! and the defaults make use of their psychovisual tuning.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::process::Command;
+
+use once_cell::sync::OnceCell;
+
+use crate::parse::{param_values, valid_params};
+use crate::settings::suggest_fix;
+use crate::Encoder;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNDETECTED: OnceCell<EncoderProfile> = OnceCell::new();
+static PROFILES: [OnceCell<EncoderProfile>; 6] = [UNDETECTED; 6];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(pub u32, pub u32, pub u32);
+
+impl Display for Version {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}.{}.{}", self.0, self.1, self.2)
+  }
+}
+
+#[derive(Debug)]
+pub struct EncoderProfile {
+  pub encoder: Encoder,
+  /// `None` if the encoder is not installed, or its version could not be parsed
+  pub version: Option<Version>,
+  /// Whether this is a psy fork of the encoder, e.g. SVT-AV1-PSY
+  pub psy: bool,
+  /// Parameters listed in the help of the encoder, empty if it could not be read
+  params: HashSet<String>,
+  /// Values listed in the help of the parameters that take one of a list of names
+  values: HashMap<String, Vec<String>>,
+}
+
+/// Parameter passed to an encoder that its build does not accept, or whose
+/// value is not listed in its help.
+///
+/// Only unknown parameters are rejected, as the values are scraped from the
+/// help, which does not always list all of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidParam {
+  pub encoder: Encoder,
+  pub param: String,
+  /// Value of the parameter, if the parameter exists but does not accept it
+  pub value: Option<String>,
+  /// Values the parameter accepts, if `value` is set
+  pub allowed: Vec<String>,
+  /// Parameter (or value) that was probably meant
+  pub suggestion: Option<String>,
+}
+
+impl Display for InvalidParam {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match &self.value {
+      None => write!(
+        f,
+        "'{}' isn't a valid parameter for {}",
+        self.param, self.encoder
+      )?,
+      Some(value) => write!(
+        f,
+        "'{}' isn't a value of '{}' listed by the help of {} (listed values: {})",
+        value,
+        self.param,
+        self.encoder,
+        self.allowed.join(", ")
+      )?,
+    }
+    if let Some(suggestion) = &self.suggestion {
+      write!(f, "\n\tDid you mean '{suggestion}'?")?;
+    }
+    Ok(())
+  }
+}
+
+impl InvalidParam {
+  /// Whether the parameter itself is unknown, rather than its value
+  pub const fn is_unknown_param(&self) -> bool {
+    self.value.is_none()
+  }
+}
+
+impl EncoderProfile {
+  /// Returns the profile of the installed build of the encoder, detecting it on first use
+  pub fn get(encoder: Encoder) -> &'static Self {
+    PROFILES[encoder as usize].get_or_init(|| {
+      let profile = Self::detect(encoder);
+      if let Some(version) = profile.version {
+        debug!(
+          "detected {}{} version {}",
+          encoder,
+          if profile.psy { " (psy fork)" } else { "" },
+          version
+        );
+      } else {
+        debug!("failed to detect the version of {}", encoder);
+      }
+      profile
+    })
+  }
+
+  fn detect(encoder: Encoder) -> Self {
+    let output = |arg: &str| {
+      Command::new(encoder.bin())
+        .arg(arg)
+        .output()
+        .map(|output| {
+          // some encoders (e.g. x265) print their version to stderr
+          let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+          text.push_str(&String::from_utf8_lossy(&output.stderr));
+          text
+        })
+        .unwrap_or_default()
+    };
+
+    let [_, help_arg] = encoder.help_command();
+    let help_text = output(help_arg);
+    // aomenc and vpxenc only print their version as part of their help
+    let version_text = match encoder {
+      Encoder::aom | Encoder::vpx => help_text.clone(),
+      _ => output("--version"),
+    };
+
+    Self {
+      encoder,
+      version: parse_version(&version_text, version_marker(encoder)),
+      psy: is_psy_fork(&version_text, version_marker(encoder)),
+      params: valid_params(&help_text, encoder)
+        .into_iter()
+        .map(Cow::into_owned)
+        .collect(),
+      values: param_values(&help_text),
+    }
+  }
+
+  /// Whether this is a version of SVT-AV1 older than 0.9.0, which needs the
+  /// speed features of the probes to be set manually. This is also assumed if
+  /// the version failed to parse, as the format of v0.9.0+ should be the same.
+  pub fn is_old_svt_av1(&self) -> bool {
+    self.encoder == Encoder::svt_av1 && self.version.map_or(true, |v| v < Version(0, 9, 0))
+  }
+
+  /// Whether the encoder lists the parameter in its help, which is assumed if
+  /// the help could not be read
+  pub fn supports(&self, param: &str) -> bool {
+    self.params.is_empty() || self.params.contains(param)
+  }
+
+  /// Returns the parameters of the arguments that this build of the encoder
+  /// does not accept. The value of a parameter is checked too if the help of
+  /// the encoder lists the values it takes.
+  pub fn check_params<S: AsRef<str>>(&self, args: &[S]) -> Vec<InvalidParam> {
+    let is_param = |arg: &str| {
+      arg.starts_with("--")
+        || (arg.starts_with('-') && arg.chars().nth(1).is_some_and(char::is_alphabetic))
+    };
+    let known = || {
+      self
+        .params
+        .iter()
+        .map(String::as_str)
+        .chain(self.fork_params().iter().copied())
+    };
+
+    let mut invalid = Vec::new();
+    let mut args = args.iter().map(AsRef::as_ref).peekable();
+    while let Some(arg) = args.next() {
+      if !is_param(arg) {
+        continue;
+      }
+      let (param, value) = match arg.split_once('=') {
+        Some((param, value)) => (param, Some(value)),
+        None => (arg, args.next_if(|next| !is_param(next))),
+      };
+
+      if !self.supports(param) && !self.fork_params().contains(&param) {
+        invalid.push(InvalidParam {
+          encoder: self.encoder,
+          param: param.to_owned(),
+          value: None,
+          allowed: Vec::new(),
+          suggestion: suggest_fix(param, known()).map(ToOwned::to_owned),
+        });
+        continue;
+      }
+
+      let (Some(value), Some(allowed)) = (value, self.values.get(param)) else {
+        continue;
+      };
+      // numbers are often accepted in place of the names, e.g. the presets of x265
+      if value.parse::<f64>().is_ok() && allowed.iter().all(|a| a.parse::<f64>().is_err()) {
+        continue;
+      }
+      // some parameters take several of the values, e.g. the tunes of x264
+      let accepted = value
+        .split(',')
+        .all(|part| allowed.iter().any(|a| a.eq_ignore_ascii_case(part)));
+      if !accepted {
+        invalid.push(InvalidParam {
+          encoder: self.encoder,
+          param: param.to_owned(),
+          value: Some(value.to_owned()),
+          allowed: allowed.clone(),
+          suggestion: suggest_fix(value, allowed.iter().map(String::as_str)).map(ToOwned::to_owned),
+        });
+      }
+    }
+
+    invalid
+  }
+
+  /// Returns the parameters that only the psy fork of the encoder has, which
+  /// are valid even if the help of this build does not list them
+  pub const fn fork_params(&self) -> &'static [&'static str] {
+    if !self.psy {
+      return &[];
+    }
+
+    match self.encoder {
+      Encoder::aom => &[
+        "--enable-experimental-psy",
+        "--sb-qp-sweep",
+        "--dist-metric",
+        "--sharpness",
+        "--enable-dnl-denoising",
+      ],
+      Encoder::svt_av1 => &[
+        "--variance-boost-strength",
+        "--variance-octile",
+        "--enable-alt-curve",
+        "--sharpness",
+        "--frame-luma-bias",
+        "--qp-scale-compress-strength",
+        "--max-32-tx-size",
+        "--adaptive-film-grain",
+        "--tf-strength",
+        "--kf-tf-strength",
+        "--noise-norm-strength",
+        "--psy-rd",
+        "--spy-rd",
+        "--chroma-qm-min",
+        "--chroma-qm-max",
+        "--ac-bias",
+        "--complex-hvs",
+        "--hbd-mds",
+      ],
+      _ => &[],
+    }
+  }
+
+  /// Adds the default arguments that take advantage of the psy fork of the
+  /// encoder to the default arguments of the encoder
+  pub fn extend_defaults(&self, args: &mut Vec<String>) {
+    if !self.psy {
+      return;
+    }
+
+    match self.encoder {
+      // unsupported arguments are removed again by `retain_supported`
+      Encoder::aom => args.push("--enable-experimental-psy=1".to_owned()),
+      // the subjective SSIM tune was added in v2.0.0 of SVT-AV1-PSY
+      Encoder::svt_av1 if self.version.is_some_and(|v| v >= Version(2, 0, 0)) => {
+        args.extend(["--tune".to_owned(), "3".to_owned()]);
+      }
+      _ => {}
+    }
+  }
+
+  /// Removes the arguments that this build of the encoder does not support.
+  ///
+  /// Only arguments of the form `--name` or `--name=value` can be removed
+  /// safely, so this only affects aomenc and vpxenc.
+  pub fn retain_supported<S: AsRef<str>>(&self, args: &mut Vec<S>) {
+    if !matches!(self.encoder, Encoder::aom | Encoder::vpx) {
+      return;
+    }
+
+    args.retain(|arg| {
+      let arg = arg.as_ref();
+      if !arg.starts_with("--") {
+        return true;
+      }
+
+      let name = arg.split_once('=').map_or(arg, |(name, _)| name);
+      let supported = self.supports(name);
+      if !supported {
+        debug!(
+          "{} {} does not support {}, leaving it out",
+          self.encoder,
+          self
+            .version
+            .map_or_else(|| "(unknown version)".to_owned(), |v| v.to_string()),
+          name
+        );
+      }
+      supported
+    });
+  }
+}
+
+/// The text that precedes the version in the version output of the encoder
+const fn version_marker(encoder: Encoder) -> &'static str {
+  match encoder {
+    // e.g. "av1 - AOMedia Project AV1 Encoder 3.8.0 (default)"
+    Encoder::aom => "AV1 Encoder",
+    // e.g. "vp9 - WebM Project VP9 Encoder v1.13.1"
+    Encoder::vpx => "VP9 Encoder",
+    // e.g. "SVT-AV1 v1.7.0 (release)"
+    Encoder::svt_av1 => "SVT-AV1",
+    // e.g. "rav1e 0.7.1 (p20231024)"
+    Encoder::rav1e => "rav1e",
+    // e.g. "x264 0.164.3108 31e19f9"
+    Encoder::x264 => "x264",
+    // e.g. "x265 [info]: HEVC encoder version 3.5+1-f0c1022b6"
+    Encoder::x265 => "version",
+  }
+}
+
+/// Whether the line of the version output that contains `marker` names a psy
+/// fork, e.g. "SVT-AV1-PSY v2.2.1-A (release)"
+fn is_psy_fork(text: &str, marker: &str) -> bool {
+  text
+    .lines()
+    .find(|line| line.contains(marker))
+    .is_some_and(|line| line.to_ascii_lowercase().contains("psy"))
+}
+
+/// Parses the first version number within the few words following `marker`.
+/// The patch version defaults to 0 if it is missing.
+pub(crate) fn parse_version(text: &str, marker: &str) -> Option<Version> {
+  let (_, after) = text.split_once(marker)?;
+
+  after.split_ascii_whitespace().take(3).find_map(|word| {
+    let word = word.strip_prefix('v').unwrap_or(word);
+    let end = word
+      .find(|c: char| !c.is_ascii_digit() && c != '.')
+      .unwrap_or(word.len());
+    let mut parts = word[..end].split('.').map(str::parse::<u32>);
+
+    let major = parts.next()?.ok()?;
+    let minor = parts.next()?.ok()?;
+    let patch = parts.next().map_or(Some(0), Result::ok)?;
+    Some(Version(major, minor, patch))
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn version_parsing() {
+    let test_cases = [
+      (
+        Encoder::aom,
+        "    av1    - AOMedia Project AV1 Encoder 3.8.0 (default)",
+        Some(Version(3, 8, 0)),
+      ),
+      (
+        Encoder::aom,
+        "    av1    - AOMedia Project AV1 Encoder v3.12.0-123-gabcdef (default)",
+        Some(Version(3, 12, 0)),
+      ),
+      (
+        Encoder::vpx,
+        "    vp9    - WebM Project VP9 Encoder v1.13.1",
+        Some(Version(1, 13, 1)),
+      ),
+      (
+        Encoder::svt_av1,
+        "SVT-AV1-PSY v2.2.1-A (release)",
+        Some(Version(2, 2, 1)),
+      ),
+      (
+        Encoder::rav1e,
+        "rav1e 0.7.1 (p20231024) (Release)",
+        Some(Version(0, 7, 1)),
+      ),
+      (
+        Encoder::x264,
+        "x264 0.164.3108 31e19f9",
+        Some(Version(0, 164, 3108)),
+      ),
+      (
+        Encoder::x265,
+        "x265 [info]: HEVC encoder version 3.5+1-f0c1022b6",
+        Some(Version(3, 5, 0)),
+      ),
+      (
+        Encoder::aom,
+        "Usage: aomenc <options> -o dst_filename",
+        None,
+      ),
+    ];
+
+    for (encoder, s, ans) in test_cases {
+      assert_eq!(parse_version(s, version_marker(encoder)), ans);
+    }
+  }
+
+  #[test]
+  fn psy_fork_detection() {
+    let test_cases = [
+      (Encoder::svt_av1, "SVT-AV1-PSY v2.2.1-A (release)", true),
+      (Encoder::svt_av1, "SVT-AV1 v2.2.1 (release)", false),
+      (
+        Encoder::aom,
+        "Included encoders:\n\n    av1    - AOMedia Project AV1 Encoder psy-v3.6.0 (default)",
+        true,
+      ),
+      (
+        Encoder::aom,
+        "Included encoders:\n\n    av1    - AOMedia Project AV1 Encoder 3.8.0 (default)",
+        false,
+      ),
+      (Encoder::x264, "x264 0.164.3108 31e19f9", false),
+    ];
+
+    for (encoder, s, ans) in test_cases {
+      assert_eq!(is_psy_fork(s, version_marker(encoder)), ans, "{s}");
+    }
+  }
+
+  #[test]
+  fn param_checking() {
+    let help_text = include_str!("../tests/x264_help.txt");
+    let profile = EncoderProfile {
+      encoder: Encoder::x264,
+      version: None,
+      psy: false,
+      params: valid_params(help_text, Encoder::x264)
+        .into_iter()
+        .map(Cow::into_owned)
+        .collect(),
+      values: param_values(help_text),
+    };
+
+    let valid = [
+      "--preset",
+      "slow",
+      "--tune",
+      "film,fastdecode",
+      "--crf",
+      "18",
+      "--qcomp=0.7",
+    ];
+    assert!(profile.check_params(&valid).is_empty());
+
+    let invalid = profile.check_params(&["--presett", "slow", "--tune", "flim", "--crf", "-1"]);
+    assert_eq!(invalid.len(), 2);
+    assert_eq!(invalid[0].param, "--presett");
+    assert_eq!(invalid[0].value, None);
+    assert_eq!(invalid[0].suggestion.as_deref(), Some("--preset"));
+    assert_eq!(invalid[1].value.as_deref(), Some("flim"));
+    assert_eq!(invalid[1].suggestion.as_deref(), Some("film"));
+  }
+}
@@ -1,12 +1,18 @@
 use std::ffi::OsStr;
+use std::fmt::{self, Display};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::str::FromStr;
 
+use anyhow::{bail, Context};
 use ffmpeg::color::TransferCharacteristic;
 use ffmpeg::format::{input, Pixel};
 use ffmpeg::media::Type as MediaType;
 use ffmpeg::Error::StreamNotFound;
+use ffmpeg::Rational;
+use itertools::Itertools;
 use path_abs::{PathAbs, PathInfo};
+use serde::{Deserialize, Serialize};
 
 use crate::{into_array, into_vec};
 
@@ -39,6 +45,246 @@ pub fn compose_ffmpeg_pipe<S: Into<String>>(
   p
 }
 
+/// Frame rate of the output, as a fraction so that e.g. 24000/1001 is exact
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrameRate {
+  pub num: u64,
+  pub den: u64,
+}
+
+impl FromStr for FrameRate {
+  type Err = String;
+
+  /// Parses a fraction such as `24000/1001`, or a number such as `30` or `23.976`
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let rate = if let Some((num, den)) = s.split_once('/') {
+      match (num.trim().parse(), den.trim().parse()) {
+        (Ok(num), Ok(den)) => Self { num, den },
+        _ => return Err(format!("invalid frame rate {s:?}")),
+      }
+    } else {
+      let s = s.trim();
+      let (int, frac) = s.split_once('.').unwrap_or((s, ""));
+      let den = 10u64.pow(frac.len() as u32);
+      match format!("{int}{frac}").parse() {
+        Ok(num) => Self { num, den },
+        Err(_) => return Err(format!("invalid frame rate {s:?}")),
+      }
+    };
+
+    if rate.num == 0 || rate.den == 0 {
+      return Err(format!("frame rate {s:?} must be positive"));
+    }
+    Ok(rate)
+  }
+}
+
+impl Display for FrameRate {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}/{}", self.num, self.den)
+  }
+}
+
+impl FrameRate {
+  pub fn as_f64(self) -> f64 {
+    self.num as f64 / self.den as f64
+  }
+
+  /// Returns the frame of the output at the time of a frame of the source
+  pub fn convert_frame(self, frame: usize, source_rate: f64) -> usize {
+    (frame as f64 * self.as_f64() / source_rate).round() as usize
+  }
+
+  /// Returns the ffmpeg filter that converts to this frame rate. The last frame
+  /// is duplicated, so that a chunk never ends up short of the frames it is
+  /// limited to with `-frames:v`.
+  pub fn filter(self, interpolate: bool) -> String {
+    if interpolate {
+      format!("minterpolate=fps={self},tpad=stop=1:stop_mode=clone")
+    } else {
+      format!("fps={self},tpad=stop=1:stop_mode=clone")
+    }
+  }
+}
+
+/// Appends a filter to the video filters of the ffmpeg arguments, or adds them
+/// if there are none
+pub fn with_video_filter(args: &[String], filter: &str) -> Vec<String> {
+  let mut args = args.to_vec();
+  if let Some(idx) = args
+    .iter()
+    .position(|arg| matches!(arg.as_str(), "-vf" | "-filter:v"))
+    .filter(|idx| idx + 1 < args.len())
+  {
+    args[idx + 1] = format!("{},{filter}", args[idx + 1]);
+  } else {
+    args.extend(["-vf".to_owned(), filter.to_owned()]);
+  }
+  args
+}
+
+/// Returns the resolution of the video after the filters of the ffmpeg
+/// arguments, from the resolution of the input, or `None` if the filters
+/// cannot be followed.
+///
+/// Only the filters that change the resolution in a predictable way (`scale`,
+/// `zscale`, `crop`, `pad`, `transpose`) are understood, with sizes that are
+/// numbers or simple expressions of `iw` and `ih` (e.g. `iw/2`). Other filters
+/// are assumed to keep the resolution.
+pub fn filtered_resolution(args: &[String], (width, height): (u32, u32)) -> Option<(u32, u32)> {
+  let mut size = (f64::from(width), f64::from(height));
+  for (name, options) in video_filters(args) {
+    size = match name {
+      "scale" | "zscale" => {
+        let w = filter_option(options, &["w", "width"], 0)?;
+        let h = filter_option(options, &["h", "height"], 1)?;
+        scaled_size(size, &w, &h)?
+      }
+      "crop" => {
+        let w = filter_option(options, &["w", "out_w"], 0).unwrap_or_else(|| "iw".to_owned());
+        let h = filter_option(options, &["h", "out_h"], 1).unwrap_or_else(|| "ih".to_owned());
+        (eval_size(&w, size)?, eval_size(&h, size)?)
+      }
+      "pad" => {
+        let w = filter_option(options, &["w", "width"], 0).unwrap_or_else(|| "iw".to_owned());
+        let h = filter_option(options, &["h", "height"], 1).unwrap_or_else(|| "ih".to_owned());
+        (
+          eval_size(&w, size)?.max(size.0),
+          eval_size(&h, size)?.max(size.1),
+        )
+      }
+      "transpose" => (size.1, size.0),
+      _ => size,
+    };
+  }
+
+  Some((size.0.round() as u32, size.1.round() as u32))
+}
+
+/// Filters that can change the number of frames
+const FRAME_COUNT_FILTERS: [&str; 12] = [
+  "fps",
+  "framerate",
+  "framestep",
+  "select",
+  "trim",
+  "mpdecimate",
+  "decimate",
+  "minterpolate",
+  "tinterlace",
+  "interlace",
+  "telecine",
+  "tpad",
+];
+
+/// Returns whether the filters of the ffmpeg arguments can change the number
+/// of frames, e.g. by converting the frame rate or dropping duplicate frames
+pub fn changes_frame_count(args: &[String]) -> bool {
+  video_filters(args).into_iter().any(|(name, options)| {
+    FRAME_COUNT_FILTERS.contains(&name)
+      // deinterlacers that output a frame for each field
+      || (matches!(name, "yadif" | "bwdif" | "w3fdif" | "estdif")
+        && filter_option(options, &["mode"], 0)
+          .is_some_and(|mode| matches!(mode.as_str(), "1" | "3" | "send_field" | "send_field_nospatial")))
+  })
+}
+
+/// Returns the names and options of the video filters of the ffmpeg arguments,
+/// in order
+pub(crate) fn video_filters(args: &[String]) -> Vec<(&str, &str)> {
+  let mut filters = Vec::new();
+  for (flag, value) in args.iter().tuple_windows() {
+    if !matches!(
+      flag.as_str(),
+      "-vf" | "-filter:v" | "-filter_complex" | "-lavfi"
+    ) {
+      continue;
+    }
+    for filter in split_unescaped(value, &[',', ';']) {
+      // labels of filter graphs, e.g. [0:v]scale=1280:-2[out]
+      let mut filter = filter.trim();
+      while let Some(rest) = filter.strip_prefix('[') {
+        filter = rest.split_once(']').map_or("", |(_, rest)| rest);
+      }
+      let filter = filter.split('[').next().unwrap_or_default();
+      let (name, options) = filter.split_once('=').unwrap_or((filter, ""));
+      filters.push((name.trim(), options));
+    }
+  }
+  filters
+}
+
+/// Splits at the separators that are not escaped with a backslash
+fn split_unescaped<'a>(value: &'a str, separators: &[char]) -> Vec<&'a str> {
+  let mut parts = Vec::new();
+  let mut start = 0;
+  let mut escaped = false;
+  for (i, c) in value.char_indices() {
+    if escaped {
+      escaped = false;
+    } else if c == '\\' {
+      escaped = true;
+    } else if separators.contains(&c) {
+      parts.push(&value[start..i]);
+      start = i + c.len_utf8();
+    }
+  }
+  parts.push(&value[start..]);
+  parts
+}
+
+/// Returns an option of a filter, either named or at its position
+pub(crate) fn filter_option(options: &str, names: &[&str], position: usize) -> Option<String> {
+  let options = split_unescaped(options, &[':']);
+  options
+    .iter()
+    .find_map(|option| {
+      let (name, value) = option.split_once('=')?;
+      names.contains(&name.trim()).then(|| value.to_owned())
+    })
+    .or_else(|| {
+      options
+        .iter()
+        .filter(|option| !option.contains('='))
+        .nth(position)
+        .map(|option| (*option).to_owned())
+    })
+    .map(|value| value.replace('\\', "").trim_matches('\'').to_owned())
+}
+
+/// Returns the size after scaling, where a negative size keeps the aspect
+/// ratio, rounded to a multiple of its absolute value
+pub(crate) fn scaled_size(size: (f64, f64), w: &str, h: &str) -> Option<(f64, f64)> {
+  let (w, h) = (eval_size(w, size)?, eval_size(h, size)?);
+  let keep_aspect = |other: f64, other_input: f64, input: f64, multiple: f64| {
+    (other / other_input * input / multiple).round() * multiple
+  };
+  Some(match (w < 0.0, h < 0.0) {
+    (false, false) => (w, h),
+    (true, false) => (keep_aspect(h, size.1, size.0, -w), h),
+    (false, true) => (w, keep_aspect(w, size.0, size.1, -h)),
+    (true, true) => size,
+  })
+}
+
+/// Evaluates a size that is a number, `iw`, `ih`, or one of them multiplied
+/// or divided by a number
+pub(crate) fn eval_size(expr: &str, (iw, ih): (f64, f64)) -> Option<f64> {
+  let value = |term: &str| match term.trim() {
+    "iw" | "in_w" => Some(iw),
+    "ih" | "in_h" => Some(ih),
+    term => term.parse().ok(),
+  };
+  let expr = expr.trim();
+  if let Some((a, b)) = expr.split_once('*') {
+    Some(value(a)? * value(b)?)
+  } else if let Some((a, b)) = expr.split_once('/') {
+    Some(value(a)? / value(b)?)
+  } else {
+    value(expr)
+  }
+}
+
 /// Get frame count using FFmpeg
 #[tracing::instrument]
 pub fn num_frames(source: &Path) -> Result<usize, ffmpeg::Error> {
@@ -144,16 +390,102 @@ pub fn get_keyframes(source: &Path) -> Result<Vec<usize>, ffmpeg::Error> {
   Ok(kfs)
 }
 
+/// Returns the first frames of the chapters of the source, without frame 0,
+/// which is always a keyframe
+#[tracing::instrument]
+pub fn chapter_frames(source: &Path) -> Result<Vec<usize>, ffmpeg::Error> {
+  let rate = frame_rate(source)?;
+  let ictx = input(&source)?;
+  // the chapters are on the timeline of the container, which does not start at 0
+  // in e.g. MPEG-TS, while frame 0 is the first frame of the video
+  let video = ictx
+    .streams()
+    .best(MediaType::Video)
+    .ok_or(StreamNotFound)?;
+  let start_time = match video.start_time() {
+    ffmpeg::ffi::AV_NOPTS_VALUE => 0.0,
+    start_time => start_time as f64 * f64::from(video.time_base()),
+  };
+
+  let mut frames = ictx
+    .chapters()
+    .map(|chapter| chapter_frame(chapter.start(), chapter.time_base(), start_time, rate))
+    .filter(|&frame| frame > 0)
+    .collect::<Vec<_>>();
+  frames.sort_unstable();
+  frames.dedup();
+
+  Ok(frames)
+}
+
+/// Returns the frame shown at a timestamp in `time_base`, rounded to the
+/// nearest frame, where `start_time` is the time of frame 0 in seconds
+fn chapter_frame(start: i64, time_base: Rational, start_time: f64, frame_rate: f64) -> usize {
+  let seconds = (start as f64).mul_add(f64::from(time_base), -start_time);
+  (seconds * frame_rate).round().max(0.0) as usize
+}
+
 /// Returns true if input file have audio in it
 pub fn has_audio(file: &Path) -> bool {
   let ictx = input(&file).unwrap();
   ictx.streams().best(MediaType::Audio).is_some()
 }
 
+/// Returns the codecs of the audio streams of the file
+pub fn audio_stream_codecs(file: &Path) -> Result<Vec<ffmpeg::codec::Id>, ffmpeg::Error> {
+  let ictx = input(&file)?;
+  Ok(
+    ictx
+      .streams()
+      .map(|stream| stream.parameters())
+      .filter(|parameters| parameters.medium() == MediaType::Audio)
+      .map(|parameters| parameters.id())
+      .collect(),
+  )
+}
+
+/// Returns the number of audio streams in the file, and the duration of the
+/// longest one in seconds
+fn audio_streams(file: &Path) -> Result<(usize, f64), ffmpeg::Error> {
+  let ictx = input(&file)?;
+  let container_duration = ictx.duration() as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE);
+
+  let durations: Vec<f64> = ictx
+    .streams()
+    .filter(|stream| stream.parameters().medium() == MediaType::Audio)
+    .map(|stream| {
+      if stream.duration() > 0 {
+        stream.duration() as f64 * f64::from(stream.time_base())
+      } else {
+        container_duration
+      }
+    })
+    .collect();
+
+  Ok((durations.len(), durations.into_iter().fold(0.0, f64::max)))
+}
+
+/// Returns true if `audio_file` contains every audio stream of `input`, at
+/// (approximately) its full duration.
+fn is_audio_complete(input: &Path, audio_file: &Path) -> bool {
+  // allow for encoder delay and padding
+  const TOLERANCE: f64 = 1.0;
+
+  match (audio_streams(input), audio_streams(audio_file)) {
+    (Ok((input_streams, input_duration)), Ok((audio_streams, audio_duration))) => {
+      audio_streams == input_streams
+        && audio_duration > 0.0
+        && (input_duration - audio_duration).abs() <= TOLERANCE
+    }
+    _ => false,
+  }
+}
+
 /// Encodes the audio using FFmpeg, blocking the current thread.
 ///
 /// This function returns `Some(output)` if the audio exists and the audio
-/// successfully encoded, or `None` otherwise.
+/// successfully encoded, or `None` otherwise. If a complete audio file from a
+/// previous run already exists in the temp folder, it is reused.
 #[must_use]
 pub fn encode_audio<S: AsRef<OsStr>>(
   input: impl AsRef<Path> + std::fmt::Debug,
@@ -165,7 +497,20 @@ pub fn encode_audio<S: AsRef<OsStr>>(
 
   if has_audio(input) {
     let audio_file = Path::new(temp).join("audio.mkv");
+
+    if audio_file.exists() {
+      if is_audio_complete(input, &audio_file) {
+        info!("audio was already encoded, skipping");
+        return Some(audio_file);
+      }
+      warn!("audio file from previous run is incomplete, encoding audio again");
+    }
+
+    // encode to a separate file first, so that audio.mkv only ever exists once
+    // the audio has been fully encoded
+    let partial_file = Path::new(temp).join("audio.partial.mkv");
     let mut encode_audio = Command::new("ffmpeg");
+    crate::cgroup::apply(&mut encode_audio);
 
     encode_audio.stdout(Stdio::piped());
     encode_audio.stderr(Stdio::piped());
@@ -176,7 +521,7 @@ pub fn encode_audio<S: AsRef<OsStr>>(
     encode_audio.args(["-map", "0", "-c", "copy", "-vn", "-dn"]);
 
     encode_audio.args(audio_params);
-    encode_audio.arg(&audio_file);
+    encode_audio.arg(&partial_file);
 
     let output = encode_audio.output().unwrap();
 
@@ -188,12 +533,49 @@ pub fn encode_audio<S: AsRef<OsStr>>(
       return None;
     }
 
+    if let Err(e) = std::fs::rename(&partial_file, &audio_file) {
+      warn!("Failed to move encoded audio into place: {e}");
+      return None;
+    }
+
     Some(audio_file)
   } else {
     None
   }
 }
 
+/// Copies the video streams of `video` and all streams of `audio` into
+/// `output`, with the metadata of `audio`
+pub fn mux_video_with_audio(video: &Path, audio: &Path, output: &Path) -> anyhow::Result<()> {
+  let mut cmd = Command::new("ffmpeg");
+  cmd.args(["-y", "-hide_banner", "-loglevel", "error", "-i"]);
+  cmd.arg(video);
+  cmd.arg("-i");
+  cmd.arg(audio);
+  cmd.args([
+    "-map",
+    "0:V",
+    "-map",
+    "1",
+    "-map_metadata",
+    "1",
+    "-c",
+    "copy",
+  ]);
+  cmd.arg(output);
+
+  let out = cmd.output().context("Failed to run ffmpeg")?;
+  if !out.status.success() {
+    bail!(
+      "FFmpeg failed to mux {} with the audio:\n{}",
+      video.display(),
+      String::from_utf8_lossy(&out.stderr)
+    );
+  }
+
+  Ok(())
+}
+
 /// Escapes paths in ffmpeg filters if on windows
 pub fn escape_path_in_filter(path: impl AsRef<Path>) -> String {
   if cfg!(windows) {
@@ -216,3 +598,104 @@ pub fn escape_path_in_filter(path: impl AsRef<Path>) -> String {
   .replace(']', r"\]")
   .replace(',', "\\,")
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn frame_rate_conversion() {
+    let ntsc: FrameRate = "24000/1001".parse().unwrap();
+    assert_eq!(
+      ntsc,
+      FrameRate {
+        num: 24000,
+        den: 1001
+      }
+    );
+    assert_eq!(
+      "23.976".parse(),
+      Ok(FrameRate {
+        num: 23976,
+        den: 1000
+      })
+    );
+    assert_eq!("30".parse(), Ok(FrameRate { num: 30, den: 1 }));
+    assert!("0".parse::<FrameRate>().is_err());
+    assert!("30/0".parse::<FrameRate>().is_err());
+    assert!("fast".parse::<FrameRate>().is_err());
+
+    let half = FrameRate { num: 30, den: 1 };
+    assert_eq!(half.convert_frame(100, 60.0), 50);
+    assert_eq!(half.convert_frame(101, 60.0), 51);
+
+    assert_eq!(
+      with_video_filter(&[], "fps=30/1"),
+      ["-vf", "fps=30/1"].map(String::from)
+    );
+    assert_eq!(
+      with_video_filter(&["-vf".to_owned(), "scale=1280:-2".to_owned()], "fps=30/1"),
+      ["-vf", "scale=1280:-2,fps=30/1"].map(String::from)
+    );
+  }
+
+  #[test]
+  fn chapter_timestamps() {
+    // Matroska chapters are in nanoseconds
+    let ns = Rational::new(1, 1_000_000_000);
+    assert_eq!(chapter_frame(0, ns, 0.0, 24000.0 / 1001.0), 0);
+    assert_eq!(
+      chapter_frame(90_090_000_000, ns, 0.0, 24000.0 / 1001.0),
+      2160
+    );
+    assert_eq!(chapter_frame(1_001, Rational::new(1, 1000), 0.0, 24.0), 24);
+    assert_eq!(chapter_frame(-1_000, Rational::new(1, 1000), 0.0, 24.0), 0);
+    // MPEG-TS usually starts at 1.4 seconds
+    let ms = Rational::new(1, 1000);
+    assert_eq!(chapter_frame(1_400, ms, 1.4, 25.0), 0);
+    assert_eq!(chapter_frame(11_400, ms, 1.4, 25.0), 250);
+  }
+
+  #[test]
+  fn resolution_after_filters() {
+    let filtered = |args: &[&str]| {
+      let args: Vec<String> = args.iter().map(|&arg| arg.to_owned()).collect();
+      filtered_resolution(&args, (3840, 2160))
+    };
+
+    assert_eq!(filtered(&[]), Some((3840, 2160)));
+    assert_eq!(filtered(&["-vf", "scale=1920:-2"]), Some((1920, 1080)));
+    assert_eq!(filtered(&["-vf", "scale=w=-1:h=720"]), Some((1280, 720)));
+    assert_eq!(filtered(&["-vf", "scale=iw/2:ih/2"]), Some((1920, 1080)));
+    assert_eq!(
+      filtered(&["-vf", "crop=3840:1600:0:280,scale=1920:-2"]),
+      Some((1920, 800))
+    );
+    assert_eq!(
+      filtered(&["-vf", "hqdn3d,zscale=width=1280:height=720:filter=spline36"]),
+      Some((1280, 720))
+    );
+    assert_eq!(filtered(&["-vf", "transpose=1"]), Some((2160, 3840)));
+    assert_eq!(
+      filtered(&["-filter_complex", "[0:v]scale=1920:1080[out]"]),
+      Some((1920, 1080))
+    );
+    assert_eq!(filtered(&["-vf", "scale=trunc(iw/3):-2"]), None);
+  }
+
+  #[test]
+  fn filters_changing_frame_count() {
+    let changes = |args: &[&str]| {
+      let args: Vec<String> = args.iter().map(|&arg| arg.to_owned()).collect();
+      changes_frame_count(&args)
+    };
+
+    assert!(!changes(&[]));
+    assert!(!changes(&["-vf", "scale=1280:-2,hqdn3d"]));
+    assert!(!changes(&["-vf", "bwdif=mode=send_frame:parity=auto"]));
+    assert!(changes(&["-vf", "crop=1920:800,mpdecimate"]));
+    assert!(changes(&["-vf", "select=not(mod(n\\,2))"]));
+    assert!(changes(&["-vf", "yadif=1"]));
+    assert!(changes(&["-filter:v", "bwdif=mode=send_field"]));
+  }
+}
@@ -0,0 +1,151 @@
+//! Detection and deinterlacing of interlaced sources.
+//!
+//! The encoders treat every frame as progressive, so interlaced content is
+//! encoded with combing artifacts. The field order of the source is detected
+//! with the idet filter of ffmpeg on its first frames, and the source can be
+//! deinterlaced with bwdif in the ffmpeg pipe of the chunks, or with QTGMC in
+//! the VapourSynth script of the chunk methods that use one. Both keep the
+//! number of frames, and flag the frames as progressive.
+
+use std::fmt::{self, Display};
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use strum::{EnumString, IntoStaticStr};
+
+/// Number of frames the field order is detected on
+const DETECTION_FRAMES: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldOrder {
+  Progressive,
+  TopFieldFirst,
+  BottomFieldFirst,
+}
+
+impl Display for FieldOrder {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Self::Progressive => "progressive",
+      Self::TopFieldFirst => "top field first",
+      Self::BottomFieldFirst => "bottom field first",
+    })
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, EnumString, IntoStaticStr)]
+pub enum Deinterlace {
+  /// bwdif, only if the source is detected as interlaced
+  #[strum(serialize = "auto")]
+  Auto,
+  #[strum(serialize = "bwdif")]
+  Bwdif,
+  #[strum(serialize = "qtgmc")]
+  Qtgmc,
+}
+
+/// Detects the field order of the first frames of the source with idet
+pub fn detect(source: &Path) -> anyhow::Result<FieldOrder> {
+  let output = Command::new("ffmpeg")
+    .args(["-hide_banner", "-nostats", "-i"])
+    .arg(source)
+    .args([
+      "-map",
+      "0:v:0",
+      "-vf",
+      "idet",
+      "-frames:v",
+      &DETECTION_FRAMES.to_string(),
+      "-an",
+      "-f",
+      "null",
+      "-",
+    ])
+    .output()
+    .context("Failed to run ffmpeg to detect interlacing")?;
+
+  let stderr = String::from_utf8_lossy(&output.stderr);
+  let Some(order) = parse_idet(&stderr) else {
+    bail!("Failed to parse the output of idet:\n{stderr}");
+  };
+
+  Ok(order)
+}
+
+/// Parses the summary of the multi frame detection of idet, e.g.
+/// "Multi frame detection: TFF:  480 BFF:    0 Progressive:   12 Undetermined:    8"
+fn parse_idet(stderr: &str) -> Option<FieldOrder> {
+  let line = stderr
+    .lines()
+    .find(|line| line.contains("Multi frame detection:"))?;
+  let (_, counts) = line.split_once("Multi frame detection:")?;
+
+  let count = |name: &str| -> Option<u64> {
+    let (_, after) = counts.split_once(name)?;
+    after.split_ascii_whitespace().next()?.parse().ok()
+  };
+  let tff = count("TFF:")?;
+  let bff = count("BFF:")?;
+  let progressive = count("Progressive:")?;
+
+  Some(if tff + bff <= progressive {
+    FieldOrder::Progressive
+  } else if tff >= bff {
+    FieldOrder::TopFieldFirst
+  } else {
+    FieldOrder::BottomFieldFirst
+  })
+}
+
+/// Returns the ffmpeg filter that deinterlaces the frames with bwdif, keeping
+/// the frame rate
+pub fn bwdif_filter(order: Option<FieldOrder>) -> String {
+  let parity = match order {
+    Some(FieldOrder::TopFieldFirst) => "tff",
+    Some(FieldOrder::BottomFieldFirst) => "bff",
+    Some(FieldOrder::Progressive) | None => "auto",
+  };
+
+  format!("bwdif=mode=send_frame:parity={parity}:deint=all,setfield=prog")
+}
+
+/// Returns the lines of a VapourSynth script that deinterlace `clip` with
+/// QTGMC of havsfunc, keeping the frame rate
+pub fn qtgmc_script(order: FieldOrder) -> String {
+  let tff = if order == FieldOrder::BottomFieldFirst {
+    "False"
+  } else {
+    "True"
+  };
+
+  format!(
+    "import havsfunc\n\
+     clip = havsfunc.QTGMC(clip, Preset='Slower', TFF={tff}, FPSDivisor=2)\n\
+     clip = core.std.SetFieldBased(clip, 0)\n"
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn idet_parsing() {
+    let stderr = "[Parsed_idet_0 @ 0x5581] Repeated Fields: Neither:   500 Top:     0 Bottom:     0\n\
+      [Parsed_idet_0 @ 0x5581] Single frame detection: TFF:   310 BFF:     2 Progressive:    40 Undetermined:   148\n\
+      [Parsed_idet_0 @ 0x5581] Multi frame detection: TFF:   452 BFF:     0 Progressive:    30 Undetermined:    18\n";
+    assert_eq!(parse_idet(stderr), Some(FieldOrder::TopFieldFirst));
+
+    let stderr =
+      "Multi frame detection: TFF:     3 BFF:     1 Progressive:   490 Undetermined:     6";
+    assert_eq!(parse_idet(stderr), Some(FieldOrder::Progressive));
+
+    let stderr =
+      "Multi frame detection: TFF:     0 BFF:   420 Progressive:    60 Undetermined:    20";
+    assert_eq!(parse_idet(stderr), Some(FieldOrder::BottomFieldFirst));
+
+    assert_eq!(parse_idet("Output #0, null, to 'pipe:':"), None);
+  }
+}
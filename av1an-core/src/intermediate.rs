@@ -0,0 +1,180 @@
+//! Lossless intermediate encodes of the chunks, e.g. to prepare a filtered
+//! master with the parallelism of the chunks before the final encode.
+//!
+//! The chunks go through the same pipeline as the chunks of an encoder: they
+//! are split, filtered by `--ffmpeg` or the VapourSynth script, encoded in
+//! parallel and concatenated into a Matroska file. Only the command reading
+//! the frames of a chunk differs, which is x264 at qp 0, or ffv1 through
+//! ffmpeg. The chunks keep the pixel format of a video source, as converting
+//! it would not be lossless.
+
+use std::fmt::{self, Display};
+
+use anyhow::Context;
+use ffmpeg::format::Pixel;
+use serde::{Deserialize, Serialize};
+
+use crate::encoder::Encoder;
+use crate::into_vec;
+use crate::parse::parse_x26x_frames;
+use crate::settings::PixelFormat;
+
+#[allow(non_camel_case_types)]
+#[derive(
+  Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug, strum::EnumString, strum::IntoStaticStr,
+)]
+pub enum Intermediate {
+  x264,
+  ffv1,
+}
+
+impl Display for Intermediate {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(<&'static str>::from(self))
+  }
+}
+
+impl Intermediate {
+  /// Returns the encoder the chunks are handled as, whose chunks are
+  /// Matroska files like the lossless chunks
+  pub const fn encoder(self) -> Encoder {
+    Encoder::x264
+  }
+
+  /// Returns the pixel format of the chunks of a source in `format`, which is
+  /// kept as is
+  pub fn pixel_format(self, format: Pixel) -> anyhow::Result<PixelFormat> {
+    let bit_depth = match self {
+      Self::x264 => Encoder::x264
+        .get_format_bit_depth(format)
+        .with_context(|| {
+          format!("x264 cannot encode {format:?} losslessly, use --intermediate ffv1")
+        })?,
+      Self::ffv1 => format_bit_depth(format),
+    };
+    Ok(PixelFormat { format, bit_depth })
+  }
+
+  /// Composes the command encoding the y4m stream of a chunk in `format`
+  /// losslessly
+  pub fn compose(self, output: String, frame_count: usize, format: PixelFormat) -> Vec<String> {
+    match self {
+      // x264 outputs 4:2:0 at the depth it was built with unless told otherwise
+      Self::x264 => Encoder::x264.compose_1_1_pass(
+        into_vec![
+          "--qp",
+          "0",
+          "--preset",
+          "veryfast",
+          "--output-depth",
+          format.bit_depth.to_string(),
+          "--output-csp",
+          x264_csp(format.format),
+        ],
+        output,
+        frame_count,
+      ),
+      Self::ffv1 => into_vec![
+        "ffmpeg",
+        "-y",
+        "-hide_banner",
+        "-loglevel",
+        "error",
+        "-stats",
+        "-f",
+        "yuv4mpegpipe",
+        "-i",
+        "-",
+        "-frames:v",
+        frame_count.to_string(),
+        "-c:v",
+        "ffv1",
+        "-level",
+        "3",
+        "-g",
+        "1",
+        "-slices",
+        "4",
+        "-slicecrc",
+        "1",
+        "-f",
+        "matroska",
+        output,
+      ],
+    }
+  }
+
+  /// Returns the frames encoded so far from a line of the output of the
+  /// command
+  pub(crate) fn parse_encoded_frames(self, line: &str) -> Option<u64> {
+    match self {
+      Self::x264 => parse_x26x_frames(line),
+      // frame=  120 fps= 42 q=-0.0 size=   98304kB time=00:00:05.00 ...
+      Self::ffv1 => line
+        .trim_start()
+        .strip_prefix("frame=")?
+        .split_ascii_whitespace()
+        .next()?
+        .parse()
+        .ok(),
+    }
+  }
+}
+
+/// Returns the chroma subsampling of `format` as an output colorspace of x264
+const fn x264_csp(format: Pixel) -> &'static str {
+  match format {
+    Pixel::YUV422P | Pixel::YUVJ422P | Pixel::YUV422P10LE | Pixel::NV16 | Pixel::NV20LE => "i422",
+    Pixel::YUV444P | Pixel::YUVJ444P | Pixel::YUV444P10LE => "i444",
+    Pixel::GRAY8 | Pixel::GRAY10LE => "i400",
+    _ => "i420",
+  }
+}
+
+/// Returns the bit depth of `format` from its name, e.g. 10 for yuv422p10le,
+/// which is 8 unless the name ends with the depth and endianness
+fn format_bit_depth(format: Pixel) -> usize {
+  let Some(name) = format
+    .descriptor()
+    .map(ffmpeg::format::pixel::Descriptor::name)
+  else {
+    return 8;
+  };
+  let Some(name) = name.strip_suffix("le").or_else(|| name.strip_suffix("be")) else {
+    return 8;
+  };
+  let digits = name.len() - name.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+  name[name.len() - digits..].parse().unwrap_or(8)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_ffmpeg_stats() {
+    let line =
+      "frame=  120 fps= 42 q=-0.0 size=   98304kB time=00:00:05.00 bitrate=161061.3kbits/s";
+    assert_eq!(Intermediate::ffv1.parse_encoded_frames(line), Some(120));
+    assert_eq!(
+      Intermediate::ffv1.parse_encoded_frames("frame=1234 fps=0.0 q=-0.0"),
+      Some(1234)
+    );
+    assert_eq!(
+      Intermediate::ffv1.parse_encoded_frames("[matroska @ 0x0] error"),
+      None
+    );
+
+    let format = PixelFormat {
+      format: Pixel::YUV422P10LE,
+      bit_depth: 10,
+    };
+    let cmd = Intermediate::ffv1.compose("out.mkv".to_owned(), 240, format);
+    assert_eq!(cmd.first().map(String::as_str), Some("ffmpeg"));
+    assert_eq!(cmd.last().map(String::as_str), Some("out.mkv"));
+
+    let cmd = Intermediate::x264.compose("out.mkv".to_owned(), 240, format);
+    let at = cmd.iter().position(|arg| arg == "--output-depth").unwrap();
+    assert_eq!(cmd[at + 1..at + 4], ["10", "--output-csp", "i422"]);
+  }
+}
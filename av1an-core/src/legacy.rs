@@ -0,0 +1,263 @@
+//! Best-effort import of temporary folders written by legacy versions of av1an,
+//! i.e. the Python implementation and early Rust versions, so that their
+//! unfinished encodes can be resumed.
+//!
+//! These versions stored the finished chunks in done.json as a plain frame
+//! count, the scenes as a list of scene changes, and the chunks either without
+//! zero padding or in a chunks.json that the current version cannot read. The
+//! original files are kept next to the converted ones with a `.legacy`
+//! extension. Chunks are created again from the scenes with the current
+//! settings, so the encode has to be resumed with the settings it was
+//! started with.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+
+use anyhow::{bail, Context};
+use dashmap::DashMap;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::chunk::Chunk;
+use crate::scenes::Scene;
+use crate::split::write_scenes_to_file;
+use crate::util::retry_io;
+use crate::{DoneChunk, DoneJson};
+
+/// Converts the files of the temp folder that were written by a legacy
+/// version to the current format. Returns whether anything was converted.
+pub fn import_temp(temp: &Path) -> anyhow::Result<bool> {
+  let encode_dir = temp.join("encode");
+
+  // chunks are renamed first, so that done.json is converted with their new names
+  let mut imported = rename_chunks(&encode_dir)?;
+  imported |= import_scenes(&temp.join("scenes.json"))?;
+  imported |= import_done(&temp.join("done.json"), &encode_dir)?;
+
+  let chunks_path = temp.join("chunks.json");
+  if let Ok(contents) = fs::read_to_string(&chunks_path) {
+    if serde_json::from_str::<Vec<Chunk>>(&contents).is_err() {
+      debug!("chunks.json was written by a legacy version, the chunks will be created again");
+      keep_legacy(&chunks_path)?;
+      imported = true;
+    }
+  }
+
+  Ok(imported)
+}
+
+/// Moves the original file aside, so that it can be recovered if the import
+/// turns out to be wrong
+fn keep_legacy(path: &Path) -> anyhow::Result<()> {
+  let mut legacy_path = path.as_os_str().to_owned();
+  legacy_path.push(".legacy");
+  let legacy_path = PathBuf::from(legacy_path);
+
+  retry_io(|| fs::rename(path, &legacy_path))
+    .with_context(|| format!("Failed to move {} aside", path.display()))
+}
+
+/// Adds the zero padding of the current naming scheme to the encoded chunks,
+/// e.g. `encode/12.ivf` becomes `encode/00012.ivf`
+fn rename_chunks(encode_dir: &Path) -> anyhow::Result<bool> {
+  let Ok(entries) = fs::read_dir(encode_dir) else {
+    return Ok(false);
+  };
+
+  let mut renamed = false;
+  for entry in entries {
+    let path = entry?.path();
+    let Some(index) = chunk_index(&path) else {
+      continue;
+    };
+
+    let name = format!("{index:05}");
+    if path.file_stem().is_some_and(|stem| stem != name.as_str()) {
+      let new_path = path
+        .with_file_name(name)
+        .with_extension(path.extension().unwrap_or_default());
+      retry_io(|| fs::rename(&path, &new_path)).with_context(|| {
+        format!(
+          "Failed to rename {} to {}",
+          path.display(),
+          new_path.display()
+        )
+      })?;
+      renamed = true;
+    }
+  }
+
+  Ok(renamed)
+}
+
+fn chunk_index(path: &Path) -> Option<usize> {
+  path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Returns the size of the encoded chunk, if it exists
+fn chunk_size(encode_dir: &Path, name: &str) -> Option<u64> {
+  fs::read_dir(encode_dir)
+    .ok()?
+    .filter_map(Result::ok)
+    .find(|entry| entry.path().file_stem().is_some_and(|stem| stem == name))
+    .and_then(|entry| entry.metadata().ok())
+    .map(|metadata| metadata.len())
+}
+
+/// Scenes as written by legacy versions, which only stored the frames where
+/// scenes change
+#[derive(Deserialize)]
+struct LegacyScenes {
+  scenes: Vec<usize>,
+  frames: usize,
+}
+
+fn import_scenes(path: &Path) -> anyhow::Result<bool> {
+  let Ok(contents) = fs::read_to_string(path) else {
+    return Ok(false);
+  };
+  // anything else is either the current format, or left for the regular parser to report
+  let Ok(legacy) = serde_json::from_str::<LegacyScenes>(&contents) else {
+    return Ok(false);
+  };
+  if legacy.frames == 0 {
+    bail!("scenes.json of the legacy version does not contain the number of frames");
+  }
+
+  debug!("converting scenes.json of a legacy version");
+  keep_legacy(path)?;
+  write_scenes_to_file(
+    &scenes_from_changes(&legacy.scenes, legacy.frames),
+    legacy.frames,
+    path,
+  )
+  .with_context(|| format!("Failed to write {}", path.display()))?;
+
+  Ok(true)
+}
+
+/// Creates the scenes between the given scene changes, which may or may not
+/// include the first frame
+fn scenes_from_changes(changes: &[usize], frames: usize) -> Vec<Scene> {
+  let mut changes: Vec<_> = changes
+    .iter()
+    .copied()
+    .filter(|&frame| frame > 0 && frame < frames)
+    .collect();
+  changes.sort_unstable();
+  changes.dedup();
+
+  std::iter::once(0)
+    .chain(changes)
+    .chain(std::iter::once(frames))
+    .collect::<Vec<_>>()
+    .windows(2)
+    .map(|bounds| Scene {
+      start_frame: bounds[0],
+      end_frame: bounds[1],
+      zone_overrides: None,
+      cut: None,
+    })
+    .collect()
+}
+
+fn import_done(path: &Path, encode_dir: &Path) -> anyhow::Result<bool> {
+  let Ok(contents) = fs::read_to_string(path) else {
+    return Ok(false);
+  };
+  if serde_json::from_str::<DoneJson>(&contents).is_ok() {
+    return Ok(false);
+  }
+
+  let legacy: Value = serde_json::from_str(&contents).context("Failed to parse done.json")?;
+  let Some(entries) = legacy.get("done").and_then(Value::as_object) else {
+    bail!("done.json does not contain the finished chunks");
+  };
+
+  let done = DashMap::new();
+  for (name, entry) in entries {
+    let index: usize = name
+      .parse()
+      .with_context(|| format!("Unexpected chunk name {name:?} in done.json"))?;
+    let frames = entry
+      .as_u64()
+      .or_else(|| entry.get("frames")?.as_u64())
+      .with_context(|| format!("Missing frame count of chunk {name} in done.json"))?;
+
+    let name = format!("{index:05}");
+    let Some(size_bytes) = chunk_size(encode_dir, &name) else {
+      warn!(
+        "chunk {} is marked as done, but its encode is missing, it will be encoded again",
+        name
+      );
+      continue;
+    };
+
+    done.insert(
+      name,
+      DoneChunk {
+        frames: frames as usize,
+        size_bytes,
+        pass_times: Vec::new(),
+        tq_cq: None,
+        mean_probe_score: None,
+        fallback: None,
+      },
+    );
+  }
+
+  // a frame count of 0 makes the resume count the frames of the input again
+  let done = DoneJson {
+    frames: AtomicUsize::new(
+      legacy
+        .get("frames")
+        .and_then(Value::as_u64)
+        .unwrap_or_default() as usize,
+    ),
+    done,
+    audio_done: AtomicBool::new(
+      legacy
+        .get("audio_done")
+        .and_then(Value::as_bool)
+        .unwrap_or_default(),
+    ),
+  };
+
+  debug!(
+    "converting done.json of a legacy version with {} finished chunks",
+    done.done.len()
+  );
+  keep_legacy(path)?;
+  let contents = serde_json::to_string(&done)?;
+  retry_io(|| fs::write(path, &contents))
+    .with_context(|| format!("Failed to write {}", path.display()))?;
+
+  Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn legacy_scene_changes() {
+    let bounds = |scenes: Vec<Scene>| {
+      scenes
+        .iter()
+        .map(|scene| (scene.start_frame, scene.end_frame))
+        .collect::<Vec<_>>()
+    };
+
+    // with and without the first frame, unsorted, duplicated, or out of range
+    assert_eq!(
+      bounds(scenes_from_changes(&[48, 120], 200)),
+      [(0, 48), (48, 120), (120, 200)]
+    );
+    assert_eq!(
+      bounds(scenes_from_changes(&[0, 120, 48, 48, 200, 250], 200)),
+      [(0, 48), (48, 120), (120, 200)]
+    );
+    assert_eq!(bounds(scenes_from_changes(&[], 200)), [(0, 200)]);
+  }
+}
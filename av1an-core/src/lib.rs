@@ -23,14 +23,13 @@ extern crate log;
 use std::cmp::max;
 use std::collections::hash_map::DefaultHasher;
 use std::fs;
-use std::fs::File;
 use std::hash::{Hash, Hasher};
-use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::string::ToString;
 use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::thread::available_parallelism;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use ::ffmpeg::color::TransferCharacteristic;
 use ::vapoursynth::api::API;
@@ -39,30 +38,59 @@ use anyhow::{bail, Context};
 use av1_grain::TransferFunction;
 use chunk::Chunk;
 use dashmap::DashMap;
-use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
 use strum::{Display, EnumString, IntoStaticStr};
 
 use crate::encoder::Encoder;
 use crate::progress_bar::finish_progress_bar;
+use crate::util::retry_io;
 
+pub mod auto_params;
+pub mod batch;
+pub mod bit_allocation;
 pub mod broker;
+pub mod cgroup;
+pub mod checkpoint;
 pub mod chunk;
+pub mod complexity;
 pub mod concat;
 pub mod context;
+pub mod crash;
+pub mod disk_space;
+pub mod dovi;
 pub mod encoder;
+pub mod encoder_profile;
 pub mod ffmpeg;
+pub mod interlace;
+pub mod intermediate;
+mod legacy;
+mod live;
 pub mod logging;
+pub mod notify;
+pub mod numa;
 pub(crate) mod parse;
+pub mod pipe_layout;
+pub mod process_group;
 pub mod progress_bar;
+pub mod proxy;
+pub mod remote;
+pub mod report;
 pub mod scene_detect;
 mod scenes;
+pub mod schedule;
 pub mod settings;
+pub mod sidecar;
 pub mod split;
+mod ssimulacra2;
+pub mod status;
+pub mod super_chunk;
 pub mod target_quality;
 pub mod util;
 pub mod vapoursynth;
+pub mod verify;
+pub mod vfr;
 pub mod vmaf;
+pub mod y4m;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Input {
@@ -116,6 +144,17 @@ impl Input {
     matches!(&self, Input::VapourSynth { .. })
   }
 
+  /// Whether the input is a URL of a file served over HTTP, which is cached in
+  /// the temporary folder before it is encoded
+  pub fn is_remote(&self) -> bool {
+    matches!(&self, Input::Video { path } if path.to_str().is_some_and(remote::is_url))
+  }
+
+  /// Whether the input is a stream read from the standard input, given as `-`
+  pub fn is_stdin(&self) -> bool {
+    matches!(&self, Input::Video { path } if path.as_os_str() == "-")
+  }
+
   pub fn frames(&self) -> anyhow::Result<usize> {
     const FAIL_MSG: &str = "Failed to get number of frames for input video";
     Ok(match &self {
@@ -221,16 +260,7 @@ impl Input {
   /// Default video without tiling is 1,1
   /// Return number of horizontal and vertical tiles
   pub fn calculate_tiles(&self) -> (u32, u32) {
-    match self.resolution() {
-      Ok((h, v)) => {
-        // tile range 0-1440 pixels
-        let horizontal = max((h - 1) / 720, 1);
-        let vertical = max((v - 1) / 720, 1);
-
-        (horizontal, vertical)
-      }
-      _ => (1, 1),
-    }
+    self.resolution().map_or((1, 1), tiles_for_resolution)
   }
 
   /// Returns the vector of arguments passed to the vspipe python environment
@@ -276,10 +306,22 @@ impl<P: AsRef<Path> + Into<PathBuf>> From<(P, Vec<String>)> for Input {
   }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 struct DoneChunk {
   frames: usize,
   size_bytes: u64,
+  /// Time spent in each pass, in seconds
+  #[serde(default)]
+  pass_times: Vec<f64>,
+  /// Quantizer chosen by target quality
+  #[serde(default)]
+  tq_cq: Option<u32>,
+  /// Mean score of the target quality probes
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  mean_probe_score: Option<f64>,
+  /// Chunk method the chunk was decoded with, if it failed with the one of the encode
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  fallback: Option<ChunkMethod>,
 }
 
 /// Concurrent data structure for keeping track of the finished chunks in an encode
@@ -290,17 +332,115 @@ struct DoneJson {
   audio_done: AtomicBool,
 }
 
-static DONE_JSON: OnceCell<DoneJson> = OnceCell::new();
+static DONE_JSON: parking_lot::RwLock<Option<&'static DoneJson>> = parking_lot::const_rwlock(None);
 
 // once_cell::sync::Lazy cannot be used here due to Lazy<T> not implementing
 // Serialize or Deserialize, we need to get a reference directly to the global
 // data
 fn get_done() -> &'static DoneJson {
-  DONE_JSON.get().unwrap()
+  DONE_JSON.read().unwrap()
 }
 
+/// Returns the state of the current encode, if it was initialized
+fn try_get_done() -> Option<&'static DoneJson> {
+  *DONE_JSON.read()
+}
+
+/// Replaces the state of the previous encode, if any, as every input of a batch
+/// has its own done.json. The previous state is leaked, as threads of the
+/// previous encode may still hold a reference to it.
 fn init_done(done: DoneJson) -> &'static DoneJson {
-  DONE_JSON.get_or_init(|| done)
+  let done = Box::leak(Box::new(done));
+  *DONE_JSON.write() = Some(done);
+  done
+}
+
+/// Serializes writes of done.json between the writer thread and explicit flushes
+static DONE_JSON_WRITE_LOCK: parking_lot::Mutex<()> = parking_lot::const_mutex(());
+
+/// Writes the current state of done.json, replacing the previous file atomically
+/// so that an interrupted write never leaves a truncated file behind.
+fn write_done(path: &Path) -> anyhow::Result<()> {
+  let _lock = DONE_JSON_WRITE_LOCK.lock();
+
+  let tmp_path = path.with_extension("json.tmp");
+  let contents = serde_json::to_string(get_done())?;
+  retry_io(|| fs::write(&tmp_path, &contents))
+    .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+  retry_io(|| fs::rename(&tmp_path, path))
+    .with_context(|| format!("Failed to replace {}", path.display()))?;
+
+  Ok(())
+}
+
+/// Persists done.json on a dedicated thread.
+///
+/// Updates are requested through a channel and coalesced, so that finishing
+/// many chunks in quick succession (or writing to a slow temp directory)
+/// does not stall the workers on serialization and IO.
+#[derive(Debug)]
+pub struct DoneJsonWriter {
+  path: PathBuf,
+  tx: parking_lot::Mutex<Option<crossbeam_channel::Sender<()>>>,
+  handle: parking_lot::Mutex<Option<std::thread::JoinHandle<()>>>,
+}
+
+impl DoneJsonWriter {
+  /// How long to wait for further updates before writing
+  const DEBOUNCE: Duration = Duration::from_millis(500);
+
+  pub fn spawn(path: PathBuf) -> Self {
+    let (tx, rx) = crossbeam_channel::unbounded::<()>();
+
+    let thread_path = path.clone();
+    let handle = std::thread::spawn(move || {
+      while rx.recv().is_ok() {
+        let deadline = Instant::now() + Self::DEBOUNCE;
+        while rx.recv_deadline(deadline).is_ok() {}
+
+        if let Err(e) = write_done(&thread_path) {
+          warn!("Failed to write {}: {}", thread_path.display(), e);
+        }
+        status::update();
+      }
+    });
+
+    Self {
+      path,
+      tx: parking_lot::Mutex::new(Some(tx)),
+      handle: parking_lot::Mutex::new(Some(handle)),
+    }
+  }
+
+  /// Requests done.json to be written in the background
+  pub fn request_save(&self) {
+    // written directly once the writer thread is stopped
+    let sent = self
+      .tx
+      .lock()
+      .as_ref()
+      .is_some_and(|tx| tx.send(()).is_ok());
+    if !sent {
+      self.flush();
+    }
+  }
+
+  /// Writes done.json immediately on the calling thread
+  pub fn flush(&self) {
+    if let Err(e) = write_done(&self.path) {
+      warn!("Failed to write {}: {}", self.path.display(), e);
+    }
+    status::update();
+  }
+
+  /// Waits for pending writes to finish and stops the writer thread
+  pub fn finish(&self) {
+    drop(self.tx.lock().take());
+    let handle = self.handle.lock().take();
+    if let Some(handle) = handle {
+      handle.join().unwrap();
+    }
+  }
 }
 
 pub fn list_index(params: &[impl AsRef<str>], is_match: fn(&str) -> bool) -> Option<usize> {
@@ -319,6 +459,8 @@ pub fn list_index(params: &[impl AsRef<str>], is_match: fn(&str) -> bool) -> Opt
 pub enum SplitMethod {
   #[strum(serialize = "av-scenechange")]
   AvScenechange,
+  #[strum(serialize = "source-keyframes")]
+  SourceKeyframes,
   #[strum(serialize = "none")]
   None,
 }
@@ -349,6 +491,51 @@ pub enum ChunkMethod {
   BESTSOURCE,
 }
 
+/// How the BestSource chunk method caches the index of the source
+#[derive(
+  PartialEq, Eq, Copy, Clone, Serialize, Deserialize, Debug, Display, EnumString, IntoStaticStr,
+)]
+pub enum BestSourceCacheMode {
+  /// The index is never written, so that every worker indexes the source again
+  #[strum(serialize = "never")]
+  Never,
+  /// The index is only written if indexing the source took long enough
+  #[strum(serialize = "auto")]
+  Auto,
+  #[strum(serialize = "always")]
+  Always,
+}
+
+impl BestSourceCacheMode {
+  /// Returns the `cachemode` of `bs.VideoSource`, with the cache path being
+  /// the path of the index
+  pub const fn cachemode(self) -> u8 {
+    match self {
+      Self::Never => 0,
+      Self::Auto => 3,
+      Self::Always => 4,
+    }
+  }
+}
+
+/// Whether the frames of the chunks are piped through ffmpeg
+#[derive(
+  PartialEq, Eq, Copy, Clone, Serialize, Deserialize, Debug, Display, EnumString, IntoStaticStr,
+)]
+pub enum PipeMode {
+  /// The filters and the pixel format conversion are done in the VapourSynth
+  /// script if they can be, and ffmpeg is only used otherwise
+  #[strum(serialize = "auto")]
+  Auto,
+  /// ffmpeg is never used, which fails if the filters cannot be done in the
+  /// VapourSynth script
+  #[strum(serialize = "vs-only")]
+  VsOnly,
+  /// The frames are always piped through ffmpeg
+  #[strum(serialize = "ffmpeg")]
+  FFmpeg,
+}
+
 #[derive(
   PartialEq, Eq, Copy, Clone, Serialize, Deserialize, Debug, Display, EnumString, IntoStaticStr,
 )]
@@ -363,9 +550,181 @@ pub enum ChunkOrdering {
   Random,
 }
 
+/// CPU scheduling priority of the processes spawned for each chunk
+#[derive(
+  PartialEq, Eq, Copy, Clone, Serialize, Deserialize, Debug, Display, EnumString, IntoStaticStr,
+)]
+pub enum ProcessPriority {
+  #[strum(serialize = "idle")]
+  Idle,
+  #[strum(serialize = "below-normal")]
+  BelowNormal,
+  #[strum(serialize = "normal")]
+  Normal,
+  #[strum(serialize = "above-normal")]
+  AboveNormal,
+  #[strum(serialize = "high")]
+  High,
+}
+
+impl ProcessPriority {
+  /// Equivalent niceness value on Unix-like systems
+  #[must_use]
+  pub const fn nice_value(self) -> i32 {
+    match self {
+      Self::Idle => 19,
+      Self::BelowNormal => 10,
+      Self::Normal => 0,
+      Self::AboveNormal => -5,
+      Self::High => -10,
+    }
+  }
+
+  /// Equivalent process creation flag (priority class) on Windows
+  #[must_use]
+  pub const fn priority_class(self) -> u32 {
+    match self {
+      Self::Idle => 0x0000_0040,
+      Self::BelowNormal => 0x0000_4000,
+      Self::Normal => 0x0000_0020,
+      Self::AboveNormal => 0x0000_8000,
+      Self::High => 0x0000_0080,
+    }
+  }
+}
+
+/// IO scheduling priority of the processes spawned for each chunk
+#[derive(
+  PartialEq, Eq, Copy, Clone, Serialize, Deserialize, Debug, Display, EnumString, IntoStaticStr,
+)]
+pub enum IoPriority {
+  #[strum(serialize = "idle")]
+  Idle,
+  #[strum(serialize = "low")]
+  Low,
+  #[strum(serialize = "normal")]
+  Normal,
+  #[strum(serialize = "high")]
+  High,
+}
+
+impl IoPriority {
+  /// Value passed to `ioprio_set` on Linux, encoded as `(class << 13) | level`
+  #[must_use]
+  pub const fn ioprio_value(self) -> i32 {
+    const IOPRIO_CLASS_BE: i32 = 2;
+    const IOPRIO_CLASS_IDLE: i32 = 3;
+    const IOPRIO_CLASS_SHIFT: i32 = 13;
+
+    match self {
+      Self::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+      Self::Low => (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | 7,
+      Self::Normal => (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | 4,
+      Self::High => IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT,
+    }
+  }
+}
+
+/// Statistic used to aggregate the per-frame VMAF scores of a target quality probe
+#[derive(
+  PartialEq, Eq, Copy, Clone, Serialize, Deserialize, Debug, Display, EnumString, IntoStaticStr,
+)]
+pub enum ProbingStatisticName {
+  #[strum(serialize = "mean")]
+  Mean,
+  #[strum(serialize = "median")]
+  Median,
+  #[strum(serialize = "harmonic")]
+  Harmonic,
+  #[strum(serialize = "percentile")]
+  Percentile,
+  #[strum(serialize = "min")]
+  Min,
+}
+
+/// A probing statistic, along with its value (the percentile, in percent)
+/// for [`ProbingStatisticName::Percentile`]
+#[derive(PartialEq, Copy, Clone, Serialize, Deserialize, Debug)]
+pub struct ProbingStatistic {
+  pub name: ProbingStatisticName,
+  pub value: Option<f64>,
+}
+
+impl Default for ProbingStatistic {
+  fn default() -> Self {
+    Self {
+      name: ProbingStatisticName::Percentile,
+      value: Some(1.0),
+    }
+  }
+}
+
+impl FromStr for ProbingStatistic {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (name, value) = match s.split_once('=') {
+      Some((name, value)) => (name, Some(value)),
+      None => (s, None),
+    };
+    let name = ProbingStatisticName::from_str(name.trim())
+      .map_err(|_| format!("unknown probing statistic: {name:?}"))?;
+
+    let value = match (name, value) {
+      (ProbingStatisticName::Percentile, Some(value)) => {
+        let value: f64 = value
+          .trim()
+          .parse()
+          .map_err(|_| format!("invalid percentile: {value:?}"))?;
+        if !(0.0..=100.0).contains(&value) {
+          return Err(format!("percentile must be between 0 and 100, got {value}"));
+        }
+        Some(value)
+      }
+      (ProbingStatisticName::Percentile, None) => {
+        return Err("percentile requires a value, e.g. percentile=1".to_owned())
+      }
+      (_, Some(_)) => return Err(format!("{name} does not take a value")),
+      (_, None) => None,
+    };
+
+    Ok(Self { name, value })
+  }
+}
+
+impl std::fmt::Display for ProbingStatistic {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self.value {
+      Some(value) => write!(f, "{}={}", self.name, value),
+      None => write!(f, "{}", self.name),
+    }
+  }
+}
+
+/// GPU memory used by each DGDecNV decoder, in MB
+const DGDECNV_GPU_MB: u64 = 512;
+
+/// Returns the number of horizontal and vertical tiles for a resolution
+#[must_use]
+pub fn tiles_for_resolution((h, v): (u32, u32)) -> (u32, u32) {
+  // tile range 0-1440 pixels
+  let horizontal = max(h.saturating_sub(1) / 720, 1);
+  let vertical = max(v.saturating_sub(1) / 720, 1);
+
+  (horizontal, vertical)
+}
+
 /// Determine the optimal number of workers for an encoder
+///
+/// The memory each worker needs scales with the number of pixels of the
+/// resolution of the encode relative to 1080p.
 #[must_use]
-pub fn determine_workers(encoder: Encoder) -> u64 {
+pub fn determine_workers(
+  encoder: Encoder,
+  chunk_method: ChunkMethod,
+  gpu_devices: &[usize],
+  (width, height): (u32, u32),
+) -> u64 {
   let mut system = sysinfo::System::new();
   system.refresh_memory();
 
@@ -374,17 +733,57 @@ pub fn determine_workers(encoder: Encoder) -> u64 {
     .get() as u64;
   // available_memory returns kb, convert to gb
   let ram_gb = system.available_memory() / 10_u64.pow(6);
+  let scale = (f64::from(width) * f64::from(height) / (1920.0 * 1080.0)).clamp(0.5, 4.0);
 
-  std::cmp::max(
+  let workers = std::cmp::max(
     match encoder {
       Encoder::aom | Encoder::rav1e | Encoder::vpx => std::cmp::min(
         (cpu as f64 / 3.0).round() as u64,
-        (ram_gb as f64 / 1.5).round() as u64,
+        (ram_gb as f64 / (1.5 * scale)).round() as u64,
       ),
-      Encoder::svt_av1 | Encoder::x264 | Encoder::x265 => std::cmp::min(cpu, ram_gb) / 8,
+      Encoder::svt_av1 | Encoder::x264 | Encoder::x265 => {
+        std::cmp::min(cpu, (ram_gb as f64 / scale) as u64) / 8
+      }
     },
     1,
-  )
+  );
+
+  // DGDecNV decodes into the memory of the GPU rather than the RAM, so every
+  // worker needs a decoder that fits in the free memory of its GPU. The workers
+  // are spread evenly across the GPUs, so the GPU with the least free memory
+  // limits the share of every GPU.
+  if chunk_method == ChunkMethod::DGDECNV {
+    if let Some(free_mb) = free_gpu_memory_mb(gpu_devices) {
+      let per_gpu = (free_mb.iter().min().copied().unwrap_or(0) / DGDECNV_GPU_MB).max(1);
+      return workers.clamp(1, per_gpu * free_mb.len() as u64);
+    }
+  }
+
+  workers
+}
+
+/// Returns the free memory of each NVIDIA GPU of `devices` (or of the first
+/// GPU if there are none), in MB, if nvidia-smi is available
+fn free_gpu_memory_mb(devices: &[usize]) -> Option<Vec<u64>> {
+  let mut cmd = std::process::Command::new("nvidia-smi");
+  cmd.args(["--query-gpu=memory.free", "--format=csv,noheader,nounits"]);
+  if !devices.is_empty() {
+    let devices: Vec<String> = devices.iter().map(ToString::to_string).collect();
+    cmd.args(["-i", &devices.join(",")]);
+  }
+  let output = cmd.output().ok()?;
+  if !output.status.success() {
+    return None;
+  }
+  let free: Vec<u64> = String::from_utf8_lossy(&output.stdout)
+    .lines()
+    .map(|line| line.trim().parse().ok())
+    .collect::<Option<_>>()?;
+  if devices.is_empty() {
+    free.first().map(|&first| vec![first])
+  } else {
+    Some(free).filter(|free| !free.is_empty())
+  }
 }
 
 pub fn hash_path(path: &Path) -> String {
@@ -394,18 +793,21 @@ pub fn hash_path(path: &Path) -> String {
 }
 
 fn save_chunk_queue(temp: &str, chunk_queue: &[Chunk]) -> anyhow::Result<()> {
-  let mut file = File::create(Path::new(temp).join("chunks.json"))
-    .with_context(|| "Failed to create chunks.json file")?;
+  let file = Path::new(temp).join("chunks.json");
+  // serializing chunk_queue as json should never fail, so unwrap is OK here
+  let contents = serde_json::to_string(&chunk_queue).unwrap();
 
-  file
-    // serializing chunk_queue as json should never fail, so unwrap is OK here
-    .write_all(serde_json::to_string(&chunk_queue).unwrap().as_bytes())
-    .with_context(|| format!("Failed to write serialized chunk_queue data to {:?}", &file))?;
+  retry_io(|| fs::write(&file, &contents)).with_context(|| {
+    format!(
+      "Failed to write serialized chunk_queue data to {}",
+      file.display()
+    )
+  })?;
 
   Ok(())
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Verbosity {
   Verbose,
   Normal,
@@ -415,8 +817,37 @@ pub enum Verbosity {
 fn read_chunk_queue(temp: &Path) -> anyhow::Result<Vec<Chunk>> {
   let file = Path::new(temp).join("chunks.json");
 
-  let contents = fs::read_to_string(&file)
+  let contents = retry_io(|| fs::read_to_string(&file))
     .with_context(|| format!("Failed to read chunk queue file {:?}", &file))?;
 
   Ok(serde_json::from_str(&contents)?)
 }
+
+#[cfg(test)]
+mod tests {
+  use std::str::FromStr;
+
+  use crate::{ProbingStatistic, ProbingStatisticName};
+
+  #[test]
+  fn probing_statistic_from_str() {
+    assert_eq!(
+      ProbingStatistic::from_str("percentile=25"),
+      Ok(ProbingStatistic {
+        name: ProbingStatisticName::Percentile,
+        value: Some(25.0),
+      })
+    );
+    assert_eq!(
+      ProbingStatistic::from_str("harmonic"),
+      Ok(ProbingStatistic {
+        name: ProbingStatisticName::Harmonic,
+        value: None,
+      })
+    );
+    assert!(ProbingStatistic::from_str("percentile").is_err());
+    assert!(ProbingStatistic::from_str("percentile=101").is_err());
+    assert!(ProbingStatistic::from_str("mean=5").is_err());
+    assert!(ProbingStatistic::from_str("mode").is_err());
+  }
+}
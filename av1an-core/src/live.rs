@@ -0,0 +1,440 @@
+//! Encoding of a stream piped into the standard input, with `-i -`.
+//!
+//! The stream is cut into segments by ffmpeg as it arrives. Once ffmpeg has
+//! finished writing a segment, its scenes are detected and its chunks are
+//! queued for the workers, so the encode keeps up with the stream instead of
+//! waiting for its end. Scene detection only sees one segment at a time, so
+//! every segment starts a new scene.
+
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Child, Command, Stdio};
+use std::sync::{atomic, mpsc};
+use std::time::{Duration, Instant};
+use std::{fs, thread};
+
+use anyhow::{bail, ensure, Context};
+use tracing::{debug, info, warn};
+
+use crate::broker::{Broker, VerifyQueue};
+use crate::chunk::Chunk;
+use crate::concat::ConcatPlan;
+use crate::context::Av1anContext;
+use crate::ffmpeg::filtered_resolution;
+use crate::progress_bar::{
+  finish_progress_bar, init_multi_progress_bar, init_progress_bar, set_audio_size, set_len,
+};
+use crate::scene_detect::scene_detect;
+use crate::scenes::Scene;
+use crate::settings::InputPixelFormat;
+use crate::split::extra_splits;
+use crate::status::{self, State};
+use crate::{
+  cgroup, create_dir, determine_workers, get_done, notify, DoneJsonWriter, Input, SplitMethod,
+  Verbosity,
+};
+
+/// Duration of the segments the stream is cut into, in seconds, which is
+/// about how far the encode lags behind the stream
+const SEGMENT_SECONDS: u32 = 30;
+
+/// Interval of the checks for segments completed by ffmpeg
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The ffmpeg process cutting the stream into segments, and the segments it
+/// has completed so far
+struct Segmenter {
+  dir: PathBuf,
+  ffmpeg: Child,
+  /// Bytes of the segment list that were already read
+  read: usize,
+}
+
+impl Segmenter {
+  fn spawn(dir: &Path) -> anyhow::Result<Self> {
+    let mut cmd = Command::new("ffmpeg");
+    cgroup::apply(&mut cmd);
+    cmd
+      .args(["-y", "-hide_banner", "-loglevel", "error", "-i", "-"])
+      .args(["-map", "0:V:0", "-map", "0:a?", "-c", "copy"])
+      .args(["-f", "segment", "-segment_format", "nut"])
+      .args(["-segment_time", &SEGMENT_SECONDS.to_string()])
+      .args(["-reset_timestamps", "1", "-segment_list_type", "csv"])
+      .arg("-segment_list")
+      .arg(dir.join("segments.csv"))
+      .arg(dir.join("%05d.nut"))
+      .stdin(Stdio::inherit())
+      .stdout(Stdio::null())
+      .stderr(Stdio::inherit());
+
+    let ffmpeg = cmd
+      .spawn()
+      .context("Failed to spawn ffmpeg to read the standard input")?;
+
+    Ok(Self {
+      dir: dir.to_path_buf(),
+      ffmpeg,
+      read: 0,
+    })
+  }
+
+  /// Returns the segments completed since the last call, and whether the
+  /// stream has ended, after which all segments have been returned
+  fn poll(&mut self) -> anyhow::Result<(Vec<PathBuf>, bool)> {
+    // the exit is checked first, so that the list read afterwards is complete
+    let status = self.ffmpeg.try_wait()?;
+
+    let list = match fs::read(self.dir.join("segments.csv")) {
+      Ok(list) => list,
+      Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+      Err(e) => return Err(e).context("Failed to read the list of segments of the stream"),
+    };
+    let (names, read) = completed_segments(&list[self.read..]);
+    self.read += read;
+    let segments = names.iter().map(|name| self.dir.join(name)).collect();
+
+    if let Some(status) = status {
+      ensure!(
+        status.success(),
+        "ffmpeg failed to read the stream from the standard input ({status})"
+      );
+    }
+
+    Ok((segments, status.is_some()))
+  }
+
+  fn kill(&mut self) {
+    if let Err(e) = self.ffmpeg.kill() {
+      debug!(
+        "failed to stop the ffmpeg process reading the stream: {}",
+        e
+      );
+    }
+  }
+}
+
+impl Av1anContext {
+  /// Encodes the stream of the standard input while it is read
+  pub(crate) fn encode_stdin(&mut self) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let dir = Path::new(&self.args.temp).join("live");
+    create_dir!(&dir)?;
+
+    status::set_state(State::SceneDetection);
+    let mut segmenter = Segmenter::spawn(&dir)?;
+
+    // the properties of the stream are only known once its first segment is written
+    let mut pending = VecDeque::new();
+    let mut ended = false;
+    while pending.is_empty() && !ended {
+      thread::sleep(POLL_INTERVAL);
+      let (segments, end) = segmenter.poll()?;
+      pending.extend(segments);
+      ended = end;
+    }
+    let Some(first) = pending.front().cloned() else {
+      bail!("The standard input ended before a segment of video could be read from it");
+    };
+
+    let resolution =
+      crate::ffmpeg::resolution(&first).context("Failed to get the resolution of the stream")?;
+    let format = crate::ffmpeg::get_pixel_format(&first)
+      .context("Failed to get the pixel format of the stream")?;
+    self.args.input_pix_format = InputPixelFormat::FFmpeg { format };
+    info!(
+      "Input: {}x{} @ {:.3} fps, {:?}, read from the standard input",
+      resolution.0,
+      resolution.1,
+      crate::ffmpeg::frame_rate(&first).unwrap_or_default(),
+      format
+    );
+
+    if self.args.workers == 0 {
+      let output_resolution = self
+        .args
+        .output_res
+        .or_else(|| filtered_resolution(&self.args.ffmpeg_filter_args, resolution))
+        .unwrap_or(resolution);
+      self.args.workers = determine_workers(
+        self.args.encoder,
+        self.args.chunk_method,
+        &self.args.gpu_devices,
+        output_resolution,
+      ) as usize;
+    }
+
+    eprintln!(
+      "Workers {} Passes {}\nParams: {}",
+      self.args.workers,
+      self.args.passes,
+      self.args.video_params.join(" ")
+    );
+
+    // the length of the progress bar grows with the stream
+    if self.args.verbosity == Verbosity::Normal {
+      init_progress_bar(1, 0);
+    } else if self.args.verbosity == Verbosity::Verbose {
+      init_multi_progress_bar(1, self.args.workers, 0, 0);
+    }
+
+    let done_writer = DoneJsonWriter::spawn(Path::new(&self.args.temp).join("done.json"));
+    status::set_state(State::Encoding);
+
+    let this = &*self;
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let (segments, total_chunks) =
+      crossbeam_utils::thread::scope(|s| -> anyhow::Result<(Vec<PathBuf>, usize)> {
+        let (tx, rx) = mpsc::channel();
+
+        let broker = Broker {
+          chunk_queue: Vec::new(),
+          project: this,
+          done_writer: &done_writer,
+          verify_queue: VerifyQueue::default(),
+          progressive_concat: None,
+          stopped_workers: atomic::AtomicUsize::new(0),
+        };
+        let receiver = &receiver;
+        let handle = s.spawn(move |_| {
+          broker.encode_received(receiver, tx, this.args.set_thread_affinity);
+        });
+
+        let mut segments = Vec::new();
+        let mut chunks = 0;
+        let mut frames = 0;
+        loop {
+          while let Some(segment) = pending.pop_front() {
+            let queued = match this.segment_chunks(&segment, chunks) {
+              Ok(queued) => queued,
+              Err(e) => {
+                segmenter.kill();
+                return Err(e.context(format!("Failed to split segment {}", segment.display())));
+              }
+            };
+            frames += queued.iter().map(Chunk::frames).sum::<usize>();
+            chunks += queued.len();
+            segments.push(segment);
+
+            get_done().frames.store(frames, atomic::Ordering::Relaxed);
+            done_writer.request_save();
+            status::set_totals(frames, chunks);
+            if this.args.verbosity != Verbosity::Quiet {
+              set_len(frames as u64);
+            }
+            for chunk in queued {
+              sender.send(chunk).unwrap();
+            }
+          }
+
+          // Broker::encode_received only sends a message if a chunk failed more than MAX_TRIES
+          if rx.try_recv().is_ok() {
+            segmenter.kill();
+            done_writer.flush();
+            notify::send(
+              &this.args,
+              start.elapsed(),
+              Some("the encoder failed on a chunk, see the log for details".to_owned()),
+            );
+            exit(1);
+          }
+
+          if ended {
+            break;
+          }
+          thread::sleep(POLL_INTERVAL);
+          let (new, end) = segmenter.poll()?;
+          pending.extend(new);
+          ended = end;
+        }
+        debug!(
+          "the standard input ended after {} frames in {} segments",
+          frames,
+          segments.len()
+        );
+
+        // the workers stop once the queued chunks are encoded
+        drop(sender);
+        if rx.recv().is_ok() {
+          done_writer.flush();
+          notify::send(
+            &this.args,
+            start.elapsed(),
+            Some("the encoder failed on a chunk, see the log for details".to_owned()),
+          );
+          exit(1);
+        }
+        handle.join().unwrap();
+
+        Ok((segments, chunks))
+      })
+      .unwrap()?;
+
+    finish_progress_bar();
+    self.report_pass_times();
+    self.report_fallbacks();
+    self.report_energy();
+
+    if !self.args.no_audio && crate::ffmpeg::has_audio(&first) {
+      match join_audio(&dir, &segments) {
+        Ok(audio) => {
+          if let Some(audio_output) =
+            crate::ffmpeg::encode_audio(&audio, &self.args.temp, &self.args.audio_params)
+          {
+            set_audio_size(audio_output.metadata()?.len());
+          }
+        }
+        Err(e) => warn!("Failed to join the audio of the stream: {:#}", e),
+      }
+    }
+
+    done_writer.finish();
+
+    let plan = ConcatPlan {
+      method: self.args.concat,
+      output: PathBuf::from(&self.args.output_file),
+      encode_dir: Path::new(&self.args.temp).join("encode"),
+      extension: self.args.encoder.output_extension().to_owned(),
+      num_files: total_chunks,
+      encoder: self.args.encoder,
+      timestamps: None,
+      dolby_vision: None,
+    };
+    if self.concatenate(&plan)? {
+      self.finish_output(start);
+    }
+
+    Ok(())
+  }
+
+  /// Detects the scenes of a segment of the stream, and returns its chunks,
+  /// numbered from `first_index`
+  fn segment_chunks(&self, segment: &Path, first_index: usize) -> anyhow::Result<Vec<Chunk>> {
+    let input = Input::Video {
+      path: segment.to_path_buf(),
+    };
+    let frames = input.frames()?;
+    if frames == 0 {
+      return Ok(Vec::new());
+    }
+    let frame_rate = input.frame_rate()?;
+
+    let mut scenes = if matches!(self.args.split_method, SplitMethod::None) {
+      vec![Scene {
+        start_frame: 0,
+        end_frame: frames,
+        zone_overrides: None,
+        cut: None,
+      }]
+    } else {
+      scene_detect(
+        &input,
+        self.args.encoder,
+        frames,
+        None,
+        self.args.min_scene_len,
+        &self.args.scaler,
+        self.args.sc_pix_format,
+        self.args.sc_method,
+        self.args.sc_downscale_height,
+        &[],
+      )?
+      .0
+    };
+    if let Some(split_len @ 1..) = self.args.extra_splits_len {
+      scenes = extra_splits(&scenes, frames, split_len);
+    }
+    debug!(
+      "segment {}: {} frames in {} scene(s)",
+      segment.display(),
+      frames,
+      scenes.len()
+    );
+
+    scenes
+      .iter()
+      .enumerate()
+      .map(|(index, scene)| {
+        self.create_select_chunk(
+          first_index + index,
+          segment,
+          scene.start_frame,
+          scene.end_frame,
+          frame_rate,
+          None,
+        )
+      })
+      .collect()
+  }
+}
+
+/// Returns the names of the segments in new data of the segment list, and the
+/// number of bytes read, as ffmpeg adds a line for each segment once it is
+/// written, which may still be incomplete
+fn completed_segments(list: &[u8]) -> (Vec<String>, usize) {
+  let read = list
+    .iter()
+    .rposition(|&b| b == b'\n')
+    .map_or(0, |end| end + 1);
+  let names = String::from_utf8_lossy(&list[..read])
+    .lines()
+    .filter_map(|line| line.split(',').next())
+    .filter(|name| !name.is_empty())
+    .map(ToOwned::to_owned)
+    .collect();
+
+  (names, read)
+}
+
+/// Joins the audio of the segments into one file, which is then encoded like
+/// the audio of a file
+fn join_audio(dir: &Path, segments: &[PathBuf]) -> anyhow::Result<PathBuf> {
+  let mut list = String::new();
+  for segment in segments {
+    // the names of the segments are relative to the list
+    let name = segment.file_name().unwrap().to_string_lossy();
+    writeln!(list, "file '{name}'").unwrap();
+  }
+  let list_path = dir.join("audio.txt");
+  fs::write(&list_path, list)?;
+
+  let audio = dir.join("audio.mka");
+  let mut cmd = Command::new("ffmpeg");
+  cgroup::apply(&mut cmd);
+  cmd
+    .args(["-y", "-hide_banner", "-loglevel", "error"])
+    .args(["-f", "concat", "-safe", "0", "-i"])
+    .arg(&list_path)
+    .args(["-map", "0:a", "-c", "copy"])
+    .arg(&audio);
+  let output = cmd.output().context("Failed to spawn ffmpeg")?;
+  ensure!(
+    output.status.success(),
+    "ffmpeg failed to join the audio of the segments: {}",
+    String::from_utf8_lossy(&output.stderr).trim()
+  );
+
+  Ok(audio)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reads_completed_segments() {
+    let list = b"00000.nut,0.000000,30.030000\n00001.nut,30.030000,60.0";
+    let (names, read) = completed_segments(list);
+    assert_eq!(names, ["00000.nut"]);
+    assert_eq!(read, 29);
+
+    // the rest of the list is read once its line is complete
+    let list = [list.as_slice(), b"60000\n"].concat();
+    let (names, read) = completed_segments(&list[29..]);
+    assert_eq!(names, ["00001.nut"]);
+    assert_eq!(read, list.len() - 29);
+
+    assert_eq!(completed_segments(b""), (Vec::new(), 0));
+  }
+}
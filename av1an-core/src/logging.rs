@@ -1,17 +1,37 @@
 use std::collections::HashMap;
 use std::env;
-use std::io::IsTerminal;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
+use crossbeam_channel::{Receiver, Sender};
 use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tracing::field::{Field, Visit};
+use tracing::{Level, Subscriber};
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
-use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::filter::{filter_fn, LevelFilter};
+use tracing_subscriber::layer::{Context, Layer};
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::{fmt, EnvFilter};
 
 // Store the worker guard globally
 static WORKER_GUARD: OnceCell<WorkerGuard> = OnceCell::new();
 
+// File the structured events are written to, if enabled
+static EVENTS: OnceCell<Mutex<File>> = OnceCell::new();
+
+// Channel the events are published to, e.g. for the terminal dashboard
+static SUBSCRIBER: OnceCell<Sender<Event>> = OnceCell::new();
+
+// Whether logging to the console is suspended, while the terminal is drawn by
+// something else
+static CONSOLE_MUTED: AtomicBool = AtomicBool::new(false);
+
 // Define our module configuration structure
 #[derive(Debug, Clone)]
 struct ModuleConfig {
@@ -123,7 +143,8 @@ pub fn init_logging() {
         // Set the writer
         .with_writer(std::io::stdout)
         // Apply the filter last
-        .with_filter(console_filter),
+        .with_filter(console_filter)
+        .with_filter(filter_fn(|_| !CONSOLE_MUTED.load(Ordering::Relaxed))),
     )
     // File output layer
     .with(
@@ -140,7 +161,11 @@ pub fn init_logging() {
         .with_writer(non_blocking)
         // Apply the filter last
         .with_filter(file_filter),
-    );
+    )
+    // Log lines of av1an for the subscriber of the events, if any
+    .with(PublishLayer.with_filter(filter_fn(|metadata| {
+      *metadata.level() <= Level::INFO && metadata.target().starts_with("av1an")
+    })));
 
   // Set as global default
   tracing::subscriber::set_global_default(subscriber)
@@ -150,3 +175,209 @@ pub fn init_logging() {
   tracing::info!("Logging system initialized");
   tracing::debug!("Module-specific logging enabled");
 }
+
+/// Structured event of an encode, written as a line of JSON to the events
+/// file, e.g. for dashboards
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+  EncodeStarted {
+    input: PathBuf,
+    output: PathBuf,
+    frames: usize,
+    /// Frames encoded by a previous run, when resuming
+    done_frames: usize,
+    chunks: usize,
+    workers: usize,
+  },
+  ChunkStarted {
+    chunk: usize,
+    frames: usize,
+    worker: usize,
+  },
+  ChunkFinished {
+    chunk: usize,
+    frames: usize,
+    worker: usize,
+    seconds: f64,
+    fps: f64,
+    size_bytes: u64,
+  },
+  /// A probe of target quality, the score is the aggregated VMAF of the probe
+  Probe {
+    chunk: usize,
+    q: u32,
+    score: f64,
+    /// Aggregated SSIMULACRA2 of the probe, with a SSIMULACRA2 target
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ssimu2: Option<f64>,
+    cached: bool,
+  },
+  /// The Q chosen by target quality for a chunk
+  TargetQuality {
+    chunk: usize,
+    q: u32,
+  },
+  /// The encoder of a chunk failed, `will_retry` is false when the worker
+  /// shuts down
+  WorkerCrash {
+    chunk: usize,
+    worker: usize,
+    attempt: usize,
+    will_retry: bool,
+    exit_status: String,
+    /// Kind of the failure, see `broker::FailureKind`
+    failure: String,
+    error: String,
+  },
+  EncodeFinished {
+    output: PathBuf,
+    seconds: f64,
+  },
+  /// Frames of a chunk encoded so far, only published to the subscriber as it
+  /// is too frequent for the events file
+  ChunkProgress {
+    chunk: usize,
+    worker: usize,
+    frames: u64,
+  },
+  /// A line of the log, only published to the subscriber
+  Log {
+    level: String,
+    message: String,
+  },
+}
+
+/// Publishes the log lines to the subscriber of the events
+struct PublishLayer;
+
+/// Formats the fields of a log line like the console does
+#[derive(Default)]
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+  fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+    if !self.0.is_empty() {
+      self.0.push(' ');
+    }
+    if field.name() == "message" {
+      write!(self.0, "{value:?}").unwrap();
+    } else {
+      write!(self.0, "{}={value:?}", field.name()).unwrap();
+    }
+  }
+}
+
+impl<S: Subscriber> Layer<S> for PublishLayer {
+  fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+    if SUBSCRIBER.get().is_none() {
+      return;
+    }
+    let mut visitor = MessageVisitor::default();
+    event.record(&mut visitor);
+    publish(Event::Log {
+      level: event.metadata().level().to_string(),
+      message: visitor.0,
+    });
+  }
+}
+
+/// Publishes the events to the returned channel from now on. There can only be
+/// one subscriber.
+pub fn subscribe() -> Receiver<Event> {
+  let (sender, receiver) = crossbeam_channel::unbounded();
+  assert!(
+    SUBSCRIBER.set(sender).is_ok(),
+    "the events already have a subscriber"
+  );
+  receiver
+}
+
+/// Suspends logging to the console, e.g. while the terminal shows a dashboard
+pub fn mute_console(muted: bool) {
+  CONSOLE_MUTED.store(muted, Ordering::Relaxed);
+}
+
+fn publish(event: Event) {
+  if let Some(sender) = SUBSCRIBER.get() {
+    // the subscriber may have stopped listening, which is fine
+    sender.send(event).ok();
+  }
+}
+
+/// Publishes the progress of a chunk to the subscriber, if any
+pub fn chunk_progress(chunk: usize, worker: usize, frames: u64) {
+  publish(Event::ChunkProgress {
+    chunk,
+    worker,
+    frames,
+  });
+}
+
+#[derive(Serialize)]
+struct EventLine<'a> {
+  time: String,
+  #[serde(flatten)]
+  event: &'a Event,
+}
+
+/// Writes the structured events to this file, appending to it if it exists
+pub fn init_events(path: &Path) -> io::Result<()> {
+  let file = OpenOptions::new().create(true).append(true).open(path)?;
+  // events of several inputs of a batch are written to the same file
+  if EVENTS.set(Mutex::new(file)).is_err() {
+    tracing::warn!(
+      "the events file was already set, ignoring {}",
+      path.display()
+    );
+  }
+  Ok(())
+}
+
+/// Writes an event to the events file if enabled, and publishes it to the
+/// subscriber if any
+pub fn event(event: &Event) {
+  publish(event.clone());
+  let Some(file) = EVENTS.get() else {
+    return;
+  };
+
+  let line = EventLine {
+    time: chrono::Local::now().to_rfc3339(),
+    event,
+  };
+  // serializing the events should never fail, so unwrap is OK
+  let mut line = serde_json::to_string(&line).unwrap();
+  line.push('\n');
+  // a single write per line, so that readers never see a partial line before it is flushed
+  let result = file.lock().write_all(line.as_bytes());
+  if let Err(e) = result {
+    tracing::warn!("failed to write to the events file: {}", e);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn event_lines() {
+    let event = Event::ChunkFinished {
+      chunk: 3,
+      frames: 120,
+      worker: 1,
+      seconds: 4.0,
+      fps: 30.0,
+      size_bytes: 1024,
+    };
+    let line = serde_json::to_value(EventLine {
+      time: "2024-01-01T00:00:00+00:00".to_owned(),
+      event: &event,
+    })
+    .unwrap();
+    assert_eq!(line["event"], "chunk-finished");
+    assert_eq!(line["chunk"], 3);
+    assert_eq!(line["fps"], 30.0);
+    assert_eq!(line["time"], "2024-01-01T00:00:00+00:00");
+  }
+}
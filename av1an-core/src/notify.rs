@@ -0,0 +1,219 @@
+//! Notifications when an encode finishes or fails.
+//!
+//! A JSON summary of the encode is posted to `--notify-url` with curl, and
+//! `--notify-desktop` shows a desktop notification with notify-send on Linux
+//! and osascript on macOS. Failing to notify is only logged, as the encode
+//! itself is not affected.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use serde::Serialize;
+
+use crate::settings::EncodeArgs;
+use crate::vmaf::read_vmaf_file;
+
+/// Seconds after which posting the summary is given up
+const TIMEOUT_SECS: u32 = 30;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+  Finished,
+  Failed,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Summary {
+  pub status: Status,
+  pub input: PathBuf,
+  pub output: PathBuf,
+  pub seconds: f64,
+  /// Size of the output, if it exists
+  pub size_bytes: Option<u64>,
+  /// Mean VMAF of the output, if it was computed with `--vmaf`
+  pub mean_vmaf: Option<f64>,
+  pub error: Option<String>,
+}
+
+impl Summary {
+  pub fn new(args: &EncodeArgs, elapsed: Duration, error: Option<String>) -> Self {
+    let output = PathBuf::from(&args.output_file);
+    let size_bytes = output.metadata().ok().map(|metadata| metadata.len());
+    let mean_vmaf = if args.vmaf && error.is_none() {
+      mean_vmaf(&output)
+    } else {
+      None
+    };
+
+    Self {
+      status: if error.is_some() {
+        Status::Failed
+      } else {
+        Status::Finished
+      },
+      input: args.input.as_path().to_path_buf(),
+      output,
+      seconds: elapsed.as_secs_f64(),
+      size_bytes,
+      mean_vmaf,
+      error,
+    }
+  }
+
+  fn message(&self) -> String {
+    let name = self
+      .input
+      .file_name()
+      .unwrap_or(self.input.as_os_str())
+      .to_string_lossy();
+    match self.status {
+      Status::Finished => format!(
+        "{name} was encoded in {:.0}s{}",
+        self.seconds,
+        self
+          .mean_vmaf
+          .map(|vmaf| format!(", mean VMAF {vmaf:.2}"))
+          .unwrap_or_default()
+      ),
+      Status::Failed => format!(
+        "{name} failed to encode: {}",
+        self.error.as_deref().unwrap_or_default()
+      ),
+    }
+  }
+}
+
+/// Reads the mean of the scores of the VMAF file written by `--vmaf` next to
+/// the output
+fn mean_vmaf(output: &Path) -> Option<f64> {
+  let scores = read_vmaf_file(output.with_extension("json")).ok()?;
+  if scores.is_empty() {
+    return None;
+  }
+  Some(scores.iter().sum::<f64>() / scores.len() as f64)
+}
+
+fn post(url: &str, summary: &Summary) -> anyhow::Result<()> {
+  // serializing the summary should never fail, so unwrap is OK
+  let body = serde_json::to_string(summary).unwrap();
+  let mut child = Command::new("curl")
+    .args(["-fsS", "-X", "POST", "-H", "Content-Type: application/json"])
+    .args(["--max-time", &TIMEOUT_SECS.to_string()])
+    .args(["--data-binary", "@-", url])
+    .stdin(Stdio::piped())
+    .stdout(Stdio::null())
+    .stderr(Stdio::piped())
+    .spawn()
+    .context("Failed to run curl, which is needed for --notify-url")?;
+  child.stdin.take().unwrap().write_all(body.as_bytes())?;
+  let output = child.wait_with_output()?;
+  if !output.status.success() {
+    bail!(
+      "curl failed ({}): {}",
+      output.status,
+      String::from_utf8_lossy(&output.stderr).trim()
+    );
+  }
+
+  Ok(())
+}
+
+fn desktop(summary: &Summary) -> anyhow::Result<()> {
+  let title = match summary.status {
+    Status::Finished => "Av1an: encode finished",
+    Status::Failed => "Av1an: encode failed",
+  };
+  let message = summary.message();
+
+  let status = if cfg!(target_os = "macos") {
+    Command::new("osascript")
+      .arg("-e")
+      .arg(format!(
+        "display notification {} with title {}",
+        applescript_string(&message),
+        applescript_string(title)
+      ))
+      .status()
+  } else if cfg!(windows) {
+    bail!("desktop notifications are not supported on Windows");
+  } else {
+    Command::new("notify-send").args([title, &message]).status()
+  }
+  .context("Failed to show a desktop notification")?;
+  if !status.success() {
+    bail!("Failed to show a desktop notification ({status})");
+  }
+
+  Ok(())
+}
+
+/// Quotes a string for AppleScript, which only knows the escapes of
+/// backslashes, quotes and a few whitespace characters
+fn applescript_string(string: &str) -> String {
+  let mut quoted = String::with_capacity(string.len() + 2);
+  quoted.push('"');
+  for c in string.chars() {
+    match c {
+      '\\' => quoted.push_str("\\\\"),
+      '"' => quoted.push_str("\\\""),
+      '\n' => quoted.push_str("\\n"),
+      '\r' => quoted.push_str("\\r"),
+      '\t' => quoted.push_str("\\t"),
+      c => quoted.push(c),
+    }
+  }
+  quoted.push('"');
+  quoted
+}
+
+/// Sends the notifications enabled in the settings about the end of the
+/// encode, with the error if it failed
+pub fn send(args: &EncodeArgs, elapsed: Duration, error: Option<String>) {
+  if args.notify_url.is_none() && !args.notify_desktop {
+    return;
+  }
+
+  let summary = Summary::new(args, elapsed, error);
+  if let Some(url) = &args.notify_url {
+    if let Err(e) = post(url, &summary) {
+      warn!("Failed to post the summary of the encode: {:#}", e);
+    }
+  }
+  if args.notify_desktop {
+    if let Err(e) = desktop(&summary) {
+      warn!("{:#}", e);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn notification_summary() {
+    let mut args = crate::scenes::get_test_args().args;
+    args.output_file = "/nonexistent/output.mkv".to_owned();
+    let summary = Summary::new(&args, Duration::from_secs(90), None);
+    assert_eq!(summary.status, Status::Finished);
+    assert_eq!(summary.size_bytes, None);
+
+    let json = serde_json::to_value(&summary).unwrap();
+    assert_eq!(json["status"], "finished");
+    assert_eq!(json["seconds"], 90.0);
+    assert_eq!(json["output"], "/nonexistent/output.mkv");
+
+    let summary = Summary::new(&args, Duration::from_secs(1), Some("crash".to_owned()));
+    assert_eq!(summary.status, Status::Failed);
+    assert!(summary.message().ends_with("failed to encode: crash"));
+
+    assert_eq!(
+      applescript_string("C:\\out \"final\".mkv\nü"),
+      r#""C:\\out \"final\".mkv\nü""#
+    );
+  }
+}
@@ -0,0 +1,164 @@
+use std::fs;
+use std::path::Path;
+use std::thread::available_parallelism;
+
+use once_cell::sync::OnceCell;
+use smallvec::SmallVec;
+use tracing::debug;
+
+static TOPOLOGY: OnceCell<NumaTopology> = OnceCell::new();
+
+/// CPUs grouped by the NUMA node they belong to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NumaTopology {
+  pub nodes: Vec<Vec<usize>>,
+}
+
+impl NumaTopology {
+  /// Detects the NUMA topology of the system, falling back to a single node
+  /// containing every available thread if it cannot be determined.
+  #[must_use]
+  pub fn detect() -> Self {
+    let nodes = Self::detect_nodes().unwrap_or_default();
+
+    if nodes.is_empty() {
+      let threads = available_parallelism().map_or(1, std::num::NonZeroUsize::get);
+      Self {
+        nodes: vec![(0..threads).collect()],
+      }
+    } else {
+      Self { nodes }
+    }
+  }
+
+  #[cfg(target_os = "linux")]
+  fn detect_nodes() -> Option<Vec<Vec<usize>>> {
+    let mut nodes: Vec<(usize, Vec<usize>)> = fs::read_dir("/sys/devices/system/node")
+      .ok()?
+      .filter_map(Result::ok)
+      .filter_map(|entry| {
+        let id = entry
+          .file_name()
+          .to_str()?
+          .strip_prefix("node")?
+          .parse::<usize>()
+          .ok()?;
+        let cpus = parse_cpu_list(&read_trimmed(&entry.path().join("cpulist"))?)?;
+        (!cpus.is_empty()).then_some((id, cpus))
+      })
+      .collect();
+    nodes.sort_unstable_by_key(|(id, _)| *id);
+
+    Some(nodes.into_iter().map(|(_, cpus)| cpus).collect())
+  }
+
+  #[cfg(not(target_os = "linux"))]
+  fn detect_nodes() -> Option<Vec<Vec<usize>>> {
+    None
+  }
+
+  /// Returns the set of CPUs a worker should be pinned to.
+  ///
+  /// Workers are distributed round-robin across the NUMA nodes, and each
+  /// worker's threads are taken from a single node. If a node is too small
+  /// to hold `threads` CPUs, the CPUs are instead assigned contiguously
+  /// across all nodes.
+  #[must_use]
+  pub fn cpu_set_for_worker(&self, worker_id: usize, threads: usize) -> SmallVec<[usize; 16]> {
+    let mut cpu_set = SmallVec::new();
+
+    if self.nodes.iter().all(|node| node.len() >= threads) {
+      let node = &self.nodes[worker_id % self.nodes.len()];
+      let slot = worker_id / self.nodes.len();
+      let start = (slot * threads) % node.len();
+      cpu_set.extend((start..start + threads).map(|i| node[i % node.len()]));
+    } else {
+      let cpus: Vec<usize> = self.nodes.iter().flatten().copied().collect();
+      let start = (threads * worker_id) % cpus.len();
+      cpu_set.extend((start..start + threads).map(|i| cpus[i % cpus.len()]));
+    }
+
+    cpu_set
+  }
+}
+
+/// Returns the NUMA topology of the system, detecting it on first use.
+pub fn topology() -> &'static NumaTopology {
+  TOPOLOGY.get_or_init(|| {
+    let topology = NumaTopology::detect();
+    debug!("detected {} NUMA node(s)", topology.nodes.len());
+    topology
+  })
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn read_trimmed(path: &Path) -> Option<String> {
+  fs::read_to_string(path).ok().map(|s| s.trim().to_owned())
+}
+
+/// Parses a Linux CPU list, such as `0-3,8-11,16`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+  let mut cpus = Vec::new();
+
+  for range in list.split(',').filter(|range| !range.is_empty()) {
+    if let Some((start, end)) = range.split_once('-') {
+      cpus.extend(start.parse::<usize>().ok()?..=end.parse::<usize>().ok()?);
+    } else {
+      cpus.push(range.parse().ok()?);
+    }
+  }
+
+  Some(cpus)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn cpu_list() {
+    assert_eq!(
+      parse_cpu_list("0-3,8-11,16"),
+      Some(vec![0, 1, 2, 3, 8, 9, 10, 11, 16])
+    );
+    assert_eq!(parse_cpu_list("5"), Some(vec![5]));
+    assert_eq!(parse_cpu_list(""), Some(vec![]));
+    assert_eq!(parse_cpu_list("0-a"), None);
+  }
+
+  #[test]
+  fn workers_stay_on_one_node() {
+    let topology = NumaTopology {
+      nodes: vec![(0..8).collect(), (8..16).collect()],
+    };
+
+    assert_eq!(topology.cpu_set_for_worker(0, 4).as_slice(), &[0, 1, 2, 3]);
+    assert_eq!(
+      topology.cpu_set_for_worker(1, 4).as_slice(),
+      &[8, 9, 10, 11]
+    );
+    assert_eq!(topology.cpu_set_for_worker(2, 4).as_slice(), &[4, 5, 6, 7]);
+    assert_eq!(
+      topology.cpu_set_for_worker(3, 4).as_slice(),
+      &[12, 13, 14, 15]
+    );
+    assert_eq!(topology.cpu_set_for_worker(4, 4).as_slice(), &[0, 1, 2, 3]);
+  }
+
+  #[test]
+  fn workers_span_nodes_when_too_large() {
+    let topology = NumaTopology {
+      nodes: vec![(0..4).collect(), (4..8).collect()],
+    };
+
+    assert_eq!(
+      topology.cpu_set_for_worker(0, 6).as_slice(),
+      &[0, 1, 2, 3, 4, 5]
+    );
+    assert_eq!(
+      topology.cpu_set_for_worker(1, 6).as_slice(),
+      &[6, 7, 0, 1, 2, 3]
+    );
+  }
+}
@@ -5,7 +5,7 @@
 //! set is available before calling them.
 
 use std::borrow::Cow;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use crate::encoder::Encoder;
 
@@ -214,12 +214,19 @@ pub fn parse_rav1e_frames(s: &str) -> Option<u64> {
 
 pub fn parse_svt_av1_frames(s: &str) -> Option<u64> {
   const SVT_AV1_IGNORED_PREFIX: &str = "Encoding frame";
-
-  if !s.starts_with(SVT_AV1_IGNORED_PREFIX) {
+  // printed instead with `--progress 2` by newer versions of SVT-AV1:
+  // Encoding:   120 Frames @ 25.43 fps | 2106.05 kbps | Time: 0:00:04 [-0:00:00] | ...
+  const SVT_AV1_VERBOSE_PREFIX: &str = "Encoding:";
+
+  let prefix_len = if s.starts_with(SVT_AV1_IGNORED_PREFIX) {
+    SVT_AV1_IGNORED_PREFIX.len()
+  } else if s.starts_with(SVT_AV1_VERBOSE_PREFIX) {
+    SVT_AV1_VERBOSE_PREFIX.len()
+  } else {
     return None;
-  }
+  };
 
-  s.get(SVT_AV1_IGNORED_PREFIX.len()..)?
+  s.get(prefix_len..)?
     .split_ascii_whitespace()
     .next()
     .and_then(|s| s.parse().ok())
@@ -299,10 +306,152 @@ pub fn valid_params(help_text: &str, encoder: Encoder) -> HashSet<Cow<'_, str>>
   params
 }
 
+/// Returns the names of the options defined at the start of a line of a help
+/// text, e.g. `-p` and `--preset` for `-p/--preset <string>`
+fn option_names(line: &str) -> Vec<String> {
+  line
+    .split(|c: char| c.is_whitespace() || c == ',' || c == '/')
+    .filter(|s| !s.is_empty())
+    // the value of the option may come before another name, e.g. `-b <arg>, --bit-depth=<arg>`
+    .take_while(|s| s.starts_with('-') || s.starts_with('<'))
+    .filter(|s| s.starts_with('-'))
+    .filter_map(|s| {
+      let end = s
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '-' && c != '_')
+        .unwrap_or(s.len());
+      let name = &s[..end];
+      (name.len() > 1 && name != "--").then(|| name.to_owned())
+    })
+    .collect()
+}
+
+/// Parses a line listing the values of an option, e.g. `vbr, cbr, cq, q`,
+/// `- ultrafast,superfast,veryfast,faster,fast` or `- psy tunings: film,grain`
+fn value_list(line: &str) -> Option<Vec<String>> {
+  let line = line.strip_prefix("- ").unwrap_or(line);
+  let line = line.split_once(':').map_or(line, |(_, list)| list);
+  let values: Vec<String> = line
+    .split(',')
+    .map(str::trim)
+    .filter(|value| !value.is_empty())
+    .map(|value| value.strip_prefix("or ").unwrap_or(value).to_owned())
+    .collect();
+  let is_name = |value: &String| {
+    value
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+  };
+  (values.len() >= 2 && values.iter().all(is_name)).then_some(values)
+}
+
+/// Returns the values accepted by the options of an encoder that take one of
+/// a list of names, given its help text. Options whose values are not listed
+/// in a known format are left out, and accept any value.
+#[must_use]
+pub fn param_values(help_text: &str) -> HashMap<String, Vec<String>> {
+  const POSSIBLE_VALUES: &str = "[possible values:";
+
+  let mut values: HashMap<String, Vec<String>> = HashMap::new();
+  // options of the last definition that takes a value
+  let mut current = Vec::new();
+  // list of possible values (of clap) that continues on the next line
+  let mut possible: Option<String> = None;
+  // values of a list that continues on the next line
+  let mut pending: Vec<String> = Vec::new();
+  // whether the line continues a list that is not only made of names, which
+  // would only give part of the values
+  let mut partial = false;
+
+  for line in help_text.lines() {
+    let trimmed = line.trim();
+    let continues = trimmed.ends_with(',');
+    let definition = trimmed.starts_with('-') && !trimmed.starts_with("- ");
+    if definition || trimmed.is_empty() {
+      current = if definition && trimmed.contains('<') {
+        option_names(trimmed)
+      } else {
+        Vec::new()
+      };
+      possible = None;
+      pending.clear();
+    }
+    if current.is_empty() || std::mem::replace(&mut partial, continues) {
+      pending.clear();
+      continue;
+    }
+
+    let list = possible.take().map_or_else(
+      || {
+        trimmed
+          .find(POSSIBLE_VALUES)
+          .map(|index| trimmed[index + POSSIBLE_VALUES.len()..].to_owned())
+      },
+      |start| Some(format!("{start} {trimmed}")),
+    );
+    let found = if let Some(list) = list {
+      partial = false;
+      let Some((list, _)) = list.split_once(']') else {
+        possible = Some(list);
+        continue;
+      };
+      value_list(list)
+    } else if definition {
+      None
+    } else {
+      value_list(trimmed)
+    };
+
+    let Some(found) = found else {
+      pending.clear();
+      continue;
+    };
+    partial = false;
+    pending.extend(found);
+    if continues {
+      continue;
+    }
+    for name in &current {
+      let entry = values.entry(name.clone()).or_default();
+      for value in &pending {
+        if !entry.contains(value) {
+          entry.push(value.clone());
+        }
+      }
+    }
+    pending.clear();
+  }
+
+  values
+}
+
 #[cfg(test)]
 mod tests {
   use crate::parse::*;
 
+  #[test]
+  fn param_values_works() {
+    let aom = param_values(include_str!("../tests/aom_help.txt"));
+    assert_eq!(aom["--end-usage"], ["vbr", "cbr", "cq", "q"]);
+    assert!(aom["--tune"].contains(&"vmaf_neg".to_owned()));
+    assert!(!aom.contains_key("--cq-level"));
+    assert_eq!(aom["--bit-depth"], aom["-b"]);
+
+    let x264 = param_values(include_str!("../tests/x264_help.txt"));
+    assert_eq!(x264["--preset"].len(), 10);
+    assert!(x264["--tune"].contains(&"stillimage".to_owned()));
+    assert!(x264["--tune"].contains(&"zerolatency".to_owned()));
+
+    let x265 = param_values(include_str!("../tests/x265_help.txt"));
+    assert!(x265["--preset"].contains(&"placebo".to_owned()));
+    assert_eq!(x265["-p"], x265["--preset"]);
+
+    let rav1e = param_values(include_str!("../tests/rav1e_help.txt"));
+    assert_eq!(rav1e["--tune"], ["Psnr", "Psychovisual"]);
+    assert!(rav1e["--primaries"].len() > 2);
+
+    assert!(!param_values(include_str!("../tests/svt_av1_help.txt")).contains_key("--preset"));
+  }
+
   #[test]
   #[allow(clippy::cognitive_complexity)]
   fn valid_params_works() {
@@ -404,6 +553,10 @@ mod tests {
         "Encoding frame 53298734 1.08 kbps 2.00 fps",
         Some(53_298_734),
       ),
+      (
+        "Encoding:   120 Frames @ 25.43 fps | 2106.05 kbps | Time: 0:00:04 [-0:00:00]",
+        Some(120),
+      ),
       ("invalid input", None),
       ("", None),
     ];
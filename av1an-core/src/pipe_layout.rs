@@ -0,0 +1,205 @@
+//! Choice between piping the source directly into the encoder, and piping it
+//! through ffmpeg first.
+//!
+//! The ffmpeg process is only needed to filter the frames or to convert their
+//! pixel format. When neither is needed, it is redundant, but it still runs in
+//! parallel to the source and buffers its output, which is sometimes faster
+//! than the source alone (e.g. with vspipe, which only outputs a frame once the
+//! previous one is consumed). Both layouts are timed on the first frames of a
+//! chunk before encoding, and the faster one is used for the whole encode.
+
+use std::io::{self, Read};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use ffmpeg::format::Pixel;
+
+use crate::chunk::Chunk;
+use crate::ffmpeg::compose_ffmpeg_pipe;
+use crate::{cgroup, Input, PipeMode};
+
+/// Number of frames each layout is timed on
+const FRAMES: usize = 100;
+
+/// How much faster piping through ffmpeg has to be to use it, as it needs an
+/// additional process per worker
+const FFMPEG_MARGIN: f64 = 0.9;
+
+/// Returns whether to pipe through ffmpeg when it is not needed
+///
+/// With `--pipe-mode auto`, this is benchmarked, and the source is piped
+/// directly if the benchmark fails.
+pub fn redundant_ffmpeg_pipe(
+  pipe_mode: PipeMode,
+  needs_ffmpeg_pipe: bool,
+  benchmark: impl FnOnce() -> anyhow::Result<bool>,
+) -> bool {
+  match pipe_mode {
+    PipeMode::FFmpeg => true,
+    PipeMode::Auto if !needs_ffmpeg_pipe => benchmark().unwrap_or_else(|e| {
+      warn!(
+        "failed to benchmark the pipe layouts, piping directly: {:#}",
+        e
+      );
+      false
+    }),
+    PipeMode::Auto | PipeMode::VsOnly => false,
+  }
+}
+
+/// Times both pipe layouts on the first frames of the chunk, and returns
+/// whether piping through ffmpeg is faster
+pub fn ffmpeg_is_faster(chunk: &Chunk, pix_format: Pixel) -> anyhow::Result<bool> {
+  let ffmpeg_pipe = compose_ffmpeg_pipe(Vec::<String>::new(), pix_format);
+
+  // alternating between the layouts evens out the effect of the file cache
+  let mut direct = Duration::MAX;
+  let mut through_ffmpeg = Duration::MAX;
+  for _ in 0..2 {
+    direct = direct.min(time_pipe(chunk, None)?);
+    through_ffmpeg = through_ffmpeg.min(time_pipe(chunk, Some(&ffmpeg_pipe))?);
+  }
+
+  let faster = is_faster(through_ffmpeg, direct);
+  info!(
+    "pipe layout: {} (source -> encoder: {:.2?}, source -> ffmpeg -> encoder: {:.2?} for {} frames)",
+    if faster {
+      "source -> ffmpeg -> encoder"
+    } else {
+      "source -> encoder"
+    },
+    direct,
+    through_ffmpeg,
+    FRAMES
+  );
+
+  Ok(faster)
+}
+
+/// Whether piping through ffmpeg is faster by the margin it needs
+fn is_faster(through_ffmpeg: Duration, direct: Duration) -> bool {
+  through_ffmpeg.as_secs_f64() < direct.as_secs_f64() * FFMPEG_MARGIN
+}
+
+/// Returns the time it takes to read the first frames of the chunk from the
+/// pipe, optionally through ffmpeg
+fn time_pipe(chunk: &Chunk, ffmpeg_pipe: Option<&[String]>) -> anyhow::Result<Duration> {
+  let [source, source_args @ ..] = &*chunk.source_cmd else {
+    unreachable!()
+  };
+  let mut command = Command::new(source);
+  if let Input::VapourSynth { vspipe_args, .. } = &chunk.input {
+    for arg in vspipe_args {
+      command.args(["-a", arg]);
+    }
+  }
+  cgroup::apply(&mut command);
+
+  let start = Instant::now();
+  let mut source_pipe = command
+    .args(source_args)
+    .stdin(Stdio::null())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::null())
+    .spawn()
+    .context("Failed to spawn the source pipe")?;
+  let mut children = Vec::with_capacity(2);
+
+  let stdout = if let Some([ffmpeg, args @ ..]) = ffmpeg_pipe {
+    let mut command = Command::new(ffmpeg);
+    cgroup::apply(&mut command);
+    let mut ffmpeg_pipe = command
+      .args(args)
+      .stdin(source_pipe.stdout.take().unwrap())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::null())
+      .spawn()
+      .context("Failed to spawn ffmpeg")?;
+    let stdout = ffmpeg_pipe.stdout.take().unwrap();
+    children.push(ffmpeg_pipe);
+    stdout
+  } else {
+    source_pipe.stdout.take().unwrap()
+  };
+  children.push(source_pipe);
+
+  let result = read_frames(stdout, FRAMES.min(chunk.frames()));
+  let elapsed = start.elapsed();
+  for mut child in children {
+    stop(&mut child);
+  }
+  result.context("Failed to read the frames of the pipe")?;
+
+  Ok(elapsed)
+}
+
+/// Reads the given number of frames of y4m, or until the end of the stream
+fn read_frames(pipe: impl Read, frames: usize) -> io::Result<()> {
+  let mut decoder = y4m::decode(pipe).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+  for _ in 0..frames {
+    match decoder.read_frame() {
+      Ok(_) => {}
+      Err(y4m::Error::EOF) => break,
+      Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+  }
+
+  Ok(())
+}
+
+fn stop(child: &mut Child) {
+  // the pipe was only read partially, so the process is killed rather than awaited
+  child.kill().ok();
+  child.wait().ok();
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// y4m stream of 2x2 frames
+  fn y4m(frames: usize) -> Vec<u8> {
+    let mut stream = b"YUV4MPEG2 W2 H2 F25:1 Ip A1:1 C420jpeg\n".to_vec();
+    for _ in 0..frames {
+      stream.extend(b"FRAME\n");
+      stream.extend([0; 6]);
+    }
+    stream
+  }
+
+  #[test]
+  fn layout_choice() {
+    let failed = || -> anyhow::Result<bool> { anyhow::bail!("no chunk") };
+    assert!(redundant_ffmpeg_pipe(PipeMode::FFmpeg, false, failed));
+    assert!(redundant_ffmpeg_pipe(PipeMode::FFmpeg, true, failed));
+    assert!(redundant_ffmpeg_pipe(PipeMode::Auto, false, || Ok(true)));
+    assert!(!redundant_ffmpeg_pipe(PipeMode::Auto, false, || Ok(false)));
+    assert!(!redundant_ffmpeg_pipe(PipeMode::Auto, false, failed));
+    // ffmpeg is part of the pipe anyway, so nothing is benchmarked
+    assert!(!redundant_ffmpeg_pipe(
+      PipeMode::Auto,
+      true,
+      || unreachable!()
+    ));
+    assert!(!redundant_ffmpeg_pipe(
+      PipeMode::VsOnly,
+      false,
+      || unreachable!()
+    ));
+
+    let second = Duration::from_secs(1);
+    assert!(is_faster(second / 2, second));
+    // within the margin, the additional process is not worth it
+    assert!(!is_faster(second * 95 / 100, second));
+    assert!(!is_faster(second * 2, second));
+  }
+
+  #[test]
+  fn read_pipe_frames() {
+    assert!(read_frames(&y4m(3)[..], 3).is_ok());
+    // the chunk may end before the frames that are timed
+    assert!(read_frames(&y4m(3)[..], 100).is_ok());
+    assert!(read_frames(&b"not y4m"[..], 1).is_err());
+  }
+}
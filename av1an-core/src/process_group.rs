@@ -0,0 +1,324 @@
+//! Termination of the processes of a chunk pipeline together.
+//!
+//! The source, ffmpeg and encoder processes of a chunk pipeline are put in a
+//! process group on Unix, or a Job Object on Windows, which is killed once the
+//! pipeline is done or when the worker running it panics, so no vspipe, ffmpeg
+//! or encoder process is left behind. On Linux, the processes are also killed
+//! if av1an itself is killed, and on Windows the Job Object kills them when its
+//! last handle is closed, which includes av1an exiting for any reason.
+//!
+//! As the process groups are separate from the one of av1an, they do not
+//! receive the Ctrl+C of the terminal, so the signals that terminate av1an are
+//! forwarded to them.
+
+/// The processes of a chunk pipeline, which are killed when this is dropped
+///
+/// On Unix, the first process leads the group, and must not be waited for
+/// before this is dropped, as the ID of the group is only known to be still
+/// in use while its leader is not reaped.
+#[derive(Debug)]
+pub struct ProcessGroup(imp::Group);
+
+impl ProcessGroup {
+  pub fn new() -> Self {
+    Self(imp::Group::new())
+  }
+
+  /// Prepares a command of the pipeline to join the group, before it is spawned
+  pub fn add(&self, command: &mut tokio::process::Command) {
+    self.0.add(command);
+  }
+
+  /// Adds a spawned process of the pipeline to the group
+  pub fn register(&self, child: &tokio::process::Child) {
+    self.0.register(child);
+  }
+}
+
+/// Interrupts the running chunk pipelines like Ctrl+C in the terminal, without
+/// signaling any other process
+pub fn interrupt_all() {
+  imp::interrupt_all();
+}
+
+impl Default for ProcessGroup {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(unix)]
+mod imp {
+  use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+  use std::sync::Once;
+
+  /// Maximum number of process groups the termination signals are forwarded to
+  const MAX_GROUPS: usize = 256;
+
+  /// Process groups of the running pipelines, 0 for free slots
+  static GROUPS: [AtomicI32; MAX_GROUPS] = [const { AtomicI32::new(0) }; MAX_GROUPS];
+
+  static FORWARD_SIGNALS: Once = Once::new();
+
+  const FORWARDED: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+  /// Sends a signal to the process groups of the running pipelines, which are
+  /// not signaled again afterwards
+  fn signal_groups(signal: libc::c_int) {
+    for group in &GROUPS {
+      let pgid = group.swap(0, Ordering::SeqCst);
+      if pgid > 0 {
+        // SAFETY: killpg is async-signal-safe
+        unsafe {
+          libc::killpg(pgid, signal);
+        }
+      }
+    }
+  }
+
+  pub fn interrupt_all() {
+    signal_groups(libc::SIGINT);
+  }
+
+  extern "C" fn forward(signal: libc::c_int) {
+    signal_groups(signal);
+    // SAFETY: only async-signal-safe functions are called
+    unsafe {
+      // terminates av1an with the default action of the signal, as without the handler
+      libc::signal(signal, libc::SIG_DFL);
+      libc::raise(signal);
+    }
+  }
+
+  /// Forwards the termination signals to the process groups, unless they are
+  /// ignored or handled otherwise (e.g. with `nohup`)
+  fn forward_signals() {
+    FORWARD_SIGNALS.call_once(|| {
+      for signal in FORWARDED {
+        // SAFETY: the handler only calls async-signal-safe functions
+        unsafe {
+          let mut previous: libc::sigaction = std::mem::zeroed();
+          if libc::sigaction(signal, std::ptr::null(), &mut previous) != 0
+            || previous.sa_sigaction != libc::SIG_DFL
+          {
+            continue;
+          }
+          let mut action: libc::sigaction = std::mem::zeroed();
+          action.sa_sigaction = forward as extern "C" fn(libc::c_int) as libc::sighandler_t;
+          libc::sigemptyset(&mut action.sa_mask);
+          libc::sigaction(signal, &action, std::ptr::null_mut());
+        }
+      }
+    });
+  }
+
+  /// Whether a child process is running, or has exited but was not waited for
+  fn is_unreaped(pid: libc::pid_t) -> bool {
+    // SAFETY: WNOWAIT leaves the child to be waited for by its owner
+    unsafe {
+      let mut info: libc::siginfo_t = std::mem::zeroed();
+      libc::waitid(
+        libc::P_PID,
+        pid as libc::id_t,
+        &mut info,
+        libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
+      ) == 0
+    }
+  }
+
+  #[derive(Debug)]
+  pub struct Group {
+    /// Process group of the pipeline, the PID of its first process, or 0 before
+    /// it is spawned
+    pgid: AtomicI32,
+    /// Index of the group in `GROUPS`, or `MAX_GROUPS` if it is not forwarded signals
+    slot: AtomicUsize,
+  }
+
+  impl Group {
+    pub fn new() -> Self {
+      forward_signals();
+      Self {
+        pgid: AtomicI32::new(0),
+        slot: AtomicUsize::new(MAX_GROUPS),
+      }
+    }
+
+    pub fn add(&self, command: &mut tokio::process::Command) {
+      // the first process creates the group, which the others join
+      let pgid = self.pgid.load(Ordering::SeqCst);
+
+      // SAFETY: setpgid and prctl are async-signal-safe. If the group cannot be
+      // joined, the process still runs in the group of av1an.
+      unsafe {
+        command.pre_exec(move || {
+          libc::setpgid(0, pgid);
+          // the process is killed when the thread of av1an that spawned it exits,
+          // e.g. if av1an is killed
+          #[cfg(target_os = "linux")]
+          libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+          Ok(())
+        });
+      }
+    }
+
+    pub fn register(&self, child: &tokio::process::Child) {
+      if self.pgid.load(Ordering::SeqCst) != 0 {
+        return;
+      }
+      let Some(leader) = child.id() else {
+        return;
+      };
+      let pgid = leader as i32;
+      self.pgid.store(pgid, Ordering::SeqCst);
+      if let Some(slot) = GROUPS.iter().position(|group| {
+        group
+          .compare_exchange(0, pgid, Ordering::SeqCst, Ordering::SeqCst)
+          .is_ok()
+      }) {
+        self.slot.store(slot, Ordering::SeqCst);
+      }
+    }
+  }
+
+  impl Drop for Group {
+    fn drop(&mut self) {
+      let pgid = *self.pgid.get_mut();
+      if pgid == 0 {
+        return;
+      }
+      if let Some(group) = GROUPS.get(*self.slot.get_mut()) {
+        let _ = group.compare_exchange(pgid, 0, Ordering::SeqCst, Ordering::SeqCst);
+      }
+      // once its leader is reaped and the processes of the pipeline have exited,
+      // the ID of the group can be reused by unrelated processes
+      if !is_unreaped(pgid) {
+        return;
+      }
+      // SAFETY: kills the processes left in the group of the pipeline, if any
+      unsafe {
+        libc::killpg(pgid, libc::SIGKILL);
+      }
+    }
+  }
+}
+
+#[cfg(windows)]
+mod imp {
+  use std::{mem, ptr};
+
+  use windows_sys::Win32::Foundation::{CloseHandle, HANDLE};
+  use windows_sys::Win32::System::JobObjects::{
+    AssignProcessToJobObject, CreateJobObjectW, JobObjectExtendedLimitInformation,
+    SetInformationJobObject, TerminateJobObject, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+    JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+  };
+
+  #[derive(Debug)]
+  pub struct Group {
+    /// Job Object of the pipeline, or 0 if it could not be created
+    job: HANDLE,
+  }
+
+  impl Group {
+    pub fn new() -> Self {
+      // SAFETY: the job is only used while its handle is open, and closed on drop
+      let job = unsafe { CreateJobObjectW(ptr::null(), ptr::null()) };
+      if job != 0 {
+        // SAFETY: a zeroed JOBOBJECT_EXTENDED_LIMIT_INFORMATION sets no other limits
+        unsafe {
+          let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = mem::zeroed();
+          info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+          SetInformationJobObject(
+            job,
+            JobObjectExtendedLimitInformation,
+            ptr::addr_of!(info).cast(),
+            mem::size_of_val(&info) as u32,
+          );
+        }
+      }
+      Self { job }
+    }
+
+    pub fn add(&self, _command: &mut tokio::process::Command) {}
+
+    pub fn register(&self, child: &tokio::process::Child) {
+      if self.job == 0 {
+        return;
+      }
+      if let Some(handle) = child.raw_handle() {
+        // SAFETY: the handle of the child is open until it is dropped
+        unsafe {
+          AssignProcessToJobObject(self.job, handle as HANDLE);
+        }
+      }
+    }
+  }
+
+  pub fn interrupt_all() {}
+
+  impl Drop for Group {
+    fn drop(&mut self) {
+      if self.job == 0 {
+        return;
+      }
+      // SAFETY: the handle of the job is owned by the group
+      unsafe {
+        TerminateJobObject(self.job, 1);
+        CloseHandle(self.job);
+      }
+    }
+  }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod imp {
+  #[derive(Debug)]
+  pub struct Group;
+
+  impl Group {
+    pub fn new() -> Self {
+      Self
+    }
+
+    pub fn add(&self, _command: &mut tokio::process::Command) {}
+
+    pub fn register(&self, _child: &tokio::process::Child) {}
+  }
+
+  pub fn interrupt_all() {}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+  use std::process::Stdio;
+
+  use super::*;
+
+  #[test]
+  fn kills_pipeline_on_drop() {
+    let rt = tokio::runtime::Builder::new_current_thread()
+      .enable_all()
+      .build()
+      .unwrap();
+    rt.block_on(async {
+      let group = ProcessGroup::new();
+      let mut children = Vec::new();
+      for _ in 0..2 {
+        let mut command = tokio::process::Command::new("sleep");
+        group.add(&mut command);
+        let child = command.arg("30").stdout(Stdio::null()).spawn().unwrap();
+        group.register(&child);
+        children.push(child);
+      }
+      drop(group);
+      for mut child in children {
+        let status = tokio::time::timeout(std::time::Duration::from_secs(5), child.wait())
+          .await
+          .unwrap()
+          .unwrap();
+        assert!(!status.success());
+      }
+    });
+  }
+}
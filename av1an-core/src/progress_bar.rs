@@ -1,11 +1,13 @@
+use std::collections::HashMap;
 use std::fmt::Write;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use indicatif::{
   HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressState,
   ProgressStyle,
 };
 use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
 
 use crate::util::printable_base10_digits;
 use crate::{get_done, Verbosity};
@@ -27,9 +29,17 @@ const INDICATIF_PROGRESS_TEMPLATE: &str = if cfg!(windows) {
 const INDICATIF_SPINNER_TEMPLATE: &str = if cfg!(windows) {
   // Do not use a spinner on Windows since the default console cannot display
   // the characters used for the spinner
-  "{elapsed_precise:.bold} [{wide_bar:.blue/white.dim}]  {pos} frames ({fps:.bold})"
+  "{elapsed_precise:.bold} [{wide_bar:.blue/white.dim}]  {pos} frames ({fps:.bold}{msg})"
 } else {
-  "{spinner:.green.bold} {elapsed_precise:.bold} [{wide_bar:.blue/white.dim}]  {pos} frames ({fps:.bold})"
+  "{spinner:.green.bold} {elapsed_precise:.bold} [{wide_bar:.blue/white.dim}]  {pos} frames ({fps:.bold}{msg})"
+};
+
+const INDICATIF_INDEX_TEMPLATE: &str = if cfg!(windows) {
+  // Do not use a spinner on Windows since the default console cannot display
+  // the characters used for the spinner
+  "{elapsed_precise:.bold} ▐{wide_bar:.blue/white.dim}▌ {pos:>3.bold}%  {msg}"
+} else {
+  "{spinner:.green.bold} {elapsed_precise:.bold} ▕{wide_bar:.blue/white.dim}▏ {pos:>3.bold}%  {msg}"
 };
 
 static PROGRESS_BAR: OnceCell<ProgressBar> = OnceCell::new();
@@ -68,7 +78,10 @@ fn pretty_progress_style(resume_frames: u64) -> ProgressStyle {
       "fixed_eta",
       move |state: &ProgressState, w: &mut dyn Write| {
         let resume_pos = state.pos() - resume_frames;
-        if resume_pos == 0 || state.elapsed().as_secs_f32() < f32::EPSILON {
+        let eta = ETA_MODEL.lock().as_ref().and_then(EtaModel::remaining);
+        if let Some(eta) = eta {
+          write!(w, "{:#}", HumanDuration(Duration::from_secs_f64(eta))).unwrap();
+        } else if resume_pos == 0 || state.elapsed().as_secs_f32() < f32::EPSILON {
           write!(w, "unknown").unwrap();
         } else {
           let spf = state.elapsed().as_secs_f32() / resume_pos as f32;
@@ -114,6 +127,26 @@ fn spinner_style(resume_frames: u64) -> ProgressStyle {
     .progress_chars(PROGRESS_CHARS)
 }
 
+/// Returns a progress bar of the percentage of the source that is indexed,
+/// hidden if the verbosity is quiet
+pub fn index_progress_bar(verbosity: Verbosity) -> ProgressBar {
+  let pb = ProgressBar::new(100)
+    .with_style(
+      ProgressStyle::default_bar()
+        .template(INDICATIF_INDEX_TEMPLATE)
+        .unwrap()
+        .progress_chars(PROGRESS_CHARS),
+    )
+    .with_message("indexing the source");
+  if verbosity == Verbosity::Quiet {
+    pb.set_draw_target(ProgressDrawTarget::hidden());
+  } else {
+    pb.set_draw_target(ProgressDrawTarget::stderr());
+    pb.enable_steady_tick(Duration::from_millis(100));
+  }
+  pb
+}
+
 /// Initialize progress bar
 /// Enables steady 100 ms tick
 pub fn init_progress_bar(len: u64, resume_frames: u64) {
@@ -131,6 +164,7 @@ pub fn init_progress_bar(len: u64, resume_frames: u64) {
   pb.reset_eta();
   pb.reset_elapsed();
   pb.set_position(0);
+  pb.set_message("");
 }
 
 pub fn convert_to_progress(resume_frames: u64) {
@@ -162,6 +196,13 @@ pub fn update_bar_info(kbps: f64, est_size: HumanBytes) {
   }
 }
 
+/// Shows the number of scenes found so far by scene detection
+pub fn update_bar_scenes(scenes: usize) {
+  if let Some(pb) = PROGRESS_BAR.get() {
+    pb.set_message(format!(", {scenes} scene(s)"));
+  }
+}
+
 pub fn set_pos(pos: u64) {
   if let Some(pb) = PROGRESS_BAR.get() {
     pb.set_position(pos);
@@ -191,8 +232,13 @@ pub fn get_first_multi_progress_bar() -> Option<&'static ProgressBar> {
 }
 
 pub fn set_len(len: u64) {
-  let pb = PROGRESS_BAR.get().unwrap();
-  pb.set_length(len);
+  if let Some(pb) = PROGRESS_BAR.get() {
+    pb.set_length(len);
+  }
+
+  if let Some((_, pbs)) = MULTI_PROGRESS_BAR.get() {
+    pbs.last().unwrap().set_length(len);
+  }
 }
 
 pub fn reset_bar_at(pos: u64) {
@@ -282,7 +328,181 @@ pub fn update_mp_bar_info(kbps: f64, est_size: HumanBytes) {
   }
 }
 
+/// Weight of a new estimate of the remaining time against the previous one,
+/// counted down since, so the ETA does not jump whenever a chunk finishes
+const ETA_SMOOTHING: f64 = 0.5;
+
+static ETA_MODEL: Mutex<Option<EtaModel>> = parking_lot::const_mutex(None);
+
+/// Encoding time of a chunk predicted from its number of frames, as
+/// `c * frames^k`, fitted to the finished chunks in log space. Long chunks of
+/// grainy or complex scenes are slower than short ones, which the exponent
+/// captures, and the spread of the speeds of the chunks around the fit raises
+/// the expected time of the chunks left.
+#[derive(Debug, Clone, Copy)]
+struct SpeedModel {
+  ln_c: f64,
+  k: f64,
+  /// Variance of the log of the encoding times around the fit
+  variance: f64,
+}
+
+impl SpeedModel {
+  /// Fits the model to the `(frames, seconds)` of the finished chunks
+  fn fit(samples: &[(usize, f64)]) -> Option<Self> {
+    let points: Vec<(f64, f64)> = samples
+      .iter()
+      .filter(|&&(frames, seconds)| frames > 0 && seconds > 0.0)
+      .map(|&(frames, seconds)| ((frames as f64).ln(), seconds.ln()))
+      .collect();
+    if points.is_empty() {
+      return None;
+    }
+
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let sxx: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    let sxy: f64 = points
+      .iter()
+      .map(|(x, y)| (x - mean_x) * (y - mean_y))
+      .sum();
+    // the time is proportional to the frames until the chunks vary enough in length
+    let k = if points.len() >= 3 && sxx > 0.1 {
+      (sxy / sxx).clamp(0.5, 1.5)
+    } else {
+      1.0
+    };
+    let ln_c = mean_y - k * mean_x;
+    let variance = if points.len() >= 2 {
+      points
+        .iter()
+        .map(|(x, y)| (y - ln_c - k * x).powi(2))
+        .sum::<f64>()
+        / (n - 1.0)
+    } else {
+      0.0
+    };
+
+    Some(Self { ln_c, k, variance })
+  }
+
+  /// Expected encoding time of a chunk, in seconds
+  fn predict(&self, frames: usize) -> f64 {
+    (self.k.mul_add((frames.max(1) as f64).ln(), self.ln_c) + self.variance / 2.0).exp()
+  }
+}
+
+/// Estimate of the time left to encode, from the chunks left and the speeds of
+/// the chunks finished so far
+#[derive(Debug)]
+struct EtaModel {
+  workers: usize,
+  /// Names and frames of the chunks left, in the order they are encoded
+  queue: Vec<(String, usize)>,
+  /// Chunks being encoded, with the time they were started
+  started: HashMap<String, Instant>,
+  /// Frames and encoding time of the finished chunks
+  samples: Vec<(usize, f64)>,
+  /// Time left in seconds, when it was estimated
+  estimate: Option<(Instant, f64)>,
+}
+
+impl EtaModel {
+  /// Time left in seconds, counted down since it was estimated
+  fn remaining(&self) -> Option<f64> {
+    self
+      .estimate
+      .map(|(at, seconds)| (seconds - at.elapsed().as_secs_f64()).max(0.0))
+  }
+
+  /// Time until the chunks left are encoded by the workers, each taking the
+  /// next chunk of the queue when it is done with its previous one
+  fn makespan(&self, model: &SpeedModel, now: Instant) -> f64 {
+    let mut workers: Vec<f64> = self
+      .queue
+      .iter()
+      .filter_map(|(name, frames)| {
+        let started = self.started.get(name)?;
+        Some((model.predict(*frames) - now.duration_since(*started).as_secs_f64()).max(0.0))
+      })
+      .collect();
+    workers.resize(self.workers.max(workers.len()), 0.0);
+
+    for (_, frames) in self
+      .queue
+      .iter()
+      .filter(|(name, _)| !self.started.contains_key(name))
+    {
+      let next = workers.iter_mut().min_by(|a, b| a.total_cmp(b)).unwrap();
+      *next += model.predict(*frames);
+    }
+
+    workers.into_iter().fold(0.0, f64::max)
+  }
+
+  /// Moves the chunks that are done from the queue to the samples, and
+  /// estimates the time left again
+  fn update(&mut self) {
+    let done = &get_done().done;
+    let now = Instant::now();
+    let started = &mut self.started;
+    let samples = &mut self.samples;
+    self.queue.retain(|(name, frames)| {
+      if !done.contains_key(name) {
+        return true;
+      }
+      if let Some(start) = started.remove(name) {
+        samples.push((*frames, now.duration_since(start).as_secs_f64()));
+      }
+      false
+    });
+
+    let Some(model) = SpeedModel::fit(&self.samples) else {
+      return;
+    };
+    let computed = self.makespan(&model, now);
+    let seconds = self.remaining().map_or(computed, |previous| {
+      ETA_SMOOTHING.mul_add(computed, (1.0 - ETA_SMOOTHING) * previous)
+    });
+    self.estimate = Some((now, seconds));
+  }
+}
+
+/// Estimates the ETA from the chunks left to encode, in the order they are
+/// encoded, instead of from the average frame rate.
+///
+/// The chunks finished in a previous run are used as samples of the encoding
+/// speed.
+pub fn init_eta(workers: usize, queue: impl IntoIterator<Item = (String, usize)>) {
+  let samples = get_done()
+    .done
+    .iter()
+    .map(|chunk| (chunk.frames, chunk.pass_times.iter().sum()))
+    .collect();
+  let mut model = EtaModel {
+    workers,
+    queue: queue.into_iter().collect(),
+    started: HashMap::new(),
+    samples,
+    estimate: None,
+  };
+  model.update();
+  *ETA_MODEL.lock() = Some(model);
+}
+
+/// Records that a chunk started encoding, for the ETA
+pub fn eta_chunk_started(name: String) {
+  if let Some(model) = ETA_MODEL.lock().as_mut() {
+    model.started.insert(name, Instant::now());
+  }
+}
+
 pub fn update_progress_bar_estimates(frame_rate: f64, total_frames: usize, verbosity: Verbosity) {
+  if let Some(model) = ETA_MODEL.lock().as_mut() {
+    model.update();
+  }
+
   let completed_frames: usize = get_done()
     .done
     .iter()
@@ -306,3 +526,46 @@ pub fn update_progress_bar_estimates(frame_rate: f64, total_frames: usize, verbo
     update_mp_bar_info(kbps, HumanBytes(est_size as u64));
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn eta_from_chunk_speeds() {
+    // chunks of 100 frames take 0.1 s per frame, and longer chunks are slower per frame
+    let samples: Vec<(usize, f64)> = [100, 200, 400, 800]
+      .iter()
+      .map(|&frames| (frames, 10.0 * (frames as f64 / 100.0).powf(1.5)))
+      .collect();
+    let model = SpeedModel::fit(&samples).unwrap();
+    assert!((model.k - 1.5).abs() < 1e-9);
+    assert!(model.variance < 1e-9);
+    assert!((model.predict(100) - 10.0).abs() < 1e-6);
+
+    let now = Instant::now();
+    let eta = EtaModel {
+      workers: 2,
+      queue: vec![
+        ("00000".to_owned(), 100),
+        ("00001".to_owned(), 100),
+        ("00002".to_owned(), 100),
+        ("00003".to_owned(), 100),
+      ],
+      started: HashMap::new(),
+      samples,
+      estimate: None,
+    };
+    // the 4 chunks of 10 s each are split between the 2 workers
+    let linear = SpeedModel {
+      ln_c: 0.1_f64.ln(),
+      k: 1.0,
+      variance: 0.0,
+    };
+    assert!((eta.makespan(&linear, now) - 20.0).abs() < 1e-6);
+
+    // a single sample is proportional to the frames
+    let model = SpeedModel::fit(&[(50, 5.0)]).unwrap();
+    assert!((model.predict(500) - 50.0).abs() < 1e-6);
+  }
+}
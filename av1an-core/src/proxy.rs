@@ -0,0 +1,152 @@
+//! Fast encodes of every chunk before the encode, which measure how many bits
+//! their scenes need, for quick consistency and the bit allocation.
+
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::{fs, thread};
+
+use anyhow::{bail, Context};
+
+use crate::chunk::Chunk;
+use crate::{cgroup, Encoder, Input};
+
+/// Returns the highest q that the encoder accepts
+pub const fn max_q(encoder: Encoder) -> usize {
+  match encoder {
+    Encoder::aom | Encoder::vpx | Encoder::svt_av1 => 63,
+    Encoder::rav1e => 255,
+    Encoder::x264 | Encoder::x265 => 51,
+  }
+}
+
+/// Returns the number of steps of q that halve the bitrate, roughly
+pub(crate) const fn q_per_halving(encoder: Encoder) -> f64 {
+  match encoder {
+    Encoder::x264 | Encoder::x265 => 6.0,
+    Encoder::aom | Encoder::vpx | Encoder::svt_av1 => 8.0,
+    Encoder::rav1e => 32.0,
+  }
+}
+
+/// Runs `measure` on every chunk, on `workers` threads, and returns the
+/// results in the order of `chunks`
+pub fn measure_chunks<T: Send>(
+  chunks: &[Chunk],
+  workers: usize,
+  measure: impl Fn(&Chunk) -> anyhow::Result<T> + Sync,
+) -> anyhow::Result<Vec<T>> {
+  let next = AtomicUsize::new(0);
+  let results = Mutex::new((0..chunks.len()).map(|_| None).collect::<Vec<_>>());
+  thread::scope(|s| {
+    let workers: Vec<_> = (0..workers.max(1))
+      .map(|_| {
+        s.spawn(|| -> anyhow::Result<()> {
+          loop {
+            let i = next.fetch_add(1, Ordering::Relaxed);
+            let Some(chunk) = chunks.get(i) else {
+              return Ok(());
+            };
+            let result = measure(chunk)?;
+            results.lock().unwrap()[i] = Some(result);
+          }
+        })
+      })
+      .collect();
+
+    workers
+      .into_iter()
+      .try_for_each(|worker| worker.join().unwrap())
+  })?;
+
+  // every chunk was measured, as the workers only stop once they are all taken
+  Ok(
+    results
+      .into_inner()
+      .unwrap()
+      .into_iter()
+      .map(Option::unwrap)
+      .collect(),
+  )
+}
+
+/// Pipes the source of the chunk through `ffmpeg_pipe` into `encoder_cmd`,
+/// which writes `output`, and returns the bits per frame of the encode.
+///
+/// The source frames of the chunk are encoded, regardless of `--fps`, and the
+/// output is left for the caller to remove.
+pub fn bits_per_frame(
+  chunk: &Chunk,
+  ffmpeg_pipe: &[String],
+  encoder_cmd: &[impl AsRef<OsStr>],
+  output: &Path,
+) -> anyhow::Result<f64> {
+  let [source, source_args @ ..] = &*chunk.source_cmd else {
+    unreachable!()
+  };
+  let mut source_command = Command::new(source);
+  if let Input::VapourSynth { vspipe_args, .. } = &chunk.input {
+    for arg in vspipe_args {
+      source_command.args(["-a", arg]);
+    }
+  }
+  cgroup::apply(&mut source_command);
+  let mut source_pipe = source_command
+    .args(source_args)
+    .stdout(Stdio::piped())
+    .stderr(Stdio::null())
+    .spawn()
+    .context("Failed to spawn the source of the proxy encode")?;
+
+  let [ffmpeg, ffmpeg_args @ ..] = ffmpeg_pipe else {
+    unreachable!()
+  };
+  let mut ffmpeg_command = Command::new(ffmpeg);
+  cgroup::apply(&mut ffmpeg_command);
+  let mut ffmpeg_pipe = ffmpeg_command
+    .args(ffmpeg_args)
+    .stdin(source_pipe.stdout.take().unwrap())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::null())
+    .spawn()
+    .context("Failed to spawn ffmpeg for the proxy encode")?;
+
+  let [encoder, encoder_args @ ..] = encoder_cmd else {
+    unreachable!()
+  };
+  let encoder = encoder.as_ref();
+  let mut encoder_command = Command::new(encoder);
+  cgroup::apply(&mut encoder_command);
+  let encode = encoder_command
+    .args(encoder_args)
+    .stdin(ffmpeg_pipe.stdout.take().unwrap())
+    .stdout(Stdio::null())
+    .stderr(Stdio::piped())
+    .output()
+    .with_context(|| {
+      format!(
+        "Failed to run {}, is it installed in the system path?",
+        encoder.to_string_lossy()
+      )
+    })?;
+  source_pipe.wait()?;
+  ffmpeg_pipe.wait()?;
+
+  if !encode.status.success() {
+    bail!(
+      "{} failed the proxy encode of chunk {}:\n{}",
+      encoder.to_string_lossy(),
+      chunk.name(),
+      String::from_utf8_lossy(&encode.stderr)
+    );
+  }
+
+  let size = fs::metadata(output)
+    .with_context(|| format!("Failed to read the proxy encode {}", output.display()))?
+    .len();
+
+  let frames = (chunk.end_frame - chunk.start_frame).max(1);
+  Ok(size as f64 * 8.0 / frames as f64)
+}
@@ -0,0 +1,193 @@
+//! Inputs read over HTTP, e.g. from a NAS, given as `-i https://...`.
+//!
+//! The chunk methods, scene detection and the ffmpeg processes of the chunks
+//! all read the input many times and out of order, which is slow and fragile
+//! over a network. The input is thus cached in the temporary folder first,
+//! downloaded with curl in segments of byte ranges when the server supports
+//! them. Each segment is retried on its own if the connection fails, and is
+//! appended to the cached file once downloaded, so that the download takes
+//! no more space than the input and a segment, and the segments already
+//! downloaded are kept for `--resume`.
+
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+use std::{io, thread};
+
+use anyhow::{bail, Context};
+use tracing::{debug, info, warn};
+
+use crate::create_dir;
+
+/// Size of the byte ranges the input is downloaded in
+const SEGMENT_SIZE: u64 = 64 << 20;
+
+/// Attempts to download a segment before the download is given up
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry of a segment, doubled for each retry
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Whether the input is a URL of a file served over HTTP
+pub fn is_url(input: &str) -> bool {
+  input.starts_with("http://") || input.starts_with("https://")
+}
+
+/// Downloads the input at `url` into the temporary folder, unless it was
+/// already, and returns the path of the cached file
+pub fn cache(url: &str, temp: &Path) -> anyhow::Result<PathBuf> {
+  let dir = temp.join("remote");
+  create_dir!(&dir)?;
+  let cached = dir.join(file_name(url));
+  let (size, ranges) = probe(url)?;
+
+  if let Some(size) = size {
+    if fs::metadata(&cached).is_ok_and(|meta| meta.len() == size) {
+      info!("using the cached download of {}", url);
+      return Ok(cached);
+    }
+  }
+
+  if let Some(size) = size.filter(|_| ranges) {
+    let segments = size.div_ceil(SEGMENT_SIZE);
+    info!(
+      "downloading {} ({} bytes) into the temporary folder in {} segment(s)",
+      url, size, segments
+    );
+    let mut output = OpenOptions::new().create(true).append(true).open(&cached)?;
+    // the segments of an interrupted download were appended already, unless
+    // the last one was only partly
+    let done = (output.metadata()?.len() / SEGMENT_SIZE).min(segments);
+    output.set_len(done * SEGMENT_SIZE)?;
+    let part = dir.join("part");
+    for segment in done..segments {
+      let start = segment * SEGMENT_SIZE;
+      let end = (start + SEGMENT_SIZE).min(size) - 1;
+      download(url, Some((start, end)), &part)?;
+      io::copy(&mut File::open(&part)?, &mut output)?;
+      fs::remove_file(&part)?;
+    }
+  } else {
+    warn!(
+      "the server of {} does not support byte ranges, downloading it as a whole",
+      url
+    );
+    download(url, None, &cached)?;
+  }
+
+  Ok(cached)
+}
+
+/// Returns the size of the file at `url`, and whether its server can send
+/// byte ranges of it
+fn probe(url: &str) -> anyhow::Result<(Option<u64>, bool)> {
+  let output = Command::new("curl")
+    .args(["-fsSIL", "--retry", &MAX_ATTEMPTS.to_string(), url])
+    .output()
+    .context("Failed to run curl, which is needed for inputs read over HTTP")?;
+  if !output.status.success() {
+    bail!(
+      "Failed to reach {} ({}): {}",
+      url,
+      output.status,
+      String::from_utf8_lossy(&output.stderr).trim()
+    );
+  }
+
+  Ok(parse_headers(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Returns the content length and whether byte ranges are accepted, from the
+/// headers of the final response after redirects
+fn parse_headers(headers: &str) -> (Option<u64>, bool) {
+  let mut size = None;
+  let mut ranges = false;
+  for line in headers.lines() {
+    // each response of a redirect starts with its status line
+    if line.starts_with("HTTP/") {
+      size = None;
+      ranges = false;
+    } else if let Some((name, value)) = line.split_once(':') {
+      let value = value.trim();
+      if name.eq_ignore_ascii_case("content-length") {
+        size = value.parse().ok();
+      } else if name.eq_ignore_ascii_case("accept-ranges") {
+        ranges = value.eq_ignore_ascii_case("bytes");
+      }
+    }
+  }
+
+  (size, ranges)
+}
+
+/// Downloads the byte range of `url` into `output`, retrying a few times
+fn download(url: &str, range: Option<(u64, u64)>, output: &Path) -> anyhow::Result<()> {
+  let mut delay = RETRY_DELAY;
+  let mut attempt = 1;
+  loop {
+    let mut cmd = Command::new("curl");
+    cmd.args(["-fsSL", "-o"]).arg(output);
+    if let Some((start, end)) = range {
+      cmd.args(["--range", &format!("{start}-{end}")]);
+    }
+    cmd.arg(url);
+
+    let result = cmd.output().context("Failed to run curl")?;
+    if result.status.success() {
+      debug!("downloaded {:?} of {}", range, url);
+      return Ok(());
+    }
+
+    let error = String::from_utf8_lossy(&result.stderr).trim().to_owned();
+    if attempt == MAX_ATTEMPTS {
+      bail!("Failed to download {url} after {MAX_ATTEMPTS} attempts: {error}");
+    }
+    warn!(
+      "failed to download {:?} of {} (attempt {}/{}), retrying in {:?}: {}",
+      range, url, attempt, MAX_ATTEMPTS, delay, error
+    );
+    thread::sleep(delay);
+    delay *= 2;
+    attempt += 1;
+  }
+}
+
+/// Returns the name of the file at `url`, without the query
+fn file_name(url: &str) -> &str {
+  let url = url.split(['?', '#']).next().unwrap_or(url);
+  let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+  without_scheme
+    .split_once('/')
+    .and_then(|(_, path)| path.rsplit('/').next())
+    .filter(|name| !name.is_empty())
+    .unwrap_or("input")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_final_response() {
+    let headers = "HTTP/1.1 302 Found\r\nLocation: /video.mkv\r\nContent-Length: 0\r\n\r\n\
+                   HTTP/1.1 200 OK\r\nContent-Length: 1048576\r\nAccept-Ranges: bytes\r\n\r\n";
+    assert_eq!(parse_headers(headers), (Some(1_048_576), true));
+
+    let headers = "HTTP/2 200\r\ncontent-length: 42\r\naccept-ranges: none\r\n\r\n";
+    assert_eq!(parse_headers(headers), (Some(42), false));
+
+    assert_eq!(parse_headers("HTTP/1.1 200 OK\r\n\r\n"), (None, false));
+  }
+
+  #[test]
+  fn names_cached_file() {
+    assert_eq!(file_name("https://nas.local/movies/video.mkv"), "video.mkv");
+    assert_eq!(
+      file_name("http://nas.local/share/video.mp4?token=abc#t=1"),
+      "video.mp4"
+    );
+    assert_eq!(file_name("https://nas.local/"), "input");
+    assert_eq!(file_name("https://nas.local"), "input");
+  }
+}
@@ -0,0 +1,491 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context};
+use plotters::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::chunk::Chunk;
+use crate::scenes::Scene;
+use crate::vmaf::{percentile_of_sorted, reference_pipe_cmd, run_vmaf, VmafModel};
+use crate::{get_done, DoneChunk, Input};
+
+/// Metrics computed by libvmaf in addition to VMAF
+const LIBVMAF_FEATURES: &[&str] = &["psnr", "float_ssim"];
+
+/// Per-frame keys in the libvmaf log, and the name they are reported under
+const LIBVMAF_METRICS: &[(&str, &str)] =
+  &[("vmaf", "VMAF"), ("psnr_y", "PSNR"), ("float_ssim", "SSIM")];
+
+const SSIMULACRA2: &str = "SSIMULACRA2";
+
+#[derive(Deserialize, Debug)]
+struct LibvmafFrame {
+  metrics: HashMap<String, f64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LibvmafLog {
+  frames: Vec<LibvmafFrame>,
+}
+
+/// Summary statistics of a metric over a range of frames
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct MetricStats {
+  pub mean: f64,
+  /// The 1st percentile score
+  pub low_1pct: f64,
+  /// `None` if any score is not positive, as the harmonic mean is then undefined
+  pub harmonic_mean: Option<f64>,
+}
+
+impl MetricStats {
+  pub fn from_scores(scores: &[f64]) -> Option<Self> {
+    if scores.is_empty() {
+      return None;
+    }
+
+    let mut sorted = scores.to_vec();
+    sorted.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Less));
+
+    let len = scores.len() as f64;
+    let harmonic_mean = if scores.iter().all(|&score| score > 0.0) {
+      Some(len / scores.iter().map(|score| score.recip()).sum::<f64>())
+    } else {
+      None
+    };
+
+    Some(Self {
+      mean: scores.iter().sum::<f64>() / len,
+      low_1pct: percentile_of_sorted(&sorted, 0.01),
+      harmonic_mean,
+    })
+  }
+}
+
+#[derive(Serialize, Debug)]
+pub struct SceneReport {
+  pub start_frame: usize,
+  pub end_frame: usize,
+  pub metrics: BTreeMap<String, MetricStats>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct QualityReport {
+  pub frames: usize,
+  pub metrics: BTreeMap<String, MetricStats>,
+  pub scenes: Vec<SceneReport>,
+  /// Per-frame scores of each metric
+  pub frame_scores: BTreeMap<String, Vec<f64>>,
+}
+
+impl QualityReport {
+  fn new(frame_scores: BTreeMap<String, Vec<f64>>, scenes: &[Scene]) -> Self {
+    let frames = frame_scores.values().map(Vec::len).max().unwrap_or(0);
+
+    let stats_of = |start: usize, end: usize| {
+      frame_scores
+        .iter()
+        .filter_map(|(name, scores)| {
+          let scores = scores.get(start..end.min(scores.len()))?;
+          Some((name.clone(), MetricStats::from_scores(scores)?))
+        })
+        .collect::<BTreeMap<_, _>>()
+    };
+
+    Self {
+      frames,
+      metrics: stats_of(0, frames),
+      scenes: scenes
+        .iter()
+        .map(|scene| SceneReport {
+          start_frame: scene.start_frame,
+          end_frame: scene.end_frame,
+          metrics: stats_of(scene.start_frame, scene.end_frame),
+        })
+        .collect(),
+      frame_scores,
+    }
+  }
+
+  fn to_html(&self) -> anyhow::Result<String> {
+    let names: Vec<&String> = self.metrics.keys().collect();
+
+    let mut html = String::from(
+      "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Av1an quality report</title>\n\
+<style>body{font-family:sans-serif}table{border-collapse:collapse}td,th{border:1px solid #ccc;padding:2px 8px;text-align:right}</style>\n\
+</head>\n<body>\n<h1>Quality report</h1>\n",
+    );
+
+    writeln!(
+      html,
+      "<p>{} frames, {} scenes</p>",
+      self.frames,
+      self.scenes.len()
+    )?;
+
+    html.push_str("<h2>Overall</h2>\n<table>\n<tr><th>Metric</th><th>Mean</th><th>1% low</th><th>Harmonic mean</th></tr>\n");
+    for (name, stats) in &self.metrics {
+      writeln!(
+        html,
+        "<tr><th>{name}</th><td>{:.3}</td><td>{:.3}</td><td>{}</td></tr>",
+        stats.mean,
+        stats.low_1pct,
+        format_harmonic_mean(stats.harmonic_mean)
+      )?;
+    }
+    html.push_str("</table>\n");
+
+    for name in &names {
+      writeln!(html, "<h2>{name}</h2>")?;
+      html.push_str(&plot_scores(name, &self.frame_scores[*name], &self.scenes)?);
+      html.push('\n');
+    }
+
+    html.push_str("<h2>Scenes</h2>\n<table>\n<tr><th>Scene</th><th>Frames</th>");
+    for name in &names {
+      write!(
+        html,
+        "<th>{name} mean</th><th>{name} 1% low</th><th>{name} harmonic mean</th>"
+      )?;
+    }
+    html.push_str("</tr>\n");
+    for (index, scene) in self.scenes.iter().enumerate() {
+      write!(
+        html,
+        "<tr><td>{index}</td><td>{}-{}</td>",
+        scene.start_frame, scene.end_frame
+      )?;
+      for name in &names {
+        if let Some(stats) = scene.metrics.get(*name) {
+          write!(
+            html,
+            "<td>{:.3}</td><td>{:.3}</td><td>{}</td>",
+            stats.mean,
+            stats.low_1pct,
+            format_harmonic_mean(stats.harmonic_mean)
+          )?;
+        } else {
+          html.push_str("<td></td><td></td><td></td>");
+        }
+      }
+      html.push_str("</tr>\n");
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+
+    Ok(html)
+  }
+}
+
+fn format_harmonic_mean(harmonic_mean: Option<f64>) -> String {
+  harmonic_mean.map_or_else(|| "-".to_string(), |mean| format!("{mean:.3}"))
+}
+
+/// Plots the per-frame scores of a metric as an SVG, with scene boundaries marked
+fn plot_scores(name: &str, scores: &[f64], scenes: &[SceneReport]) -> anyhow::Result<String> {
+  let mut svg = String::new();
+
+  {
+    let root = SVGBackend::with_string(&mut svg, (1600, 400)).into_drawing_area();
+    root.fill(&WHITE)?;
+
+    let (min, max) = scores
+      .iter()
+      .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &score| {
+        (min.min(score), max.max(score))
+      });
+    let (min, max) = if min < max {
+      (min.floor(), max.ceil())
+    } else {
+      (min - 1.0, max + 1.0)
+    };
+
+    let mut chart = ChartBuilder::on(&root)
+      .caption(name, ("sans-serif", 20))
+      .set_label_area_size(LabelAreaPosition::Bottom, 30)
+      .set_label_area_size(LabelAreaPosition::Left, 50)
+      .margin(10)
+      .build_cartesian_2d(0..scores.len(), min..max)?;
+
+    chart.configure_mesh().disable_x_mesh().draw()?;
+
+    chart.draw_series(scenes.iter().skip(1).map(|scene| {
+      PathElement::new(
+        vec![(scene.start_frame, min), (scene.start_frame, max)],
+        BLACK.mix(0.15),
+      )
+    }))?;
+
+    chart.draw_series(LineSeries::new(scores.iter().copied().enumerate(), BLUE))?;
+
+    root.present()?;
+  }
+
+  Ok(svg)
+}
+
+/// Parses the per-frame output of `ssimulacra2_rs video --verbose`, which
+/// contains lines in the form `Frame 12: 81.23456789`
+pub(crate) fn parse_ssimulacra2_output(output: &str) -> Vec<f64> {
+  let mut scores: Vec<(usize, f64)> = output
+    .lines()
+    .filter_map(|line| {
+      let (frame, score) = line.trim().strip_prefix("Frame ")?.split_once(':')?;
+      let frame = frame
+        .split(|c: char| !c.is_ascii_digit())
+        .next()?
+        .parse()
+        .ok()?;
+      Some((frame, score.trim().parse().ok()?))
+    })
+    .collect();
+  scores.sort_unstable_by_key(|(frame, _)| *frame);
+
+  scores.into_iter().map(|(_, score)| score).collect()
+}
+
+fn run_ssimulacra2(encoded: &Path, reference: &Input) -> anyhow::Result<Vec<f64>> {
+  let output = Command::new("ssimulacra2_rs")
+    .arg("video")
+    .arg(reference.as_path())
+    .arg(encoded)
+    .arg("--verbose")
+    .stdin(Stdio::null())
+    .output()?;
+
+  if !output.status.success() {
+    bail!(
+      "ssimulacra2_rs failed: {}",
+      String::from_utf8_lossy(&output.stderr)
+    );
+  }
+
+  Ok(parse_ssimulacra2_output(&String::from_utf8_lossy(
+    &output.stdout,
+  )))
+}
+
+/// Results of an encoded chunk, to analyze the distribution of quality and size
+/// across the scenes
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ChunkReport {
+  pub index: usize,
+  pub start_frame: usize,
+  pub end_frame: usize,
+  pub frames: usize,
+  /// Q chosen by target quality
+  pub q: Option<u32>,
+  pub size_bytes: u64,
+  pub bitrate_kbps: f64,
+  /// Time spent encoding all passes, in seconds
+  pub encode_time: f64,
+  pub fps: f64,
+  /// Mean score of the target quality probes
+  pub mean_probe_score: Option<f64>,
+}
+
+impl ChunkReport {
+  fn new(chunk: &Chunk, done: &DoneChunk) -> Self {
+    let encode_time: f64 = done.pass_times.iter().sum();
+    let seconds = done.frames as f64 / chunk.frame_rate;
+    Self {
+      index: chunk.index,
+      start_frame: chunk.start_frame,
+      end_frame: chunk.end_frame,
+      frames: done.frames,
+      q: done.tq_cq,
+      size_bytes: done.size_bytes,
+      bitrate_kbps: if seconds > 0.0 {
+        done.size_bytes as f64 * 8.0 / 1000.0 / seconds
+      } else {
+        0.0
+      },
+      encode_time,
+      fps: if encode_time > 0.0 {
+        done.frames as f64 / encode_time
+      } else {
+        0.0
+      },
+      mean_probe_score: done.mean_probe_score,
+    }
+  }
+}
+
+/// Writes the results of every encoded chunk to `chunks_report.json` in the
+/// temporary folder, and returns its path
+pub fn chunks_report(temp: &Path, chunks: &[Chunk]) -> anyhow::Result<PathBuf> {
+  let mut chunks: Vec<&Chunk> = chunks.iter().collect();
+  chunks.sort_unstable_by_key(|chunk| chunk.index);
+
+  let done = &get_done().done;
+  let report: Vec<ChunkReport> = chunks
+    .into_iter()
+    .filter_map(|chunk| Some(ChunkReport::new(chunk, done.get(&chunk.name())?.value())))
+    .collect();
+
+  let path = temp.join("chunks_report.json");
+  fs::write(&path, serde_json::to_string_pretty(&report)?)
+    .with_context(|| format!("Failed to write {}", path.display()))?;
+  Ok(path)
+}
+
+/// Writes a JSON and an HTML quality report next to the output file.
+///
+/// VMAF, PSNR, SSIM and (if `ssimulacra2_rs` is installed) SSIMULACRA2 are
+/// computed for every frame of the encode, and summarized overall and per scene.
+pub fn quality_report(
+  encoded: &Path,
+  reference: &Input,
+  scenes: &[Scene],
+  model: Option<&VmafModel>,
+  res: &str,
+  scaler: &str,
+  filter: Option<&str>,
+  threads: usize,
+) -> anyhow::Result<()> {
+  let log_file = encoded.with_extension("metrics.json");
+  let json_file = encoded.with_extension("report.json");
+  let html_file = encoded.with_extension("report.html");
+
+  println!(":: Quality report");
+
+  let (pipe_cmd, vspipe_args) = reference_pipe_cmd(reference);
+  run_vmaf(
+    encoded,
+    &pipe_cmd,
+    vspipe_args,
+    &log_file,
+    model,
+    res,
+    scaler,
+    1,
+    filter,
+    threads,
+    LIBVMAF_FEATURES,
+  )
+  .map_err(|e| anyhow::anyhow!("{e}"))?;
+
+  let log: LibvmafLog = serde_json::from_str(&fs::read_to_string(&log_file)?)
+    .with_context(|| "Failed to parse libvmaf log")?;
+
+  let mut frame_scores = BTreeMap::new();
+  for (key, name) in LIBVMAF_METRICS {
+    let scores: Option<Vec<f64>> = log
+      .frames
+      .iter()
+      .map(|frame| frame.metrics.get(*key).copied())
+      .collect();
+    if let Some(scores) = scores {
+      frame_scores.insert((*name).to_string(), scores);
+    } else {
+      warn!("libvmaf did not report {}, skipping it", name);
+    }
+  }
+
+  if which::which("ssimulacra2_rs").is_ok() {
+    match run_ssimulacra2(encoded, reference) {
+      Ok(scores) if !scores.is_empty() => {
+        frame_scores.insert(SSIMULACRA2.to_string(), scores);
+      }
+      Ok(_) => warn!("ssimulacra2_rs did not report any scores, skipping SSIMULACRA2"),
+      Err(e) => warn!("{}, skipping SSIMULACRA2", e),
+    }
+  } else {
+    warn!("ssimulacra2_rs not found in system path, skipping SSIMULACRA2");
+  }
+
+  let report = QualityReport::new(frame_scores, scenes);
+
+  fs::write(&json_file, serde_json::to_string_pretty(&report)?)?;
+  fs::write(&html_file, report.to_html()?)?;
+
+  info!(
+    "quality report written to {} and {}",
+    json_file.display(),
+    html_file.display()
+  );
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn metric_stats() {
+    let stats = MetricStats::from_scores(&[1.0, 2.0, 4.0, 4.0]).unwrap();
+    assert!((stats.mean - 2.75).abs() < f64::EPSILON);
+    assert!((stats.low_1pct - 1.0).abs() < f64::EPSILON);
+    assert!((stats.harmonic_mean.unwrap() - 2.0).abs() < f64::EPSILON);
+
+    assert_eq!(
+      MetricStats::from_scores(&[-1.0, 2.0])
+        .unwrap()
+        .harmonic_mean,
+      None
+    );
+    assert_eq!(MetricStats::from_scores(&[]), None);
+  }
+
+  #[test]
+  fn chunk_report() {
+    let chunk = Chunk {
+      temp: "none".to_owned(),
+      index: 3,
+      input: Input::Video {
+        path: "test.mkv".into(),
+      },
+      source_cmd: vec!["".into()],
+      output_ext: "ivf".to_owned(),
+      start_frame: 100,
+      end_frame: 150,
+      output_frames: None,
+      frame_rate: 25.0,
+      tq_cq: Some(30),
+      target_quality: crate::target_quality::ChunkTarget::Inherit,
+      passes: 2,
+      video_params: vec![],
+      encoder: crate::Encoder::aom,
+      noise_size: (None, None),
+      ignore_frame_mismatch: false,
+      pix_format: None,
+      part: None,
+      skip: false,
+      denoise: None,
+    };
+    let done = DoneChunk {
+      frames: 50,
+      size_bytes: 250_000,
+      pass_times: vec![2.0, 8.0],
+      tq_cq: Some(30),
+      mean_probe_score: Some(95.5),
+      fallback: None,
+    };
+    assert_eq!(
+      ChunkReport::new(&chunk, &done),
+      ChunkReport {
+        index: 3,
+        start_frame: 100,
+        end_frame: 150,
+        frames: 50,
+        q: Some(30),
+        size_bytes: 250_000,
+        bitrate_kbps: 1000.0,
+        encode_time: 10.0,
+        fps: 5.0,
+        mean_probe_score: Some(95.5),
+      }
+    );
+  }
+
+  #[test]
+  fn ssimulacra2_output() {
+    let output = "Frame 1: 80.5\nFrame 0: 90.25\nVideo Score for 2 frames\nMean: 85.375\n";
+    assert_eq!(parse_ssimulacra2_output(output), vec![90.25, 80.5]);
+  }
+}
@@ -1,20 +1,255 @@
-use std::io::{IsTerminal, Read};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, BufReader, IsTerminal, Read};
+use std::path::Path;
 use std::process::{Command, Stdio};
+use std::sync::Arc;
 use std::thread;
 
 use ansi_term::Style;
-use anyhow::bail;
+use anyhow::{bail, Context};
 use av_scenechange::decoder::Decoder;
 use av_scenechange::ffmpeg::FfmpegDecoder;
 use av_scenechange::vapoursynth::VapoursynthDecoder;
-use av_scenechange::{detect_scene_changes, DetectionOptions, SceneDetectionSpeed};
+use av_scenechange::{new_detector, DetectionOptions, SceneDetectionSpeed};
 use ffmpeg::format::Pixel;
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 use smallvec::{smallvec, SmallVec};
+use strum::{Display, EnumString, IntoStaticStr};
+use v_frame::frame::Frame;
 
-use crate::scenes::Scene;
-use crate::{into_smallvec, progress_bar, Encoder, Input, ScenecutMethod, Verbosity};
+use crate::scenes::{Scene, SceneCut};
+use crate::split::keyframe_scenes;
+use crate::{cgroup, into_smallvec, progress_bar, Encoder, Input, ScenecutMethod, Verbosity};
 
+/// Detector of the scene changes of `--split-method av-scenechange`
+#[derive(
+  Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, EnumString, IntoStaticStr, Display,
+)]
+pub enum ScBackend {
+  /// The scene change detector of rav1e
+  #[strum(serialize = "av-scenechange")]
+  AvScenechange,
+  /// The scdet filter of ffmpeg
+  #[strum(serialize = "ffmpeg-scdet")]
+  FfmpegScdet,
+  /// The wwxd plugin of VapourSynth
+  #[strum(serialize = "vapoursynth-wwxd")]
+  VapoursynthWwxd,
+}
+
+/// Threshold of the score of scdet above which a frame is a scene change,
+/// the default of the filter
+const SCDET_THRESHOLD: f64 = 10.0;
+
+/// Detects the scenes of the input with an external detector, which finds
+/// the scene changes of the whole input at once. The scene changes are
+/// normalized into scenes like the keyframes of `--split-method
+/// source-keyframes`, keeping the zones and the minimum scene length. Returns
+/// the scenes, the number of frames, and the luma difference of each frame to
+/// the previous one if the detector measures it.
+#[tracing::instrument]
+pub fn external_detect(
+  backend: ScBackend,
+  input: &Input,
+  vs_script: Option<&Path>,
+  total_frames: usize,
+  min_scene_len: usize,
+  verbosity: Verbosity,
+  sc_scaler: &str,
+  sc_downscale_height: Option<usize>,
+  zones: &[Scene],
+) -> anyhow::Result<(Vec<Scene>, usize, Vec<f64>)> {
+  if verbosity != Verbosity::Quiet {
+    if std::io::stderr().is_terminal() {
+      eprintln!("{}", Style::default().bold().paint("Scene detection"));
+    } else {
+      eprintln!("Scene detection");
+    }
+    progress_bar::init_progress_bar(total_frames as u64, 0);
+  }
+  let callback = |frames: usize| {
+    if verbosity != Verbosity::Quiet {
+      progress_bar::set_pos(frames as u64);
+    }
+  };
+
+  let (scene_changes, differences) = match backend {
+    ScBackend::AvScenechange => unreachable!("av-scenechange is not an external detector"),
+    ScBackend::FfmpegScdet => {
+      scdet_scene_changes(input, sc_scaler, sc_downscale_height, &callback)?
+    }
+    ScBackend::VapoursynthWwxd => {
+      // a video input is read with the script of its chunk method, which takes no arguments
+      let script = match input {
+        Input::VapourSynth { path, .. } => path.as_path(),
+        Input::Video { .. } => vs_script.context(
+          "--sc-backend vapoursynth-wwxd needs a VapourSynth input, or a video input split with a \
+           VapourSynth chunk method (lsmash, ffms2, dgdecnv or bestsource)",
+        )?,
+      };
+      let scene_changes =
+        crate::vapoursynth::wwxd_scene_changes(script, input.as_vspipe_args_map()?, &callback)?;
+      (scene_changes, Vec::new())
+    }
+  };
+  progress_bar::finish_progress_bar();
+  debug!(
+    "{} found {} scene change(s)",
+    backend,
+    scene_changes.len().saturating_sub(1)
+  );
+
+  let scenes = keyframe_scenes(&scene_changes, total_frames, min_scene_len, zones)
+    .into_iter()
+    .map(|scene| Scene {
+      cut: scene_cut(&differences, scene.start_frame),
+      ..scene
+    })
+    .collect();
+
+  Ok((scenes, total_frames, differences))
+}
+
+/// Detects the scene changes with the scdet filter of ffmpeg, on the luma of
+/// the frames. Returns the first frame of every scene, and the mean absolute
+/// difference of the luma of each frame to the previous one, relative to its
+/// maximum value.
+fn scdet_scene_changes(
+  input: &Input,
+  sc_scaler: &str,
+  sc_downscale_height: Option<usize>,
+  callback: &dyn Fn(usize),
+) -> anyhow::Result<(Vec<usize>, Vec<f64>)> {
+  let mut filters = Vec::new();
+  if let Some(height) = sc_downscale_height {
+    filters.push(format!("scale=-2:'min({height},ih)':flags={sc_scaler}"));
+  }
+  filters.extend([
+    "format=gray".to_owned(),
+    format!("scdet=threshold={SCDET_THRESHOLD}"),
+    "metadata=mode=print:file=-".to_owned(),
+  ]);
+
+  let mut ffmpeg = Command::new("ffmpeg");
+  cgroup::apply(&mut ffmpeg);
+  ffmpeg.args(["-hide_banner", "-loglevel", "error", "-nostats"]);
+  let mut vspipe = match input {
+    Input::VapourSynth { path, .. } => {
+      let mut vspipe = Command::new("vspipe");
+      cgroup::apply(&mut vspipe);
+      vspipe
+        .args(["-c", "y4m"])
+        .arg(path)
+        .arg("-")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+      for arg in input.as_vspipe_args_vec()? {
+        vspipe.args(["-a", &arg]);
+      }
+      let mut vspipe = vspipe
+        .spawn()
+        .context("Failed to run vspipe for scene detection")?;
+      ffmpeg
+        .stdin(vspipe.stdout.take().unwrap())
+        .args(["-i", "pipe:"]);
+      Some(vspipe)
+    }
+    Input::Video { path } => {
+      ffmpeg
+        .stdin(Stdio::null())
+        .args(["-r", "1", "-i"])
+        .arg(path);
+      None
+    }
+  };
+  let mut child = ffmpeg
+    .args([
+      "-map",
+      "0:v:0",
+      "-vf",
+      &filters.join(","),
+      "-f",
+      "null",
+      "-",
+    ])
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .context("Failed to run ffmpeg for scene detection")?;
+
+  let (mut scene_changes, mut differences) = (vec![0], Vec::new());
+  let mut frame = 0;
+  for line in BufReader::new(child.stdout.take().unwrap()).lines() {
+    match parse_scdet_line(&line?) {
+      Some(ScdetLine::Frame(n)) => {
+        frame = n;
+        differences.resize(frame + 1, 0.0);
+        callback(frame + 1);
+      }
+      Some(ScdetLine::Difference(mafd)) => {
+        if let Some(difference) = differences.get_mut(frame) {
+          *difference = mafd / 255.0;
+        }
+      }
+      Some(ScdetLine::SceneChange) if frame > 0 => scene_changes.push(frame),
+      _ => {}
+    }
+  }
+
+  let output = child.wait_with_output()?;
+  if !output.status.success() {
+    bail!(
+      "ffmpeg failed to detect the scenes with scdet ({}): {}",
+      output.status,
+      String::from_utf8_lossy(&output.stderr).trim()
+    );
+  }
+  if let Some(vspipe) = &mut vspipe {
+    let status = vspipe.wait()?;
+    if !status.success() {
+      bail!("vspipe failed to read the input for scene detection ({status})");
+    }
+  }
+
+  Ok((scene_changes, differences))
+}
+
+#[derive(Debug, PartialEq)]
+enum ScdetLine {
+  /// The metadata of the frame with this number follows
+  Frame(usize),
+  /// Mean absolute difference to the previous frame, up to 255
+  Difference(f64),
+  /// The frame is a scene change
+  SceneChange,
+}
+
+/// Parses a line printed by the metadata filter after scdet
+fn parse_scdet_line(line: &str) -> Option<ScdetLine> {
+  // frame:42   pts:42      pts_time:1.68
+  // lavfi.scd.mafd=3.141
+  // lavfi.scd.score=0.512
+  // lavfi.scd.time=1.68
+  if let Some(frame) = line.strip_prefix("frame:") {
+    return frame
+      .split_ascii_whitespace()
+      .next()?
+      .parse()
+      .ok()
+      .map(ScdetLine::Frame);
+  }
+  let (key, value) = line.trim().split_once('=')?;
+  match key {
+    "lavfi.scd.mafd" => value.parse().ok().map(ScdetLine::Difference),
+    "lavfi.scd.time" => Some(ScdetLine::SceneChange),
+    _ => None,
+  }
+}
+
+/// Detects the scenes of the input. Returns the scenes, the number of frames,
+/// and the luma difference of each frame to the previous one.
 #[tracing::instrument]
 pub fn av_scenechange_detect(
   input: &Input,
@@ -27,7 +262,7 @@ pub fn av_scenechange_detect(
   sc_method: ScenecutMethod,
   sc_downscale_height: Option<usize>,
   zones: &[Scene],
-) -> anyhow::Result<(Vec<Scene>, usize)> {
+) -> anyhow::Result<(Vec<Scene>, usize, Vec<f64>)> {
   if verbosity != Verbosity::Quiet {
     if std::io::stderr().is_terminal() {
       eprintln!("{}", Style::default().bold().paint("Scene detection"));
@@ -47,15 +282,16 @@ pub fn av_scenechange_detect(
     frames
   });
 
-  let scenes = scene_detect(
+  let (scenes, differences) = scene_detect(
     input,
     encoder,
     total_frames,
     if verbosity == Verbosity::Quiet {
       None
     } else {
-      Some(&|frames| {
+      Some(&|frames, scenes| {
         progress_bar::set_pos(frames as u64);
+        progress_bar::update_bar_scenes(scenes);
       })
     },
     min_scene_len,
@@ -70,23 +306,27 @@ pub fn av_scenechange_detect(
 
   progress_bar::finish_progress_bar();
 
-  Ok((scenes, frames))
+  Ok((scenes, frames, differences))
 }
 
 /// Detect scene changes using rav1e scene detector.
+///
+/// Also returns the luma difference of each frame of the video to the previous
+/// one, which is 0 for the first frame of every zone. `callback` is called
+/// with the number of frames read and of scenes found so far.
 #[allow(clippy::option_if_let_else)]
 pub fn scene_detect(
   input: &Input,
   encoder: Encoder,
   total_frames: usize,
-  callback: Option<&dyn Fn(usize)>,
+  callback: Option<&dyn Fn(usize, usize)>,
   min_scene_len: usize,
   sc_scaler: &str,
   sc_pix_format: Option<Pixel>,
   sc_method: ScenecutMethod,
   sc_downscale_height: Option<usize>,
   zones: &[Scene],
-) -> anyhow::Result<Vec<Scene>> {
+) -> anyhow::Result<(Vec<Scene>, Vec<f64>)> {
   let (mut decoder, bit_depth) = build_decoder(
     input,
     encoder,
@@ -96,6 +336,7 @@ pub fn scene_detect(
   )?;
 
   let mut scenes = Vec::new();
+  let mut differences = Vec::with_capacity(total_frames);
   let mut cur_zone = zones.first().filter(|frame| frame.start_frame == 0);
   let mut next_zone_idx = if zones.is_empty() {
     None
@@ -132,9 +373,11 @@ pub fn scene_detect(
     } else {
       None
     };
+    // the keyframes of the zone include its first frame, which starts a scene
+    let scenes_found = scenes.len();
     let callback = callback.map(|cb| {
-      |frames, _keyframes| {
-        cb(frames + frames_read);
+      move |frames, keyframes| {
+        cb(frames + frames_read, scenes_found + keyframes);
       }
     });
     let sc_result = if bit_depth > 8 {
@@ -142,6 +385,7 @@ pub fn scene_detect(
         &mut decoder,
         options,
         frame_limit,
+        bit_depth,
         callback.as_ref().map(|cb| cb as &dyn Fn(usize, usize)),
       )
     } else {
@@ -149,6 +393,7 @@ pub fn scene_detect(
         &mut decoder,
         options,
         frame_limit,
+        bit_depth,
         callback.as_ref().map(|cb| cb as &dyn Fn(usize, usize)),
       )
     }?;
@@ -167,9 +412,11 @@ pub fn scene_detect(
         start_frame: start + frames_read,
         end_frame: end + frames_read,
         zone_overrides: cur_zone.and_then(|zone| zone.zone_overrides.clone()),
+        cut: scene_cut(&sc_result.differences, start),
       });
     }
 
+    let last_start = scene_changes.last().copied().unwrap_or_default();
     scenes.push(Scene {
       start_frame: scenes
         .last()
@@ -182,7 +429,9 @@ pub fn scene_detect(
         total_frames
       },
       zone_overrides: cur_zone.and_then(|zone| zone.zone_overrides.clone()),
+      cut: scene_cut(&sc_result.differences, last_start),
     });
+    differences.extend_from_slice(&sc_result.differences);
     if let Some(next_idx) = next_zone_idx {
       if cur_zone.map_or(true, |zone| zone.end_frame == zones[next_idx].start_frame) {
         cur_zone = Some(&zones[next_idx]);
@@ -201,7 +450,147 @@ pub fn scene_detect(
       cur_zone = None;
     }
   }
-  Ok(scenes)
+  Ok((scenes, differences))
+}
+
+/// Scene changes of a segment of the video, along with the luma difference of
+/// each frame to the previous one
+struct Detection {
+  scene_changes: Vec<usize>,
+  frame_count: usize,
+  differences: Vec<f64>,
+}
+
+/// Runs the scene change detector of av-scenechange over the decoded frames.
+///
+/// This follows `av_scenechange::detect_scene_changes`, which does not expose
+/// the frames, so that the difference between consecutive frames can be
+/// measured to score the scene changes.
+fn detect_scene_changes<R: Read, T: v_frame::pixel::Pixel>(
+  dec: &mut Decoder<R>,
+  opts: DetectionOptions,
+  frame_limit: Option<usize>,
+  bit_depth: usize,
+  progress_callback: Option<&dyn Fn(usize, usize)>,
+) -> anyhow::Result<Detection> {
+  let mut detector = new_detector::<R, T>(dec, opts)?;
+  let video_details = dec.get_video_details()?;
+  let mut frame_queue: BTreeMap<usize, Arc<Frame<T>>> = BTreeMap::new();
+  let mut keyframes = BTreeSet::new();
+  keyframes.insert(0);
+  let mut differences = Vec::new();
+
+  let mut frameno = 0;
+  loop {
+    let mut next_input_frameno = frame_queue.keys().last().copied().map_or(0, |key| key + 1);
+    while next_input_frameno
+      < (frameno + opts.lookahead_distance + 1).min(frame_limit.unwrap_or(usize::MAX))
+    {
+      let Ok(frame) = dec.read_video_frame(&video_details) else {
+        // End of input
+        break;
+      };
+      let difference = next_input_frameno
+        .checked_sub(1)
+        .and_then(|previous| frame_queue.get(&previous))
+        .map_or(0.0, |previous| luma_difference(previous, &frame, bit_depth));
+      differences.push(difference);
+      frame_queue.insert(next_input_frameno, Arc::new(frame));
+      next_input_frameno += 1;
+    }
+
+    // The frame_queue should start at whatever the previous frame was
+    let frame_set = frame_queue
+      .values()
+      .take(opts.lookahead_distance + 2)
+      .collect::<Vec<_>>();
+    if frame_set.len() < 2 {
+      // End of video
+      break;
+    }
+    if frameno == 0
+      || detector.analyze_next_frame(
+        &frame_set,
+        frameno as u64,
+        *keyframes
+          .iter()
+          .last()
+          .expect("at least 1 keyframe should exist"),
+      )
+    {
+      keyframes.insert(frameno as u64);
+    }
+
+    if frameno > 0 {
+      frame_queue.remove(&(frameno - 1));
+    }
+
+    frameno += 1;
+    if let Some(progress_fn) = progress_callback {
+      progress_fn(frameno, keyframes.len());
+    }
+    if frame_limit == Some(frameno) {
+      break;
+    }
+  }
+
+  Ok(Detection {
+    scene_changes: keyframes.into_iter().map(|val| val as usize).collect(),
+    frame_count: frameno,
+    differences,
+  })
+}
+
+/// Mean absolute difference of the luma of two frames, sampled on a sparse
+/// grid, relative to the maximum value of the bit depth
+fn luma_difference<T: v_frame::pixel::Pixel>(a: &Frame<T>, b: &Frame<T>, bit_depth: usize) -> f64 {
+  const STEP: usize = 4;
+
+  let (mut sum, mut count) = (0_u64, 0_u64);
+  for (row_a, row_b) in a.planes[0]
+    .rows_iter()
+    .zip(b.planes[0].rows_iter())
+    .step_by(STEP)
+  {
+    for (&x, &y) in row_a.iter().zip(row_b).step_by(STEP) {
+      let (x, y): (u32, u32) = (x.into(), y.into());
+      sum += u64::from(x.abs_diff(y));
+      count += 1;
+    }
+  }
+
+  sum as f64 / count.max(1) as f64 / f64::from((1_u32 << bit_depth) - 1)
+}
+
+/// Scores the scene change at `frame` of a segment by how much the difference
+/// to the previous frame stands out from the differences around it. Returns
+/// `None` for the start of the segment, which is not a detected scene change.
+fn scene_cut(differences: &[f64], frame: usize) -> Option<SceneCut> {
+  /// Frames on either side of the scene change the difference is compared to
+  const RADIUS: usize = 5;
+
+  if frame == 0 || frame >= differences.len() {
+    return None;
+  }
+
+  let cost = differences[frame];
+  let neighbors: Vec<f64> = (frame.saturating_sub(RADIUS).max(1)
+    ..(frame + RADIUS + 1).min(differences.len()))
+    .filter(|&i| i != frame)
+    .map(|i| differences[i])
+    .collect();
+  let baseline = if neighbors.is_empty() {
+    0.0
+  } else {
+    neighbors.iter().sum::<f64>() / neighbors.len() as f64
+  };
+  let confidence = if cost > 0.0 {
+    (1.0 - baseline / cost).clamp(0.0, 1.0)
+  } else {
+    0.0
+  };
+
+  Some(SceneCut { cost, confidence })
 }
 
 #[tracing::instrument]
@@ -237,6 +626,7 @@ fn build_decoder(
 
       if !filters.is_empty() || !vspipe_args.is_empty() {
         let mut command = Command::new("vspipe");
+        cgroup::apply(&mut command);
         command
           .arg("-c")
           .arg("y4m")
@@ -250,8 +640,10 @@ fn build_decoder(
           command.args(["-a", &arg]);
         }
         let vspipe = command.spawn()?.stdout.unwrap();
+        let mut ffmpeg = Command::new("ffmpeg");
+        cgroup::apply(&mut ffmpeg);
         Decoder::Y4m(y4m::Decoder::new(
-          Command::new("ffmpeg")
+          ffmpeg
             .stdin(vspipe)
             .args(["-i", "pipe:", "-f", "yuv4mpegpipe", "-strict", "-1"])
             .args(filters)
@@ -271,8 +663,10 @@ fn build_decoder(
         .unwrap_or_else(|e| panic!("FFmpeg failed to get pixel format for input video: {e:?}"));
       bit_depth = encoder.get_format_bit_depth(sc_pix_format.unwrap_or(input_pix_format))?;
       if !filters.is_empty() {
+        let mut ffmpeg = Command::new("ffmpeg");
+        cgroup::apply(&mut ffmpeg);
         Decoder::Y4m(y4m::Decoder::new(
-          Command::new("ffmpeg")
+          ffmpeg
             .args(["-r", "1", "-i"])
             .arg(path)
             .args(filters.as_ref())
@@ -292,3 +686,44 @@ fn build_decoder(
 
   Ok((decoder, bit_depth))
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn scene_cut_confidence() {
+    // a hard cut at frame 5, and a fade from frame 11 on
+    let mut differences = vec![
+      0.0, 0.01, 0.01, 0.01, 0.01, 0.4, 0.01, 0.01, 0.01, 0.01, 0.01,
+    ];
+    differences.extend([0.05; 11]);
+
+    let cut = scene_cut(&differences, 5).unwrap();
+    assert!((cut.cost - 0.4).abs() < f64::EPSILON);
+    assert!(cut.confidence > 0.9);
+
+    let fade = scene_cut(&differences, 16).unwrap();
+    assert!(fade.confidence < 0.1);
+
+    assert_eq!(scene_cut(&differences, 0), None);
+    assert_eq!(scene_cut(&differences, differences.len()), None);
+  }
+
+  #[test]
+  fn scdet_metadata() {
+    assert_eq!(
+      parse_scdet_line("frame:42   pts:42      pts_time:1.68"),
+      Some(ScdetLine::Frame(42))
+    );
+    assert_eq!(
+      parse_scdet_line("lavfi.scd.mafd=3.5"),
+      Some(ScdetLine::Difference(3.5))
+    );
+    assert_eq!(
+      parse_scdet_line("lavfi.scd.time=1.68"),
+      Some(ScdetLine::SceneChange)
+    );
+    assert_eq!(parse_scdet_line("lavfi.scd.score=0.512"), None);
+  }
+}
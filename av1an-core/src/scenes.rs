@@ -1,8 +1,8 @@
 use std::collections::HashMap;
-use std::process::{exit, Command};
 use std::str::FromStr;
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, ensure, Result};
+use ffmpeg::format::Pixel;
 use itertools::Itertools;
 use nom::branch::alt;
 use nom::bytes::complete::{tag, take_till, take_while};
@@ -12,9 +12,11 @@ use nom::multi::{many1, separated_list0};
 use nom::sequence::{preceded, tuple};
 use serde::{Deserialize, Serialize};
 
+use crate::chunk::pixel_name;
+use crate::concat::ConcatMethod;
 use crate::context::Av1anContext;
-use crate::parse::valid_params;
-use crate::settings::{invalid_params, suggest_fix};
+use crate::encoder_profile::{EncoderProfile, InvalidParam};
+use crate::target_quality::ChunkTarget;
 use crate::Encoder;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -23,6 +25,21 @@ pub struct Scene {
   // Reminding again that end_frame is *exclusive*
   pub end_frame: usize,
   pub zone_overrides: Option<ZoneOptions>,
+  /// The scene change this scene starts with, if it was found by scene detection
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub cut: Option<SceneCut>,
+}
+
+/// Scores of a scene change found by scene detection, which distinguish hard
+/// cuts from gradual transitions such as fades
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct SceneCut {
+  /// Mean absolute difference of the luma of the frames on either side of the
+  /// scene change, relative to the maximum value of the bit depth
+  pub cost: f64,
+  /// How much the difference stands out from the frames around the scene
+  /// change, from 0 for a gradual transition to 1 for a hard cut
+  pub confidence: f64,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -31,15 +48,43 @@ pub struct ZoneOptions {
   pub passes: u8,
   pub video_params: Vec<String>,
   pub photon_noise: Option<u8>,
+  #[serde(default)]
+  pub photon_noise_size: (Option<u32>, Option<u32>),
+  #[serde(default)]
+  pub chroma_noise: bool,
+  #[serde(default)]
+  pub target_quality: ChunkTarget,
   pub extra_splits_len: Option<usize>,
   pub min_scene_len: usize,
+  /// Pixel format of the zone, if it is not the one of the encode
+  #[serde(default, skip_serializing_if = "Option::is_none", with = "pixel_name")]
+  pub pix_format: Option<Pixel>,
+  /// Whether the zone keeps the frames of the source, encoded near-losslessly
+  #[serde(default)]
+  pub skip: bool,
+  /// Strength of the `hqdn3d` filter denoising the frames of the zone before
+  /// they are encoded
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub denoise: Option<f64>,
+}
+
+/// Photon noise strength synthesized per unit of denoise strength, when a zone
+/// denoises its frames without setting `--photon-noise` itself
+const PHOTON_NOISE_PER_DENOISE: f64 = 2.0;
+
+/// Returns the photon noise strength replacing the grain removed by denoising
+/// with the given strength
+fn denoise_photon_noise(strength: f64) -> u8 {
+  (strength * PHOTON_NOISE_PER_DENOISE)
+    .round()
+    .clamp(1.0, 64.0) as u8
 }
 
 impl Scene {
   pub fn parse_from_zone(input: &str, context: &Av1anContext) -> Result<Self> {
     let (_, (start, _, end, _, encoder, reset, zone_args)): (
       _,
-      (usize, _, usize, _, Encoder, bool, &str),
+      (usize, _, usize, _, Option<Encoder>, bool, &str),
     ) = tuple::<_, _, nom::error::Error<&str>, _>((
       map_res(digit1, str::parse),
       many1(char(' ')),
@@ -51,7 +96,8 @@ impl Scene {
         }
       }),
       many1(char(' ')),
-      map_res(
+      // `skip` keeps the frames of the source instead of naming an encoder
+      map(
         alt((
           tag("aom"),
           tag("rav1e"),
@@ -59,8 +105,9 @@ impl Scene {
           tag("x265"),
           tag("vpx"),
           tag("svt-av1"),
+          tag("skip"),
         )),
-        Encoder::from_str,
+        |res: &str| Encoder::from_str(res).ok(),
       ),
       map(
         opt(preceded(many1(char(' ')), tag("reset"))),
@@ -78,6 +125,36 @@ impl Scene {
     if start >= context.frames || end > context.frames {
       bail!("Start and end frames must not be past the end of the video");
     }
+    let Some(encoder) = encoder else {
+      ensure!(
+        !reset && zone_args.is_empty(),
+        "Skip zones keep the frames of the source, and take no reset or options"
+      );
+      // the frames are encoded near-losslessly by the encoder of the encode, so
+      // that the chunks are concatenated like the others
+      return Ok(Self {
+        start_frame: start,
+        end_frame: end,
+        zone_overrides: Some(ZoneOptions {
+          encoder: context.args.encoder,
+          passes: 1,
+          video_params: context
+            .args
+            .encoder
+            .near_lossless_params(context.args.video_params.clone()),
+          photon_noise: None,
+          photon_noise_size: (None, None),
+          chroma_noise: false,
+          target_quality: ChunkTarget::Disabled,
+          extra_splits_len: context.args.extra_splits_len,
+          min_scene_len: context.args.min_scene_len,
+          pix_format: None,
+          skip: true,
+          denoise: None,
+        }),
+        cut: None,
+      });
+    };
     if encoder.format() != context.args.encoder.format() {
       bail!(
         "Zone specifies using {}, but this cannot be used in the same file as {}",
@@ -85,23 +162,12 @@ impl Scene {
         context.args.encoder,
       );
     }
-    if encoder != context.args.encoder {
-      if encoder
-        .get_format_bit_depth(context.args.output_pix_format.format)
-        .is_err()
-      {
-        bail!(
-          "Output pixel format {:?} is not supported by {} (used in zones file)",
-          context.args.output_pix_format.format,
-          encoder
-        );
-      }
-      if !reset {
-        bail!("Zone includes encoder change but previous args were kept. You probably meant to specify \"reset\".");
-      }
+    if encoder != context.args.encoder && !reset {
+      bail!("Zone includes encoder change but previous args were kept. You probably meant to specify \"reset\".");
     }
 
-    // Inherit from encode args or reset to defaults
+    // Inherit from encode args or reset to defaults. Scene detection settings are
+    // not affected by `reset`, as they do not change how a chunk is encoded.
     let mut video_params = if reset {
       Vec::new()
     } else {
@@ -112,14 +178,33 @@ impl Scene {
     } else {
       context.args.passes
     };
-    let mut photon_noise = if reset {
-      None
+    let (mut photon_noise, mut photon_noise_size, mut chroma_noise) = if reset {
+      (None, (None, None), false)
     } else {
-      context.args.photon_noise
+      (
+        context.args.photon_noise,
+        context.args.photon_noise_size,
+        context.args.chroma_noise,
+      )
+    };
+    let mut target_quality = if reset {
+      ChunkTarget::Disabled
+    } else {
+      ChunkTarget::Inherit
     };
     let mut extra_splits_len = context.args.extra_splits_len;
     let mut min_scene_len = context.args.min_scene_len;
 
+    // `--chroma-noise` is the only flag without a value, which the parser below
+    // would otherwise mistake for the value of the preceding option
+    if zone_args.split(' ').any(|arg| arg == "--chroma-noise") {
+      chroma_noise = true;
+    }
+    let zone_args = zone_args
+      .split(' ')
+      .filter(|&arg| arg != "--chroma-noise")
+      .join(" ");
+
     // Parse overrides
     let zone_args: (&str, Vec<(&str, Option<&str>)>) =
       separated_list0::<_, _, _, nom::error::Error<&str>, _, _>(
@@ -131,16 +216,80 @@ impl Scene {
           ))),
           opt(preceded(alt((space1, tag("="))), take_while(|c| c != ' '))),
         )),
-      )(zone_args)
+      )(zone_args.trim())
       .map_err(|e| anyhow!("Invalid zone file syntax: {}", e))?;
     let mut zone_args = zone_args.1.into_iter().collect::<HashMap<_, _>>();
     if let Some(zone_passes) = zone_args.remove("--passes") {
       passes = zone_passes.unwrap().parse().unwrap();
+      ensure!(
+        (1..=encoder.max_passes()).contains(&passes),
+        "{encoder} supports 1 to {} passes, not {passes}",
+        encoder.max_passes()
+      );
     } else if [Encoder::aom, Encoder::vpx].contains(&encoder) && zone_args.contains_key("--rt") {
       passes = 1;
     }
-    if let Some(zone_photon_noise) = zone_args.remove("--photon-noise") {
-      photon_noise = Some(zone_photon_noise.unwrap().parse().unwrap());
+    let mut pix_format = None;
+    if let Some(zone_pix_format) = zone_args.remove("--pix-format") {
+      let zone_pix_format = zone_pix_format.unwrap();
+      let format: Pixel = zone_pix_format
+        .parse()
+        .map_err(|_| anyhow!("Zone specifies an unknown pixel format {zone_pix_format}"))?;
+      if format != context.args.output_pix_format.format {
+        if context.args.concat == ConcatMethod::FFmpeg {
+          bail!(
+            "Zone changes the pixel format to {zone_pix_format}, but the chunks cannot be concatenated with ffmpeg if their pixel formats differ, use --concat mkvmerge or ivf"
+          );
+        }
+        pix_format = Some(format);
+      }
+    }
+    let format = pix_format.unwrap_or(context.args.output_pix_format.format);
+    if (encoder != context.args.encoder || pix_format.is_some())
+      && encoder.get_format_bit_depth(format).is_err()
+    {
+      bail!(
+        "Output pixel format {:?} is not supported by {} (used in zones file)",
+        format,
+        encoder
+      );
+    }
+    let zone_photon_noise = zone_args.remove("--photon-noise");
+    if let Some(zone_photon_noise) = zone_photon_noise {
+      // 0 disables it, like for the encode
+      photon_noise = Some(zone_photon_noise.unwrap().parse().unwrap()).filter(|&noise| noise != 0);
+    }
+    let mut denoise = None;
+    if let Some(zone_denoise) = zone_args.remove("--denoise") {
+      let strength = zone_denoise
+        .and_then(|strength| strength.parse::<f64>().ok())
+        .filter(|&strength| strength > 0.0 && strength.is_finite())
+        .ok_or_else(|| {
+          anyhow!("Zone specifies an invalid --denoise strength, expected a positive number")
+        })?;
+      denoise = Some(strength);
+      // the grain removed from the frames is synthesized back by the encoder,
+      // unless the zone sets the photon noise itself
+      if zone_photon_noise.is_none()
+        && [Encoder::aom, Encoder::rav1e, Encoder::svt_av1].contains(&encoder)
+      {
+        photon_noise = Some(denoise_photon_noise(strength));
+      }
+    }
+    if let Some(zone_width) = zone_args.remove("--photon-noise-width") {
+      photon_noise_size.0 = Some(zone_width.unwrap().parse().unwrap());
+    }
+    if let Some(zone_height) = zone_args.remove("--photon-noise-height") {
+      photon_noise_size.1 = Some(zone_height.unwrap().parse().unwrap());
+    }
+    if chroma_noise && photon_noise.is_none() {
+      bail!("Zone enables --chroma-noise without --photon-noise");
+    }
+    if let Some(zone_target) = zone_args.remove("--target-quality") {
+      if context.args.target_quality.is_none() {
+        bail!("Zone specifies --target-quality, but target quality is not enabled for the encode");
+      }
+      target_quality = ChunkTarget::Target(zone_target.unwrap().parse()?);
     }
     if let Some(zone_xs) = zone_args
       .remove("-x")
@@ -170,37 +319,18 @@ impl Scene {
     };
 
     if !context.args.force {
-      let help_text = {
-        let [cmd, arg] = encoder.help_command();
-        String::from_utf8(Command::new(cmd).arg(arg).output().unwrap().stdout).unwrap()
-      };
-      let valid_params = valid_params(&help_text, encoder);
-      let interleaved_args: Vec<&str> = raw_zone_args
-        .iter()
-        .filter_map(|param| {
-          if param.starts_with('-') && [Encoder::aom, Encoder::vpx].contains(&encoder) {
-            // These encoders require args to be passed using an equal sign,
-            // e.g. `--cq-level=30`
-            param.split('=').next()
-          } else {
-            // The other encoders use a space, so we don't need to do extra splitting,
-            // e.g. `--crf 30`
-            None
-          }
-        })
-        .collect();
-      let invalid_params = invalid_params(&interleaved_args, &valid_params);
-
-      for wrong_param in &invalid_params {
-        eprintln!("'{wrong_param}' isn't a valid parameter for {encoder}");
-        if let Some(suggestion) = suggest_fix(wrong_param, &valid_params) {
-          eprintln!("\tDid you mean '{suggestion}'?");
-        }
+      let (invalid_params, unlisted_values): (Vec<_>, Vec<_>) = EncoderProfile::get(encoder)
+        .check_params(&raw_zone_args)
+        .into_iter()
+        .partition(InvalidParam::is_unknown_param);
+      for unlisted in &unlisted_values {
+        warn!("{unlisted}");
       }
-
       if !invalid_params.is_empty() {
-        println!("\nTo continue anyway, run av1an with '--force'");
-        exit(1);
+        bail!(
+          "{}\n\nTo continue anyway, run av1an with '--force'",
+          invalid_params.iter().join("\n")
+        );
       }
     }
 
@@ -235,46 +365,83 @@ impl Scene {
         passes,
         video_params,
         photon_noise,
+        photon_noise_size,
+        chroma_noise,
+        target_quality,
         extra_splits_len,
         min_scene_len,
+        pix_format,
+        skip: false,
+        denoise,
       }),
+      cut: None,
     })
   }
 }
 
 #[cfg(test)]
-fn get_test_args() -> Av1anContext {
+pub fn get_test_args() -> Av1anContext {
   use std::path::PathBuf;
 
   use ffmpeg::format::Pixel;
 
   use crate::concat::ConcatMethod;
+  use crate::scene_detect::ScBackend;
   use crate::settings::{EncodeArgs, InputPixelFormat, PixelFormat};
   use crate::{
-    into_vec, ChunkMethod, ChunkOrdering, Input, ScenecutMethod, SplitMethod, Verbosity,
+    into_vec, BestSourceCacheMode, ChunkMethod, ChunkOrdering, Input, PipeMode, ScenecutMethod,
+    SplitMethod, Verbosity,
   };
 
   let args = EncodeArgs {
     log_file: PathBuf::new(),
+    status_dir: PathBuf::new(),
+    notify_url: None,
+    notify_desktop: false,
     ffmpeg_filter_args: Vec::new(),
+    fps: None,
+    fps_interpolate: false,
+    deinterlace: None,
     temp: String::new(),
+    temp_on: None,
+    max_temp_size: None,
+    ignore_disk_space: false,
+    min_free_space: None,
     force: false,
     passes: 2,
     video_params: into_vec!["--cq-level=40", "--cpu-used=0", "--aq-mode=1"],
+    auto_params: false,
+    output_res: None,
+    dolby_vision: false,
     output_file: String::new(),
     audio_params: Vec::new(),
+    no_audio: false,
+    audio_only: None,
     chunk_method: ChunkMethod::LSMASH,
+    bestsource_cachemode: BestSourceCacheMode::Always,
+    gpu_devices: Vec::new(),
+    pipe_mode: PipeMode::Auto,
     chunk_order: ChunkOrdering::Random,
     concat: ConcatMethod::FFmpeg,
+    webm: false,
+    chunk_command: Vec::new(),
+    no_concat: false,
+    verify_output: None,
+    progressive_concat: false,
     encoder: Encoder::aom,
+    intermediate: None,
     extra_splits_len: Some(100),
+    extra_splits_adaptive: false,
     photon_noise: Some(10),
     photon_noise_size: (None, None),
     chroma_noise: false,
     sc_pix_format: None,
     keep: false,
+    keep_chunk_logs: false,
     max_tries: 3,
+    checkpoint_frames: None,
     min_scene_len: 10,
+    min_keyframe_interval: None,
     input_pix_format: InputPixelFormat::FFmpeg {
       format: Pixel::YUV420P10LE,
     },
@@ -286,21 +453,41 @@ fn get_test_args() -> Av1anContext {
       bit_depth: 10,
     },
     resume: false,
+    remux: false,
     scenes: None,
     split_method: SplitMethod::AvScenechange,
     sc_method: ScenecutMethod::Standard,
+    sc_backend: ScBackend::AvScenechange,
     sc_only: false,
+    benchmark: None,
+    preview_chunks: None,
+    super_chunks: None,
     sc_downscale_height: None,
+    sc_proxy: None,
     force_keyframes: Vec::new(),
+    force_keyframes_from_chapters: false,
     target_quality: None,
+    quick_consistency: false,
+    allocate_bitrate: None,
+    max_chunk_bitrate: None,
     vmaf: false,
+    quality_report: false,
     verbosity: Verbosity::Normal,
     workers: 1,
     set_thread_affinity: None,
+    priority: None,
+    io_priority: None,
+    cgroup: false,
+    max_memory: None,
+    schedule: None,
+    power_draw: None,
+    energy_price: None,
     zones: None,
     scaler: String::new(),
     ignore_frame_mismatch: false,
     vmaf_path: None,
+    vmaf_model_auto: None,
+    vmaf_neg: false,
     vmaf_res: "1920x1080".to_string(),
     vmaf_threads: None,
     vmaf_filter: None,
@@ -309,6 +496,12 @@ fn get_test_args() -> Av1anContext {
     vs_script: None,
     frames: 6900,
     args,
+    source_frame_rate: once_cell::sync::OnceCell::new(),
+    redundant_ffmpeg_pipe: false,
+    qtgmc: None,
+    script_filters: None,
+    fallback_script: once_cell::sync::OnceCell::new(),
+    filtered_frames: once_cell::sync::OnceCell::new(),
   }
 }
 
@@ -471,3 +664,220 @@ fn validate_zones_no_args_reset() {
   assert_eq!(zone_overrides.photon_noise, None);
   assert!(zone_overrides.video_params.is_empty());
 }
+
+#[test]
+fn validate_zones_invalid_params() {
+  let args = get_test_args();
+  // the values listed by the help are only warned about
+  assert!(Scene::parse_from_zone("0 100 aom reset --end-usage=qq", &args).is_ok());
+
+  let error = Scene::parse_from_zone("0 100 aom reset --cq-levle=20", &args).unwrap_err();
+  assert!(error.to_string().contains("Did you mean '--cq-level'?"));
+  assert!(error.to_string().contains("--force"));
+}
+
+#[test]
+fn validate_zones_reset_av1an_options() {
+  let input = "729 1337 aom reset --cq-level=20";
+  let mut args = get_test_args();
+  args.args.photon_noise_size = (Some(1280), Some(720));
+  args.args.chroma_noise = true;
+  let result = Scene::parse_from_zone(input, &args).unwrap();
+
+  let zone_overrides = result.zone_overrides.unwrap();
+  assert_eq!(zone_overrides.passes, Encoder::aom.get_default_pass());
+  assert_eq!(zone_overrides.photon_noise, None);
+  assert_eq!(zone_overrides.photon_noise_size, (None, None));
+  assert!(!zone_overrides.chroma_noise);
+  assert_eq!(zone_overrides.target_quality, ChunkTarget::Disabled);
+  assert_eq!(
+    zone_overrides.video_params,
+    vec!["--cq-level=20".to_owned()]
+  );
+}
+
+#[test]
+fn validate_zones_inherit_av1an_options() {
+  let input = "729 1337 aom --cq-level=20";
+  let mut args = get_test_args();
+  args.args.photon_noise_size = (Some(1280), Some(720));
+  args.args.chroma_noise = true;
+  let result = Scene::parse_from_zone(input, &args).unwrap();
+
+  let zone_overrides = result.zone_overrides.unwrap();
+  assert_eq!(zone_overrides.passes, 2);
+  assert_eq!(zone_overrides.photon_noise, Some(10));
+  assert_eq!(zone_overrides.photon_noise_size, (Some(1280), Some(720)));
+  assert!(zone_overrides.chroma_noise);
+  assert_eq!(zone_overrides.target_quality, ChunkTarget::Inherit);
+}
+
+#[test]
+fn validate_zones_reenable_after_reset() {
+  let input = "729 1337 aom reset --photon-noise 8 --chroma-noise --photon-noise-width 1280 \
+               --photon-noise-height 720 --cq-level=20";
+  let args = get_test_args();
+  let result = Scene::parse_from_zone(input, &args).unwrap();
+
+  let zone_overrides = result.zone_overrides.unwrap();
+  assert_eq!(zone_overrides.photon_noise, Some(8));
+  assert_eq!(zone_overrides.photon_noise_size, (Some(1280), Some(720)));
+  assert!(zone_overrides.chroma_noise);
+  assert_eq!(
+    zone_overrides.video_params,
+    vec!["--cq-level=20".to_owned()]
+  );
+}
+
+#[test]
+fn validate_zones_chroma_noise_without_photon_noise() {
+  let input = "729 1337 aom reset --chroma-noise --cq-level=20";
+  let args = get_test_args();
+  let result = Scene::parse_from_zone(input, &args);
+  assert_eq!(
+    result.err().unwrap().to_string(),
+    "Zone enables --chroma-noise without --photon-noise"
+  );
+}
+
+#[test]
+fn validate_zones_pix_format() {
+  let input = "729 1337 aom --pix-format yuv420p --passes 1";
+  let mut args = get_test_args();
+  let result = Scene::parse_from_zone(input, &args);
+  assert_eq!(
+    result.err().unwrap().to_string(),
+    "Zone changes the pixel format to yuv420p, but the chunks cannot be concatenated with ffmpeg if their pixel formats differ, use --concat mkvmerge or ivf"
+  );
+
+  // the pixel format of the encode is not an override
+  let result = Scene::parse_from_zone("729 1337 aom --pix-format yuv420p10le", &args).unwrap();
+  assert_eq!(result.zone_overrides.unwrap().pix_format, None);
+
+  args.args.concat = ConcatMethod::MKVMerge;
+  let result = Scene::parse_from_zone(input, &args).unwrap();
+  let zone_overrides = result.zone_overrides.unwrap();
+  assert_eq!(zone_overrides.pix_format, Some(Pixel::YUV420P));
+  assert_eq!(zone_overrides.passes, 1);
+  assert!(!zone_overrides
+    .video_params
+    .iter()
+    .any(|param| param.contains("pix")));
+
+  let result = Scene::parse_from_zone("729 1337 aom --pix-format rgb24", &args);
+  assert!(result.is_err());
+}
+
+#[test]
+fn validate_zones_target_quality() {
+  use std::path::PathBuf;
+
+  use ffmpeg::format::Pixel;
+
+  use crate::encoder::RateParam;
+  use crate::target_quality::TargetQuality;
+  use crate::vmaf::FrameWeighting;
+  use crate::ProbingStatistic;
+
+  let input = "729 1337 aom reset --target-quality 90 --cpu-used=5";
+  let mut args = get_test_args();
+  let result = Scene::parse_from_zone(input, &args);
+  assert_eq!(
+    result.err().unwrap().to_string(),
+    "Zone specifies --target-quality, but target quality is not enabled for the encode"
+  );
+
+  args.args.target_quality = Some(TargetQuality {
+    vmaf_res: "1920x1080".to_string(),
+    probe_res: None,
+    vmaf_scaler: "bicubic".to_string(),
+    vmaf_filter: None,
+    vmaf_threads: 0,
+    model: None::<PathBuf>,
+    model_auto: None,
+    vmaf_neg: false,
+    probing_rate: 4,
+    probes: 4,
+    target: 95.0,
+    ssimu2_target: None,
+    rate: RateParam::Q,
+    min_q: 10,
+    max_q: 50,
+    encoder: Encoder::aom,
+    pix_format: Pixel::YUV420P10LE,
+    temp: String::new(),
+    workers: 1,
+    video_params: Vec::new(),
+    vspipe_args: Vec::new(),
+    probe_slow: false,
+    probe_workers: 0,
+    probe_parallel: false,
+    probe_warm_start: false,
+    probe_verify: None,
+    verify_chunks: None,
+    smoothing: None,
+    probing_statistic: ProbingStatistic::default(),
+    frame_weighting: FrameWeighting::default(),
+  });
+  let result = Scene::parse_from_zone(input, &args).unwrap();
+  let zone_overrides = result.zone_overrides.unwrap();
+  assert_eq!(zone_overrides.target_quality, ChunkTarget::Target(90.0));
+  assert_eq!(zone_overrides.video_params, vec!["--cpu-used=5".to_owned()]);
+}
+
+#[test]
+fn validate_zones_skip() {
+  use crate::into_vec;
+
+  let near_lossless: Vec<String> = into_vec![
+    "--cq-level=1",
+    "--cpu-used=0",
+    "--aq-mode=1",
+    "--end-usage=q"
+  ];
+  let input = "729 1337 skip";
+  let mut args = get_test_args();
+  args.args.photon_noise = Some(8);
+  let result = Scene::parse_from_zone(input, &args).unwrap();
+  assert_eq!(result.start_frame, 729);
+  assert_eq!(result.end_frame, 1337);
+
+  let zone_overrides = result.zone_overrides.unwrap();
+  assert!(zone_overrides.skip);
+  assert_eq!(zone_overrides.encoder, Encoder::aom);
+  assert_eq!(zone_overrides.passes, 1);
+  assert_eq!(zone_overrides.photon_noise, None);
+  assert_eq!(zone_overrides.target_quality, ChunkTarget::Disabled);
+  assert_eq!(zone_overrides.video_params, near_lossless);
+
+  assert!(Scene::parse_from_zone("729 1337 skip reset", &args).is_err());
+  assert!(Scene::parse_from_zone("729 1337 skip --cq-level=20", &args).is_err());
+}
+
+#[test]
+fn validate_zones_denoise() {
+  let args = get_test_args();
+
+  // the grain removed by the denoising is synthesized back
+  let result = Scene::parse_from_zone("729 1337 aom --denoise 4", &args).unwrap();
+  let zone_overrides = result.zone_overrides.unwrap();
+  assert_eq!(zone_overrides.denoise, Some(4.0));
+  assert_eq!(zone_overrides.photon_noise, Some(8));
+  assert!(zone_overrides
+    .video_params
+    .iter()
+    .all(|param| param != "--denoise"));
+
+  // unless the zone sets the photon noise itself
+  let result = Scene::parse_from_zone("729 1337 aom --denoise 4 --photon-noise 0", &args).unwrap();
+  let zone_overrides = result.zone_overrides.unwrap();
+  assert_eq!(zone_overrides.denoise, Some(4.0));
+  assert_eq!(zone_overrides.photon_noise, None);
+
+  // zones without --denoise pass the frames through untouched
+  let result = Scene::parse_from_zone("729 1337 aom --cq-level=20", &args).unwrap();
+  assert_eq!(result.zone_overrides.unwrap().denoise, None);
+
+  assert!(Scene::parse_from_zone("729 1337 aom --denoise 0", &args).is_err());
+  assert!(Scene::parse_from_zone("729 1337 aom --denoise strong", &args).is_err());
+}
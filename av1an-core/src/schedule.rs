@@ -0,0 +1,164 @@
+//! Daily windows of local time in which chunks are encoded, so that an encode
+//! can run unattended e.g. only overnight.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use chrono::{Local, Timelike};
+use serde::{Deserialize, Serialize};
+
+use crate::status::{self, State};
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// How often paused workers check whether a window has started
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Whether the workers are paused, so that it is only logged once
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Windows of `(start, end)` minutes after midnight, in local time. A window
+/// whose end is before its start spans midnight, and an end of 24:00 is kept as
+/// 1440, so that a window can span the whole day.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+  windows: Vec<(u16, u16)>,
+}
+
+impl FromStr for Schedule {
+  type Err = String;
+
+  /// Parses windows of the form `HH:MM-HH:MM`, separated by commas
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let windows = s
+      .split(',')
+      .map(|window| {
+        let (start, end) = window
+          .split_once('-')
+          .ok_or_else(|| format!("expected a window of the form HH:MM-HH:MM, got {window:?}"))?;
+        let (start, end) = (parse_time(start)? % MINUTES_PER_DAY, parse_time(end)?);
+        if start == end {
+          return Err(format!("window {window:?} is empty"));
+        }
+        Ok((start, end))
+      })
+      .collect::<Result<_, _>>()?;
+
+    Ok(Self { windows })
+  }
+}
+
+fn parse_time(time: &str) -> Result<u16, String> {
+  let (hours, minutes) = time
+    .trim()
+    .split_once(':')
+    .ok_or_else(|| format!("expected a time of the form HH:MM, got {time:?}"))?;
+  match (hours.parse::<u16>(), minutes.parse::<u16>()) {
+    // 24:00 is allowed as the end of the day
+    (Ok(hours), Ok(minutes)) if (hours < 24 && minutes < 60) || (hours == 24 && minutes == 0) => {
+      Ok(hours * 60 + minutes)
+    }
+    _ => Err(format!("invalid time {time:?}")),
+  }
+}
+
+fn format_time(minutes: u16) -> String {
+  format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+impl Display for Schedule {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let windows: Vec<_> = self
+      .windows
+      .iter()
+      .map(|&(start, end)| format!("{}-{}", format_time(start), format_time(end)))
+      .collect();
+    f.write_str(&windows.join(","))
+  }
+}
+
+impl Schedule {
+  /// Whether a time, in minutes after midnight, is within one of the windows
+  pub fn allows(&self, minutes: u16) -> bool {
+    self.windows.iter().any(|&(start, end)| {
+      if start < end {
+        (start..end).contains(&minutes)
+      } else {
+        minutes >= start || minutes < end
+      }
+    })
+  }
+
+  /// Returns the start of the next window after a time outside the windows
+  fn next_start(&self, minutes: u16) -> u16 {
+    self
+      .windows
+      .iter()
+      .map(|&(start, _)| start)
+      .min_by_key(|&start| (start + MINUTES_PER_DAY - minutes) % MINUTES_PER_DAY)
+      .unwrap_or(minutes)
+  }
+
+  /// Blocks the calling worker until the local time is within one of the windows
+  pub fn wait(&self) {
+    loop {
+      let now = Local::now();
+      let minutes = (now.hour() * 60 + now.minute()) as u16;
+      if self.allows(minutes) {
+        if PAUSED.swap(false, Ordering::Relaxed) {
+          info!("schedule window started, resuming encoding");
+          status::set_state(State::Encoding);
+        }
+        return;
+      }
+
+      if !PAUSED.swap(true, Ordering::Relaxed) {
+        info!(
+          "outside of the schedule {}, pausing until {}",
+          self,
+          format_time(self.next_start(minutes))
+        );
+        status::set_state(State::Paused);
+      }
+      thread::sleep(POLL_INTERVAL);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn schedule_windows() {
+    let schedule: Schedule = "22:00-07:00, 12:30-13:00".parse().unwrap();
+    assert_eq!(schedule.to_string(), "22:00-07:00,12:30-13:00");
+
+    assert!(schedule.allows(23 * 60));
+    assert!(schedule.allows(0));
+    assert!(schedule.allows(6 * 60 + 59));
+    assert!(!schedule.allows(7 * 60));
+    assert!(schedule.allows(12 * 60 + 45));
+    assert!(!schedule.allows(13 * 60));
+    assert!(!schedule.allows(21 * 60 + 59));
+
+    assert_eq!(schedule.next_start(8 * 60), 12 * 60 + 30);
+    assert_eq!(schedule.next_start(14 * 60), 22 * 60);
+
+    let schedule: Schedule = "18:00-24:00".parse().unwrap();
+    assert!(schedule.allows(23 * 60 + 59));
+    assert!(!schedule.allows(0));
+    assert_eq!(schedule.to_string(), "18:00-24:00");
+
+    let schedule: Schedule = "00:00-24:00".parse().unwrap();
+    assert!((0..MINUTES_PER_DAY).all(|minutes| schedule.allows(minutes)));
+
+    assert!("22:00".parse::<Schedule>().is_err());
+    assert!("22:00-22:00".parse::<Schedule>().is_err());
+    assert!("25:00-07:00".parse::<Schedule>().is_err());
+    assert!("22:60-07:00".parse::<Schedule>().is_err());
+  }
+}
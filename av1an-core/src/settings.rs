@@ -1,23 +1,33 @@
-use std::borrow::{Borrow, Cow};
 use std::cmp::Ordering;
-use std::collections::HashSet;
 use std::path::{Path, PathBuf};
-use std::process::{exit, Command};
+use std::process::exit;
 
-use anyhow::{bail, ensure};
+use anyhow::{bail, ensure, Context};
+use ffmpeg::codec;
 use ffmpeg::format::Pixel;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
-use crate::concat::ConcatMethod;
-use crate::encoder::Encoder;
-use crate::parse::valid_params;
+use crate::auto_params::{auto_arguments, SourceInfo};
+use crate::bit_allocation::BitAllocation;
+use crate::concat::{is_mp4, ConcatMethod};
+use crate::encoder::{Encoder, RateParam};
+use crate::encoder_profile::{EncoderProfile, InvalidParam};
+use crate::ffmpeg::{changes_frame_count, filtered_resolution, FrameRate};
+use crate::interlace::Deinterlace;
+use crate::intermediate::Intermediate;
+use crate::scene_detect::ScBackend;
+use crate::schedule::Schedule;
 use crate::target_quality::TargetQuality;
 use crate::vapoursynth::{
   is_bestsource_installed, is_dgdecnv_installed, is_ffms2_installed, is_lsmash_installed,
 };
-use crate::vmaf::validate_libvmaf;
-use crate::{ChunkMethod, ChunkOrdering, Input, ScenecutMethod, SplitMethod, Verbosity};
+use crate::verify::VerifyOutput;
+use crate::vmaf::{validate_libvmaf, VmafModelAuto};
+use crate::{
+  remote, tiles_for_resolution, BestSourceCacheMode, ChunkMethod, ChunkOrdering, Input, IoPriority,
+  PipeMode, ProcessPriority, ScenecutMethod, SplitMethod, Verbosity,
+};
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct PixelFormat {
@@ -32,33 +42,62 @@ pub enum InputPixelFormat {
 }
 
 #[allow(clippy::struct_excessive_bools)]
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct EncodeArgs {
   pub input: Input,
   pub temp: String,
+  pub temp_on: Option<PathBuf>,
+  pub max_temp_size: Option<u64>,
+  pub ignore_disk_space: bool,
+  pub min_free_space: Option<u64>,
   pub output_file: String,
 
   pub chunk_method: ChunkMethod,
+  pub bestsource_cachemode: BestSourceCacheMode,
+  pub gpu_devices: Vec<usize>,
+  pub pipe_mode: PipeMode,
   pub chunk_order: ChunkOrdering,
   pub scaler: String,
   pub scenes: Option<PathBuf>,
   pub split_method: SplitMethod,
   pub sc_pix_format: Option<Pixel>,
   pub sc_method: ScenecutMethod,
+  pub sc_backend: ScBackend,
   pub sc_only: bool,
+  pub benchmark: Option<usize>,
+  pub preview_chunks: Option<Vec<usize>>,
+  pub super_chunks: Option<usize>,
   pub sc_downscale_height: Option<usize>,
+  pub sc_proxy: Option<Input>,
   pub extra_splits_len: Option<usize>,
+  pub extra_splits_adaptive: bool,
   pub min_scene_len: usize,
+  pub min_keyframe_interval: Option<usize>,
   pub force_keyframes: Vec<usize>,
+  pub force_keyframes_from_chapters: bool,
   pub ignore_frame_mismatch: bool,
 
   pub max_tries: usize,
+  /// Chunks longer than this are encoded again in parts of at most this many
+  /// frames when the encoder fails on them
+  pub checkpoint_frames: Option<usize>,
 
   pub passes: u8,
   pub video_params: Vec<String>,
+  pub auto_params: bool,
+  pub output_res: Option<(u32, u32)>,
+  pub dolby_vision: bool,
   pub encoder: Encoder,
+  pub intermediate: Option<Intermediate>,
   pub workers: usize,
   pub set_thread_affinity: Option<usize>,
+  pub priority: Option<ProcessPriority>,
+  pub io_priority: Option<IoPriority>,
+  pub cgroup: bool,
+  pub max_memory: Option<u64>,
+  pub schedule: Option<Schedule>,
+  pub power_draw: Option<f64>,
+  pub energy_price: Option<f64>,
   pub photon_noise: Option<u8>,
   pub photon_noise_size: (Option<u32>, Option<u32>), // Width and Height
   pub chroma_noise: bool,
@@ -66,27 +105,78 @@ pub struct EncodeArgs {
 
   // FFmpeg params
   pub ffmpeg_filter_args: Vec<String>,
+  pub fps: Option<FrameRate>,
+  pub fps_interpolate: bool,
+  pub deinterlace: Option<Deinterlace>,
   pub audio_params: Vec<String>,
+  pub no_audio: bool,
+  /// Existing encode whose video is muxed with the audio of the input, without
+  /// encoding the video
+  pub audio_only: Option<PathBuf>,
   pub input_pix_format: InputPixelFormat,
   pub output_pix_format: PixelFormat,
 
   pub verbosity: Verbosity,
   pub log_file: PathBuf,
+  pub status_dir: PathBuf,
+  /// URL the summary of the encode is posted to when it finishes or fails,
+  /// which is not saved with the settings as it may contain a token
+  #[serde(skip)]
+  pub notify_url: Option<String>,
+  pub notify_desktop: bool,
   pub resume: bool,
+  pub remux: bool,
   pub keep: bool,
+  pub keep_chunk_logs: bool,
   pub force: bool,
 
   pub concat: ConcatMethod,
+  pub webm: bool,
+  pub chunk_command: Vec<String>,
+  pub no_concat: bool,
+  pub verify_output: Option<VerifyOutput>,
+  pub progressive_concat: bool,
   pub target_quality: Option<TargetQuality>,
+  pub quick_consistency: bool,
+  pub allocate_bitrate: Option<BitAllocation>,
+  pub max_chunk_bitrate: Option<f64>,
   pub vmaf: bool,
+  pub quality_report: bool,
   pub vmaf_path: Option<PathBuf>,
+  pub vmaf_model_auto: Option<VmafModelAuto>,
+  pub vmaf_neg: bool,
   pub vmaf_res: String,
   pub vmaf_threads: Option<usize>,
   pub vmaf_filter: Option<String>,
 }
 
 impl EncodeArgs {
+  /// Returns the resolution of the encoded video: `--output-res` if it is set,
+  /// or else the resolution of the input after the filters of `--ffmpeg`
+  pub fn output_resolution(&self) -> anyhow::Result<(u32, u32)> {
+    if let Some(resolution) = self.output_res {
+      return Ok(resolution);
+    }
+    let input = self.input.resolution()?;
+    Ok(filtered_resolution(&self.ffmpeg_filter_args, input).unwrap_or(input))
+  }
+
+  /// Returns the folder of the index caches of the chunk methods, which is
+  /// on the storage of `--temp-on` if it is set
+  pub fn index_dir(&self) -> PathBuf {
+    let temp = Path::new(&self.temp);
+    self.temp_on.as_ref().map_or_else(
+      || temp.join("split"),
+      |dir| dir.join(temp.file_name().unwrap_or(temp.as_os_str())),
+    )
+  }
+
   pub fn validate(&mut self) -> anyhow::Result<()> {
+    self.validate_paths()?;
+    if self.input.is_stdin() {
+      self.validate_stdin()?;
+    }
+
     if self.concat == ConcatMethod::Ivf
       && !matches!(
         self.encoder,
@@ -96,18 +186,127 @@ impl EncodeArgs {
       bail!(".ivf only supports VP8, VP9, and AV1");
     }
 
+    if self.webm {
+      self.validate_webm()?;
+    }
+
     ensure!(self.max_tries > 0);
+    if self.checkpoint_frames.is_some() {
+      ensure!(
+        self.fps.is_none(),
+        "--checkpoint-frames cannot be used with --fps, which changes the number of frames of the chunks"
+      );
+      ensure!(
+        !changes_frame_count(&self.ffmpeg_filter_args),
+        "--checkpoint-frames cannot be used with filters of --ffmpeg that change the number of frames of the chunks"
+      );
+    }
+
+    if let Some(chunks) = self.benchmark {
+      ensure!(chunks > 0, "--benchmark requires at least one chunk");
+    }
+
+    if let Some(chunks) = &self.preview_chunks {
+      ensure!(
+        !chunks.is_empty(),
+        "--preview-chunks requires at least one chunk"
+      );
+    }
+
+    if let Some(size) = self.super_chunks {
+      ensure!(
+        size > 0,
+        "--super-chunks requires at least one chunk per super-chunk"
+      );
+    }
+
+    ensure!(
+      !self.force_keyframes_from_chapters || self.input.is_video(),
+      "--force-keyframes-from-chapters reads the chapters of a video, and cannot be used with a VapourSynth script"
+    );
+
+    if let Some(interval) = self.min_keyframe_interval {
+      ensure!(
+        interval > 0,
+        "--min-keyframe-interval must be at least 1 frame"
+      );
+      if let Some(split_len @ 1..) = self.extra_splits_len {
+        ensure!(
+          interval <= split_len,
+          "--min-keyframe-interval ({interval}) cannot be longer than --extra-split ({split_len})"
+        );
+      }
+    }
+
+    if let Some(intermediate) = self.intermediate {
+      ensure!(
+        self.encoder == intermediate.encoder() && self.passes == 1,
+        "--intermediate encodes the chunks in 1 pass, and their encoder cannot be set"
+      );
+      ensure!(
+        self.target_quality.is_none(),
+        "--intermediate is lossless, and cannot be used with --target-quality"
+      );
+      ensure!(
+        self.concat != ConcatMethod::Ivf,
+        "The chunks of --intermediate are Matroska files, which cannot be concatenated with --concat ivf"
+      );
+    }
+
+    if let Some(dir) = &self.temp_on {
+      ensure!(
+        dir.is_dir(),
+        "The folder of --temp-on {} does not exist",
+        dir.display()
+      );
+    }
+    if self.max_temp_size.is_some() {
+      ensure!(
+        self.chunk_method == ChunkMethod::Hybrid,
+        "--max-temp-size requires --chunk-method hybrid, whose segments are split while the chunks are encoded"
+      );
+      ensure!(
+        self.super_chunks.is_some(),
+        "--max-temp-size requires --super-chunks, which deletes the chunks once they are concatenated"
+      );
+      ensure!(
+        !self.resume,
+        "--max-temp-size cannot be used when resuming, as the segments of finished super-chunks were deleted"
+      );
+      ensure!(
+        cfg!(unix),
+        "--max-temp-size is only supported on Unix, where the segmenting can be paused"
+      );
+    }
+
+    if let Some(watts) = self.power_draw {
+      ensure!(watts > 0.0, "--power-draw must be positive");
+    }
+    if let Some(price) = self.energy_price {
+      ensure!(price >= 0.0, "--energy-price must not be negative");
+    }
 
     ensure!(
-      self.input.as_path().exists(),
+      self.input.is_stdin() || self.input.is_remote() || self.input.as_path().exists(),
       "Input file {:?} does not exist!",
       self.input
     );
 
-    if self.target_quality.is_some() {
+    if self.target_quality.is_some() || self.allocate_bitrate.is_some() || self.quality_report {
       validate_libvmaf()?;
     }
 
+    if self.input.as_path().to_str().is_some_and(remote::is_url) {
+      ensure!(
+        self.input.is_video(),
+        "VapourSynth scripts cannot be read over HTTP"
+      );
+      ensure!(
+        which::which("curl").is_ok(),
+        "curl not found, which is needed for inputs read over HTTP. Is it installed in system path?"
+      );
+    }
+
     if which::which("ffmpeg").is_err() {
       bail!("FFmpeg not found. Is it installed in system path?");
     }
@@ -116,6 +315,76 @@ impl EncodeArgs {
       bail!("mkvmerge not found, but `--concat mkvmerge` was specified. Is it installed in system path?");
     }
 
+    if self.dolby_vision {
+      ensure!(
+        self.encoder == Encoder::x265,
+        "--dolby-vision is only supported with x265"
+      );
+      ensure!(
+        self.input.is_video(),
+        "--dolby-vision requires a video input to extract the RPU from"
+      );
+      ensure!(
+        which::which("dovi_tool").is_ok(),
+        "dovi_tool not found, but --dolby-vision was specified. Is it installed in system path?"
+      );
+    }
+
+    if self.deinterlace == Some(Deinterlace::Qtgmc) {
+      ensure!(
+        self.input.is_video()
+          && matches!(
+            self.chunk_method,
+            ChunkMethod::LSMASH | ChunkMethod::FFMS2 | ChunkMethod::DGDECNV | ChunkMethod::BESTSOURCE
+          ),
+        "--deinterlace qtgmc requires a video input with a VapourSynth chunk method (lsmash, ffms2, dgdecnv or bestsource)"
+      );
+    }
+
+    if let Some(video) = &self.audio_only {
+      ensure!(
+        self.input.is_video(),
+        "--audio-only requires a video input, as the audio of VapourSynth scripts is not supported"
+      );
+      ensure!(
+        video.is_file(),
+        "The video of --audio-only {} does not exist",
+        video.display()
+      );
+      ensure!(
+        resolve_path(video) != resolve_path(Path::new(&self.output_file)),
+        "The video of --audio-only {} is the output file, which would be overwritten",
+        video.display()
+      );
+    }
+
+    if self.quick_consistency {
+      ensure!(
+        self.target_quality.is_none(),
+        "--quick-consistency cannot be used with --target-quality"
+      );
+      ensure!(
+        which::which("x264").is_ok(),
+        "x264 not found, but --quick-consistency was specified. Is it installed in system path?"
+      );
+    }
+
+    if let Some(kbps) = self.max_chunk_bitrate {
+      ensure!(kbps > 0.0, "--max-chunk-bitrate must be positive");
+    }
+
+    if let Some(allocation) = &self.allocate_bitrate {
+      ensure!(allocation.kbps > 0.0, "--allocate-bitrate must be positive");
+      ensure!(
+        self.target_quality.is_none() && !self.quick_consistency,
+        "--allocate-bitrate cannot be used with --target-quality or --quick-consistency"
+      );
+      ensure!(
+        allocation.probes.min_q <= allocation.probes.max_q,
+        "--min-q must not be higher than --max-q"
+      );
+    }
+
     if self.encoder == Encoder::x265 && self.concat != ConcatMethod::MKVMerge {
       bail!("mkvmerge is required for concatenating x265, as x265 outputs raw HEVC bitstream files without the timestamps correctly set, which FFmpeg cannot concatenate \
 properly into a mkv file. Specify mkvmerge as the concatenation method by setting `--concat mkvmerge`.");
@@ -133,6 +402,10 @@ properly into a mkv file. Specify mkvmerge as the concatenation method by settin
         "FFMS2 is not installed, but it was specified as the chunk method"
       );
     }
+    ensure!(
+      self.gpu_devices.is_empty() || self.chunk_method == ChunkMethod::DGDECNV,
+      "--gpu-devices is only used by --chunk-method dgdecnv"
+    );
     if self.chunk_method == ChunkMethod::DGDECNV && which::which("dgindexnv").is_err() {
       ensure!(
         is_dgdecnv_installed(),
@@ -156,6 +429,12 @@ properly into a mkv file. Specify mkvmerge as the concatenation method by settin
     if let Some(vmaf_path) = &self
       .target_quality
       .as_ref()
+      .or_else(|| {
+        self
+          .allocate_bitrate
+          .as_ref()
+          .map(|allocation| &allocation.probes)
+      })
       .and_then(|tq| tq.model.as_ref())
     {
       ensure!(vmaf_path.exists());
@@ -167,20 +446,95 @@ properly into a mkv file. Specify mkvmerge as the concatenation method by settin
       }
 
       ensure!(target_quality.min_q >= 1);
+
+      if target_quality.ssimu2_target.is_some() {
+        ensure!(
+          which::which("ssimulacra2_rs").is_ok(),
+          "ssimulacra2_rs not found, but a SSIMULACRA2 target was specified. Is it installed in \
+           system path?"
+        );
+      }
+
+      if target_quality.rate == RateParam::Bitrate {
+        ensure!(
+          target_quality.min_q < target_quality.max_q,
+          "--min-bitrate must be lower than --max-bitrate"
+        );
+        ensure!(
+          self.max_chunk_bitrate.is_none(),
+          "--max-chunk-bitrate cannot be used with --tq-rate bitrate, which already sets the \
+           bitrate of each chunk"
+        );
+      }
+
+      if let Some(probe_res) = &target_quality.probe_res {
+        ensure!(
+          probe_res
+            .split_once('x')
+            .is_some_and(|(w, h)| w.parse::<u32>().is_ok() && h.parse::<u32>().is_ok()),
+          "--probe-res must be in the form WIDTHxHEIGHT, e.g. 1920x1080"
+        );
+      }
+
+      if let Some(tolerance) = target_quality.probe_verify {
+        ensure!(
+          tolerance >= 0.0,
+          "--probe-verify tolerance must not be negative"
+        );
+      }
+
+      if let Some(tolerance) = target_quality.verify_chunks {
+        ensure!(
+          tolerance >= 0.0,
+          "--verify-chunks tolerance must not be negative"
+        );
+      }
     }
 
+    // the ffv1 intermediate is encoded by ffmpeg, which was checked above
     let encoder_bin = self.encoder.bin();
-    if which::which(encoder_bin).is_err() {
+    if self.intermediate != Some(Intermediate::ffv1) && which::which(encoder_bin).is_err() {
       bail!(
         "Encoder {} not found. Is it installed in the system path?",
         encoder_bin
       );
     }
 
+    if self.output_res.is_none() && !self.input.is_stdin() {
+      if let Ok(input) = self.input.resolution() {
+        if filtered_resolution(&self.ffmpeg_filter_args, input).is_none() {
+          warn!(
+            "the resolution after the filters of --ffmpeg could not be determined, set it with \
+             --output-res; the input resolution {}x{} is used instead",
+            input.0, input.1
+          );
+        }
+      }
+    }
+
     if self.video_params.is_empty() {
-      self.video_params = self
-        .encoder
-        .get_default_arguments(self.input.calculate_tiles());
+      let resolution = self.output_resolution();
+      self.video_params = if self.auto_params {
+        let info = SourceInfo::probe(&self.input, resolution?)?;
+        if info.hdr && self.output_pix_format.bit_depth < 10 {
+          warn!(
+            "--auto-params: the source is HDR, but it is encoded in {:?}, which has {} bits; \
+             set --pix-format to a 10-bit format to keep its range",
+            self.output_pix_format.format, self.output_pix_format.bit_depth
+          );
+        }
+        auto_arguments(self.encoder, &info)
+      } else {
+        self
+          .encoder
+          .get_default_arguments(resolution.map_or((1, 1), tiles_for_resolution))
+      };
+      let profile = EncoderProfile::get(self.encoder);
+      profile.extend_defaults(&mut self.video_params);
+      profile.retain_supported(&mut self.video_params);
+      if self.auto_params {
+        info!("--auto-params: {}", self.video_params.join(" "));
+      }
     }
 
     if let Some(strength) = self.photon_noise {
@@ -199,11 +553,60 @@ properly into a mkv file. Specify mkvmerge as the concatenation method by settin
         .iter()
         .any(|param| param == "--enable-keyframe-filtering=2")
     {
-      bail!(
+      // MP4 outputs are concatenated with mkvmerge and then remuxed by ffmpeg
+      ensure!(
+        self.concat == ConcatMethod::FFmpeg
+          && is_mp4(Path::new(&self.output_file))
+          && which::which("mkvmerge").is_ok(),
         "keyframe filtering mode 2 currently only works when using mkvmerge as the concat method"
       );
+      warn!(
+        "keyframe filtering mode 2 produces broken files when concatenating with ffmpeg, \
+         concatenating with mkvmerge and remuxing into MP4 instead"
+      );
+      self.concat = ConcatMethod::MKVMerge;
     }
 
+    if is_mp4(Path::new(&self.output_file)) {
+      ensure!(
+        self.concat != ConcatMethod::Ivf,
+        "--concat ivf writes IVF files, and cannot output MP4"
+      );
+      ensure!(
+        !self.dolby_vision,
+        "--dolby-vision injects the RPU with mkvmerge, and cannot output MP4"
+      );
+    }
+
+    ensure!(
+      self.sc_backend == ScBackend::AvScenechange
+        || matches!(self.split_method, SplitMethod::AvScenechange),
+      "--sc-backend is only used by --split-method av-scenechange"
+    );
+    if self.sc_backend != ScBackend::AvScenechange {
+      ensure!(
+        matches!(self.sc_method, ScenecutMethod::Standard),
+        "--sc-method is only used by --sc-backend av-scenechange"
+      );
+      ensure!(
+        self.sc_pix_format.is_none(),
+        "--sc-pix-format is only used by --sc-backend av-scenechange, {} detects the scenes on \
+         the luma of the frames",
+        self.sc_backend
+      );
+    }
+    ensure!(
+      !matches!(self.split_method, SplitMethod::SourceKeyframes) || self.input.is_video(),
+      "--split-method source-keyframes needs a video input, as VapourSynth scripts have no keyframes"
+    );
+
+    ensure!(
+      self.passes <= self.encoder.max_passes(),
+      "{} supports at most {} passes",
+      self.encoder,
+      self.encoder.max_passes()
+    );
+
     if matches!(self.encoder, Encoder::aom | Encoder::vpx)
       && self.passes != 1
       && self.video_params.iter().any(|param| param == "--rt")
@@ -212,7 +615,8 @@ properly into a mkv file. Specify mkvmerge as the concatenation method by settin
       self.passes = 1;
     }
 
-    if !self.force {
+    // the parameters of the lossless intermediates are not set by --video-params
+    if !self.force && self.intermediate.is_none() {
       self.validate_encoder_params();
       self.check_rate_control();
     }
@@ -220,39 +624,159 @@ properly into a mkv file. Specify mkvmerge as the concatenation method by settin
     Ok(())
   }
 
-  fn validate_encoder_params(&self) {
-    let video_params: Vec<&str> = self
-      .video_params
+  /// Ensures that no option needs the whole input before it is encoded, as a
+  /// stream read from stdin is encoded while it is read
+  fn validate_stdin(&self) -> anyhow::Result<()> {
+    let conflicts = [
+      (self.resume, "--resume"),
+      (self.remux, "--remux"),
+      (self.scenes.is_some(), "--scenes"),
+      (self.zones.is_some(), "--zones"),
+      (self.sc_only, "--sc-only"),
+      (self.sc_proxy.is_some(), "--sc-proxy"),
+      (
+        matches!(self.split_method, SplitMethod::SourceKeyframes),
+        "--split-method source-keyframes",
+      ),
+      (self.sc_backend != ScBackend::AvScenechange, "--sc-backend"),
+      (!self.force_keyframes.is_empty(), "--force-keyframes"),
+      (
+        self.force_keyframes_from_chapters,
+        "--force-keyframes-from-chapters",
+      ),
+      (self.benchmark.is_some(), "--benchmark"),
+      (self.preview_chunks.is_some(), "--preview-chunks"),
+      (self.super_chunks.is_some(), "--super-chunks"),
+      (self.progressive_concat, "--progressive-concat"),
+      (self.verify_output.is_some(), "--verify-output"),
+      (self.auto_params, "--auto-params"),
+      (self.dolby_vision, "--dolby-vision"),
+      (self.fps.is_some(), "--fps"),
+      (self.deinterlace.is_some(), "--deinterlace"),
+      (self.audio_only.is_some(), "--audio-only"),
+      (self.allocate_bitrate.is_some(), "--allocate-bitrate"),
+      (self.vmaf, "--vmaf"),
+      (self.quality_report, "--quality-report"),
+    ];
+    if let Some((_, option)) = conflicts.iter().find(|(set, _)| *set) {
+      bail!("{option} cannot be used when the input is read from stdin with -i -");
+    }
+
+    Ok(())
+  }
+
+  /// Ensures that the encoder, the concatenation and the audio of `--webm` can
+  /// be muxed into WebM
+  fn validate_webm(&self) -> anyhow::Result<()> {
+    ensure!(
+      matches!(
+        self.encoder,
+        Encoder::aom | Encoder::rav1e | Encoder::svt_av1 | Encoder::vpx
+      ),
+      "--webm only supports the VP9 and AV1 encoders (aom, rav1e, svt-av1 and vpx)"
+    );
+    ensure!(
+      self.concat == ConcatMethod::FFmpeg,
+      "--webm concatenates the chunks with ffmpeg, and cannot be used with --concat {}",
+      self.concat
+    );
+    ensure!(
+      self.intermediate.is_none(),
+      "--webm cannot be used with --intermediate, whose codecs cannot be muxed into WebM"
+    );
+    ensure!(
+      Path::new(&self.output_file)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("webm")),
+      "--webm requires the output to have a .webm extension, got {}",
+      self.output_file
+    );
+    let codecs = audio_codecs(&self.audio_params).collect::<Vec<_>>();
+    if let Some(codec) = codecs
       .iter()
-      .filter_map(|param| {
-        if param.starts_with('-') && [Encoder::aom, Encoder::vpx].contains(&self.encoder) {
-          // These encoders require args to be passed using an equal sign,
-          // e.g. `--cq-level=30`
-          param.split('=').next()
-        } else {
-          // The other encoders use a space, so we don't need to do extra splitting,
-          // e.g. `--crf 30`
-          None
-        }
-      })
-      .collect();
-
-    let help_text = {
-      let [cmd, arg] = self.encoder.help_command();
-      String::from_utf8(Command::new(cmd).arg(arg).output().unwrap().stdout).unwrap()
-    };
-    let valid_params = valid_params(&help_text, self.encoder);
-    let invalid_params = invalid_params(&video_params, &valid_params);
-
-    for wrong_param in &invalid_params {
-      eprintln!(
-        "'{}' isn't a valid parameter for {}",
-        wrong_param, self.encoder,
-      );
-      if let Some(suggestion) = suggest_fix(wrong_param, &valid_params) {
-        eprintln!("\tDid you mean '{suggestion}'?");
+      .find(|codec| !["libopus", "opus", "libvorbis", "vorbis", "copy"].contains(codec))
+    {
+      bail!("--webm only supports Opus and Vorbis audio, but -a/--audio-params sets {codec}");
+    }
+    // the audio is copied unless a codec is set, so it must already be Opus or
+    // Vorbis, which is only known for files
+    let copied = codecs.is_empty() || codecs.contains(&"copy");
+    if copied
+      && !self.no_audio
+      && self.input.is_video()
+      && !self.input.is_stdin()
+      && !self.input.is_remote()
+    {
+      let path = self.input.as_video_path();
+      let source_codecs = crate::ffmpeg::audio_stream_codecs(path)
+        .with_context(|| format!("Failed to read the audio streams of {}", path.display()))?;
+      if let Some(codec) = source_codecs
+        .into_iter()
+        .find(|&codec| !matches!(codec, codec::Id::OPUS | codec::Id::VORBIS))
+      {
+        bail!(
+          "--webm only supports Opus and Vorbis audio, but the audio of the input is {} and is \
+           copied, set an audio codec with -a/--audio-params, e.g. -a \"-c:a libopus -b:a 128k\"",
+          codec.name()
+        );
       }
     }
+    Ok(())
+  }
+
+  /// Ensures that neither the output nor the deletion of the temporary folder
+  /// can destroy the input, which is checked before anything is overwritten
+  pub fn validate_paths(&self) -> anyhow::Result<()> {
+    let input = resolve_path(self.input.as_path());
+    let output = resolve_path(Path::new(&self.output_file));
+    let temp = resolve_path(Path::new(&self.temp));
+
+    ensure!(
+      output != input,
+      "The output file {} is the input file, which would be overwritten",
+      output.display()
+    );
+    // this includes the index files of the chunk methods, which are kept in the temporary folder
+    ensure!(
+      !output.starts_with(&temp),
+      "The output file {} is within the temporary folder {}, which is deleted after encoding",
+      output.display(),
+      temp.display()
+    );
+    ensure!(
+      !input.starts_with(&temp),
+      "The input file {} is within the temporary folder {}, which is deleted before encoding",
+      input.display(),
+      temp.display()
+    );
+    if let Some(proxy) = &self.sc_proxy {
+      ensure!(
+        proxy.as_path().exists(),
+        "The scene detection proxy {} does not exist",
+        proxy.as_path().display()
+      );
+      ensure!(
+        !resolve_path(proxy.as_path()).starts_with(&temp),
+        "The scene detection proxy {} is within the temporary folder {}, which is deleted before encoding",
+        proxy.as_path().display(),
+        temp.display()
+      );
+    }
+
+    Ok(())
+  }
+
+  fn validate_encoder_params(&self) {
+    let (invalid_params, unlisted_values): (Vec<_>, Vec<_>) = EncoderProfile::get(self.encoder)
+      .check_params(&self.video_params)
+      .into_iter()
+      .partition(InvalidParam::is_unknown_param);
+    for unlisted in &unlisted_values {
+      warn!("{unlisted}");
+    }
+    for invalid in &invalid_params {
+      eprintln!("{invalid}");
+    }
 
     if !invalid_params.is_empty() {
       println!("\nTo continue anyway, run av1an with '--force'");
@@ -308,33 +832,53 @@ properly into a mkv file. Specify mkvmerge as the concatenation method by settin
   }
 }
 
-#[must_use]
-pub(crate) fn invalid_params<'a>(
-  params: &'a [&'a str],
-  valid_options: &'a HashSet<Cow<'a, str>>,
-) -> Vec<&'a str> {
-  params
-    .iter()
-    .filter(|param| !valid_options.contains(Borrow::<str>::borrow(&**param)))
-    .copied()
-    .collect()
+/// Returns the codecs ffmpeg audio arguments set, for all or some audio streams
+fn audio_codecs(params: &[String]) -> impl Iterator<Item = &str> {
+  params.windows(2).filter_map(|pair| {
+    let option = pair[0].split(':').next().unwrap_or_default();
+    let stream_type = pair[0].split(':').nth(1);
+    (matches!(option, "-c" | "-codec") && matches!(stream_type, None | Some("a"))
+      || option == "-acodec")
+      .then_some(pair[1].as_str())
+  })
+}
+
+/// Returns the canonical path, or that of its parent for a file that does not exist yet
+fn resolve_path(path: &Path) -> PathBuf {
+  if let Ok(path) = path.canonicalize() {
+    return path;
+  }
+
+  match (path.parent(), path.file_name()) {
+    (Some(parent), Some(name)) => {
+      let parent = if parent.as_os_str().is_empty() {
+        Path::new(".")
+      } else {
+        parent
+      };
+      parent
+        .canonicalize()
+        .map_or_else(|_| path.to_path_buf(), |parent| parent.join(name))
+    }
+    _ => path.to_path_buf(),
+  }
 }
 
 #[must_use]
 pub(crate) fn suggest_fix<'a>(
   wrong_arg: &str,
-  arg_dictionary: &'a HashSet<Cow<'a, str>>,
+  arg_dictionary: impl IntoIterator<Item = &'a str>,
 ) -> Option<&'a str> {
   // Minimum threshold to consider a suggestion similar enough that it could be a typo
   const MIN_THRESHOLD: f64 = 0.75;
 
   arg_dictionary
-    .iter()
+    .into_iter()
     .map(|arg| (arg, strsim::jaro_winkler(arg, wrong_arg)))
     .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(Ordering::Less))
     .and_then(|(suggestion, score)| {
       if score > MIN_THRESHOLD {
-        Some(suggestion.borrow())
+        Some(suggestion)
       } else {
         None
       }
@@ -376,3 +920,25 @@ pub(crate) fn insert_noise_table_params(
     _ => unimplemented!("This encoder does not support grain synth through av1an"),
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::into_vec;
+
+  #[test]
+  fn audio_codecs_of_params() {
+    let params: Vec<String> =
+      into_vec!["-c:a:0", "libopus", "-b:a:0", "128k", "-c:a:1", "aac", "-c:s", "copy"];
+    assert_eq!(
+      audio_codecs(&params).collect::<Vec<_>>(),
+      ["libopus", "aac"]
+    );
+
+    let params: Vec<String> = into_vec!["-c", "copy", "-acodec", "libvorbis"];
+    assert_eq!(
+      audio_codecs(&params).collect::<Vec<_>>(),
+      ["copy", "libvorbis"]
+    );
+  }
+}
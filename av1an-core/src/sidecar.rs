@@ -0,0 +1,170 @@
+//! Settings of an encode, saved next to its output.
+//!
+//! The sidecar contains the resolved settings of the encode along with the
+//! versions of av1an and of the encoder, so that an encode can be reproduced,
+//! or attached to a bug report. `--from-settings` applies the settings of a
+//! sidecar to another input, keeping only what is specific to the input (its
+//! paths, scenes, zones and keyframes) from the command line.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::encoder_profile::EncoderProfile;
+use crate::settings::EncodeArgs;
+use crate::util::retry_io;
+use crate::Input;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Settings {
+  pub av1an_version: String,
+  /// Version of the encoder, if it could be detected
+  pub encoder_version: Option<String>,
+  pub args: EncodeArgs,
+}
+
+/// Same as `Settings`, without taking the arguments of the encode
+#[derive(Serialize)]
+struct SettingsRef<'a> {
+  av1an_version: &'a str,
+  encoder_version: Option<String>,
+  args: &'a EncodeArgs,
+}
+
+/// Returns the path of the sidecar of an output
+pub fn path(output: &Path) -> PathBuf {
+  output.with_extension("settings.json")
+}
+
+fn encoder_version(args: &EncodeArgs) -> Option<String> {
+  let profile = EncoderProfile::get(args.encoder);
+  profile.version.map(|version| {
+    format!(
+      "{} {}{}",
+      args.encoder,
+      version,
+      if profile.psy { " (psy)" } else { "" }
+    )
+  })
+}
+
+/// Writes the settings of the encode next to its output
+pub fn write(args: &EncodeArgs) -> anyhow::Result<()> {
+  let path = path(Path::new(&args.output_file));
+  let settings = SettingsRef {
+    av1an_version: env!("CARGO_PKG_VERSION"),
+    encoder_version: encoder_version(args),
+    args,
+  };
+  let contents = serde_json::to_string_pretty(&settings)?;
+  retry_io(|| fs::write(&path, &contents))
+    .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Reads the settings of a sidecar, warning about version differences that
+/// may change the result
+pub fn read(path: &Path) -> anyhow::Result<Settings> {
+  let contents =
+    fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+  let settings: Settings = serde_json::from_str(&contents).with_context(|| {
+    format!(
+      "Failed to parse {}, it may have been written by an incompatible version of av1an",
+      path.display()
+    )
+  })?;
+
+  if settings.av1an_version != env!("CARGO_PKG_VERSION") {
+    warn!(
+      "{} was written by av1an {}, but this is av1an {}",
+      path.display(),
+      settings.av1an_version,
+      env!("CARGO_PKG_VERSION")
+    );
+  }
+  let installed = encoder_version(&settings.args);
+  if settings.encoder_version.is_some() && installed != settings.encoder_version {
+    warn!(
+      "{} was encoded with {}, but {} is installed",
+      path.display(),
+      settings.encoder_version.as_deref().unwrap_or_default(),
+      installed.as_deref().unwrap_or("an unknown version")
+    );
+  }
+
+  Ok(settings)
+}
+
+/// Applies the settings of a sidecar to the input of `current`, keeping the
+/// paths and the settings of `current` that only make sense for its input
+pub fn replay(settings: EncodeArgs, current: EncodeArgs) -> EncodeArgs {
+  if settings.zones.is_some() || !settings.force_keyframes.is_empty() {
+    warn!(
+      "the zones and forced keyframes of the settings refer to frames of their input, and are not applied"
+    );
+  }
+
+  let target_quality = settings.target_quality.map(|mut tq| {
+    tq.temp.clone_from(&current.temp);
+    tq.vspipe_args = match &current.input {
+      Input::VapourSynth { vspipe_args, .. } => vspipe_args.clone(),
+      Input::Video { .. } => Vec::new(),
+    };
+    tq
+  });
+
+  EncodeArgs {
+    input: current.input,
+    temp: current.temp,
+    output_file: current.output_file,
+    input_pix_format: current.input_pix_format,
+    log_file: current.log_file,
+    status_dir: current.status_dir,
+    notify_url: current.notify_url,
+    notify_desktop: current.notify_desktop,
+    verbosity: current.verbosity,
+    resume: current.resume,
+    remux: current.remux,
+    keep: current.keep,
+    scenes: current.scenes,
+    sc_only: current.sc_only,
+    zones: current.zones,
+    force_keyframes: current.force_keyframes,
+    benchmark: current.benchmark,
+    preview_chunks: current.preview_chunks,
+    audio_only: current.audio_only,
+    target_quality,
+    ..settings
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn settings_replay() {
+    let mut previous = crate::scenes::get_test_args().args;
+    previous.video_params = vec!["--cpu-used=4".to_owned()];
+    previous.workers = 3;
+    let contents = serde_json::to_string(&SettingsRef {
+      av1an_version: env!("CARGO_PKG_VERSION"),
+      encoder_version: None,
+      args: &previous,
+    })
+    .unwrap();
+    let settings: Settings = serde_json::from_str(&contents).unwrap();
+
+    let mut current = crate::scenes::get_test_args().args;
+    current.input = Input::Video {
+      path: PathBuf::from("other.mkv"),
+    };
+    current.output_file = "other_aom.mkv".to_owned();
+    let args = replay(settings.args, current);
+    assert_eq!(args.video_params, ["--cpu-used=4"]);
+    assert_eq!(args.workers, 3);
+    assert_eq!(args.input.as_path(), Path::new("other.mkv"));
+    assert_eq!(args.output_file, "other_aom.mkv");
+  }
+}
@@ -1,18 +1,37 @@
+use std::collections::BTreeSet;
 use std::fs::File;
-use std::io::prelude::*;
 use std::io::BufReader;
 use std::path::Path;
-use std::process::{Command, Stdio};
+use std::process::{Child, Command, Stdio};
 use std::string::ToString;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use std::{cmp, thread};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 use serde::{Deserialize, Serialize};
 
+use crate::chunk::Chunk;
 use crate::scenes::Scene;
+use crate::util::{dir_size, retry_io};
+use crate::Input;
 
-pub fn segment(input: impl AsRef<Path>, temp: impl AsRef<Path>, segments: &[usize]) {
-  let input = input.as_ref();
-  let temp = temp.as_ref();
+/// Whether the segments are still split in the background, with
+/// `--max-temp-size`
+static SPLITTING: AtomicBool = AtomicBool::new(false);
+
+/// Whether the segmenting in the background is to be stopped, as the encode
+/// has ended before it finished
+static STOP_SPLITTING: AtomicBool = AtomicBool::new(false);
+
+/// Number of workers waiting for the segment of their chunk to be split
+static WAITING: AtomicUsize = AtomicUsize::new(0);
+
+/// Interval of the checks of the size of the temporary folder, and of the
+/// segments being split
+const SPLIT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+fn segment_command(input: &Path, temp: &Path, segments: &[usize]) -> Command {
   let mut cmd = Command::new("ffmpeg");
 
   cmd.stdout(Stdio::piped());
@@ -47,10 +66,157 @@ pub fn segment(input: impl AsRef<Path>, temp: impl AsRef<Path>, segments: &[usiz
     let split_path = Path::new(temp).join("split").join("%05d.mkv");
     cmd.arg(split_path);
   }
-  let out = cmd.output().unwrap();
+  cmd
+}
+
+pub fn segment(input: impl AsRef<Path>, temp: impl AsRef<Path>, segments: &[usize]) {
+  let out = segment_command(input.as_ref(), temp.as_ref(), segments)
+    .output()
+    .unwrap();
   assert!(out.status.success(), "FFmpeg failed to segment: {out:#?}");
 }
 
+/// Splits the segments in the background while the chunks are encoded
+///
+/// ffmpeg is paused while the temporary folder is larger than `max_size`,
+/// until finished super-chunks are concatenated and deleted.
+pub fn segment_within(
+  input: &Path,
+  temp: &Path,
+  segments: &[usize],
+  max_size: u64,
+) -> anyhow::Result<()> {
+  let child = segment_command(input, temp, segments)
+    .stdin(Stdio::null())
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .spawn()
+    .context("Failed to spawn ffmpeg to segment the input")?;
+  STOP_SPLITTING.store(false, Ordering::SeqCst);
+  SPLITTING.store(true, Ordering::SeqCst);
+
+  let temp = temp.to_path_buf();
+  thread::spawn(move || {
+    let mut segmenter = Segmenter {
+      child,
+      paused: false,
+    };
+    let status = loop {
+      if STOP_SPLITTING.load(Ordering::SeqCst) {
+        debug!("stopping the segmenting, as the encode has ended");
+        break None;
+      }
+      match segmenter.child.try_wait() {
+        Ok(None) => {}
+        Ok(Some(status)) => break Some(status),
+        Err(e) => {
+          error!("Failed to wait for ffmpeg segmenting the input: {}", e);
+          break None;
+        }
+      }
+
+      // the budget is exceeded rather than stalling the workers waiting for a segment
+      let pause = WAITING.load(Ordering::SeqCst) == 0 && dir_size(&temp) > max_size;
+      if pause != segmenter.paused {
+        if pause {
+          info!("the temporary folder exceeds --max-temp-size, pausing the segmenting");
+        } else {
+          info!("resuming the segmenting");
+        }
+        set_paused(&segmenter.child, pause);
+        segmenter.paused = pause;
+      }
+      thread::sleep(SPLIT_POLL_INTERVAL);
+    };
+
+    if !STOP_SPLITTING.load(Ordering::SeqCst) && !status.is_some_and(|status| status.success()) {
+      error!("FFmpeg failed to segment the input ({:?})", status);
+    }
+  });
+
+  Ok(())
+}
+
+/// Stops the segmenting in the background if it is still running, e.g.
+/// because the encode failed, and waits for ffmpeg to exit
+pub fn stop_segmenting() {
+  if !SPLITTING.load(Ordering::SeqCst) {
+    return;
+  }
+  STOP_SPLITTING.store(true, Ordering::SeqCst);
+  while SPLITTING.load(Ordering::SeqCst) {
+    thread::sleep(Duration::from_millis(50));
+  }
+}
+
+/// The ffmpeg process splitting the segments in the background, which is
+/// resumed and killed when this is dropped before it exited, so that it is
+/// never left stopped
+struct Segmenter {
+  child: Child,
+  paused: bool,
+}
+
+impl Drop for Segmenter {
+  fn drop(&mut self) {
+    if matches!(self.child.try_wait(), Ok(None)) {
+      if self.paused {
+        set_paused(&self.child, false);
+      }
+      self.child.kill().ok();
+      self.child.wait().ok();
+    }
+    SPLITTING.store(false, Ordering::SeqCst);
+  }
+}
+
+#[cfg(unix)]
+fn set_paused(child: &Child, paused: bool) {
+  let signal = if paused { libc::SIGSTOP } else { libc::SIGCONT };
+  // SAFETY: the child has not been waited for, so its pid cannot have been reused
+  unsafe {
+    libc::kill(child.id() as libc::pid_t, signal);
+  }
+}
+
+#[cfg(not(unix))]
+fn set_paused(_child: &Child, _paused: bool) {}
+
+/// Waits until the segment the chunk is encoded from is split, if the
+/// segments are still split in the background
+pub fn wait_for_segment(chunk: &Chunk) {
+  let Input::Video { path } = &chunk.input else {
+    return;
+  };
+  if !SPLITTING.load(Ordering::SeqCst) || is_split(path) {
+    return;
+  }
+
+  debug!(
+    "[chunk {}] waiting for {} to be split",
+    chunk.index,
+    path.display()
+  );
+  WAITING.fetch_add(1, Ordering::SeqCst);
+  while SPLITTING.load(Ordering::SeqCst) && !is_split(path) {
+    thread::sleep(SPLIT_POLL_INTERVAL);
+  }
+  WAITING.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// Whether ffmpeg has finished writing the segment, which it has once it
+/// started writing the next one
+fn is_split(segment: &Path) -> bool {
+  segment
+    .file_stem()
+    .and_then(|stem| stem.to_str()?.parse::<usize>().ok())
+    .is_some_and(|index| {
+      segment
+        .with_file_name(format!("{:05}.mkv", index + 1))
+        .exists()
+    })
+}
+
 pub fn extra_splits(scenes: &[Scene], total_frames: usize, split_size: usize) -> Vec<Scene> {
   let mut new_scenes: Vec<Scene> = Vec::with_capacity(scenes.len());
 
@@ -74,20 +240,91 @@ pub fn extra_splits(scenes: &[Scene], total_frames: usize, split_size: usize) ->
       for n in 1..additional_splits {
         let new_split =
           (distance as f64 * (n as f64 / additional_splits as f64)) as usize + scene.start_frame;
+        let start_frame = new_scenes
+          .last()
+          .map_or(scene.start_frame, |scene| scene.end_frame);
         new_scenes.push(Scene {
-          start_frame: new_scenes
-            .last()
-            .map_or(scene.start_frame, |scene| scene.end_frame),
+          start_frame,
           end_frame: new_split,
+          // only the first part starts at the scene change
+          cut: scene.cut.filter(|_| start_frame == scene.start_frame),
           ..scene.clone()
         });
       }
     }
+    let start_frame = new_scenes
+      .last()
+      .map_or(scene.start_frame, |scene| scene.end_frame);
+    new_scenes.push(Scene {
+      start_frame,
+      end_frame: scene.end_frame,
+      cut: scene.cut.filter(|_| start_frame == scene.start_frame),
+      ..scene.clone()
+    });
+  }
+
+  new_scenes
+}
+
+/// Splits scenes longer than `split_size` like [`extra_splits`], but at the
+/// most static frames instead of at even intervals.
+///
+/// Each split is placed at the frame with the lowest luma difference to the
+/// previous frame, between half of `split_size` and `split_size` frames after
+/// the previous split, so that the keyframe is cheap to encode and falls into a
+/// static section rather than into motion. `differences` are the luma
+/// differences of all frames of the video, as measured by the scene detection.
+pub fn adaptive_extra_splits(
+  scenes: &[Scene],
+  total_frames: usize,
+  split_size: usize,
+  min_scene_len: usize,
+  differences: &[f64],
+) -> Vec<Scene> {
+  if differences.len() < total_frames {
+    return extra_splits(scenes, total_frames, split_size);
+  }
+
+  let mut new_scenes: Vec<Scene> = Vec::with_capacity(scenes.len());
+  for scene in scenes {
+    let (split_size, min_scene_len) =
+      scene
+        .zone_overrides
+        .as_ref()
+        .map_or((split_size, min_scene_len), |ovr| {
+          (
+            ovr.extra_splits_len.unwrap_or(usize::MAX),
+            ovr.min_scene_len,
+          )
+        });
+
+    // every split has to move forward by at least a frame
+    let split_size = split_size.max(1);
+    let mut start_frame = scene.start_frame;
+    while scene.end_frame - start_frame > split_size {
+      let latest = start_frame + split_size;
+      let earliest = (start_frame + cmp::max(split_size / 2, min_scene_len).max(1)).min(latest);
+      // the remainder of the scene should not be shorter than the minimum either
+      let latest = latest
+        .min(scene.end_frame.saturating_sub(min_scene_len))
+        .max(earliest);
+      // the latest of equally static frames, so that the scene is split as few times as possible
+      let split = (earliest..=latest)
+        .min_by(|&a, &b| differences[a].total_cmp(&differences[b]).then(b.cmp(&a)))
+        .unwrap_or(latest);
+
+      new_scenes.push(Scene {
+        start_frame,
+        end_frame: split,
+        cut: scene.cut.filter(|_| start_frame == scene.start_frame),
+        ..scene.clone()
+      });
+      start_frame = split;
+    }
     new_scenes.push(Scene {
-      start_frame: new_scenes
-        .last()
-        .map_or(scene.start_frame, |scene| scene.end_frame),
+      start_frame,
       end_frame: scene.end_frame,
+      cut: scene.cut.filter(|_| start_frame == scene.start_frame),
       ..scene.clone()
     });
   }
@@ -95,12 +332,81 @@ pub fn extra_splits(scenes: &[Scene], total_frames: usize, split_size: usize) ->
   new_scenes
 }
 
+/// Merges the scenes shorter than `min_interval` frames into a neighbouring
+/// scene, so that keyframes are at least `min_interval` frames apart.
+///
+/// Of the two scene changes around a short scene, the one with the lower
+/// confidence is dropped, so that hard cuts are kept over gradual transitions,
+/// and of equally confident ones the one merging the shorter scenes. Scene
+/// changes without scores, e.g. those added by extra splits, count as hard
+/// cuts. The frames of `keep`, such as forced keyframes and the boundaries of
+/// zones, are never dropped, so the scenes between them may stay shorter.
+pub fn merge_short_scenes(scenes: Vec<Scene>, min_interval: usize, keep: &[usize]) -> Vec<Scene> {
+  let len = |scene: &Scene| scene.end_frame - scene.start_frame;
+  let confidence = |scene: &Scene| scene.cut.map_or(1.0, |cut| cut.confidence);
+  let droppable = |scene: &Scene| !keep.contains(&scene.start_frame);
+
+  // only the last merged scene can still be short, as the scene change at its
+  // end is not known until the next scene
+  let mut merged: Vec<Scene> = Vec::with_capacity(scenes.len());
+  for scene in scenes {
+    let mut next = Some(scene);
+    while let (Some(scene), [.., last]) = (&next, &merged[..]) {
+      if len(last) >= min_interval {
+        break;
+      }
+      // the scene change at the start of the short scene, and the one at its end
+      let start = (merged.len() > 1 && droppable(last))
+        .then(|| (confidence(last), len(&merged[merged.len() - 2]) + len(last)));
+      let end = droppable(scene).then(|| (confidence(scene), len(last) + len(scene)));
+      let drop_end = match (start, end) {
+        (Some(start), Some(end)) => end.0.total_cmp(&start.0).then(end.1.cmp(&start.1)).is_lt(),
+        (Some(_), None) => false,
+        (None, Some(_)) => true,
+        (None, None) => break,
+      };
+      if drop_end {
+        let end_frame = next.take().unwrap().end_frame;
+        merged.last_mut().unwrap().end_frame = end_frame;
+      } else {
+        let last = merged.pop().unwrap();
+        merged.last_mut().unwrap().end_frame = last.end_frame;
+      }
+    }
+    merged.extend(next);
+  }
+
+  if let [.., _, last] = &merged[..] {
+    if len(last) < min_interval && droppable(last) {
+      let last = merged.pop().unwrap();
+      merged.last_mut().unwrap().end_frame = last.end_frame;
+    }
+  }
+
+  merged
+}
+
+/// Version of the format of the scenes file
+///
+/// It is increased whenever a change would break parsing by older versions.
+/// Files without a version were written before it was added, and are read as
+/// version 1.
+pub const SCENES_FORMAT_VERSION: u32 = 1;
+
+/// Contents of the scenes file, as described by `schemas/scenes.schema.json`.
+/// Fields added later must have a default, so that older files still parse.
 #[derive(Deserialize, Serialize, Debug)]
 struct ScenesData {
+  #[serde(default = "default_version")]
+  version: u32,
   scenes: Vec<Scene>,
   frames: usize,
 }
 
+const fn default_version() -> u32 {
+  1
+}
+
 pub fn write_scenes_to_file(
   scenes: &[Scene],
   total_frames: usize,
@@ -108,6 +414,7 @@ pub fn write_scenes_to_file(
 ) -> std::io::Result<()> {
   // Writes a list of scenes and frame count to the file
   let data = ScenesData {
+    version: SCENES_FORMAT_VERSION,
     scenes: scenes.to_vec(),
     frames: total_frames,
   };
@@ -115,15 +422,14 @@ pub fn write_scenes_to_file(
   // serializing the data should never fail, so unwrap is OK
   let serialized = serde_json::to_string(&data).unwrap();
 
-  let mut file = File::create(scene_path)?;
-
-  file.write_all(serialized.as_bytes())?;
+  retry_io(|| std::fs::write(&scene_path, &serialized))?;
 
   Ok(())
 }
 
 pub fn read_scenes_from_file(scene_path: &Path) -> anyhow::Result<(Vec<Scene>, usize)> {
-  let file = File::open(scene_path)?;
+  let file = retry_io(|| File::open(scene_path))
+    .with_context(|| format!("Failed to open scenes file {}", scene_path.display()))?;
 
   let reader = BufReader::new(file);
 
@@ -133,15 +439,113 @@ pub fn read_scenes_from_file(scene_path: &Path) -> anyhow::Result<(Vec<Scene>, u
     )
   })?;
 
+  if data.version > SCENES_FORMAT_VERSION {
+    bail!(
+      "The scenes file {} has version {} of the format, but this version of av1an only reads up to version {SCENES_FORMAT_VERSION}",
+      scene_path.display(),
+      data.version
+    );
+  }
+  validate_scenes(&data.scenes, data.frames)
+    .with_context(|| format!("Invalid scenes file {}", scene_path.display()))?;
+
   Ok((data.scenes, data.frames))
 }
 
+/// Splits the video into scenes at the keyframes of the source.
+///
+/// The boundaries of the zones are always kept, and a keyframe is skipped if
+/// it is closer than the minimum scene length of its zone to the previous
+/// cut or to the next boundary of a zone.
+pub fn keyframe_scenes(
+  keyframes: &[usize],
+  frames: usize,
+  min_scene_len: usize,
+  zones: &[Scene],
+) -> Vec<Scene> {
+  let zone_at = |frame: usize| {
+    zones
+      .iter()
+      .find(|zone| (zone.start_frame..zone.end_frame).contains(&frame))
+  };
+  let boundaries: BTreeSet<usize> = zones
+    .iter()
+    .flat_map(|zone| [zone.start_frame, zone.end_frame])
+    .chain([0])
+    .filter(|&frame| frame < frames)
+    .collect();
+
+  let mut cuts: Vec<usize> = Vec::new();
+  let candidates: BTreeSet<usize> = keyframes
+    .iter()
+    .copied()
+    .filter(|&frame| frame < frames)
+    .chain(boundaries.iter().copied())
+    .collect();
+  for frame in candidates {
+    if boundaries.contains(&frame) {
+      cuts.push(frame);
+      continue;
+    }
+    let min_scene_len = zone_at(frame)
+      .and_then(|zone| zone.zone_overrides.as_ref())
+      .map_or(min_scene_len, |overrides| overrides.min_scene_len);
+    let previous = cuts.last().copied().unwrap_or(0);
+    let next = boundaries.range(frame..).next().copied().unwrap_or(frames);
+    if frame - previous >= min_scene_len && next - frame >= min_scene_len {
+      cuts.push(frame);
+    }
+  }
+
+  cuts
+    .iter()
+    .zip(cuts.iter().skip(1).chain([&frames]))
+    .map(|(&start_frame, &end_frame)| Scene {
+      start_frame,
+      end_frame,
+      zone_overrides: zone_at(start_frame).and_then(|zone| zone.zone_overrides.clone()),
+      cut: None,
+    })
+    .collect()
+}
+
+/// Checks that the scenes are in order, do not overlap, and are within the
+/// frames of the video
+pub fn validate_scenes(scenes: &[Scene], frames: usize) -> anyhow::Result<()> {
+  let mut previous_end = 0;
+  for (i, scene) in scenes.iter().enumerate() {
+    if scene.start_frame >= scene.end_frame {
+      bail!(
+        "Scene {i} starts at frame {} but ends at frame {}",
+        scene.start_frame,
+        scene.end_frame
+      );
+    }
+    if scene.start_frame < previous_end {
+      bail!(
+        "Scene {i} starts at frame {}, before the end of the previous scene at frame {previous_end}",
+        scene.start_frame
+      );
+    }
+    if scene.end_frame > frames {
+      bail!(
+        "Scene {i} ends at frame {}, past the end of the video at frame {frames}",
+        scene.end_frame
+      );
+    }
+    previous_end = scene.end_frame;
+  }
+
+  Ok(())
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
   use crate::encoder::Encoder;
   use crate::into_vec;
   use crate::scenes::ZoneOptions;
+  use crate::target_quality::ChunkTarget;
 
   #[test]
   fn test_extra_split_no_segments() {
@@ -152,6 +556,7 @@ mod tests {
         start_frame: 0,
         end_frame: 300,
         zone_overrides: None,
+        cut: None,
       }],
       total_frames,
       split_size,
@@ -167,6 +572,49 @@ mod tests {
     );
   }
 
+  #[test]
+  fn test_adaptive_extra_split() {
+    let total_frames = 300;
+    // motion everywhere, except for static sections around frames 100 and 220
+    let mut differences = vec![0.1; total_frames];
+    differences[100] = 0.001;
+    differences[220] = 0.002;
+    let scenes = [Scene {
+      start_frame: 0,
+      end_frame: 300,
+      zone_overrides: None,
+      cut: None,
+    }];
+
+    let done = adaptive_extra_splits(&scenes, total_frames, 150, 24, &differences);
+    assert_eq!(
+      vec![0usize, 100, 220],
+      done
+        .iter()
+        .map(|done| done.start_frame)
+        .collect::<Vec<usize>>()
+    );
+    assert!(done
+      .iter()
+      .all(|scene| scene.end_frame - scene.start_frame <= 150));
+
+    // splits of single frames without a minimum scene length still end
+    let mut differences = vec![0.1; total_frames];
+    differences[0] = 0.0;
+    let done = adaptive_extra_splits(&scenes, total_frames, 1, 0, &differences);
+    assert_eq!(done.len(), total_frames);
+
+    // without differences, the scenes are split at even intervals
+    let done = adaptive_extra_splits(&scenes, total_frames, 240, 24, &[]);
+    assert_eq!(
+      vec![0usize, 150],
+      done
+        .iter()
+        .map(|done| done.start_frame)
+        .collect::<Vec<usize>>()
+    );
+  }
+
   #[test]
   fn test_extra_split_segments() {
     let total_frames = 2000;
@@ -177,51 +625,61 @@ mod tests {
           start_frame: 0,
           end_frame: 150,
           zone_overrides: None,
+          cut: None,
         },
         Scene {
           start_frame: 150,
           end_frame: 460,
           zone_overrides: None,
+          cut: None,
         },
         Scene {
           start_frame: 460,
           end_frame: 728,
           zone_overrides: None,
+          cut: None,
         },
         Scene {
           start_frame: 728,
           end_frame: 822,
           zone_overrides: None,
+          cut: None,
         },
         Scene {
           start_frame: 822,
           end_frame: 876,
           zone_overrides: None,
+          cut: None,
         },
         Scene {
           start_frame: 876,
           end_frame: 890,
           zone_overrides: None,
+          cut: None,
         },
         Scene {
           start_frame: 890,
           end_frame: 1100,
           zone_overrides: None,
+          cut: None,
         },
         Scene {
           start_frame: 1100,
           end_frame: 1399,
           zone_overrides: None,
+          cut: None,
         },
         Scene {
           start_frame: 1399,
           end_frame: 1709,
           zone_overrides: None,
+          cut: None,
         },
         Scene {
           start_frame: 1709,
           end_frame: 2000,
           zone_overrides: None,
+          cut: None,
         },
       ],
       total_frames,
@@ -252,11 +710,13 @@ mod tests {
           start_frame: 0,
           end_frame: 150,
           zone_overrides: None,
+          cut: None,
         },
         Scene {
           start_frame: 150,
           end_frame: 460,
           zone_overrides: None,
+          cut: None,
         },
         Scene {
           start_frame: 460,
@@ -266,34 +726,46 @@ mod tests {
             passes: 1,
             extra_splits_len: Some(50),
             min_scene_len: 12,
+            pix_format: None,
+            skip: false,
+            denoise: None,
             photon_noise: None,
+            photon_noise_size: (None, None),
+            chroma_noise: false,
+            target_quality: ChunkTarget::Disabled,
             video_params: into_vec!["--speed", "8"],
           }),
+          cut: None,
         },
         Scene {
           start_frame: 728,
           end_frame: 822,
           zone_overrides: None,
+          cut: None,
         },
         Scene {
           start_frame: 822,
           end_frame: 876,
           zone_overrides: None,
+          cut: None,
         },
         Scene {
           start_frame: 876,
           end_frame: 890,
           zone_overrides: None,
+          cut: None,
         },
         Scene {
           start_frame: 890,
           end_frame: 1100,
           zone_overrides: None,
+          cut: None,
         },
         Scene {
           start_frame: 1100,
           end_frame: 1399,
           zone_overrides: None,
+          cut: None,
         },
         Scene {
           start_frame: 1399,
@@ -303,14 +775,22 @@ mod tests {
             passes: 1,
             extra_splits_len: Some(split_size),
             min_scene_len: 12,
+            pix_format: None,
+            skip: false,
+            denoise: None,
             photon_noise: None,
+            photon_noise_size: (None, None),
+            chroma_noise: false,
+            target_quality: ChunkTarget::Disabled,
             video_params: into_vec!["--speed", "3"],
           }),
+          cut: None,
         },
         Scene {
           start_frame: 1709,
           end_frame: 2000,
           zone_overrides: None,
+          cut: None,
         },
       ],
       total_frames,
@@ -346,4 +826,173 @@ mod tests {
       }
     }
   }
+
+  #[test]
+  fn split_at_keyframes() {
+    let zone = |start_frame, end_frame, min_scene_len| Scene {
+      start_frame,
+      end_frame,
+      zone_overrides: Some(ZoneOptions {
+        encoder: Encoder::aom,
+        passes: 1,
+        video_params: Vec::new(),
+        photon_noise: None,
+        photon_noise_size: (None, None),
+        chroma_noise: false,
+        target_quality: ChunkTarget::Inherit,
+        extra_splits_len: None,
+        min_scene_len,
+        pix_format: None,
+        skip: false,
+        denoise: None,
+      }),
+      cut: None,
+    };
+    let starts =
+      |scenes: &[Scene]| -> Vec<usize> { scenes.iter().map(|scene| scene.start_frame).collect() };
+
+    // keyframes too close to the previous cut are skipped
+    let scenes = keyframe_scenes(&[0, 48, 60, 120, 290, 400], 300, 24, &[]);
+    assert_eq!(starts(&scenes), [0, 48, 120]);
+    assert_eq!(scenes.last().unwrap().end_frame, 300);
+
+    // the boundaries of zones are kept, and the zones use their own minimum
+    let scenes = keyframe_scenes(&[0, 60, 120, 140, 150, 190], 300, 24, &[zone(130, 200, 5)]);
+    assert_eq!(starts(&scenes), [0, 60, 130, 140, 150, 190, 200]);
+    assert!(scenes[1].zone_overrides.is_none());
+    assert!(scenes[2..6]
+      .iter()
+      .all(|scene| scene.zone_overrides.is_some()));
+    assert!(scenes[6].zone_overrides.is_none());
+    validate_scenes(&scenes, 300).unwrap();
+  }
+
+  #[test]
+  fn merge_scenes_by_confidence() {
+    let scene = |start_frame, end_frame, confidence: Option<f64>| Scene {
+      start_frame,
+      end_frame,
+      zone_overrides: None,
+      cut: confidence.map(|confidence| crate::scenes::SceneCut {
+        cost: 0.1,
+        confidence,
+      }),
+    };
+    let starts =
+      |scenes: &[Scene]| -> Vec<usize> { scenes.iter().map(|scene| scene.start_frame).collect() };
+    let scenes = vec![
+      scene(0, 100, None),
+      // a fade, whose cut is dropped rather than the hard cut at 110
+      scene(100, 110, Some(0.2)),
+      scene(110, 200, Some(0.9)),
+      // the cut at 200 is forced, so the one at 205 is dropped
+      scene(200, 205, Some(0.8)),
+      scene(205, 300, Some(0.95)),
+      // the last scene is merged into the previous one
+      scene(300, 310, Some(1.0)),
+    ];
+
+    let merged = merge_short_scenes(scenes.clone(), 24, &[200]);
+    assert_eq!(starts(&merged), [0, 110, 200]);
+    assert_eq!(merged.last().unwrap().end_frame, 310);
+    assert_eq!(merged[1].start_frame, 110);
+    assert!(merged[1].cut.is_some_and(|cut| cut.confidence > 0.5));
+    validate_scenes(&merged, 310).unwrap();
+
+    assert_eq!(
+      starts(&merge_short_scenes(scenes, 5, &[])),
+      [0, 100, 110, 200, 205, 300]
+    );
+
+    // a long run of short scenes is merged into scenes of at least the minimum
+    let scenes: Vec<_> = (0..100_000).map(|i| scene(i, i + 1, Some(0.5))).collect();
+    let merged = merge_short_scenes(scenes, 24, &[]);
+    assert!(merged
+      .iter()
+      .all(|scene| scene.end_frame - scene.start_frame >= 24));
+    validate_scenes(&merged, 100_000).unwrap();
+  }
+
+  #[test]
+  fn scenes_file_compatibility() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("scenes.json");
+
+    // written before the version and the scene cuts were added
+    std::fs::write(
+      &path,
+      r#"{"scenes":[{"start_frame":0,"end_frame":48,"zone_overrides":null},{"start_frame":48,"end_frame":96,"zone_overrides":null}],"frames":96}"#,
+    )
+    .unwrap();
+    let (scenes, frames) = read_scenes_from_file(&path).unwrap();
+    assert_eq!(frames, 96);
+    assert_eq!(scenes.len(), 2);
+    assert!(scenes[1].cut.is_none());
+
+    std::fs::write(
+      &path,
+      r#"{"version":2,"scenes":[{"start_frame":0,"end_frame":96}],"frames":96}"#,
+    )
+    .unwrap();
+    assert!(read_scenes_from_file(&path).is_err());
+
+    std::fs::write(
+      &path,
+      r#"{"scenes":[{"start_frame":0,"end_frame":50},{"start_frame":48,"end_frame":96}],"frames":96}"#,
+    )
+    .unwrap();
+    assert!(read_scenes_from_file(&path).is_err());
+  }
+
+  #[test]
+  fn scenes_schema_covers_fields() {
+    let schema: serde_json::Value =
+      serde_json::from_str(include_str!("../schemas/scenes.schema.json")).unwrap();
+    let data = serde_json::to_value(ScenesData {
+      version: SCENES_FORMAT_VERSION,
+      scenes: vec![Scene {
+        start_frame: 0,
+        end_frame: 10,
+        zone_overrides: Some(ZoneOptions {
+          encoder: Encoder::aom,
+          passes: 1,
+          video_params: Vec::new(),
+          photon_noise: None,
+          photon_noise_size: (None, None),
+          chroma_noise: false,
+          target_quality: ChunkTarget::Inherit,
+          extra_splits_len: None,
+          min_scene_len: 24,
+          pix_format: Some(ffmpeg::format::Pixel::YUV420P),
+          skip: false,
+          denoise: None,
+        }),
+        cut: Some(crate::scenes::SceneCut {
+          cost: 0.5,
+          confidence: 1.0,
+        }),
+      }],
+      frames: 10,
+    })
+    .unwrap();
+
+    // every field that is written must be described by the schema
+    let fields = |value: &serde_json::Value| -> Vec<String> {
+      value.as_object().unwrap().keys().cloned().collect()
+    };
+    let described =
+      |definition: &serde_json::Value| -> Vec<String> { fields(&definition["properties"]) };
+    let scene = &data["scenes"][0];
+    for (value, definition) in [
+      (&data, &schema),
+      (scene, &schema["$defs"]["scene"]),
+      (&scene["zone_overrides"], &schema["$defs"]["zone_options"]),
+      (&scene["cut"], &schema["$defs"]["scene_cut"]),
+    ] {
+      let described = described(definition);
+      for field in fields(value) {
+        assert!(described.contains(&field), "{field} is not in the schema");
+      }
+    }
+  }
 }
@@ -0,0 +1,142 @@
+//! SSIMULACRA2 scores of target quality probes, computed by `ssimulacra2_rs`.
+//!
+//! VMAF is computed by ffmpeg from the source pipe of the chunk, but
+//! `ssimulacra2_rs` compares two files. The frames of the chunk that a probe
+//! encodes are written once per chunk, selected and scaled like the probes,
+//! and each probe is compared with them.
+
+use std::ffi::OsStr;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::broker::EncoderCrash;
+use crate::cgroup;
+use crate::report::parse_ssimulacra2_output;
+use crate::util::retry_io;
+
+/// Counter giving each reference being written a name of its own
+static PARTIAL_REFERENCES: AtomicUsize = AtomicUsize::new(0);
+
+/// Writes the frames of the chunk that its probes encode to `reference`, as
+/// y4m, unless it was already written by an earlier probe.
+///
+/// `filter` selects and scales the frames like the probes.
+pub fn write_reference(
+  source_pipe_cmd: &[impl AsRef<OsStr>],
+  vspipe_args: &[String],
+  filter: &str,
+  reference: &Path,
+) -> Result<(), Box<EncoderCrash>> {
+  if reference.exists() {
+    return Ok(());
+  }
+
+  let mut source_pipe = if let [cmd, args @ ..] = source_pipe_cmd {
+    let mut source_pipe = Command::new(cmd);
+    cgroup::apply(&mut source_pipe);
+    for arg in vspipe_args {
+      source_pipe.args(["-a", arg]);
+    }
+    source_pipe.args(args);
+    source_pipe.stdout(Stdio::piped());
+    source_pipe.stderr(Stdio::piped());
+    source_pipe
+      .spawn()
+      .map_err(|e| failed("start the source pipe of the reference", &e))?
+  } else {
+    unreachable!()
+  };
+  // read while ffmpeg runs, so that the source never blocks on a full pipe
+  let mut source_stderr = source_pipe.stderr.take().unwrap();
+  let source_stderr = thread::spawn(move || {
+    let mut stderr = Vec::new();
+    let _ = source_stderr.read_to_end(&mut stderr);
+    stderr
+  });
+
+  // written under another name first, so that probes running in parallel
+  // never read a partial reference, nor write the same file
+  let partial = reference.with_extension(format!(
+    "{}.tmp",
+    PARTIAL_REFERENCES.fetch_add(1, Ordering::Relaxed)
+  ));
+  let mut cmd = Command::new("ffmpeg");
+  cgroup::apply(&mut cmd);
+  cmd
+    .args(["-loglevel", "error", "-hide_banner", "-y", "-i", "-", "-vf"])
+    .arg(filter)
+    .args(["-vsync", "0", "-strict", "-1", "-f", "yuv4mpegpipe"])
+    .arg(&partial)
+    .stdin(source_pipe.stdout.take().unwrap())
+    .stdout(Stdio::null())
+    .stderr(Stdio::piped());
+
+  let output = cmd.output();
+  let source_status = source_pipe.wait();
+  let source_stderr = source_stderr.join().unwrap_or_default();
+  let output = output.map_err(|e| failed("run ffmpeg to write the reference", &e))?;
+  let source_status = source_status.map_err(|e| failed("wait for the source pipe", &e))?;
+  // a source that fails early would leave a reference missing frames
+  if !output.status.success() || !source_status.success() {
+    let _ = std::fs::remove_file(&partial);
+    return Err(Box::new(EncoderCrash {
+      exit_status: if output.status.success() {
+        source_status
+      } else {
+        output.status
+      },
+      source_pipe_stderr: source_stderr.into(),
+      ffmpeg_pipe_stderr: None,
+      stderr: output.stderr.into(),
+      stdout: String::new().into(),
+      pipeline: Vec::new(),
+    }));
+  }
+
+  retry_io(|| std::fs::rename(&partial, reference))
+    .map_err(|e| failed("rename the reference", &e))?;
+  Ok(())
+}
+
+/// Returns the SSIMULACRA2 score of each frame of the probe, compared with the
+/// frames written by [`write_reference`]
+pub fn run_probe(reference: &Path, probe: &Path) -> Result<Vec<f64>, Box<EncoderCrash>> {
+  let mut cmd = Command::new("ssimulacra2_rs");
+  cgroup::apply(&mut cmd);
+  let output = cmd
+    .arg("video")
+    .arg(reference)
+    .arg(probe)
+    .arg("--verbose")
+    .stdin(Stdio::null())
+    .output()
+    .map_err(|e| failed("run ssimulacra2_rs", &e))?;
+
+  let scores = parse_ssimulacra2_output(&String::from_utf8_lossy(&output.stdout));
+  if !output.status.success() || scores.is_empty() {
+    return Err(Box::new(EncoderCrash {
+      exit_status: output.status,
+      source_pipe_stderr: String::new().into(),
+      ffmpeg_pipe_stderr: None,
+      stderr: output.stderr.into(),
+      stdout: output.stdout.into(),
+      pipeline: Vec::new(),
+    }));
+  }
+  Ok(scores)
+}
+
+/// Returns the error of a step of scoring a probe that failed to run
+fn failed(action: &str, error: &io::Error) -> Box<EncoderCrash> {
+  Box::new(EncoderCrash {
+    exit_status: ExitStatus::default(),
+    stdout: format!("SSIMULACRA2 FAILED: unable to {action}: {error}").into(),
+    stderr: String::new().into(),
+    source_pipe_stderr: String::new().into(),
+    ffmpeg_pipe_stderr: None,
+    pipeline: Vec::new(),
+  })
+}
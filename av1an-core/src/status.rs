@@ -0,0 +1,203 @@
+//! Files that let external tools (dashboards, `tail -f`, ...) follow an encode.
+//!
+//! The files in the temporary directory always have the same names:
+//!
+//! - `log.log`: the log file, unless `--log-file` is specified
+//! - `status.json`: the progress of the encode, see [`Status`]
+//! - `done.json`: the chunks that have finished encoding
+//! - `chunks.json`: the chunk queue
+//!
+//! As the name of the temporary directory is a hash of the input by default,
+//! a `latest.json` file pointing to these files, along with `latest` (the
+//! temporary directory) and `latest.log` symlinks where supported, is also
+//! written to the status directory whenever an encode starts.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use once_cell::sync::OnceCell;
+use path_abs::PathAbs;
+use serde::Serialize;
+
+use crate::settings::EncodeArgs;
+use crate::try_get_done;
+use crate::util::retry_io;
+
+static STATUS: OnceCell<StatusFile> = OnceCell::new();
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum State {
+  SceneDetection,
+  Encoding,
+  /// Waiting for the next window of `--schedule`
+  Paused,
+  Concatenating,
+  Finished,
+}
+
+/// Contents of `status.json`
+#[derive(Serialize, Debug)]
+pub struct Status {
+  pub pid: u32,
+  pub state: State,
+  pub frames: usize,
+  pub encoded_frames: usize,
+  pub chunks: usize,
+  pub encoded_chunks: usize,
+  /// Unix timestamp of the last update
+  pub updated: u64,
+}
+
+#[derive(Debug)]
+struct StatusFile {
+  path: PathBuf,
+  state: parking_lot::Mutex<State>,
+  frames: AtomicUsize,
+  chunks: AtomicUsize,
+}
+
+/// Contents of `latest.json`
+#[derive(Serialize, Debug)]
+struct Latest {
+  pid: u32,
+  input: PathBuf,
+  output: PathBuf,
+  temp: PathBuf,
+  log: PathBuf,
+  status: PathBuf,
+  done: PathBuf,
+  chunks: PathBuf,
+  vmaf_plot: Option<PathBuf>,
+  quality_report: Option<PathBuf>,
+}
+
+fn absolute(path: impl AsRef<Path>) -> PathBuf {
+  PathAbs::new(path.as_ref()).map_or_else(|_| path.as_ref().to_path_buf(), PathBuf::from)
+}
+
+/// Writes a file atomically, so that readers never see a partial file
+fn write_atomic(path: &Path, contents: &str) -> std::io::Result<()> {
+  let tmp_path = path.with_extension("json.tmp");
+  retry_io(|| fs::write(&tmp_path, contents))?;
+  retry_io(|| fs::rename(&tmp_path, path))
+}
+
+/// Starts tracking the status of the encode in `<temp>/status.json`
+pub fn init(temp: &Path) {
+  STATUS.get_or_init(|| StatusFile {
+    path: temp.join("status.json"),
+    state: parking_lot::Mutex::new(State::SceneDetection),
+    frames: AtomicUsize::new(0),
+    chunks: AtomicUsize::new(0),
+  });
+  update();
+}
+
+pub fn set_totals(frames: usize, chunks: usize) {
+  if let Some(status) = STATUS.get() {
+    status.frames.store(frames, Ordering::Relaxed);
+    status.chunks.store(chunks, Ordering::Relaxed);
+  }
+}
+
+pub fn set_state(state: State) {
+  if let Some(status) = STATUS.get() {
+    *status.state.lock() = state;
+  }
+  update();
+}
+
+/// Rewrites `status.json` with the current progress
+pub fn update() {
+  let Some(status) = STATUS.get() else {
+    return;
+  };
+
+  let (encoded_frames, encoded_chunks) = try_get_done().map_or((0, 0), |done| {
+    (
+      done.done.iter().map(|chunk| chunk.frames).sum(),
+      done.done.len(),
+    )
+  });
+
+  let state = *status.state.lock();
+  let contents = Status {
+    pid: std::process::id(),
+    state,
+    frames: status.frames.load(Ordering::Relaxed),
+    encoded_frames,
+    chunks: status.chunks.load(Ordering::Relaxed),
+    encoded_chunks,
+    updated: SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map_or(0, |time| time.as_secs()),
+  };
+
+  if let Err(e) = write_atomic(&status.path, &serde_json::to_string(&contents).unwrap()) {
+    warn!("Failed to write {}: {}", status.path.display(), e);
+  }
+}
+
+/// Points `latest.json` and the `latest`/`latest.log` symlinks of the status
+/// directory to the files of this encode
+pub fn write_latest(status_dir: &Path, args: &EncodeArgs) -> anyhow::Result<()> {
+  fs::create_dir_all(status_dir)?;
+
+  let temp = absolute(&args.temp);
+  let log = absolute(&args.log_file);
+  let output = absolute(&args.output_file);
+  let latest = Latest {
+    pid: std::process::id(),
+    input: absolute(args.input.as_path()),
+    vmaf_plot: args.vmaf.then(|| output.with_extension("svg")),
+    quality_report: args
+      .quality_report
+      .then(|| output.with_extension("report.html")),
+    output,
+    status: temp.join("status.json"),
+    done: temp.join("done.json"),
+    chunks: temp.join("chunks.json"),
+    log: log.clone(),
+    temp: temp.clone(),
+  };
+  write_atomic(
+    &status_dir.join("latest.json"),
+    &serde_json::to_string_pretty(&latest)?,
+  )?;
+
+  // symlinks are a convenience, and may not be permitted (e.g. on Windows without developer mode)
+  for (link, target, is_dir) in [("latest", &temp, true), ("latest.log", &log, false)] {
+    let link = status_dir.join(link);
+    if link.symlink_metadata().is_ok() {
+      // a symlink to a directory is removed with remove_dir on Windows
+      let _ = fs::remove_file(&link).or_else(|_| fs::remove_dir(&link));
+    }
+    if let Err(e) = symlink(target, &link, is_dir) {
+      debug!("Failed to create symlink {}: {}", link.display(), e);
+    }
+  }
+
+  Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path, _is_dir: bool) -> std::io::Result<()> {
+  std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path, is_dir: bool) -> std::io::Result<()> {
+  if is_dir {
+    std::os::windows::fs::symlink_dir(target, link)
+  } else {
+    std::os::windows::fs::symlink_file(target, link)
+  }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn symlink(_target: &Path, _link: &Path, _is_dir: bool) -> std::io::Result<()> {
+  Err(std::io::ErrorKind::Unsupported.into())
+}
@@ -0,0 +1,145 @@
+//! Super-chunks of consecutive chunks, which are encoded one after another.
+//!
+//! Once all chunks of a super-chunk are encoded, they are concatenated into a
+//! single file in `super/`, and the encoded chunks as well as their split
+//! source files (of the segment and hybrid chunk methods) are removed. Only the
+//! chunks of a single super-chunk exist at any time, which bounds the size of
+//! the temporary folder for very long inputs.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::chunk::Chunk;
+use crate::concat::{self, ConcatMethod};
+use crate::util::retry_io;
+use crate::{create_dir, Input};
+
+/// Returns the index of the super-chunk that contains the chunk
+pub const fn index_of(chunk: &Chunk, size: usize) -> usize {
+  chunk.index / size
+}
+
+/// Returns the number of super-chunks of an encode
+pub const fn count(total_chunks: usize, size: usize) -> usize {
+  total_chunks.div_ceil(size)
+}
+
+/// Returns the extension of the concatenated super-chunks, which mkvmerge and
+/// ffmpeg always write as Matroska
+pub const fn extension(method: ConcatMethod) -> &'static str {
+  match method {
+    ConcatMethod::Ivf => "ivf",
+    ConcatMethod::MKVMerge | ConcatMethod::FFmpeg => "mkv",
+  }
+}
+
+/// Returns the folder that contains the concatenated super-chunks
+pub fn dir(temp: &Path) -> PathBuf {
+  temp.join("super")
+}
+
+/// Concatenates the encoded chunks of a finished super-chunk.
+///
+/// The chunks and their split source files that no later chunk uses are
+/// removed afterwards. This can be called again after it was interrupted, e.g.
+/// when resuming.
+///
+/// `chunks` are all chunks of the encode, including the finished ones.
+pub fn finish(
+  temp: &Path,
+  index: usize,
+  size: usize,
+  chunks: &[Chunk],
+  method: ConcatMethod,
+) -> anyhow::Result<()> {
+  let output = dir(temp).join(format!("{index:05}.{}", extension(method)));
+  // the chunks are moved here first, as the concatenation expects them to be
+  // numbered from 0 in a folder of their own
+  let staging_dir = dir(temp).join(format!("{index:05}"));
+  if output.exists() && !staging_dir.exists() {
+    return Ok(());
+  }
+
+  let mut group: Vec<_> = chunks
+    .iter()
+    .filter(|chunk| index_of(chunk, size) == index)
+    .collect();
+  group.sort_unstable_by_key(|chunk| chunk.index);
+  if group.is_empty() {
+    return Ok(());
+  }
+
+  let encode_dir = staging_dir.join("encode");
+  create_dir!(dir(temp))?;
+  create_dir!(staging_dir)?;
+  create_dir!(encode_dir)?;
+
+  for (i, chunk) in group.iter().enumerate() {
+    let chunk_output = PathBuf::from(chunk.output());
+    if chunk_output.exists() {
+      let staged = encode_dir.join(format!("{i:05}.{}", chunk.output_ext));
+      retry_io(|| fs::rename(&chunk_output, &staged))
+        .with_context(|| format!("Failed to move {}", chunk_output.display()))?;
+    }
+  }
+
+  debug!(
+    "concatenating chunks {}..={} into super-chunk {}",
+    group.first().map_or(0, |chunk| chunk.index),
+    group.last().map_or(0, |chunk| chunk.index),
+    output.display()
+  );
+  match method {
+    ConcatMethod::Ivf => concat::ivf(&encode_dir, &output)?,
+    ConcatMethod::MKVMerge => concat::mkvmerge(
+      &staging_dir,
+      &encode_dir,
+      &output,
+      &group[0].output_ext,
+      group.len(),
+      None,
+    )?,
+    ConcatMethod::FFmpeg => concat::ffmpeg(&staging_dir, &encode_dir, &output, group[0].encoder)?,
+  }
+
+  retry_io(|| fs::remove_dir_all(&staging_dir))
+    .with_context(|| format!("Failed to remove {}", staging_dir.display()))?;
+
+  // with the hybrid chunk method, several chunks can be selected from the same segment
+  let split_dir = temp.join("split");
+  for chunk in &group {
+    let Input::Video { path } = &chunk.input else {
+      continue;
+    };
+    let used_later = chunks.iter().any(|later| {
+      index_of(later, size) > index
+        && matches!(&later.input, Input::Video { path: later_path } if later_path == path)
+    });
+    if path.starts_with(&split_dir) && !used_later {
+      if let Err(e) = fs::remove_file(path) {
+        if e.kind() != ErrorKind::NotFound {
+          warn!("Failed to remove split file {}: {}", path.display(), e);
+        }
+      }
+    }
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn super_chunk_groups() {
+    assert_eq!(count(10, 4), 3);
+    assert_eq!(count(12, 4), 3);
+    assert_eq!(count(1, 100), 1);
+    assert_eq!(extension(ConcatMethod::Ivf), "ivf");
+    assert_eq!(extension(ConcatMethod::FFmpeg), "mkv");
+  }
+}
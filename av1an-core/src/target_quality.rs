@@ -1,32 +1,269 @@
-use std::cmp;
 use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
 use std::convert::TryInto;
 use std::fmt::Error;
+use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::atomic::{self, AtomicUsize};
 use std::thread::available_parallelism;
+use std::{cmp, fs};
 
+use dashmap::DashMap;
 use ffmpeg::format::Pixel;
+use once_cell::sync::{Lazy, OnceCell};
 use serde::{Deserialize, Serialize};
 use splines::{Interpolation, Key, Spline};
 
 use crate::broker::EncoderCrash;
 use crate::chunk::Chunk;
-use crate::vmaf::{self, read_weighted_vmaf};
-use crate::Encoder;
+use crate::encoder::RateParam;
+use crate::logging::{self, Event};
+use crate::util::retry_io;
+use crate::vmaf::{self, read_probe_vmaf, FrameWeighting, VmafModelAuto};
+use crate::{cgroup, ssimulacra2, Encoder, ProbingStatistic};
 
-const VMAF_PERCENTILE: f64 = 0.01;
+static PROBE_CACHE: OnceCell<ProbeCache> = OnceCell::new();
 
+/// Encode workers that have no chunks left to encode, whose slots can be used
+/// to run the probes of a chunk in parallel
+static IDLE_SLOTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Marks the slot of an encode worker as idle
+pub fn release_idle_slot() {
+  IDLE_SLOTS.fetch_add(1, atomic::Ordering::Relaxed);
+}
+
+pub fn reset_idle_slots() {
+  IDLE_SLOTS.store(0, atomic::Ordering::Relaxed);
+}
+
+/// Q chosen for each chunk of this run, keyed by temp folder and chunk index,
+/// which neighboring chunks can start their search from
+static CONVERGED_Q: Lazy<DashMap<(String, usize), u32>> = Lazy::new(DashMap::new);
+
+/// Returns the Q chosen for the previous chunk (or else the next one), if
+/// its search has already finished, in this run or a previous one
+fn neighbor_q(chunk: &Chunk) -> Option<u32> {
+  neighbor_qs(chunk).into_iter().flatten().next()
+}
+
+/// Returns the Qs chosen for the previous and the next chunk, for those whose
+/// search has already finished, in this run or a previous one
+fn neighbor_qs(chunk: &Chunk) -> [Option<u32>; 2] {
+  [chunk.index.checked_sub(1), chunk.index.checked_add(1)].map(|index| {
+    let index = index?;
+    CONVERGED_Q
+      .get(&(chunk.temp.clone(), index))
+      .map(|q| *q)
+      .or_else(|| {
+        let done = crate::try_get_done()?.done.get(&format!("{index:05}"))?;
+        done.tq_cq
+      })
+  })
+}
+
+/// Clamps `q` to at most `max_diff` away from the Qs of the neighboring
+/// chunks, `[previous, next]`. If both cannot be met, the previous chunk wins.
+fn smooth_q(q: u32, neighbors: [Option<u32>; 2], max_diff: u32) -> u32 {
+  neighbors
+    .into_iter()
+    .rev()
+    .flatten()
+    .fold(q, |q, neighbor| {
+      q.clamp(neighbor.saturating_sub(max_diff), neighbor + max_diff)
+    })
+}
+
+/// Mean score of the probes of the search of each chunk of this run, keyed by
+/// temp folder and chunk index, for the report of the chunks
+static PROBE_SCORES: Lazy<DashMap<(String, usize), f64>> = Lazy::new(DashMap::new);
+
+/// Returns the mean score of the probes of the search of the chunk
+pub(crate) fn mean_probe_score(chunk: &Chunk) -> Option<f64> {
+  PROBE_SCORES
+    .get(&(chunk.temp.clone(), chunk.index))
+    .map(|score| *score)
+}
+
+fn mean_score(vmaf_cq: &[(f64, u32)]) -> f64 {
+  vmaf_cq.iter().map(|(score, _)| score).sum::<f64>() / vmaf_cq.len() as f64
+}
+
+/// Probes and chosen Q of the search of each chunk of this run, keyed by temp
+/// folder and chunk index, which the encoded chunk is verified against
+static SEARCHES: Lazy<DashMap<(String, usize), Search>> = Lazy::new(DashMap::new);
+
+#[derive(Debug, Clone)]
+struct Search {
+  probes: Vec<(f64, u32)>,
+  q: f64,
+  q_vmaf: f64,
+  target: f64,
+}
+
+impl Search {
+  /// How likely the encode is to miss the target, relative to the tolerance.
+  ///
+  /// The steeper the scores change around the chosen Q, the smaller the margin
+  /// by which Q can be off before the score leaves the tolerance. Complex
+  /// chunks, which need a Q far below the maximum, weigh more, as their scores
+  /// are the most sensitive to it. Above 1, a single Q step is enough to miss.
+  fn risk(&self, min_q: u32, max_q: u32, tolerance: f64) -> f64 {
+    let mut probes = self.probes.clone();
+    probes.sort_by_key(|&(_, q)| q);
+    let slope = probes
+      .windows(2)
+      .find(|pair| f64::from(pair[0].1) <= self.q && self.q <= f64::from(pair[1].1))
+      .map_or(0.0, |pair| {
+        (pair[1].0 - pair[0].0).abs() / f64::from(pair[1].1 - pair[0].1)
+      });
+    let complexity = (f64::from(max_q) - self.q) / f64::from((max_q - min_q).max(1));
+
+    slope * (1.0 + complexity) / tolerance.max(f64::EPSILON)
+  }
+}
+
+fn take_idle_slot() -> bool {
+  IDLE_SLOTS
+    .fetch_update(
+      atomic::Ordering::Relaxed,
+      atomic::Ordering::Relaxed,
+      |slots| slots.checked_sub(1),
+    )
+    .is_ok()
+}
+
+/// Probe results persisted in the temp folder, so that they can be reused by
+/// later runs (on `--resume`, or when re-running with a different target).
+///
+/// Probes are keyed by a hash of the chunk and of every setting that affects
+/// the score of a probe, and stored as `(q, score)` pairs.
+#[derive(Debug)]
+struct ProbeCache {
+  path: PathBuf,
+  probes: DashMap<String, Vec<(u32, f64)>>,
+  write_lock: parking_lot::Mutex<()>,
+}
+
+impl ProbeCache {
+  fn load(path: PathBuf) -> Self {
+    let probes = fs::read_to_string(&path)
+      .ok()
+      .and_then(|contents| serde_json::from_str(&contents).ok())
+      .unwrap_or_default();
+
+    Self {
+      path,
+      probes,
+      write_lock: parking_lot::Mutex::new(()),
+    }
+  }
+
+  fn get(&self, key: &str) -> Vec<(u32, f64)> {
+    self
+      .probes
+      .get(key)
+      .map(|probes| probes.clone())
+      .unwrap_or_default()
+  }
+
+  fn insert(&self, key: &str, q: u32, score: f64) {
+    self
+      .probes
+      .entry(key.to_owned())
+      .or_default()
+      .push((q, score));
+
+    let _lock = self.write_lock.lock();
+    let tmp_path = self.path.with_extension("json.tmp");
+    let result = serde_json::to_string(&self.probes)
+      .map_err(anyhow::Error::from)
+      .and_then(|json| Ok(retry_io(|| fs::write(&tmp_path, &json))?))
+      .and_then(|()| Ok(retry_io(|| fs::rename(&tmp_path, &self.path))?));
+    if let Err(e) = result {
+      warn!("Failed to save probe results: {}", e);
+    }
+  }
+}
+
+/// Targets of `--target-quality`, parsed from a VMAF score, e.g. `95`, or
+/// from the scores of each metric, e.g. `vmaf=95,ssimu2=80`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualityTargets {
+  pub vmaf: f64,
+  pub ssimu2: Option<f64>,
+}
+
+impl FromStr for QualityTargets {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if let Ok(vmaf) = s.trim().parse() {
+      return Ok(Self { vmaf, ssimu2: None });
+    }
+
+    let (mut vmaf, mut ssimu2) = (None, None);
+    for target in s.split(',') {
+      let (metric, score) = target
+        .split_once('=')
+        .ok_or_else(|| format!("expected METRIC=SCORE, got {target:?}"))?;
+      let score: f64 = score
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid score {score:?}"))?;
+      match metric.trim() {
+        "vmaf" => vmaf = Some(score),
+        "ssimu2" => ssimu2 = Some(score),
+        metric => {
+          return Err(format!(
+            "unknown metric {metric:?}, expected vmaf or ssimu2"
+          ))
+        }
+      }
+    }
+
+    Ok(Self {
+      vmaf: vmaf.ok_or("a VMAF target is required, e.g. vmaf=95,ssimu2=80")?,
+      ssimu2,
+    })
+  }
+}
+
+/// Target quality setting of a chunk, which can be changed by its zone
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChunkTarget {
+  /// Uses the target of `--target-quality`, if any
+  #[default]
+  Inherit,
+  /// Encodes with the encoder's own rate control settings
+  Disabled,
+  /// Searches the Q for this target instead
+  Target(f64),
+}
+
+#[allow(clippy::struct_excessive_bools)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TargetQuality {
   pub vmaf_res: String,
+  /// Resolution the probes are encoded (and their VMAF is calculated) at
+  pub probe_res: Option<String>,
   pub vmaf_scaler: String,
   pub vmaf_filter: Option<String>,
   pub vmaf_threads: usize,
   pub model: Option<PathBuf>,
+  pub model_auto: Option<VmafModelAuto>,
+  pub vmaf_neg: bool,
   pub probing_rate: usize,
   pub probes: u32,
   pub target: f64,
+  /// SSIMULACRA2 score the chosen Q must also reach, with a VMAF target
+  pub ssimu2_target: Option<f64>,
+  /// Parameter the search chooses for each chunk
+  pub rate: RateParam,
+  /// Range of the parameter, the bitrates in kbps with [`RateParam::Bitrate`]
   pub min_q: u32,
   pub max_q: u32,
   pub encoder: Encoder,
@@ -36,74 +273,299 @@ pub struct TargetQuality {
   pub video_params: Vec<String>,
   pub vspipe_args: Vec<String>,
   pub probe_slow: bool,
+  pub probe_workers: usize,
+  pub probe_parallel: bool,
+  pub probe_warm_start: bool,
+  pub probe_verify: Option<f64>,
+  pub verify_chunks: Option<f64>,
+  /// Largest difference of the Q of a chunk from the Qs of its neighbors
+  pub smoothing: Option<u32>,
+  pub probing_statistic: ProbingStatistic,
+  pub frame_weighting: FrameWeighting,
 }
 
 impl TargetQuality {
+  fn probe_cache(&self) -> &'static ProbeCache {
+    PROBE_CACHE.get_or_init(|| ProbeCache::load(Path::new(&self.temp).join("probes.json")))
+  }
+
+  /// Identifies the probes of a chunk, independently of the target
+  fn probe_key(&self, chunk: &Chunk) -> String {
+    let mut s = DefaultHasher::new();
+    chunk.source_cmd.hash(&mut s);
+    chunk.start_frame.hash(&mut s);
+    chunk.end_frame.hash(&mut s);
+    format!("{:?} {:?}", self.encoder, self.pix_format).hash(&mut s);
+    self.video_params.hash(&mut s);
+    self.probe_slow.hash(&mut s);
+    self.probing_rate.hash(&mut s);
+    self.probing_statistic.to_string().hash(&mut s);
+    self.model.hash(&mut s);
+    self.model_auto.hash(&mut s);
+    self.vmaf_neg.hash(&mut s);
+    self.vmaf_res.hash(&mut s);
+    self.probe_res.hash(&mut s);
+    self.vmaf_scaler.hash(&mut s);
+    self.vmaf_filter.hash(&mut s);
+    // the defaults keep the keys of the probes of earlier versions
+    if self.rate != RateParam::Q {
+      self.rate.to_string().hash(&mut s);
+    }
+    if !self.frame_weighting.is_none() {
+      self.frame_weighting.to_string().hash(&mut s);
+    }
+    if let Some(denoise) = chunk.denoise_filter() {
+      denoise.hash(&mut s);
+    }
+    format!("{:x}", s.finish())
+  }
+
+  /// Converts a level of the search to the value of the rate parameter, or a
+  /// value to its level.
+  ///
+  /// The search works on levels, which lower the quality as they rise. The
+  /// probe cache, the neighbors of a chunk and its result hold values.
+  const fn level(&self, value: u32) -> u32 {
+    self.rate.level(value, self.min_q, self.max_q)
+  }
+
   fn per_shot_target_quality(&self, chunk: &Chunk) -> Result<u32, Box<EncoderCrash>> {
-    let mut vmaf_cq = vec![];
+    let cache = self.probe_cache();
+    let key = self.probe_key(chunk);
+    let ssimu2_key = format!("{key}-ssimu2");
+    let cached = cache.get(&key);
+    let cached_ssimu2 = if self.ssimu2_target.is_some() {
+      cache.get(&ssimu2_key)
+    } else {
+      Vec::new()
+    };
+    let probed = DashMap::new();
+
+    // Reuses the scores of a previous run (or of the search of the other
+    // metric) if this q was already probed
+    let probe = |level: u32| -> Result<(f64, Option<f64>), Box<EncoderCrash>> {
+      if let Some(scores) = probed.get(&level) {
+        return Ok(*scores);
+      }
+
+      let q = self.level(level);
+      let find = |probes: &[(u32, f64)]| {
+        probes
+          .iter()
+          .find(|&&(cached_q, _)| cached_q == q)
+          .map(|&(_, score)| score)
+      };
+      let cached_scores = match (find(&cached), self.ssimu2_target) {
+        (Some(vmaf), None) => Some((vmaf, None)),
+        (Some(vmaf), Some(_)) => find(&cached_ssimu2).map(|ssimu2| (vmaf, Some(ssimu2))),
+        (None, _) => None,
+      };
+
+      let scores = if let Some(scores) = cached_scores {
+        scores
+      } else {
+        let scores = self.probe_scores(chunk, level, self.probing_rate)?;
+        cache.insert(&key, q, scores.0);
+        if let Some(ssimu2) = scores.1 {
+          cache.insert(&ssimu2_key, q, ssimu2);
+        }
+        scores
+      };
+      logging::event(&Event::Probe {
+        chunk: chunk.index,
+        q,
+        score: scores.0,
+        ssimu2: scores.1,
+        cached: cached_scores.is_some(),
+      });
+      probed.insert(level, scores);
+      Ok(scores)
+    };
+
+    let vmaf_q = self.search(chunk, Metric::Vmaf, self.target, &cached, &|level| {
+      probe(level).map(|(vmaf, _)| vmaf)
+    })?;
+    let Some(ssimu2_target) = self.ssimu2_target else {
+      return Ok(vmaf_q);
+    };
+    let ssimu2_q = self.search(
+      chunk,
+      Metric::Ssimu2,
+      ssimu2_target,
+      &cached_ssimu2,
+      &|level| probe(level).map(|(_, ssimu2)| ssimu2.unwrap()),
+    )?;
+
+    // both targets must be met, so the Q of the higher quality wins
+    if self.level(ssimu2_q) < self.level(vmaf_q) {
+      debug!(
+        "chunk {}: SSIMULACRA2 needs {rate}={}, VMAF only {rate}={}",
+        chunk.name(),
+        ssimu2_q,
+        vmaf_q,
+        rate = self.rate
+      );
+      // the Q was not chosen by the VMAF search, so it is not verified against it
+      SEARCHES.remove(&(chunk.temp.clone(), chunk.index));
+      return Ok(ssimu2_q);
+    }
+    Ok(vmaf_q)
+  }
+
+  /// Encodes a probe of the chunk at a level of the search, and returns its
+  /// VMAF and, with a SSIMULACRA2 target, its SSIMULACRA2 score
+  fn probe_scores(
+    &self,
+    chunk: &Chunk,
+    level: u32,
+    probing_rate: usize,
+  ) -> Result<(f64, Option<f64>), Box<EncoderCrash>> {
+    let vmaf = read_probe_vmaf(
+      self.vmaf_probe(chunk, level, probing_rate)?,
+      self.probing_statistic,
+      self.frame_weighting,
+      probing_rate,
+    )
+    .unwrap();
+    let ssimu2 = if self.ssimu2_target.is_some() {
+      Some(self.ssimu2_probe(chunk, level, probing_rate)?)
+    } else {
+      None
+    };
+    Ok((vmaf, ssimu2))
+  }
+
+  /// Searches the Q of the chunk whose `metric` score meets `target`, with
+  /// `cached` probes of previous runs of this metric
+  fn search(
+    &self,
+    chunk: &Chunk,
+    metric: Metric,
+    target: f64,
+    cached: &[(u32, f64)],
+    probe: &(dyn Fn(u32) -> Result<f64, Box<EncoderCrash>> + Sync),
+  ) -> Result<u32, Box<EncoderCrash>> {
     let frames = chunk.frames();
 
-    // Make middle probe
-    let middle_point = (self.min_q + self.max_q) / 2;
-    let last_q = middle_point;
+    let mut vmaf_cq = if self.probe_parallel && take_idle_slot() {
+      // min_q and max_q bracket the target, so they can be probed at the same time
+      let (min_score, max_score) = crossbeam_utils::thread::scope(|s| {
+        let max_score = s.spawn(|_| probe(self.max_q));
+        (probe(self.min_q), max_score.join().unwrap())
+      })
+      .unwrap();
+      release_idle_slot();
+
+      vec![(min_score?, self.min_q), (max_score?, self.max_q)]
+    } else if let Some(warm_q) = self.probe_warm_start.then(|| neighbor_q(chunk)).flatten() {
+      // Adjacent chunks usually need a similar Q, so the search starts at the Q of the
+      // neighboring chunk, and steps towards the target from there to bracket it
+      let warm_q = self.level(warm_q.clamp(self.min_q, self.max_q));
+      let score = probe(warm_q)?;
+      let step = ((self.max_q - self.min_q) / 8).max(1);
+      let next_q = if score < target {
+        warm_q.saturating_sub(step).max(self.min_q)
+      } else {
+        (warm_q + step).min(self.max_q)
+      };
 
-    let mut score =
-      read_weighted_vmaf(self.vmaf_probe(chunk, last_q as usize)?, VMAF_PERCENTILE).unwrap();
-    vmaf_cq.push((score, last_q));
+      let mut probes = vec![(score, warm_q)];
+      if next_q != warm_q {
+        let next_score = probe(next_q)?;
+        probes.push((next_score, next_q));
 
-    // Initialize search boundary
-    let mut vmaf_lower = score;
-    let mut vmaf_upper = score;
-    let mut vmaf_cq_lower = last_q;
-    let mut vmaf_cq_upper = last_q;
+        // fall back to the end of the range if the step was not enough to reach the target
+        if (next_score < target) == (score < target) && next_q != self.min_q && next_q != self.max_q
+        {
+          let edge_q = if score < target {
+            self.min_q
+          } else {
+            self.max_q
+          };
+          probes.push((probe(edge_q)?, edge_q));
+        }
+      }
 
-    // Branch
-    let next_q = if score < self.target {
-      self.min_q
+      probes
     } else {
-      self.max_q
+      // Make middle probe
+      let middle_point = (self.min_q + self.max_q) / 2;
+      let score = probe(middle_point)?;
+
+      // Branch
+      let next_q = if score < target {
+        self.min_q
+      } else {
+        self.max_q
+      };
+
+      vec![(score, middle_point), (probe(next_q)?, next_q)]
     };
 
     // Edge case check
-    score = read_weighted_vmaf(self.vmaf_probe(chunk, next_q as usize)?, VMAF_PERCENTILE).unwrap();
-    vmaf_cq.push((score, next_q));
-
-    if (next_q == self.min_q && score < self.target)
-      || (next_q == self.max_q && score > self.target)
-    {
+    if let Some(&(score, q)) = vmaf_cq.iter().find(|&&(score, q)| {
+      (q == self.min_q && score < target) || (q == self.max_q && score > target)
+    }) {
+      let q = self.level(q);
       log_probes(
-        &mut vmaf_cq,
+        &mut self.probe_values(&vmaf_cq),
         frames as u32,
         self.probing_rate as u32,
         &chunk.name(),
-        next_q,
+        metric.name(),
+        q,
         score,
-        if score < self.target {
+        if score < target {
           Skip::Low
         } else {
           Skip::High
         },
       );
-      return Ok(next_q);
+      if metric == Metric::Vmaf {
+        PROBE_SCORES.insert((chunk.temp.clone(), chunk.index), mean_score(&vmaf_cq));
+      }
+      return Ok(q);
     }
 
-    // Set boundary
-    if score < self.target {
-      vmaf_lower = score;
-      vmaf_cq_lower = next_q;
-    } else {
-      vmaf_upper = score;
-      vmaf_cq_upper = next_q;
+    // Initialize search boundary with the closest probes on each side of the target
+    let closest = |below: bool| {
+      vmaf_cq
+        .iter()
+        .copied()
+        .filter(|&(score, _)| (score < target) == below)
+        .min_by(|a, b| (a.0 - target).abs().total_cmp(&(b.0 - target).abs()))
+    };
+    let (mut vmaf_lower, mut vmaf_cq_lower) = closest(true).unwrap_or(vmaf_cq[0]);
+    let (mut vmaf_upper, mut vmaf_cq_upper) = closest(false).unwrap_or(vmaf_cq[0]);
+    let initial_probes = vmaf_cq.len() as u32;
+
+    // Narrow the search down with the probes of previous runs
+    for &(cached_q, cached_score) in cached {
+      let cached_q = self.level(cached_q);
+      if vmaf_cq.iter().any(|&(_, q)| q == cached_q) {
+        continue;
+      }
+      vmaf_cq.push((cached_score, cached_q));
+
+      if cached_score < target {
+        if cached_score > vmaf_lower {
+          vmaf_lower = cached_score;
+          vmaf_cq_lower = cached_q;
+        }
+      } else if cached_score < vmaf_upper {
+        vmaf_upper = cached_score;
+        vmaf_cq_upper = cached_q;
+      }
     }
 
     // VMAF search
-    for _ in 0..self.probes - 2 {
+    for _ in 0..self.probes.saturating_sub(initial_probes) {
       let new_point = weighted_search(
         f64::from(vmaf_cq_lower),
         vmaf_lower,
         f64::from(vmaf_cq_upper),
         vmaf_upper,
-        self.target,
+        target,
       );
 
       if vmaf_cq
@@ -114,11 +576,11 @@ impl TargetQuality {
         break;
       }
 
-      score = read_weighted_vmaf(self.vmaf_probe(chunk, new_point)?, VMAF_PERCENTILE).unwrap();
+      let score = probe(new_point as u32)?;
       vmaf_cq.push((score, new_point as u32));
 
       // Update boundary
-      if score < self.target {
+      if score < target {
         vmaf_lower = score;
         vmaf_cq_lower = new_point as u32;
       } else {
@@ -127,21 +589,268 @@ impl TargetQuality {
       }
     }
 
-    let (q, q_vmaf) = interpolated_target_q(vmaf_cq.clone(), self.target);
+    let (mut q, mut q_vmaf) = interpolated_target_q(vmaf_cq.clone(), target);
+
+    // the verification of the chosen Q and of the encode are by VMAF
+    if let (Some(tolerance), Metric::Vmaf) = (self.probe_verify, metric) {
+      (q, q_vmaf) = self.verify_target_q(chunk, &vmaf_cq, q, q_vmaf, tolerance)?;
+    }
+
+    if self.verify_chunks.is_some() && metric == Metric::Vmaf {
+      SEARCHES.insert(
+        (chunk.temp.clone(), chunk.index),
+        Search {
+          probes: vmaf_cq.clone(),
+          q,
+          q_vmaf,
+          target,
+        },
+      );
+    }
+
+    let q = self.level(q as u32);
     log_probes(
-      &mut vmaf_cq,
+      &mut self.probe_values(&vmaf_cq),
       frames as u32,
       self.probing_rate as u32,
       &chunk.name(),
-      q as u32,
+      metric.name(),
+      q,
       q_vmaf,
       Skip::None,
     );
+    if metric == Metric::Vmaf {
+      PROBE_SCORES.insert((chunk.temp.clone(), chunk.index), mean_score(&vmaf_cq));
+    }
+
+    Ok(q)
+  }
 
-    Ok(q as u32)
+  /// Returns the probes of a search with the values of their levels
+  fn probe_values(&self, probes: &[(f64, u32)]) -> Vec<(f64, u32)> {
+    probes
+      .iter()
+      .map(|&(score, level)| (score, self.level(level)))
+      .collect()
   }
 
-  fn vmaf_probe(&self, chunk: &Chunk, q: usize) -> Result<PathBuf, Box<EncoderCrash>> {
+  /// Verifies the q chosen by the search with a full framerate probe.
+  ///
+  /// If the verified score deviates from the target by more than `tolerance`,
+  /// the scores of the search are assumed to be off by the same amount, and q
+  /// is interpolated again from the corrected scores.
+  fn verify_target_q(
+    &self,
+    chunk: &Chunk,
+    vmaf_cq: &[(f64, u32)],
+    q: f64,
+    q_vmaf: f64,
+    tolerance: f64,
+  ) -> Result<(f64, f64), Box<EncoderCrash>> {
+    let verified = read_probe_vmaf(
+      self.vmaf_probe(chunk, q as u32, 1)?,
+      self.probing_statistic,
+      self.frame_weighting,
+      1,
+    )
+    .unwrap();
+
+    if (verified - self.target).abs() <= tolerance {
+      debug!(
+        "chunk {}: verified {}={}, VMAF={:.2}",
+        chunk.name(),
+        self.rate,
+        self.level(q as u32),
+        verified
+      );
+      return Ok((q, verified));
+    }
+
+    let (nudged_q, nudged_vmaf) = self.nudge_q(vmaf_cq, q_vmaf, verified, self.target);
+
+    debug!(
+      "chunk {}: verified VMAF={:.2} at {rate}={}, nudged to {rate}={}",
+      chunk.name(),
+      verified,
+      self.level(q as u32),
+      self.level(nudged_q as u32),
+      rate = self.rate
+    );
+
+    Ok((nudged_q, nudged_vmaf))
+  }
+
+  /// Nudges Q towards the target, assuming that the scores of the search are
+  /// off by as much as the verified score of the chosen Q
+  fn nudge_q(&self, vmaf_cq: &[(f64, u32)], q_vmaf: f64, verified: f64, target: f64) -> (f64, f64) {
+    // keep the corrected target within the probed scores, as the interpolation cannot extrapolate
+    let offset = verified - q_vmaf;
+    let (min_score, max_score) = vmaf_cq
+      .iter()
+      .fold((f64::MAX, f64::MIN), |(min, max), &(score, _)| {
+        (min.min(score), max.max(score))
+      });
+    let corrected_target = (target - offset).clamp(min_score, max_score);
+
+    let nudged_q = interpolate_target_q(vmaf_cq.to_vec(), corrected_target)
+      .unwrap()
+      .clamp(f64::from(self.min_q), f64::from(self.max_q));
+    let nudged_vmaf = interpolate_target_vmaf(vmaf_cq.to_vec(), nudged_q).unwrap() + offset;
+
+    (nudged_q, nudged_vmaf)
+  }
+
+  /// Returns the risk of the encode of the chunk missing the target, if it
+  /// can be verified, i.e. if its Q was chosen by a search in this run that
+  /// did not end at either end of the Q range
+  pub fn verify_risk(&self, chunk: &Chunk) -> Option<f64> {
+    let tolerance = self.verify_chunks?;
+    let search = SEARCHES.get(&(chunk.temp.clone(), chunk.index))?;
+    Some(search.risk(self.min_q, self.max_q, tolerance))
+  }
+
+  /// Scores the encoded chunk at the full framerate, and returns the Q to
+  /// encode it again with if its score misses the target by more than the
+  /// tolerance of `--verify-chunks`
+  pub fn verify_encoded_chunk(&self, chunk: &Chunk) -> Result<Option<u32>, Box<EncoderCrash>> {
+    let (Some(tolerance), Some(search)) = (
+      self.verify_chunks,
+      SEARCHES
+        .get(&(chunk.temp.clone(), chunk.index))
+        .map(|search| search.clone()),
+    ) else {
+      return Ok(None);
+    };
+
+    let fl_path = Path::new(&chunk.temp)
+      .join("split")
+      .join(format!("{}_verify.json", chunk.index));
+    vmaf::run_vmaf(
+      Path::new(&chunk.output()),
+      chunk.source_cmd.as_slice(),
+      self.vspipe_args.clone(),
+      &fl_path,
+      vmaf::select_model(
+        self.model.as_deref(),
+        self.model_auto.as_ref(),
+        self.vmaf_neg,
+        &self.vmaf_res,
+      )
+      .as_ref(),
+      &self.vmaf_res,
+      &self.vmaf_scaler,
+      1,
+      self.reference_filter(chunk).as_deref(),
+      self.vmaf_threads,
+      &[],
+    )?;
+    let verified =
+      read_probe_vmaf(fl_path, self.probing_statistic, self.frame_weighting, 1).unwrap();
+
+    if (verified - search.target).abs() <= tolerance {
+      debug!(
+        "chunk {}: encode verified at Q={:.0}, VMAF={:.2}",
+        chunk.name(),
+        search.q,
+        verified
+      );
+      return Ok(None);
+    }
+
+    let (nudged_q, _) = self.nudge_q(&search.probes, search.q_vmaf, verified, search.target);
+    let nudged_q = self.smooth(chunk, self.level(nudged_q.round() as u32));
+    if Some(nudged_q) == chunk.tq_cq {
+      return Ok(None);
+    }
+
+    info!(
+      "chunk {}: encode scored VMAF={:.2} at {}={}, re-encoding at {}={}",
+      chunk.name(),
+      verified,
+      self.rate,
+      self.level(search.q.round() as u32),
+      self.rate,
+      nudged_q
+    );
+    Ok(Some(nudged_q))
+  }
+
+  /// Limits the difference of the Q chosen for a chunk from the Qs of the
+  /// neighboring chunks whose search has finished, with `--tq-smoothing`
+  fn smooth(&self, chunk: &Chunk, q: u32) -> u32 {
+    let Some(max_diff) = self.smoothing else {
+      return q;
+    };
+    let smoothed = smooth_q(q, neighbor_qs(chunk), max_diff).clamp(self.min_q, self.max_q);
+    if smoothed != q {
+      debug!(
+        "chunk {}: {}={} smoothed to {} towards the neighboring chunks",
+        chunk.name(),
+        self.rate,
+        q,
+        smoothed
+      );
+    }
+    smoothed
+  }
+
+  /// Scores the probe of the chunk at a level of the search, encoded by
+  /// [`Self::vmaf_probe`], with SSIMULACRA2
+  fn ssimu2_probe(
+    &self,
+    chunk: &Chunk,
+    level: u32,
+    probing_rate: usize,
+  ) -> Result<f64, Box<EncoderCrash>> {
+    let split = Path::new(&chunk.temp).join("split");
+
+    // the frames of the chunk the probes encode, filtered like the reference of
+    // VMAF before they are sampled
+    let mut filter = self.reference_filter(chunk).into_iter().collect::<Vec<_>>();
+    filter.push(format!("select=not(mod(n\\,{probing_rate}))"));
+    if let Some(res) = &self.probe_res {
+      filter.push(format!(
+        "scale={res}:flags={}:force_original_aspect_ratio=decrease",
+        self.vmaf_scaler
+      ));
+    }
+    let reference = split.join(format!("{}_ssimu2_{probing_rate}.y4m", chunk.index));
+    ssimulacra2::write_reference(
+      &chunk.source_cmd,
+      &self.vspipe_args,
+      &filter.join(","),
+      &reference,
+    )?;
+
+    let probe = split.join(format!("v_{}_{}.ivf", self.level(level), chunk.index));
+    let scores = ssimulacra2::run_probe(&reference, &probe)?;
+    Ok(vmaf::aggregate_scores(
+      scores,
+      self.probing_statistic,
+      self.frame_weighting,
+      probing_rate,
+    ))
+  }
+
+  /// Returns the filters of the frames of the chunk that encodes are compared
+  /// with, which are denoised like the frames the chunk encodes
+  fn reference_filter(&self, chunk: &Chunk) -> Option<String> {
+    let filters = chunk
+      .denoise_filter()
+      .into_iter()
+      .chain(self.vmaf_filter.clone())
+      .collect::<Vec<_>>();
+    (!filters.is_empty()).then(|| filters.join(","))
+  }
+
+  /// Encodes and scores a probe of the chunk at a level of the search
+  fn vmaf_probe(
+    &self,
+    chunk: &Chunk,
+    level: u32,
+    probing_rate: usize,
+  ) -> Result<PathBuf, Box<EncoderCrash>> {
+    let q = self.level(level);
     let vmaf_threads = if self.vmaf_threads == 0 {
       vmaf_auto_threads(self.workers)
     } else {
@@ -151,17 +860,25 @@ impl TargetQuality {
     let cmd = self.encoder.probe_cmd(
       self.temp.clone(),
       chunk.index,
-      q,
-      self.pix_format,
-      self.probing_rate,
+      self.rate,
+      q as usize,
+      chunk.pix_format.unwrap_or(self.pix_format),
+      probing_rate,
       vmaf_threads,
       self.video_params.clone(),
       self.probe_slow,
+      self
+        .probe_res
+        .as_deref()
+        .map(|res| (res, self.vmaf_scaler.as_str())),
+      chunk.denoise_filter().as_deref(),
     );
 
     let future = async {
       let mut source = if let [pipe_cmd, args @ ..] = &*chunk.source_cmd {
-        tokio::process::Command::new(pipe_cmd)
+        let mut command = tokio::process::Command::new(pipe_cmd);
+        cgroup::apply_async(&mut command);
+        command
           .args(args)
           .stderr(if cfg!(windows) {
             Stdio::null()
@@ -178,7 +895,9 @@ impl TargetQuality {
       let source_pipe_stdout: Stdio = source.stdout.take().unwrap().try_into().unwrap();
 
       let mut source_pipe = if let [ffmpeg, args @ ..] = &*cmd.0 {
-        tokio::process::Command::new(ffmpeg)
+        let mut command = tokio::process::Command::new(ffmpeg);
+        cgroup::apply_async(&mut command);
+        command
           .args(args)
           .stdin(source_pipe_stdout)
           .stdout(Stdio::piped())
@@ -196,7 +915,9 @@ impl TargetQuality {
       let source_pipe_stdout: Stdio = source_pipe.stdout.take().unwrap().try_into().unwrap();
 
       let enc_pipe = if let [cmd, args @ ..] = &*cmd.1 {
-        tokio::process::Command::new(cmd.as_ref())
+        let mut command = tokio::process::Command::new(cmd.as_ref());
+        cgroup::apply_async(&mut command);
+        command
           .args(args.iter().map(AsRef::as_ref))
           .stdin(source_pipe_stdout)
           .stdout(Stdio::piped())
@@ -223,6 +944,7 @@ impl TargetQuality {
           stderr: enc_output.stderr.into(),
           source_pipe_stderr: source_pipe_output.stderr.into(),
           ffmpeg_pipe_stderr: None,
+          pipeline: Vec::new(),
         };
         error!("[chunk {}] {}", chunk.index, e);
         return Err(e);
@@ -238,24 +960,43 @@ impl TargetQuality {
 
     rt.block_on(future)?;
 
+    self.score_vmaf(chunk, q, probing_rate)
+  }
+
+  /// Scores the encode of the chunk at `q` in the split folder, written like
+  /// the probes, with VMAF, and returns the path of its log
+  pub(crate) fn score_vmaf(
+    &self,
+    chunk: &Chunk,
+    q: u32,
+    probing_rate: usize,
+  ) -> Result<PathBuf, Box<EncoderCrash>> {
     let probe_name = Path::new(&chunk.temp)
       .join("split")
       .join(format!("v_{q}_{}.ivf", chunk.index));
     let fl_path = Path::new(&chunk.temp)
       .join("split")
-      .join(format!("{}.json", chunk.index));
+      .join(format!("{}_{q}.json", chunk.index));
 
+    let res = self.probe_res.as_ref().unwrap_or(&self.vmaf_res);
     vmaf::run_vmaf(
       &probe_name,
       chunk.source_cmd.as_slice(),
       self.vspipe_args.clone(),
       &fl_path,
-      self.model.as_ref(),
-      &self.vmaf_res,
+      vmaf::select_model(
+        self.model.as_deref(),
+        self.model_auto.as_ref(),
+        self.vmaf_neg,
+        res,
+      )
+      .as_ref(),
+      res,
       &self.vmaf_scaler,
-      self.probing_rate,
-      self.vmaf_filter.as_deref(),
+      probing_rate,
+      self.reference_filter(chunk).as_deref(),
       self.vmaf_threads,
+      &[],
     )?;
 
     Ok(fl_path)
@@ -265,7 +1006,30 @@ impl TargetQuality {
     &self,
     chunk: &mut Chunk,
   ) -> Result<(), Box<EncoderCrash>> {
-    chunk.tq_cq = Some(self.per_shot_target_quality(chunk)?);
+    let tq_cq = match chunk.target_quality {
+      ChunkTarget::Inherit => Some(self.per_shot_target_quality(chunk)?),
+      ChunkTarget::Disabled => None,
+      ChunkTarget::Target(target) => {
+        // the zone may also have reset the encoder settings the probes are based on
+        let zone_tq = Self {
+          target,
+          encoder: chunk.encoder,
+          video_params: chunk.video_params.clone(),
+          ..self.clone()
+        };
+        Some(zone_tq.per_shot_target_quality(chunk)?)
+      }
+    };
+    let tq_cq = tq_cq.map(|q| self.smooth(chunk, q));
+
+    if let Some(q) = tq_cq {
+      CONVERGED_Q.insert((chunk.temp.clone(), chunk.index), q);
+      logging::event(&Event::TargetQuality {
+        chunk: chunk.index,
+        q,
+      });
+    }
+    chunk.tq_cq = tq_cq;
     Ok(())
   }
 }
@@ -333,6 +1097,22 @@ pub fn interpolate_target_vmaf(scores: Vec<(f64, u32)>, q: f64) -> Result<f64, E
   Ok(spline.sample(q).unwrap())
 }
 
+/// Metric a search of target quality meets its target by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Metric {
+  Vmaf,
+  Ssimu2,
+}
+
+impl Metric {
+  const fn name(self) -> &'static str {
+    match self {
+      Self::Vmaf => "VMAF",
+      Self::Ssimu2 => "SSIMULACRA2",
+    }
+  }
+}
+
 #[derive(Copy, Clone)]
 pub enum Skip {
   High,
@@ -345,6 +1125,7 @@ pub fn log_probes(
   frames: u32,
   probing_rate: u32,
   chunk_idx: &str,
+  metric: &str,
   target_q: u32,
   target_vmaf: f64,
   skip: Skip,
@@ -357,8 +1138,9 @@ pub fn log_probes(
     chunk_idx, probing_rate, frames
   );
   debug!(
-    "chunk {}: TQ-Probes: {:.2?}{}",
+    "chunk {}: TQ-Probes ({}): {:.2?}{}",
     chunk_idx,
+    metric,
     vmaf_cq_scores,
     match skip {
       Skip::High => " Early Skip High Q",
@@ -367,8 +1149,8 @@ pub fn log_probes(
     }
   );
   debug!(
-    "chunk {}: Target Q={:.0}, VMAF={:.2}",
-    chunk_idx, target_q, target_vmaf
+    "chunk {}: Target Q={:.0}, {}={:.2}",
+    chunk_idx, target_q, metric, target_vmaf
   );
 }
 
@@ -447,7 +1229,7 @@ fn lagrange_bisect(p: &[(u32, f64)], y: f64) -> (u32, f64) {
 
 #[cfg(test)]
 mod tests {
-  use crate::target_quality::lagrange_bisect;
+  use crate::target_quality::{lagrange_bisect, smooth_q, QualityTargets, Search};
 
   #[test]
   fn test_bisect() {
@@ -463,4 +1245,58 @@ mod tests {
     assert!(lagrange_bisect(&sorted, -1.0).0 == 0);
     assert!(lagrange_bisect(&sorted, 2.0 * 256.0 * 256.0).0 == 256);
   }
+
+  #[test]
+  fn test_search_risk() {
+    let search = |probes: Vec<(f64, u32)>, q: f64| Search {
+      probes,
+      q,
+      q_vmaf: 95.0,
+      target: 95.0,
+    };
+
+    // 0.5 VMAF per Q step, halfway through the Q range
+    let flat = search(vec![(97.0, 20), (93.0, 28)], 24.0);
+    assert!((flat.risk(10, 40, 1.0) - 0.5 * 46.0 / 30.0).abs() < 1e-9);
+
+    // the same slope needs a lower Q on complex content, which is riskier
+    let complex = search(vec![(97.0, 12), (93.0, 20)], 16.0);
+    assert!(complex.risk(10, 40, 1.0) > flat.risk(10, 40, 1.0));
+
+    // steeper scores are riskier, and a wider tolerance is less so
+    let steep = search(vec![(99.0, 22), (91.0, 26)], 24.0);
+    assert!(steep.risk(10, 40, 1.0) > 1.0);
+    assert!(steep.risk(10, 40, 4.0) < 1.0);
+  }
+
+  #[test]
+  fn test_smooth_q() {
+    assert_eq!(smooth_q(30, [None, None], 4), 30);
+    assert_eq!(smooth_q(30, [Some(20), None], 4), 24);
+    assert_eq!(smooth_q(14, [None, Some(20)], 4), 16);
+    assert_eq!(smooth_q(22, [Some(20), Some(24)], 4), 22);
+    // the neighbors are too far apart for both to be met
+    assert_eq!(smooth_q(30, [Some(20), Some(40)], 4), 24);
+    assert_eq!(smooth_q(0, [Some(2), None], 4), 0);
+  }
+
+  #[test]
+  fn test_quality_targets() {
+    assert_eq!(
+      "95".parse(),
+      Ok(QualityTargets {
+        vmaf: 95.0,
+        ssimu2: None
+      })
+    );
+    assert_eq!(
+      "vmaf=95.5,ssimu2=80".parse(),
+      Ok(QualityTargets {
+        vmaf: 95.5,
+        ssimu2: Some(80.0)
+      })
+    );
+    assert!("ssimu2=80".parse::<QualityTargets>().is_err());
+    assert!("vmaf=95,psnr=40".parse::<QualityTargets>().is_err());
+  }
 }
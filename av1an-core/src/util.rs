@@ -1,5 +1,6 @@
-use std::io;
 use std::path::{absolute, Path, PathBuf};
+use std::time::Duration;
+use std::{io, thread};
 
 /// Count the number of elements passed to this macro.
 ///
@@ -134,6 +135,21 @@ pub fn read_in_dir(path: &Path) -> anyhow::Result<impl Iterator<Item = PathBuf>>
   }))
 }
 
+/// Returns the total size of the files in the folder and its subfolders,
+/// skipping those that cannot be read, e.g. as they were just deleted
+pub(crate) fn dir_size(path: &Path) -> u64 {
+  std::fs::read_dir(path).map_or(0, |dir| {
+    dir
+      .filter_map(Result::ok)
+      .map(|entry| match entry.file_type() {
+        Ok(file_type) if file_type.is_dir() => dir_size(&entry.path()),
+        Ok(_) => entry.metadata().map_or(0, |meta| meta.len()),
+        Err(_) => 0,
+      })
+      .sum()
+  })
+}
+
 #[inline]
 pub(crate) fn to_absolute_path(path: &Path) -> io::Result<PathBuf> {
   if cfg!(target_os = "windows") {
@@ -143,10 +159,165 @@ pub(crate) fn to_absolute_path(path: &Path) -> io::Result<PathBuf> {
   }
 }
 
+/// Runs a filesystem operation, retrying it with exponential backoff if it
+/// fails with an error that is likely transient.
+///
+/// Network filesystems (e.g. NFS or SMB temp directories) occasionally fail
+/// reads and writes that succeed when retried shortly after.
+pub fn retry_io<T>(mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+  const ATTEMPTS: u32 = 5;
+  const INITIAL_DELAY: Duration = Duration::from_millis(100);
+
+  let mut delay = INITIAL_DELAY;
+  let mut attempt = 1;
+  loop {
+    match op() {
+      Err(e) if attempt < ATTEMPTS && is_transient(&e) => {
+        debug!(
+          "transient I/O error (attempt {}/{}), retrying in {:?}: {}",
+          attempt, ATTEMPTS, delay, e
+        );
+        thread::sleep(delay);
+        delay *= 2;
+        attempt += 1;
+      }
+      result => return result,
+    }
+  }
+}
+
+/// Whether an I/O error is likely to go away if the operation is retried
+fn is_transient(e: &io::Error) -> bool {
+  if matches!(
+    e.kind(),
+    io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+  ) {
+    return true;
+  }
+
+  #[cfg(unix)]
+  let transient = [libc::EIO, libc::EAGAIN, libc::EBUSY, libc::ESTALE];
+  // ERROR_SHARING_VIOLATION, ERROR_LOCK_VIOLATION, ERROR_UNEXP_NET_ERR,
+  // ERROR_NETNAME_DELETED and ERROR_SEM_TIMEOUT
+  #[cfg(windows)]
+  let transient = [32, 33, 59, 64, 121];
+  #[cfg(not(any(unix, windows)))]
+  let transient: [i32; 0] = [];
+
+  e.raw_os_error()
+    .is_some_and(|code| transient.contains(&code))
+}
+
+/// Parses a size in bytes, with an optional binary suffix (`K`, `M`, `G` or `T`,
+/// optionally followed by `B` or `iB`), e.g. `512M` or `8GiB`.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+  let size = size.trim();
+  let split = size
+    .find(|c: char| !c.is_ascii_digit() && c != '.')
+    .unwrap_or(size.len());
+  let (number, suffix) = size.split_at(split);
+
+  let number: f64 = number
+    .parse()
+    .map_err(|_| format!("invalid size: {size:?}"))?;
+  let multiplier: u64 = match suffix.trim().to_ascii_uppercase().as_str() {
+    "" | "B" => 1,
+    "K" | "KB" | "KIB" => 1 << 10,
+    "M" | "MB" | "MIB" => 1 << 20,
+    "G" | "GB" | "GIB" => 1 << 30,
+    "T" | "TB" | "TIB" => 1 << 40,
+    _ => return Err(format!("invalid size suffix: {suffix:?}")),
+  };
+
+  Ok((number * multiplier as f64) as u64)
+}
+
+/// Formats a size in bytes with a binary unit, e.g. `4.00 KiB`
+pub fn format_size(bytes: u64) -> String {
+  const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+  let mut size = bytes as f64;
+  let mut unit = 0;
+  while size >= 1024.0 && unit < UNITS.len() - 1 {
+    size /= 1024.0;
+    unit += 1;
+  }
+  if unit == 0 {
+    format!("{bytes} B")
+  } else {
+    format!("{size:.2} {}", UNITS[unit])
+  }
+}
+
+/// Parses a resolution in the form `WIDTHxHEIGHT`, e.g. `1920x1080`.
+pub fn parse_resolution(resolution: &str) -> Result<(u32, u32), String> {
+  resolution
+    .split_once('x')
+    .and_then(|(width, height)| Some((width.trim().parse().ok()?, height.trim().parse().ok()?)))
+    .filter(|&(width, height)| width > 0 && height > 0)
+    .ok_or_else(|| format!("invalid resolution: {resolution:?}, expected WIDTHxHEIGHT"))
+}
+
+/// Replaces the `{name}` placeholders of a template with their values, where
+/// `{{` and `}}` are literal braces
+pub fn fill_template(template: &str, values: &[(&str, String)]) -> Result<String, String> {
+  let mut filled = String::with_capacity(template.len());
+  let mut chars = template.chars();
+  while let Some(c) = chars.next() {
+    match c {
+      '{' if chars.as_str().starts_with('{') => {
+        chars.next();
+        filled.push('{');
+      }
+      '}' if chars.as_str().starts_with('}') => {
+        chars.next();
+        filled.push('}');
+      }
+      '{' => {
+        let rest = chars.as_str();
+        let Some(end) = rest.find('}') else {
+          return Err(format!("unclosed placeholder in {template:?}"));
+        };
+        let name = &rest[..end];
+        let Some((_, value)) = values.iter().find(|(key, _)| *key == name) else {
+          let known: Vec<_> = values.iter().map(|(key, _)| *key).collect();
+          return Err(format!(
+            "unknown placeholder {{{name}}} in {template:?}, expected one of {}",
+            known.join(", ")
+          ));
+        };
+        filled.push_str(value);
+        chars = rest[end + 1..].chars();
+      }
+      '}' => return Err(format!("unmatched }} in {template:?}")),
+      c => filled.push(c),
+    }
+  }
+
+  Ok(filled)
+}
+
 #[cfg(test)]
 mod tests {
   use std::borrow::Cow;
 
+  use super::{fill_template, parse_size, retry_io};
+
+  #[test]
+  fn output_templates() {
+    let values = [("stem", "movie".to_owned()), ("encoder", "aom".to_owned())];
+    assert_eq!(
+      fill_template("{stem}.{encoder}.mkv", &values).unwrap(),
+      "movie.aom.mkv"
+    );
+    assert_eq!(
+      fill_template("out/{{{stem}}}.mkv", &values).unwrap(),
+      "out/{movie}.mkv"
+    );
+    assert!(fill_template("{stem}.{crf}.mkv", &values).is_err());
+    assert!(fill_template("{stem.mkv", &values).is_err());
+    assert!(fill_template("stem}.mkv", &values).is_err());
+  }
+
   #[test]
   fn count_macro() {
     assert_eq!(crate::count!["rav1e", "-s", "10",], 3);
@@ -180,4 +351,36 @@ mod tests {
 
     assert_eq!(v1, v2);
   }
+
+  #[test]
+  fn size_suffixes() {
+    assert_eq!(parse_size("1024"), Ok(1024));
+    assert_eq!(parse_size("512M"), Ok(512 << 20));
+    assert_eq!(parse_size("8GiB"), Ok(8 << 30));
+    assert_eq!(parse_size("1.5g"), Ok(3 << 29));
+    assert!(parse_size("8X").is_err());
+    assert!(parse_size("G").is_err());
+  }
+
+  #[test]
+  fn retry_transient_errors() {
+    let mut attempts = 0;
+    let result = retry_io(|| {
+      attempts += 1;
+      if attempts < 3 {
+        Err(std::io::ErrorKind::Interrupted.into())
+      } else {
+        Ok(attempts)
+      }
+    });
+    assert_eq!(result.unwrap(), 3);
+
+    let mut attempts = 0;
+    let result: std::io::Result<()> = retry_io(|| {
+      attempts += 1;
+      Err(std::io::ErrorKind::NotFound.into())
+    });
+    assert!(result.is_err());
+    assert_eq!(attempts, 1);
+  }
 }
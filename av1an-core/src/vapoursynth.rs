@@ -1,62 +1,77 @@
 use std::collections::HashSet;
+use std::fmt::Write as _;
 use std::fs::File;
-use std::io::Write;
+use std::io::{BufRead, BufReader, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
 
-use anyhow::{anyhow, bail};
+use anyhow::{anyhow, bail, Context};
+use ffmpeg::format::Pixel;
 use once_cell::sync::Lazy;
 use path_abs::PathAbs;
 use vapoursynth::prelude::*;
 use vapoursynth::video_info::VideoInfo;
 
-use super::ChunkMethod;
+use super::{BestSourceCacheMode, ChunkMethod};
+use crate::ffmpeg::{eval_size, filter_option, scaled_size, video_filters};
+use crate::interlace::{qtgmc_script, FieldOrder};
+use crate::progress_bar::index_progress_bar;
 use crate::util::to_absolute_path;
+use crate::Verbosity;
 
-static VAPOURSYNTH_PLUGINS: Lazy<HashSet<String>> = Lazy::new(|| {
-  let environment = Environment::new().expect("Failed to initialize VapourSynth environment");
+pub const LSMASH_PLUGIN: &str = "systems.innocent.lsmas";
+pub const FFMS2_PLUGIN: &str = "com.vapoursynth.ffms2";
+pub const DGDECNV_PLUGIN: &str = "com.vapoursynth.dgdecodenv";
+pub const BESTSOURCE_PLUGIN: &str = "com.vapoursynth.bestsource";
+
+static VAPOURSYNTH_PLUGINS: Lazy<HashSet<String>> =
+  Lazy::new(|| plugins().expect("Failed to load the VapourSynth plugins"));
+
+/// Returns the identifiers of the installed VapourSynth plugins, or an error
+/// if the VapourSynth core cannot be created
+pub fn plugins() -> anyhow::Result<HashSet<String>> {
+  let environment = Environment::new().context("Failed to initialize VapourSynth environment")?;
   let core = environment
     .get_core()
-    .expect("Failed to get VapourSynth core");
+    .context("Failed to get VapourSynth core")?;
 
   let plugins = core.plugins();
-  plugins
-    .keys()
-    .filter_map(|plugin| {
-      plugins
-        .get::<&[u8]>(plugin)
-        .ok()
-        .and_then(|slice| simdutf8::basic::from_utf8(slice).ok())
-        .and_then(|s| s.split(';').nth(1))
-        .map(ToOwned::to_owned)
-    })
-    .collect()
-});
+  Ok(
+    plugins
+      .keys()
+      .filter_map(|plugin| {
+        plugins
+          .get::<&[u8]>(plugin)
+          .ok()
+          .and_then(|slice| simdutf8::basic::from_utf8(slice).ok())
+          .and_then(|s| s.split(';').nth(1))
+          .map(ToOwned::to_owned)
+      })
+      .collect(),
+  )
+}
 
 pub fn is_lsmash_installed() -> bool {
-  static LSMASH_PRESENT: Lazy<bool> =
-    Lazy::new(|| VAPOURSYNTH_PLUGINS.contains("systems.innocent.lsmas"));
+  static LSMASH_PRESENT: Lazy<bool> = Lazy::new(|| VAPOURSYNTH_PLUGINS.contains(LSMASH_PLUGIN));
 
   *LSMASH_PRESENT
 }
 
 pub fn is_ffms2_installed() -> bool {
-  static FFMS2_PRESENT: Lazy<bool> =
-    Lazy::new(|| VAPOURSYNTH_PLUGINS.contains("com.vapoursynth.ffms2"));
+  static FFMS2_PRESENT: Lazy<bool> = Lazy::new(|| VAPOURSYNTH_PLUGINS.contains(FFMS2_PLUGIN));
 
   *FFMS2_PRESENT
 }
 
 pub fn is_dgdecnv_installed() -> bool {
-  static DGDECNV_PRESENT: Lazy<bool> =
-    Lazy::new(|| VAPOURSYNTH_PLUGINS.contains("com.vapoursynth.dgdecodenv"));
+  static DGDECNV_PRESENT: Lazy<bool> = Lazy::new(|| VAPOURSYNTH_PLUGINS.contains(DGDECNV_PLUGIN));
 
   *DGDECNV_PRESENT
 }
 
 pub fn is_bestsource_installed() -> bool {
   static BESTSOURCE_PRESENT: Lazy<bool> =
-    Lazy::new(|| VAPOURSYNTH_PLUGINS.contains("com.vapoursynth.bestsource"));
+    Lazy::new(|| VAPOURSYNTH_PLUGINS.contains(BESTSOURCE_PLUGIN));
 
   *BESTSOURCE_PRESENT
 }
@@ -188,10 +203,79 @@ fn get_transfer(env: &Environment) -> anyhow::Result<u8> {
   Ok(transfer)
 }
 
+/// Detects the scene changes of the script with the wwxd plugin, calling
+/// `callback` with the number of frames read so far. Returns the first frame
+/// of every scene.
+pub fn wwxd_scene_changes(
+  source: &Path,
+  vspipe_args_map: OwnedMap,
+  callback: &dyn Fn(usize),
+) -> anyhow::Result<Vec<usize>> {
+  const OUTPUT_INDEX: i32 = 0;
+
+  let mut environment = Environment::new().unwrap();
+  if environment.set_variables(&vspipe_args_map).is_err() {
+    bail!("Failed to set vspipe arguments");
+  }
+  environment
+    .eval_file(source, EvalFlags::SetWorkingDir)
+    .with_context(|| format!("Failed to evaluate {}", source.display()))?;
+  let frames = get_num_frames(&environment)?;
+
+  #[cfg(feature = "vapoursynth_new_api")]
+  let (node, _) = environment.get_output(OUTPUT_INDEX)?;
+  #[cfg(not(feature = "vapoursynth_new_api"))]
+  let node = environment.get_output(OUTPUT_INDEX)?;
+
+  let core = environment.get_core()?;
+  let api = API::get().context("Failed to get the VapourSynth API")?;
+
+  // wwxd only reads 8-bit YUV
+  let mut args = OwnedMap::new(api);
+  args.set_node("clip", &node)?;
+  args.set_int("format", PresetFormat::YUV420P8 as i64)?;
+  let converted = core
+    .get_plugin_by_namespace("resize")?
+    .context("The resize plugin of VapourSynth is missing")?
+    .invoke("Bilinear", &args)?;
+  if let Some(error) = converted.error() {
+    bail!("Failed to convert the clip for wwxd: {error}");
+  }
+
+  let mut args = OwnedMap::new(api);
+  args.set_node("clip", &converted.get_node("clip")?)?;
+  let detected = core
+    .get_plugin_by_namespace("wwxd")?
+    .context("The wwxd plugin of VapourSynth is not installed")?
+    .invoke("WWXD", &args)?;
+  if let Some(error) = detected.error() {
+    bail!("wwxd failed: {error}");
+  }
+  let node = detected.get_node("clip")?;
+
+  let mut scene_changes = vec![0];
+  for n in 0..frames {
+    let frame = node.get_frame(n)?;
+    if n > 0 && frame.props().get::<i64>("Scenechange").unwrap_or(0) == 1 {
+      scene_changes.push(n);
+    }
+    callback(n + 1);
+  }
+
+  Ok(scene_changes)
+}
+
+/// Creates the script that loads the source with the chunk method, optionally
+/// deinterlacing it with QTGMC, with the index of the source in `index_dir`
 pub fn create_vs_file(
   temp: &str,
+  index_dir: &Path,
   source: &Path,
   chunk_method: ChunkMethod,
+  qtgmc: Option<FieldOrder>,
+  bestsource_cachemode: BestSourceCacheMode,
+  gpu_devices: &[usize],
+  filters: Option<&str>,
 ) -> anyhow::Result<PathBuf> {
   let temp: &Path = temp.as_ref();
   let source = to_absolute_path(source)?;
@@ -200,7 +284,7 @@ pub fn create_vs_file(
 
   let mut load_script = File::create(&load_script_path)?;
 
-  let cache_file = PathAbs::new(temp.join("split").join(format!(
+  let cache_file = PathAbs::new(index_dir.join(format!(
     "cache.{}",
     match chunk_method {
       ChunkMethod::FFMS2 => "ffindex",
@@ -213,22 +297,36 @@ pub fn create_vs_file(
 
   if chunk_method == ChunkMethod::DGDECNV {
     // Run dgindexnv to generate the .dgi index file
-    let dgindexnv_output = temp.join("split").join("index.dgi");
+    let dgindexnv_output = index_dir.join("index.dgi");
 
-    Command::new("dgindexnv")
+    let output = Command::new("dgindexnv")
       .arg("-h")
       .arg("-i")
-      .arg(source)
+      .arg(&source)
       .arg("-o")
       .arg(&dgindexnv_output)
-      .output()?;
+      .output()
+      .context("Failed to run dgindexnv")?;
+    if !output.status.success() || !dgindexnv_output.exists() {
+      bail!(
+        "dgindexnv failed to index {} ({}): {}",
+        source.display(),
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+      );
+    }
 
     let dgindex_path = to_absolute_path(&dgindexnv_output)?;
+    // the workers decode on the GPU passed with `-a gpu=<device>`, and the
+    // script is otherwise decoded on the first GPU of --gpu-devices
+    let device = gpu_devices.first().copied().unwrap_or(0);
+    // pulldown flags are ignored, so that the frames are the coded frames
+    // counted (and trimmed into chunks) with ffmpeg
     load_script.write_all(
       format!(
         "from vapoursynth import core\n\
               core.max_cache_size=1024\n\
-            core.dgdecodenv.DGSource(source={dgindex_path:?}).set_output()"
+            clip = core.dgdecodenv.DGSource(source={dgindex_path:?}, fieldop=2, deviceid=int(globals().get(\"gpu\", {device})))\n"
       )
       .as_bytes(),
     )?;
@@ -237,7 +335,8 @@ pub fn create_vs_file(
       format!(
         "from vapoursynth import core\n\
           core.max_cache_size=1024\n\
-        core.bs.VideoSource({source:?}, cachepath={cache_file:?}).set_output()"
+        clip = core.bs.VideoSource({source:?}, cachepath={cache_file:?}, cachemode={}, showprogress=True)\n",
+        bestsource_cachemode.cachemode()
       )
       .as_bytes(),
     )?;
@@ -247,7 +346,7 @@ pub fn create_vs_file(
       format!(
         "from vapoursynth import core\n\
             core.max_cache_size=1024\n\
-      core.{}({:?}, cachefile={:?}).set_output()",
+      clip = core.{}({:?}, cachefile={:?})\n",
         match chunk_method {
           ChunkMethod::FFMS2 => "ffms2.Source",
           ChunkMethod::LSMASH => "lsmas.LWLibavSource",
@@ -260,9 +359,171 @@ pub fn create_vs_file(
     )?;
   }
 
+  if let Some(order) = qtgmc {
+    load_script.write_all(qtgmc_script(order).as_bytes())?;
+  }
+  if let Some(filters) = filters {
+    load_script.write_all(filters.as_bytes())?;
+  }
+  load_script.write_all(b"clip.set_output()\n")?;
+
   Ok(load_script_path)
 }
 
+/// Returns the lines of a VapourSynth script that apply the ffmpeg filters and
+/// convert `clip` to the pixel format
+///
+/// Returns `None` if the arguments are not only filters that can be done in
+/// VapourSynth, which cannot crop or scale the chroma planes of
+/// `source_format` by half a sample, unlike ffmpeg.
+pub fn filter_script(
+  ffmpeg_filter_args: &[String],
+  resolution: (u32, u32),
+  source_format: Pixel,
+  format: Pixel,
+) -> Option<String> {
+  match ffmpeg_filter_args {
+    [] => {}
+    [flag, _] if matches!(flag.as_str(), "-vf" | "-filter:v") => {}
+    _ => return None,
+  }
+
+  let (sub_w, sub_h) = chroma_subsampling(source_format);
+  let fits = |horizontal: f64, vertical: f64| horizontal % sub_w == 0.0 && vertical % sub_h == 0.0;
+
+  let mut script = String::from("import vapoursynth as vs\n");
+  let mut size = (f64::from(resolution.0), f64::from(resolution.1));
+  for (name, options) in video_filters(ffmpeg_filter_args) {
+    match name {
+      "scale" => {
+        let w = filter_option(options, &["w", "width"], 0)?;
+        let h = filter_option(options, &["h", "height"], 1)?;
+        size = scaled_size(size, &w, &h)?;
+        if !fits(size.0, size.1) {
+          return None;
+        }
+        writeln!(
+          script,
+          "clip = core.resize.Bicubic(clip, width={}, height={})",
+          size.0, size.1
+        )
+        .ok()?;
+      }
+      "crop" => {
+        let w = filter_option(options, &["w", "out_w"], 0).unwrap_or_else(|| "iw".to_owned());
+        let h = filter_option(options, &["h", "out_h"], 1).unwrap_or_else(|| "ih".to_owned());
+        let (w, h) = (eval_size(&w, size)?, eval_size(&h, size)?);
+        let x = filter_option(options, &["x"], 2)
+          .map_or(Some((size.0 - w) / 2.0), |x| eval_size(&x, size))?;
+        let y = filter_option(options, &["y"], 3)
+          .map_or(Some((size.1 - h) / 2.0), |y| eval_size(&y, size))?;
+        let (left, top) = (x.floor(), y.floor());
+        let (right, bottom) = ((size.0 - w - left).max(0.0), (size.1 - h - top).max(0.0));
+        if !(fits(left, top) && fits(right, bottom)) {
+          return None;
+        }
+        writeln!(
+          script,
+          "clip = core.std.Crop(clip, left={left}, right={right}, top={top}, bottom={bottom})",
+        )
+        .ok()?;
+        size = (w, h);
+      }
+      "hflip" => script.push_str("clip = core.std.FlipHorizontal(clip)\n"),
+      "vflip" => script.push_str("clip = core.std.FlipVertical(clip)\n"),
+      _ => return None,
+    }
+  }
+
+  writeln!(
+    script,
+    "clip = core.resize.Bicubic(clip, format=vs.{})",
+    vs_format(format)?
+  )
+  .ok()?;
+  Some(script)
+}
+
+/// Returns the horizontal and vertical chroma subsampling of a pixel format,
+/// which is assumed to be 4:2:0 for the formats not known here
+const fn chroma_subsampling(format: Pixel) -> (f64, f64) {
+  match format {
+    Pixel::YUV444P
+    | Pixel::YUV444P10LE
+    | Pixel::YUV444P12LE
+    | Pixel::GRAY8
+    | Pixel::GRAY10LE
+    | Pixel::GRAY12LE
+    | Pixel::GBRP
+    | Pixel::RGB24 => (1.0, 1.0),
+    Pixel::YUV422P | Pixel::YUV422P10LE | Pixel::YUV422P12LE => (2.0, 1.0),
+    _ => (2.0, 2.0),
+  }
+}
+
+/// Returns the name of the VapourSynth preset format of a pixel format
+const fn vs_format(format: Pixel) -> Option<&'static str> {
+  Some(match format {
+    Pixel::YUV420P => "YUV420P8",
+    Pixel::YUV422P => "YUV422P8",
+    Pixel::YUV444P => "YUV444P8",
+    Pixel::YUV420P10LE => "YUV420P10",
+    Pixel::YUV422P10LE => "YUV422P10",
+    Pixel::YUV444P10LE => "YUV444P10",
+    Pixel::YUV420P12LE => "YUV420P12",
+    Pixel::YUV422P12LE => "YUV422P12",
+    Pixel::YUV444P12LE => "YUV444P12",
+    Pixel::GRAY8 => "GRAY8",
+    Pixel::GRAY10LE => "GRAY10",
+    Pixel::GRAY12LE => "GRAY12",
+    _ => return None,
+  })
+}
+
+/// Indexes the source of a BestSource script up front, showing its progress
+///
+/// BestSource scans the whole source to index it, so the workers share the
+/// index rather than each of them indexing the source.
+pub fn index_bestsource(script: &Path, verbosity: Verbosity) -> anyhow::Result<()> {
+  let mut child = Command::new("vspipe")
+    .arg("-i")
+    .arg(script)
+    .arg("-")
+    .stdout(Stdio::null())
+    .stderr(Stdio::piped())
+    .spawn()
+    .context("Failed to run vspipe to index the source with BestSource")?;
+
+  let progress = index_progress_bar(verbosity);
+  let mut messages = Vec::new();
+  for line in BufReader::new(child.stderr.take().unwrap()).split(b'\n') {
+    let line = String::from_utf8_lossy(&line?).into_owned();
+    match index_progress(&line) {
+      Some(percent) => progress.set_position(percent),
+      None => messages.push(line),
+    }
+  }
+  progress.finish_and_clear();
+
+  let status = child.wait()?;
+  if !status.success() {
+    bail!(
+      "BestSource failed to index the source ({}): {}",
+      status,
+      messages.join("\n").trim()
+    );
+  }
+
+  Ok(())
+}
+
+/// Parses the percentage of the source that is indexed from a progress
+/// message of BestSource
+fn index_progress(line: &str) -> Option<u64> {
+  let start = line.find("index progress ")? + "index progress ".len();
+  line[start..].split('%').next()?.trim().parse().ok()
+}
+
 pub fn num_frames(source: &Path, vspipe_args_map: OwnedMap) -> anyhow::Result<usize> {
   // Create a new VSScript environment.
   let mut environment = Environment::new().unwrap();
@@ -363,3 +624,93 @@ pub fn pixel_format(source: &Path, vspipe_args_map: OwnedMap) -> anyhow::Result<
     Property::Constant(x) => Ok(x.name().to_string()),
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn bestsource_index_progress() {
+    assert_eq!(
+      index_progress("Information: VideoSource track #0 index progress 42%"),
+      Some(42)
+    );
+    assert_eq!(
+      index_progress("VideoSource track #0 index progress 100%"),
+      Some(100)
+    );
+    assert_eq!(index_progress("Script evaluation failed"), None);
+  }
+
+  #[test]
+  fn filters_in_script() {
+    let args = |vf: &str| vec!["-vf".to_owned(), vf.to_owned()];
+
+    assert_eq!(
+      filter_script(&[], (1920, 1080), Pixel::YUV420P, Pixel::YUV420P10LE).as_deref(),
+      Some("import vapoursynth as vs\nclip = core.resize.Bicubic(clip, format=vs.YUV420P10)\n")
+    );
+    assert_eq!(
+      filter_script(
+        &args("crop=1920:800,scale=-2:720"),
+        (1920, 1080),
+        Pixel::YUV420P,
+        Pixel::YUV420P
+      )
+      .as_deref(),
+      Some(
+        "import vapoursynth as vs\n\
+         clip = core.std.Crop(clip, left=0, right=0, top=140, bottom=140)\n\
+         clip = core.resize.Bicubic(clip, width=1728, height=720)\n\
+         clip = core.resize.Bicubic(clip, format=vs.YUV420P8)\n"
+      )
+    );
+    assert!(filter_script(&args("hflip"), (1920, 1080), Pixel::YUV420P, Pixel::YUV420P).is_some());
+
+    // filters and arguments that VapourSynth does not do go through ffmpeg
+    assert_eq!(
+      filter_script(&args("yadif"), (1920, 1080), Pixel::YUV420P, Pixel::YUV420P),
+      None
+    );
+    assert_eq!(
+      filter_script(
+        &["-r".to_owned(), "24".to_owned()],
+        (1920, 1080),
+        Pixel::YUV420P,
+        Pixel::YUV420P
+      ),
+      None
+    );
+    assert_eq!(
+      filter_script(&[], (1920, 1080), Pixel::YUV420P, Pixel::RGB24),
+      None
+    );
+
+    // crops by an odd number of pixels split chroma samples of 4:2:0 and 4:2:2
+    let odd_crop = args("crop=1920:1078:0:1");
+    assert_eq!(
+      filter_script(&odd_crop, (1920, 1080), Pixel::YUV420P, Pixel::YUV420P),
+      None
+    );
+    assert!(filter_script(&odd_crop, (1920, 1080), Pixel::YUV422P, Pixel::YUV420P).is_some());
+    assert!(filter_script(&odd_crop, (1920, 1080), Pixel::YUV444P, Pixel::YUV420P).is_some());
+    assert_eq!(
+      filter_script(
+        &args("crop=1918:1080:1:0"),
+        (1920, 1080),
+        Pixel::YUV422P,
+        Pixel::YUV420P
+      ),
+      None
+    );
+    assert_eq!(
+      filter_script(
+        &args("scale=1279:-1"),
+        (1920, 1080),
+        Pixel::YUV420P,
+        Pixel::YUV420P
+      ),
+      None
+    );
+  }
+}
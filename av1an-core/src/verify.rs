@@ -0,0 +1,240 @@
+//! Verification of the output once the chunks are concatenated.
+//!
+//! The output is decoded by ffmpeg, and its frame count and duration are
+//! compared with the frames of the encode, so that frames lost or timestamps
+//! broken by the concatenation are found while the chunks still exist. The
+//! decoded frames can also be compared with the frames of the chunks, which
+//! finds the chunk a problem starts at.
+
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::get_done;
+
+#[derive(
+  Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug, strum::EnumString, strum::IntoStaticStr,
+)]
+pub enum VerifyOutput {
+  /// Compares the frame count and duration of the output with the encode
+  #[strum(serialize = "frames")]
+  Frames,
+  /// Also compares the hashes of the frames of the output with the chunks
+  #[strum(serialize = "hashes")]
+  Hashes,
+}
+
+impl Display for VerifyOutput {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(<&'static str>::from(self))
+  }
+}
+
+/// Frames of a decoded video
+#[derive(Debug, Default, PartialEq)]
+struct Decoded {
+  /// MD5 hash of each frame, in order
+  hashes: Vec<String>,
+  /// Seconds from the start of the first frame to the end of the last one
+  duration: f64,
+}
+
+/// Decodes the first video stream of `path`, failing if the decoder reports
+/// errors
+fn decode(path: &Path) -> anyhow::Result<Decoded> {
+  let output = Command::new("ffmpeg")
+    .args([
+      "-nostdin",
+      "-hide_banner",
+      "-nostats",
+      "-loglevel",
+      "error",
+      "-i",
+    ])
+    .arg(path)
+    .args(["-map", "0:v:0", "-vsync", "0", "-f", "framemd5", "-"])
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .output()
+    .with_context(|| format!("Failed to run ffmpeg to decode {}", path.display()))?;
+
+  let errors = String::from_utf8_lossy(&output.stderr);
+  if !output.status.success() || !errors.trim().is_empty() {
+    bail!(
+      "ffmpeg failed to decode {}:\n{}",
+      path.display(),
+      errors.trim()
+    );
+  }
+
+  Ok(parse_framemd5(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parses the output of the framemd5 muxer of ffmpeg for one stream
+fn parse_framemd5(framemd5: &str) -> Decoded {
+  let mut timebase = 1.0;
+  let mut hashes = Vec::new();
+  let mut span: Option<(i64, i64)> = None;
+
+  for line in framemd5.lines() {
+    if let Some(tb) = line.strip_prefix("#tb 0:") {
+      if let Some((num, den)) = tb.trim().split_once('/') {
+        if let (Ok(num), Ok(den)) = (num.parse::<f64>(), den.parse::<f64>()) {
+          timebase = num / den;
+        }
+      }
+      continue;
+    }
+    if line.starts_with('#') {
+      continue;
+    }
+
+    // stream, dts, pts, duration, size, hash
+    let fields: Vec<_> = line.split(',').map(str::trim).collect();
+    let [_, _, pts, duration, _, hash] = fields[..] else {
+      continue;
+    };
+    let (Ok(pts), Ok(duration)) = (pts.parse::<i64>(), duration.parse::<i64>()) else {
+      continue;
+    };
+    hashes.push(hash.to_owned());
+    span = Some(span.map_or((pts, pts + duration), |(start, end)| {
+      (start.min(pts), end.max(pts + duration))
+    }));
+  }
+
+  Decoded {
+    hashes,
+    duration: span.map_or(0.0, |(start, end)| (end - start) as f64 * timebase),
+  }
+}
+
+/// Decodes the output, and fails if its frame count or duration differs from
+/// the `frames` of the encode at `frame_rate`.
+///
+/// With [`VerifyOutput::Hashes`],
+/// the frames are also compared with the frames of the `chunks`, which are the
+/// files that were concatenated, in order.
+pub fn verify(
+  output: &Path,
+  frames: usize,
+  frame_rate: f64,
+  mode: VerifyOutput,
+  chunks: &[PathBuf],
+) -> anyhow::Result<()> {
+  let decoded = decode(output)?;
+  let mut problems = compare(&decoded, frames, frame_rate);
+
+  if mode == VerifyOutput::Hashes {
+    let mut start = 0;
+    for chunk in chunks {
+      let name = chunk
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+      let chunk_frames = decode(chunk)?.hashes;
+
+      if let Some(encoded) = get_done().done.get(&name) {
+        if encoded.frames != chunk_frames.len() {
+          problems.push(format!(
+            "chunk {name} decodes to {} frames, but {} were encoded",
+            chunk_frames.len(),
+            encoded.frames
+          ));
+        }
+      }
+
+      let output_frames = decoded.hashes.get(start..).unwrap_or_default();
+      if let Some(frame) = chunk_frames
+        .iter()
+        .zip(output_frames)
+        .position(|(chunk_hash, output_hash)| chunk_hash != output_hash)
+        .or_else(|| (output_frames.len() < chunk_frames.len()).then_some(output_frames.len()))
+      {
+        problems.push(format!(
+          "frame {} of the output differs from frame {frame} of chunk {name}, the frames of the \
+           output are not compared further",
+          start + frame
+        ));
+        break;
+      }
+      start += chunk_frames.len();
+    }
+  }
+
+  if !problems.is_empty() {
+    bail!(
+      "The output {} failed verification:\n{}",
+      output.display(),
+      problems.join("\n")
+    );
+  }
+
+  info!(
+    "verified the output: {} frames, {:.3}s",
+    decoded.hashes.len(),
+    decoded.duration
+  );
+  Ok(())
+}
+
+/// Returns the differences between the decoded output and the frames of the
+/// encode
+fn compare(decoded: &Decoded, frames: usize, frame_rate: f64) -> Vec<String> {
+  let mut problems = Vec::new();
+  if decoded.hashes.len() != frames {
+    problems.push(format!(
+      "the output has {} frames, but {frames} were encoded",
+      decoded.hashes.len()
+    ));
+  }
+
+  // variable frame rate sources are only at `frame_rate` on average
+  let expected = frames as f64 / frame_rate;
+  if (decoded.duration - expected).abs() > (2.0 / frame_rate).max(expected * 0.01) {
+    problems.push(format!(
+      "the output lasts {:.3}s, but {frames} frames at {frame_rate:.3} fps last {expected:.3}s",
+      decoded.duration
+    ));
+  }
+  problems
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_and_compare_frames() {
+    let framemd5 = "#format: frame checksums
+#version: 2
+#hash: MD5
+#tb 0: 1/24
+#media_type 0: video
+#codec_id 0: rawvideo
+#dimensions 0: 640x360
+#sar 0: 1/1
+#stream#, dts,        pts, duration,     size, hash
+0,          0,          0,        1,   345600, 0f343b0931126a20f133d67c2b018a3b
+0,          1,          1,        1,   345600, 1f343b0931126a20f133d67c2b018a3b
+0,          2,          2,        1,   345600, 2f343b0931126a20f133d67c2b018a3b
+";
+    let decoded = parse_framemd5(framemd5);
+    assert_eq!(decoded.hashes.len(), 3);
+    assert_eq!(decoded.hashes[1], "1f343b0931126a20f133d67c2b018a3b");
+    assert!((decoded.duration - 0.125).abs() < 1e-9);
+
+    assert!(compare(&decoded, 3, 24.0).is_empty());
+    assert_eq!(compare(&decoded, 4, 24.0).len(), 1);
+
+    // a gap in the timestamps
+    let decoded = Decoded {
+      duration: 10.0,
+      ..decoded
+    };
+    assert_eq!(compare(&decoded, 3, 24.0).len(), 1);
+  }
+}
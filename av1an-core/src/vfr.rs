@@ -0,0 +1,174 @@
+//! Variable frame rate sources.
+//!
+//! The chunks are piped to the encoders as y4m, which has a constant frame
+//! rate, so the timestamps of a variable frame rate source would be lost and
+//! the video would drift from the audio. The timestamps of the source are read
+//! once and saved to the temporary folder, and split into a timestamp file
+//! (format v2 of mkvmerge) per chunk, which mkvmerge applies to the chunks when
+//! concatenating them.
+
+use std::fmt::Write;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use ffmpeg::format::input;
+use ffmpeg::media::Type as MediaType;
+
+use crate::create_dir;
+use crate::scenes::Scene;
+use crate::util::retry_io;
+
+/// Frame durations may differ by this many milliseconds due to the rounding
+/// of the timestamps to the time base, e.g. of Matroska
+const TOLERANCE_MS: f64 = 2.0;
+
+/// Returns the path of the timestamps of the whole source in the temporary
+/// folder, which only exists for variable frame rate sources
+pub fn timestamps_path(temp: &Path) -> PathBuf {
+  temp.join("timestamps.txt")
+}
+
+/// Reads the presentation timestamps of the video of the source, in
+/// milliseconds
+pub fn read_timestamps(source: &Path) -> anyhow::Result<Vec<f64>> {
+  let mut ictx = input(&source)?;
+  let stream = ictx
+    .streams()
+    .best(MediaType::Video)
+    .context("The source does not contain a video stream")?;
+  let index = stream.index();
+  let time_base = f64::from(stream.time_base()) * 1000.0;
+
+  let mut timestamps = Vec::new();
+  for (stream, packet) in ictx.packets().filter_map(Result::ok) {
+    if stream.index() == index {
+      let Some(pts) = packet.pts() else {
+        bail!("A frame of the source has no timestamp");
+      };
+      timestamps.push(pts as f64 * time_base);
+    }
+  }
+  // packets are in decoding order, which differs from presentation order with B-frames
+  timestamps.sort_unstable_by(f64::total_cmp);
+
+  Ok(timestamps)
+}
+
+/// Returns the duration of most frames
+fn typical_duration(timestamps: &[f64]) -> Option<f64> {
+  let mut durations: Vec<f64> = timestamps.windows(2).map(|w| w[1] - w[0]).collect();
+  durations.sort_unstable_by(f64::total_cmp);
+  durations.get(durations.len() / 2).copied()
+}
+
+/// Whether the frame durations differ by more than the rounding of the
+/// timestamps
+pub fn is_vfr(timestamps: &[f64]) -> bool {
+  let Some(typical) = typical_duration(timestamps) else {
+    return false;
+  };
+
+  timestamps
+    .windows(2)
+    .any(|w| (w[1] - w[0] - typical).abs() > TOLERANCE_MS)
+}
+
+/// Saves the timestamps of the whole source, with the end of the last frame
+/// as an additional timestamp
+pub fn save_timestamps(temp: &Path, timestamps: &[f64]) -> anyhow::Result<()> {
+  let mut contents = String::from("# timestamp format v2\n");
+  for timestamp in timestamps {
+    writeln!(contents, "{timestamp:.3}")?;
+  }
+  if let (Some(last), Some(duration)) = (timestamps.last(), typical_duration(timestamps)) {
+    writeln!(contents, "{:.3}", last + duration)?;
+  }
+
+  let path = timestamps_path(temp);
+  retry_io(|| fs::write(&path, &contents))
+    .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn load_timestamps(temp: &Path) -> anyhow::Result<Vec<f64>> {
+  let path = timestamps_path(temp);
+  let contents =
+    fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+
+  contents
+    .lines()
+    .filter(|line| !line.starts_with('#'))
+    .map(|line| {
+      line
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid timestamp {line:?} in {}", path.display()))
+    })
+    .collect()
+}
+
+/// Returns the timestamp file of the frames of a chunk, relative to its first
+/// frame, including the end of its last frame so that the next chunk starts
+/// at the right time
+fn chunk_timestamps(timestamps: &[f64], frames: Range<usize>) -> String {
+  let start = timestamps[frames.start];
+  let mut contents = String::from("# timestamp format v2\n");
+  for timestamp in &timestamps[frames.start..=frames.end] {
+    writeln!(contents, "{:.3}", timestamp - start).unwrap();
+  }
+
+  contents
+}
+
+/// Writes the timestamp file of every scene, named after the chunk, and
+/// returns the folder that contains them
+pub fn write_chunk_timestamps(temp: &Path, scenes: &[Scene]) -> anyhow::Result<PathBuf> {
+  let timestamps = load_timestamps(temp)?;
+  if scenes
+    .last()
+    .is_some_and(|scene| scene.end_frame >= timestamps.len())
+  {
+    bail!("The saved timestamps do not match the frames of the source");
+  }
+
+  let dir = temp.join("timestamps");
+  create_dir!(dir)?;
+  // chunks are created from the scenes in order, so the chunk index is the scene index
+  for (index, scene) in scenes.iter().enumerate() {
+    let path = dir.join(format!("{index:05}.txt"));
+    let contents = chunk_timestamps(&timestamps, scene.start_frame..scene.end_frame);
+    retry_io(|| fs::write(&path, &contents))
+      .with_context(|| format!("Failed to write {}", path.display()))?;
+  }
+
+  Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn vfr_timestamps() {
+    // 23.976 fps, rounded to milliseconds
+    let cfr: Vec<f64> = (0..100)
+      .map(|i| (f64::from(i) * 1001.0 / 24.0).round())
+      .collect();
+    assert!(!is_vfr(&cfr));
+
+    // 24 fps followed by 30 fps
+    let vfr: Vec<f64> = (0..50)
+      .map(|i| f64::from(i) * 1000.0 / 24.0)
+      .chain((1..50).map(|i| 50.0 * 1000.0 / 24.0 + f64::from(i) * 1000.0 / 30.0))
+      .collect();
+    assert!(is_vfr(&vfr));
+    assert!(!is_vfr(&[0.0]));
+
+    let timestamps = [0.0, 40.0, 80.0, 100.0, 120.0, 140.0];
+    assert_eq!(
+      chunk_timestamps(&timestamps, 2..4),
+      "# timestamp format v2\n0.000\n20.000\n40.000\n"
+    );
+  }
+}
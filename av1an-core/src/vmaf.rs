@@ -1,20 +1,25 @@
 use std::cmp::Ordering;
 use std::ffi::OsStr;
-use std::path::Path;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::str::FromStr;
 
 use anyhow::{anyhow, Context};
 use plotters::prelude::*;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 
 use crate::broker::EncoderCrash;
-use crate::util::printable_base10_digits;
-use crate::{ffmpeg, ref_smallvec, Input};
+use crate::util::{printable_base10_digits, retry_io};
+use crate::{cgroup, ffmpeg, ref_smallvec, Input, ProbingStatistic, ProbingStatisticName};
 
 #[derive(Deserialize, Debug)]
 struct VmafScore {
   vmaf: f64,
+  /// Motion feature of the VMAF models, named `motion2` by older libvmaf
+  #[serde(default, alias = "motion2")]
+  integer_motion2: Option<f64>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -27,7 +32,151 @@ struct VmafResult {
   frames: Vec<Metrics>,
 }
 
-pub fn plot_vmaf_score_file(scores_file: &Path, plot_path: &Path) -> anyhow::Result<()> {
+/// VMAF model used by libvmaf
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum VmafModel {
+  /// Model file
+  Path(PathBuf),
+  /// Model built into libvmaf, e.g. `vmaf_4k_v0.6.1`
+  Version(String),
+}
+
+impl VmafModel {
+  /// Returns the value of the `model` option of the libvmaf filter
+  fn filter_option(&self) -> String {
+    match self {
+      Self::Path(path) => format!("path={}", ffmpeg::escape_path_in_filter(path)),
+      Self::Version(version) => format!("version={version}"),
+    }
+  }
+}
+
+impl FromStr for VmafModel {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.strip_prefix("version=") {
+      Some("") => Err("missing built-in model name after \"version=\"".to_owned()),
+      Some(version) => Ok(Self::Version(version.to_owned())),
+      None => Ok(Self::Path(PathBuf::from(s))),
+    }
+  }
+}
+
+/// VMAF models chosen by the resolution VMAF is calculated at
+///
+/// Each model applies from a minimum height, compared against the height of a
+/// 16:9 frame of the same width for wider frames, so that cropped 4K content
+/// still uses the 4K model.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct VmafModelAuto {
+  /// `(min_height, model)`, sorted by descending height
+  buckets: Vec<(u32, VmafModel)>,
+}
+
+impl Default for VmafModelAuto {
+  fn default() -> Self {
+    Self {
+      buckets: vec![
+        (2160, VmafModel::Version("vmaf_4k_v0.6.1".to_owned())),
+        (0, VmafModel::Version("vmaf_v0.6.1".to_owned())),
+      ],
+    }
+  }
+}
+
+impl FromStr for VmafModelAuto {
+  type Err = String;
+
+  /// Parses buckets of the form `MIN_HEIGHT=MODEL`, separated by commas, where
+  /// `MODEL` is a path or `version=NAME` for a model built into libvmaf.
+  /// An empty string selects the default buckets.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if s.trim().is_empty() {
+      return Ok(Self::default());
+    }
+
+    let mut buckets = s
+      .split(',')
+      .map(|bucket| {
+        let (height, model) = bucket
+          .split_once('=')
+          .ok_or_else(|| format!("expected MIN_HEIGHT=MODEL, got {bucket:?}"))?;
+        let height = height
+          .trim()
+          .parse::<u32>()
+          .map_err(|_| format!("invalid height {height:?}"))?;
+        Ok((height, model.trim().parse()?))
+      })
+      .collect::<Result<Vec<_>, String>>()?;
+
+    buckets.sort_by_key(|&(height, _)| std::cmp::Reverse(height));
+    if buckets.last().map(|&(height, _)| height) != Some(0) {
+      return Err("a model for height 0 is required, to cover every resolution".to_owned());
+    }
+
+    Ok(Self { buckets })
+  }
+}
+
+impl VmafModelAuto {
+  /// Returns the model for a resolution of the form `WxH`. The model of the
+  /// lowest bucket is used if the resolution cannot be parsed.
+  pub fn select(&self, res: &str) -> &VmafModel {
+    let height = res
+      .split_once('x')
+      .and_then(|(w, h)| Some((w.parse::<u32>().ok()?, h.parse::<u32>().ok()?)))
+      .map_or(0, |(width, height)| height.max(width * 9 / 16));
+
+    self
+      .buckets
+      .iter()
+      .find(|&&(min_height, _)| height >= min_height)
+      .map_or(&self.buckets[self.buckets.len() - 1].1, |(_, model)| model)
+  }
+}
+
+/// Returns the model to calculate VMAF at the given resolution with, either
+/// the model passed with `--vmaf-path`, or the one chosen by `--vmaf-model-auto`.
+/// `None` uses the default model of libvmaf.
+///
+/// With `neg`, models built into libvmaf are replaced by their NEG (no
+/// enhancement gain) variant, which does not reward sharpening. Models given
+/// by path are used as is.
+pub fn select_model(
+  path: Option<&Path>,
+  auto: Option<&VmafModelAuto>,
+  neg: bool,
+  res: &str,
+) -> Option<VmafModel> {
+  let model = path
+    .map(|path| VmafModel::Path(path.to_path_buf()))
+    .or_else(|| auto.map(|auto| auto.select(res).clone()));
+
+  if !neg {
+    return model;
+  }
+  match model {
+    None => Some(VmafModel::Version("vmaf_v0.6.1neg".to_owned())),
+    Some(VmafModel::Version(version)) if !version.ends_with("neg") => {
+      Some(VmafModel::Version(format!("{version}neg")))
+    }
+    model => model,
+  }
+}
+
+/// Scene boundary (and quantizer chosen by target quality) annotated on the VMAF plot
+#[derive(Debug, Clone, Copy)]
+pub struct SceneAnnotation {
+  pub start_frame: usize,
+  pub cq: Option<u32>,
+}
+
+pub fn plot_vmaf_score_file(
+  scores_file: &Path,
+  plot_path: &Path,
+  scenes: &[SceneAnnotation],
+) -> anyhow::Result<()> {
   let scores = read_vmaf_file(scores_file).with_context(|| "Failed to parse VMAF file")?;
 
   let mut sorted_scores = scores.clone();
@@ -47,16 +196,51 @@ pub fn plot_vmaf_score_file(scores_file: &Path, plot_path: &Path) -> anyhow::Res
   let perc_50 = percentile_of_sorted(&sorted_scores, 0.50);
   let perc_75 = percentile_of_sorted(&sorted_scores, 0.75);
 
+  let max_cq = scenes.iter().filter_map(|scene| scene.cq).max();
+
   let mut chart = ChartBuilder::on(&root)
     .set_label_area_size(LabelAreaPosition::Bottom, (5).percent())
     .set_label_area_size(LabelAreaPosition::Left, (5).percent())
     .set_label_area_size(LabelAreaPosition::Right, (7).percent())
     .set_label_area_size(LabelAreaPosition::Top, (5).percent())
     .margin((1).percent())
-    .build_cartesian_2d(0_u32..length, perc_1.floor()..100.0)?;
+    .build_cartesian_2d(0_u32..length, perc_1.floor()..100.0)?
+    .set_secondary_coord(0_u32..length, 0_u32..max_cq.unwrap_or(1) + 1);
 
   chart.configure_mesh().draw()?;
 
+  // Scene boundaries
+  chart.draw_series(
+    scenes
+      .iter()
+      .filter(|scene| scene.start_frame != 0)
+      .map(|scene| {
+        let x = scene.start_frame as u32;
+        PathElement::new(vec![(x, perc_1.floor()), (x, 100.0)], BLACK.mix(0.15))
+      }),
+  )?;
+
+  // Quantizer chosen by target quality for each scene
+  if max_cq.is_some() {
+    chart.configure_secondary_axes().y_desc("CQ").draw()?;
+
+    let ends = scenes
+      .iter()
+      .skip(1)
+      .map(|scene| scene.start_frame as u32)
+      .chain(std::iter::once(length));
+    chart
+      .draw_secondary_series(LineSeries::new(
+        scenes.iter().zip(ends).flat_map(|(scene, end)| {
+          let cq = scene.cq.unwrap_or_default();
+          [(scene.start_frame as u32, cq), (end, cq)]
+        }),
+        MAGENTA,
+      ))?
+      .label("CQ")
+      .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], MAGENTA));
+  }
+
   // 1%
   chart
     .draw_series(LineSeries::new((0..=length).map(|x| (x, perc_1)), RED))?
@@ -114,25 +298,10 @@ pub fn validate_libvmaf() -> anyhow::Result<()> {
   Ok(())
 }
 
-pub fn plot(
-  encoded: &Path,
-  reference: &Input,
-  model: Option<impl AsRef<Path>>,
-  res: &str,
-  scaler: &str,
-  sample_rate: usize,
-  filter: Option<&str>,
-  threads: usize,
-) -> Result<(), Box<EncoderCrash>> {
-  let json_file = encoded.with_extension("json");
-  let plot_file = encoded.with_extension("svg");
-  let vspipe_args;
-
-  println!(":: VMAF Run");
-
-  let pipe_cmd: SmallVec<[&OsStr; 8]> = match reference {
-    Input::Video { ref path } => {
-      vspipe_args = vec![];
+/// Returns the command piping the reference as y4m, along with the vspipe arguments to pass to it
+pub fn reference_pipe_cmd(reference: &Input) -> (SmallVec<[&OsStr; 8]>, Vec<String>) {
+  match reference {
+    Input::Video { ref path } => (
       ref_smallvec!(
         OsStr,
         8,
@@ -146,16 +315,36 @@ pub fn plot(
           "yuv4mpegpipe",
           "-"
         ]
-      )
-    }
+      ),
+      vec![],
+    ),
     Input::VapourSynth {
       ref path,
-      vspipe_args: args,
-    } => {
-      vspipe_args = args.to_owned();
-      ref_smallvec!(OsStr, 8, ["vspipe", "-c", "y4m", path, "-"])
-    }
-  };
+      vspipe_args,
+    } => (
+      ref_smallvec!(OsStr, 8, ["vspipe", "-c", "y4m", path, "-"]),
+      vspipe_args.clone(),
+    ),
+  }
+}
+
+pub fn plot(
+  encoded: &Path,
+  reference: &Input,
+  scenes: &[SceneAnnotation],
+  model: Option<&VmafModel>,
+  res: &str,
+  scaler: &str,
+  sample_rate: usize,
+  filter: Option<&str>,
+  threads: usize,
+) -> Result<(), Box<EncoderCrash>> {
+  let json_file = encoded.with_extension("json");
+  let plot_file = encoded.with_extension("svg");
+
+  println!(":: VMAF Run");
+
+  let (pipe_cmd, vspipe_args) = reference_pipe_cmd(reference);
 
   run_vmaf(
     encoded,
@@ -168,9 +357,10 @@ pub fn plot(
     sample_rate,
     filter,
     threads,
+    &[],
   )?;
 
-  plot_vmaf_score_file(&json_file, &plot_file).unwrap();
+  plot_vmaf_score_file(&json_file, &plot_file, scenes).unwrap();
   Ok(())
 }
 
@@ -179,46 +369,62 @@ pub fn run_vmaf(
   reference_pipe_cmd: &[impl AsRef<OsStr>],
   vspipe_args: Vec<String>,
   stat_file: impl AsRef<Path>,
-  model: Option<impl AsRef<Path>>,
+  model: Option<&VmafModel>,
   res: &str,
   scaler: &str,
   sample_rate: usize,
   vmaf_filter: Option<&str>,
   threads: usize,
+  features: &[&str],
 ) -> Result<(), Box<EncoderCrash>> {
-  let mut filter = if sample_rate > 1 {
-    format!(
+  // the reference is filtered before its frames are sampled, as temporal
+  // filters such as denoisers need the frames around them
+  let mut filter = vmaf_filter.map_or_else(String::new, |vmaf_filter| format!("{vmaf_filter},"));
+
+  if sample_rate > 1 {
+    write!(
+      filter,
       "select=not(mod(n\\,{})),setpts={:.4}*PTS,",
       sample_rate,
       1.0 / sample_rate as f64,
     )
-  } else {
+    .unwrap();
+  }
+
+  // additional metrics computed by libvmaf alongside VMAF, e.g. psnr or float_ssim
+  let features = if features.is_empty() {
     String::new()
+  } else {
+    format!(
+      ":feature='{}'",
+      features
+        .iter()
+        .map(|feature| format!("name={feature}"))
+        .collect::<Vec<_>>()
+        .join("|")
+    )
   };
 
-  if let Some(vmaf_filter) = vmaf_filter {
-    filter.reserve(1 + vmaf_filter.len());
-    filter.push_str(vmaf_filter);
-    filter.push(',');
-  }
-
   let vmaf = if let Some(model) = model {
     format!(
-      "[distorted][ref]libvmaf=log_fmt='json':eof_action=endall:log_path={}:model='path={}':n_threads={}",
+      "[distorted][ref]libvmaf=log_fmt='json':eof_action=endall:log_path={}:model='{}':n_threads={}{}",
       ffmpeg::escape_path_in_filter(stat_file),
-      ffmpeg::escape_path_in_filter(&model),
-      threads
+      model.filter_option(),
+      threads,
+      features
     )
   } else {
     format!(
-      "[distorted][ref]libvmaf=log_fmt='json':eof_action=endall:log_path={}:n_threads={}",
+      "[distorted][ref]libvmaf=log_fmt='json':eof_action=endall:log_path={}:n_threads={}{}",
       ffmpeg::escape_path_in_filter(stat_file),
-      threads
+      threads,
+      features
     )
   };
 
   let mut source_pipe = if let [cmd, args @ ..] = reference_pipe_cmd {
     let mut source_pipe = Command::new(cmd);
+    cgroup::apply(&mut source_pipe);
     // Append vspipe python arguments to the environment if there are any
     for arg in vspipe_args {
       source_pipe.args(["-a", &arg]);
@@ -232,6 +438,7 @@ pub fn run_vmaf(
   };
 
   let mut cmd = Command::new("ffmpeg");
+  cgroup::apply(&mut cmd);
   cmd.args([
     "-loglevel",
     "error",
@@ -268,6 +475,7 @@ pub fn run_vmaf(
       ffmpeg_pipe_stderr: None,
       stderr: output.stderr.into(),
       stdout: String::new().into(),
+      pipeline: Vec::new(),
     }));
   }
 
@@ -275,7 +483,7 @@ pub fn run_vmaf(
 }
 
 pub fn read_vmaf_file(file: impl AsRef<Path>) -> Result<Vec<f64>, serde_json::Error> {
-  let json_str = std::fs::read_to_string(file).unwrap();
+  let json_str = retry_io(|| std::fs::read_to_string(&file)).map_err(serde_json::Error::io)?;
   let vmaf_results = serde_json::from_str::<VmafResult>(&json_str)?;
   let v = vmaf_results
     .frames
@@ -286,15 +494,214 @@ pub fn read_vmaf_file(file: impl AsRef<Path>) -> Result<Vec<f64>, serde_json::Er
   Ok(v)
 }
 
-/// Read a certain percentile VMAF score from the VMAF json file
+/// Weights of the frames of a probe in its score
+///
+/// Parsed from `skip=N,motion=W`, where either can be left out, or `none`.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct FrameWeighting {
+  /// Frames ignored after the keyframe each chunk starts with
+  pub skip: usize,
+  /// Extra weight of a frame per its motion relative to the mean motion of
+  /// the probe, so that high-motion frames weigh more
+  pub motion: f64,
+}
+
+impl FromStr for FrameWeighting {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut weighting = Self::default();
+    if s.trim() == "none" {
+      return Ok(weighting);
+    }
+
+    for option in s.split(',') {
+      let (key, value) = option
+        .split_once('=')
+        .ok_or_else(|| format!("expected skip=N or motion=W, got {option:?}"))?;
+      match key.trim() {
+        "skip" => {
+          weighting.skip = value
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid number of frames to skip: {value:?}"))?;
+        }
+        "motion" => {
+          weighting.motion = value
+            .trim()
+            .parse()
+            .ok()
+            .filter(|motion: &f64| *motion >= 0.0)
+            .ok_or_else(|| format!("invalid motion weight: {value:?}"))?;
+        }
+        key => return Err(format!("unknown frame weighting: {key:?}")),
+      }
+    }
+
+    Ok(weighting)
+  }
+}
+
+impl std::fmt::Display for FrameWeighting {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if self.is_none() {
+      f.write_str("none")
+    } else {
+      write!(f, "skip={},motion={}", self.skip, self.motion)
+    }
+  }
+}
+
+impl FrameWeighting {
+  /// Whether every frame weighs the same
+  pub fn is_none(&self) -> bool {
+    *self == Self::default()
+  }
+
+  /// Returns the weight of each frame of a probe with the given motion, whose
+  /// frames are every `probing_rate`-th frame of the chunk
+  fn weights(&self, motion: &[Option<f64>], probing_rate: usize) -> Vec<f64> {
+    let mut weights: Vec<f64> = (0..motion.len())
+      .map(|i| {
+        if i * probing_rate < self.skip {
+          0.0
+        } else {
+          1.0
+        }
+      })
+      .collect();
+    // a chunk shorter than the skipped frames is still scored by all of them
+    if weights.iter().all(|&weight| weight == 0.0) {
+      weights.fill(1.0);
+    }
+
+    let motion: Option<Vec<f64>> = motion.iter().copied().collect();
+    if let Some(motion) = motion.filter(|_| self.motion > 0.0) {
+      let mean = motion.iter().sum::<f64>() / motion.len() as f64;
+      if mean > 0.0 {
+        for (weight, motion) in weights.iter_mut().zip(motion) {
+          *weight *= self.motion.mul_add(motion / mean, 1.0);
+        }
+      }
+    }
+    weights
+  }
+}
+
+/// Reads the VMAF score of each frame from the VMAF json file, with its weight
+fn read_weighted_frames(
+  file: &Path,
+  weighting: FrameWeighting,
+  probing_rate: usize,
+) -> Result<Vec<(f64, f64)>, serde_json::Error> {
+  let json_str = retry_io(|| std::fs::read_to_string(file)).map_err(serde_json::Error::io)?;
+  let frames = serde_json::from_str::<VmafResult>(&json_str)?.frames;
+
+  let motion: Vec<_> = frames
+    .iter()
+    .map(|frame| frame.metrics.integer_motion2)
+    .collect();
+  if weighting.motion > 0.0 && motion.iter().any(Option::is_none) {
+    debug!(
+      "{} has no motion scores, frames are not weighted by motion",
+      file.display()
+    );
+  }
+  let weights = weighting.weights(&motion, probing_rate);
+
+  Ok(
+    frames
+      .into_iter()
+      .map(|frame| frame.metrics.vmaf)
+      .zip(weights)
+      .collect(),
+  )
+}
+
+/// Returns the score below which frames of `percentile` of the total weight
+/// fall
+fn weighted_percentile(mut frames: Vec<(f64, f64)>, percentile: f64) -> f64 {
+  assert!(!frames.is_empty());
+
+  frames.sort_unstable_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Less));
+  let total: f64 = frames.iter().map(|&(_, weight)| weight).sum();
+  let mut cumulative = 0.0;
+  for &(score, weight) in &frames {
+    cumulative += weight;
+    if weight > 0.0 && cumulative >= percentile * total {
+      return score;
+    }
+  }
+  frames[frames.len() - 1].0
+}
+
+/// Aggregates the scores of frames with their weights with `statistic`
+fn weighted_statistic(frames: Vec<(f64, f64)>, statistic: ProbingStatistic) -> f64 {
+  assert!(!frames.is_empty());
+  let total: f64 = frames.iter().map(|&(_, weight)| weight).sum();
+
+  match statistic.name {
+    ProbingStatisticName::Mean => {
+      frames
+        .iter()
+        .map(|&(score, weight)| score * weight)
+        .sum::<f64>()
+        / total
+    }
+    ProbingStatisticName::Median => weighted_percentile(frames, 0.5),
+    ProbingStatisticName::Harmonic => {
+      total
+        / frames
+          .iter()
+          .map(|&(score, weight)| weight / score)
+          .sum::<f64>()
+    }
+    ProbingStatisticName::Min => frames
+      .into_iter()
+      .filter(|&(_, weight)| weight > 0.0)
+      .fold(f64::MAX, |min, (score, _)| min.min(score)),
+    ProbingStatisticName::Percentile => {
+      weighted_percentile(frames, statistic.value.unwrap_or(1.0) / 100.0)
+    }
+  }
+}
+
+/// Aggregates the scores of the frames of a probe of another metric than VMAF
+/// with `statistic`, weighted by `weighting` without motion scores
+pub(crate) fn aggregate_scores(
+  scores: Vec<f64>,
+  statistic: ProbingStatistic,
+  weighting: FrameWeighting,
+  probing_rate: usize,
+) -> f64 {
+  let weights = weighting.weights(&vec![None; scores.len()], probing_rate);
+  weighted_statistic(scores.into_iter().zip(weights).collect(), statistic)
+}
+
+/// Read a certain percentile VMAF score from the VMAF json file, with the
+/// frames of the probe weighted by `weighting`
 ///
 /// Do not call this function more than once on the same json file,
 /// as this function is only more efficient for a single read.
 pub fn read_weighted_vmaf<P: AsRef<Path>>(
   file: P,
   percentile: f64,
+  weighting: FrameWeighting,
+  probing_rate: usize,
 ) -> Result<f64, serde_json::Error> {
-  fn inner(file: &Path, percentile: f64) -> Result<f64, serde_json::Error> {
+  fn inner(
+    file: &Path,
+    percentile: f64,
+    weighting: FrameWeighting,
+    probing_rate: usize,
+  ) -> Result<f64, serde_json::Error> {
+    if !weighting.is_none() {
+      return Ok(weighted_percentile(
+        read_weighted_frames(file, weighting, probing_rate)?,
+        percentile,
+      ));
+    }
+
     let mut scores = read_vmaf_file(file)?;
 
     assert!(!scores.is_empty());
@@ -309,7 +716,50 @@ pub fn read_weighted_vmaf<P: AsRef<Path>>(
     Ok(*kth_element)
   }
 
-  inner(file.as_ref(), percentile)
+  inner(file.as_ref(), percentile, weighting, probing_rate)
+}
+
+/// Reads the VMAF score of a target quality probe from the VMAF json file,
+/// aggregating the per-frame scores with the given statistic
+///
+/// The scores are weighted by `weighting`, for a probe of every
+/// `probing_rate`-th frame of the chunk.
+pub fn read_probe_vmaf<P: AsRef<Path>>(
+  file: P,
+  statistic: ProbingStatistic,
+  weighting: FrameWeighting,
+  probing_rate: usize,
+) -> Result<f64, serde_json::Error> {
+  if statistic.name == ProbingStatisticName::Percentile {
+    return read_weighted_vmaf(
+      file,
+      statistic.value.unwrap_or(1.0) / 100.0,
+      weighting,
+      probing_rate,
+    );
+  }
+  if !weighting.is_none() {
+    return Ok(weighted_statistic(
+      read_weighted_frames(file.as_ref(), weighting, probing_rate)?,
+      statistic,
+    ));
+  }
+
+  let mut scores = read_vmaf_file(file)?;
+  assert!(!scores.is_empty());
+  let len = scores.len() as f64;
+
+  Ok(match statistic.name {
+    ProbingStatisticName::Mean => scores.iter().sum::<f64>() / len,
+    ProbingStatisticName::Median => {
+      scores.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Less));
+      percentile_of_sorted(&scores, 0.5)
+    }
+    // a score of 0 makes the harmonic mean 0, as expected
+    ProbingStatisticName::Harmonic => len / scores.iter().map(|score| score.recip()).sum::<f64>(),
+    ProbingStatisticName::Min => scores.into_iter().fold(f64::MAX, f64::min),
+    ProbingStatisticName::Percentile => unreachable!(),
+  })
 }
 
 /// Calculates percentile from an array of sorted values
@@ -320,3 +770,98 @@ pub fn percentile_of_sorted(scores: &[f64], percentile: f64) -> f64 {
 
   scores[k]
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn vmaf_model_auto_selection() {
+    let auto = VmafModelAuto::default();
+    assert_eq!(
+      auto.select("3840x2160"),
+      &VmafModel::Version("vmaf_4k_v0.6.1".to_owned())
+    );
+    // cropped 4K is still matched as 4K
+    assert_eq!(
+      auto.select("3840x1600"),
+      &VmafModel::Version("vmaf_4k_v0.6.1".to_owned())
+    );
+    assert_eq!(
+      auto.select("1920x1080"),
+      &VmafModel::Version("vmaf_v0.6.1".to_owned())
+    );
+    assert_eq!(
+      auto.select("inputres"),
+      &VmafModel::Version("vmaf_v0.6.1".to_owned())
+    );
+
+    let auto: VmafModelAuto = "0=version=vmaf_v0.6.1,1440=/models/hd.json"
+      .parse()
+      .unwrap();
+    assert_eq!(
+      auto.select("2560x1440"),
+      &VmafModel::Path(PathBuf::from("/models/hd.json"))
+    );
+    assert_eq!(
+      auto.select("1280x720"),
+      &VmafModel::Version("vmaf_v0.6.1".to_owned())
+    );
+
+    assert!("1080=/models/hd.json".parse::<VmafModelAuto>().is_err());
+    assert!("hd=/models/hd.json".parse::<VmafModelAuto>().is_err());
+
+    // NEG variants replace the built-in models only
+    assert_eq!(
+      select_model(None, None, true, "1920x1080"),
+      Some(VmafModel::Version("vmaf_v0.6.1neg".to_owned()))
+    );
+    assert_eq!(
+      select_model(None, Some(&VmafModelAuto::default()), true, "3840x2160"),
+      Some(VmafModel::Version("vmaf_4k_v0.6.1neg".to_owned()))
+    );
+    assert_eq!(
+      select_model(None, Some(&auto), true, "2560x1440"),
+      Some(VmafModel::Path(PathBuf::from("/models/hd.json")))
+    );
+    assert_eq!(select_model(None, None, false, "1920x1080"), None);
+  }
+
+  #[test]
+  fn frame_weighting() {
+    let weighting: FrameWeighting = "skip=4,motion=1".parse().unwrap();
+    assert_eq!(
+      weighting,
+      FrameWeighting {
+        skip: 4,
+        motion: 1.0
+      }
+    );
+    assert!("none".parse::<FrameWeighting>().unwrap().is_none());
+    assert!("skip=-1".parse::<FrameWeighting>().is_err());
+    assert!("motion".parse::<FrameWeighting>().is_err());
+
+    // with a probing rate of 2, the first 2 probe frames are the first 4 frames of the chunk
+    let motion = [Some(1.0), Some(1.0), Some(1.0), Some(3.0)];
+    assert_eq!(
+      weighting.weights(&motion, 2),
+      [0.0, 0.0, 1.0 + 1.0 / 1.5, 1.0 + 3.0 / 1.5]
+    );
+    // without motion scores, only the skipped frames change
+    assert_eq!(weighting.weights(&[None, None, None], 1), [1.0; 3]);
+    assert_eq!(
+      weighting.weights(&[None; 6], 1),
+      [0.0, 0.0, 0.0, 0.0, 1.0, 1.0]
+    );
+
+    let frames = vec![(90.0, 0.0), (80.0, 1.0), (95.0, 3.0)];
+    let check = |statistic: &str, expected: f64| {
+      let score = weighted_statistic(frames.clone(), statistic.parse().unwrap());
+      assert!((score - expected).abs() < 1e-9, "{statistic}: {score}");
+    };
+    check("min", 80.0);
+    check("median", 95.0);
+    check("mean", 91.25);
+    check("percentile=25", 80.0);
+  }
+}
@@ -0,0 +1,201 @@
+//! Counting of the frames of a y4m stream, as it is piped into the encoder.
+//!
+//! The progress of a chunk is otherwise only known by parsing the stderr of
+//! the encoder, which breaks whenever a new version of an encoder changes its
+//! output. The frames piped into the encoder are counted from the headers of
+//! the stream instead, which only depend on the y4m format. The frames are
+//! counted as soon as the encoder has read them, so the count is ahead of the
+//! encoded frames by the lookahead of the encoder.
+
+/// Longest header that is parsed, as the stream is not y4m past that
+const MAX_HEADER_LEN: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+  /// In the header of the stream
+  StreamHeader,
+  /// In the header of a frame
+  FrameHeader,
+  /// In the data of a frame, with the number of bytes left
+  FrameData(usize),
+  /// The stream could not be parsed, and is no longer counted
+  Unknown,
+}
+
+/// Counts the frames of a y4m stream, from the data fed in order
+#[derive(Debug, Clone)]
+pub struct FrameCounter {
+  state: State,
+  header: Vec<u8>,
+  frame_size: usize,
+  frames: u64,
+}
+
+impl Default for FrameCounter {
+  fn default() -> Self {
+    Self {
+      state: State::StreamHeader,
+      header: Vec::new(),
+      frame_size: 0,
+      frames: 0,
+    }
+  }
+}
+
+impl FrameCounter {
+  /// Returns the number of complete frames fed so far
+  pub const fn frames(&self) -> u64 {
+    self.frames
+  }
+
+  /// Feeds the next bytes of the stream, and returns the number of complete
+  /// frames fed so far
+  pub fn feed(&mut self, mut data: &[u8]) -> u64 {
+    while !data.is_empty() {
+      match self.state {
+        State::StreamHeader | State::FrameHeader => {
+          let Some(end) = data.iter().position(|&b| b == b'\n') else {
+            self.header.extend_from_slice(data);
+            if self.header.len() > MAX_HEADER_LEN {
+              self.give_up();
+            }
+            break;
+          };
+          self.header.extend_from_slice(&data[..end]);
+          data = &data[end + 1..];
+          self.end_header();
+        }
+        State::FrameData(left) => {
+          let read = left.min(data.len());
+          data = &data[read..];
+          if read == left {
+            self.frames += 1;
+            self.state = State::FrameHeader;
+          } else {
+            self.state = State::FrameData(left - read);
+          }
+        }
+        State::Unknown => break,
+      }
+    }
+
+    self.frames
+  }
+
+  fn end_header(&mut self) {
+    let header = std::mem::take(&mut self.header);
+    let header = String::from_utf8_lossy(&header);
+    self.state = match self.state {
+      State::StreamHeader => match frame_size(&header) {
+        Some(size) => {
+          self.frame_size = size;
+          State::FrameHeader
+        }
+        None => State::Unknown,
+      },
+      _ if header.starts_with("FRAME") => State::FrameData(self.frame_size),
+      _ => State::Unknown,
+    };
+    if self.state == State::Unknown {
+      self.give_up();
+    }
+  }
+
+  fn give_up(&mut self) {
+    debug!("the y4m stream could not be parsed, its frames are no longer counted");
+    self.state = State::Unknown;
+    self.header = Vec::new();
+  }
+}
+
+/// Returns the size in bytes of the frames of a stream with this header
+fn frame_size(header: &str) -> Option<usize> {
+  let mut params = header.split(' ');
+  if params.next()? != "YUV4MPEG2" {
+    return None;
+  }
+
+  let (mut width, mut height, mut colorspace) = (None, None, "420");
+  for param in params {
+    if let Some(w) = param.strip_prefix('W') {
+      width = w.parse::<usize>().ok();
+    } else if let Some(h) = param.strip_prefix('H') {
+      height = h.parse::<usize>().ok();
+    } else if let Some(c) = param.strip_prefix('C') {
+      colorspace = c;
+    }
+  }
+  let (width, height) = (width?, height?);
+
+  let (planes, depth) = if let Some(depth) = colorspace.strip_prefix("mono") {
+    (width * height, depth)
+  } else if let Some(rest) = colorspace.strip_prefix("444alpha") {
+    (4 * width * height, rest)
+  } else {
+    let (chroma_width, chroma_height, rest) = if let Some(rest) = colorspace.strip_prefix("420") {
+      (width.div_ceil(2), height.div_ceil(2), rest)
+    } else if let Some(rest) = colorspace.strip_prefix("422") {
+      (width.div_ceil(2), height, rest)
+    } else if let Some(rest) = colorspace.strip_prefix("444") {
+      (width, height, rest)
+    } else if let Some(rest) = colorspace.strip_prefix("411") {
+      (width.div_ceil(4), height, rest)
+    } else {
+      return None;
+    };
+    (width * height + 2 * chroma_width * chroma_height, rest)
+  };
+
+  // the sampling is followed by the bit depth (p10, or 16 for mono), or by the
+  // siting of the chroma at 8 bits (420jpeg, 420paldv)
+  let depth = depth.trim_start_matches('p').parse::<usize>().unwrap_or(8);
+
+  Some(planes * if depth > 8 { 2 } else { 1 })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn counts_y4m_frames() {
+    let header = b"YUV4MPEG2 W4 H2 F24:1 Ip A1:1 C420p10 XYSCSS=420P10\n";
+    // 4x2 luma and two 2x1 chroma planes at 2 bytes per sample
+    let frame = [b"FRAME\n".as_slice(), &[b'F'; 24]].concat();
+
+    let mut stream = header.to_vec();
+    for _ in 0..3 {
+      stream.extend_from_slice(&frame);
+    }
+
+    // the count does not depend on how the stream is split
+    for split in [1, 5, 7, 64, stream.len()] {
+      let mut counter = FrameCounter::default();
+      let counts: Vec<u64> = stream
+        .chunks(split)
+        .map(|data| counter.feed(data))
+        .collect();
+      assert_eq!(counter.frames(), 3, "split {split}");
+      assert!(counts.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    // a partial frame is not counted
+    let mut counter = FrameCounter::default();
+    assert_eq!(counter.feed(&stream[..stream.len() - 1]), 2);
+
+    assert_eq!(frame_size("YUV4MPEG2 W1920 H1080 F24:1"), Some(3_110_400));
+    assert_eq!(frame_size("YUV4MPEG2 W1920 H1080 C444"), Some(6_220_800));
+    assert_eq!(frame_size("YUV4MPEG2 W1920 H1080 Cmono"), Some(2_073_600));
+    assert_eq!(frame_size("YUV4MPEG2 W1920 H1080 Cmono16"), Some(4_147_200));
+    assert_eq!(
+      frame_size("YUV4MPEG2 W1920 H1080 C420jpeg"),
+      Some(3_110_400)
+    );
+    assert_eq!(frame_size("YUV4MPEG2 W5 H3 C420"), Some(15 + 2 * 3 * 2));
+    assert_eq!(frame_size("RIFF W1920 H1080"), None);
+
+    // a stream that is not y4m is no longer counted
+    let mut counter = FrameCounter::default();
+    assert_eq!(counter.feed(b"not y4m\nFRAME\n"), 0);
+  }
+}
@@ -0,0 +1,67 @@
+//! `av1an concat`: concatenates the chunks of an encode again.
+//!
+//! Before concatenating, an encode saves how its chunks are concatenated in
+//! `concat.json` of the temporary folder. If the concatenation fails, e.g.
+//! because mkvmerge is missing, the temporary folder is kept, and its chunks
+//! can be concatenated again without encoding them, into another output or
+//! with another method if needed.
+
+use std::path::PathBuf;
+
+use anyhow::{bail, ensure};
+use av1an_core::concat::{ConcatMethod, ConcatPlan};
+
+/// Concatenate the encoded chunks of the temporary folder of an encode again
+#[derive(clap::Args, Debug)]
+pub struct ConcatArgs {
+  /// Temporary folder of the encode
+  #[clap(long)]
+  temp: PathBuf,
+
+  /// Output file, by default the output of the encode
+  #[clap(short, long)]
+  output: Option<PathBuf>,
+
+  /// Concatenation method, by default the method of the encode
+  #[clap(short, long)]
+  concat: Option<ConcatMethod>,
+}
+
+pub fn run(args: ConcatArgs) -> anyhow::Result<()> {
+  if !ConcatPlan::path(&args.temp).exists() {
+    bail!(
+      "{} does not contain the concatenation of an encode, which is only saved once all chunks \
+       are encoded. Use --resume to finish the encode first",
+      args.temp.display()
+    );
+  }
+  let mut plan = ConcatPlan::load(&args.temp)?;
+  if let Some(output) = args.output {
+    plan.output = output;
+  }
+  if let Some(method) = args.concat {
+    plan.method = method;
+  }
+
+  match plan.method {
+    ConcatMethod::Ivf => ensure!(
+      plan.extension == "ivf",
+      "the chunks are .{} files, which cannot be concatenated with --concat ivf",
+      plan.extension
+    ),
+    ConcatMethod::MKVMerge => ensure!(
+      which::which("mkvmerge").is_ok(),
+      "mkvmerge not found, but `--concat mkvmerge` was specified. Is it installed in system path?"
+    ),
+    ConcatMethod::FFmpeg => {}
+  }
+
+  plan.run(&args.temp)?;
+  println!(
+    "concatenated the chunks into {}, the temporary folder {} can be removed",
+    plan.output.display(),
+    args.temp.display()
+  );
+
+  Ok(())
+}
@@ -0,0 +1,320 @@
+//! Defaults for the options of the command line from configuration files.
+//!
+//! Options are read from `av1an.toml` in the configuration folder of the user
+//! (e.g. `~/.config/av1an/av1an.toml` on Linux), then from `av1an.toml` in the
+//! current directory, each file overriding the previous one. The keys are the
+//! long names of the options, and the `[preset.<name>]` tables hold presets,
+//! selected with `--preset <name>`, which override the other options of both
+//! files. Options given on the command line override all of them, as the
+//! options of the files are only the defaults of the command line, so that
+//! they never conflict with the options given on it.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Command, CommandFactory, FromArgMatches};
+
+use crate::subcommand::{Cli, Subcommand};
+
+const FILE_NAME: &str = "av1an.toml";
+
+/// Value of an option from a configuration file
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigOption {
+  pub value: toml::Value,
+  /// File the value was read from, and the preset it is part of
+  pub source: String,
+}
+
+/// Returns the configuration files that exist, from the lowest to the highest
+/// priority
+fn config_files() -> Vec<PathBuf> {
+  dirs::config_dir()
+    .map(|dir| dir.join("av1an").join(FILE_NAME))
+    .into_iter()
+    .chain(std::iter::once(PathBuf::from(FILE_NAME)))
+    .filter(|path| path.is_file())
+    .collect()
+}
+
+fn load(path: &Path) -> anyhow::Result<toml::Table> {
+  let contents =
+    fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+  contents
+    .parse()
+    .with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+/// Merges the options of the configuration files, and of the preset if any
+pub fn merge(
+  files: &[(String, toml::Table)],
+  preset: Option<&str>,
+) -> anyhow::Result<BTreeMap<String, ConfigOption>> {
+  let mut options = BTreeMap::new();
+  for (source, table) in files {
+    for (name, value) in table.iter().filter(|(name, _)| *name != "preset") {
+      options.insert(
+        name.clone(),
+        ConfigOption {
+          value: value.clone(),
+          source: source.clone(),
+        },
+      );
+    }
+  }
+
+  let Some(preset) = preset else {
+    return Ok(options);
+  };
+  let mut found = false;
+  for (source, table) in files {
+    let Some(values) = table.get("preset").and_then(|presets| presets.get(preset)) else {
+      continue;
+    };
+    let Some(values) = values.as_table() else {
+      bail!("Preset {preset:?} in {source} is not a table");
+    };
+    found = true;
+    for (name, value) in values {
+      options.insert(
+        name.clone(),
+        ConfigOption {
+          value: value.clone(),
+          source: format!("{source} (preset {preset})"),
+        },
+      );
+    }
+  }
+  if !found {
+    bail!(
+      "Preset {preset:?} is not defined in any configuration file ({})",
+      if files.is_empty() {
+        "no av1an.toml was found".to_owned()
+      } else {
+        files
+          .iter()
+          .map(|(source, _)| source.as_str())
+          .collect::<Vec<_>>()
+          .join(", ")
+      }
+    );
+  }
+
+  Ok(options)
+}
+
+/// Returns the values of the options from the configuration files by the id
+/// of their argument, except for the options given on the command line
+pub fn config_defaults(
+  command: &Command,
+  matches: &ArgMatches,
+  options: &BTreeMap<String, ConfigOption>,
+) -> anyhow::Result<Vec<(String, Vec<String>)>> {
+  let mut defaults = Vec::new();
+  for (name, option) in options {
+    let Some(arg) = command
+      .get_arguments()
+      .find(|arg| arg.get_long() == Some(name.as_str()))
+    else {
+      bail!("Unknown option {name:?} in {}", option.source);
+    };
+    if name == "preset" {
+      bail!(
+        "--preset cannot be set in {}, presets are selected on the command line",
+        option.source
+      );
+    }
+    if on_command_line(command, matches, name) {
+      continue;
+    }
+
+    let values = match &option.value {
+      toml::Value::Array(values) => values.clone(),
+      value => vec![value.clone()],
+    };
+    let values = values
+      .into_iter()
+      .map(|value| match value {
+        toml::Value::String(value) => Ok(value),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        _ => bail!(
+          "Option {name:?} in {} must be a string, number, boolean or array of these",
+          option.source
+        ),
+      })
+      .collect::<anyhow::Result<_>>()?;
+    defaults.push((arg.get_id().to_string(), values));
+  }
+
+  Ok(defaults)
+}
+
+/// Sets the values of the configuration files as the defaults of their
+/// options, which unlike arguments never conflict with the options given on
+/// the command line
+fn with_defaults(command: Command, defaults: &[(String, Vec<String>)]) -> Command {
+  defaults.iter().fold(command, |command, (id, values)| {
+    command.mut_arg(id, |arg| arg.default_values(values))
+  })
+}
+
+/// Returns whether the option with this long name is given on the command
+/// line
+fn on_command_line(command: &Command, matches: &ArgMatches, name: &str) -> bool {
+  command
+    .get_arguments()
+    .find(|arg| arg.get_long() == Some(name))
+    .is_some_and(|arg| {
+      matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+    })
+}
+
+/// Describes the options of the configuration files, and which of them are
+/// overridden by the command line
+fn describe(
+  command: &Command,
+  matches: &ArgMatches,
+  options: &BTreeMap<String, ConfigOption>,
+) -> Vec<String> {
+  options
+    .iter()
+    .map(|(name, option)| {
+      format!(
+        "--{name} = {} ({}{})",
+        option.value,
+        option.source,
+        if on_command_line(command, matches, name) {
+          ", overridden by the command line"
+        } else {
+          ""
+        }
+      )
+    })
+    .collect()
+}
+
+/// Parses the command line, with the options of the configuration files as
+/// defaults of an encode. Also returns the description of these options, to
+/// be logged once logging is set up.
+pub fn parse(args: Vec<OsString>) -> anyhow::Result<(Cli, Vec<String>)> {
+  let cli_command = Cli::command();
+  let cli_matches = cli_command.clone().get_matches_from(&args);
+  // the options of an encode follow its subcommand, if any
+  let (command, matches, subcommand) = match cli_matches.subcommand() {
+    None => (&cli_command, &cli_matches, None),
+    Some((name, matches)) if Subcommand::ENCODES.contains(&name) => (
+      cli_command.find_subcommand(name).unwrap(),
+      matches,
+      Some(name),
+    ),
+    Some(_) => return Ok((Cli::from_arg_matches(&cli_matches)?, Vec::new())),
+  };
+
+  let mut files = Vec::new();
+  for path in config_files() {
+    let table = load(&path)?;
+    files.push((path.display().to_string(), table));
+  }
+  let options = merge(
+    &files,
+    matches.get_one::<String>("preset").map(String::as_str),
+  )?;
+  let description = describe(command, matches, &options);
+
+  if matches.get_flag("print_config") {
+    if description.is_empty() {
+      println!("no options are set by configuration files");
+    }
+    for line in description {
+      println!("{line}");
+    }
+    std::process::exit(0);
+  }
+
+  let defaults = config_defaults(command, matches, &options)?;
+  if defaults.is_empty() {
+    return Ok((Cli::from_arg_matches(&cli_matches)?, description));
+  }
+  let cli_command = match subcommand {
+    Some(name) => cli_command.mut_subcommand(name, |command| with_defaults(command, &defaults)),
+    None => with_defaults(cli_command, &defaults),
+  };
+  let cli_matches = cli_command.get_matches_from(args);
+  Ok((Cli::from_arg_matches(&cli_matches)?, description))
+}
+
+#[cfg(test)]
+mod tests {
+  use clap::{Arg, ArgAction};
+
+  use super::*;
+
+  #[test]
+  fn layered_config() {
+    let user: toml::Table = r#"
+      encoder = "aom"
+      workers = 4
+
+      [preset.fast]
+      video-params = "--cpu-used=6"
+    "#
+    .parse()
+    .unwrap();
+    let project: toml::Table = r#"
+      workers = 2
+      keep = true
+
+      [preset.fast]
+      workers = 8
+    "#
+    .parse()
+    .unwrap();
+    let files = [("user".to_owned(), user), ("project".to_owned(), project)];
+
+    let options = merge(&files, Some("fast")).unwrap();
+    assert_eq!(options["workers"].value, toml::Value::Integer(8));
+    assert_eq!(options["workers"].source, "project (preset fast)");
+    assert_eq!(options["encoder"].source, "user");
+    assert!(merge(&files, Some("slow")).is_err());
+
+    // a subset of the options, as the version of the full command line probes VapourSynth
+    let command = Command::new("av1an").args([
+      Arg::new("encoder").long("encoder"),
+      Arg::new("workers").long("workers"),
+      Arg::new("keep").long("keep").action(ArgAction::SetTrue),
+      Arg::new("video_params")
+        .long("video-params")
+        .allow_hyphen_values(true),
+      Arg::new("passes").long("passes").conflicts_with("keep"),
+    ]);
+    let args = ["av1an", "--workers", "3", "--passes", "1"];
+    let matches = command.clone().try_get_matches_from(args).unwrap();
+    let defaults = config_defaults(&command, &matches, &options).unwrap();
+    assert_eq!(
+      defaults,
+      [
+        ("encoder".to_owned(), vec!["aom".to_owned()]),
+        ("keep".to_owned(), vec!["true".to_owned()]),
+        ("video_params".to_owned(), vec!["--cpu-used=6".to_owned()]),
+      ]
+    );
+
+    // the options of the files do not conflict with those of the command line
+    let matches = with_defaults(command, &defaults)
+      .try_get_matches_from(args)
+      .unwrap();
+    assert_eq!(matches.get_one::<String>("workers").unwrap(), "3");
+    assert_eq!(matches.get_one::<String>("encoder").unwrap(), "aom");
+    assert!(matches.get_flag("keep"));
+    assert_eq!(
+      matches.get_one::<String>("video_params").unwrap(),
+      "--cpu-used=6"
+    );
+  }
+}
@@ -0,0 +1,352 @@
+//! `av1an doctor`: checks the dependencies of av1an.
+//!
+//! Every external program and VapourSynth plugin that av1an can use is looked
+//! for, with its version when it can be found, and a hint to install it on
+//! this platform when it is missing. Only ffmpeg and one of the encoders are
+//! required, the other dependencies are only needed by some options.
+
+use std::collections::HashSet;
+use std::process::Command;
+
+use anyhow::bail;
+use av1an_core::encoder::Encoder;
+use av1an_core::encoder_profile::EncoderProfile;
+use av1an_core::{vapoursynth, vmaf};
+
+const ENCODERS: [Encoder; 6] = [
+  Encoder::aom,
+  Encoder::rav1e,
+  Encoder::vpx,
+  Encoder::svt_av1,
+  Encoder::x264,
+  Encoder::x265,
+];
+
+/// Models built into libvmaf that are used by default
+const VMAF_MODELS: [&str; 2] = ["vmaf_v0.6.1", "vmaf_4k_v0.6.1"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Requirement {
+  Required,
+  /// Only needed by some options, described by the text
+  Optional(&'static str),
+}
+
+#[derive(Debug)]
+struct Check {
+  name: String,
+  requirement: Requirement,
+  /// Version or details if found, `None` if missing
+  found: Option<String>,
+  hint: &'static str,
+}
+
+/// Returns the hint to install a dependency on this platform
+const fn install_hint(
+  windows: &'static str,
+  macos: &'static str,
+  linux: &'static str,
+) -> &'static str {
+  if cfg!(windows) {
+    windows
+  } else if cfg!(target_os = "macos") {
+    macos
+  } else {
+    linux
+  }
+}
+
+/// Runs a program and returns its output (stdout followed by stderr), or
+/// `None` if it could not be run
+fn output(program: &str, args: &[&str]) -> Option<String> {
+  let output = Command::new(program).args(args).output().ok()?;
+  let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+  text.push_str(&String::from_utf8_lossy(&output.stderr));
+  Some(text)
+}
+
+/// Returns the version from the output of `--version`, i.e. the first word
+/// after "version", or the first line if there is no such word
+fn parse_version(text: &str) -> String {
+  let line = text.lines().next().unwrap_or_default().trim();
+  line
+    .split_whitespace()
+    .skip_while(|word| !word.eq_ignore_ascii_case("version"))
+    .nth(1)
+    .unwrap_or(line)
+    .to_owned()
+}
+
+fn program(name: &str, args: &[&str], requirement: Requirement, hint: &'static str) -> Check {
+  Check {
+    name: name.to_owned(),
+    requirement,
+    found: output(name, args).map(|text| parse_version(&text)),
+    hint,
+  }
+}
+
+fn encoder(encoder: Encoder) -> Check {
+  let profile = EncoderProfile::get(encoder);
+  let found = output(encoder.bin(), &[encoder.help_command()[1]]).map(|_| {
+    profile.version.map_or_else(
+      || "unknown version".to_owned(),
+      |version| format!("{version}{}", if profile.psy { " (psy)" } else { "" }),
+    )
+  });
+  Check {
+    name: encoder.bin().to_owned(),
+    requirement: Requirement::Optional("--encoder"),
+    found,
+    hint: match encoder {
+      Encoder::aom => install_hint(
+        "download a build of aomenc and add it to the PATH",
+        "brew install aom",
+        "install aom (aomenc) with your package manager",
+      ),
+      Encoder::rav1e => install_hint(
+        "download rav1e from https://github.com/xiph/rav1e/releases",
+        "brew install rav1e",
+        "install rav1e with your package manager, or cargo install rav1e",
+      ),
+      Encoder::vpx => install_hint(
+        "download vpxenc from https://github.com/webmproject/libvpx",
+        "brew install libvpx",
+        "install libvpx (vpxenc) with your package manager",
+      ),
+      Encoder::svt_av1 => install_hint(
+        "download SvtAv1EncApp from https://gitlab.com/AOMediaCodec/SVT-AV1/-/releases",
+        "brew install svt-av1",
+        "install svt-av1 (SvtAv1EncApp) with your package manager",
+      ),
+      Encoder::x264 => install_hint(
+        "download x264 from https://artifacts.videolan.org/x264/",
+        "brew install x264",
+        "install x264 with your package manager",
+      ),
+      Encoder::x265 => install_hint(
+        "download a build of x265 and add it to the PATH",
+        "brew install x265",
+        "install x265 with your package manager",
+      ),
+    },
+  }
+}
+
+/// Checks for a plugin among the `plugins` found, which are `None` if the
+/// VapourSynth core could not be created
+fn plugin(
+  name: &str,
+  plugins: Option<&HashSet<String>>,
+  id: &str,
+  requirement: Requirement,
+  hint: &'static str,
+) -> Check {
+  Check {
+    name: name.to_owned(),
+    requirement,
+    found: plugins
+      .filter(|plugins| plugins.contains(id))
+      .map(|_| "installed".to_owned()),
+    hint,
+  }
+}
+
+/// Checks that libvmaf has a model built in, by computing VMAF of a few frames
+/// of a test source
+fn vmaf_model(model: &str) -> Check {
+  let filter = format!("[0:v][1:v]libvmaf=model=version={model}");
+  let found = Command::new("ffmpeg")
+    .args(["-hide_banner", "-loglevel", "error", "-f", "lavfi"])
+    .args(["-i", "testsrc=size=64x64:duration=0.1", "-f", "lavfi"])
+    .args(["-i", "testsrc=size=64x64:duration=0.1", "-lavfi", &filter])
+    .args(["-f", "null", "-"])
+    .output()
+    .ok()
+    .filter(|output| output.status.success())
+    .map(|_| "built into libvmaf".to_owned());
+  Check {
+    name: format!("VMAF model {model}"),
+    requirement: Requirement::Optional("--vmaf, --target-quality"),
+    found,
+    hint:
+      "use a build of ffmpeg with libvmaf and its built-in models, or pass a model with --vmaf-path",
+  }
+}
+
+fn checks() -> Vec<Check> {
+  let mut checks = vec![program(
+    "ffmpeg",
+    &["-version"],
+    Requirement::Required,
+    install_hint(
+      "download ffmpeg from https://www.gyan.dev/ffmpeg/builds/ and add it to the PATH",
+      "brew install ffmpeg",
+      "install ffmpeg with your package manager",
+    ),
+  )];
+  checks.push(Check {
+    name: "libvmaf".to_owned(),
+    requirement: Requirement::Optional("--vmaf, --target-quality"),
+    found: vmaf::validate_libvmaf()
+      .is_ok()
+      .then(|| "ffmpeg is built with libvmaf".to_owned()),
+    hint: "use a build of ffmpeg configured with --enable-libvmaf",
+  });
+  if checks[1].found.is_some() {
+    checks.extend(VMAF_MODELS.iter().map(|model| vmaf_model(model)));
+  }
+
+  checks.extend(ENCODERS.iter().map(|&e| encoder(e)));
+
+  checks.push(program(
+    "vspipe",
+    &["--version"],
+    Requirement::Optional("VapourSynth inputs and chunk methods"),
+    install_hint(
+      "install VapourSynth from https://github.com/vapoursynth/vapoursynth/releases",
+      "brew install vapoursynth",
+      "install vapoursynth with your package manager",
+    ),
+  ));
+  // the plugins are queried directly, as the core fails to load when e.g. the
+  // Python module of VapourSynth is missing
+  let plugins = vapoursynth::plugins();
+  checks.push(Check {
+    name: "VapourSynth core".to_owned(),
+    requirement: Requirement::Optional("VapourSynth inputs and chunk methods"),
+    found: plugins.as_ref().ok().map(|_| "loaded".to_owned()),
+    hint: "check that VapourSynth is installed for the Python found in the PATH, e.g. with vspipe --version",
+  });
+  let plugins = plugins.ok();
+  checks.push(plugin(
+    "lsmash plugin",
+    plugins.as_ref(),
+    vapoursynth::LSMASH_PLUGIN,
+    Requirement::Optional("--chunk-method lsmash"),
+    install_hint(
+      "vsrepo install lsmas",
+      "brew install vapoursynth-lsmash, or vsrepo install lsmas",
+      "install the L-SMASH-Works VapourSynth plugin with your package manager, or vsrepo install lsmas",
+    ),
+  ));
+  checks.push(plugin(
+    "ffms2 plugin",
+    plugins.as_ref(),
+    vapoursynth::FFMS2_PLUGIN,
+    Requirement::Optional("--chunk-method ffms2"),
+    install_hint(
+      "vsrepo install ffms2",
+      "brew install ffms2, or vsrepo install ffms2",
+      "install the ffms2 VapourSynth plugin with your package manager, or vsrepo install ffms2",
+    ),
+  ));
+  checks.push(plugin(
+    "dgdecnv plugin",
+    plugins.as_ref(),
+    vapoursynth::DGDECNV_PLUGIN,
+    Requirement::Optional("--chunk-method dgdecnv"),
+    "download DGDecNV from https://www.rationalqm.us/dgdecnv/dgdecnv.html (requires an NVIDIA GPU)",
+  ));
+  // dgindexnv has no option to print its version
+  checks.push(Check {
+    name: "dgindexnv".to_owned(),
+    requirement: Requirement::Optional("--chunk-method dgdecnv"),
+    found: which::which("dgindexnv")
+      .ok()
+      .map(|path| path.display().to_string()),
+    hint: "add the folder of DGDecNV, which contains dgindexnv, to the PATH",
+  });
+  checks.push(plugin(
+    "bestsource plugin",
+    plugins.as_ref(),
+    vapoursynth::BESTSOURCE_PLUGIN,
+    Requirement::Optional("--chunk-method bestsource"),
+    install_hint(
+      "vsrepo install bs",
+      "vsrepo install bs",
+      "install the BestSource VapourSynth plugin with your package manager, or vsrepo install bs",
+    ),
+  ));
+  checks.push(program(
+    "mkvmerge",
+    &["--version"],
+    Requirement::Optional("--concat mkvmerge"),
+    install_hint(
+      "install MKVToolNix from https://mkvtoolnix.download/downloads.html",
+      "brew install mkvtoolnix",
+      "install mkvtoolnix with your package manager",
+    ),
+  ));
+
+  checks
+}
+
+fn print(checks: &[Check]) {
+  let width = checks
+    .iter()
+    .map(|check| check.name.len())
+    .max()
+    .unwrap_or(0);
+  for check in checks {
+    match (&check.found, check.requirement) {
+      (Some(found), _) => println!("[ok]      {:width$}  {found}", check.name),
+      (None, Requirement::Required) => {
+        println!("[missing] {:width$}  required: {}", check.name, check.hint);
+      }
+      (None, Requirement::Optional(needed_by)) => {
+        println!(
+          "[missing] {:width$}  needed by {needed_by}: {}",
+          check.name, check.hint
+        );
+      }
+    }
+  }
+}
+
+/// Checks the dependencies, failing if a required one is missing
+pub fn run(version: &str) -> anyhow::Result<()> {
+  println!("av1an {}\n", version.lines().next().unwrap_or_default());
+
+  let checks = checks();
+  print(&checks);
+
+  let missing: Vec<_> = checks
+    .iter()
+    .filter(|check| check.found.is_none() && check.requirement == Requirement::Required)
+    .map(|check| check.name.as_str())
+    .collect();
+  if !missing.is_empty() {
+    bail!("missing required dependencies: {}", missing.join(", "));
+  }
+  if checks
+    .iter()
+    .filter(|check| ENCODERS.iter().any(|e| e.bin() == check.name))
+    .all(|check| check.found.is_none())
+  {
+    bail!("no encoder is installed");
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn doctor_versions() {
+    assert_eq!(
+      parse_version("ffmpeg version 7.0.1 Copyright (c) 2000-2024 the FFmpeg developers\n"),
+      "7.0.1"
+    );
+    assert_eq!(
+      parse_version("mkvmerge v84.0 ('Sleeper Agent') 64-bit"),
+      "mkvmerge v84.0 ('Sleeper Agent') 64-bit"
+    );
+    assert_eq!(
+      parse_version("VapourSynth Video Processing Library\n"),
+      "VapourSynth Video Processing Library"
+    );
+  }
+}
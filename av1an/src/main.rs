@@ -7,24 +7,46 @@ use std::{panic, process};
 use ::ffmpeg::format::Pixel;
 use ansi_term::{Color, Style};
 use anyhow::{anyhow, bail, ensure, Context};
+use av1an_core::bit_allocation::BitAllocation;
 use av1an_core::concat::ConcatMethod;
 use av1an_core::context::Av1anContext;
-use av1an_core::encoder::Encoder;
-use av1an_core::logging::init_logging;
+use av1an_core::encoder::{Encoder, RateParam};
+use av1an_core::ffmpeg::FrameRate;
+use av1an_core::interlace::Deinterlace;
+use av1an_core::intermediate::Intermediate;
+use av1an_core::logging::{init_events, init_logging};
 use av1an_core::progress_bar::{get_first_multi_progress_bar, get_progress_bar};
+use av1an_core::scene_detect::ScBackend;
+use av1an_core::schedule::Schedule;
 use av1an_core::settings::{EncodeArgs, InputPixelFormat, PixelFormat};
-use av1an_core::target_quality::{adapt_probing_rate, TargetQuality};
-use av1an_core::util::read_in_dir;
+use av1an_core::target_quality::{adapt_probing_rate, QualityTargets, TargetQuality};
+use av1an_core::util::{fill_template, parse_resolution, parse_size, read_in_dir};
+use av1an_core::verify::VerifyOutput;
+use av1an_core::vmaf::{FrameWeighting, VmafModelAuto};
 use av1an_core::{
-  ffmpeg, hash_path, into_vec, vapoursynth, ChunkMethod, ChunkOrdering, Input, ScenecutMethod,
-  SplitMethod, Verbosity,
+  batch, cgroup, ffmpeg, hash_path, into_vec, remote, sidecar, vapoursynth, BestSourceCacheMode,
+  ChunkMethod, ChunkOrdering, Input, IoPriority, PipeMode, ProbingStatistic, ProcessPriority,
+  ScenecutMethod, SplitMethod, Verbosity,
 };
-use clap::{value_parser, Parser};
+use clap::value_parser;
 use flexi_logger::writers::LogWriter;
 use flexi_logger::{Level, LevelFilter};
 use once_cell::sync::OnceCell;
 use path_abs::{PathAbs, PathInfo};
-use tracing::{instrument, warn};
+use subcommand::Subcommand;
+use tracing::{info, instrument, warn};
+use tui::Tui;
+
+mod concat;
+mod config;
+mod doctor;
+mod models;
+mod probe;
+mod subcommand;
+mod tui;
+
+/// Output file of each input when neither -o nor --output-template is specified
+const DEFAULT_OUTPUT_TEMPLATE: &str = "{stem}_{encoder}.mkv";
 
 fn main() -> anyhow::Result<()> {
   let orig_hook = panic::take_hook();
@@ -113,26 +135,69 @@ fn version() -> &'static str {
   })
 }
 
-/// Cross-platform command-line AV1 / VP9 / HEVC / H264 encoding framework with per-scene quality encoding
-#[derive(Parser, Debug)]
-#[clap(name = "av1an", version = version())]
+/// Options of an encode
+#[derive(clap::Args, Debug)]
 pub struct CliOpts {
   /// Input file to encode
   ///
-  /// Can be a video or vapoursynth (.py, .vpy) script.
-  #[clap(short, required = true)]
+  /// Can be a video or vapoursynth (.py, .vpy) script, or the http:// or https:// URL of a video,
+  /// e.g. on a NAS, which is downloaded into the temporary folder with curl in segments that are
+  /// retried on their own and kept for --resume. It can also be - to encode a y4m or NUT stream
+  /// piped into stdin while it is read. The stream is cut into segments of 30 seconds, and the
+  /// chunks of each segment are encoded as soon as it is complete, which requires -o and cannot be
+  /// combined with options that need the whole input beforehand, such as --resume, --zones or
+  /// --vmaf.
+  #[clap(short, required_unless_present = "print_config")]
   pub input: Vec<PathBuf>,
 
   /// Video output file
   #[clap(short)]
   pub output_file: Option<PathBuf>,
 
+  /// Output file of each input when -o is not specified, e.g. when encoding a folder
+  ///
+  /// Placeholders are replaced for each input: {stem} (file name of the input without its extension), {encoder},
+  /// {width}, {height} and {resolution} (WIDTHxHEIGHT) of the input, {target_quality} (the VMAF target of
+  /// --target-quality, or "none"), {q} (the q or crf set by -v/--video-params or the default parameters, or "none"),
+  /// and {date} (the current date as YYYY-MM-DD). Braces are written as {{ and }}.
+  #[clap(
+    long,
+    conflicts_with = "output_file",
+    default_value = DEFAULT_OUTPUT_TEMPLATE
+  )]
+  pub output_template: String,
+
+  /// Use the options of this preset of the configuration files
+  ///
+  /// Options are read from av1an.toml in the configuration folder of the user (e.g. ~/.config/av1an/av1an.toml on
+  /// Linux), then from av1an.toml in the current directory, which overrides it. The keys are the long names of the
+  /// options (e.g. encoder = "svt-av1" or keep = true), and the [preset.<name>] tables hold presets, which override
+  /// the other options of both files. Options given on the command line override all of them.
+  #[clap(long)]
+  pub preset: Option<String>,
+
+  /// Print the options set by the configuration files and the preset, and exit
+  #[clap(long)]
+  pub print_config: bool,
+
   /// Temporary directory to use
   ///
   /// If not specified, the temporary directory name is a hash of the input file name.
+  ///
+  /// At the end of the encode, a report of the chunks is written to it as chunks_report.json, with the frame range,
+  /// the q chosen by target quality, the size, bitrate, encoding time and speed of each chunk, and the mean score of
+  /// its target quality probes. Use --keep to keep it after the encode.
   #[clap(long)]
   pub temp: Option<PathBuf>,
 
+  /// Folder on fast storage for the index caches of the chunk methods, e.g. an SSD
+  ///
+  /// The indexes of lsmash, ffms2, bestsource and dgdecnv are read at random by every worker, while the split
+  /// segments and encoded chunks are written and read in order and stay in --temp, which can then be on a larger and
+  /// slower drive. The indexes are kept in a folder named after the temporary directory.
+  #[clap(long)]
+  pub temp_on: Option<PathBuf>,
+
   /// Disable printing progress to the terminal
   #[clap(short, long, conflicts_with = "verbose")]
   pub quiet: bool,
@@ -141,10 +206,48 @@ pub struct CliOpts {
   #[clap(long)]
   pub verbose: bool,
 
+  /// Show a dashboard in the terminal instead of the progress bars
+  ///
+  /// The dashboard shows the chunk, frames and fps of every worker, the overall progress and ETA, the memory usage of
+  /// the system, the recently finished chunks with their size, and the log. Pressing q or Ctrl+C interrupts the encode,
+  /// which can be resumed with --resume.
+  #[clap(long, conflicts_with_all = &["quiet", "verbose"])]
+  pub tui: bool,
+
   /// Log file location [default: <temp dir>/log.log]
   #[clap(short, long)]
   pub log_file: Option<String>,
 
+  /// Also write structured events of the encode to this file, as one JSON object per line
+  ///
+  /// The events are the start and end of the encode and of every chunk (with its fps and size), the probes and chosen
+  /// Q of target quality, and the crashes of the encoder. Every line has an "event" field with the kind of event and a
+  /// "time" field, the file is appended to if it exists.
+  #[clap(long, value_name = "FILE")]
+  pub events_file: Option<PathBuf>,
+
+  /// Directory where pointers to the files of the latest encode are written [default: <system temp dir>/av1an]
+  ///
+  /// latest.json lists the paths of the temporary directory, log file, status.json, done.json, chunks.json and
+  /// reports of the most recently started encode. Where supported, latest (temporary directory) and latest.log
+  /// symlinks are created as well.
+  #[clap(long, env = "AV1AN_STATUS_DIR")]
+  pub status_dir: Option<PathBuf>,
+
+  /// POST a JSON summary of the encode to this URL when it finishes or fails
+  ///
+  /// The summary contains the status ("finished" or "failed"), input, output, duration in seconds, size of the output,
+  /// mean VMAF (with --vmaf) and error of the encode. It is posted with curl, which must be installed. The URL is not
+  /// saved in the settings.json of the encode.
+  #[clap(long, value_name = "URL")]
+  pub notify_url: Option<String>,
+
+  /// Show a desktop notification when the encode finishes or fails
+  ///
+  /// Uses notify-send on Linux and osascript on macOS.
+  #[clap(long)]
+  pub notify_desktop: bool,
+
   /// Set log level for log file (does not affect command-line log level)
   ///
   /// error: Designates very serious errors.
@@ -161,14 +264,57 @@ pub struct CliOpts {
   pub log_level: LevelFilter,
 
   /// Resume previous session from temporary directory
+  ///
+  /// Temporary directories of legacy versions of av1an (including the Python version) are converted to the current
+  /// format on a best-effort basis. The chunks are then created again from the scenes, so the same settings as the
+  /// original encode have to be used.
   #[clap(short, long)]
   pub resume: bool,
 
+  /// Keep encoding the remaining inputs when an input fails
+  ///
+  /// When several inputs (or a folder) are given, the status of each input is kept in a manifest in the current
+  /// directory, which also lists the error of every failed input. With --resume, the inputs that were encoded are
+  /// skipped and the others are resumed, including the failed ones.
+  #[clap(long)]
+  pub continue_on_error: bool,
+
+  /// Encode with the settings of a previous encode, read from the settings.json written next to its output
+  ///
+  /// Every encode writes its resolved settings, along with the versions of av1an and of the encoder, to
+  /// <output>.settings.json. The settings of the file replace the options of the command line, except for the input,
+  /// output, temporary directory, logging, scenes, zones and keyframes, which are specific to the input. A warning is
+  /// shown when the versions of av1an or of the encoder differ from the ones of the previous encode.
+  #[clap(long, value_name = "FILE", conflicts_with = "audio_only")]
+  pub from_settings: Option<PathBuf>,
+
+  /// Reuse the encoded chunks of a previous session, and only encode the audio and concatenate again
+  ///
+  /// All chunks in the temporary directory must have been encoded, e.g. by a previous encode with
+  /// --keep, or with --no-concat, which implies --keep. The video is not touched at all, while the
+  /// audio is always encoded again with the current -a/--audio-params and the chunks are
+  /// concatenated with the current --concat method, which makes it quick to try different audio
+  /// settings. Implies --resume and --keep.
+  #[clap(long, conflicts_with_all = &["sc_only", "benchmark"])]
+  pub remux: bool,
+
   /// Do not delete the temporary folder after encoding has finished
   #[clap(short, long)]
   pub keep: bool,
 
+  /// Keep the logs of the encoder of the chunks that succeeded
+  ///
+  /// The stderr of the encoder and of the source pipe of each pass of each chunk is written to
+  /// temp/logs/chunk_{index}_pass{n}.log, and the logs of a chunk are deleted once it is finished.
+  /// With this option they are kept, even when the rest of the temporary folder is deleted.
+  #[clap(long)]
+  pub keep_chunk_logs: bool,
+
   /// Do not check if the encoder arguments specified by -v/--video-params are valid
+  ///
+  /// The parameters, and the values of the parameters that take one of a list of names (e.g. --tune), are checked against the help of
+  /// the encoder, in both the video params and the zones file. Unknown parameters stop the encode, while values missing from the
+  /// help are only warned about, as it does not always list all of them.
   #[clap(long)]
   pub force: bool,
 
@@ -180,10 +326,34 @@ pub struct CliOpts {
   #[clap(short = 'n', conflicts_with = "overwrite")]
   pub never_overwrite: bool,
 
+  /// Never ask for confirmation, and fail instead of asking, e.g. in scripts or containers without a terminal
+  ///
+  /// An existing output file is then an error unless -y or -n is passed.
+  #[clap(long, env = "AV1AN_NON_INTERACTIVE")]
+  pub non_interactive: bool,
+
   /// Maximum number of chunk restarts for an encode
+  ///
+  /// Failures are classified from the exit status and output of the pipeline: when the encoder rejects its
+  /// parameters the encode stops without trying again, as every chunk would fail the same, a chunk whose source
+  /// failed to decode is tried again after a delay, and running out of memory stops a worker for the rest of the
+  /// encode.
+  ///
+  /// When the encoder still fails on a chunk after all tries, a crash_{chunk}.sh script (.bat on Windows) that runs
+  /// the pipeline of the chunk again, and a crash_{chunk}.json record of the crash, are written to the temporary
+  /// folder for bug reports.
   #[clap(long, default_value_t = 3, value_parser = value_parser!(u32).range(1..))]
   pub max_tries: u32,
 
+  /// Encode chunks longer than this many frames again in parts when the encoder fails on them
+  ///
+  /// Instead of encoding the whole chunk again, a chunk on which the encoder fails is split into parts of at most this
+  /// many frames, which are encoded (and retried according to --max-tries) one after another, and concatenated once
+  /// they are all encoded. The parts that are encoded are kept, so that another failure, or resuming an interrupted
+  /// encode, only encodes the remaining parts. The parts start with a keyframe, so this only affects chunks that fail.
+  #[clap(long, value_name = "FRAMES", value_parser = value_parser!(u64).range(1..), conflicts_with = "fps")]
+  pub checkpoint_frames: Option<u64>,
+
   /// Number of workers to spawn [0 = automatic]
   #[clap(short, long, default_value_t = 0)]
   pub workers: usize,
@@ -192,9 +362,68 @@ pub struct CliOpts {
   ///
   /// This is currently only supported on Linux and Windows, and does nothing on unsupported platforms.
   /// Leaving this option unspecified allows the OS to schedule all processes spawned.
+  ///
+  /// On Linux, workers are spread across NUMA nodes, and the threads of each worker are taken
+  /// from a single node whenever the node has enough threads. The vspipe, ffmpeg and encoder
+  /// processes of a worker all inherit its affinity.
   #[clap(long)]
   pub set_thread_affinity: Option<usize>,
 
+  /// CPU priority of the encoder, ffmpeg and vspipe processes spawned for each chunk
+  ///
+  /// Available priorities: idle, below-normal, normal, above-normal, high
+  ///
+  /// On Linux and macOS this sets the niceness of the processes (19, 10, 0, -5 and -10 respectively),
+  /// on Windows it sets their priority class. Raising the priority above normal usually requires
+  /// elevated privileges, and is silently ignored otherwise. Leaving this option unspecified
+  /// keeps the priority inherited from av1an.
+  #[clap(long)]
+  pub priority: Option<ProcessPriority>,
+
+  /// IO priority of the encoder, ffmpeg and vspipe processes spawned for each chunk
+  ///
+  /// Available priorities: idle, low, normal, high
+  ///
+  /// This is currently only supported on Linux (equivalent to ionice), and does nothing on other
+  /// platforms. Leaving this option unspecified keeps the IO priority inherited from av1an.
+  #[clap(long)]
+  pub io_priority: Option<IoPriority>,
+
+  /// Place the encoder, ffmpeg and vspipe processes in a dedicated cgroup (Linux only)
+  ///
+  /// This requires cgroup v2, and a cgroup that av1an is allowed to manage (e.g. by running av1an with
+  /// `systemd-run --user --scope -p Delegate=yes`). The CPU weight of the cgroup is set according to --priority,
+  /// and its memory limit to --max-memory, which are then enforced by the kernel for all processes combined.
+  #[clap(long)]
+  pub cgroup: bool,
+
+  /// Maximum memory used by all encoder, ffmpeg and vspipe processes combined, e.g. 16G (requires --cgroup)
+  ///
+  /// Processes exceeding the limit are reclaimed from, and eventually killed by the kernel, in which case the
+  /// chunk is retried according to --max-tries.
+  #[clap(long, requires = "cgroup", value_parser = parse_size)]
+  pub max_memory: Option<u64>,
+
+  /// Only start encoding chunks within the given daily windows of local time, e.g. "22:00-07:00"
+  ///
+  /// Multiple windows can be separated by commas, e.g. "22:00-07:00,12:00-13:30". Outside of the windows, workers
+  /// finish the chunk they are encoding and then pause until the next window starts, so chunks in progress may
+  /// run past the end of a window.
+  #[clap(long)]
+  pub schedule: Option<Schedule>,
+
+  /// Average power draw of the system while encoding, in watts, to estimate the energy used by the encode
+  ///
+  /// The estimate is printed after encoding, and is based on the time spent encoding each chunk (including chunks
+  /// encoded before resuming), divided by the number of workers. It does not include scene detection, target quality
+  /// probes or concatenation.
+  #[clap(long, value_name = "WATTS")]
+  pub power_draw: Option<f64>,
+
+  /// Price of energy per kWh, to estimate the cost of the encode along with its energy (requires --power-draw)
+  #[clap(long, value_name = "PRICE", requires = "power_draw")]
+  pub energy_price: Option<f64>,
+
   /// Scaler used for scene detection (if --sc-downscale-height XXXX is used) and VMAF calculation
   ///
   /// Valid scalers are based on the scalers available in ffmpeg, including lanczos[1-9] with [1-9]
@@ -222,6 +451,10 @@ pub struct CliOpts {
   /// "av-scenechange" uses an algorithm to analyze which frames of the video are the start of new
   /// scenes, while "none" disables scene detection entirely (and only relies on -x/--extra-split to
   /// add extra scenecuts).
+  ///
+  /// "source-keyframes" splits at the keyframes of the source instead of detecting the scenes, which
+  /// is fast for re-encodes of masters whose keyframes are already at the scene changes. Keyframes
+  /// closer than --min-scene-len to the previous split are skipped. Only for video inputs.
   #[clap(long, default_value_t = SplitMethod::AvScenechange, help_heading = "Scene Detection")]
   pub split_method: SplitMethod,
 
@@ -233,9 +466,29 @@ pub struct CliOpts {
   #[clap(long, default_value_t = ScenecutMethod::Standard, help_heading = "Scene Detection")]
   pub sc_method: ScenecutMethod,
 
+  /// Scene detector of --split-method av-scenechange
+  ///
+  /// av-scenechange - The scene detection of rav1e, set with --sc-method.
+  ///
+  /// ffmpeg-scdet - The scdet filter of ffmpeg on the luma of the frames, which decodes formats av-scenechange cannot
+  /// read directly.
+  ///
+  /// vapoursynth-wwxd - The wwxd plugin of VapourSynth, which needs a VapourSynth input, or a video input split with a
+  /// VapourSynth chunk method.
+  ///
+  /// The scene changes of every detector are split into scenes the same way, keeping the zones and --min-scene-len.
+  /// Only av-scenechange and ffmpeg-scdet score the scene changes in the scene file, and --sc-method and
+  /// --sc-pix-format only apply to av-scenechange.
+  #[clap(long, default_value_t = ScBackend::AvScenechange, help_heading = "Scene Detection")]
+  pub sc_backend: ScBackend,
+
   /// Run the scene detection only before exiting
   ///
   /// Requires a scene file with --scenes.
+  ///
+  /// Each scene found by av-scenechange includes the scores of the scene change it starts with in the scene file:
+  /// its cost, the mean absolute difference of the luma of the frames on either side relative to the maximum value,
+  /// and its confidence, from 0 for a gradual transition such as a fade to 1 for a hard cut.
   #[clap(long, requires("scenes"), help_heading = "Scene Detection")]
   pub sc_only: bool,
 
@@ -253,6 +506,14 @@ pub struct CliOpts {
   #[clap(long, help_heading = "Scene Detection")]
   pub sc_downscale_height: Option<usize>,
 
+  /// Run the scene detection on this proxy of the input instead
+  ///
+  /// The proxy is a smaller copy of the input with the same frames, e.g. a 480p x264 encode of it, which is decoded
+  /// much faster than an 8K or remote source. The chunks are still encoded from the input. The proxy must have as many
+  /// frames as the input.
+  #[clap(long, value_name = "FILE", help_heading = "Scene Detection")]
+  pub sc_proxy: Option<PathBuf>,
+
   /// Maximum scene length
   ///
   /// When a scenecut is found whose distance to the previous scenecut is greater than the value
@@ -261,10 +522,39 @@ pub struct CliOpts {
   #[clap(short = 'x', long, help_heading = "Scene Detection")]
   pub extra_split: Option<usize>,
 
+  /// Place extra splits at the most static frames instead of at even intervals
+  ///
+  /// Each extra split is placed at the frame that differs the least from the previous frame, between half of the
+  /// maximum scene length and the maximum scene length after the previous split. Keyframes in static sections are
+  /// cheaper to encode than keyframes in motion. This uses the frame differences measured by av-scenechange, so it
+  /// has no effect with --split-method none or an existing scene file.
+  #[clap(long, help_heading = "Scene Detection")]
+  pub extra_split_adaptive: bool,
+
   /// Minimum number of frames for a scenecut
   #[clap(long, default_value_t = 24, help_heading = "Scene Detection")]
   pub min_scene_len: usize,
 
+  /// Minimum length of a scenecut, in seconds
+  ///
+  /// Converted to frames with the frame rate of each input, so that inputs of different frame rates get the same
+  /// minimum duration. Cannot be used with --min-scene-len.
+  #[clap(
+    long,
+    conflicts_with = "min_scene_len",
+    help_heading = "Scene Detection"
+  )]
+  pub min_scene_len_sec: Option<f64>,
+
+  /// Minimum number of frames between keyframes, after scene detection
+  ///
+  /// Scenes shorter than this are merged into a neighbouring scene. Of the two scene changes
+  /// around a short scene, the one scene detection is the least confident in is dropped, so that
+  /// hard cuts are kept over fades. Forced keyframes and the boundaries of zones are always kept.
+  /// The maximum distance between keyframes is set by --extra-split.
+  #[clap(long, help_heading = "Scene Detection")]
+  pub min_keyframe_interval: Option<usize>,
+
   /// Comma-separated list of frames to force as keyframes
   ///
   /// Can be useful for improving seeking with chapters, etc.
@@ -272,6 +562,13 @@ pub struct CliOpts {
   #[clap(long, help_heading = "Scene Detection")]
   pub force_keyframes: Option<String>,
 
+  /// Force keyframes at the start of the chapters of the input
+  ///
+  /// The timestamps of the chapters are converted to frames with the frame rate of the input, and
+  /// merged with --force-keyframes, so that seeking to a chapter lands exactly on a keyframe.
+  #[clap(long, help_heading = "Scene Detection")]
+  pub force_keyframes_from_chapters: bool,
+
   /// Ignore any detected mismatch between scene frame count and encoder frame count
   #[clap(long, help_heading = "Encoding")]
   pub ignore_frame_mismatch: bool,
@@ -280,15 +577,50 @@ pub struct CliOpts {
   #[clap(short, long, default_value_t = Encoder::aom, help_heading = "Encoding")]
   pub encoder: Encoder,
 
+  /// Encode the chunks losslessly into an intermediate master instead, with x264 at qp 0 or with ffv1
+  ///
+  /// The chunks are split, filtered by --ffmpeg or the VapourSynth script, and encoded in parallel like the chunks of
+  /// an encoder, then concatenated into a Matroska file. This prepares a filtered master, e.g. for archival or
+  /// inspection, with the parallelism of the chunks before the final encode. x264 chunks are encoded with
+  /// --preset veryfast, and ffv1 chunks by ffmpeg with only intra frames and slice CRCs. The chunks keep the pixel
+  /// format of a video source instead of --pix-format, of which x264 supports up to 10 bits.
+  #[clap(
+    long,
+    conflicts_with_all = &["encoder", "video_params", "auto_params", "passes", "target_quality", "photon_noise", "pix_format"],
+    help_heading = "Encoding"
+  )]
+  pub intermediate: Option<Intermediate>,
+
   /// Parameters for video encoder
   ///
   /// These parameters are for the encoder binary directly, so the ffmpeg syntax cannot be used.
   /// For example, CRF is specified in ffmpeg via "-crf <crf>", but the x264 binary takes this
   /// value with double dashes, as in "--crf <crf>". See the --help output of each encoder for
   /// a list of valid options.
+  ///
+  /// The psy forks of aomenc and SVT-AV1 are detected automatically, so that their additional
+  /// parameters are accepted, and the default parameters make use of their psychovisual tuning.
   #[clap(short, long, allow_hyphen_values = true, help_heading = "Encoding")]
   pub video_params: Option<String>,
 
+  /// Pick the default parameters of the encoder for the source, instead of using the same defaults for every source
+  ///
+  /// The resolution and frame rate of the source select the preset, tiles and keyframe interval, HDR sources are
+  /// encoded with their color parameters in the bit depth of --pix-format, and grainy sources (estimated from a few
+  /// frames) are encoded without the temporal filtering that would smooth out the grain. The parameters that are
+  /// picked are logged.
+  #[clap(long, conflicts_with = "video_params", help_heading = "Encoding")]
+  pub auto_params: bool,
+
+  /// Keep the Dolby Vision metadata of a profile 8.1 source when encoding with x265
+  ///
+  /// The RPU of the source is extracted with dovi_tool, and split into the frame ranges of the chunks, which x265
+  /// embeds with --dolby-vision-rpu. After concatenation, the complete RPU is injected into the output again. Requires
+  /// dovi_tool and mkvmerge. The HDR10 parameters of x265 (e.g. --master-display and --max-cll) still have to be
+  /// specified with --video-params.
+  #[clap(long, help_heading = "Encoding")]
+  pub dolby_vision: bool,
+
   /// Number of encoder passes
   ///
   /// Since aom and vpx benefit from two-pass mode even with constant quality mode (unlike other
@@ -297,7 +629,10 @@ pub struct CliOpts {
   ///
   /// When using aom or vpx with RT mode (--rt), one-pass mode is always used regardless of the
   /// value specified by this flag (as RT mode in aom and vpx only supports one-pass encoding).
-  #[clap(short, long, value_parser = value_parser!(u8).range(1..=2), help_heading = "Encoding")]
+  ///
+  /// Three passes are supported by aom, svt-av1, x264 and x265, where the second pass refines the
+  /// statistics of the first one.
+  #[clap(short, long, value_parser = value_parser!(u8).range(1..=3), help_heading = "Encoding")]
   pub passes: Option<u8>,
 
   /// Audio encoding parameters (ffmpeg syntax)
@@ -321,7 +656,28 @@ pub struct CliOpts {
   #[clap(short, long, allow_hyphen_values = true, help_heading = "Encoding")]
   pub audio_params: Option<String>,
 
+  /// Do not extract the audio of the input, so that the output only contains the video
+  ///
+  /// The subtitles of the input, which are muxed along with the audio, are not kept either.
+  #[clap(long, visible_alias = "video-only", conflicts_with_all = &["audio_params", "audio_only"], help_heading = "Encoding")]
+  pub no_audio: bool,
+
+  /// Only encode the audio of the input, and mux it with the video of this existing encode into the output
+  ///
+  /// The audio (and subtitles) of the input are encoded with -a/--audio-params, while the video streams of the given
+  /// file are copied without being encoded again, e.g. to try different audio settings on a finished encode. Nothing
+  /// is done with the video of the input.
+  #[clap(long, value_name = "VIDEO", conflicts_with_all = &["sc_only", "remux", "resume", "benchmark", "preview_chunks"], help_heading = "Encoding")]
+  pub audio_only: Option<PathBuf>,
+
   /// FFmpeg filter options
+  ///
+  /// The filters are applied to each chunk separately. If they can change the number of frames
+  /// (e.g. fps, select, trim or mpdecimate), every chunk is passed through the filters once before
+  /// encoding to count its frames, so that the frame counts of the chunks and the progress match
+  /// the output. Scene detection works on the frames of the input, but the input is also passed
+  /// through the filters once to count its frames, so that --min-scene-len, --extra-split and
+  /// --min-keyframe-interval are frames of the output.
   #[clap(
     short = 'f',
     long = "ffmpeg",
@@ -330,6 +686,49 @@ pub struct CliOpts {
   )]
   pub ffmpeg_filter_args: Option<String>,
 
+  /// Resolution of the encoded video, e.g. 1920x1080, for the default tiles and number of workers
+  ///
+  /// By default, the resolution is that of the input after the scale, crop, pad and transpose filters of -f/--ffmpeg.
+  /// This only needs to be set if the filters change the resolution in a way that cannot be followed, e.g. with
+  /// expressions other than `iw/2`.
+  #[clap(long, value_parser = parse_resolution, help_heading = "Encoding")]
+  pub output_res: Option<(u32, u32)>,
+
+  /// Convert the video to this frame rate, e.g. 24000/1001 or 30
+  ///
+  /// Frames are dropped or duplicated with the fps filter of ffmpeg, which is added to the filters of -f/--ffmpeg in
+  /// the pipe of every chunk. Scene detection and chunking still work on the frames of the input, and the frames of
+  /// each chunk are converted so that they add up to the converted length of the whole video. The duration of the
+  /// video is kept, so the audio does not need to be adjusted.
+  ///
+  /// Cannot be used with --vmaf, --quality-report or --verify-chunks, as the frames would no longer match the frames
+  /// of the input.
+  #[clap(long, conflicts_with_all = &["vmaf", "quality_report", "verify_chunks"], help_heading = "Encoding")]
+  pub fps: Option<FrameRate>,
+
+  /// Interpolate frames with the minterpolate filter of ffmpeg when converting with --fps
+  ///
+  /// Motion interpolation is much slower than dropping or duplicating frames, but is smoother when increasing the
+  /// frame rate.
+  #[clap(long, requires = "fps", help_heading = "Encoding")]
+  pub fps_interpolate: bool,
+
+  /// Deinterlace the source
+  ///
+  /// The field order of video inputs is detected on their first frames with the idet filter of ffmpeg, and a warning is
+  /// shown for interlaced sources, as the encoders treat all frames as progressive.
+  ///
+  /// auto - Deinterlace with bwdif if the source is detected as interlaced.
+  ///
+  /// bwdif - Deinterlace with the bwdif filter of ffmpeg, which is added to the filters of -f/--ffmpeg.
+  ///
+  /// qtgmc - Deinterlace with QTGMC in the VapourSynth script of the chunk method, which is slower but of much higher
+  /// quality. Requires havsfunc, and one of the lsmash, ffms2, dgdecnv or bestsource chunk methods.
+  ///
+  /// The frame rate is kept, and the frames are flagged as progressive.
+  #[clap(long, help_heading = "Encoding")]
+  pub deinterlace: Option<Deinterlace>,
+
   /// Method used for piping exact ranges of frames to the encoder
   ///
   /// Methods that require an external vapoursynth plugin:
@@ -346,6 +745,7 @@ pub struct CliOpts {
   /// dgdecnv - Very fast, but only decodes AVC, HEVC, MPEG-2, and VC1. Does not require intermediate files.
   /// Requires dgindexnv to be present in system path, NVIDIA GPU that support CUDA video decoding, and dgdecnv vapoursynth plugin
   /// to be installed.
+  /// Pulldown flags are ignored, and the number of workers is limited by the free memory of the GPU when it is not set.
   ///
   /// bestsource - Very slow but accurate. Linearly decodes input files, very slow. Does not require intermediate files, requires the BestSource vapoursynth plugin
   /// to be installed.
@@ -363,9 +763,48 @@ pub struct CliOpts {
   /// Requires intermediate files (which can be large).
   ///
   /// Default: lsmash (if available), otherwise ffms2 (if available), otherwise DGDecNV (if available), otherwise bestsource (if available), otherwise hybrid.
+  ///
+  /// A chunk the encoder keeps failing on with a vapoursynth method (for example, on a broken region of the bitstream) is decoded
+  /// again with ffms2, then with ffmpeg like hybrid, instead of failing the encode. The chunks decoded with a fallback are logged.
   #[clap(short = 'm', long, help_heading = "Encoding")]
   pub chunk_method: Option<ChunkMethod>,
 
+  /// How the bestsource chunk method caches the index of the source
+  ///
+  /// With bestsource, the source is indexed before scene detection, as its linear scan of the source is slow. The workers then share
+  /// the index.
+  ///
+  /// never - The index is not written, so every worker indexes the source again.
+  ///
+  /// auto - The index is only written if indexing the source took long enough, as decided by bestsource.
+  ///
+  /// always - The index is always written.
+  #[clap(long, default_value_t = BestSourceCacheMode::Always, help_heading = "Encoding")]
+  pub bestsource_cachemode: BestSourceCacheMode,
+
+  /// NVIDIA GPUs to decode with DGDecNV on, e.g. 0,1 (defaults to the first GPU)
+  ///
+  /// The decoders of the workers are spread round-robin across the GPUs, and the default number of workers is sized
+  /// by the free memory of the GPUs, as every decoder needs memory on its GPU.
+  #[clap(long, help_heading = "Encoding")]
+  pub gpu_devices: Option<String>,
+
+  /// Whether the frames of the chunks are piped through ffmpeg before the encoder
+  ///
+  /// ffmpeg is only needed to filter the frames (-f/--ffmpeg) or to convert their pixel format, and needs an additional
+  /// process per worker.
+  ///
+  /// auto - With a vapoursynth chunk method, the filters and the pixel format conversion are done in the VapourSynth
+  /// script instead, if they can be: scale, crop, hflip and vflip with sizes that are numbers or simple expressions of
+  /// iw and ih, and that do not split the chroma samples of the source (e.g. odd crops of 4:2:0 video). Otherwise, or
+  /// with other chunk methods, the frames are piped through ffmpeg when needed.
+  ///
+  /// vs-only - Like auto, but fails if the frames would have to be piped through ffmpeg.
+  ///
+  /// ffmpeg - The frames are always piped through ffmpeg, even if it is not needed.
+  #[clap(long, default_value_t = PipeMode::Auto, help_heading = "Encoding")]
+  pub pipe_mode: PipeMode,
+
   /// The order in which av1an will encode chunks
   ///
   /// Available methods:
@@ -381,6 +820,55 @@ pub struct CliOpts {
   #[clap(long, default_value_t = ChunkOrdering::LongestFirst, help_heading = "Encoding")]
   pub chunk_order: ChunkOrdering,
 
+  /// Benchmark the current settings by encoding only this many chunks, sampled evenly across the video
+  ///
+  /// Reports the measured fps along with the projected encode time and output size for the whole
+  /// video, then exits without concatenating.
+  #[clap(long, help_heading = "Encoding")]
+  pub benchmark: Option<usize>,
+
+  /// Encode only these chunks (comma-separated indices) with the current settings, and write each as a playable file
+  ///
+  /// The chunks are written next to the output file, e.g. chunk 12 of output.mkv to output.chunk00012.ivf, so that
+  /// settings can be tried on hard scenes before starting a full encode. The indices of the chunks are those of the
+  /// scenes in scenes.json, e.g. as reported by --sc-only.
+  #[clap(long, conflicts_with_all = &["benchmark", "remux"], help_heading = "Encoding")]
+  pub preview_chunks: Option<String>,
+
+  /// Group this many consecutive chunks into super-chunks, which are encoded one after another (disabled by default)
+  ///
+  /// Once all chunks of a super-chunk are encoded, they are concatenated without audio into the super folder of the
+  /// temporary directory, and the encoded chunks as well as their split source files of the segment and hybrid chunk
+  /// methods are deleted. This bounds the size of the temporary directory for very long inputs, at the cost of some
+  /// parallelism at the end of each super-chunk. The same value has to be used when resuming the encode.
+  #[clap(long, help_heading = "Encoding")]
+  pub super_chunks: Option<usize>,
+
+  /// Pause splitting the input while the temporary directory is larger than this size, e.g. 200G
+  ///
+  /// The input is split into segments in the background while the chunks are encoded, and the splitting is paused
+  /// until finished super-chunks are concatenated and their segments deleted. It is not paused while a worker waits
+  /// for its segment, so the size can be exceeded by the segments being encoded. Requires --chunk-method hybrid and
+  /// --super-chunks, and is only supported on Unix.
+  #[clap(long, value_parser = parse_size, help_heading = "Encoding")]
+  pub max_temp_size: Option<u64>,
+
+  /// Start encoding even if the disks of the temporary directory and of the output seem too small
+  ///
+  /// Before encoding, the space needed is estimated from the size of the source, assuming the encode is no larger
+  /// than it: the encoded chunks, the segments of the source with the segment and hybrid chunk methods, and the
+  /// output. Av1an refuses to start if the disks do not have that much space free.
+  #[clap(long, help_heading = "Encoding")]
+  pub ignore_disk_space: bool,
+
+  /// Pause the workers while the disk of the temporary directory or of the output has less free space than this
+  ///
+  /// Workers check the free space before starting each chunk, and wait until space is freed instead of failing
+  /// in the middle of the encode or while concatenating. Pausing and resuming are logged as warnings. Disabled by
+  /// default.
+  #[clap(long, value_parser = parse_size, help_heading = "Encoding")]
+  pub min_free_space: Option<u64>,
+
   /// Generates a photon noise table and applies it using grain synthesis [strength: 0-64] (disabled by default)
   ///
   /// Photon noise tables are more visually pleasing than the film grain generated by aomenc,
@@ -411,18 +899,89 @@ pub struct CliOpts {
   /// ffmpeg - Uses ffmpeg for concatenation. Unfortunately, ffmpeg sometimes produces files
   /// with partially broken audio seeking, so mkvmerge should generally be preferred if available.
   /// ffmpeg concatenation also produces broken files with the --enable-keyframe-filtering=2 option
-  /// in aomenc, so it is disabled if that option is used. However, ffmpeg can mux into formats other
-  /// than matroska (.mkv), such as WebM. To output WebM, use a .webm extension in the output file.
+  /// in aomenc, so it is disabled if that option is used, except for MP4 outputs, which are then
+  /// concatenated with mkvmerge if it is installed. However, ffmpeg can mux into formats other
+  /// than matroska (.mkv), such as WebM and MP4. To output WebM or MP4, use a .webm or .mp4
+  /// extension in the output file. MP4 outputs have the index at the front (faststart) and the
+  /// av01 or hvc1 codec tag.
   ///
   /// mkvmerge - Generally the best concatenation method (as it does not have either of the
-  /// aforementioned issues that ffmpeg has), but can only produce matroska (.mkv) files. Requires mkvmerge
-  /// to be installed.
+  /// aforementioned issues that ffmpeg has), but can only produce matroska (.mkv) files, which
+  /// are remuxed with ffmpeg for MP4 outputs. Requires mkvmerge to be installed. This is also the
+  /// only method that keeps the timestamps of variable frame rate sources, which are detected
+  /// automatically.
   ///
   /// ivf - Experimental concatenation method implemented in av1an itself to concatenate to an ivf
-  /// file (which only supports VP8, VP9, and AV1, and does not support audio).
+  /// file (which only supports VP8, VP9, and AV1, and does not support audio). The timestamps of the
+  /// chunks are made continuous, and the chunks must have the same codec and frame rate, and start
+  /// with a keyframe.
   #[clap(short, long, default_value_t = ConcatMethod::FFmpeg, help_heading = "Encoding")]
   pub concat: ConcatMethod,
 
+  /// Output WebM with Opus audio
+  ///
+  /// Only VP9 and AV1 encoders (aom, rav1e, svt-av1 and vpx) can be used, the chunks are concatenated with ffmpeg
+  /// with the cues of the WebM at the front for faster seeking, and the audio is encoded with libopus at 128k
+  /// unless -a/--audio-params is set, which can only use libopus, libvorbis or copy. The audio is copied when
+  /// -a/--audio-params sets no codec, which requires the audio of the input to be Opus or Vorbis. Subtitles and
+  /// attachments of the input are not kept. The output must have a .webm extension, which --output-template then
+  /// defaults to.
+  #[clap(long, help_heading = "Encoding")]
+  pub webm: bool,
+
+  /// Encode chunks whose bitrate exceeds this many kbps again at a higher q/crf
+  ///
+  /// After a chunk is encoded, its bitrate is checked against the limit. A chunk over the limit is encoded
+  /// again with its q/crf raised by the estimated number of steps that brings it under the limit, up to 3 times
+  /// or until the highest q/crf of the encoder, which prevents spikes of bitrate in grainy scenes. The q/crf is
+  /// taken from --video-params, or from target quality, so the rate control of the encoder must be set to a q/crf
+  /// mode.
+  #[clap(long, help_heading = "Encoding")]
+  pub max_chunk_bitrate: Option<f64>,
+
+  /// Command to run on each finished chunk, e.g. to hand it off to an external packager
+  ///
+  /// The command is run by the worker as soon as the chunk has been encoded. {chunk} is replaced by
+  /// the path of the encoded chunk, {index} by the index of the chunk and {frames} by its number of
+  /// frames. If {chunk} is not used, the path is appended as the last argument. A failing command is
+  /// logged, but does not stop the encode.
+  #[clap(long, help_heading = "Encoding")]
+  pub chunk_command: Option<String>,
+
+  /// Do not concatenate the encoded chunks into an output file
+  ///
+  /// Useful with --chunk-command, when the chunks are consumed by another tool instead. Implies --keep, so that the
+  /// encoded chunks are kept in the encode folder of the temporary directory.
+  #[clap(long, help_heading = "Encoding")]
+  pub no_concat: bool,
+
+  /// Concatenate the finished chunks at the start of the video into the output while the encode is still running
+  ///
+  /// The output is updated at most every 30 seconds, so that the start of a long encode can be previewed. The chunks
+  /// are encoded in the order they appear in the video regardless of --chunk-order, so that the output can keep
+  /// growing.
+  #[clap(long, conflicts_with_all = &["no_concat", "super_chunks"], help_heading = "Encoding")]
+  pub progressive_concat: bool,
+
+  /// Decode the output once it is concatenated, and check its frame count and duration against the encode
+  ///
+  /// frames - The output is decoded with ffmpeg, and its frame count and duration must match the frames of the
+  /// encode. Errors reported by the decoder also fail the verification.
+  ///
+  /// hashes - The encoded chunks are also decoded, and the hashes of their frames are compared with the frames of
+  /// the output, which finds the chunk the output starts to differ at. This decodes the video twice.
+  ///
+  /// If the verification fails, the encode fails and the temporary folder is kept, so that the output can be
+  /// concatenated again.
+  #[clap(
+    long,
+    num_args = 0..=1,
+    default_missing_value = "frames",
+    conflicts_with = "no_concat",
+    help_heading = "Encoding"
+  )]
+  pub verify_output: Option<VerifyOutput>,
+
   /// FFmpeg pixel format
   #[clap(long, default_value = "yuv420p10le", help_heading = "Encoding")]
   pub pix_format: Pixel,
@@ -460,7 +1019,16 @@ pub struct CliOpts {
   ///
   /// Example line 2 will encode frames 169-1329 using rav1e.
   /// The `reset` keyword instructs av1an to ignore any settings
-  /// which affect the encoder, and use only the parameters from this zone.
+  /// which affect the encoder, and use only the parameters from this zone:
+  ///
+  /// - `--video-params` are cleared
+  /// - `--passes` is set to the default of the zone's encoder
+  /// - `--photon-noise`, `--photon-noise-width`, `--photon-noise-height`
+  ///   and `--chroma-noise` are disabled
+  /// - `--target-quality` is disabled, so the zone's own rate control is used
+  /// - `--extra-split` and `--min-scene-len` are kept, as they only affect scene detection
+  ///
+  /// Any of these can be enabled again for the zone by passing them in the zone's args.
   ///
   /// For segments where no zone is specified,
   /// the settings passed to av1an itself will be used.
@@ -471,23 +1039,75 @@ pub struct CliOpts {
   /// - `-x`/`--extra-split`
   /// - `--min-scene-len`
   /// - `--passes`
-  /// - `--photon-noise` (aomenc/rav1e only)
+  /// - `--pix-format`, which needs `--concat mkvmerge` or `ivf` if it differs from the
+  ///   pixel format of the encode
+  /// - `--photon-noise` (aomenc/rav1e only), where 0 disables it for the zone
+  /// - `--photon-noise-width`/`--photon-noise-height`
+  /// - `--chroma-noise`
+  /// - `--denoise <STRENGTH>`, which denoises the frames of the zone with ffmpeg's
+  ///   `hqdn3d` filter before they are encoded, and synthesizes the removed grain
+  ///   back with a `--photon-noise` of twice the strength unless the zone sets it
+  /// - `--target-quality` (only if target quality is enabled for the whole encode)
   #[clap(long, help_heading = "Encoding", verbatim_doc_comment)]
   pub zones: Option<PathBuf>,
 
+  /// Check the zones file against the input and exit, without encoding
+  ///
+  /// Each zone is parsed and checked with the same rules as an encode, including the number of frames of the input
+  /// and the other encoding options. The first invalid zone is reported with its line number.
+  #[clap(long, requires = "zones", help_heading = "Encoding")]
+  pub validate_zones: bool,
+
   /// Plot an SVG of the VMAF for the encode
   ///
   /// This option is independent of --target-quality, i.e. it can be used with or without it.
-  /// The SVG plot is created in the same directory as the output file.
+  /// The SVG plot is created in the same directory as the output file. Scene boundaries are marked
+  /// on the plot, along with the CQ chosen for each scene when --target-quality is used.
   #[clap(long, help_heading = "VMAF")]
   pub vmaf: bool,
 
-  /// Path to VMAF model (used by --vmaf and --target-quality)
+  /// Generate a quality report for the encode
   ///
-  /// If not specified, ffmpeg's default is used.
+  /// Computes VMAF, PSNR, SSIM and SSIMULACRA2 for every frame, and writes a JSON and an HTML report
+  /// with the mean, 1% low and harmonic mean of each metric, overall and per scene, along with plots.
+  /// The reports are created in the same directory as the output file. SSIMULACRA2 requires
+  /// ssimulacra2_rs to be installed, and is skipped otherwise. The --vmaf-* options also apply here.
   #[clap(long, help_heading = "VMAF")]
+  pub quality_report: bool,
+
+  /// Path to VMAF model (used by --vmaf and --target-quality)
+  ///
+  /// If not specified, ffmpeg's default is used. The models downloaded by `av1an models fetch` can be given by name
+  /// instead: vmaf, vmaf_4k or vmaf_neg.
+  #[clap(long, value_parser = models::resolve_vmaf_path, help_heading = "VMAF")]
   pub vmaf_path: Option<PathBuf>,
 
+  /// Choose the VMAF model by the resolution VMAF is calculated at (used by --vmaf and --target-quality)
+  ///
+  /// Without a value, libvmaf's built-in vmaf_4k_v0.6.1 model is used from 2160p, and vmaf_v0.6.1 below. Custom
+  /// models can be given per resolution as a comma-separated list of MIN_HEIGHT=MODEL, where MODEL is a path or
+  /// version=NAME for a model built into libvmaf, e.g. `2160=/models/4k.json,0=version=vmaf_v0.6.1`. A model for
+  /// height 0 is required. Frames wider than 16:9 are matched by the height of a 16:9 frame of the same width.
+  ///
+  /// Target quality probes use the model for --probe-res if set, or --vmaf-res.
+  #[clap(
+    long,
+    num_args = 0..=1,
+    default_missing_value = "",
+    conflicts_with = "vmaf_path",
+    value_name = "BUCKETS",
+    help_heading = "VMAF"
+  )]
+  pub vmaf_model_auto: Option<VmafModelAuto>,
+
+  /// Use the NEG (no enhancement gain) variant of the VMAF model (used by --vmaf and --target-quality)
+  ///
+  /// Standard VMAF rewards sharpening and other enhancements, which can mislead the Q chosen by target quality for
+  /// each scene. The NEG models do not, and score only the fidelity to the source. Applies to the models built into
+  /// libvmaf, including the ones chosen by --vmaf-model-auto.
+  #[clap(long, conflicts_with = "vmaf_path", help_heading = "VMAF")]
+  pub vmaf_neg: bool,
+
   /// Resolution used for VMAF calculation
   ///
   /// If set to inputres, the output video will be scaled to the resolution of the input video.
@@ -504,14 +1124,58 @@ pub struct CliOpts {
   #[clap(long, help_heading = "VMAF")]
   pub vmaf_filter: Option<String>,
 
+  /// Even out the quality of the scenes with a quick x264 proxy encode instead of target quality probes
+  ///
+  /// Every chunk is encoded once with the ultrafast preset of x264 at a fixed CRF, and the bits per frame of these
+  /// encodes are used as an estimate of the complexity of the scenes. The q/crf set in --video-params is lowered for
+  /// complex scenes and raised for simple ones, relative to the median scene. This is much faster than --target-quality,
+  /// but less accurate, as the complexity for x264 does not necessarily match the complexity for the encoder.
+  ///
+  /// Requires x264. The adjusted q/crf of each chunk is saved in the temporary folder, so the proxy encodes are not
+  /// repeated when resuming.
+  #[clap(
+    long,
+    conflicts_with = "target_quality",
+    help_heading = "Target Quality"
+  )]
+  pub quick_consistency: bool,
+
+  /// Allocate an average bitrate in kbps to the scenes, for the highest mean VMAF
+  ///
+  /// Every chunk is first probed like target quality does: it is encoded at --probes q/crf spread evenly between
+  /// --min-q and --max-q, and each probe is scored with VMAF. The q/crf of each scene is then chosen so that the whole
+  /// encode fits the bitrate with the highest VMAF, weighted by the length of the scenes, which gives the bits to the
+  /// scenes where they raise the VMAF the most. The final pass encodes each chunk at constant q with --video-params, so
+  /// the rate control of the encoder must be set to a q/crf mode.
+  ///
+  /// The probes encode every frame at the full resolution, as their sizes predict the size of the encode, so
+  /// --probing-rate and --probe-res do not apply, but the other VMAF options of target quality do, e.g. --vmaf-path,
+  /// --vmaf-res, --probing-stat or --probe-slow. The probes run as many encodes at once as --workers, or one per core
+  /// by default. The bitrate is only met approximately, as the final parameters do not compress the same as the probes.
+  /// The q/crf of each chunk is saved in the temporary folder, so the probes are not repeated when resuming.
+  #[clap(
+    long,
+    conflicts_with_all = ["target_quality", "quick_consistency"],
+    help_heading = "Target Quality"
+  )]
+  pub allocate_bitrate: Option<f64>,
+
   /// Target a VMAF score for encoding (disabled by default)
   ///
   /// For each chunk, target quality uses an algorithm to find the quantizer/crf needed to achieve a certain VMAF score.
   /// Target quality mode is much slower than normal encoding, but can improve the consistency of quality in some cases.
   ///
   /// The VMAF score range is 0-100 (where 0 is the worst quality, and 100 is the best). Floating-point values are allowed.
+  ///
+  /// Probe results are saved in the temporary folder, and reused by later runs on the same input, either with --resume,
+  /// or with a different target if the temporary folder was kept.
+  ///
+  /// A SSIMULACRA2 target can be added to the VMAF target, e.g. vmaf=95,ssimu2=80, so that chunks whose problems VMAF
+  /// does not see (like banding) cannot pass. Each probe is then also scored by ssimulacra2_rs, which must be installed,
+  /// the Q is searched for each metric, and the lower Q, which meets both targets, is used. --probe-verify and
+  /// --verify-chunks only verify the VMAF target.
   #[clap(long, help_heading = "Target Quality")]
-  pub target_quality: Option<f64>,
+  pub target_quality: Option<QualityTargets>,
 
   /// Maximum number of probes allowed for target quality
   #[clap(long, default_value_t = 4, help_heading = "Target Quality")]
@@ -521,12 +1185,94 @@ pub struct CliOpts {
   #[clap(long, default_value_t = 1, help_heading = "Target Quality")]
   pub probing_rate: u32,
 
+  /// Statistic used to aggregate the VMAF scores of the frames of a probe
+  ///
+  /// Available statistics: mean, median, harmonic (harmonic mean), min, percentile=N (the N-th percentile, where N
+  /// is between 0 and 100). Lower percentiles (or min) make target quality more conservative, by targeting the
+  /// worst frames of each chunk rather than the average.
+  #[clap(long, default_value_t = ProbingStatistic::default(), help_heading = "Target Quality")]
+  pub probing_stat: ProbingStatistic,
+
+  /// Weight the VMAF scores of the frames of a probe by their importance
+  ///
+  /// skip=N ignores the first N frames of each chunk, after the keyframe it starts with, whose quality is rarely
+  /// representative of the rest of the chunk. motion=W weights frames by their motion: a frame of the mean motion of
+  /// the probe weighs 1+W, and a still frame 1. Both can be combined, e.g. skip=3,motion=1. The weights apply to
+  /// every --probing-stat.
+  #[clap(long, default_value_t = FrameWeighting::default(), help_heading = "Target Quality")]
+  pub probe_frame_weighting: FrameWeighting,
+
+  /// Resolution to downscale the probes to, e.g. 1920x1080
+  ///
+  /// Probes are encoded at this resolution, and their VMAF is calculated against the source downscaled to the same
+  /// resolution (instead of --vmaf-res). This greatly speeds up target quality for high resolution sources, at the
+  /// cost of some accuracy. The aspect ratio of the source is preserved.
+  #[clap(long, help_heading = "Target Quality")]
+  pub probe_res: Option<String>,
+
   /// Use encoding settings for probes specified by --video-params rather than faster, less accurate settings
   ///
   /// Note that this always performs encoding in one-pass mode, regardless of --passes.
   #[clap(long, help_heading = "Target Quality")]
   pub probe_slow: bool,
 
+  /// Number of workers dedicated to target quality probing
+  ///
+  /// When set, the probes of upcoming chunks run in these workers ahead of the encoders, so that
+  /// the Q-search of the next chunks overlaps with the encoding of the current ones. By default
+  /// (0), each encode worker probes its chunk right before encoding it.
+  #[clap(long, default_value_t = 0, help_heading = "Target Quality")]
+  pub probe_workers: usize,
+
+  /// Run the first two probes of a chunk in parallel when an encode worker is idle
+  ///
+  /// Instead of probing the middle of the Q range first to decide which end to probe next, min_q and max_q are
+  /// probed at the same time, which reduces the time spent searching the Q of each chunk. Workers only become idle
+  /// once there are no chunks left to encode, so this mostly speeds up the last chunks of an encode.
+  #[clap(long, help_heading = "Target Quality")]
+  pub probe_parallel: bool,
+
+  /// Start the Q-search of each chunk from the Q chosen for the previous chunk
+  ///
+  /// Adjacent chunks usually need a similar Q, so instead of probing the middle of the Q range first, the first
+  /// probe uses the Q of the previous (or next) chunk if its search has already finished, and the second probe steps
+  /// from there towards the target. This typically saves one probe per chunk.
+  #[clap(long, help_heading = "Target Quality")]
+  pub probe_warm_start: bool,
+
+  /// Verify the Q chosen by target quality with a full framerate probe
+  ///
+  /// After the Q-search converges, the chosen Q is probed again at the full framerate. If its VMAF score deviates
+  /// from the target by more than the given tolerance, Q is nudged towards the target. This costs one extra probe per
+  /// chunk, and is most useful with --probing-rate above 1.
+  #[clap(long, value_name = "TOLERANCE", help_heading = "Target Quality")]
+  pub probe_verify: Option<f64>,
+
+  /// Verify the VMAF of encoded chunks, and encode the chunks that miss the target again
+  ///
+  /// Each chunk whose Q was chosen by the Q-search is scored at the full framerate after it is encoded. If its score
+  /// deviates from the target by more than the given tolerance, Q is nudged towards the target and the chunk is
+  /// encoded again, once. Chunks that are likely to miss the target, because their score changes steeply around the
+  /// chosen Q or because they are complex, are verified first, as soon as they are encoded, so that they are encoded
+  /// again while other workers are still encoding. The remaining chunks are verified once all chunks are encoded.
+  /// Chunks encoded by a previous run are not verified.
+  #[clap(long, value_name = "TOLERANCE", help_heading = "Target Quality")]
+  pub verify_chunks: Option<f64>,
+
+  /// Limit how much the Q chosen for a chunk can differ from the Q of its neighbors (e.g. 4 for ±4)
+  ///
+  /// Reduces the visible quality pumping at scene boundaries when target quality chooses very different Qs for
+  /// similar content. The Q of a chunk is clamped to the range around the Qs of the previous and next chunks whose
+  /// search has already finished, so it is most effective with --chunk-order sequential. With --tq-rate bitrate, the
+  /// difference is in kbps.
+  #[clap(
+    long,
+    value_name = "MAX_DIFF",
+    requires = "target_quality",
+    help_heading = "Target Quality"
+  )]
+  pub tq_smoothing: Option<u32>,
+
   /// Lower bound for target quality Q-search early exit
   ///
   /// If min_q is tested and the probe's VMAF score is lower than target_quality, the Q-search early exits and
@@ -544,6 +1290,39 @@ pub struct CliOpts {
   /// If not specified, the default value is used (chosen per encoder).
   #[clap(long, help_heading = "Target Quality")]
   pub max_q: Option<u32>,
+
+  /// Rate control parameter that target quality searches for each chunk
+  ///
+  /// q: the q/crf of the constant quality mode of the encoder
+  ///
+  /// bitrate: the target bitrate of the VBR mode of the encoder, between --min-bitrate and
+  /// --max-bitrate, for when the encode has to be VBR, e.g. x264 with --bitrate. The constant
+  /// quality options in the video parameters are replaced by those of the VBR mode
+  /// (--end-usage=vbr for aomenc and vpxenc, --rc 1 for SVT-AV1).
+  #[clap(long, default_value_t = RateParam::Q, help_heading = "Target Quality")]
+  pub tq_rate: RateParam,
+
+  /// Lowest bitrate in kbps target quality searches, with --tq-rate bitrate, which replaces
+  /// --min-q and --max-q
+  #[clap(
+    long,
+    requires = "target_quality",
+    required_if_eq("tq_rate", "bitrate"),
+    conflicts_with_all = &["min_q", "max_q"],
+    help_heading = "Target Quality"
+  )]
+  pub min_bitrate: Option<u32>,
+
+  /// Highest bitrate in kbps target quality searches, with --tq-rate bitrate, which replaces
+  /// --min-q and --max-q
+  #[clap(
+    long,
+    requires = "target_quality",
+    required_if_eq("tq_rate", "bitrate"),
+    conflicts_with_all = &["min_q", "max_q"],
+    help_heading = "Target Quality"
+  )]
+  pub max_bitrate: Option<u32>,
 }
 
 impl CliOpts {
@@ -555,35 +1334,93 @@ impl CliOpts {
     output_pix_format: Pixel,
   ) -> Option<TargetQuality> {
     self.target_quality.map(|tq| {
-      let (min, max) = self.encoder.get_default_cq_range();
-      let min_q = self.min_q.unwrap_or(min as u32);
-      let max_q = self.max_q.unwrap_or(max as u32);
+      let probes = self.probe_params(temp_dir, video_params, output_pix_format);
+      let (min_q, max_q) = match self.tq_rate {
+        RateParam::Q => (probes.min_q, probes.max_q),
+        RateParam::Bitrate => (
+          self.min_bitrate.unwrap_or_default(),
+          self.max_bitrate.unwrap_or_default(),
+        ),
+      };
 
       TargetQuality {
-        vmaf_res: self.vmaf_res.clone(),
-        vmaf_scaler: self.scaler.clone(),
-        vmaf_filter: self.vmaf_filter.clone(),
-        vmaf_threads: self.vmaf_threads.unwrap_or_else(|| {
-          available_parallelism()
-            .expect("Unrecoverable: Failed to get thread count")
-            .get()
-        }),
-        model: self.vmaf_path.clone(),
-        probes: self.probes,
-        target: tq,
+        target: tq.vmaf,
+        ssimu2_target: tq.ssimu2,
+        rate: self.tq_rate,
         min_q,
         max_q,
-        encoder: self.encoder,
-        pix_format: output_pix_format,
-        temp: temp_dir.clone(),
-        workers: self.workers,
-        video_params: video_params.clone(),
-        vspipe_args: self.vspipe_args.clone(),
-        probe_slow: self.probe_slow,
-        probing_rate: adapt_probing_rate(self.probing_rate as usize),
+        ..probes
       }
     })
   }
+
+  /// Returns the settings of --allocate-bitrate, whose probes encode the full resolution, as their sizes predict the
+  /// size of the encode
+  pub fn bit_allocation_params(
+    &self,
+    temp_dir: String,
+    video_params: Vec<String>,
+    output_pix_format: Pixel,
+  ) -> Option<BitAllocation> {
+    self.allocate_bitrate.map(|kbps| BitAllocation {
+      kbps,
+      probes: TargetQuality {
+        probe_res: None,
+        ..self.probe_params(temp_dir, video_params, output_pix_format)
+      },
+    })
+  }
+
+  /// Returns the settings of the VMAF probes of the chunks, over the q range of --min-q and --max-q, without a target
+  fn probe_params(
+    &self,
+    temp_dir: String,
+    video_params: Vec<String>,
+    output_pix_format: Pixel,
+  ) -> TargetQuality {
+    let (min, max) = self.encoder.get_default_cq_range();
+    let (min_q, max_q) = (
+      self.min_q.unwrap_or(min as u32),
+      self.max_q.unwrap_or(max as u32),
+    );
+
+    TargetQuality {
+      vmaf_res: self.vmaf_res.clone(),
+      probe_res: self.probe_res.clone(),
+      vmaf_scaler: self.scaler.clone(),
+      vmaf_filter: self.vmaf_filter.clone(),
+      vmaf_threads: self.vmaf_threads.unwrap_or_else(|| {
+        available_parallelism()
+          .expect("Unrecoverable: Failed to get thread count")
+          .get()
+      }),
+      model: self.vmaf_path.clone(),
+      model_auto: self.vmaf_model_auto.clone(),
+      vmaf_neg: self.vmaf_neg,
+      probes: self.probes,
+      target: 0.0,
+      ssimu2_target: None,
+      rate: RateParam::Q,
+      min_q,
+      max_q,
+      encoder: self.encoder,
+      pix_format: output_pix_format,
+      temp: temp_dir,
+      workers: self.workers,
+      video_params,
+      vspipe_args: self.vspipe_args.clone(),
+      probe_slow: self.probe_slow,
+      probe_workers: self.probe_workers,
+      probe_parallel: self.probe_parallel,
+      probe_warm_start: self.probe_warm_start,
+      probe_verify: self.probe_verify,
+      verify_chunks: self.verify_chunks,
+      smoothing: self.tq_smoothing,
+      probing_statistic: self.probing_stat,
+      frame_weighting: self.probe_frame_weighting,
+      probing_rate: adapt_probing_rate(self.probing_rate as usize),
+    }
+  }
 }
 
 fn confirm(prompt: &str) -> io::Result<bool> {
@@ -593,7 +1430,14 @@ fn confirm(prompt: &str) -> io::Result<bool> {
   loop {
     stdout.write_all(prompt.as_bytes())?;
     stdout.flush()?;
-    stdin.read_line(&mut buf)?;
+    // e.g. in a container started without -i, where an empty answer would be taken as yes
+    if stdin.read_line(&mut buf)? == 0 {
+      println!();
+      return Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "stdin is closed, so the question cannot be answered, pass -y or -n instead",
+      ));
+    }
 
     match buf.as_str().trim() {
       // allows enter to continue
@@ -615,7 +1459,21 @@ pub(crate) fn resolve_file_paths(path: &Path) -> anyhow::Result<Box<dyn Iterator
   // TODO: to validate file extensions
   // let valid_media_extensions = ["mkv", "mov", "mp4", "webm", "avi", "qt", "ts", "m2t", "py", "vpy"];
 
-  ensure!(path.exists(), "Input path {:?} does not exist. Please ensure you typed it properly and it has not been moved.", path);
+  // the stream of stdin is read while it is encoded, and URLs are downloaded when encoding
+  if path.as_os_str() == "-" || path.to_str().is_some_and(remote::is_url) {
+    return Ok(Box::new(std::iter::once(path.to_path_buf())));
+  }
+
+  ensure!(
+    path.exists(),
+    "Input path {:?} does not exist. Please ensure you typed it properly and it has not been moved.{}",
+    path,
+    // Docker and Podman
+    missing_path_hint(
+      path,
+      Path::new("/.dockerenv").exists() || Path::new("/run/.containerenv").exists()
+    )
+  );
 
   if path.is_dir() {
     Ok(Box::new(read_in_dir(path)?))
@@ -624,9 +1482,94 @@ pub(crate) fn resolve_file_paths(path: &Path) -> anyhow::Result<Box<dyn Iterator
   }
 }
 
+/// Returns a hint for the error of an input path that does not exist, for the
+/// mistakes made when running in a container, which only sees the folders
+/// mounted into it with `-v`
+fn missing_path_hint(path: &Path, in_container: bool) -> String {
+  let text = path.to_string_lossy();
+  let bytes = text.as_bytes();
+  let windows_path = bytes.len() >= 3
+    && bytes[0].is_ascii_alphabetic()
+    && bytes[1] == b':'
+    && matches!(bytes[2], b'\\' | b'/');
+
+  if !cfg!(windows) && (windows_path || text.contains('\\')) {
+    let hint = "\n\nThis looks like a Windows path, which is not a path on this system.";
+    if in_container {
+      format!(
+        "{hint} In a container, pass the path of the file in the folder mounted with -v instead, e.g. \
+         -i {} with the folder of the file mounted with -v \"<folder>:/videos\".",
+        text.rsplit(['\\', '/']).next().unwrap_or(&text)
+      )
+    } else {
+      hint.to_owned()
+    }
+  } else if in_container {
+    "\n\nav1an runs in a container, which only sees the folders mounted with -v, e.g. -v \
+     \"$(pwd):/videos\" for the current folder, where relative paths are looked up."
+      .to_owned()
+  } else {
+    String::new()
+  }
+}
+
+/// Returns the output file of an input from --output-template
+fn output_from_template(
+  template: &str,
+  input: &Input,
+  encoder: Encoder,
+  target_quality: Option<f64>,
+  video_params: &[String],
+) -> anyhow::Result<String> {
+  let mut values = vec![
+    (
+      "stem",
+      input
+        .as_path()
+        .file_stem()
+        .unwrap_or_else(|| input.as_path().as_ref())
+        .to_string_lossy()
+        .into_owned(),
+    ),
+    ("encoder", encoder.to_string()),
+    (
+      "target_quality",
+      target_quality.map_or_else(|| "none".to_owned(), |target| target.to_string()),
+    ),
+    ("date", chrono::Local::now().format("%Y-%m-%d").to_string()),
+  ];
+
+  // probing the input is only worth it if its resolution is used
+  if ["{width}", "{height}", "{resolution}"]
+    .iter()
+    .any(|placeholder| template.contains(placeholder))
+  {
+    let (width, height) = input.resolution()?;
+    values.push(("width", width.to_string()));
+    values.push(("height", height.to_string()));
+    values.push(("resolution", format!("{width}x{height}")));
+  }
+  if template.contains("{q}") {
+    let q = if video_params.is_empty() {
+      encoder.get_q(&encoder.get_default_arguments(input.calculate_tiles()))
+    } else {
+      encoder.get_q(video_params)
+    };
+    values.push(("q", q.map_or_else(|| "none".to_owned(), |q| q.to_string())));
+  }
+
+  fill_template(template, &values).map_err(|e| anyhow!("Invalid --output-template: {e}"))
+}
+
 /// Returns vector of Encode args ready to be fed to encoder
 #[tracing::instrument]
-pub fn parse_cli(args: CliOpts) -> anyhow::Result<Vec<EncodeArgs>> {
+pub fn parse_cli(mut args: CliOpts) -> anyhow::Result<Vec<EncodeArgs>> {
+  // the lossless chunks are encoded in 1 pass, and handled as the chunks of their encoder
+  if let Some(intermediate) = args.intermediate {
+    args.encoder = intermediate.encoder();
+    args.passes = Some(1);
+  }
+
   let input_paths = &*args.input;
 
   let mut inputs = Vec::new();
@@ -644,6 +1587,10 @@ pub fn parse_cli(args: CliOpts) -> anyhow::Result<Vec<EncodeArgs>> {
     };
 
     let input = Input::from((input, args.vspipe_args.clone()));
+    ensure!(
+      !input.is_stdin() || args.output_file.is_some(),
+      "-o is required when the input is read from stdin with -i -"
+    );
 
     let video_params = if let Some(args) = args.video_params.as_ref() {
       shlex::split(args).ok_or_else(|| anyhow!("Failed to split video encoder arguments"))?
@@ -656,18 +1603,28 @@ pub fn parse_cli(args: CliOpts) -> anyhow::Result<Vec<EncodeArgs>> {
     };
 
     // TODO make an actual constructor for this
-    let arg = EncodeArgs {
+    let mut arg = EncodeArgs {
       log_file: if let Some(log_file) = args.log_file.as_ref() {
         Path::new(&format!("{log_file}.log")).to_owned()
       } else {
         Path::new(&temp).join("log.log")
       },
+      status_dir: args
+        .status_dir
+        .clone()
+        .unwrap_or_else(|| std::env::temp_dir().join("av1an")),
+      notify_url: args.notify_url.clone(),
+      notify_desktop: args.notify_desktop,
+      fps: args.fps,
+      fps_interpolate: args.fps_interpolate,
+      deinterlace: args.deinterlace,
       ffmpeg_filter_args: if let Some(args) = args.ffmpeg_filter_args.as_ref() {
         shlex::split(args).ok_or_else(|| anyhow!("Failed to split ffmpeg filter arguments"))?
       } else {
         Vec::new()
       },
       temp: temp.clone(),
+      temp_on: args.temp_on.clone(),
       force: args.force,
       passes: if let Some(passes) = args.passes {
         passes
@@ -675,6 +1632,9 @@ pub fn parse_cli(args: CliOpts) -> anyhow::Result<Vec<EncodeArgs>> {
         args.encoder.get_default_pass()
       },
       video_params: video_params.clone(),
+      auto_params: args.auto_params,
+      output_res: args.output_res,
+      dolby_vision: args.dolby_vision,
       output_file: if let Some(path) = args.output_file.as_ref() {
         let path = PathAbs::new(path)?;
 
@@ -686,28 +1646,64 @@ pub fn parse_cli(args: CliOpts) -> anyhow::Result<Vec<EncodeArgs>> {
 
         path.to_string_lossy().to_string()
       } else {
-        format!(
-          "{}_{}.mkv",
-          input
-            .as_path()
-            .file_stem()
-            .unwrap_or_else(|| input.as_path().as_ref())
-            .to_string_lossy(),
-          args.encoder
-        )
+        // the default template is a Matroska file, which --webm cannot output
+        let template = if args.webm && args.output_template == DEFAULT_OUTPUT_TEMPLATE {
+          DEFAULT_OUTPUT_TEMPLATE.replace(".mkv", ".webm")
+        } else {
+          args.output_template.clone()
+        };
+        let output = output_from_template(
+          &template,
+          &input,
+          args.encoder,
+          args.target_quality.map(|tq| tq.vmaf),
+          &video_params,
+        )?;
+        if let Some(parent) = Path::new(&output).parent() {
+          ensure!(
+            parent.as_os_str().is_empty() || parent.exists(),
+            "The folder of the output {output:?} does not exist"
+          );
+        }
+        output
       },
-      audio_params: if let Some(args) = args.audio_params.as_ref() {
-        shlex::split(args)
-          .ok_or_else(|| anyhow!("Failed to split ffmpeg audio encoder arguments"))?
-      } else {
-        into_vec!["-c:a", "copy"]
+      audio_params: {
+        let mut audio_params = if let Some(args) = args.audio_params.as_ref() {
+          shlex::split(args)
+            .ok_or_else(|| anyhow!("Failed to split ffmpeg audio encoder arguments"))?
+        } else if args.webm {
+          into_vec!["-c:a", "libopus", "-b:a", "128k"]
+        } else {
+          into_vec!["-c:a", "copy"]
+        };
+        if args.webm {
+          // WebM only supports WebVTT subtitles, and no attachments
+          audio_params.extend(into_vec!["-sn", "-map", "-0:t?"]);
+        }
+        audio_params
       },
+      no_audio: args.no_audio,
+      audio_only: args.audio_only.clone(),
       chunk_method: args
         .chunk_method
         .unwrap_or_else(vapoursynth::best_available_chunk_method),
+      bestsource_cachemode: args.bestsource_cachemode,
+      gpu_devices: parse_comma_separated_numbers(args.gpu_devices.as_deref().unwrap_or(""))?,
+      pipe_mode: args.pipe_mode,
       chunk_order: args.chunk_order,
       concat: args.concat,
+      webm: args.webm,
+      chunk_command: if let Some(command) = args.chunk_command.as_ref() {
+        shlex::split(command).ok_or_else(|| anyhow!("Failed to split chunk command"))?
+      } else {
+        Vec::new()
+      },
+      no_concat: args.no_concat,
+      progressive_concat: args.progressive_concat,
+      verify_output: args.verify_output,
       encoder: args.encoder,
+      intermediate: args.intermediate,
+      extra_splits_adaptive: args.extra_split_adaptive,
       extra_splits_len: match args.extra_split {
         Some(0) => None,
         Some(x) => Some(x),
@@ -723,11 +1719,35 @@ pub fn parse_cli(args: CliOpts) -> anyhow::Result<Vec<EncodeArgs>> {
       photon_noise_size: (args.photon_noise_width, args.photon_noise_height),
       chroma_noise: args.chroma_noise,
       sc_pix_format: args.sc_pix_format,
-      keep: args.keep,
+      keep: args.keep || args.remux,
+      keep_chunk_logs: args.keep_chunk_logs,
       max_tries: args.max_tries as usize,
-      min_scene_len: args.min_scene_len,
+      checkpoint_frames: args.checkpoint_frames.map(|frames| frames as usize),
+      min_scene_len: match args.min_scene_len_sec {
+        Some(sec) if !(sec.is_finite() && sec > 0.0) => {
+          bail!("--min-scene-len-sec must be a finite number greater than 0")
+        }
+        Some(sec) => match input.frame_rate() {
+          Ok(fps) => ((fps * sec).round() as usize).max(1),
+          Err(_) => {
+            warn!(
+              "failed to read the frame rate, using a minimum scene length of {} frames",
+              args.min_scene_len
+            );
+            args.min_scene_len
+          }
+        },
+        None => args.min_scene_len,
+      },
+      min_keyframe_interval: args.min_keyframe_interval,
       input_pix_format: {
         match &input {
+          // the format of a stream is read from its first segment, and of a URL once it is downloaded
+          Input::Video { .. } if input.is_stdin() || input.is_remote() => {
+            InputPixelFormat::FFmpeg {
+              format: args.pix_format,
+            }
+          }
           Input::Video { path } => InputPixelFormat::FFmpeg {
             format: ffmpeg::get_pixel_format(path.as_ref()).with_context(|| {
               format!("FFmpeg failed to get pixel format for input video {path:?}")
@@ -743,22 +1763,50 @@ pub fn parse_cli(args: CliOpts) -> anyhow::Result<Vec<EncodeArgs>> {
       },
       input,
       output_pix_format,
-      resume: args.resume,
+      resume: args.resume || args.remux,
+      remux: args.remux,
       scenes: args.scenes.clone(),
       split_method: args.split_method.clone(),
       sc_method: args.sc_method,
+      sc_backend: args.sc_backend,
       sc_only: args.sc_only,
+      benchmark: args.benchmark,
+      preview_chunks: args
+        .preview_chunks
+        .as_deref()
+        .map(parse_comma_separated_numbers)
+        .transpose()?,
+      super_chunks: args.super_chunks,
+      max_temp_size: args.max_temp_size,
+      ignore_disk_space: args.ignore_disk_space,
+      min_free_space: args.min_free_space,
       sc_downscale_height: args.sc_downscale_height,
+      sc_proxy: args
+        .sc_proxy
+        .as_ref()
+        .map(|proxy| Input::from((proxy.clone(), Vec::new()))),
       force_keyframes: parse_comma_separated_numbers(
         args.force_keyframes.as_deref().unwrap_or(""),
       )?,
+      force_keyframes_from_chapters: args.force_keyframes_from_chapters,
+      quick_consistency: args.quick_consistency,
+      allocate_bitrate: args.bit_allocation_params(
+        temp.clone(),
+        video_params.clone(),
+        output_pix_format.format,
+      ),
+      max_chunk_bitrate: args.max_chunk_bitrate,
       target_quality: args.target_quality_params(temp, video_params, output_pix_format.format),
       vmaf: args.vmaf,
+      quality_report: args.quality_report,
       vmaf_path: args.vmaf_path.clone(),
+      vmaf_model_auto: args.vmaf_model_auto.clone(),
+      vmaf_neg: args.vmaf_neg,
       vmaf_res: args.vmaf_res.clone(),
       vmaf_threads: args.vmaf_threads,
       vmaf_filter: args.vmaf_filter.clone(),
-      verbosity: if args.quiet {
+      // the dashboard replaces the progress bars
+      verbosity: if args.quiet || args.tui {
         Verbosity::Quiet
       } else if args.verbose {
         Verbosity::Verbose
@@ -767,6 +1815,13 @@ pub fn parse_cli(args: CliOpts) -> anyhow::Result<Vec<EncodeArgs>> {
       },
       workers: args.workers,
       set_thread_affinity: args.set_thread_affinity,
+      priority: args.priority,
+      io_priority: args.io_priority,
+      cgroup: args.cgroup,
+      max_memory: args.max_memory,
+      schedule: args.schedule.clone(),
+      power_draw: args.power_draw,
+      energy_price: args.energy_price,
       zones: args.zones.clone(),
       scaler: {
         let mut scaler = args.scaler.to_string().clone();
@@ -785,26 +1840,49 @@ pub fn parse_cli(args: CliOpts) -> anyhow::Result<Vec<EncodeArgs>> {
       ignore_frame_mismatch: args.ignore_frame_mismatch,
     };
 
-    if !args.overwrite {
-      // UGLY: taking first file for output file
-      if let Some(path) = args.output_file.as_ref() {
-        if path.exists()
-          && (args.never_overwrite
-            || !confirm(&format!(
-              "Output file {path:?} exists. Do you want to overwrite it? [Y/n]: "
-            ))?)
-        {
-          println!("Not overwriting, aborting.");
-          exit(0);
-        }
-      } else {
-        let path: &Path = arg.output_file.as_ref();
+    // the lossless intermediates keep the pixel format of the source
+    if let (Some(intermediate), InputPixelFormat::FFmpeg { format }) =
+      (args.intermediate, arg.input_pix_format)
+    {
+      arg.output_pix_format = intermediate.pixel_format(format)?;
+    }
+
+    if let Some(path) = &args.from_settings {
+      arg = sidecar::replay(sidecar::read(path)?.args, arg);
+    }
 
-        if path.exists()
-          && (args.never_overwrite
-            || !confirm(&format!(
-              "Default output file {path:?} exists. Do you want to overwrite it? [Y/n]: "
-            ))?)
+    // before asking to overwrite the output, which may be the input
+    arg.validate_paths()?;
+
+    // the answer to the prompt would be read from the stream of the input
+    if arg.input.is_stdin()
+      && !args.overwrite
+      && !args.never_overwrite
+      && Path::new(&arg.output_file).exists()
+    {
+      bail!(
+        "Output file {} exists, pass -y to overwrite it, as the input is read from stdin",
+        arg.output_file
+      );
+    }
+
+    // nothing is written when only checking the zones
+    if !args.overwrite && !args.validate_zones {
+      // UGLY: taking first file for output file
+      let (path, name) = args.output_file.as_ref().map_or_else(
+        || (Path::new(&arg.output_file), "Default output file"),
+        |path| (path.as_path(), "Output file"),
+      );
+      if path.exists() {
+        ensure!(
+          args.never_overwrite || !args.non_interactive,
+          "{name} {path:?} exists, pass -y to overwrite it or -n to keep it, as \
+           --non-interactive does not ask"
+        );
+        if args.never_overwrite
+          || !confirm(&format!(
+            "{name} {path:?} exists. Do you want to overwrite it? [Y/n]: "
+          ))?
         {
           println!("Not overwriting, aborting.");
           exit(0);
@@ -876,14 +1954,74 @@ impl LogWriter for StderrLogger {
 
 #[instrument]
 pub fn run() -> anyhow::Result<()> {
+  let (cli, config) = config::parse(std::env::args_os().collect())?;
+  let cli_args = match cli.command {
+    None => cli.encode,
+    Some(Subcommand::Encode(encode)) => encode,
+    Some(Subcommand::ScDetect(mut encode)) => {
+      // as --sc-only requires it
+      ensure!(
+        encode.scenes.is_some(),
+        "sc-detect requires --scenes, the file the scenes are written to"
+      );
+      encode.sc_only = true;
+      encode
+    }
+    Some(Subcommand::Benchmark { chunks, mut encode }) => {
+      encode.benchmark = Some(chunks);
+      encode
+    }
+    Some(Subcommand::Resume(mut encode)) => {
+      encode.resume = true;
+      encode
+    }
+    Some(Subcommand::Concat(args)) => {
+      init_logging();
+      return concat::run(args);
+    }
+    Some(Subcommand::Probe(args)) => return probe::run(args),
+    Some(Subcommand::Doctor) => return doctor::run(version()),
+    Some(Subcommand::Models(args)) => return models::run(args),
+  };
+
   init_logging();
+  for line in &config {
+    info!("config: {}", line);
+  }
 
-  let cli_args = CliOpts::parse();
+  if let Some(path) = &cli_args.events_file {
+    init_events(path).with_context(|| format!("Failed to open {}", path.display()))?;
+  }
 
   //let log_level = cli_args.log_level;
-  let args = parse_cli(cli_args)?;
-
-  for arg in args {
+  let validate_zones = cli_args.validate_zones;
+  let continue_on_error = cli_args.continue_on_error;
+  let tui = cli_args.tui;
+  let mut args = parse_cli(*cli_args)?;
+
+  // the cgroup is created by the first encode, and removed even if one fails
+  let _cgroup = cgroup::RemoveGuard;
+  // restores the terminal when dropped, before the error of the encode is printed if it fails
+  let _tui = if tui && !validate_zones {
+    if io::stdout().is_terminal() {
+      Some(Tui::start())
+    } else {
+      warn!("--tui is ignored as the output is not a terminal");
+      None
+    }
+  } else {
+    None
+  };
+
+  if validate_zones {
+    for arg in args {
+      let zones_file = arg.zones.clone().unwrap_or_default();
+      let zones = Av1anContext::check_zones(arg)?;
+      println!("{}: {} valid zones", zones_file.display(), zones.len());
+    }
+  } else if args.len() > 1 {
+    batch::run(args, continue_on_error)?;
+  } else if let Some(arg) = args.pop() {
     Av1anContext::new(arg)?.encode_file()?;
   }
 
@@ -903,3 +2041,20 @@ fn parse_comma_separated_numbers(string: &str) -> anyhow::Result<Vec<usize>> {
   }
   Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn missing_path_hints() {
+    assert_eq!(missing_path_hint(Path::new("video.mkv"), false), "");
+    assert!(missing_path_hint(Path::new("video.mkv"), true).contains("-v"));
+    if !cfg!(windows) {
+      let hint = missing_path_hint(Path::new("C:\\Users\\me\\video.mkv"), true);
+      assert!(hint.contains("Windows path"));
+      assert!(hint.contains("-i video.mkv"));
+      assert!(missing_path_hint(Path::new("D:/video.mkv"), false).contains("Windows path"));
+    }
+  }
+}
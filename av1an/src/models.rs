@@ -0,0 +1,185 @@
+//! `av1an models`: downloads VMAF models into a cache folder.
+//!
+//! The models of Netflix's VMAF repository are downloaded into `av1an/models`
+//! of the cache folder of the user (e.g. `~/.cache/av1an/models` on Linux), or
+//! into `AV1AN_MODELS_DIR` if it is set, e.g. to a volume of a Docker
+//! container. `--vmaf-path` then takes the name of a model in this folder, such
+//! as `vmaf_4k`, in place of a path.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context};
+
+/// Environment variable overriding the folder of the downloaded models
+const MODELS_DIR_ENV: &str = "AV1AN_MODELS_DIR";
+
+/// Folder of the models in the VMAF repository
+const MODELS_URL: &str = "https://raw.githubusercontent.com/Netflix/vmaf/master/model";
+
+/// Models that can be downloaded, by name and file name
+const MODELS: [(&str, &str); 3] = [
+  ("vmaf", "vmaf_v0.6.1.json"),
+  ("vmaf_4k", "vmaf_4k_v0.6.1.json"),
+  ("vmaf_neg", "vmaf_v0.6.1neg.json"),
+];
+
+/// Download and list the VMAF models that --vmaf-path accepts by name
+#[derive(clap::Args, Debug)]
+pub struct ModelsArgs {
+  #[clap(subcommand)]
+  action: Action,
+
+  /// Folder of the models, by default `av1an/models` of the cache folder of the user
+  #[clap(long, global = true, env = MODELS_DIR_ENV)]
+  dir: Option<PathBuf>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Action {
+  /// Download models (all of them by default) with curl
+  Fetch {
+    /// Names of the models: vmaf, vmaf_4k or vmaf_neg
+    #[clap(value_parser = model_name)]
+    models: Vec<String>,
+
+    /// Download the models again even if they were already downloaded
+    #[clap(long)]
+    force: bool,
+  },
+  /// List the models and whether they were downloaded
+  List,
+}
+
+fn model_name(name: &str) -> Result<String, String> {
+  if MODELS.iter().any(|&(model, _)| model == name) {
+    Ok(name.to_owned())
+  } else {
+    Err(format!(
+      "unknown model, expected one of {}",
+      MODELS.map(|(model, _)| model).join(", ")
+    ))
+  }
+}
+
+/// Returns the folder of the downloaded models
+fn models_dir() -> Option<PathBuf> {
+  std::env::var_os(MODELS_DIR_ENV)
+    .filter(|dir| !dir.is_empty())
+    .map(PathBuf::from)
+    .or_else(|| dirs::cache_dir().map(|dir| dir.join("av1an").join("models")))
+}
+
+/// Returns the file of a model in `dir`
+fn model_path(dir: &Path, name: &str) -> Option<PathBuf> {
+  MODELS
+    .iter()
+    .find(|&&(model, _)| model == name)
+    .map(|(_, file)| dir.join(file))
+}
+
+/// Resolves the value of `--vmaf-path`, which is a path to a model file, or
+/// the name of a model downloaded by `av1an models fetch`
+pub fn resolve_vmaf_path(value: &str) -> Result<PathBuf, String> {
+  let path = PathBuf::from(value);
+  if path.exists() {
+    return Ok(path);
+  }
+  let Some(model) = models_dir().and_then(|dir| model_path(&dir, value)) else {
+    return Ok(path);
+  };
+  if !model.exists() {
+    return Err(format!(
+      "the {value} model is not downloaded yet, run `av1an models fetch {value}`"
+    ));
+  }
+  Ok(model)
+}
+
+/// Downloads a model to `path`, through a temporary file so that an
+/// interrupted download is not mistaken for a model
+fn download(file: &str, path: &Path) -> anyhow::Result<()> {
+  let partial = path.with_extension("json.part");
+  let url = format!("{MODELS_URL}/{file}");
+  let status = Command::new("curl")
+    .args(["--fail", "--location", "--silent", "--show-error"])
+    .args(["--retry", "3", "--output"])
+    .arg(&partial)
+    .arg(&url)
+    .status()
+    .context("Failed to run curl, which downloads the models")?;
+  if !status.success() {
+    let _ = fs::remove_file(&partial);
+    bail!("Failed to download {url} ({status})");
+  }
+  fs::rename(&partial, path)
+    .with_context(|| format!("Failed to move the model to {}", path.display()))
+}
+
+pub fn run(args: ModelsArgs) -> anyhow::Result<()> {
+  let Some(dir) = args.dir.or_else(models_dir) else {
+    bail!("No cache folder was found for the models, set {MODELS_DIR_ENV}");
+  };
+
+  match args.action {
+    Action::Fetch { models, force } => {
+      fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+      for (name, file) in MODELS {
+        if !models.is_empty() && !models.iter().any(|model| model == name) {
+          continue;
+        }
+        let path = dir.join(file);
+        if path.exists() && !force {
+          println!("{name:<9} already downloaded to {}", path.display());
+          continue;
+        }
+        download(file, &path)?;
+        println!("{name:<9} downloaded to {}", path.display());
+      }
+    }
+    Action::List => {
+      println!("models folder: {}", dir.display());
+      for (name, file) in MODELS {
+        let state = if dir.join(file).exists() {
+          "downloaded"
+        } else {
+          "not downloaded"
+        };
+        println!("  {name:<9} {file:<20} {state}");
+      }
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use clap::Parser;
+
+  use super::*;
+
+  /// The subcommand on its own, as the command line of av1an probes VapourSynth
+  #[derive(Parser)]
+  struct Models {
+    #[clap(flatten)]
+    args: ModelsArgs,
+  }
+
+  #[test]
+  fn model_names() {
+    let args = Models::try_parse_from(["models", "fetch", "vmaf_4k", "vmaf_neg"])
+      .unwrap()
+      .args;
+    assert!(
+      matches!(args.action, Action::Fetch { models, force: false } if models == ["vmaf_4k", "vmaf_neg"])
+    );
+    assert!(Models::try_parse_from(["models", "fetch", "vmaf_hd"]).is_err());
+
+    assert_eq!(
+      model_path(Path::new("models"), "vmaf_4k"),
+      Some(Path::new("models").join("vmaf_4k_v0.6.1.json"))
+    );
+    assert_eq!(model_path(Path::new("models"), "model.json"), None);
+  }
+}
@@ -0,0 +1,65 @@
+//! `av1an probe`: prints the properties of a video that av1an reads from it.
+
+use std::path::PathBuf;
+
+use anyhow::Context;
+use av1an_core::ffmpeg;
+
+/// Print the properties of a video that av1an uses to encode it
+#[derive(clap::Args, Debug)]
+pub struct ProbeArgs {
+  /// Video to probe
+  input: PathBuf,
+
+  /// Also find the keyframes of the video, which reads all of its packets
+  #[clap(long)]
+  keyframes: bool,
+}
+
+pub fn run(args: ProbeArgs) -> anyhow::Result<()> {
+  let input = args.input.as_path();
+  ::ffmpeg::init()?;
+
+  let (width, height) = ffmpeg::resolution(input)
+    .with_context(|| format!("Failed to read the video stream of {}", input.display()))?;
+  println!("resolution:      {width}x{height}");
+  println!("pixel format:    {:?}", ffmpeg::get_pixel_format(input)?);
+  println!("frame rate:      {:.3}", ffmpeg::frame_rate(input)?);
+  println!("frames:          {}", ffmpeg::num_frames(input)?);
+  println!(
+    "transfer:        {:?}",
+    ffmpeg::transfer_characteristics(input)?
+  );
+  println!(
+    "audio:           {}",
+    if ffmpeg::has_audio(input) {
+      "yes"
+    } else {
+      "no"
+    }
+  );
+  let chapters = ffmpeg::chapter_frames(input)?;
+  if !chapters.is_empty() {
+    println!(
+      "chapters:        {}",
+      chapters
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+    );
+  }
+  if args.keyframes {
+    let keyframes = ffmpeg::get_keyframes(input)?;
+    println!(
+      "keyframes:       {}",
+      keyframes
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+    );
+  }
+
+  Ok(())
+}
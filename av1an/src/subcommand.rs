@@ -0,0 +1,112 @@
+//! Subcommands of av1an, which are the first argument.
+//!
+//! Without a subcommand, the arguments are those of `encode`, so that command
+//! lines of earlier versions keep working. `sc-detect`, `benchmark` and
+//! `resume` take the options of an encode as well, and only select the mode of
+//! the encode, like `--sc-only`, `--benchmark` and `--resume`. The other
+//! subcommands are utilities with options of their own.
+
+use clap::Parser;
+
+use crate::{concat, models, probe, version, CliOpts};
+
+/// Chunks encoded by `av1an benchmark` when their number is not given
+const DEFAULT_BENCHMARK_CHUNKS: usize = 10;
+
+/// Cross-platform command-line AV1 / VP9 / HEVC / H264 encoding framework with per-scene quality encoding
+#[derive(Parser, Debug)]
+#[clap(
+  name = "av1an",
+  version = version(),
+  args_conflicts_with_subcommands = true,
+  subcommand_negates_reqs = true
+)]
+pub struct Cli {
+  #[clap(subcommand)]
+  pub command: Option<Subcommand>,
+
+  #[clap(flatten)]
+  pub encode: Box<CliOpts>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum Subcommand {
+  /// Encode the input (the default without a subcommand)
+  Encode(Box<CliOpts>),
+  /// Detect the scenes of the input into --scenes only
+  ScDetect(Box<CliOpts>),
+  /// Concatenate the chunks of an encode again
+  Concat(concat::ConcatArgs),
+  /// Print the properties of a video
+  Probe(probe::ProbeArgs),
+  /// Check the dependencies of av1an
+  Doctor,
+  /// Download the VMAF models that --vmaf-path accepts by name
+  Models(models::ModelsArgs),
+  /// Encode [CHUNKS] chunks (10 by default) and project the whole encode
+  Benchmark {
+    #[clap(default_value_t = DEFAULT_BENCHMARK_CHUNKS)]
+    chunks: usize,
+
+    #[clap(flatten)]
+    encode: Box<CliOpts>,
+  },
+  /// Resume the encode of the temporary folder
+  Resume(Box<CliOpts>),
+}
+
+impl Subcommand {
+  /// Names of the subcommands that take the options of an encode
+  pub const ENCODES: [&'static str; 4] = ["encode", "sc-detect", "benchmark", "resume"];
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+
+  use super::*;
+
+  /// The subcommands without the version of av1an, which probes VapourSynth
+  #[derive(Parser)]
+  #[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+  struct Args {
+    #[clap(subcommand)]
+    command: Option<Subcommand>,
+
+    #[clap(flatten)]
+    encode: Box<CliOpts>,
+  }
+
+  #[test]
+  fn subcommand_arguments() {
+    let parse = |args: &[&str]| Args::try_parse_from(args).unwrap();
+
+    let args = parse(&["av1an", "-i", "in.mkv"]);
+    assert!(args.command.is_none());
+    assert_eq!(args.encode.input, [PathBuf::from("in.mkv")]);
+    assert!(matches!(
+      parse(&["av1an", "encode", "-i", "in.mkv"]).command,
+      Some(Subcommand::Encode(encode)) if encode.input == [PathBuf::from("in.mkv")]
+    ));
+    assert!(matches!(
+      parse(&["av1an", "benchmark", "4", "-i", "in.mkv"]).command,
+      Some(Subcommand::Benchmark { chunks: 4, .. })
+    ));
+    assert!(matches!(
+      parse(&["av1an", "benchmark", "-i", "in.mkv"]).command,
+      Some(Subcommand::Benchmark { chunks: 10, .. })
+    ));
+    assert!(matches!(
+      parse(&["av1an", "concat", "--temp", "t"]).command,
+      Some(Subcommand::Concat(_))
+    ));
+    assert!(matches!(
+      parse(&["av1an", "models", "fetch", "vmaf_4k"]).command,
+      Some(Subcommand::Models(_))
+    ));
+    // an input named like a subcommand is only one after -i
+    assert!(parse(&["av1an", "-i", "probe"]).command.is_none());
+    // the options of an encode are not taken before a subcommand
+    assert!(Args::try_parse_from(["av1an", "-i", "in.mkv", "doctor"]).is_err());
+  }
+}
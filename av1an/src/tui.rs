@@ -0,0 +1,466 @@
+//! Terminal dashboard of the encode, enabled with `--tui`.
+//!
+//! The dashboard replaces the progress bars, and is drawn from the events the
+//! encode publishes (see `av1an_core::logging::Event`): the progress of every
+//! worker, the recently finished chunks, the memory usage of the system and
+//! the log. Pressing q or Ctrl+C interrupts the encode, which can be resumed
+//! with --resume.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use av1an_core::logging::{self, Event};
+use av1an_core::process_group;
+use av1an_core::util::format_size;
+use crossbeam_channel::Receiver;
+use ratatui::crossterm::event::{self as term_event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Gauge, List, ListItem, Paragraph, Row, Table};
+use ratatui::Frame;
+use sysinfo::System;
+
+/// Number of finished chunks and of log lines that are kept
+const HISTORY: usize = 100;
+/// Interval between redraws, and between checks for key presses
+const TICK: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone)]
+struct Worker {
+  chunk: usize,
+  frames: usize,
+  encoded: u64,
+  started: Instant,
+}
+
+#[derive(Debug, Clone)]
+struct FinishedChunk {
+  chunk: usize,
+  frames: usize,
+  fps: f64,
+  size_bytes: u64,
+}
+
+/// State of the dashboard, updated from the events of the encode
+#[derive(Debug)]
+struct Dashboard {
+  input: String,
+  total_frames: usize,
+  /// Frames encoded by a previous run, which do not count towards the fps
+  resumed_frames: usize,
+  /// Frames of the chunks finished by this run
+  finished_frames: usize,
+  workers: Vec<Option<Worker>>,
+  finished: VecDeque<FinishedChunk>,
+  log: VecDeque<String>,
+  started: Option<Instant>,
+  used_memory: u64,
+  total_memory: u64,
+}
+
+impl Dashboard {
+  const fn new() -> Self {
+    Self {
+      input: String::new(),
+      total_frames: 0,
+      resumed_frames: 0,
+      finished_frames: 0,
+      workers: Vec::new(),
+      finished: VecDeque::new(),
+      log: VecDeque::new(),
+      started: None,
+      used_memory: 0,
+      total_memory: 0,
+    }
+  }
+
+  fn push_log(&mut self, line: String) {
+    if self.log.len() == HISTORY {
+      self.log.pop_front();
+    }
+    self.log.push_back(line);
+  }
+
+  fn worker(&mut self, index: usize) -> &mut Option<Worker> {
+    if self.workers.len() <= index {
+      self.workers.resize(index + 1, None);
+    }
+    &mut self.workers[index]
+  }
+
+  fn apply(&mut self, event: Event) {
+    match event {
+      Event::EncodeStarted {
+        input,
+        frames,
+        done_frames,
+        workers,
+        ..
+      } => {
+        *self = Self {
+          input: input.display().to_string(),
+          total_frames: frames,
+          resumed_frames: done_frames,
+          workers: vec![None; workers],
+          // the log of the previous inputs of a batch is kept
+          log: std::mem::take(&mut self.log),
+          started: Some(Instant::now()),
+          ..Self::new()
+        };
+      }
+      Event::ChunkStarted {
+        chunk,
+        frames,
+        worker,
+      } => {
+        *self.worker(worker) = Some(Worker {
+          chunk,
+          frames,
+          encoded: 0,
+          started: Instant::now(),
+        });
+      }
+      Event::ChunkProgress {
+        chunk,
+        worker,
+        frames,
+      } => {
+        if let Some(state) = self
+          .worker(worker)
+          .as_mut()
+          .filter(|state| state.chunk == chunk)
+        {
+          state.encoded = frames;
+        }
+      }
+      Event::ChunkFinished {
+        chunk,
+        frames,
+        worker,
+        fps,
+        size_bytes,
+        ..
+      } => {
+        *self.worker(worker) = None;
+        self.finished_frames += frames;
+        if self.finished.len() == HISTORY {
+          self.finished.pop_back();
+        }
+        self.finished.push_front(FinishedChunk {
+          chunk,
+          frames,
+          fps,
+          size_bytes,
+        });
+      }
+      Event::WorkerCrash {
+        chunk,
+        worker,
+        attempt,
+        will_retry,
+        exit_status,
+        failure,
+        ..
+      } => {
+        *self.worker(worker) = None;
+        self.push_log(format!(
+          "ERROR chunk {chunk} crashed on attempt {attempt} ({exit_status}, {failure}){}",
+          if will_retry { ", retrying" } else { "" }
+        ));
+      }
+      Event::TargetQuality { chunk, q } => {
+        self.push_log(format!("INFO chunk {chunk}: target quality chose Q {q}"));
+      }
+      Event::EncodeFinished { output, seconds } => {
+        self.push_log(format!(
+          "INFO encoded {} in {seconds:.0}s",
+          output.display()
+        ));
+      }
+      Event::Log { level, message } => self.push_log(format!("{level} {message}")),
+      Event::Probe { .. } => {}
+    }
+  }
+
+  /// Frames encoded by this run, including the unfinished chunks
+  fn encoded_frames(&self) -> u64 {
+    self.finished_frames as u64
+      + self
+        .workers
+        .iter()
+        .flatten()
+        .map(|worker| worker.encoded)
+        .sum::<u64>()
+  }
+
+  /// Returns the fps of this run and the estimated seconds until the end
+  fn speed(&self) -> Option<(f64, f64)> {
+    let elapsed = self.started?.elapsed().as_secs_f64();
+    let encoded = self.encoded_frames() as f64;
+    if encoded == 0.0 || elapsed == 0.0 {
+      return None;
+    }
+    let fps = encoded / elapsed;
+    let remaining = self
+      .total_frames
+      .saturating_sub(self.resumed_frames)
+      .saturating_sub(self.encoded_frames() as usize);
+    Some((fps, remaining as f64 / fps))
+  }
+
+  fn draw(&self, frame: &mut Frame) {
+    let [progress_area, middle_area, log_area] = Layout::vertical([
+      Constraint::Length(3),
+      Constraint::Min(6),
+      Constraint::Percentage(35),
+    ])
+    .areas(frame.area());
+    let [workers_area, side_area] =
+      Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+        .areas(middle_area);
+    let [memory_area, finished_area] =
+      Layout::vertical([Constraint::Length(3), Constraint::Min(3)]).areas(side_area);
+
+    let done = self.resumed_frames as u64 + self.encoded_frames();
+    let ratio = if self.total_frames == 0 {
+      0.0
+    } else {
+      (done as f64 / self.total_frames as f64).min(1.0)
+    };
+    let speed = self.speed().map_or_else(
+      || "waiting for the first frames".to_owned(),
+      |(fps, eta)| format!("{fps:.2} fps, eta {}", format_duration(eta)),
+    );
+    frame.render_widget(
+      Gauge::default()
+        .block(Block::bordered().title(format!(" {} ", self.input)))
+        .gauge_style(Style::default().fg(Color::Green))
+        .ratio(ratio)
+        .label(format!("{done}/{} frames, {speed}", self.total_frames)),
+      progress_area,
+    );
+
+    let rows = self.workers.iter().enumerate().map(|(index, worker)| {
+      worker.as_ref().map_or_else(
+        || Row::new(vec![index.to_string(), "idle".to_owned()]),
+        |worker| {
+          let elapsed = worker.started.elapsed().as_secs_f64();
+          Row::new(vec![
+            index.to_string(),
+            format!("{:05}", worker.chunk),
+            format!("{}/{}", worker.encoded, worker.frames),
+            format!("{:.2}", worker.encoded as f64 / elapsed.max(f64::EPSILON)),
+            format_duration(elapsed),
+          ])
+        },
+      )
+    });
+    frame.render_widget(
+      Table::new(rows, [Constraint::Fill(1); 5])
+        .header(Row::new(["Worker", "Chunk", "Frames", "fps", "Time"]).bold())
+        .block(Block::bordered().title(" Workers ")),
+      workers_area,
+    );
+
+    frame.render_widget(
+      Paragraph::new(format!(
+        "{} / {} used",
+        format_size(self.used_memory),
+        format_size(self.total_memory)
+      ))
+      .block(Block::bordered().title(" Memory ")),
+      memory_area,
+    );
+
+    let finished: Vec<_> = self
+      .finished
+      .iter()
+      .map(|chunk| {
+        ListItem::new(format!(
+          "{:05}: {} frames, {}, {:.2} fps",
+          chunk.chunk,
+          chunk.frames,
+          format_size(chunk.size_bytes),
+          chunk.fps
+        ))
+      })
+      .collect();
+    frame.render_widget(
+      List::new(finished).block(Block::bordered().title(" Finished chunks ")),
+      finished_area,
+    );
+
+    // the last lines that fit, without the borders
+    let visible = usize::from(log_area.height.saturating_sub(2));
+    let log: Vec<_> = self
+      .log
+      .iter()
+      .skip(self.log.len().saturating_sub(visible))
+      .map(|line| {
+        let style = if line.starts_with("ERROR") {
+          Style::default().fg(Color::Red)
+        } else if line.starts_with("WARN") {
+          Style::default().fg(Color::Yellow)
+        } else {
+          Style::default()
+        };
+        Line::styled(line.as_str(), style)
+      })
+      .collect();
+    frame.render_widget(
+      Paragraph::new(log).block(Block::bordered().title(" Log (q to interrupt) ")),
+      log_area,
+    );
+  }
+}
+
+fn format_duration(seconds: f64) -> String {
+  let seconds = seconds as u64;
+  format!(
+    "{}:{:02}:{:02}",
+    seconds / 3600,
+    seconds / 60 % 60,
+    seconds % 60
+  )
+}
+
+/// Interrupts av1an and the encoders like Ctrl+C outside of the dashboard,
+/// once the terminal is restored
+fn interrupt() -> ! {
+  ratatui::restore();
+  logging::mute_console(false);
+  process_group::interrupt_all();
+  std::process::exit(130);
+}
+
+fn run(events: &Receiver<Event>, stop: &AtomicBool) -> std::io::Result<()> {
+  let mut terminal = ratatui::init();
+  let mut dashboard = Dashboard::new();
+  let mut system = System::new();
+  let mut memory_refreshed: Option<Instant> = None;
+
+  while !stop.load(Ordering::Relaxed) {
+    for event in events.try_iter() {
+      dashboard.apply(event);
+    }
+    if memory_refreshed.map_or(true, |refreshed| {
+      refreshed.elapsed() >= Duration::from_secs(1)
+    }) {
+      system.refresh_memory();
+      dashboard.used_memory = system.used_memory();
+      dashboard.total_memory = system.total_memory();
+      memory_refreshed = Some(Instant::now());
+    }
+    terminal.draw(|frame| dashboard.draw(frame))?;
+
+    if term_event::poll(TICK)? {
+      if let term_event::Event::Key(key) = term_event::read()? {
+        let ctrl_c =
+          key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+        if key.kind == KeyEventKind::Press && (ctrl_c || key.code == KeyCode::Char('q')) {
+          interrupt();
+        }
+      }
+    }
+  }
+
+  Ok(())
+}
+
+/// The dashboard, drawn by a thread until it is dropped
+pub struct Tui {
+  stop: Arc<AtomicBool>,
+  handle: Option<JoinHandle<()>>,
+}
+
+impl Tui {
+  /// Shows the dashboard, muting the log of the console meanwhile
+  pub fn start() -> Self {
+    let events = logging::subscribe();
+    logging::mute_console(true);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let handle = thread::spawn({
+      let stop = Arc::clone(&stop);
+      move || {
+        if let Err(e) = run(&events, &stop) {
+          ratatui::restore();
+          logging::mute_console(false);
+          tracing::error!("the dashboard failed: {}", e);
+        }
+      }
+    });
+
+    Self {
+      stop,
+      handle: Some(handle),
+    }
+  }
+}
+
+impl Drop for Tui {
+  fn drop(&mut self) {
+    self.stop.store(true, Ordering::Relaxed);
+    if let Some(handle) = self.handle.take() {
+      handle.join().ok();
+    }
+    ratatui::restore();
+    logging::mute_console(false);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+
+  use super::*;
+
+  #[test]
+  fn dashboard_events() {
+    let mut dashboard = Dashboard::new();
+    dashboard.apply(Event::EncodeStarted {
+      input: PathBuf::from("input.mkv"),
+      output: PathBuf::from("output.mkv"),
+      frames: 1000,
+      done_frames: 200,
+      chunks: 10,
+      workers: 2,
+    });
+    dashboard.apply(Event::ChunkStarted {
+      chunk: 3,
+      frames: 100,
+      worker: 1,
+    });
+    dashboard.apply(Event::ChunkProgress {
+      chunk: 3,
+      worker: 1,
+      frames: 40,
+    });
+    assert_eq!(dashboard.encoded_frames(), 40);
+
+    dashboard.apply(Event::ChunkFinished {
+      chunk: 3,
+      frames: 100,
+      worker: 1,
+      seconds: 2.0,
+      fps: 50.0,
+      size_bytes: 4096,
+    });
+    assert_eq!(dashboard.encoded_frames(), 100);
+    assert!(dashboard.workers[1].is_none());
+    assert_eq!(dashboard.finished[0].chunk, 3);
+
+    dashboard.apply(Event::Log {
+      level: "WARN".to_owned(),
+      message: "x".to_owned(),
+    });
+    assert_eq!(dashboard.log.back().unwrap(), "WARN x");
+
+    assert_eq!(format_size(4096), "4.00 KiB");
+    assert_eq!(format_duration(3725.0), "1:02:05");
+  }
+}
@@ -0,0 +1,179 @@
+//! End-to-end tests of the whole pipeline with synthetic sources.
+//!
+//! A short clip is generated with the test sources of ffmpeg, made of segments
+//! with hard cuts at known frames, and encoded with the av1an binary. The tests
+//! check that the scene detection finds the cuts, that the frames of the chunks
+//! add up, and that the concatenated output decodes completely.
+//!
+//! These tests need ffmpeg and x264 (and mkvmerge for the mkvmerge tests) in
+//! the system path, so they are ignored by default. Run them with:
+//!
+//! ```sh
+//! cargo test -p av1an --test synthetic -- --ignored
+//! ```
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use av1an_core::split::read_scenes_from_file;
+
+/// Frame rate and length in frames of each segment of the synthetic clip
+const FRAME_RATE: usize = 24;
+const SEGMENT_FRAMES: usize = 48;
+
+/// Sources of the segments, which differ enough for a hard cut between each
+const SEGMENTS: [&str; 3] = [
+  "testsrc2=size=320x240",
+  "smptebars=size=320x240",
+  "mandelbrot=size=320x240",
+];
+
+struct Clip {
+  path: PathBuf,
+  frames: usize,
+  cuts: Vec<usize>,
+}
+
+/// Creates an empty folder for a test, which is removed by the next run
+fn test_dir(name: &str) -> PathBuf {
+  let dir = std::env::temp_dir().join(format!("av1an-synthetic-{name}"));
+  if dir.exists() {
+    fs::remove_dir_all(&dir).unwrap();
+  }
+  fs::create_dir_all(&dir).unwrap();
+  dir
+}
+
+/// Generates the segments of the clip, concatenated into a single y4m file
+fn generate_clip(dir: &Path) -> Clip {
+  let path = dir.join("synthetic.y4m");
+  let mut command = Command::new("ffmpeg");
+  command.args(["-y", "-hide_banner", "-loglevel", "error"]);
+  for source in SEGMENTS {
+    command.args(["-f", "lavfi", "-i"]).arg(format!(
+      "{source}:rate={FRAME_RATE},trim=end_frame={SEGMENT_FRAMES}"
+    ));
+  }
+  let inputs: String = (0..SEGMENTS.len()).map(|i| format!("[{i}:v]")).collect();
+  command
+    .arg("-filter_complex")
+    .arg(format!(
+      "{inputs}concat=n={}:v=1:a=0,format=yuv420p",
+      SEGMENTS.len()
+    ))
+    .args(["-f", "yuv4mpegpipe"])
+    .arg(&path);
+  let status = command.status().expect("ffmpeg is required for this test");
+  assert!(status.success(), "ffmpeg failed to generate the clip");
+
+  Clip {
+    path,
+    frames: SEGMENTS.len() * SEGMENT_FRAMES,
+    cuts: (1..SEGMENTS.len()).map(|i| i * SEGMENT_FRAMES).collect(),
+  }
+}
+
+/// Encodes the clip with x264, keeping the temporary folder, and returns the
+/// path of the output
+fn encode(dir: &Path, clip: &Clip, args: &[&str]) -> PathBuf {
+  let output = dir.join("output.mkv");
+  let status = Command::new(env!("CARGO_BIN_EXE_av1an"))
+    .arg("-i")
+    .arg(&clip.path)
+    .arg("-o")
+    .arg(&output)
+    .arg("--temp")
+    .arg(dir.join("temp"))
+    .arg("--scenes")
+    .arg(dir.join("scenes.json"))
+    .args([
+      "-y",
+      "--keep",
+      "--quiet",
+      "--encoder",
+      "x264",
+      "--video-params",
+      " --preset ultrafast --crf 30",
+      "--pix-format",
+      "yuv420p",
+    ])
+    .args(args)
+    .status()
+    .unwrap();
+  assert!(status.success(), "av1an failed with {args:?}");
+
+  output
+}
+
+fn count_frames(path: &Path) -> usize {
+  ffmpeg::init().unwrap();
+  av1an_core::ffmpeg::num_frames(path).unwrap()
+}
+
+/// Checks the scenes, the encoded chunks and the output of an encode
+fn check_encode(dir: &Path, clip: &Clip, output: &Path) {
+  let (scenes, frames) = read_scenes_from_file(&dir.join("scenes.json")).unwrap();
+  assert_eq!(frames, clip.frames);
+  let starts: Vec<usize> = scenes.iter().map(|scene| scene.start_frame).collect();
+  for cut in &clip.cuts {
+    assert!(
+      starts.contains(cut),
+      "the cut at frame {cut} was not detected, scenes start at {starts:?}"
+    );
+  }
+
+  let mut chunk_frames = 0;
+  for entry in fs::read_dir(dir.join("temp").join("encode")).unwrap() {
+    chunk_frames += count_frames(&entry.unwrap().path());
+  }
+  assert_eq!(chunk_frames, clip.frames, "the chunks do not add up");
+
+  assert_eq!(count_frames(output), clip.frames);
+  let decode = Command::new("ffmpeg")
+    .args(["-hide_banner", "-loglevel", "error", "-i"])
+    .arg(output)
+    .args(["-f", "null", "-"])
+    .output()
+    .unwrap();
+  assert!(
+    decode.status.success() && decode.stderr.is_empty(),
+    "the output does not decode cleanly:\n{}",
+    String::from_utf8_lossy(&decode.stderr)
+  );
+}
+
+fn run(name: &str, args: &[&str]) {
+  let dir = test_dir(name);
+  let clip = generate_clip(&dir);
+  let output = encode(&dir, &clip, args);
+  check_encode(&dir, &clip, &output);
+  fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+#[ignore = "requires ffmpeg and x264"]
+fn synthetic_hybrid() {
+  run("hybrid", &["--chunk-method", "hybrid"]);
+}
+
+#[test]
+#[ignore = "requires ffmpeg and x264"]
+fn synthetic_select() {
+  run("select", &["--chunk-method", "select"]);
+}
+
+#[test]
+#[ignore = "requires ffmpeg and x264"]
+fn synthetic_extra_splits() {
+  run("extra-splits", &["--chunk-method", "hybrid", "-x", "30"]);
+}
+
+#[test]
+#[ignore = "requires ffmpeg, x264 and mkvmerge"]
+fn synthetic_mkvmerge() {
+  run(
+    "mkvmerge",
+    &["--chunk-method", "hybrid", "--concat", "mkvmerge"],
+  );
+}